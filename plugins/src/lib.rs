@@ -14,16 +14,19 @@ extern crate syn;
 use proc_macro::TokenStream;
 use proc_macro2::{Span, TokenStream as TokenStream2};
 use quote::{format_ident, quote, ToTokens};
+use std::collections::HashMap;
 use syn::{
     braced,
     ext::IdentExt,
     parenthesized,
     parse::{Parse, ParseStream},
     parse_macro_input, parse_quote, parse_str,
+    punctuated::Punctuated,
     spanned::Spanned,
     token::Comma,
-    Attribute, FnArg, Ident, ImplItem, ImplItemMethod, ImplItemType, ItemImpl, Lit, LitBool,
-    MetaNameValue, Pat, PatType, ReturnType, Token, Type, Visibility,
+    Attribute, FnArg, GenericArgument, Ident, ImplItem, ImplItemMethod, ImplItemType, ItemImpl,
+    Lit, LitBool, Meta, MetaNameValue, Pat, PatType, PathArguments, ReturnType, Token, Type,
+    TypeParamBound, Visibility,
 };
 
 /// Accumulates multiple errors into a result.
@@ -49,6 +52,23 @@ struct RpcMethod {
     attrs: Vec<Attribute>,
     ident: Ident,
     args: Vec<PatType>,
+    /// Whether each arg is marked `#[inject]`, i.e. resolved from the server's dependencies
+    /// instead of being sent by the client.
+    injected: Vec<bool>,
+    /// Whether each arg is marked `#[encrypted]`.
+    encrypted: Vec<bool>,
+    /// Whether the rpc is marked `#[encrypted]`, i.e. its response is encrypted.
+    encrypted_response: bool,
+    /// The wire tag of the rpc, given by `#[tarpc(id = N)]`.
+    id: Option<(u32, Span)>,
+    /// The type of the items of the pages returned by the rpc, if it's marked
+    /// `#[tarpc(paged)]`.
+    page_item: Option<Type>,
+    /// Whether the rpc is marked `#[tarpc(one_way)]`, i.e. the client doesn't wait for a response.
+    one_way: bool,
+    /// The ok and error types of the `Result` returned by the rpc, if it's marked
+    /// `#[tarpc(app_error)]`.
+    app_error: Option<(Type, Type)>,
     output: ReturnType,
 }
 
@@ -65,29 +85,65 @@ impl Parse for Service {
             rpcs.push(content.parse()?);
         }
         let mut ident_errors = Ok(());
+        let encrypted = rpcs.iter().any(RpcMethod::is_encrypted);
         for rpc in &rpcs {
-            if rpc.ident == "new" {
+            if rpc.ident == "new"
+                || rpc.ident == "closed"
+                || rpc.ident == "with_transport_class"
+                || rpc.ident == "with_transport_fallback"
+                || rpc.ident == "with_failover"
+                || (encrypted && rpc.ident == "with_keys")
+            {
                 extend_errors!(
                     ident_errors,
                     syn::Error::new(
                         rpc.ident.span(),
                         format!(
-                            "method name conflicts with generated fn `{}Client::new`",
-                            ident.unraw()
+                            "method name conflicts with generated fn `{}Client::{}`",
+                            ident.unraw(),
+                            rpc.ident
                         )
                     )
                 );
             }
-            if rpc.ident == "serve" {
+            if rpc.ident == "serve" || rpc.ident == "serve_with" {
                 extend_errors!(
                     ident_errors,
                     syn::Error::new(
                         rpc.ident.span(),
-                        format!("method name conflicts with generated fn `{ident}::serve`")
+                        format!(
+                            "method name conflicts with generated fn `{ident}::{}`",
+                            rpc.ident
+                        )
                     )
                 );
             }
         }
+        if rpcs.iter().any(|rpc| rpc.id.is_some()) {
+            let mut ids = HashMap::new();
+            for rpc in &rpcs {
+                match rpc.id {
+                    None => extend_errors!(
+                        ident_errors,
+                        syn::Error::new(
+                            rpc.ident.span(),
+                            "every rpc needs a `#[tarpc(id = N)]` once any rpc has one"
+                        )
+                    ),
+                    Some((id, span)) => {
+                        if let Some(other) = ids.insert(id, &rpc.ident) {
+                            extend_errors!(
+                                ident_errors,
+                                syn::Error::new(
+                                    span,
+                                    format!("id {id} is already used by rpc `{other}`")
+                                )
+                            );
+                        }
+                    }
+                }
+            }
+        }
         ident_errors?;
 
         Ok(Self {
@@ -99,20 +155,73 @@ impl Parse for Service {
     }
 }
 
+impl RpcMethod {
+    /// Returns true if any of the rpc's args, or its response, are encrypted.
+    fn is_encrypted(&self) -> bool {
+        self.encrypted_response || self.encrypted.contains(&true)
+    }
+}
+
 impl Parse for RpcMethod {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        let attrs = input.call(Attribute::parse_outer)?;
+        let mut attrs = input.call(Attribute::parse_outer)?;
+        let num_attrs = attrs.len();
+        attrs.retain(|attr| !attr.path.is_ident("encrypted"));
+        let encrypted_response = attrs.len() < num_attrs;
+        let RpcOptions {
+            id,
+            paged,
+            one_way,
+            app_error: is_app_error,
+        } = parse_rpc_options(&mut attrs)?;
         input.parse::<Token![async]>()?;
         input.parse::<Token![fn]>()?;
-        let ident = input.parse()?;
+        let ident: Ident = input.parse()?;
         let content;
         parenthesized!(content in input);
         let mut args = Vec::new();
+        let mut injected = Vec::new();
+        let mut encrypted = Vec::new();
         let mut errors = Ok(());
         for arg in content.parse_terminated::<FnArg, Comma>(FnArg::parse)? {
             match arg {
-                FnArg::Typed(captured) if matches!(&*captured.pat, Pat::Ident(_)) => {
+                FnArg::Typed(mut captured) if matches!(&*captured.pat, Pat::Ident(_)) => {
+                    let attrs = captured.attrs.len();
+                    captured.attrs.retain(|attr| !attr.path.is_ident("inject"));
+                    let inject = captured.attrs.len() < attrs;
+                    let attrs = captured.attrs.len();
+                    captured
+                        .attrs
+                        .retain(|attr| !attr.path.is_ident("encrypted"));
+                    let encrypt = captured.attrs.len() < attrs;
+                    if inject && encrypt {
+                        extend_errors!(
+                            errors,
+                            syn::Error::new(
+                                captured.pat.span(),
+                                "injected args aren't sent, so they can't be encrypted"
+                            )
+                        );
+                    }
+                    if inject && matches!(&*captured.ty, Type::Reference(_)) {
+                        extend_errors!(
+                            errors,
+                            syn::Error::new(
+                                captured.ty.span(),
+                                "injected args are cloned from the server's dependencies, so they \
+                                 must be owned, e.g. `Arc<T>` instead of `&T`"
+                            )
+                        );
+                    }
+                    if inject && stream_item_type(&captured.ty).is_some() {
+                        extend_errors!(
+                            errors,
+                            syn::Error::new(captured.ty.span(), "stream args can't be injected")
+                        );
+                    }
                     args.push(captured);
+                    injected.push(inject);
+                    encrypted.push(encrypt);
                 }
                 FnArg::Typed(captured) => {
                     extend_errors!(
@@ -128,27 +237,281 @@ impl Parse for RpcMethod {
                 }
             }
         }
-        errors?;
+        let stream_args = args
+            .iter()
+            .filter(|arg| stream_item_type(&arg.ty).is_some())
+            .collect::<Vec<_>>();
+        if stream_args.len() > 1 {
+            for arg in &stream_args[1..] {
+                extend_errors!(
+                    errors,
+                    syn::Error::new(
+                        arg.ty.span(),
+                        "client-streaming rpcs can only have one stream arg"
+                    )
+                );
+            }
+        }
         let output = input.parse()?;
+        let streaming = !stream_args.is_empty()
+            || matches!(&output, ReturnType::Type(_, ty) if stream_item_type(ty).is_some());
+        if streaming && (encrypted_response || encrypted.contains(&true)) {
+            extend_errors!(
+                errors,
+                syn::Error::new(
+                    ident.span(),
+                    "encrypted fields are only supported in rpcs that don't stream"
+                )
+            );
+        }
+        let mut page_item = None;
+        if paged {
+            let page_arg = args
+                .iter()
+                .zip(&injected)
+                .next_back()
+                .filter(|(arg, &injected)| {
+                    !injected
+                        && matches!(
+                            &*arg.ty,
+                            Type::Path(ty) if ty.path.segments.last().map_or(false, |segment| segment.ident == "Option")
+                        )
+                });
+            if page_arg.is_none() {
+                extend_errors!(
+                    errors,
+                    syn::Error::new(
+                        ident.span(),
+                        "paged rpcs take an `Option<PageToken>` as their last arg"
+                    )
+                );
+            }
+            let output_span = match &output {
+                ReturnType::Type(_, ty) => {
+                    page_item = page_item_type(ty).cloned();
+                    ty.span()
+                }
+                ReturnType::Default => ident.span(),
+            };
+            if page_item.is_none() {
+                extend_errors!(
+                    errors,
+                    syn::Error::new(output_span, "paged rpcs return a `Page<T>`")
+                );
+            }
+            if streaming || encrypted_response || encrypted.contains(&true) {
+                extend_errors!(
+                    errors,
+                    syn::Error::new(
+                        ident.span(),
+                        "paged rpcs can't stream or have encrypted fields"
+                    )
+                );
+            }
+        }
+        if one_way {
+            match &output {
+                ReturnType::Type(_, ty) if !matches!(&**ty, Type::Tuple(ty) if ty.elems.is_empty()) =>
+                {
+                    extend_errors!(
+                        errors,
+                        syn::Error::new(ty.span(), "one-way rpcs don't return anything")
+                    );
+                }
+                _ => {}
+            }
+            if streaming || paged || encrypted_response || encrypted.contains(&true) {
+                extend_errors!(
+                    errors,
+                    syn::Error::new(
+                        ident.span(),
+                        "one-way rpcs can't stream, be paged, or have encrypted fields"
+                    )
+                );
+            }
+        }
+        let mut app_error = None;
+        if is_app_error {
+            let (output_span, types) = match &output {
+                ReturnType::Type(_, ty) => (ty.span(), result_types(ty)),
+                ReturnType::Default => (ident.span(), None),
+            };
+            match types {
+                Some((ok, err)) => app_error = Some((ok.clone(), err.clone())),
+                None => extend_errors!(
+                    errors,
+                    syn::Error::new(output_span, "app_error rpcs return a `Result<T, E>`")
+                ),
+            }
+            if streaming || paged || one_way || encrypted_response || encrypted.contains(&true) {
+                extend_errors!(
+                    errors,
+                    syn::Error::new(
+                        ident.span(),
+                        "app_error rpcs can't stream, be paged, be one-way, or have encrypted \
+                         fields"
+                    )
+                );
+            }
+        }
+        errors?;
         input.parse::<Token![;]>()?;
 
         Ok(Self {
             attrs,
             ident,
             args,
+            injected,
+            encrypted,
+            encrypted_response,
+            id,
+            page_item,
+            one_way,
+            app_error,
             output,
         })
     }
 }
 
-// If `derive_serde` meta item is not present, defaults to cfg!(feature = "serde1").
-// `derive_serde` can only be true when serde1 is enabled.
-struct DeriveSerde(bool);
+/// The options of an rpc, given by its `#[tarpc(...)]` attributes.
+struct RpcOptions {
+    /// The wire tag given by `id = N`.
+    id: Option<(u32, Span)>,
+    paged: bool,
+    one_way: bool,
+    app_error: bool,
+}
+
+/// Parses the options of an rpc from its `#[tarpc(...)]` attributes, and removes them.
+fn parse_rpc_options(attrs: &mut Vec<Attribute>) -> syn::Result<RpcOptions> {
+    let mut id = None;
+    let mut paged = false;
+    let mut one_way = false;
+    let mut app_error = false;
+    let mut errors = Ok(());
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("tarpc")) {
+        let metas = attr.parse_args_with(Punctuated::<Meta, Comma>::parse_terminated)?;
+        for meta in metas {
+            let meta = match meta {
+                Meta::NameValue(meta) if meta.path.is_ident("id") => meta,
+                Meta::Path(path) if path.is_ident("paged") => {
+                    if paged {
+                        extend_errors!(
+                            errors,
+                            syn::Error::new(path.span(), "`paged` appears more than once")
+                        );
+                    }
+                    paged = true;
+                    continue;
+                }
+                Meta::Path(path) if path.is_ident("one_way") => {
+                    if one_way {
+                        extend_errors!(
+                            errors,
+                            syn::Error::new(path.span(), "`one_way` appears more than once")
+                        );
+                    }
+                    one_way = true;
+                    continue;
+                }
+                Meta::Path(path) if path.is_ident("app_error") => {
+                    if app_error {
+                        extend_errors!(
+                            errors,
+                            syn::Error::new(path.span(), "`app_error` appears more than once")
+                        );
+                    }
+                    app_error = true;
+                    continue;
+                }
+                meta => {
+                    extend_errors!(
+                        errors,
+                        syn::Error::new(
+                            meta.path().span(),
+                            "tarpc rpcs do not support this meta item"
+                        )
+                    );
+                    continue;
+                }
+            };
+            let value = match &meta.lit {
+                Lit::Int(lit) => lit.base10_parse::<u32>().ok().filter(|&id| id < 1 << 31),
+                _ => None,
+            };
+            match value {
+                Some(_) if id.is_some() => extend_errors!(
+                    errors,
+                    syn::Error::new(meta.span(), "`id` appears more than once")
+                ),
+                Some(value) => id = Some((value, meta.lit.span())),
+                None => extend_errors!(
+                    errors,
+                    syn::Error::new(meta.lit.span(), "`id` expects an integer below 2^31")
+                ),
+            }
+        }
+    }
+    errors?;
+    attrs.retain(|attr| !attr.path.is_ident("tarpc"));
+    Ok(RpcOptions {
+        id,
+        paged,
+        one_way,
+        app_error,
+    })
+}
+
+/// Returns `(T, E)` if `ty` is a path to a type named `Result<T, E>`.
+fn result_types(ty: &Type) -> Option<(&Type, &Type)> {
+    let segment = match ty {
+        Type::Path(ty) if ty.qself.is_none() => ty.path.segments.last()?,
+        _ => return None,
+    };
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args)
+            if segment.ident == "Result" && args.args.len() == 2 =>
+        {
+            match (&args.args[0], &args.args[1]) {
+                (GenericArgument::Type(ok), GenericArgument::Type(err)) => Some((ok, err)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Returns `T` if `ty` is a path to a type named `Page<T>`.
+fn page_item_type(ty: &Type) -> Option<&Type> {
+    let segment = match ty {
+        Type::Path(ty) if ty.qself.is_none() => ty.path.segments.last()?,
+        _ => return None,
+    };
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) if segment.ident == "Page" && args.args.len() == 1 => {
+            match args.args.first()? {
+                GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The options of a service, given as `name = value` args of `#[tarpc::service]`.
+struct ServiceOptions {
+    /// Defaults to cfg!(feature = "serde1") if the `derive_serde` meta item is not present. Can
+    /// only be true when serde1 is enabled.
+    derive_serde: bool,
+    /// Whether to generate an `Erased{Service}Client` alongside the client.
+    erased_client: bool,
+}
 
-impl Parse for DeriveSerde {
+impl Parse for ServiceOptions {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        let mut result = Ok(None);
+        let mut result = Ok(());
         let mut derive_serde = Vec::new();
+        let mut erased_client = Vec::new();
         let meta_items = input.parse_terminated::<MetaNameValue, Comma>(MetaNameValue::parse)?;
         for meta in meta_items {
             if meta.path.segments.len() != 1 {
@@ -162,6 +525,19 @@ impl Parse for DeriveSerde {
                 continue;
             }
             let segment = meta.path.segments.first().unwrap();
+            if segment.ident == "erased_client" {
+                if !matches!(meta.lit, Lit::Bool(_)) {
+                    extend_errors!(
+                        result,
+                        syn::Error::new(
+                            meta.lit.span(),
+                            "`erased_client` expects a value of type `bool`"
+                        )
+                    );
+                }
+                erased_client.push(meta);
+                continue;
+            }
             if segment.ident != "derive_serde" {
                 extend_errors!(
                     result,
@@ -173,10 +549,7 @@ impl Parse for DeriveSerde {
                 continue;
             }
             match meta.lit {
-                Lit::Bool(LitBool { value: true, .. }) if cfg!(feature = "serde1") => {
-                    result = result.and(Ok(Some(true)))
-                }
-                Lit::Bool(LitBool { value: true, .. }) => {
+                Lit::Bool(LitBool { value: true, .. }) if !cfg!(feature = "serde1") => {
                     extend_errors!(
                         result,
                         syn::Error::new(
@@ -185,7 +558,7 @@ impl Parse for DeriveSerde {
                         )
                     );
                 }
-                Lit::Bool(LitBool { value: false, .. }) => result = result.and(Ok(Some(false))),
+                Lit::Bool(_) => {}
                 _ => extend_errors!(
                     result,
                     syn::Error::new(
@@ -196,22 +569,32 @@ impl Parse for DeriveSerde {
             }
             derive_serde.push(meta);
         }
-        if derive_serde.len() > 1 {
-            for (i, derive_serde) in derive_serde.iter().enumerate() {
-                extend_errors!(
-                    result,
-                    syn::Error::new(
-                        derive_serde.span(),
-                        format!(
-                            "`derive_serde` appears more than once (occurrence #{})",
-                            i + 1
+        for (name, occurrences) in [
+            ("derive_serde", &derive_serde),
+            ("erased_client", &erased_client),
+        ] {
+            if occurrences.len() > 1 {
+                for (i, occurrence) in occurrences.iter().enumerate() {
+                    extend_errors!(
+                        result,
+                        syn::Error::new(
+                            occurrence.span(),
+                            format!("`{}` appears more than once (occurrence #{})", name, i + 1)
                         )
-                    )
-                );
+                    );
+                }
             }
         }
-        let derive_serde = result?.unwrap_or(cfg!(feature = "serde1"));
-        Ok(Self(derive_serde))
+        result?;
+        let flag = |metas: &[MetaNameValue]| {
+            metas
+                .first()
+                .map(|meta| matches!(meta.lit, Lit::Bool(LitBool { value: true, .. })))
+        };
+        Ok(Self {
+            derive_serde: flag(&derive_serde).unwrap_or(cfg!(feature = "serde1")),
+            erased_client: flag(&erased_client).unwrap_or(false),
+        })
     }
 }
 
@@ -234,6 +617,32 @@ pub fn derive_serde(_attr: TokenStream, item: TokenStream) -> TokenStream {
     proc_macro::TokenStream::from(gen)
 }
 
+/// Returns `T` if `ty` is `impl Stream<Item = T>`, i.e. if it is the return type of a
+/// server-streaming rpc, or the type of the stream arg of a client-streaming rpc.
+fn stream_item_type(ty: &Type) -> Option<&Type> {
+    let bounds = match ty {
+        Type::ImplTrait(impl_trait) => &impl_trait.bounds,
+        _ => return None,
+    };
+    bounds.iter().find_map(|bound| {
+        let segment = match bound {
+            TypeParamBound::Trait(bound) => bound.path.segments.last()?,
+            _ => return None,
+        };
+        match &segment.arguments {
+            PathArguments::AngleBracketed(args) if segment.ident == "Stream" => {
+                args.args.iter().find_map(|arg| match arg {
+                    GenericArgument::Binding(binding) if binding.ident == "Item" => {
+                        Some(&binding.ty)
+                    }
+                    _ => None,
+                })
+            }
+            _ => None,
+        }
+    })
+}
+
 /// Generates:
 /// - service trait
 /// - serve fn
@@ -241,9 +650,14 @@ pub fn derive_serde(_attr: TokenStream, item: TokenStream) -> TokenStream {
 /// - new_stub client factory fn
 /// - Request and Response enums
 /// - ResponseFut Future
+/// - ResponseStream Stream, if any rpcs are server-streaming
+/// - type-erased client stub struct, with `erased_client = true`
+///
+/// The stream arg of a client-streaming rpc is not part of its request variant; instead, each
+/// item is sent as a separate `{Rpc}StreamItem` request variant.
 #[proc_macro_attribute]
 pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
-    let derive_serde = parse_macro_input!(attr as DeriveSerde);
+    let options = parse_macro_input!(attr as ServiceOptions);
     let unit_type: &Type = &parse_quote!(());
     let Service {
         ref attrs,
@@ -256,9 +670,94 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
         .iter()
         .map(|rpc| snake_to_camel(&rpc.ident.unraw().to_string()))
         .collect();
+    let request_ident = &format_ident!("{}Request", ident);
     let args: &[&[PatType]] = &rpcs.iter().map(|rpc| &*rpc.args).collect::<Vec<_>>();
+    let request_item_types = &args
+        .iter()
+        .map(|args| args.iter().find_map(|arg| stream_item_type(&arg.ty)))
+        .collect::<Vec<_>>();
+    // The stream arg of a client-streaming rpc is passed to the service as a RequestStream.
+    let trait_args = &args
+        .iter()
+        .map(|args| {
+            args.iter()
+                .map(|arg| match stream_item_type(&arg.ty) {
+                    Some(item) => PatType {
+                        ty: parse_quote!(tarpc::server::RequestStream<#request_ident, #item>),
+                        ..arg.clone()
+                    },
+                    None => arg.clone(),
+                })
+                .collect()
+        })
+        .collect::<Vec<_>>();
+    // Injected args are resolved by the server, and stream args are sent as separate requests.
+    let request_args = &rpcs
+        .iter()
+        .map(|rpc| {
+            rpc.args
+                .iter()
+                .zip(&rpc.injected)
+                .filter(|(arg, &injected)| !injected && stream_item_type(&arg.ty).is_none())
+                .map(|(arg, _)| arg)
+                .collect()
+        })
+        .collect::<Vec<_>>();
+    // Encrypted args are sent as their ciphertext.
+    let request_fields = &rpcs
+        .iter()
+        .map(|rpc| {
+            rpc.args
+                .iter()
+                .zip(&rpc.injected)
+                .zip(&rpc.encrypted)
+                .filter(|((arg, &injected), _)| !injected && stream_item_type(&arg.ty).is_none())
+                .map(|((arg, _), &encrypted)| {
+                    let ty = &arg.ty;
+                    if encrypted {
+                        PatType {
+                            ty: parse_quote!(tarpc::encryption::Encrypted<#ty>),
+                            ..arg.clone()
+                        }
+                    } else {
+                        arg.clone()
+                    }
+                })
+                .collect()
+        })
+        .collect::<Vec<_>>();
+    let encrypted_request_args = &rpcs
+        .iter()
+        .map(|rpc| {
+            rpc.args
+                .iter()
+                .zip(&rpc.encrypted)
+                .filter(|(_, &encrypted)| encrypted)
+                .map(|(arg, _)| arg)
+                .collect()
+        })
+        .collect::<Vec<_>>();
+    let injected_args = &rpcs
+        .iter()
+        .map(|rpc| {
+            rpc.args
+                .iter()
+                .zip(&rpc.injected)
+                .filter(|(_, &injected)| injected)
+                .map(|(arg, _)| arg)
+                .collect()
+        })
+        .collect::<Vec<_>>();
     let response_fut_name = &format!("{}ResponseFut", ident.unraw());
-    let derive_serialize = if derive_serde.0 {
+    let response_stream_name = &format!("{}ResponseStream", ident.unraw());
+    let stream_item_types = rpcs
+        .iter()
+        .map(|rpc| match rpc.output {
+            ReturnType::Type(_, ref ty) => stream_item_type(ty),
+            ReturnType::Default => None,
+        })
+        .collect::<Vec<_>>();
+    let derive_serialize = if options.derive_serde {
         Some(
             quote! {#[derive(tarpc::serde::Serialize, tarpc::serde::Deserialize)]
             #[serde(crate = "tarpc::serde")]},
@@ -273,32 +772,82 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
         .map(|m| format!("{ident}.{m}"))
         .collect::<Vec<_>>();
 
+    let return_types = &rpcs
+        .iter()
+        .zip(&stream_item_types)
+        .map(
+            |(rpc, stream_item_type)| match (stream_item_type, &rpc.output) {
+                (Some(item), _) => *item,
+                (None, ReturnType::Type(_, ref ty)) => &**ty,
+                (None, ReturnType::Default) => unit_type,
+            },
+        )
+        .collect::<Vec<_>>();
+
     ServiceGenerator {
         response_fut_name,
         service_ident: ident,
         server_ident: &format_ident!("Serve{}", ident),
         response_fut_ident: &Ident::new(response_fut_name, ident.span()),
+        response_stream_name,
+        response_stream_ident: &Ident::new(response_stream_name, ident.span()),
         client_ident: &format_ident!("{}Client", ident),
-        request_ident: &format_ident!("{}Request", ident),
+        erased_client_ident: options
+            .erased_client
+            .then(|| format_ident!("Erased{}Client", ident))
+            .as_ref(),
+        request_ident,
         response_ident: &format_ident!("{}Response", ident),
         vis,
-        args,
+        args: trait_args,
+        request_args,
+        injected_args,
         method_attrs: &rpcs.iter().map(|rpc| &*rpc.attrs).collect::<Vec<_>>(),
         method_idents: &methods,
         request_names: &request_names,
         attrs,
         rpcs,
-        return_types: &rpcs
+        return_types,
+        // An encrypted response is sent as its ciphertext.
+        response_types: &return_types
             .iter()
-            .map(|rpc| match rpc.output {
-                ReturnType::Type(_, ref ty) => ty,
-                ReturnType::Default => unit_type,
+            .zip(rpcs)
+            .map(|(&ty, rpc)| {
+                if rpc.encrypted_response {
+                    parse_quote!(tarpc::encryption::Encrypted<#ty>)
+                } else {
+                    ty.clone()
+                }
             })
             .collect::<Vec<_>>(),
+        request_fields,
+        encrypted_request_args,
+        streaming: &stream_item_types
+            .iter()
+            .map(Option::is_some)
+            .collect::<Vec<_>>(),
+        request_item_types,
         arg_pats: &args
             .iter()
             .map(|args| args.iter().map(|arg| &*arg.pat).collect())
             .collect::<Vec<_>>(),
+        request_arg_pats: &request_args
+            .iter()
+            .map(|args| args.iter().map(|arg| &*arg.pat).collect())
+            .collect::<Vec<_>>(),
+        stream_arg_pats: &args
+            .iter()
+            .map(|args| {
+                args.iter()
+                    .find(|arg| stream_item_type(&arg.ty).is_some())
+                    .map(|arg| &*arg.pat)
+            })
+            .collect::<Vec<_>>(),
+        stream_item_idents: &camel_case_fn_names
+            .iter()
+            .zip(rpcs)
+            .map(|(name, rpc)| format_ident!("{}StreamItem", name, span = rpc.ident.span()))
+            .collect::<Vec<_>>(),
         camel_case_idents: &rpcs
             .iter()
             .zip(camel_case_fn_names.iter())
@@ -306,9 +855,19 @@ pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
             .collect::<Vec<_>>(),
         future_types: &camel_case_fn_names
             .iter()
-            .map(|name| parse_str(&format!("{name}Fut")).unwrap())
+            .zip(&stream_item_types)
+            .map(|(name, stream_item_type)| match stream_item_type {
+                Some(_) => parse_str(&format!("{name}Stream")).unwrap(),
+                None => parse_str(&format!("{name}Fut")).unwrap(),
+            })
             .collect::<Vec<_>>(),
         derive_serialize: derive_serialize.as_ref(),
+        wire_ids: rpcs
+            .iter()
+            .map(|rpc| rpc.id.map(|(id, _)| id))
+            .collect::<Option<Vec<_>>>()
+            .filter(|ids| !ids.is_empty())
+            .as_deref(),
     }
     .into_token_stream()
     .into()
@@ -320,15 +879,26 @@ fn associated_type_for_rpc(method: &ImplItemMethod) -> String {
     snake_to_camel(&method.sig.ident.unraw().to_string()) + "Fut"
 }
 
+/// generate an identifier consisting of the method name to CamelCase with
+/// Stream appended to it.
+fn associated_stream_type_for_rpc(method: &ImplItemMethod) -> String {
+    snake_to_camel(&method.sig.ident.unraw().to_string()) + "Stream"
+}
+
 /// Transforms an async function into a sync one, returning a type declaration
-/// for the return type (a future).
+/// for the return type (a future, or a stream for server-streaming rpcs).
 fn transform_method(method: &mut ImplItemMethod) -> ImplItemType {
     method.sig.asyncness = None;
 
     // get either the return type or ().
     let ret = match &method.sig.output {
         ReturnType::Default => quote!(()),
-        ReturnType::Type(_, ret) => quote!(#ret),
+        ReturnType::Type(_, ret) => {
+            if let Some(item) = stream_item_type(ret) {
+                return transform_stream_method(method, quote!(#item));
+            }
+            quote!(#ret)
+        }
     };
 
     let fut_name = associated_type_for_rpc(method);
@@ -357,6 +927,49 @@ fn transform_method(method: &mut ImplItemMethod) -> ImplItemType {
     t
 }
 
+/// Rewrites the `impl Stream<Item = T>` arg of a client-streaming rpc to the
+/// `RequestStream<{Service}Request, T>` the service trait expects.
+fn transform_stream_args(method: &mut ImplItemMethod, request_path: &syn::Path) {
+    for arg in &mut method.sig.inputs {
+        if let FnArg::Typed(arg) = arg {
+            if let Some(item) = stream_item_type(&arg.ty) {
+                let item = item.clone();
+                arg.ty = parse_quote!(tarpc::server::RequestStream<#request_path, #item>);
+            }
+        }
+    }
+}
+
+/// Transforms an async function returning `impl Stream<Item = #item>` into a sync one, returning a
+/// type declaration for the return type (a stream).
+fn transform_stream_method(method: &mut ImplItemMethod, item: TokenStream2) -> ImplItemType {
+    let stream_name = associated_stream_type_for_rpc(method);
+    let stream_name_ident = Ident::new(&stream_name, method.sig.ident.span());
+
+    // generate the updated return signature.
+    method.sig.output = parse_quote! {
+        -> ::core::pin::Pin<Box<
+                dyn tarpc::futures::Stream<Item = #item> + ::core::marker::Send
+            >>
+    };
+
+    // transform the body of the method into a stream that first runs the body to produce the
+    // stream, then yields its items.
+    let block = method.block.clone();
+    method.block = parse_quote! [{
+        Box::pin(tarpc::futures::stream::StreamExt::flatten(
+            tarpc::futures::stream::once(async move
+                #block
+            )
+        ))
+    }];
+
+    // generate and return type declaration for return type.
+    parse_quote! {
+        type #stream_name_ident = ::core::pin::Pin<Box<dyn tarpc::futures::Stream<Item = #item> + ::core::marker::Send>>;
+    }
+}
+
 #[proc_macro_attribute]
 pub fn server(_attr: TokenStream, input: TokenStream) -> TokenStream {
     let mut item = syn::parse_macro_input!(input as ItemImpl);
@@ -366,10 +979,20 @@ pub fn server(_attr: TokenStream, input: TokenStream) -> TokenStream {
     let mut types: Vec<ImplItemType> = Vec::new();
     let mut expected_non_async_types: Vec<(&ImplItemMethod, String)> = Vec::new();
     let mut found_non_async_types: Vec<&ImplItemType> = Vec::new();
+    let request_path = item.trait_.as_ref().map(|(_, path, _)| {
+        let mut path = path.clone();
+        if let Some(last) = path.segments.last_mut() {
+            last.ident = format_ident!("{}Request", last.ident);
+        }
+        path
+    });
 
     for inner in &mut item.items {
         match inner {
             ImplItem::Method(method) => {
+                if let Some(request_path) = &request_path {
+                    transform_stream_args(method, request_path);
+                }
                 if method.sig.asyncness.is_some() {
                     // if this function is declared async, transform it into a regular function
                     let typedecl = transform_method(method);
@@ -406,7 +1029,11 @@ fn verify_types_were_provided(
 ) -> syn::Result<()> {
     let mut result = Ok(());
     for (method, expected) in expected {
-        if !provided.iter().any(|typedecl| typedecl.ident == expected) {
+        let expected_stream = associated_stream_type_for_rpc(method);
+        if !provided
+            .iter()
+            .any(|typedecl| typedecl.ident == expected || typedecl.ident == expected_stream)
+        {
             let mut e = syn::Error::new(
                 span,
                 format!("not all trait items implemented, missing: `{expected}`"),
@@ -435,7 +1062,11 @@ struct ServiceGenerator<'a> {
     server_ident: &'a Ident,
     response_fut_ident: &'a Ident,
     response_fut_name: &'a str,
+    response_stream_ident: &'a Ident,
+    response_stream_name: &'a str,
     client_ident: &'a Ident,
+    /// The ident of the type-erased client, if the service generates one.
+    erased_client_ident: Option<&'a Ident>,
     request_ident: &'a Ident,
     response_ident: &'a Ident,
     vis: &'a Visibility,
@@ -446,10 +1077,30 @@ struct ServiceGenerator<'a> {
     method_idents: &'a [&'a Ident],
     request_names: &'a [String],
     method_attrs: &'a [&'a [Attribute]],
-    args: &'a [&'a [PatType]],
+    args: &'a [Vec<PatType>],
+    request_args: &'a [Vec<&'a PatType>],
+    request_fields: &'a [Vec<PatType>],
+    encrypted_request_args: &'a [Vec<&'a PatType>],
+    injected_args: &'a [Vec<&'a PatType>],
     return_types: &'a [&'a Type],
+    response_types: &'a [Type],
+    streaming: &'a [bool],
+    request_item_types: &'a [Option<&'a Type>],
     arg_pats: &'a [Vec<&'a Pat>],
+    request_arg_pats: &'a [Vec<&'a Pat>],
+    stream_arg_pats: &'a [Option<&'a Pat>],
+    stream_item_idents: &'a [Ident],
     derive_serialize: Option<&'a TokenStream2>,
+    /// The wire tags of the rpcs, if they're given by `#[tarpc(id = N)]`.
+    wire_ids: Option<&'a [u32]>,
+}
+
+/// Returns the name an encrypted field is identified by: `{Service}.{rpc}.{arg}` for an arg.
+fn encrypted_field_name(request_name: &str, arg: &PatType) -> String {
+    match &*arg.pat {
+        Pat::Ident(pat) => format!("{request_name}.{}", pat.ident.unraw()),
+        _ => unreachable!("patterns aren't allowed in RPC args"),
+    }
 }
 
 impl<'a> ServiceGenerator<'a> {
@@ -460,29 +1111,39 @@ impl<'a> ServiceGenerator<'a> {
             vis,
             future_types,
             return_types,
+            streaming,
             service_ident,
             server_ident,
+            args,
             ..
         } = self;
 
         let types_and_fns = rpcs
             .iter()
+            .zip(args)
             .zip(future_types.iter())
             .zip(return_types.iter())
+            .zip(streaming.iter())
             .map(
                 |(
-                    (
-                        RpcMethod {
-                            attrs, ident, args, ..
-                        },
-                        future_type,
-                    ),
-                    output,
+                    (((RpcMethod { attrs, ident, .. }, args), future_type), output),
+                    &streaming,
                 )| {
-                    let ty_doc = format!("The response future returned by [`{service_ident}::{ident}`].");
+                    let ty = if streaming {
+                        let ty_doc = format!("The response stream returned by [`{service_ident}::{ident}`].");
+                        quote! {
+                            #[doc = #ty_doc]
+                            type #future_type: tarpc::futures::Stream<Item = #output>;
+                        }
+                    } else {
+                        let ty_doc = format!("The response future returned by [`{service_ident}::{ident}`].");
+                        quote! {
+                            #[doc = #ty_doc]
+                            type #future_type: std::future::Future<Output = #output>;
+                        }
+                    };
                     quote! {
-                        #[doc = #ty_doc]
-                        type #future_type: std::future::Future<Output = #output>;
+                        #ty
 
                         #( #attrs )*
                         fn #ident(self, context: tarpc::context::Context, #( #args ),*) -> Self::#future_type;
@@ -498,7 +1159,13 @@ impl<'a> ServiceGenerator<'a> {
                 /// Returns a serving function to use with
                 /// [InFlightRequest::execute](tarpc::server::InFlightRequest::execute).
                 fn serve(self) -> #server_ident<Self> {
-                    #server_ident { service: self }
+                    self.serve_with(tarpc::server::Dependencies::new())
+                }
+
+                /// Returns a serving function that resolves the `#[inject]` args of rpcs from
+                /// `dependencies`.
+                fn serve_with(self, dependencies: tarpc::server::Dependencies) -> #server_ident<Self> {
+                    #server_ident { service: self, dependencies }
                 }
             }
         }
@@ -514,6 +1181,8 @@ impl<'a> ServiceGenerator<'a> {
             #[derive(Clone)]
             #vis struct #server_ident<S> {
                 service: S,
+                #[allow(dead_code)]
+                dependencies: tarpc::server::Dependencies,
             }
         }
     }
@@ -525,45 +1194,260 @@ impl<'a> ServiceGenerator<'a> {
             service_ident,
             response_ident,
             response_fut_ident,
+            response_stream_ident,
             camel_case_idents,
             arg_pats,
+            request_arg_pats,
             method_idents,
             request_names,
+            streaming,
+            injected_args,
             ..
         } = self;
 
-        quote! {
-            impl<S> tarpc::server::Serve<#request_ident> for #server_ident<S>
-                where S: #service_ident
-            {
-                type Resp = #response_ident;
-                type Fut = #response_fut_ident<S>;
+        let injections = injected_args
+            .iter()
+            .zip(request_names)
+            .map(|(injected_args, request_name)| {
+                let (pats, tys): (Vec<_>, Vec<_>) =
+                    injected_args.iter().map(|arg| (&arg.pat, &arg.ty)).unzip();
+                quote! {
+                    #(
+                        let #pats: #tys = match self.dependencies.get::<#tys>() {
+                            Some(dependency) => dependency,
+                            None => {
+                                return tarpc::server::Served::Error(tarpc::ServerError::new(
+                                    std::io::ErrorKind::NotFound,
+                                    format!(
+                                        "missing dependency `{}` of `{}`",
+                                        std::any::type_name::<#tys>(),
+                                        #request_name,
+                                    ),
+                                ));
+                            }
+                        };
+                    )*
+                }
+            })
+            .collect::<Vec<_>>();
 
-                fn method(&self, req: &#request_ident) -> Option<&'static str> {
-                    Some(match req {
-                        #(
-                            #request_ident::#camel_case_idents{..} => {
-                                #request_names
+        // Encrypted args are decrypted with the keys in the server's dependencies, which are
+        // also passed on to encrypt the response.
+        let (decryptions, response_keys): (Vec<_>, Vec<_>) = self
+            .rpcs
+            .iter()
+            .zip(self.encrypted_request_args)
+            .zip(request_names)
+            .map(|((rpc, encrypted_args), request_name)| {
+                if !rpc.is_encrypted() {
+                    return (quote!(), quote!());
+                }
+                let pats = encrypted_args.iter().map(|arg| &arg.pat);
+                let fields = encrypted_args
+                    .iter()
+                    .map(|arg| encrypted_field_name(request_name, arg));
+                let decryption = quote! {
+                    let keys = match self.dependencies.get::<tarpc::encryption::Keys>() {
+                        Some(keys) => keys,
+                        None => {
+                            return tarpc::server::Served::Error(tarpc::ServerError::new(
+                                std::io::ErrorKind::NotFound,
+                                format!("missing key provider for `{}`", #request_name),
+                            ));
+                        }
+                    };
+                    #(
+                        let #pats = match keys.open(#fields, #pats) {
+                            Ok(#pats) => #pats,
+                            Err(e) => {
+                                return tarpc::server::Served::Error(tarpc::ServerError::new(
+                                    std::io::ErrorKind::InvalidData,
+                                    format!("could not decrypt `{}`: {}", #fields, e),
+                                ));
                             }
-                        )*
-                    })
+                        };
+                    )*
+                };
+                let response_keys = if rpc.encrypted_response {
+                    quote!(, keys)
+                } else {
+                    quote!()
+                };
+                (decryption, response_keys)
+            })
+            .unzip();
+
+        let unary_fut_ty = if streaming.iter().any(|&streaming| !streaming) {
+            quote!(#response_fut_ident<S>)
+        } else {
+            quote!(std::future::Pending<#response_ident>)
+        };
+
+        // A service whose requests are all answered with a single response, which can't fail
+        // before its handler runs, is served by calling the handler. Otherwise, the injections,
+        // decryptions, and streams of each request are set up first.
+        let single_response = !streaming.iter().any(|&streaming| streaming)
+            && self.request_item_types.iter().all(Option::is_none)
+            && injected_args.iter().all(Vec::is_empty)
+            && !self.rpcs.iter().any(RpcMethod::is_encrypted);
+        if single_response {
+            return quote! {
+                impl<S> tarpc::server::Serve<#request_ident> for #server_ident<S>
+                    where S: #service_ident
+                {
+                    type Resp = #response_ident;
+                    type Fut = #unary_fut_ty;
+
+                    fn method(&self, req: &#request_ident) -> Option<&'static str> {
+                        Some(req.method_name())
+                    }
+
+                    fn serve_with_items(
+                        self,
+                        ctx: tarpc::context::Context,
+                        req: #request_ident,
+                        _: tarpc::server::RequestStream<#request_ident>,
+                    ) -> tarpc::server::Served<Self::Fut> {
+                        tarpc::server::Served::Response(match req {
+                            #(
+                                #request_ident::#camel_case_idents{ #( #request_arg_pats ),* } => {
+                                    #response_fut_ident::#camel_case_idents(
+                                        #service_ident::#method_idents(
+                                            self.service, ctx, #( #arg_pats ),*
+                                        )
+                                    )
+                                }
+                            )*
+                        })
+                    }
                 }
+            };
+        }
+
+        let handlers = camel_case_idents
+            .iter()
+            .zip(method_idents)
+            .zip(arg_pats)
+            .zip(&response_keys)
+            .zip(streaming)
+            .map(
+                |((((camel_case_ident, method_ident), arg_pats), response_keys), &streaming)| {
+                    let call = quote! {
+                        #service_ident::#method_ident(self.service, ctx, #( #arg_pats ),*)
+                    };
+                    if streaming {
+                        quote! {
+                            tarpc::server::Served::Stream(Box::pin(
+                                #response_stream_ident::<S>::#camel_case_ident(#call)
+                            ))
+                        }
+                    } else {
+                        quote! {
+                            tarpc::server::Served::Response(
+                                #response_fut_ident::#camel_case_ident(#call #response_keys)
+                            )
+                        }
+                    }
+                },
+            )
+            .collect::<Vec<_>>();
+
+        // Server-streaming handlers are boxed, so they must be sendable.
+        let (_, stream_types) = self.rpcs_where_streaming(true);
+        let bounds = if stream_types.is_empty() {
+            quote!(S: #service_ident)
+        } else {
+            quote! {
+                S: #service_ident + 'static,
+                #( <S as #service_ident>::#stream_types: Send + 'static, )*
+            }
+        };
+
+        let (item_idents, _) = self.client_streaming_rpcs();
+        let item_idents = item_idents.iter().map(|(_, item_ident)| item_ident);
+        let stream_args = self
+            .stream_arg_pats
+            .iter()
+            .zip(self.stream_item_idents)
+            .map(|(stream_arg_pat, item_ident)| {
+                stream_arg_pat.map(|stream_arg_pat| {
+                    quote! {
+                        let #stream_arg_pat = items.items(|req| match req {
+                            #request_ident::#item_ident(item) => Some(item),
+                            _ => None,
+                        });
+                    }
+                })
+            });
 
-                fn serve(self, ctx: tarpc::context::Context, req: #request_ident) -> Self::Fut {
+        quote! {
+            impl<S> #server_ident<S>
+                where #bounds
+            {
+                #[allow(unused_variables)]
+                fn serve_request(
+                    self,
+                    ctx: tarpc::context::Context,
+                    req: #request_ident,
+                    items: tarpc::server::RequestStream<#request_ident>,
+                ) -> tarpc::server::Served<#unary_fut_ty>
+                {
                     match req {
                         #(
-                            #request_ident::#camel_case_idents{ #( #arg_pats ),* } => {
-                                #response_fut_ident::#camel_case_idents(
-                                    #service_ident::#method_idents(
-                                        self.service, ctx, #( #arg_pats ),*
-                                    )
-                                )
+                            #request_ident::#camel_case_idents{ #( #request_arg_pats ),* } => {
+                                #injections
+                                #decryptions
+                                #stream_args
+                                #handlers
+                            }
+                        )*
+                        #(
+                            #request_ident::#item_idents(_) => {
+                                tarpc::server::Served::Error(tarpc::ServerError::new(
+                                    std::io::ErrorKind::InvalidInput,
+                                    "stream items must follow their client-streaming request",
+                                ))
                             }
                         )*
                     }
                 }
             }
-        }
+
+            impl<S> tarpc::server::Serve<#request_ident> for #server_ident<S>
+                where #bounds
+            {
+                type Resp = #response_ident;
+                type Fut = #unary_fut_ty;
+
+                fn method(&self, req: &#request_ident) -> Option<&'static str> {
+                    Some(req.method_name())
+                }
+
+                fn serve_with_items(
+                    self,
+                    ctx: tarpc::context::Context,
+                    req: #request_ident,
+                    items: tarpc::server::RequestStream<#request_ident>,
+                ) -> tarpc::server::Served<Self::Fut>
+                {
+                    self.serve_request(ctx, req, items)
+                }
+            }
+        }
+    }
+
+    /// Returns the camel-case idents and stream item variant idents, along with the request
+    /// names, of the client-streaming rpcs.
+    #[allow(clippy::type_complexity)]
+    fn client_streaming_rpcs(&self) -> (Vec<(&Ident, &Ident)>, Vec<&String>) {
+        self.camel_case_idents
+            .iter()
+            .zip(self.stream_item_idents)
+            .zip(self.request_names)
+            .zip(self.request_item_types)
+            .filter(|(_, item_type)| item_type.is_some())
+            .map(|(pair, _)| pair)
+            .unzip()
     }
 
     fn enum_request(&self) -> TokenStream2 {
@@ -572,9 +1456,28 @@ impl<'a> ServiceGenerator<'a> {
             vis,
             request_ident,
             camel_case_idents,
-            args,
+            request_fields,
             ..
         } = self;
+        let (item_idents, item_types): (Vec<_>, Vec<_>) = self
+            .stream_item_idents
+            .iter()
+            .zip(self.request_item_types)
+            .filter_map(|(item_ident, item_type)| Some((item_ident, (*item_type)?)))
+            .unzip();
+
+        let (derive_serialize, impl_serde) = match (derive_serialize, self.wire_ids) {
+            (Some(_), Some(ids)) => (None, self.impl_serde_for_request(ids)),
+            _ => (derive_serialize, TokenStream2::new()),
+        };
+        let request_names = self.request_names;
+        let (item_rpcs, item_request_names) = self.client_streaming_rpcs();
+        let item_variants = item_rpcs.iter().map(|(_, item_ident)| item_ident);
+        let field_names = request_fields
+            .iter()
+            .map(|fields| fields.iter().map(|field| &*field.pat).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let field_types = request_fields.iter().flatten().map(|field| &*field.ty);
 
         quote! {
             /// The request sent over the wire from the client to the server.
@@ -582,8 +1485,50 @@ impl<'a> ServiceGenerator<'a> {
             #[derive(Debug)]
             #derive_serialize
             #vis enum #request_ident {
-                #( #camel_case_idents{ #( #args ),* } ),*
+                #( #camel_case_idents{ #( #request_fields ),* }, )*
+                #( #item_idents(#item_types), )*
             }
+
+            impl #request_ident {
+                /// The names of the service's methods, e.g. `World.hello`, in the order they are
+                /// declared.
+                #vis const METHODS: &'static [&'static str] = &[ #( #request_names ),* ];
+
+                /// Returns the name of the method the request calls, e.g. `World.hello`. The
+                /// items of a client-streaming request are named by the method of the request.
+                #vis fn method_name(&self) -> &'static str {
+                    match *self {
+                        #( #request_ident::#camel_case_idents{..} => #request_names, )*
+                        #( #request_ident::#item_variants(..) => #item_request_names, )*
+                    }
+                }
+            }
+
+            // Requests are cloneable if their fields are, e.g. to be retried on a failover
+            // channel. The bounds are higher-ranked so that they're only checked where a clone
+            // is needed.
+            impl ::core::clone::Clone for #request_ident
+            where
+                #( for<'__a> #field_types: ::core::clone::Clone, )*
+                #( for<'__a> #item_types: ::core::clone::Clone, )*
+            {
+                fn clone(&self) -> Self {
+                    match *self {
+                        #(
+                            #request_ident::#camel_case_idents{ #( ref #field_names ),* } =>
+                                #request_ident::#camel_case_idents{
+                                    #( #field_names: ::core::clone::Clone::clone(#field_names) ),*
+                                },
+                        )*
+                        #(
+                            #request_ident::#item_idents(ref item) =>
+                                #request_ident::#item_idents(::core::clone::Clone::clone(item)),
+                        )*
+                    }
+                }
+            }
+
+            #impl_serde
         }
     }
 
@@ -593,36 +1538,273 @@ impl<'a> ServiceGenerator<'a> {
             vis,
             response_ident,
             camel_case_idents,
-            return_types,
+            response_types,
             ..
         } = self;
 
+        let (derive_serialize, impl_serde) = match (derive_serialize, self.wire_ids) {
+            (Some(_), Some(ids)) => (None, self.impl_serde_for_response(ids)),
+            _ => (derive_serialize, TokenStream2::new()),
+        };
+
         quote! {
             /// The response sent over the wire from the server to the client.
             #[allow(missing_docs)]
             #[derive(Debug)]
             #derive_serialize
             #vis enum #response_ident {
-                #( #camel_case_idents(#return_types) ),*
+                #( #camel_case_idents(#response_types) ),*
+            }
+
+            #impl_serde
+        }
+    }
+
+    /// Serializes requests as their rpcs' wire tags; the items of client-streaming rpcs are tagged
+    /// with the rpc's tag plus 2^31. The fields of each request are serialized as a struct.
+    fn impl_serde_for_request(&self, ids: &[u32]) -> TokenStream2 {
+        let &Self {
+            request_ident,
+            camel_case_idents,
+            request_fields,
+            request_item_types,
+            stream_item_idents,
+            ..
+        } = self;
+
+        let field_names = request_fields
+            .iter()
+            .map(|fields| fields.iter().map(|field| &*field.pat).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let field_types = request_fields
+            .iter()
+            .map(|fields| fields.iter().map(|field| &*field.ty).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let lifetimes = request_fields
+            .iter()
+            .map(|fields| (!fields.is_empty()).then(|| quote!(<'__a>)))
+            .collect::<Vec<_>>();
+        let (item_idents, item_ids): (Vec<_>, Vec<_>) = stream_item_idents
+            .iter()
+            .zip(ids)
+            .zip(request_item_types)
+            .filter(|(_, item_type)| item_type.is_some())
+            .map(|((item_ident, id), _)| (item_ident, id | 1 << 31))
+            .unzip();
+        let names = camel_case_idents
+            .iter()
+            .chain(item_idents.iter().copied())
+            .map(|ident| ident.to_string())
+            .collect::<Vec<_>>();
+        let tags = ids.iter().chain(&item_ids).collect::<Vec<_>>();
+        let indices = 0..camel_case_idents.len();
+        let item_indices = camel_case_idents.len()..names.len();
+        let enum_name = request_ident.to_string();
+        let variant_names = &names[..camel_case_idents.len()];
+        let item_names = &names[camel_case_idents.len()..];
+
+        quote! {
+            impl tarpc::serde::Serialize for #request_ident {
+                fn serialize<S>(&self, serializer: S) -> ::core::result::Result<S::Ok, S::Error>
+                where
+                    S: tarpc::serde::Serializer,
+                {
+                    match self {
+                        #(
+                            #request_ident::#camel_case_idents { #( #field_names ),* } => {
+                                #[derive(tarpc::serde::Serialize)]
+                                #[serde(crate = "tarpc::serde")]
+                                struct #camel_case_idents #lifetimes {
+                                    #( #field_names: &'__a #field_types ),*
+                                }
+                                serializer.serialize_newtype_variant(
+                                    #enum_name,
+                                    #ids,
+                                    #variant_names,
+                                    &#camel_case_idents { #( #field_names ),* },
+                                )
+                            }
+                        )*
+                        #(
+                            #request_ident::#item_idents(item) => serializer
+                                .serialize_newtype_variant(#enum_name, #item_ids, #item_names, item),
+                        )*
+                    }
+                }
+            }
+
+            impl<'de> tarpc::serde::Deserialize<'de> for #request_ident {
+                fn deserialize<D>(deserializer: D) -> ::core::result::Result<Self, D::Error>
+                where
+                    D: tarpc::serde::Deserializer<'de>,
+                {
+                    const NAMES: &[&str] = &[ #( #names ),* ];
+                    const TAGS: &[u32] = &[ #( #tags ),* ];
+
+                    struct Visitor;
+
+                    impl<'de> tarpc::serde::de::Visitor<'de> for Visitor {
+                        type Value = #request_ident;
+
+                        fn expecting(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                            f.write_str(concat!("enum ", #enum_name))
+                        }
+
+                        fn visit_enum<A>(self, data: A)
+                            -> ::core::result::Result<#request_ident, A::Error>
+                        where
+                            A: tarpc::serde::de::EnumAccess<'de>,
+                        {
+                            let (variant, access) = tarpc::serde::de::EnumAccess::variant_seed(
+                                data,
+                                tarpc::VariantTag { tags: TAGS, names: NAMES },
+                            )?;
+                            match variant {
+                                #(
+                                    #indices => {
+                                        #[derive(tarpc::serde::Deserialize)]
+                                        #[serde(crate = "tarpc::serde")]
+                                        struct #camel_case_idents {
+                                            #( #field_names: #field_types ),*
+                                        }
+                                        let #camel_case_idents { #( #field_names ),* } =
+                                            tarpc::serde::de::VariantAccess::newtype_variant(access)?;
+                                        Ok(#request_ident::#camel_case_idents { #( #field_names ),* })
+                                    }
+                                )*
+                                #(
+                                    #item_indices => tarpc::serde::de::VariantAccess::newtype_variant(access)
+                                        .map(#request_ident::#item_idents),
+                                )*
+                                _ => unreachable!(),
+                            }
+                        }
+                    }
+
+                    deserializer.deserialize_enum(#enum_name, NAMES, Visitor)
+                }
             }
         }
     }
 
+    /// Serializes responses as their rpcs' wire tags.
+    fn impl_serde_for_response(&self, ids: &[u32]) -> TokenStream2 {
+        let &Self {
+            response_ident,
+            camel_case_idents,
+            ..
+        } = self;
+
+        let names = camel_case_idents
+            .iter()
+            .map(|ident| ident.to_string())
+            .collect::<Vec<_>>();
+        let indices = 0..camel_case_idents.len();
+        let enum_name = response_ident.to_string();
+
+        quote! {
+            impl tarpc::serde::Serialize for #response_ident {
+                fn serialize<S>(&self, serializer: S) -> ::core::result::Result<S::Ok, S::Error>
+                where
+                    S: tarpc::serde::Serializer,
+                {
+                    match self {
+                        #(
+                            #response_ident::#camel_case_idents(response) => serializer
+                                .serialize_newtype_variant(#enum_name, #ids, #names, response),
+                        )*
+                    }
+                }
+            }
+
+            impl<'de> tarpc::serde::Deserialize<'de> for #response_ident {
+                fn deserialize<D>(deserializer: D) -> ::core::result::Result<Self, D::Error>
+                where
+                    D: tarpc::serde::Deserializer<'de>,
+                {
+                    const NAMES: &[&str] = &[ #( #names ),* ];
+                    const TAGS: &[u32] = &[ #( #ids ),* ];
+
+                    struct Visitor;
+
+                    impl<'de> tarpc::serde::de::Visitor<'de> for Visitor {
+                        type Value = #response_ident;
+
+                        fn expecting(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                            f.write_str(concat!("enum ", #enum_name))
+                        }
+
+                        fn visit_enum<A>(self, data: A)
+                            -> ::core::result::Result<#response_ident, A::Error>
+                        where
+                            A: tarpc::serde::de::EnumAccess<'de>,
+                        {
+                            let (variant, access) = tarpc::serde::de::EnumAccess::variant_seed(
+                                data,
+                                tarpc::VariantTag { tags: TAGS, names: NAMES },
+                            )?;
+                            match variant {
+                                #(
+                                    #indices => tarpc::serde::de::VariantAccess::newtype_variant(access)
+                                        .map(#response_ident::#camel_case_idents),
+                                )*
+                                _ => unreachable!(),
+                            }
+                        }
+                    }
+
+                    deserializer.deserialize_enum(#enum_name, NAMES, Visitor)
+                }
+            }
+        }
+    }
+
+    /// Returns the camel-case idents and associated types of the rpcs that are, or are not,
+    /// server-streaming.
+    fn rpcs_where_streaming(&self, streaming: bool) -> (Vec<&Ident>, Vec<&Type>) {
+        self.camel_case_idents
+            .iter()
+            .zip(self.future_types)
+            .zip(self.streaming)
+            .filter(|(_, &is_streaming)| is_streaming == streaming)
+            .map(|(pair, _)| pair)
+            .unzip()
+    }
+
+    /// Returns the rpcs that aren't server-streaming.
+    fn unary_rpcs(&self) -> impl Iterator<Item = &RpcMethod> {
+        self.rpcs
+            .iter()
+            .zip(self.streaming)
+            .filter(|(_, &streaming)| !streaming)
+            .map(|(rpc, _)| rpc)
+    }
+
     fn enum_response_future(&self) -> TokenStream2 {
         let &Self {
             vis,
             service_ident,
             response_fut_ident,
-            camel_case_idents,
-            future_types,
             ..
         } = self;
+        let (camel_case_idents, future_types) = self.rpcs_where_streaming(false);
+        if camel_case_idents.is_empty() {
+            return quote!();
+        }
+        // Futures of encrypted responses carry the keys to encrypt them with.
+        let keys = self.unary_rpcs().map(|rpc| {
+            if rpc.encrypted_response {
+                quote!(, tarpc::encryption::Keys)
+            } else {
+                quote!()
+            }
+        });
 
         quote! {
             /// A future resolving to a server response.
             #[allow(missing_docs)]
             #vis enum #response_fut_ident<S: #service_ident> {
-                #( #camel_case_idents(<S as #service_ident>::#future_types) ),*
+                #( #camel_case_idents(<S as #service_ident>::#future_types #keys) ),*
             }
         }
     }
@@ -634,6 +1816,9 @@ impl<'a> ServiceGenerator<'a> {
             response_fut_name,
             ..
         } = self;
+        if !self.streaming.iter().any(|&streaming| !streaming) {
+            return quote!();
+        }
 
         quote! {
             impl<S: #service_ident> std::fmt::Debug for #response_fut_ident<S> {
@@ -649,9 +1834,40 @@ impl<'a> ServiceGenerator<'a> {
             service_ident,
             response_fut_ident,
             response_ident,
-            camel_case_idents,
             ..
         } = self;
+        let (camel_case_idents, _) = self.rpcs_where_streaming(false);
+        if camel_case_idents.is_empty() {
+            return quote!();
+        }
+        let arms = self
+            .unary_rpcs()
+            .zip(&camel_case_idents)
+            .zip(
+                self.request_names
+                    .iter()
+                    .zip(self.streaming)
+                    .filter(|(_, &s)| !s),
+            )
+            .map(|((rpc, camel_case_ident), (request_name, _))| {
+                if rpc.encrypted_response {
+                    quote! {
+                        #response_fut_ident::#camel_case_ident(resp, keys) =>
+                            std::pin::Pin::new_unchecked(resp)
+                                .poll(cx)
+                                .map(|resp| #response_ident::#camel_case_ident(
+                                    keys.seal(#request_name, &resp)
+                                )),
+                    }
+                } else {
+                    quote! {
+                        #response_fut_ident::#camel_case_ident(resp) =>
+                            std::pin::Pin::new_unchecked(resp)
+                                .poll(cx)
+                                .map(#response_ident::#camel_case_ident),
+                    }
+                }
+            });
 
         quote! {
             impl<S: #service_ident> std::future::Future for #response_fut_ident<S> {
@@ -659,14 +1875,84 @@ impl<'a> ServiceGenerator<'a> {
 
                 fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>)
                     -> std::task::Poll<#response_ident>
+                {
+                    unsafe {
+                        match std::pin::Pin::get_unchecked_mut(self) {
+                            #( #arms )*
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn enum_response_stream(&self) -> TokenStream2 {
+        let &Self {
+            vis,
+            service_ident,
+            response_stream_ident,
+            ..
+        } = self;
+        let (camel_case_idents, stream_types) = self.rpcs_where_streaming(true);
+        if camel_case_idents.is_empty() {
+            return quote!();
+        }
+
+        quote! {
+            /// A stream of server responses to a server-streaming request.
+            #[allow(missing_docs)]
+            #vis enum #response_stream_ident<S: #service_ident> {
+                #( #camel_case_idents(<S as #service_ident>::#stream_types) ),*
+            }
+        }
+    }
+
+    fn impl_debug_for_response_stream(&self) -> TokenStream2 {
+        let &Self {
+            service_ident,
+            response_stream_ident,
+            response_stream_name,
+            ..
+        } = self;
+        if !self.streaming.iter().any(|&streaming| streaming) {
+            return quote!();
+        }
+
+        quote! {
+            impl<S: #service_ident> std::fmt::Debug for #response_stream_ident<S> {
+                fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    fmt.debug_struct(#response_stream_name).finish()
+                }
+            }
+        }
+    }
+
+    fn impl_stream_for_response_stream(&self) -> TokenStream2 {
+        let &Self {
+            service_ident,
+            response_stream_ident,
+            response_ident,
+            ..
+        } = self;
+        let (camel_case_idents, _) = self.rpcs_where_streaming(true);
+        if camel_case_idents.is_empty() {
+            return quote!();
+        }
+
+        quote! {
+            impl<S: #service_ident> tarpc::futures::Stream for #response_stream_ident<S> {
+                type Item = #response_ident;
+
+                fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>)
+                    -> std::task::Poll<Option<#response_ident>>
                 {
                     unsafe {
                         match std::pin::Pin::get_unchecked_mut(self) {
                             #(
-                                #response_fut_ident::#camel_case_idents(resp) =>
+                                #response_stream_ident::#camel_case_idents(resp) =>
                                     std::pin::Pin::new_unchecked(resp)
-                                        .poll(cx)
-                                        .map(#response_ident::#camel_case_idents),
+                                        .poll_next(cx)
+                                        .map(|item| item.map(#response_ident::#camel_case_idents)),
                             )*
                         }
                     }
@@ -689,7 +1975,10 @@ impl<'a> ServiceGenerator<'a> {
             #[derive(Clone, Debug)]
             /// The client stub that makes RPC calls to the server. All request methods return
             /// [Futures](std::future::Future).
-            #vis struct #client_ident(tarpc::client::Channel<#request_ident, #response_ident>);
+            #vis struct #client_ident(
+                tarpc::client::Channel<#request_ident, #response_ident>,
+                Option<tarpc::encryption::Keys>,
+            );
         }
     }
 
@@ -701,6 +1990,17 @@ impl<'a> ServiceGenerator<'a> {
             response_ident,
             ..
         } = self;
+        let with_keys = if self.rpcs.iter().any(RpcMethod::is_encrypted) {
+            quote! {
+                /// Returns a client that encrypts and decrypts the `#[encrypted]` fields of rpcs
+                /// with `keys`.
+                #vis fn with_keys(self, keys: tarpc::encryption::Keys) -> Self {
+                    #client_ident(self.0, Some(keys))
+                }
+            }
+        } else {
+            quote!()
+        };
 
         quote! {
             impl #client_ident {
@@ -711,15 +2011,55 @@ impl<'a> ServiceGenerator<'a> {
                         tarpc::client::RequestDispatch<#request_ident, #response_ident, T>
                     >
                 where
-                    T: tarpc::Transport<tarpc::ClientMessage<#request_ident>, tarpc::Response<#response_ident>>
+                    T: tarpc::Transport<tarpc::ClientMessage<#request_ident>, tarpc::ServerMessage<#response_ident>>
                 {
                     let new_client = tarpc::client::new(config, transport);
                     tarpc::client::NewClient {
-                        client: #client_ident(new_client.client),
+                        client: #client_ident(new_client.client, None),
                         dispatch: new_client.dispatch,
                     }
                 }
 
+                /// Returns a future that resolves once the client's dispatch terminates, with the
+                /// reason it terminated.
+                #vis fn closed(&self)
+                    -> impl std::future::Future<Output = tarpc::client::CloseReason> + Send + 'static
+                {
+                    self.0.closed()
+                }
+
+                /// Returns the capabilities the server announced in the handshake, if the client
+                /// was configured to open one and the server has answered.
+                #vis fn peer_capabilities(&self) -> Option<tarpc::capabilities::Capabilities> {
+                    self.0.peer_capabilities()
+                }
+
+                /// Sets the class of the transport the client sends requests over, so that
+                /// requests can select it with a [`tarpc::context::TransportHint`].
+                #vis fn with_transport_class(self, class: tarpc::context::TransportClass) -> Self {
+                    #client_ident(self.0.with_transport_class(class), self.1)
+                }
+
+                /// Returns a client that routes each request to this client or to `fallback`,
+                /// according to the transport hint of the request's context; see
+                /// [`Channel::with_transport_fallback`](tarpc::client::Channel::with_transport_fallback).
+                #vis fn with_transport_fallback(self, fallback: Self) -> Self {
+                    #client_ident(self.0.with_transport_fallback(fallback.0), self.1)
+                }
+
+                /// Returns a client that retries requests on `failover` when they fail with an
+                /// [unavailable](tarpc::client::RpcError::is_unavailable) error; see
+                /// [`Channel::with_failover`](tarpc::client::Channel::with_failover). Only
+                /// available if the requests of the service can be cloned.
+                #vis fn with_failover(self, failover: Self) -> Self
+                where
+                    for<'__a> #request_ident: std::clone::Clone,
+                {
+                    #client_ident(self.0.with_failover(failover.0), self.1)
+                }
+
+                #with_keys
+
             }
         }
     }
@@ -733,30 +2073,659 @@ impl<'a> ServiceGenerator<'a> {
             vis,
             method_idents,
             request_names,
-            args,
+            request_args: args,
             return_types,
-            arg_pats,
+            request_arg_pats: arg_pats,
             camel_case_idents,
+            streaming,
+            request_item_types,
+            stream_item_idents,
             ..
         } = self;
 
+        let methods = method_idents
+            .iter()
+            .zip(method_attrs)
+            .zip(args)
+            .zip(return_types)
+            .zip(arg_pats)
+            .zip(camel_case_idents)
+            .zip(request_names)
+            .zip(streaming)
+            .zip(request_item_types.iter().zip(stream_item_idents))
+            .zip(self.rpcs.iter().zip(self.encrypted_request_args))
+            .map(
+                |(
+                    (
+                        (
+                            (
+                                (((((method_ident, method_attrs), args), return_type), arg_pats), camel_case_ident),
+                                request_name,
+                            ),
+                            &streaming,
+                        ),
+                        (request_item_type, stream_item_ident),
+                    ),
+                    (rpc, encrypted_args),
+                )| {
+                    match (request_item_type, streaming) {
+                        (None, false) if rpc.one_way => quote! {
+                            #[allow(unused)]
+                            #( #method_attrs )*
+                            #vis fn #method_ident(&self, ctx: tarpc::context::Context, #( #args ),*)
+                                -> impl std::future::Future<Output = Result<(), tarpc::client::RpcError>> + '_ {
+                                let request = #request_ident::#camel_case_ident { #( #arg_pats ),* };
+                                self.0.send_one_way(ctx, #request_name, request)
+                            }
+                        },
+                        (None, false) if rpc.is_encrypted() => {
+                            let pats = encrypted_args.iter().map(|arg| &arg.pat);
+                            let fields = encrypted_args
+                                .iter()
+                                .map(|arg| encrypted_field_name(request_name, arg));
+                            let (keys, response) = if rpc.encrypted_response {
+                                (
+                                    quote!(keys),
+                                    quote! {
+                                        keys.open(#request_name, msg).map_err(|e| {
+                                            tarpc::client::RpcError::Encryption(e.to_string())
+                                        })
+                                    },
+                                )
+                            } else {
+                                (quote!(_), quote!(std::result::Result::Ok(msg)))
+                            };
+                            quote! {
+                                #[allow(unused)]
+                                #( #method_attrs )*
+                                #vis fn #method_ident(&self, ctx: tarpc::context::Context, #( #args ),*)
+                                    -> impl std::future::Future<Output = Result<#return_type, tarpc::client::RpcError>> + '_ {
+                                    let keys = self.1.clone().ok_or_else(|| {
+                                        tarpc::client::RpcError::Encryption(format!(
+                                            "no keys to encrypt `{}` with", #request_name
+                                        ))
+                                    });
+                                    let resp = keys.map(|keys| {
+                                        #( let #pats = keys.seal(#fields, &#pats); )*
+                                        let request = #request_ident::#camel_case_ident { #( #arg_pats ),* };
+                                        (keys, self.0.call(ctx, #request_name, request))
+                                    });
+                                    async move {
+                                        let (#keys, resp) = resp?;
+                                        match resp.await? {
+                                            #response_ident::#camel_case_ident(msg) => #response,
+                                            _ => unreachable!(),
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        (Some(request_item_type), true) => quote! {
+                            #[allow(unused)]
+                            #( #method_attrs )*
+                            #vis fn #method_ident(&self, ctx: tarpc::context::Context, #( #args ),*)
+                                -> impl std::future::Future<Output = Result<(
+                                    impl tarpc::futures::Sink<#request_item_type, Error = tarpc::client::RpcError>,
+                                    impl tarpc::futures::Stream<Item = Result<#return_type, tarpc::client::RpcError>>,
+                                ), tarpc::client::RpcError>> + '_ {
+                                let request = #request_ident::#camel_case_ident { #( #arg_pats ),* };
+                                let resp = self.0.call_bidi_stream(ctx, #request_name, request);
+                                async move {
+                                    let (items, stream) = resp.await?;
+                                    let items = tarpc::futures::SinkExt::with(items, |item: #request_item_type| {
+                                        std::future::ready(Ok::<_, tarpc::client::RpcError>(
+                                            #request_ident::#stream_item_ident(item)
+                                        ))
+                                    });
+                                    let stream = tarpc::futures::stream::StreamExt::map(stream, |resp| {
+                                        resp.map(|resp| match resp {
+                                            #response_ident::#camel_case_ident(msg) => msg,
+                                            _ => unreachable!(),
+                                        })
+                                    });
+                                    Ok((items, stream))
+                                }
+                            }
+                        },
+                        (Some(request_item_type), false) => quote! {
+                            #[allow(unused)]
+                            #( #method_attrs )*
+                            #vis fn #method_ident(&self, ctx: tarpc::context::Context, #( #args ),*)
+                                -> impl std::future::Future<Output = Result<(
+                                    impl tarpc::futures::Sink<#request_item_type, Error = tarpc::client::RpcError>,
+                                    impl std::future::Future<Output = Result<#return_type, tarpc::client::RpcError>>,
+                                ), tarpc::client::RpcError>> + '_ {
+                                let request = #request_ident::#camel_case_ident { #( #arg_pats ),* };
+                                let resp = self.0.call_client_stream(ctx, #request_name, request);
+                                async move {
+                                    let (items, response) = resp.await?;
+                                    let items = tarpc::futures::SinkExt::with(items, |item: #request_item_type| {
+                                        std::future::ready(Ok::<_, tarpc::client::RpcError>(
+                                            #request_ident::#stream_item_ident(item)
+                                        ))
+                                    });
+                                    let response = async move {
+                                        match response.await? {
+                                            #response_ident::#camel_case_ident(msg) => std::result::Result::Ok(msg),
+                                            _ => unreachable!(),
+                                        }
+                                    };
+                                    Ok((items, response))
+                                }
+                            }
+                        },
+                        (None, true) => quote! {
+                            #[allow(unused)]
+                            #( #method_attrs )*
+                            #vis fn #method_ident(&self, ctx: tarpc::context::Context, #( #args ),*)
+                                -> impl std::future::Future<Output = Result<
+                                    impl tarpc::futures::Stream<Item = Result<#return_type, tarpc::client::RpcError>>,
+                                    tarpc::client::RpcError
+                                >> + '_ {
+                                let request = #request_ident::#camel_case_ident { #( #arg_pats ),* };
+                                let resp = self.0.call_stream(ctx, #request_name, request);
+                                async move {
+                                    let stream = resp.await?;
+                                    Ok(tarpc::futures::stream::StreamExt::map(stream, |resp| {
+                                        resp.map(|resp| match resp {
+                                            #response_ident::#camel_case_ident(msg) => msg,
+                                            _ => unreachable!(),
+                                        })
+                                    }))
+                                }
+                            }
+                        },
+                        (None, false) if rpc.app_error.is_some() => {
+                            let (ok, err) = rpc.app_error.as_ref().unwrap();
+                            quote! {
+                                #[allow(unused)]
+                                #( #method_attrs )*
+                                #vis fn #method_ident(&self, ctx: tarpc::context::Context, #( #args ),*)
+                                    -> impl std::future::Future<Output = Result<#ok, tarpc::client::CallError<#err>>> + '_ {
+                                    let request = #request_ident::#camel_case_ident { #( #arg_pats ),* };
+                                    let resp = self.0.call(ctx, #request_name, request);
+                                    async move {
+                                        match resp.await? {
+                                            #response_ident::#camel_case_ident(msg) => {
+                                                msg.map_err(tarpc::client::CallError::App)
+                                            }
+                                            _ => unreachable!(),
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        (None, false) => quote! {
+                            #[allow(unused)]
+                            #( #method_attrs )*
+                            #vis fn #method_ident(&self, ctx: tarpc::context::Context, #( #args ),*)
+                                -> impl std::future::Future<Output = Result<#return_type, tarpc::client::RpcError>> + '_ {
+                                let request = #request_ident::#camel_case_ident { #( #arg_pats ),* };
+                                let resp = self.0.call(ctx, #request_name, request);
+                                async move {
+                                    match resp.await? {
+                                        #response_ident::#camel_case_ident(msg) => std::result::Result::Ok(msg),
+                                        _ => unreachable!(),
+                                    }
+                                }
+                            }
+                        },
+                    }
+                },
+            );
+
         quote! {
             impl #client_ident {
-                #(
+                #( #methods )*
+            }
+        }
+    }
+
+    fn impl_client_paged_methods(&self) -> TokenStream2 {
+        let &Self {
+            client_ident,
+            vis,
+            method_idents,
+            request_args,
+            ..
+        } = self;
+
+        let methods = self
+            .rpcs
+            .iter()
+            .zip(method_idents)
+            .zip(request_args)
+            .filter_map(|((rpc, method_ident), args)| {
+                let page_item = rpc.page_item.as_ref()?;
+                let (_, args) = args.split_last()?;
+                let pats = args.iter().map(|arg| &arg.pat);
+                let pages_ident = format_ident!("{}_pages", method_ident);
+                let doc = format!(
+                    " Streams the items of every page of [`{method_ident}`](Self::{method_ident}), \
+                     fetching each page once the items of the previous page are consumed. The \
+                     args are cloned for each page, and every page is fetched with `ctx`."
+                );
+                Some(quote! {
                     #[allow(unused)]
-                    #( #method_attrs )*
-                    #vis fn #method_idents(&self, ctx: tarpc::context::Context, #( #args ),*)
-                        -> impl std::future::Future<Output = Result<#return_types, tarpc::client::RpcError>> + '_ {
-                        let request = #request_ident::#camel_case_idents { #( #arg_pats ),* };
-                        let resp = self.0.call(ctx, #request_names, request);
-                        async move {
-                            match resp.await? {
-                                #response_ident::#camel_case_idents(msg) => std::result::Result::Ok(msg),
-                                _ => unreachable!(),
-                            }
+                    #[doc = #doc]
+                    #vis fn #pages_ident(&self, ctx: tarpc::context::Context, #( #args ),*)
+                        -> impl tarpc::futures::Stream<Item = Result<#page_item, tarpc::client::RpcError>> + '_ {
+                        tarpc::paging::pages(move |page| {
+                            self.#method_ident(ctx, #( std::clone::Clone::clone(&#pats), )* page)
+                        })
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        if methods.is_empty() {
+            return TokenStream2::new();
+        }
+
+        quote! {
+            impl #client_ident {
+                #( #methods )*
+            }
+        }
+    }
+
+    fn struct_erased_client(&self) -> TokenStream2 {
+        let erased_client_ident = match self.erased_client_ident {
+            Some(ident) => ident,
+            None => return TokenStream2::new(),
+        };
+        let &Self {
+            client_ident,
+            request_ident,
+            response_ident,
+            vis,
+            method_attrs,
+            method_idents,
+            request_args,
+            request_arg_pats,
+            return_types,
+            streaming,
+            request_item_types,
+            ..
+        } = self;
+        let rpc_error = quote!(tarpc::client::RpcError);
+        let boxed = |ty: TokenStream2| quote!(tarpc::futures::future::BoxFuture<'static, Result<#ty, #rpc_error>>);
+        let boxed_stream =
+            |ty: &Type| quote!(tarpc::futures::stream::BoxStream<'static, Result<#ty, #rpc_error>>);
+        let boxed_sink = |ty: &Type| quote!(std::pin::Pin<Box<dyn tarpc::futures::Sink<#ty, Error = #rpc_error> + Send>>);
+
+        let methods = method_idents
+            .iter()
+            .zip(method_attrs)
+            .zip(request_args.iter().zip(request_arg_pats))
+            .zip(return_types.iter().zip(streaming))
+            .zip(request_item_types.iter().zip(self.rpcs))
+            .map(
+                |(
+                    (((method_ident, method_attrs), (args, arg_pats)), (&return_type, &streaming)),
+                    (request_item_type, rpc),
+                )| {
+                    let (output, convert) = match (request_item_type, streaming) {
+                        (None, false) => match &rpc.app_error {
+                            Some((ok, err)) => (
+                                quote! {
+                                    tarpc::futures::future::BoxFuture<
+                                        'static,
+                                        Result<#ok, tarpc::client::CallError<#err>>,
+                                    >
+                                },
+                                quote!(),
+                            ),
+                            None => (boxed(quote!(#return_type)), quote!()),
+                        },
+                        (None, true) => (
+                            boxed(boxed_stream(return_type)),
+                            quote!(.map(tarpc::futures::StreamExt::boxed)),
+                        ),
+                        (Some(item), false) => {
+                            let sink = boxed_sink(item);
+                            let response = boxed(quote!(#return_type));
+                            (
+                                boxed(quote!((#sink, #response))),
+                                quote! {
+                                    .map(|(items, response)| {
+                                        (Box::pin(items) as #sink, tarpc::futures::FutureExt::boxed(response))
+                                    })
+                                },
+                            )
+                        }
+                        (Some(item), true) => {
+                            let sink = boxed_sink(item);
+                            let stream = boxed_stream(return_type);
+                            (
+                                boxed(quote!((#sink, #stream))),
+                                quote! {
+                                    .map(|(items, stream)| {
+                                        (Box::pin(items) as #sink, tarpc::futures::StreamExt::boxed(stream))
+                                    })
+                                },
+                            )
+                        }
+                    };
+                    quote! {
+                        #[allow(unused)]
+                        #( #method_attrs )*
+                        #vis fn #method_ident(&self, ctx: tarpc::context::Context, #( #args ),*) -> #output {
+                            let client = self.0.clone();
+                            Box::pin(async move {
+                                client.#method_ident(ctx, #( #arg_pats ),*).await #convert
+                            })
+                        }
+                    }
+                },
+            );
+        let paged_methods = self
+            .rpcs
+            .iter()
+            .zip(method_idents)
+            .zip(request_args)
+            .filter_map(|((rpc, method_ident), args)| {
+                let page_item = rpc.page_item.as_ref()?;
+                let (_, args) = args.split_last()?;
+                let pats = args.iter().map(|arg| &arg.pat).collect::<Vec<_>>();
+                let pages_ident = format_ident!("{}_pages", method_ident);
+                let doc = format!(
+                    " Streams the items of every page of [`{method_ident}`](Self::{method_ident}); \
+                     see [`{client_ident}::{pages_ident}`]."
+                );
+                let output = boxed_stream(page_item);
+                Some(quote! {
+                    #[allow(unused)]
+                    #[doc = #doc]
+                    #vis fn #pages_ident(&self, ctx: tarpc::context::Context, #( #args ),*) -> #output {
+                        let client = self.0.clone();
+                        tarpc::futures::StreamExt::boxed(tarpc::paging::pages(move |page| {
+                            let client = client.clone();
+                            #( let #pats = std::clone::Clone::clone(&#pats); )*
+                            async move { client.#method_ident(ctx, #( #pats, )* page).await }
+                        }))
+                    }
+                })
+            });
+        let doc = format!(
+            " A [`{client_ident}`] whose rpcs return boxed futures and streams that are `Send` and \
+             `'static`, so that it can be stored and called without naming any of their types, \
+             e.g. in a plugin registry or a dependency-injection container. Like the client, it \
+             doesn't name the transport its requests are sent over."
+        );
+
+        quote! {
+            #[allow(unused)]
+            #[derive(Clone, Debug)]
+            #[doc = #doc]
+            #vis struct #erased_client_ident(#client_ident);
+
+            impl #erased_client_ident {
+                /// Returns a new client stub that sends requests over the given transport.
+                #vis fn new<T>(config: tarpc::client::Config, transport: T)
+                    -> tarpc::client::NewClient<
+                        Self,
+                        tarpc::client::RequestDispatch<#request_ident, #response_ident, T>
+                    >
+                where
+                    T: tarpc::Transport<tarpc::ClientMessage<#request_ident>, tarpc::ServerMessage<#response_ident>>
+                {
+                    let new_client = #client_ident::new(config, transport);
+                    tarpc::client::NewClient {
+                        client: #erased_client_ident(new_client.client),
+                        dispatch: new_client.dispatch,
+                    }
+                }
+
+                #( #methods )*
+                #( #paged_methods )*
+            }
+
+            impl From<#client_ident> for #erased_client_ident {
+                fn from(client: #client_ident) -> Self {
+                    #erased_client_ident(client)
+                }
+            }
+        }
+    }
+
+    fn impl_tower_service_for_client(&self) -> TokenStream2 {
+        if !cfg!(feature = "tower") {
+            return TokenStream2::new();
+        }
+
+        let &Self {
+            client_ident,
+            request_ident,
+            response_ident,
+            request_names,
+            camel_case_idents,
+            streaming,
+            request_item_types,
+            ..
+        } = self;
+
+        let (unary_idents, unary_names): (Vec<_>, Vec<_>) = camel_case_idents
+            .iter()
+            .zip(request_names)
+            .zip(streaming.iter().zip(request_item_types))
+            .filter(|(_, (&streaming, request_item_type))| {
+                !streaming && request_item_type.is_none()
+            })
+            .map(|(ident_and_name, _)| ident_and_name)
+            .unzip();
+
+        quote! {
+            impl tarpc::tower_service::Service<#request_ident> for #client_ident {
+                type Response = #response_ident;
+                type Error = tarpc::client::RpcError;
+                type Future = std::pin::Pin<Box<
+                    dyn std::future::Future<Output = Result<#response_ident, tarpc::client::RpcError>>
+                        + Send
+                >>;
+
+                fn poll_ready(&mut self, _: &mut std::task::Context<'_>)
+                    -> std::task::Poll<Result<(), tarpc::client::RpcError>> {
+                    std::task::Poll::Ready(Ok(()))
+                }
+
+                fn call(&mut self, request: #request_ident) -> Self::Future {
+                    let ctx = tarpc::context::current();
+                    let client = self.0.clone();
+                    Box::pin(async move {
+                        let request_name = match &request {
+                            #( #request_ident::#unary_idents { .. } => Some(#unary_names), )*
+                            #[allow(unreachable_patterns)]
+                            _ => None,
+                        };
+                        let request_name = match request_name {
+                            Some(request_name) => request_name,
+                            None => return Err(tarpc::client::RpcError::Server(tarpc::ServerError::new(
+                                std::io::ErrorKind::Unsupported,
+                                "streaming rpcs cannot be called through tower::Service",
+                            ))),
+                        };
+                        client.call(ctx, request_name, request).await
+                    })
+                }
+            }
+
+            impl tarpc::layers::Method for #request_ident {
+                fn method(&self) -> Option<&'static str> {
+                    Some(self.method_name())
+                }
+            }
+        }
+    }
+
+    fn impl_named_for_messages(&self) -> TokenStream2 {
+        if self.derive_serialize.is_none() {
+            return TokenStream2::new();
+        }
+
+        let &Self {
+            service_ident,
+            request_ident,
+            response_ident,
+            ..
+        } = self;
+        let service_name = service_ident.unraw().to_string();
+
+        quote! {
+            impl tarpc::router::Named for #request_ident {
+                const SERVICE: &'static str = #service_name;
+            }
+
+            impl tarpc::router::Named for #response_ident {
+                const SERVICE: &'static str = #service_name;
+            }
+        }
+    }
+
+    fn impl_reflect_for_request(&self) -> TokenStream2 {
+        if !cfg!(feature = "reflection") {
+            return TokenStream2::new();
+        }
+
+        let &Self {
+            service_ident,
+            request_ident,
+            request_names,
+            request_args,
+            request_item_types,
+            return_types,
+            streaming,
+            ..
+        } = self;
+        let service_name = service_ident.unraw().to_string();
+        let methods = request_names
+            .iter()
+            .zip(request_args)
+            .zip(request_item_types.iter().zip(return_types))
+            .zip(streaming)
+            .map(
+                |(((request_name, args), (request_item_type, return_type)), streaming)| {
+                    let arg_names = args.iter().map(|arg| match &*arg.pat {
+                        Pat::Ident(pat) => pat.ident.unraw().to_string(),
+                        _ => unreachable!("patterns aren't allowed in RPC args"),
+                    });
+                    let arg_types = args.iter().map(|arg| type_name(&arg.ty));
+                    let request_item = match request_item_type {
+                        Some(ty) => {
+                            let ty = type_name(ty);
+                            quote!(Some(std::borrow::Cow::Borrowed(#ty)))
+                        }
+                        None => quote!(None),
+                    };
+                    let response = type_name(return_type);
+                    quote! {
+                        tarpc::reflection::MethodDescriptor {
+                            name: std::borrow::Cow::Borrowed(#request_name),
+                            args: vec![
+                                #(
+                                    tarpc::reflection::ArgDescriptor {
+                                        name: std::borrow::Cow::Borrowed(#arg_names),
+                                        ty: std::borrow::Cow::Borrowed(#arg_types),
+                                    },
+                                )*
+                            ],
+                            request_item: #request_item,
+                            response: std::borrow::Cow::Borrowed(#response),
+                            server_streaming: #streaming,
                         }
                     }
-                )*
+                },
+            );
+
+        quote! {
+            impl tarpc::reflection::Reflect for #request_ident {
+                fn descriptor() -> tarpc::reflection::ServiceDescriptor {
+                    tarpc::reflection::ServiceDescriptor {
+                        name: std::borrow::Cow::Borrowed(#service_name),
+                        methods: vec![ #( #methods ),* ],
+                    }
+                }
+            }
+        }
+    }
+
+    fn impl_method_for_messages(&self) -> TokenStream2 {
+        if !cfg!(feature = "codec-metrics") {
+            return TokenStream2::new();
+        }
+
+        let &Self {
+            request_ident,
+            response_ident,
+            camel_case_idents,
+            request_names,
+            ..
+        } = self;
+
+        quote! {
+            impl tarpc::serde_transport::metrics::Method for #request_ident {
+                fn method(&self) -> Option<&'static str> {
+                    Some(self.method_name())
+                }
+            }
+
+            impl tarpc::serde_transport::metrics::Method for #response_ident {
+                fn method(&self) -> Option<&'static str> {
+                    Some(match self {
+                        #( #response_ident::#camel_case_idents(..) => #request_names, )*
+                    })
+                }
+            }
+        }
+    }
+
+    fn impl_json_rpc_methods_for_request(&self) -> TokenStream2 {
+        if !cfg!(feature = "json-rpc") || self.derive_serialize.is_none() {
+            return TokenStream2::new();
+        }
+
+        let &Self {
+            request_ident,
+            rpcs,
+            camel_case_idents,
+            request_fields,
+            streaming,
+            request_item_types,
+            ..
+        } = self;
+
+        // JSON-RPC clients can't stream, nor produce the ciphertext of encrypted args.
+        let (method_names, (variant_names, param_names)): (Vec<_>, (Vec<_>, Vec<Vec<_>>)) = rpcs
+            .iter()
+            .zip(camel_case_idents)
+            .zip(request_fields)
+            .zip(streaming.iter().zip(request_item_types))
+            .filter(|(((rpc, _), _), (&streaming, request_item_type))| {
+                !streaming && request_item_type.is_none() && !rpc.encrypted.contains(&true)
+            })
+            .map(|(((rpc, camel_case_ident), fields), _)| {
+                let params = fields
+                    .iter()
+                    .map(|field| match &*field.pat {
+                        Pat::Ident(pat) => pat.ident.unraw().to_string(),
+                        _ => unreachable!("patterns aren't allowed in RPC args"),
+                    })
+                    .collect();
+                (
+                    rpc.ident.unraw().to_string(),
+                    (camel_case_ident.to_string(), params),
+                )
+            })
+            .unzip();
+
+        quote! {
+            impl tarpc::server::json_rpc::Methods for #request_ident {
+                fn json_rpc_method(method: &str)
+                    -> Option<(&'static str, &'static [&'static str])> {
+                    match method {
+                        #( #method_names => Some((#variant_names, &[ #( #param_names ),* ])), )*
+                        _ => None,
+                    }
+                }
             }
         }
     }
@@ -773,13 +2742,44 @@ impl<'a> ToTokens for ServiceGenerator<'a> {
             self.enum_response_future(),
             self.impl_debug_for_response_future(),
             self.impl_future_for_response_future(),
+            self.enum_response_stream(),
+            self.impl_debug_for_response_stream(),
+            self.impl_stream_for_response_stream(),
             self.struct_client(),
             self.impl_client_new(),
             self.impl_client_rpc_methods(),
+            self.impl_client_paged_methods(),
+            self.struct_erased_client(),
+            self.impl_tower_service_for_client(),
+            self.impl_json_rpc_methods_for_request(),
+            self.impl_named_for_messages(),
+            self.impl_reflect_for_request(),
+            self.impl_method_for_messages(),
         ])
     }
 }
 
+/// Returns the name of a type as written, without the whitespace between its tokens, e.g.
+/// `Vec<String>` rather than `Vec < String >`.
+fn type_name(ty: &Type) -> String {
+    let tokens = ty.to_token_stream().to_string();
+    let mut name = String::with_capacity(tokens.len());
+    let mut chars = tokens.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ' ' {
+            let word_char = |c: char| c.is_alphanumeric() || c == '_';
+            let between_words =
+                name.ends_with(word_char) && chars.peek().copied().map_or(false, word_char);
+            if between_words || name.ends_with(',') {
+                name.push(' ');
+            }
+        } else {
+            name.push(c);
+        }
+    }
+    name
+}
+
 fn snake_to_camel(ident_str: &str) -> String {
     let mut camel_ty = String::with_capacity(ident_str.len());
 
@@ -1,5 +1,7 @@
+#![allow(non_local_definitions)]
+
 use assert_type_eq::assert_type_eq;
-use futures::Future;
+use futures::{Future, Stream};
 use std::pin::Pin;
 use tarpc::context;
 
@@ -48,6 +50,68 @@ fn type_generation_works() {
     }
 }
 
+#[tarpc::service]
+trait Streams {
+    async fn chars(s: String) -> impl Stream<Item = char>;
+    async fn only_streams(n: u32) -> impl Stream<Item = u32>;
+}
+
+#[test]
+fn stream_type_generation_works() {
+    #[tarpc::server]
+    impl Streams for () {
+        async fn chars(self, _: context::Context, s: String) -> impl Stream<Item = char> {
+            futures::stream::iter(s.chars().collect::<Vec<_>>())
+        }
+
+        async fn only_streams(self, _: context::Context, n: u32) -> impl Stream<Item = u32> {
+            futures::stream::iter(0..n)
+        }
+    }
+
+    {
+        assert_type_eq!(
+            <() as Streams>::CharsStream,
+            Pin<Box<dyn Stream<Item = char> + Send>>
+        );
+    }
+    {
+        assert_type_eq!(
+            <() as Streams>::OnlyStreamsStream,
+            Pin<Box<dyn Stream<Item = u32> + Send>>
+        );
+    }
+}
+
+#[tarpc::service]
+trait Uploads {
+    async fn upload(name: String, chunks: impl Stream<Item = Vec<u8>>) -> usize;
+}
+
+#[test]
+fn client_stream_arg_is_rewritten() {
+    #[tarpc::server]
+    impl Uploads for () {
+        async fn upload(
+            self,
+            _: context::Context,
+            _: String,
+            chunks: impl Stream<Item = Vec<u8>>,
+        ) -> usize {
+            use futures::StreamExt;
+            chunks
+                .map(|chunk| chunk.len())
+                .fold(0, |a, b| async move { a + b })
+                .await
+        }
+    }
+
+    assert_type_eq!(
+        <() as Uploads>::UploadFut,
+        Pin<Box<dyn Future<Output = usize> + Send>>
+    );
+}
+
 #[allow(non_camel_case_types)]
 #[test]
 fn raw_idents_work() {
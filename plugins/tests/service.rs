@@ -29,6 +29,83 @@ fn att_service_trait() {
     }
 }
 
+#[test]
+fn att_service_trait_streaming() {
+    use futures::{
+        future::{ready, Ready},
+        stream::{iter, Iter},
+    };
+
+    #[tarpc::service]
+    trait Foo {
+        async fn chars(s: String) -> impl Stream<Item = char>;
+        async fn len(s: String) -> usize;
+    }
+
+    impl Foo for () {
+        type CharsStream = Iter<std::vec::IntoIter<char>>;
+        fn chars(self, _: context::Context, s: String) -> Self::CharsStream {
+            iter(s.chars().collect::<Vec<_>>())
+        }
+
+        type LenFut = Ready<usize>;
+        fn len(self, _: context::Context, s: String) -> Self::LenFut {
+            ready(s.len())
+        }
+    }
+}
+
+#[test]
+fn att_service_trait_client_streaming() {
+    use futures::{future::BoxFuture, StreamExt};
+    use tarpc::server::RequestStream;
+
+    #[tarpc::service]
+    trait Foo {
+        async fn sum(start: u64, numbers: impl Stream<Item = u64>) -> u64;
+    }
+
+    impl Foo for () {
+        type SumFut = BoxFuture<'static, u64>;
+        fn sum(
+            self,
+            _: context::Context,
+            start: u64,
+            numbers: RequestStream<FooRequest, u64>,
+        ) -> Self::SumFut {
+            Box::pin(numbers.fold(start, |sum, n| async move { sum + n }))
+        }
+    }
+
+    // The stream arg is sent as separate items rather than as part of the request. Items are
+    // named by the method of their request.
+    assert_eq!(FooRequest::METHODS, ["Foo.sum"]);
+    assert_eq!(FooRequest::Sum { start: 0 }.method_name(), "Foo.sum");
+    assert_eq!(FooRequest::SumStreamItem(1).method_name(), "Foo.sum");
+}
+
+#[test]
+fn att_service_trait_bidi_streaming() {
+    use futures::StreamExt;
+    use tarpc::server::RequestStream;
+
+    #[tarpc::service]
+    trait Foo {
+        async fn double(numbers: impl Stream<Item = u64>) -> impl Stream<Item = u64>;
+    }
+
+    impl Foo for () {
+        type DoubleStream = futures::stream::Map<RequestStream<FooRequest, u64>, fn(u64) -> u64>;
+        fn double(
+            self,
+            _: context::Context,
+            numbers: RequestStream<FooRequest, u64>,
+        ) -> Self::DoubleStream {
+            numbers.map(|n| n * 2)
+        }
+    }
+}
+
 #[allow(non_camel_case_types)]
 #[test]
 fn raw_idents() {
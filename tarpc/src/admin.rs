@@ -0,0 +1,156 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides an administration service, so that operators can inspect a running tarpc server
+//! without redeploying it or raising its log level.
+//!
+//! A server serves an [`AdminServer`] as an [`Admin`] service, e.g.
+//! [mounted](crate::router::Router::mount) alongside its other services, and shares the state it
+//! exposes with its channels: for example, the [`RejectionLog`] set in
+//! [`Config::rejections`](crate::server::Config::rejections), or the [`FaultInjector`] set in
+//! [`Config::faults`](crate::server::Config::faults), whose rules operators can change at
+//! runtime, e.g. to validate the retries of canary clients.
+//!
+//! ```
+//! use tarpc::{admin::AdminServer, server::{self, RejectionLog}};
+//!
+//! let rejections = RejectionLog::default();
+//! let admin = AdminServer::new().with_rejections(rejections.clone());
+//! let config = server::Config::default().with_rejections(rejections);
+//! ```
+
+use crate::{
+    context,
+    server::{faults::FaultRule, rejections::Rejection, FaultInjector, RejectionLog},
+};
+use futures::future::{self, Ready};
+
+/// Reports the internal state of a server to its operators.
+#[tarpc_plugins::service]
+pub trait Admin {
+    /// Returns up to `limit` of the most recently rejected requests, newest first, or none if the
+    /// server doesn't log rejections.
+    async fn rejections(limit: u32) -> Vec<Rejection>;
+
+    /// Returns the rules the server injects faults by, in the order they are matched, or none if
+    /// the server doesn't inject faults.
+    async fn fault_rules() -> Vec<FaultRule>;
+
+    /// Replaces the rules the server injects faults by. Returns false, and changes nothing, if the
+    /// server doesn't inject faults.
+    async fn set_fault_rules(rules: Vec<FaultRule>) -> bool;
+}
+
+/// The state of a server exposed by the [`Admin`] service. Cheap to clone; clones share their
+/// state.
+///
+/// An admin server is served as an [`Admin`] service with [`serve`](Admin::serve).
+#[derive(Clone, Debug, Default)]
+pub struct AdminServer {
+    rejections: Option<RejectionLog>,
+    faults: Option<FaultInjector>,
+}
+
+impl AdminServer {
+    /// Returns an admin server that exposes no state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Exposes the rejections recorded in `log`.
+    pub fn with_rejections(mut self, log: RejectionLog) -> Self {
+        self.rejections = Some(log);
+        self
+    }
+
+    /// Exposes the rules of `faults`, and lets operators replace them.
+    pub fn with_faults(mut self, faults: FaultInjector) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+}
+
+impl Admin for AdminServer {
+    type RejectionsFut = Ready<Vec<Rejection>>;
+    type FaultRulesFut = Ready<Vec<FaultRule>>;
+    type SetFaultRulesFut = Ready<bool>;
+
+    fn rejections(self, _: context::Context, limit: u32) -> Self::RejectionsFut {
+        future::ready(
+            self.rejections
+                .map(|log| log.recent(limit as usize))
+                .unwrap_or_default(),
+        )
+    }
+
+    fn fault_rules(self, _: context::Context) -> Self::FaultRulesFut {
+        future::ready(self.faults.map(|faults| faults.rules()).unwrap_or_default())
+    }
+
+    fn set_fault_rules(self, _: context::Context, rules: Vec<FaultRule>) -> Self::SetFaultRulesFut {
+        future::ready(match self.faults {
+            Some(faults) => {
+                faults.set_rules(rules);
+                true
+            }
+            None => false,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "tokio1"))]
+mod tests {
+    use super::*;
+    use crate::{
+        client,
+        server::{rejections::RejectReason, BaseChannel, Channel},
+        transport::channel,
+    };
+
+    #[tokio::test]
+    async fn rejections_are_queryable() -> anyhow::Result<()> {
+        let log = RejectionLog::default();
+        let (tx, rx) = channel::unbounded();
+        tokio::spawn(
+            BaseChannel::with_defaults(rx)
+                .execute(AdminServer::new().with_rejections(log.clone()).serve()),
+        );
+        let client = AdminClient::new(client::Config::default(), tx).spawn();
+
+        assert!(client.rejections(context::current(), 10).await?.is_empty());
+        log.record(Rejection::new(RejectReason::Throttled).with_method("World.hello"));
+        log.record(Rejection::new(RejectReason::Invalid).with_peer("127.0.0.1:8080"));
+
+        let rejections = client.rejections(context::current(), 1).await?;
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].reason, RejectReason::Invalid);
+        assert_eq!(rejections[0].peer.as_deref(), Some("127.0.0.1:8080"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fault_rules_are_settable() -> anyhow::Result<()> {
+        let faults = FaultInjector::new();
+        let (tx, rx) = channel::unbounded();
+        tokio::spawn(
+            BaseChannel::with_defaults(rx)
+                .execute(AdminServer::new().with_faults(faults.clone()).serve()),
+        );
+        let client = AdminClient::new(client::Config::default(), tx).spawn();
+
+        let rules = vec![FaultRule::new(std::io::ErrorKind::ConnectionRefused, 0.01)
+            .with_method("World.hello")
+            .with_peer("canary")];
+        assert!(
+            client
+                .set_fault_rules(context::current(), rules.clone())
+                .await?
+        );
+        assert_eq!(faults.rules(), rules);
+        assert_eq!(client.fault_rules(context::current()).await?, rules);
+        Ok(())
+    }
+}
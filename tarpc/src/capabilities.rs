@@ -0,0 +1,174 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides the optional capabilities that peers exchange in a handshake, so that clients and
+//! servers can detect each other's features at runtime rather than by version number.
+//!
+//! A client configured with [`capabilities`](crate::client::Config::capabilities) announces them
+//! in a [`ClientMessage::Hello`](crate::ClientMessage::Hello) before its first request, and the
+//! server answers with its own [`capabilities`](crate::server::Config::capabilities) in a
+//! [`ServerMessage::Hello`](crate::ServerMessage::Hello). Each side then reads what its peer
+//! announced from its channel: [`client::Channel::peer_capabilities`] and
+//! [`server::Channel::peer_capabilities`].
+//!
+//! Clients don't handshake by default, so that they can talk to servers that predate
+//! handshakes.
+//!
+//! ```
+//! use tarpc::{capabilities::Capabilities, client};
+//!
+//! let mut config = client::Config::default();
+//! config.capabilities = Some(Capabilities::SUPPORTED | Capabilities::COMPRESSION);
+//! ```
+//!
+//! [`client::Channel::peer_capabilities`]: crate::client::Channel::peer_capabilities
+//! [`server::Channel::peer_capabilities`]: crate::server::Channel::peer_capabilities
+
+use std::{fmt, ops};
+
+/// A set of optional protocol capabilities.
+///
+/// Bits this version of tarpc doesn't know are kept, so that the capabilities of newer peers
+/// round-trip unchanged.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde1",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Server-streaming, client-streaming, and bidirectional-streaming rpcs.
+    pub const STREAMING: Self = Self(1 << 0);
+    /// Compressed frames, e.g. with a
+    /// [compression](crate::transport::compression) transport.
+    pub const COMPRESSION: Self = Self(1 << 1);
+    /// Acknowledgements of [cancellations](crate::ClientMessage::Cancel).
+    pub const CANCELLATION_ACK: Self = Self(1 << 2);
+    /// Credit-based flow control of [stream items](crate::ClientMessage::StreamCredit).
+    pub const FLOW_CONTROL: Self = Self(1 << 3);
+    /// [One-way](crate::ClientMessage::OneWay) requests.
+    pub const ONE_WAY: Self = Self(1 << 4);
+    /// Messages [pushed](crate::ServerMessage::Push) by servers.
+    pub const PUSH: Self = Self(1 << 5);
+    /// [Close](crate::ServerMessage::Close) messages sent by servers when they tear channels
+    /// down.
+    pub const CLOSE: Self = Self(1 << 6);
+
+    /// The capabilities implemented by every tarpc channel of this version, regardless of its
+    /// transport.
+    pub const SUPPORTED: Self = Self(
+        Self::STREAMING.0 | Self::FLOW_CONTROL.0 | Self::ONE_WAY.0 | Self::PUSH.0 | Self::CLOSE.0,
+    );
+
+    const NAMES: [(Self, &'static str); 7] = [
+        (Self::STREAMING, "STREAMING"),
+        (Self::COMPRESSION, "COMPRESSION"),
+        (Self::CANCELLATION_ACK, "CANCELLATION_ACK"),
+        (Self::FLOW_CONTROL, "FLOW_CONTROL"),
+        (Self::ONE_WAY, "ONE_WAY"),
+        (Self::PUSH, "PUSH"),
+        (Self::CLOSE, "CLOSE"),
+    ];
+
+    /// Returns the empty set.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns the set of capabilities encoded in `bits`, including bits this version doesn't
+    /// know.
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the bits encoding the set.
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if the set is empty.
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns true if the set contains every capability in `other`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the capabilities in both sets, i.e. those two peers can rely on.
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl ops::BitOrAssign for Capabilities {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+impl ops::BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        self.intersection(other)
+    }
+}
+
+impl fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut set = f.debug_set();
+        let mut unknown = self.0;
+        for (capability, name) in Self::NAMES {
+            if self.contains(capability) {
+                set.entry(&format_args!("{}", name));
+                unknown &= !capability.0;
+            }
+        }
+        if unknown != 0 {
+            set.entry(&format_args!("{:#x}", unknown));
+        }
+        set.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_combine() {
+        let client = Capabilities::STREAMING | Capabilities::COMPRESSION;
+        let server = Capabilities::SUPPORTED;
+        assert!(client.contains(Capabilities::COMPRESSION));
+        assert!(!server.contains(client));
+        assert_eq!(client & server, Capabilities::STREAMING);
+        assert!(Capabilities::empty().is_empty());
+    }
+
+    #[test]
+    fn unknown_bits_are_kept() {
+        let capabilities = Capabilities::from_bits(Capabilities::PUSH.bits() | 1 << 31);
+        assert_eq!(capabilities.bits(), 1 << 5 | 1 << 31);
+        assert_eq!(format!("{:?}", capabilities), "{PUSH, 0x80000000}");
+    }
+}
@@ -8,24 +8,51 @@
 
 mod in_flight_requests;
 
+/// Provides adaptive limits on the number of requests a client has in flight.
+pub mod limits;
+
+/// Provides a pool of client connections that keeps a minimum number of idle connections
+/// established.
+#[cfg(feature = "tokio1")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tokio1")))]
+pub mod pool;
+
+/// Provides generators of the IDs of requests.
+pub mod request_ids;
+
 use crate::{
     cancellations::{cancellations, CanceledRequests, RequestCancellation},
-    context, trace, ClientMessage, Request, Response, ServerError, Transport,
+    capabilities::Capabilities,
+    context::{self, TransportClass, TransportHint},
+    metrics::{Recorder, Side},
+    trace,
+    transport::{DecodeError, ProtocolError},
+    util, ClientMessage, Request, Response, ServerError, ServerMessage, Transport,
+};
+use fnv::FnvHashMap;
+use futures::{
+    channel::mpsc as item_mpsc,
+    future::Shared,
+    prelude::*,
+    ready,
+    stream::{Fuse, SelectAll},
+    task::*,
 };
-use futures::{prelude::*, ready, stream::Fuse, task::*};
-use in_flight_requests::{DeadlineExceededError, InFlightRequests};
+use in_flight_requests::{DeadlineExceededError, InFlightRequests, ResponseCompletion};
+use limits::GradientLimit;
 use pin_project::pin_project;
+use request_ids::RequestIdGenerator;
+use std::fmt::Debug;
 use std::{
     convert::TryFrom,
     error::Error,
-    fmt,
+    fmt, io, iter,
     pin::Pin,
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc,
+        Arc, Mutex,
     },
 };
-use std::fmt::Debug;
 use tokio::sync::{mpsc, oneshot};
 use tracing::Span;
 
@@ -41,6 +68,28 @@ pub struct Config {
     /// `pending_requests_buffer` controls the size of the channel clients use
     /// to communicate with the request dispatch task.
     pub pending_request_buffer: usize,
+    /// If set, the number of requests in flight is further limited by an adaptive limit, which
+    /// shrinks when the latency of responses rises, to protect an overloaded server.
+    pub adaptive_concurrency: Option<limits::Gradient>,
+    /// If set, the deadline of each request is clamped to the range of the policy when the
+    /// request is sent.
+    pub deadline_clamp: Option<limits::DeadlineClamp>,
+    /// If set, the client opens a [handshake](crate::capabilities) announcing these capabilities
+    /// before its first request, and learns the capabilities of the server. Servers that predate
+    /// handshakes reject it, so it's unset by default.
+    pub capabilities: Option<Capabilities>,
+    /// The maximum number of requests coalesced into one [batch](ClientMessage::Batch) when
+    /// several are waiting to be sent, saving the framing and syscalls of sending them one at a
+    /// time. Servers that predate batches reject them, so it's 1, i.e. no batching, by default.
+    pub max_batch_size: usize,
+    /// If set, the dispatch records the requests it sends, and the responses it receives, with
+    /// the recorder.
+    pub metrics: Option<Arc<dyn Recorder>>,
+    /// Generates the IDs of requests. Sharing a generator between the channels of successive
+    /// connections keeps IDs unique across reconnects, and a
+    /// [`Snowflake`](request_ids::Snowflake) generator embeds a shard in them. By default, each
+    /// config counts from 0.
+    pub request_ids: Arc<dyn RequestIdGenerator>,
 }
 
 impl Default for Config {
@@ -48,6 +97,12 @@ impl Default for Config {
         Config {
             max_in_flight_requests: 1_000,
             pending_request_buffer: 100,
+            adaptive_concurrency: None,
+            deadline_clamp: None,
+            capabilities: None,
+            max_batch_size: 1,
+            metrics: None,
+            request_ids: Arc::new(request_ids::Sequential::new()),
         }
     }
 }
@@ -62,9 +117,9 @@ pub struct NewClient<C, D> {
 }
 
 impl<C, D, E> NewClient<C, D>
-    where
-        D: Future<Output=Result<(), E>> + Send + 'static,
-        E: std::error::Error + Send + Sync + 'static,
+where
+    D: Future<Output = Result<(), E>> + Send + 'static,
+    E: std::error::Error + Send + Sync + 'static,
 {
     /// Helper method to spawn the dispatch on the default executor.
     #[cfg(feature = "tokio1")]
@@ -85,6 +140,14 @@ impl<C, D> fmt::Debug for NewClient<C, D> {
     }
 }
 
+/// The number of request items that can be buffered client-side per client-streaming request
+/// before the [`RequestSink`] applies backpressure.
+const REQUEST_STREAM_BUFFER: usize = 16;
+
+/// The number of messages pushed by the server that can be buffered client-side before further
+/// pushes are dropped.
+const PUSH_BUFFER: usize = 100;
+
 const _CHECK_USIZE: () = assert!(
     std::mem::size_of::<usize>() <= std::mem::size_of::<u64>(),
     "usize is too big to fit in u64"
@@ -93,11 +156,68 @@ const _CHECK_USIZE: () = assert!(
 /// Handles communication from the client to request dispatch.
 #[derive(Debug)]
 pub struct Channel<Req, Resp> {
-    to_dispatch: mpsc::Sender<DispatchRequest<Req, Resp>>,
+    to_dispatch: DispatchSender<Req, Resp>,
     /// Channel to send a cancel message to the dispatcher.
     cancellation: RequestCancellation,
-    /// The ID to use for the next request to stage.
-    next_request_id: Arc<AtomicUsize>,
+    /// Channel to send stream credits to the dispatcher.
+    stream_credits: mpsc::UnboundedSender<u64>,
+    /// Generates the IDs of requests to stage.
+    request_ids: Arc<dyn RequestIdGenerator>,
+    /// Resolves once the dispatch terminates.
+    closed: Shared<oneshot::Receiver<CloseReason>>,
+    /// The class of the transport the dispatch sends requests over.
+    class: Option<TransportClass>,
+    /// Channels over other transports that requests can be routed to, in order of preference.
+    fallbacks: Arc<[Channel<Req, Resp>]>,
+    /// Messages pushed by the server, until taken by [`Channel::pushes`].
+    pushes: Arc<Mutex<Option<mpsc::Receiver<Resp>>>>,
+    /// The capabilities the server announced in the handshake, once it has.
+    peer_capabilities: Arc<Mutex<Option<Capabilities>>>,
+    /// Computes the size of requests, to advise to the server.
+    request_sizer: Option<RequestSizer<Req>>,
+    /// The channels requests are retried on when they fail with an unavailable error.
+    failover: Option<Failover<Req, Resp>>,
+    /// Records the requests retried on a failover channel.
+    metrics: Option<Arc<dyn Recorder>>,
+}
+
+/// Computes the serialized size of requests, in bytes.
+struct RequestSizer<Req>(Arc<dyn Fn(&Req) -> u64 + Send + Sync>);
+
+impl<Req> Clone for RequestSizer<Req> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<Req> fmt::Debug for RequestSizer<Req> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RequestSizer")
+    }
+}
+
+/// The channels a request is retried on, in order, when it fails with an
+/// [unavailable](RpcError::is_unavailable) error, and how to clone requests to retry them.
+struct Failover<Req, Resp> {
+    channels: Arc<[Channel<Req, Resp>]>,
+    clone_request: Arc<dyn Fn(&Req) -> Req + Send + Sync>,
+}
+
+impl<Req, Resp> Clone for Failover<Req, Resp> {
+    fn clone(&self) -> Self {
+        Self {
+            channels: self.channels.clone(),
+            clone_request: self.clone_request.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Failover<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Failover")
+            .field("channels", &self.channels.len())
+            .finish()
+    }
 }
 
 impl<Req, Resp> Clone for Channel<Req, Resp> {
@@ -105,40 +225,229 @@ impl<Req, Resp> Clone for Channel<Req, Resp> {
         Self {
             to_dispatch: self.to_dispatch.clone(),
             cancellation: self.cancellation.clone(),
-            next_request_id: self.next_request_id.clone(),
+            stream_credits: self.stream_credits.clone(),
+            request_ids: self.request_ids.clone(),
+            closed: self.closed.clone(),
+            class: self.class,
+            fallbacks: self.fallbacks.clone(),
+            pushes: self.pushes.clone(),
+            peer_capabilities: self.peer_capabilities.clone(),
+            request_sizer: self.request_sizer.clone(),
+            failover: self.failover.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> Channel<Req, Resp> {
+    /// Returns a future that resolves once the dispatch of the channel terminates, with the reason
+    /// it terminated. Requests sent after that fail with [`RpcError::Disconnected`], so this can be
+    /// used to rebuild a client as soon as its connection is lost.
+    pub fn closed(&self) -> impl Future<Output = CloseReason> + Send + 'static {
+        self.closed
+            .clone()
+            .map(|reason| reason.unwrap_or(CloseReason::DispatchDropped))
+    }
+
+    /// Sets the class of the transport the channel sends requests over, so that requests can
+    /// select it with a [`TransportHint`].
+    pub fn with_transport_class(mut self, class: TransportClass) -> Self {
+        self.class = Some(class);
+        self
+    }
+
+    /// Sets a function computing the serialized size of requests, e.g. with the codec of the
+    /// transport. The size is advised to the server in the [size hint](context::SizeHint) of
+    /// each request whose context doesn't already advise one, so that the server can decide
+    /// whether to admit the request, and how much to allocate for it, before handling it.
+    pub fn with_request_sizer<F>(mut self, sizer: F) -> Self
+    where
+        F: Fn(&Req) -> u64 + Send + Sync + 'static,
+    {
+        self.request_sizer = Some(RequestSizer(Arc::new(sizer)));
+        self
+    }
+
+    /// Returns a channel that routes each request to this channel or to `fallback`, e.g. a
+    /// channel over a local unix socket with a fallback over TCP to a remote server.
+    ///
+    /// Requests are routed by the [transport hint](context::Context::transport) of their
+    /// context. Requests without a hint are sent over the first channel whose dispatch has not
+    /// terminated, trying this channel, then its fallbacks in the order they were added.
+    /// [`closed`](Self::closed) still resolves when this channel's dispatch terminates.
+    ///
+    /// A request is sent over a single channel, even if it fails there. To retry requests that
+    /// fail with an unavailable error on another channel, see
+    /// [`with_failover`](Self::with_failover).
+    pub fn with_transport_fallback(mut self, fallback: Channel<Req, Resp>) -> Self {
+        let fallbacks = self
+            .fallbacks
+            .iter()
+            .cloned()
+            .chain(iter::once(Channel {
+                fallbacks: Arc::new([]),
+                ..fallback.clone()
+            }))
+            .chain(fallback.fallbacks.iter().cloned())
+            .collect();
+        self.fallbacks = fallbacks;
+        self
+    }
+
+    /// Returns a channel that retries requests on `failover`, e.g. a channel to a read replica or
+    /// to a local cache service, when they fail on this channel with an
+    /// [unavailable](RpcError::is_unavailable) error.
+    ///
+    /// Failovers chain: a request is tried on this channel, then on each failover in the order
+    /// they were added, until it doesn't fail with an unavailable error. Each retry is recorded
+    /// by the [metrics](Config::metrics) of this channel. Only unary requests, sent with
+    /// [`call`](Self::call), are retried, since the items of streaming requests can't be replayed.
+    ///
+    /// Failovers are tried regardless of the transport hint of the request. To route requests by
+    /// their transport hint instead, see
+    /// [`with_transport_fallback`](Self::with_transport_fallback).
+    pub fn with_failover(mut self, failover: Channel<Req, Resp>) -> Self
+    where
+        Req: Clone + 'static,
+    {
+        let channels = self
+            .failover
+            .iter()
+            .flat_map(|failover| failover.channels.iter().cloned())
+            .chain(iter::once(Channel {
+                failover: None,
+                ..failover.clone()
+            }))
+            .chain(
+                failover
+                    .failover
+                    .iter()
+                    .flat_map(|failover| failover.channels.iter().cloned()),
+            )
+            .collect();
+        self.failover = Some(Failover {
+            channels,
+            clone_request: Arc::new(Req::clone),
+        });
+        self
+    }
+
+    /// Returns the stream of messages the server [pushes](ServerMessage::Push) over the channel,
+    /// e.g. cache invalidations. The stream is only returned once, to the first caller among the
+    /// channel and its clones.
+    ///
+    /// Up to 100 pushed messages are buffered until the stream is polled; pushes that arrive while
+    /// the buffer is full are dropped.
+    pub fn pushes(&self) -> Option<Pushes<Resp>> {
+        self.pushes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .map(|pushes| Pushes { pushes })
+    }
+
+    /// Returns the capabilities the server announced in the [handshake](crate::capabilities), or
+    /// None if the client wasn't [configured](Config::capabilities) to handshake, or the server
+    /// hasn't answered yet. The server answers before responding to any request.
+    pub fn peer_capabilities(&self) -> Option<Capabilities> {
+        *self
+            .peer_capabilities
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn is_closed(&self) -> bool {
+        self.to_dispatch.is_closed()
+    }
+
+    /// Advises the size of `request` in `ctx`, unless the caller already did.
+    fn advise_size(&self, ctx: &mut context::Context, request: &Req) {
+        if let (None, Some(RequestSizer(sizer))) = (ctx.size_hint.request, &self.request_sizer) {
+            ctx.size_hint.request = Some(sizer(request));
         }
     }
+
+    /// Selects the channel to send a request over, according to the transport hint in `ctx`.
+    fn route(&self, ctx: &context::Context) -> Result<&Self, RpcError> {
+        let channels = || iter::once(self).chain(self.fallbacks.iter());
+        let open = || channels().filter(|channel| !channel.is_closed());
+        let channel = match ctx.transport {
+            None => open().next(),
+            Some(TransportHint::Prefer(class)) => open()
+                .find(|channel| channel.class == Some(class))
+                .or_else(|| open().next()),
+            Some(TransportHint::Require(class)) => {
+                let channel = open()
+                    .find(|channel| channel.class == Some(class))
+                    .or_else(|| channels().find(|channel| channel.class == Some(class)));
+                if channel.is_none() {
+                    return Err(RpcError::Disconnected(format!(
+                        "no transport of class {}",
+                        class
+                    )));
+                }
+                channel
+            }
+        };
+        // If every channel is closed, sending the request reports the disconnection.
+        Ok(channel.unwrap_or(self))
+    }
 }
 
 impl<Req: Debug, Resp: Debug> Channel<Req, Resp> {
     /// Sends a request to the dispatch task to forward to the server, returning a [`Future`] that
-    /// resolves to the response.
+    /// resolves to the response. The request is retried on the [failovers](Self::with_failover) of
+    /// the channel, if any, while it fails with an unavailable error.
+    pub async fn call(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<Resp, RpcError> {
+        let failover = match &self.failover {
+            Some(failover) => failover,
+            None => return self.call_once(ctx, request_name, request).await,
+        };
+        // Failovers are never empty; the last one is tried with the original request.
+        let (last, failovers) = failover.channels.split_last().unwrap();
+        for channel in iter::once(self).chain(failovers) {
+            match channel
+                .call_once(ctx, request_name, (failover.clone_request)(&request))
+                .await
+            {
+                Err(e) if e.is_unavailable() => {
+                    tracing::info!(error = %e, "Failover");
+                    if let Some(metrics) = &self.metrics {
+                        metrics.failover(request_name);
+                    }
+                }
+                result => return result,
+            }
+        }
+        last.call_once(ctx, request_name, request).await
+    }
+
     #[tracing::instrument(
     name = "RPC",
     skip(self, ctx, request_name, request),
     fields(
     rpc.trace_id = tracing::field::Empty,
+    rpc.request_id = tracing::field::Empty,
     rpc.deadline = % humantime::format_rfc3339(ctx.deadline),
     otel.kind = "client",
     otel.name = request_name)
     )]
-    pub async fn call(
+    async fn call_once(
         &self,
         mut ctx: context::Context,
         request_name: &'static str,
         request: Req,
     ) -> Result<Resp, RpcError> {
+        self.advise_size(&mut ctx, &request);
+        let channel = self.route(&ctx)?;
         let span = Span::current();
-        ctx.trace_context = trace::Context::try_from(&span).unwrap_or_else(|_| {
-            tracing::trace!(
-                "OpenTelemetry subscriber not installed; making unsampled child context."
-            );
-            ctx.trace_context.new_child()
-        });
-        span.record("rpc.trace_id", &tracing::field::display(ctx.trace_id()));
+        let request_id = channel.start_request(&mut ctx, &span);
         let (response_completion, mut response) = oneshot::channel();
-        let request_id =
-            u64::try_from(self.next_request_id.fetch_add(1, Ordering::Relaxed)).unwrap();
 
         // ResponseGuard impls Drop to cancel in-flight requests. It should be created before
         // sending out the request; otherwise, the response future could be dropped after the
@@ -147,21 +456,261 @@ impl<Req: Debug, Resp: Debug> Channel<Req, Resp> {
         let response_guard = ResponseGuard {
             response: &mut response,
             request_id,
-            cancellation: &self.cancellation,
+            cancellation: &channel.cancellation,
             cancel: true,
         };
-        self.to_dispatch
+        channel
+            .to_dispatch
             .send(DispatchRequest {
                 ctx,
                 span,
+                method: request_name,
                 request_id,
                 request,
-                response_completion,
+                request_items: None,
+                response_completion: ResponseCompletion::Unary(response_completion),
             })
             .await
-            .map_err(|mpsc::error::SendError(dispatch_req)| RpcError::Disconnected(format!("mpsc::error::SendError: {:?}", dispatch_req)))?;
+            .map_err(|mpsc::error::SendError(dispatch_req)| {
+                RpcError::Disconnected(format!("mpsc::error::SendError: {:?}", dispatch_req))
+            })?;
         response_guard.response().await
     }
+
+    /// Sends a server-streaming request to the dispatch task to forward to the server, returning
+    /// a [`ResponseStream`] that yields each response as it arrives.
+    #[tracing::instrument(
+    name = "RPC",
+    skip(self, ctx, request_name, request),
+    fields(
+    rpc.trace_id = tracing::field::Empty,
+    rpc.request_id = tracing::field::Empty,
+    rpc.deadline = % humantime::format_rfc3339(ctx.deadline),
+    otel.kind = "client",
+    otel.name = request_name)
+    )]
+    pub async fn call_stream(
+        &self,
+        mut ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<ResponseStream<Resp>, RpcError> {
+        self.advise_size(&mut ctx, &request);
+        let channel = self.route(&ctx)?;
+        let span = Span::current();
+        let request_id = channel.start_request(&mut ctx, &span);
+        let (response_completion, responses) = mpsc::unbounded_channel();
+
+        // Like ResponseGuard, the stream cancels the request when dropped, so it is created
+        // before sending out the request.
+        let response_stream = ResponseStream {
+            responses,
+            stream_credits: channel.stream_credits.clone(),
+            cancellation: channel.cancellation.clone(),
+            request_id,
+            complete: false,
+        };
+        channel
+            .to_dispatch
+            .send(DispatchRequest {
+                ctx,
+                span,
+                method: request_name,
+                request_id,
+                request,
+                request_items: None,
+                response_completion: ResponseCompletion::Stream(response_completion),
+            })
+            .await
+            .map_err(|mpsc::error::SendError(dispatch_req)| {
+                RpcError::Disconnected(format!("mpsc::error::SendError: {:?}", dispatch_req))
+            })?;
+        Ok(response_stream)
+    }
+
+    /// Sends a client-streaming request to the dispatch task to forward to the server, returning
+    /// a [`RequestSink`] for the items to send after the request, and a [`ResponseFuture`] that
+    /// resolves to the response.
+    ///
+    /// The request items are sent in order after the request. Closing or dropping the sink
+    /// signals the end of the items to the server.
+    #[tracing::instrument(
+    name = "RPC",
+    skip(self, ctx, request_name, request),
+    fields(
+    rpc.trace_id = tracing::field::Empty,
+    rpc.request_id = tracing::field::Empty,
+    rpc.deadline = % humantime::format_rfc3339(ctx.deadline),
+    otel.kind = "client",
+    otel.name = request_name)
+    )]
+    pub async fn call_client_stream(
+        &self,
+        mut ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<(RequestSink<Req>, ResponseFuture<Resp>), RpcError> {
+        self.advise_size(&mut ctx, &request);
+        let channel = self.route(&ctx)?;
+        let span = Span::current();
+        let request_id = channel.start_request(&mut ctx, &span);
+        let (response_completion, response) = oneshot::channel();
+        let (request_sink, request_items) = item_mpsc::channel(REQUEST_STREAM_BUFFER);
+
+        // Like ResponseGuard, the response future cancels the request when dropped, so it is
+        // created before sending out the request.
+        let response = ResponseFuture {
+            response,
+            cancellation: channel.cancellation.clone(),
+            request_id,
+            complete: false,
+        };
+        channel
+            .to_dispatch
+            .send(DispatchRequest {
+                ctx,
+                span,
+                method: request_name,
+                request_id,
+                request,
+                request_items: Some(request_items),
+                response_completion: ResponseCompletion::Unary(response_completion),
+            })
+            .await
+            .map_err(|mpsc::error::SendError(dispatch_req)| {
+                RpcError::Disconnected(format!("mpsc::error::SendError: {:?}", dispatch_req))
+            })?;
+        Ok((RequestSink(request_sink), response))
+    }
+
+    /// Sends a bidirectional-streaming request to the dispatch task to forward to the server,
+    /// returning a [`RequestSink`] for the items to send after the request, and a
+    /// [`ResponseStream`] that yields each response as it arrives.
+    ///
+    /// Both sides can send items until they close their end of the stream; the request completes
+    /// when the server closes its end.
+    #[tracing::instrument(
+    name = "RPC",
+    skip(self, ctx, request_name, request),
+    fields(
+    rpc.trace_id = tracing::field::Empty,
+    rpc.request_id = tracing::field::Empty,
+    rpc.deadline = % humantime::format_rfc3339(ctx.deadline),
+    otel.kind = "client",
+    otel.name = request_name)
+    )]
+    pub async fn call_bidi_stream(
+        &self,
+        mut ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<(RequestSink<Req>, ResponseStream<Resp>), RpcError> {
+        self.advise_size(&mut ctx, &request);
+        let channel = self.route(&ctx)?;
+        let span = Span::current();
+        let request_id = channel.start_request(&mut ctx, &span);
+        let (response_completion, responses) = mpsc::unbounded_channel();
+        let (request_sink, request_items) = item_mpsc::channel(REQUEST_STREAM_BUFFER);
+
+        // Like ResponseGuard, the stream cancels the request when dropped, so it is created
+        // before sending out the request.
+        let response_stream = ResponseStream {
+            responses,
+            stream_credits: channel.stream_credits.clone(),
+            cancellation: channel.cancellation.clone(),
+            request_id,
+            complete: false,
+        };
+        channel
+            .to_dispatch
+            .send(DispatchRequest {
+                ctx,
+                span,
+                method: request_name,
+                request_id,
+                request,
+                request_items: Some(request_items),
+                response_completion: ResponseCompletion::Stream(response_completion),
+            })
+            .await
+            .map_err(|mpsc::error::SendError(dispatch_req)| {
+                RpcError::Disconnected(format!("mpsc::error::SendError: {:?}", dispatch_req))
+            })?;
+        Ok((RequestSink(request_sink), response_stream))
+    }
+
+    /// Sends a [one-way](ClientMessage::OneWay) request to the dispatch task to forward to the
+    /// server, returning a [`Future`] that resolves once the dispatch task has accepted it.
+    ///
+    /// The server sends no response, so the request is not tracked as in flight: a successful
+    /// result only means the request was handed to the dispatch task, not that the server
+    /// received it.
+    #[tracing::instrument(
+    name = "RPC",
+    skip(self, ctx, request_name, request),
+    fields(
+    rpc.trace_id = tracing::field::Empty,
+    rpc.deadline = % humantime::format_rfc3339(ctx.deadline),
+    otel.kind = "client",
+    otel.name = request_name)
+    )]
+    pub async fn send_one_way(
+        &self,
+        mut ctx: context::Context,
+        request_name: &'static str,
+        request: Req,
+    ) -> Result<(), RpcError> {
+        self.advise_size(&mut ctx, &request);
+        let channel = self.route(&ctx)?;
+        let span = Span::current();
+        propagate_trace(&mut ctx, &span);
+        channel
+            .to_dispatch
+            .send(DispatchRequest {
+                ctx,
+                span,
+                method: request_name,
+                // One-way requests have no ID.
+                request_id: 0,
+                request,
+                request_items: None,
+                response_completion: ResponseCompletion::OneWay,
+            })
+            .await
+            .map_err(|mpsc::error::SendError(dispatch_req)| {
+                RpcError::Disconnected(format!("mpsc::error::SendError: {:?}", dispatch_req))
+            })
+    }
+
+    /// Assigns the request an ID, recorded on `span`, and propagates the trace context of `span`
+    /// into `ctx`.
+    fn start_request(&self, ctx: &mut context::Context, span: &Span) -> u64 {
+        propagate_trace(ctx, span);
+        let request_id = self.request_ids.next_request_id();
+        span.record("rpc.request_id", request_id);
+        request_id
+    }
+}
+
+/// Propagates the trace context of `span` into `ctx`.
+fn propagate_trace(ctx: &mut context::Context, span: &Span) {
+    ctx.trace_context = trace::Context::try_from(span).unwrap_or_else(|_| {
+        tracing::trace!("OpenTelemetry subscriber not installed; making unsampled child context.");
+        ctx.trace_context.new_child()
+    });
+    span.record("rpc.trace_id", tracing::field::display(ctx.trace_id()));
+}
+
+/// Returns the parts of `ctx` sent to the server with a request.
+fn wire_context(ctx: &context::Context) -> context::Context {
+    context::Context {
+        deadline: ctx.deadline,
+        trace_context: ctx.trace_context,
+        keep_alive: ctx.keep_alive,
+        size_hint: ctx.size_hint,
+        transport: None,
+        peer_addr: None,
+    }
 }
 
 /// A server response that is completed by request dispatch when the corresponding response
@@ -175,8 +724,12 @@ struct ResponseGuard<'a, Resp> {
 
 /// An error that can occur in the processing of an RPC. This is not request-specific errors but
 /// rather cross-cutting errors that can always occur.
+///
+/// The enum is non-exhaustive: client features, like [encrypted](crate::encryption) fields, add
+/// ways for an rpc to fail, so matches on it need a wildcard arm.
 #[derive(thiserror::Error, Clone, Debug, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum RpcError {
     /// The client disconnected from the server.
     #[error("the client disconnected from the server")]
@@ -187,6 +740,44 @@ pub enum RpcError {
     /// The server aborted request processing.
     #[error("the server aborted request processing")]
     Server(#[from] ServerError),
+    /// An encrypted field could not be encrypted or decrypted.
+    #[error("an encrypted field could not be encrypted or decrypted: {0}")]
+    Encryption(String),
+}
+
+/// The error of an rpc marked `#[tarpc(app_error)]`, whose handler returns `Result<T, E>`: either
+/// the application error `E` returned by the handler, or an [`RpcError`] that kept the handler
+/// from returning at all. Clients can match on their own error types instead of inspecting
+/// [`ServerError`]s, which carry strings.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum CallError<E> {
+    /// The rpc failed before the handler returned.
+    #[error(transparent)]
+    Rpc(#[from] RpcError),
+    /// The handler returned an application error.
+    #[error("the handler returned an error: {0}")]
+    App(#[source] E),
+}
+
+impl RpcError {
+    /// Returns true if the error suggests that the server is unavailable, i.e. disconnected,
+    /// refusing requests, or shedding load, so that the request could succeed on another server.
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            RpcError::Disconnected(_) => true,
+            RpcError::Server(e) => {
+                e.cause().is_retryable()
+                    || matches!(
+                        e.kind,
+                        io::ErrorKind::ConnectionRefused
+                            | io::ErrorKind::ConnectionReset
+                            | io::ErrorKind::NotConnected
+                    )
+            }
+            RpcError::DeadlineExceeded | RpcError::Encryption(_) => false,
+        }
+    }
 }
 
 impl From<DeadlineExceededError> for RpcError {
@@ -206,7 +797,9 @@ impl<Resp> ResponseGuard<'_, Resp> {
                 // The oneshot is Canceled when the dispatch task ends. In that case,
                 // there's nothing listening on the other side, so there's no point in
                 // propagating cancellation.
-                Err(RpcError::Disconnected("oneshot::error::RecvError error, no additional information".to_string()))
+                Err(RpcError::Disconnected(
+                    "oneshot::error::RecvError error, no additional information".to_string(),
+                ))
             }
         }
     }
@@ -232,63 +825,312 @@ impl<Resp> Drop for ResponseGuard<'_, Resp> {
     }
 }
 
-/// Returns a channel and dispatcher that manages the lifecycle of requests initiated by the
-/// channel.
-pub fn new<Req, Resp, C>(
-    config: Config,
-    transport: C,
-) -> NewClient<Channel<Req, Resp>, RequestDispatch<Req, Resp, C>>
-    where
-        C: Transport<ClientMessage<Req>, Response<Resp>>,
-{
-    let (to_dispatch, pending_requests) = mpsc::channel(config.pending_request_buffer);
-    let (cancellation, canceled_requests) = cancellations();
-    let canceled_requests = canceled_requests;
+/// A sink for the items of a client-streaming request, returned by
+/// [`Channel::call_client_stream`] and [`Channel::call_bidi_stream`].
+///
+/// Closing or dropping the sink signals the end of the items to the server. Items sent after the
+/// request has completed are discarded.
+#[derive(Debug)]
+pub struct RequestSink<Req>(item_mpsc::Sender<Req>);
 
-    NewClient {
-        client: Channel {
-            to_dispatch,
-            cancellation,
-            next_request_id: Arc::new(AtomicUsize::new(0)),
-        },
-        dispatch: RequestDispatch {
-            config,
-            canceled_requests,
-            transport: transport.fuse(),
-            in_flight_requests: InFlightRequests::default(),
-            pending_requests,
-        },
+impl<Req> RequestSink<Req> {
+    fn map_err(_: item_mpsc::SendError) -> RpcError {
+        RpcError::Disconnected("the request dispatch ended before the stream completed".to_string())
     }
 }
 
-/// Handles the lifecycle of requests, writing requests to the wire, managing cancellations,
-/// and dispatching responses to the appropriate channel.
-#[must_use]
-#[pin_project]
+impl<Req> Sink<Req> for RequestSink<Req> {
+    type Error = RpcError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), RpcError>> {
+        Pin::new(&mut self.0).poll_ready(cx).map_err(Self::map_err)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Req) -> Result<(), RpcError> {
+        Pin::new(&mut self.0)
+            .start_send(item)
+            .map_err(Self::map_err)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), RpcError>> {
+        Pin::new(&mut self.0).poll_flush(cx).map_err(Self::map_err)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), RpcError>> {
+        Pin::new(&mut self.0).poll_close(cx).map_err(Self::map_err)
+    }
+}
+
+/// A future resolving to the response to a client-streaming request, returned by
+/// [`Channel::call_client_stream`]. If dropped before completion, a cancellation message is sent
+/// to the server.
 #[derive(Debug)]
-pub struct RequestDispatch<Req, Resp, C> {
-    /// Writes requests to the wire and reads responses off the wire.
-    #[pin]
-    transport: Fuse<C>,
-    /// Requests waiting to be written to the wire.
-    pending_requests: mpsc::Receiver<DispatchRequest<Req, Resp>>,
-    /// Requests that were dropped.
-    canceled_requests: CanceledRequests,
-    /// Requests already written to the wire that haven't yet received responses.
-    in_flight_requests: InFlightRequests<Resp>,
-    /// Configures limits to prevent unlimited resource usage.
-    config: Config,
+pub struct ResponseFuture<Resp> {
+    response: oneshot::Receiver<Result<Response<Resp>, DeadlineExceededError>>,
+    cancellation: RequestCancellation,
+    request_id: u64,
+    complete: bool,
 }
 
-/// Critical errors that result in a Channel disconnecting.
-#[derive(thiserror::Error, Debug)]
-pub enum ChannelError<E>
-    where
-        E: Error + Send + Sync + 'static,
-{
+impl<Resp> Future for ResponseFuture<Resp> {
+    type Output = Result<Resp, RpcError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let response = ready!(Pin::new(&mut self.response).poll(cx));
+        self.complete = true;
+        Poll::Ready(match response {
+            Ok(resp) => Ok(resp?.message?),
+            Err(oneshot::error::RecvError { .. }) => Err(RpcError::Disconnected(
+                "oneshot::error::RecvError error, no additional information".to_string(),
+            )),
+        })
+    }
+}
+
+// Cancels the request when dropped, if not already complete.
+impl<Resp> Drop for ResponseFuture<Resp> {
+    fn drop(&mut self) {
+        // See the Drop impl of ResponseGuard for why the receiver is closed first.
+        self.response.close();
+        if !self.complete {
+            self.cancellation.cancel(self.request_id);
+        }
+    }
+}
+
+/// The items of a client-streaming request, followed by `None` to mark the end of the items.
+#[derive(Debug)]
+struct RequestItems<Req> {
+    request_id: u64,
+    items: Option<item_mpsc::Receiver<Req>>,
+}
+
+impl<Req> Stream for RequestItems<Req> {
+    type Item = (u64, Option<Req>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let request_id = self.request_id;
+        let items = match &mut self.items {
+            Some(items) => items,
+            None => return Poll::Ready(None),
+        };
+        match ready!(items.poll_next_unpin(cx)) {
+            Some(item) => Poll::Ready(Some((request_id, Some(item)))),
+            None => {
+                self.items = None;
+                Poll::Ready(Some((request_id, None)))
+            }
+        }
+    }
+}
+
+/// A stream of the messages a server pushes to a client, returned by [`Channel::pushes`]. The
+/// stream ends when the dispatch of the channel terminates.
+#[derive(Debug)]
+pub struct Pushes<Resp> {
+    pushes: mpsc::Receiver<Resp>,
+}
+
+impl<Resp> Stream for Pushes<Resp> {
+    type Item = Resp;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Resp>> {
+        self.pushes.poll_recv(cx)
+    }
+}
+
+/// A stream of responses to a server-streaming request, returned by [`Channel::call_stream`] and
+/// [`Channel::call_bidi_stream`].
+///
+/// Each consumed item allows the server to send one more, so a stream that isn't polled will
+/// eventually pause the server's handler. If dropped before the stream completes, a cancellation
+/// message is sent to the server.
+#[derive(Debug)]
+pub struct ResponseStream<Resp> {
+    responses: mpsc::UnboundedReceiver<Option<Result<Resp, RpcError>>>,
+    stream_credits: mpsc::UnboundedSender<u64>,
+    cancellation: RequestCancellation,
+    request_id: u64,
+    complete: bool,
+}
+
+impl<Resp> Stream for ResponseStream<Resp> {
+    type Item = Result<Resp, RpcError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.complete {
+            return Poll::Ready(None);
+        }
+        let next = match ready!(self.responses.poll_recv(cx)) {
+            Some(Some(Ok(item))) => {
+                // Make room for the server to send another item in place of this one.
+                let _ = self.stream_credits.send(self.request_id);
+                return Poll::Ready(Some(Ok(item)));
+            }
+            Some(Some(Err(e))) => Some(Err(e)),
+            Some(None) => None,
+            // The sender is dropped without ending the stream when the dispatch task ends.
+            None => Some(Err(RpcError::Disconnected(
+                "the request dispatch ended before the stream completed".to_string(),
+            ))),
+        };
+        self.complete = true;
+        Poll::Ready(next)
+    }
+}
+
+// Cancels the request when dropped, if not already complete.
+impl<Resp> Drop for ResponseStream<Resp> {
+    fn drop(&mut self) {
+        // See the Drop impl of ResponseGuard for why the receiver is closed first.
+        self.responses.close();
+        if !self.complete {
+            self.cancellation.cancel(self.request_id);
+        }
+    }
+}
+
+/// Returns a channel and dispatcher that manages the lifecycle of requests initiated by the
+/// channel.
+pub fn new<Req, Resp, C>(
+    config: Config,
+    transport: C,
+) -> NewClient<Channel<Req, Resp>, RequestDispatch<Req, Resp, C>>
+where
+    C: Transport<ClientMessage<Req>, ServerMessage<Resp>>,
+{
+    let (to_dispatch, pending_requests) = dispatch_queue(config.pending_request_buffer);
+    let (cancellation, canceled_requests) = cancellations();
+    let canceled_requests = canceled_requests;
+    let (stream_credits_tx, stream_credits) = mpsc::unbounded_channel();
+    let (closed_tx, closed) = oneshot::channel();
+    let (pushes_tx, pushes) = mpsc::channel(PUSH_BUFFER);
+    let peer_capabilities = Arc::new(Mutex::new(None));
+    let in_flight_requests = InFlightRequests::new(config.metrics.clone());
+
+    NewClient {
+        client: Channel {
+            to_dispatch,
+            cancellation,
+            stream_credits: stream_credits_tx,
+            request_ids: config.request_ids.clone(),
+            closed: closed.shared(),
+            class: None,
+            fallbacks: Arc::new([]),
+            pushes: Arc::new(Mutex::new(Some(pushes))),
+            peer_capabilities: peer_capabilities.clone(),
+            request_sizer: None,
+            failover: None,
+            metrics: config.metrics.clone(),
+        },
+        dispatch: RequestDispatch {
+            hello: config.capabilities,
+            peer_capabilities,
+            closed: Some(closed_tx),
+            concurrency_limit: config
+                .adaptive_concurrency
+                .clone()
+                .map(|settings| GradientLimit::new(settings).with_metrics(config.metrics.clone())),
+            config,
+            canceled_requests,
+            stream_credits,
+            pending_stream_credits: FnvHashMap::default(),
+            request_streams: SelectAll::new(),
+            transport: transport.fuse(),
+            in_flight_requests,
+            pending_requests,
+            deferred_request: None,
+            pushes: pushes_tx,
+            span: tracing::info_span!("Channel", otel.kind = "client"),
+        },
+    }
+}
+
+/// Handles the lifecycle of requests, writing requests to the wire, managing cancellations,
+/// and dispatching responses to the appropriate channel.
+#[must_use]
+#[pin_project]
+#[derive(Debug)]
+pub struct RequestDispatch<Req, Resp, C> {
+    /// Writes requests to the wire and reads responses off the wire.
+    #[pin]
+    transport: Fuse<C>,
+    /// Requests waiting to be written to the wire.
+    pending_requests: PendingRequests<Req, Resp>,
+    /// A request taken while filling a batch that can't be part of it, sent next.
+    deferred_request: Option<DispatchRequest<Req, Resp>>,
+    /// Requests that were dropped.
+    canceled_requests: CanceledRequests,
+    /// IDs of streaming requests whose client consumed a stream item.
+    stream_credits: mpsc::UnboundedReceiver<u64>,
+    /// Stream credits received but not yet written to the wire, coalesced per request.
+    pending_stream_credits: FnvHashMap<u64, u32>,
+    /// Items of client-streaming requests already written to the wire.
+    request_streams: SelectAll<RequestItems<Req>>,
+    /// Requests already written to the wire that haven't yet received responses.
+    in_flight_requests: InFlightRequests<Resp>,
+    /// Configures limits to prevent unlimited resource usage.
+    config: Config,
+    /// Adapts the number of requests in flight to the latency of responses, if configured.
+    concurrency_limit: Option<GradientLimit>,
+    /// Notifies channels of the reason the dispatch terminated.
+    closed: Option<oneshot::Sender<CloseReason>>,
+    /// Forwards messages pushed by the server to the [`Pushes`] stream.
+    pushes: mpsc::Sender<Resp>,
+    /// The capabilities to announce in the handshake, until it's sent.
+    hello: Option<Capabilities>,
+    /// The capabilities the server announced in the handshake, shared with the channels.
+    peer_capabilities: Arc<Mutex<Option<Capabilities>>>,
+    /// The span of the channel, entered while the dispatch is polled.
+    span: Span,
+}
+
+/// The reason the dispatch of a [`Channel`] terminated.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CloseReason {
+    /// The server closed the connection.
+    #[error("the server closed the connection")]
+    ServerClosed,
+    /// All channels were dropped, and all requests in flight completed.
+    #[error("all channels were dropped")]
+    ChannelsDropped,
+    /// The dispatch failed; contains the error and its sources.
+    #[error("the dispatch failed: {0}")]
+    Failed(String),
+    /// The dispatch was dropped before terminating, e.g. because it was never spawned.
+    #[error("the dispatch was dropped")]
+    DispatchDropped,
+}
+
+impl CloseReason {
+    fn failed(e: &dyn Error) -> Self {
+        let mut message = e.to_string();
+        let mut source = e.source();
+        while let Some(e) = source {
+            message += &format!(": {}", e);
+            source = e.source();
+        }
+        CloseReason::Failed(message)
+    }
+}
+
+/// Critical errors that result in a Channel disconnecting. Each carries the error of the
+/// transport that caused it, except for timer errors.
+#[derive(thiserror::Error, Debug)]
+pub enum ChannelError<E>
+where
+    E: Error + Send + Sync + 'static,
+{
     /// Could not read from the transport.
     #[error("could not read from the transport")]
     Read(#[source] E),
+    /// Read a message that could not be [decoded](DecodeError).
+    #[error("could not decode a message")]
+    Decode(#[source] E),
+    /// Read a message that [violates the protocol](ProtocolError).
+    #[error("the server violated the protocol")]
+    Protocol(#[source] E),
     /// Could not ready the transport for writes.
     #[error("could not ready the transport for writes")]
     Ready(#[source] E),
@@ -306,14 +1148,42 @@ pub enum ChannelError<E>
     Timer(#[source] tokio::time::error::Error),
 }
 
+impl<E> ChannelError<E>
+where
+    E: Error + Send + Sync + 'static,
+{
+    /// Classifies an error reading from the transport by the error in its chain.
+    fn read(e: E) -> Self {
+        if ProtocolError::find(&e).is_some() {
+            ChannelError::Protocol(e)
+        } else if DecodeError::find(&e).is_some() {
+            ChannelError::Decode(e)
+        } else {
+            ChannelError::Read(e)
+        }
+    }
+}
+
 impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
-    where
-        C: Transport<ClientMessage<Req>, Response<Resp>>,
+where
+    C: Transport<ClientMessage<Req>, ServerMessage<Resp>>,
 {
     fn in_flight_requests<'a>(self: &'a mut Pin<&mut Self>) -> &'a mut InFlightRequests<Resp> {
         self.as_mut().project().in_flight_requests
     }
 
+    fn concurrency_limit_mut<'a>(self: &'a mut Pin<&mut Self>) -> &'a mut Option<GradientLimit> {
+        self.as_mut().project().concurrency_limit
+    }
+
+    /// Returns the number of requests that can be in flight at once.
+    fn max_in_flight_requests(&self) -> usize {
+        match &self.concurrency_limit {
+            Some(limit) => limit.limit().min(self.config.max_in_flight_requests),
+            None => self.config.max_in_flight_requests,
+        }
+    }
+
     fn transport_pin_mut<'a>(self: &'a mut Pin<&mut Self>) -> Pin<&'a mut Fuse<C>> {
         self.as_mut().project().transport
     }
@@ -358,9 +1228,21 @@ impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
         self.as_mut().project().canceled_requests
     }
 
+    fn stream_credits_mut<'a>(
+        self: &'a mut Pin<&mut Self>,
+    ) -> &'a mut mpsc::UnboundedReceiver<u64> {
+        self.as_mut().project().stream_credits
+    }
+
+    fn request_streams_mut<'a>(
+        self: &'a mut Pin<&mut Self>,
+    ) -> &'a mut SelectAll<RequestItems<Req>> {
+        self.as_mut().project().request_streams
+    }
+
     fn pending_requests_mut<'a>(
         self: &'a mut Pin<&mut Self>,
-    ) -> &'a mut mpsc::Receiver<DispatchRequest<Req, Resp>> {
+    ) -> &'a mut PendingRequests<Req, Resp> {
         self.as_mut().project().pending_requests
     }
 
@@ -370,9 +1252,9 @@ impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
     ) -> Poll<Option<Result<(), ChannelError<C::Error>>>> {
         self.transport_pin_mut()
             .poll_next(cx)
-            .map_err(ChannelError::Read)
-            .map_ok(|response| {
-                self.complete(response);
+            .map_err(ChannelError::read)
+            .map_ok(|message| {
+                self.complete(message);
             })
     }
 
@@ -385,6 +1267,15 @@ impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
             Closed,
         }
 
+        // The handshake precedes every other message.
+        if self.hello.is_some() {
+            ready!(self.ensure_writeable(cx)?);
+            let capabilities = self.as_mut().project().hello.take().unwrap();
+            self.start_send(ClientMessage::Hello { capabilities })?;
+            tracing::info!(?capabilities, "SendHello");
+            return Poll::Ready(Some(Ok(())));
+        }
+
         let pending_requests_status = match self.as_mut().poll_write_request(cx)? {
             Poll::Ready(Some(())) => return Poll::Ready(Some(Ok(()))),
             Poll::Ready(None) => ReceiverStatus::Closed,
@@ -397,6 +1288,20 @@ impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
             Poll::Pending => ReceiverStatus::Pending,
         };
 
+        let stream_credits_status = match self.as_mut().poll_write_stream_credit(cx)? {
+            Poll::Ready(Some(())) => return Poll::Ready(Some(Ok(()))),
+            Poll::Ready(None) => ReceiverStatus::Closed,
+            Poll::Pending => ReceiverStatus::Pending,
+        };
+
+        // Request streams are only added when writing a request, so there being none is
+        // equivalent to them being closed.
+        let request_streams_status = match self.as_mut().poll_write_request_item(cx)? {
+            Poll::Ready(Some(())) => return Poll::Ready(Some(Ok(()))),
+            Poll::Ready(None) => ReceiverStatus::Closed,
+            Poll::Pending => ReceiverStatus::Pending,
+        };
+
         // Receiving Poll::Ready(None) when polling expired requests never indicates "Closed",
         // because there can temporarily be zero in-flight rquests. Therefore, there is no need to
         // track the status like is done with pending and cancelled requests.
@@ -404,15 +1309,31 @@ impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
             // Expired requests are considered complete; there is no compelling reason to send a
             // cancellation message to the server, since it will have already exhausted its
             // allotted processing time.
+            if let Some(limit) = self.concurrency_limit_mut() {
+                limit.on_dropped();
+            }
             return Poll::Ready(Some(Ok(())));
         }
 
-        match (pending_requests_status, canceled_requests_status) {
-            (ReceiverStatus::Closed, ReceiverStatus::Closed) => {
+        match (
+            pending_requests_status,
+            canceled_requests_status,
+            stream_credits_status,
+            request_streams_status,
+        ) {
+            (
+                ReceiverStatus::Closed,
+                ReceiverStatus::Closed,
+                ReceiverStatus::Closed,
+                ReceiverStatus::Closed,
+            ) => {
                 ready!(self.poll_close(cx)?);
                 Poll::Ready(None)
             }
-            (ReceiverStatus::Pending, _) | (_, ReceiverStatus::Pending) => {
+            (ReceiverStatus::Pending, _, _, _)
+            | (_, ReceiverStatus::Pending, _, _)
+            | (_, _, ReceiverStatus::Pending, _)
+            | (_, _, _, ReceiverStatus::Pending) => {
                 // No more messages to process, so flush any messages buffered in the transport.
                 ready!(self.poll_flush(cx)?);
 
@@ -431,11 +1352,12 @@ impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<DispatchRequest<Req, Resp>, ChannelError<C::Error>>>> {
-        if self.in_flight_requests().len() >= self.config.max_in_flight_requests {
+        let max_in_flight_requests = self.max_in_flight_requests();
+        if self.in_flight_requests().len() >= max_in_flight_requests {
             tracing::info!(
                 "At in-flight request capacity ({}/{}).",
                 self.in_flight_requests().len(),
-                self.config.max_in_flight_requests
+                max_in_flight_requests
             );
 
             // No need to schedule a wakeup, because timers and responses are responsible
@@ -445,6 +1367,10 @@ impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
 
         ready!(self.ensure_writeable(cx)?);
 
+        if let Some(request) = self.as_mut().project().deferred_request.take() {
+            return Poll::Ready(Some(Ok(request)));
+        }
+
         loop {
             match ready!(self.pending_requests_mut().poll_recv(cx)) {
                 Some(request) => {
@@ -484,6 +1410,76 @@ impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
         }
     }
 
+    /// Yields the stream credits for a request still in flight. The credits granted since the
+    /// last write are coalesced, so that each request gets a single credit message however many
+    /// items its consumer took in the meantime.
+    ///
+    /// Note that stream credits will only be yielded if the transport is *ready* to be written to
+    /// (i.e. start_send would succeed).
+    fn poll_next_stream_credit(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(Span, u64, u32), ChannelError<C::Error>>>> {
+        ready!(self.ensure_writeable(cx)?);
+
+        let mut closed = false;
+        loop {
+            match self.stream_credits_mut().poll_recv(cx) {
+                Poll::Ready(Some(request_id)) => {
+                    *self
+                        .as_mut()
+                        .project()
+                        .pending_stream_credits
+                        .entry(request_id)
+                        .or_default() += 1;
+                }
+                Poll::Ready(None) => {
+                    closed = true;
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+        let this = self.as_mut().project();
+        while let Some(&request_id) = this.pending_stream_credits.keys().next() {
+            let credits = this
+                .pending_stream_credits
+                .remove(&request_id)
+                .unwrap_or_default();
+            if let Some(span) = this.in_flight_requests.span(request_id) {
+                return Poll::Ready(Some(Ok((span.clone(), request_id, credits))));
+            }
+        }
+        if closed {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Yields the next item (or end of items) of a client-streaming request still in flight.
+    ///
+    /// Note that an item will only be yielded if the transport is *ready* to be written to (i.e.
+    /// start_send would succeed).
+    fn poll_next_request_item(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(Span, u64, Option<Req>), ChannelError<C::Error>>>> {
+        ready!(self.ensure_writeable(cx)?);
+
+        loop {
+            match ready!(self.request_streams_mut().poll_next_unpin(cx)) {
+                Some((request_id, item)) => {
+                    // Items of requests that are no longer in flight are discarded.
+                    if let Some(span) = self.in_flight_requests().span(request_id) {
+                        return Poll::Ready(Some(Ok((span.clone(), request_id, item))));
+                    }
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+
     /// Returns Ready if writing a message to the transport (i.e. via write_request or
     /// write_cancel) would not fail due to a full buffer. If the transport is not ready to be
     /// written to, flushes it until it is ready.
@@ -501,37 +1497,139 @@ impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
         self: &'a mut Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<(), ChannelError<C::Error>>>> {
-        let DispatchRequest {
-            ctx,
-            span,
-            request_id,
-            request,
-            response_completion,
-        } = match ready!(self.as_mut().poll_next_request(cx)?) {
+        let dispatch_request = match ready!(self.as_mut().poll_next_request(cx)?) {
             Some(dispatch_request) => dispatch_request,
             None => return Poll::Ready(None),
         };
-        let entered = span.enter();
+        if let ResponseCompletion::OneWay = dispatch_request.response_completion {
+            let DispatchRequest {
+                mut ctx,
+                span,
+                request,
+                ..
+            } = dispatch_request;
+            let _entered = span.enter();
+            self.clamp_deadline(&mut ctx);
+            self.start_send(ClientMessage::OneWay {
+                context: wire_context(&ctx),
+                message: request,
+            })?;
+            tracing::info!("SendOneWayRequest");
+            return Poll::Ready(Some(Ok(())));
+        }
+        let request = match self.track_request(dispatch_request) {
+            Some(request) => request,
+            None => return Poll::Ready(Some(Ok(()))),
+        };
+        let message = if self.config.max_batch_size > 1 {
+            let mut requests = vec![request];
+            self.fill_batch(cx, &mut requests);
+            if requests.len() > 1 {
+                tracing::debug!(requests = requests.len(), "SendBatch");
+                ClientMessage::Batch { requests }
+            } else {
+                ClientMessage::Request(requests.pop().unwrap())
+            }
+        } else {
+            ClientMessage::Request(request)
+        };
         // poll_next_request only returns Ready if there is room to buffer another request.
         // Therefore, we can call write_request without fear of erroring due to a full
         // buffer.
-        let request_id = request_id;
-        let request = ClientMessage::Request(Request {
+        if util::fail_point("tarpc::client::before_send", |_| ()).is_none() {
+            self.start_send(message)?;
+        }
+        Poll::Ready(Some(Ok(())))
+    }
+
+    /// Adds the pending requests that are ready to `requests`, up to the max batch size and the
+    /// in-flight request capacity. A one-way request ends the batch, and is sent after it.
+    fn fill_batch(
+        self: &mut Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        requests: &mut Vec<Request<Req>>,
+    ) {
+        while requests.len() < self.config.max_batch_size
+            && self.in_flight_requests().len() < self.max_in_flight_requests()
+        {
+            let request = match self.pending_requests_mut().poll_recv(cx) {
+                Poll::Ready(Some(request)) => request,
+                Poll::Ready(None) | Poll::Pending => return,
+            };
+            if request.response_completion.is_closed() {
+                let _entered = request.span.enter();
+                tracing::info!("AbortRequest");
+                continue;
+            }
+            if let ResponseCompletion::OneWay = request.response_completion {
+                *self.as_mut().project().deferred_request = Some(request);
+                return;
+            }
+            requests.extend(self.track_request(request));
+        }
+    }
+
+    /// Starts tracking the request as in flight, and returns the request to write to the wire. If
+    /// a request with the same ID is already in flight, e.g. because the
+    /// [generator](Config::request_ids) repeated an ID, the request is failed instead, and
+    /// nothing is written.
+    fn track_request(
+        self: &mut Pin<&mut Self>,
+        dispatch_request: DispatchRequest<Req, Resp>,
+    ) -> Option<Request<Req>> {
+        let DispatchRequest {
+            mut ctx,
+            span,
+            method,
+            request_id,
+            request,
+            request_items,
+            response_completion,
+        } = dispatch_request;
+        let entered = span.enter();
+        self.clamp_deadline(&mut ctx);
+        let request = Request {
             id: request_id,
             message: request,
-            context: context::Context {
-                deadline: ctx.deadline,
-                trace_context: ctx.trace_context,
-            },
-        });
-        self.start_send(request)?;
-        tracing::info!("SendRequest");
+            context: wire_context(&ctx),
+        };
         drop(entered);
 
-        self.in_flight_requests()
-            .insert_request(request_id, ctx, span, response_completion)
-            .expect("Request IDs should be unique");
-        Poll::Ready(Some(Ok(())))
+        let request_span = span.clone();
+        if self
+            .in_flight_requests()
+            .insert_request(request_id, method, ctx, span, response_completion)
+            .is_err()
+        {
+            let _entered = request_span.enter();
+            tracing::warn!("DuplicateRequestId");
+            return None;
+        }
+        request_span.in_scope(|| tracing::info!("SendRequest"));
+        if let Some(metrics) = &self.config.metrics {
+            metrics.queue_depth(Side::Client, self.pending_requests.len());
+        }
+        if let Some(items) = request_items {
+            self.request_streams_mut().push(RequestItems {
+                request_id,
+                items: Some(items),
+            });
+        }
+        Some(request)
+    }
+
+    /// Clamps the deadline of the request to the configured policy, if any.
+    fn clamp_deadline(&self, ctx: &mut context::Context) {
+        if let Some(policy) = &self.config.deadline_clamp {
+            let deadline = policy.clamp(ctx.deadline, crate::util::time::now());
+            if deadline != ctx.deadline {
+                tracing::debug!(
+                    rpc.deadline = %humantime::format_rfc3339(deadline),
+                    "ClampDeadline"
+                );
+                ctx.deadline = deadline;
+            }
+        }
     }
 
     fn poll_write_cancel<'a>(
@@ -553,15 +1651,107 @@ impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
         Poll::Ready(Some(Ok(())))
     }
 
-    /// Sends a server response to the client task that initiated the associated request.
-    fn complete(mut self: Pin<&mut Self>, response: Response<Resp>) -> bool {
-        self.in_flight_requests().complete_request(response)
+    fn poll_write_stream_credit<'a>(
+        self: &'a mut Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(), ChannelError<C::Error>>>> {
+        let (span, request_id, credits) = match ready!(self.as_mut().poll_next_stream_credit(cx)?) {
+            Some(credit) => credit,
+            None => return Poll::Ready(None),
+        };
+        let _entered = span.enter();
+
+        let credit = ClientMessage::StreamCredit {
+            request_id,
+            credits,
+        };
+        self.start_send(credit)?;
+        tracing::trace!("SendStreamCredit");
+        Poll::Ready(Some(Ok(())))
+    }
+
+    fn poll_write_request_item<'a>(
+        self: &'a mut Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(), ChannelError<C::Error>>>> {
+        let (span, request_id, item) = match ready!(self.as_mut().poll_next_request_item(cx)?) {
+            Some(triple) => triple,
+            None => return Poll::Ready(None),
+        };
+        let _entered = span.enter();
+
+        match item {
+            Some(item) => {
+                self.start_send(ClientMessage::StreamItem { request_id, item })?;
+                tracing::trace!("SendStreamItem");
+            }
+            None => {
+                self.start_send(ClientMessage::StreamEnd { request_id })?;
+                tracing::info!("SendStreamEnd");
+            }
+        }
+        Poll::Ready(Some(Ok(())))
+    }
+
+    /// Sends a server message to the client task that initiated the associated request.
+    fn complete(mut self: Pin<&mut Self>, message: ServerMessage<Resp>) -> bool {
+        match message {
+            ServerMessage::Response(response) => {
+                let in_flight = self.in_flight_requests().len();
+                match self.in_flight_requests().complete_request(response) {
+                    Some(rtt) => {
+                        if let Some(limit) = self.concurrency_limit_mut() {
+                            limit.on_response(rtt, in_flight);
+                        }
+                        true
+                    }
+                    None => false,
+                }
+            }
+            ServerMessage::StreamItem { request_id, item } => {
+                self.in_flight_requests().stream_item(request_id, item)
+            }
+            ServerMessage::StreamEnd { request_id } => {
+                self.in_flight_requests().end_stream(request_id)
+            }
+            ServerMessage::KeepAlive { request_id } => {
+                self.in_flight_requests().keep_alive(request_id)
+            }
+            ServerMessage::Push(message) => {
+                if let Err(mpsc::error::TrySendError::Full(_)) =
+                    self.as_mut().project().pushes.try_send(message)
+                {
+                    tracing::warn!("DroppedPush: the buffer of pushed messages is full");
+                }
+                true
+            }
+            ServerMessage::Hello { capabilities } => {
+                tracing::info!(?capabilities, "ReceiveHello");
+                *self
+                    .peer_capabilities
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner()) = Some(capabilities);
+                true
+            }
+            ServerMessage::Close { aborted } => {
+                tracing::info!(aborted = aborted.len(), "ReceiveClose");
+                for request_id in aborted {
+                    self.in_flight_requests().complete_request(Response {
+                        request_id,
+                        message: Err(ServerError::shutdown(
+                            "the server closed the channel before responding",
+                        )),
+                    });
+                }
+                true
+            }
+        }
     }
 }
 
 impl<Req, Resp, C> Future for RequestDispatch<Req, Resp, C>
-    where
-        C: Transport<ClientMessage<Req>, Response<Resp>>,
+where
+    C: Transport<ClientMessage<Req>, ServerMessage<Resp>>,
 {
     type Output = Result<(), ChannelError<C::Error>>;
 
@@ -569,16 +1759,38 @@ impl<Req, Resp, C> Future for RequestDispatch<Req, Resp, C>
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), ChannelError<C::Error>>> {
-        loop {
-            match (self.as_mut().pump_read(cx)?, self.as_mut().pump_write(cx)?) {
-                (Poll::Ready(None), _) => {
-                    tracing::info!("Shutdown: read half closed, so shutting down.");
-                    return Poll::Ready(Ok(()));
-                }
-                (read, Poll::Ready(None)) => {
-                    if self.in_flight_requests.is_empty() {
-                        tracing::info!("Shutdown: write half closed, and no requests in flight.");
-                        return Poll::Ready(Ok(()));
+        let span = self.span.clone();
+        let _entered = span.enter();
+        let result = ready!(self.as_mut().run(cx));
+        let reason = match &result {
+            Ok(reason) => reason.clone(),
+            Err(e) => CloseReason::failed(e),
+        };
+        if let Some(closed) = self.as_mut().project().closed.take() {
+            let _ = closed.send(reason);
+        }
+        Poll::Ready(result.map(|_| ()))
+    }
+}
+
+impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
+where
+    C: Transport<ClientMessage<Req>, ServerMessage<Resp>>,
+{
+    fn run(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<CloseReason, ChannelError<C::Error>>> {
+        loop {
+            match (self.as_mut().pump_read(cx)?, self.as_mut().pump_write(cx)?) {
+                (Poll::Ready(None), _) => {
+                    tracing::info!("Shutdown: read half closed, so shutting down.");
+                    return Poll::Ready(Ok(CloseReason::ServerClosed));
+                }
+                (read, Poll::Ready(None)) => {
+                    if self.in_flight_requests.is_empty() {
+                        tracing::info!("Shutdown: write half closed, and no requests in flight.");
+                        return Poll::Ready(Ok(CloseReason::ChannelsDropped));
                     }
                     tracing::info!(
                         "Shutdown: write half closed, and {} requests in flight.",
@@ -602,30 +1814,117 @@ impl<Req, Resp, C> Future for RequestDispatch<Req, Resp, C>
 struct DispatchRequest<Req, Resp> {
     pub ctx: context::Context,
     pub span: Span,
+    pub method: &'static str,
     pub request_id: u64,
     pub request: Req,
-    pub response_completion: oneshot::Sender<Result<Response<Resp>, DeadlineExceededError>>,
+    pub request_items: Option<item_mpsc::Receiver<Req>>,
+    pub response_completion: ResponseCompletion<Resp>,
+}
+
+/// Returns a queue of requests from channels to the dispatch that holds `buffer` requests, and
+/// counts the requests it holds.
+fn dispatch_queue<Req, Resp>(
+    buffer: usize,
+) -> (DispatchSender<Req, Resp>, PendingRequests<Req, Resp>) {
+    let (tx, rx) = mpsc::channel(buffer);
+    let len = Arc::new(AtomicUsize::new(0));
+    (
+        DispatchSender {
+            tx,
+            len: len.clone(),
+        },
+        PendingRequests { rx, len },
+    )
+}
+
+/// Sends requests from a [`Channel`] to the dispatch.
+#[derive(Debug)]
+struct DispatchSender<Req, Resp> {
+    tx: mpsc::Sender<DispatchRequest<Req, Resp>>,
+    /// The number of requests queued, shared with [`PendingRequests`].
+    len: Arc<AtomicUsize>,
+}
+
+impl<Req, Resp> Clone for DispatchSender<Req, Resp> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            len: self.len.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> DispatchSender<Req, Resp> {
+    /// Waits for room in the queue, then queues `request`.
+    async fn send(
+        &self,
+        request: DispatchRequest<Req, Resp>,
+    ) -> Result<(), mpsc::error::SendError<DispatchRequest<Req, Resp>>> {
+        let permit = match self.tx.reserve().await {
+            Ok(permit) => permit,
+            Err(mpsc::error::SendError(())) => return Err(mpsc::error::SendError(request)),
+        };
+        // Counted before it's sent, so that the dispatch never receives an uncounted request.
+        self.len.fetch_add(1, Ordering::Relaxed);
+        permit.send(request);
+        Ok(())
+    }
+
+    fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+/// The requests queued for the dispatch.
+#[derive(Debug)]
+struct PendingRequests<Req, Resp> {
+    rx: mpsc::Receiver<DispatchRequest<Req, Resp>>,
+    /// The number of requests queued, shared with the [`DispatchSender`]s.
+    len: Arc<AtomicUsize>,
+}
+
+impl<Req, Resp> PendingRequests<Req, Resp> {
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<DispatchRequest<Req, Resp>>> {
+        let request = ready!(self.rx.poll_recv(cx));
+        if request.is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        Poll::Ready(request)
+    }
+
+    /// Returns the number of requests queued.
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{cancellations, Channel, DispatchRequest, RequestDispatch, ResponseGuard};
+    use super::{
+        cancellations, dispatch_queue, Channel, CloseReason, DispatchRequest, RequestDispatch,
+        ResponseGuard, RpcError, PUSH_BUFFER,
+    };
     use crate::{
         client::{
-            in_flight_requests::{DeadlineExceededError, InFlightRequests},
+            in_flight_requests::{DeadlineExceededError, InFlightRequests, ResponseCompletion},
+            limits::{self, GradientLimit},
+            new,
+            request_ids::{self, RequestIdGenerator},
             Config,
         },
-        context,
+        context::{self, SizeHint, TransportClass, TransportHint},
+        metrics::EventRecorder,
         transport::{self, channel::UnboundedChannel},
-        ClientMessage, Response,
+        ClientMessage, Response, ServerError, ServerMessage,
     };
     use assert_matches::assert_matches;
-    use futures::{prelude::*, task::*};
+    use fnv::FnvHashMap;
+    use futures::{prelude::*, stream::SelectAll, task::*};
     use std::{
-        convert::TryFrom,
+        io,
         pin::Pin,
-        sync::atomic::{AtomicUsize, Ordering},
-        sync::Arc,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
     };
     use tokio::sync::{mpsc, oneshot};
     use tracing::Span;
@@ -638,19 +1937,59 @@ mod tests {
 
         dispatch
             .in_flight_requests
-            .insert_request(0, context::current(), Span::current(), tx)
+            .insert_request(
+                0,
+                "",
+                context::current(),
+                Span::current(),
+                ResponseCompletion::Unary(tx),
+            )
             .unwrap();
         server_channel
-            .send(Response {
-                request_id: 0,
-                message: Ok("Resp".into()),
-            })
+            .send(
+                Response {
+                    request_id: 0,
+                    message: Ok("Resp".into()),
+                }
+                .into(),
+            )
             .await
             .unwrap();
         assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
         assert_matches!(rx.try_recv(), Ok(Ok(Response { request_id: 0, message: Ok(resp) })) if resp == "Resp");
     }
 
+    #[tokio::test]
+    async fn close_completes_aborted_requests() {
+        let (mut dispatch, mut _channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (tx, mut rx) = oneshot::channel();
+
+        dispatch
+            .in_flight_requests
+            .insert_request(
+                0,
+                "",
+                context::current(),
+                Span::current(),
+                ResponseCompletion::Unary(tx),
+            )
+            .unwrap();
+        server_channel
+            .send(ServerMessage::Close { aborted: vec![0] })
+            .await
+            .unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert_matches!(
+            rx.try_recv(),
+            Ok(Ok(Response {
+                request_id: 0,
+                message: Err(e),
+            })) if e.cause() == crate::ServerErrorCause::Shutdown && e.cause().is_retryable()
+        );
+        assert!(dispatch.in_flight_requests.is_empty());
+    }
+
     #[tokio::test]
     async fn dispatch_response_cancels_on_drop() {
         let (cancellation, mut canceled_requests) = cancellations();
@@ -674,7 +2013,7 @@ mod tests {
             request_id: 0,
             message: Ok("well done"),
         }))
-            .unwrap();
+        .unwrap();
         // resp's drop() is run, but should not send a cancel message.
         ResponseGuard {
             response: &mut response,
@@ -682,9 +2021,9 @@ mod tests {
             request_id: 3,
             cancel: true,
         }
-            .response()
-            .await
-            .unwrap();
+        .response()
+        .await
+        .unwrap();
         drop(cancellation);
         let cx = &mut Context::from_waker(noop_waker_ref());
         assert_eq!(canceled_requests.poll_recv(cx), Poll::Ready(None));
@@ -699,7 +2038,7 @@ mod tests {
         let _resp = send_request(&mut channel, "hi", tx, &mut rx).await;
 
         #[allow(unstable_name_collisions)]
-            let req = dispatch.as_mut().poll_next_request(cx).ready();
+        let req = dispatch.as_mut().poll_next_request(cx).ready();
         assert!(req.is_some());
 
         let req = req.unwrap();
@@ -707,6 +2046,103 @@ mod tests {
         assert_eq!(req.request, "hi".to_string());
     }
 
+    #[tokio::test]
+    async fn stage_request_waits_for_adaptive_limit() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        dispatch.concurrency_limit = Some(GradientLimit::new(limits::Gradient {
+            initial_limit: 1,
+            ..Default::default()
+        }));
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (tx1, mut rx1) = oneshot::channel();
+        let (tx2, mut rx2) = oneshot::channel();
+        let mut channel2 = channel.clone();
+
+        let _resp1 = send_request(&mut channel, "hi", tx1, &mut rx1).await;
+        assert_matches!(
+            dispatch.as_mut().poll_write_request(cx),
+            Poll::Ready(Some(Ok(())))
+        );
+        let _resp2 = send_request(&mut channel2, "hi", tx2, &mut rx2).await;
+        assert_matches!(dispatch.as_mut().poll_next_request(cx), Poll::Pending);
+
+        send_response(
+            &mut server_channel,
+            Response {
+                request_id: 0,
+                message: Ok("hello".into()),
+            },
+        )
+        .await;
+        assert_matches!(dispatch.as_mut().pump_read(cx), Poll::Ready(Some(Ok(()))));
+        assert_matches!(
+            dispatch.as_mut().poll_next_request(cx),
+            Poll::Ready(Some(Ok(_)))
+        );
+    }
+
+    #[tokio::test]
+    async fn closed_resolves_when_dispatch_fails() {
+        let (mut dispatch, channel, server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let closed = channel.closed();
+
+        drop(server_channel);
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Ready(Err(_)));
+        assert_matches!(closed.await, CloseReason::Failed(e)
+            if e.starts_with("could not ready the transport for writes: "));
+        assert_matches!(channel.closed().await, CloseReason::Failed(_));
+    }
+
+    #[tokio::test]
+    async fn closed_resolves_when_dispatch_dropped() {
+        let (dispatch, channel, _server_channel) = set_up();
+
+        drop(dispatch);
+        assert_eq!(channel.closed().await, CloseReason::DispatchDropped);
+    }
+
+    #[tokio::test]
+    async fn route_follows_transport_hint() {
+        let (local_dispatch, local, _local_server) = set_up();
+        let (remote_dispatch, remote, _remote_server) = set_up();
+        let channel = local
+            .with_transport_class(TransportClass::LOCAL)
+            .with_transport_fallback(remote.with_transport_class(TransportClass::REMOTE));
+        let route = |transport| {
+            let ctx = context::Context {
+                transport,
+                ..context::current()
+            };
+            channel.route(&ctx).map(|channel| channel.class)
+        };
+
+        assert_eq!(route(None), Ok(Some(TransportClass::LOCAL)));
+        assert_eq!(
+            route(Some(TransportHint::Prefer(TransportClass::REMOTE))),
+            Ok(Some(TransportClass::REMOTE))
+        );
+        assert_matches!(
+            route(Some(TransportHint::Require(TransportClass::new("leader")))),
+            Err(RpcError::Disconnected(_))
+        );
+
+        // Requests without a hint, or with a preference, fall back to open channels.
+        drop(local_dispatch);
+        assert_eq!(route(None), Ok(Some(TransportClass::REMOTE)));
+        assert_eq!(
+            route(Some(TransportHint::Prefer(TransportClass::LOCAL))),
+            Ok(Some(TransportClass::REMOTE))
+        );
+        assert_eq!(
+            route(Some(TransportHint::Require(TransportClass::LOCAL))),
+            Ok(Some(TransportClass::LOCAL))
+        );
+
+        drop(remote_dispatch);
+        assert_eq!(route(None), Ok(Some(TransportClass::LOCAL)));
+    }
+
     // Regression test for  https://github.com/google/tarpc/issues/220
     #[tokio::test]
     async fn stage_request_channel_dropped_doesnt_panic() {
@@ -725,7 +2161,7 @@ mod tests {
                 message: Ok("hello".into()),
             },
         )
-            .await;
+        .await;
         dispatch.await.unwrap();
     }
 
@@ -782,37 +2218,485 @@ mod tests {
         assert!(dispatch.as_mut().poll_next_request(cx).is_pending());
     }
 
+    #[tokio::test]
+    async fn stream_items_complete_response_stream() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let mut stream = channel
+            .call_stream(context::current(), "", "hi".into())
+            .await
+            .unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::Request(_)))
+        );
+
+        server_channel
+            .send(ServerMessage::StreamItem {
+                request_id: 0,
+                item: "item".into(),
+            })
+            .await
+            .unwrap();
+        server_channel
+            .send(ServerMessage::StreamEnd { request_id: 0 })
+            .await
+            .unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert!(dispatch.in_flight_requests.is_empty());
+
+        assert_matches!(stream.next().await, Some(Ok(item)) if item == "item");
+        assert_matches!(stream.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn response_stream_grants_credit_per_consumed_item() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let mut stream = channel
+            .call_stream(context::current(), "", "hi".into())
+            .await
+            .unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::Request(_)))
+        );
+
+        server_channel
+            .send(ServerMessage::StreamItem {
+                request_id: 0,
+                item: "item".into(),
+            })
+            .await
+            .unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert_matches!(stream.next().await, Some(Ok(_)));
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::StreamCredit {
+                request_id: 0,
+                credits: 1
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn response_stream_coalesces_credits() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let mut stream = channel
+            .call_stream(context::current(), "", "hi".into())
+            .await
+            .unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::Request(_)))
+        );
+
+        for item in ["a", "b", "c"] {
+            server_channel
+                .send(ServerMessage::StreamItem {
+                    request_id: 0,
+                    item: item.into(),
+                })
+                .await
+                .unwrap();
+        }
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        for _ in 0..3 {
+            assert_matches!(stream.next().await, Some(Ok(_)));
+        }
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::StreamCredit {
+                request_id: 0,
+                credits: 3
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn request_sink_items_are_sent_after_request() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let (mut items, response) = channel
+            .call_client_stream(context::current(), "", "hi".into())
+            .await
+            .unwrap();
+        items.send("a".into()).await.unwrap();
+        items.send("b".into()).await.unwrap();
+        items.close().await.unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::Request(crate::Request { id: 0, .. })))
+        );
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::StreamItem { request_id: 0, item })) if item == "a"
+        );
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::StreamItem { request_id: 0, item })) if item == "b"
+        );
+        assert_matches!(
+            server_channel.next().await,
+            Some(Ok(ClientMessage::StreamEnd { request_id: 0 }))
+        );
+
+        send_response(
+            &mut server_channel,
+            Response {
+                request_id: 0,
+                message: Ok("hello".into()),
+            },
+        )
+        .await;
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert_matches!(response.await, Ok(resp) if resp == "hello");
+    }
+
+    #[tokio::test]
+    async fn response_stream_error_ends_stream() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let mut stream = channel
+            .call_stream(context::current(), "", "hi".into())
+            .await
+            .unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+
+        send_response(
+            &mut server_channel,
+            Response {
+                request_id: 0,
+                message: Err(crate::ServerError::new(std::io::ErrorKind::Other, "error")),
+            },
+        )
+        .await;
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+
+        assert_matches!(stream.next().await, Some(Err(RpcError::Server(_))));
+        assert_matches!(stream.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn response_stream_dropped_is_canceled() {
+        let (mut dispatch, channel, _server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+
+        let stream = channel
+            .call_stream(context::current(), "", "hi".into())
+            .await
+            .unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert!(!dispatch.in_flight_requests.is_empty());
+
+        drop(stream);
+        assert_matches!(
+            dispatch.as_mut().poll_next_cancellation(cx),
+            Poll::Ready(Some(Ok(_)))
+        );
+        assert!(dispatch.in_flight_requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn request_sizer_advises_request_size() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let channel = channel.with_request_sizer(|request: &String| request.len() as u64);
+
+        let _stream = channel
+            .call_stream(context::current(), "", "hello".into())
+            .await
+            .unwrap();
+        // A size advised by the caller is kept.
+        let mut ctx = context::current();
+        ctx.size_hint.request = Some(1);
+        ctx.size_hint.response = Some(2);
+        let _stream = channel.call_stream(ctx, "", "hi".into()).await.unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+
+        for expected in [
+            SizeHint {
+                request: Some(5),
+                response: None,
+            },
+            SizeHint {
+                request: Some(1),
+                response: Some(2),
+            },
+        ] {
+            match server_channel.next().await.unwrap().unwrap() {
+                ClientMessage::Request(request) => {
+                    assert_eq!(request.context.size_hint, expected)
+                }
+                message => panic!("Unexpected message: {:?}", message),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatch_records_metrics() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let recorder = Arc::new(EventRecorder::default());
+        dispatch.config.metrics = Some(recorder.clone());
+        dispatch.in_flight_requests = InFlightRequests::new(Some(recorder.clone()));
+
+        let _stream = channel
+            .call_stream(context::current(), "World.hello", "hi".into())
+            .await
+            .unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        let request_id = match server_channel.next().await.unwrap().unwrap() {
+            ClientMessage::Request(request) => request.id,
+            message => panic!("Unexpected message: {:?}", message),
+        };
+        server_channel
+            .send(ServerMessage::StreamEnd { request_id })
+            .await
+            .unwrap();
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+
+        assert_eq!(
+            recorder.events(),
+            [
+                "client started World.hello",
+                "client queue 0",
+                "client completed World.hello success",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn dispatch_queue_counts_queued_requests() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (to_dispatch, mut pending_requests) = dispatch_queue::<String, String>(3);
+        let mut responses = vec![];
+        for request_id in 0..2 {
+            let (tx, rx) = oneshot::channel();
+            responses.push(rx);
+            to_dispatch
+                .send(DispatchRequest {
+                    ctx: context::current(),
+                    span: Span::none(),
+                    method: "",
+                    request_id,
+                    request: "hi".into(),
+                    request_items: None,
+                    response_completion: ResponseCompletion::Unary(tx),
+                })
+                .await
+                .unwrap();
+        }
+        assert_eq!(pending_requests.len(), 2);
+
+        assert_matches!(pending_requests.poll_recv(cx), Poll::Ready(Some(_)));
+        assert_eq!(pending_requests.len(), 1);
+        assert_matches!(pending_requests.poll_recv(cx), Poll::Ready(Some(_)));
+        assert_matches!(pending_requests.poll_recv(cx), Poll::Pending);
+        assert_eq!(pending_requests.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn dispatch_clamps_deadlines() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let policy = limits::DeadlineClamp::new(Duration::from_secs(1), Duration::from_secs(60));
+        dispatch.config.deadline_clamp = Some(policy.clone());
+
+        let (tx, mut rx) = oneshot::channel();
+        let resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+        dispatch.as_mut().poll_write_request(cx).ready();
+        drop(resp);
+        let deadline = match server_channel.next().await.unwrap().unwrap() {
+            ClientMessage::Request(request) => request.context.deadline,
+            message => panic!("Unexpected message: {:?}", message),
+        };
+        // The context of send_request has the default deadline, 10s from now.
+        assert!(deadline > SystemTime::now() + Duration::from_secs(5));
+        assert_eq!(policy.clamped(), 0);
+
+        dispatch.config.deadline_clamp = Some(limits::DeadlineClamp::new(
+            Duration::ZERO,
+            Duration::from_secs(1),
+        ));
+        let (tx, mut rx) = oneshot::channel();
+        let _resp = send_request(&mut channel, "hi", tx, &mut rx).await;
+        dispatch.as_mut().poll_write_request(cx).ready();
+        let deadline = match server_channel.next().await.unwrap().unwrap() {
+            ClientMessage::Request(request) => request.context.deadline,
+            message => panic!("Unexpected message: {:?}", message),
+        };
+        assert!(deadline <= SystemTime::now() + Duration::from_secs(1));
+        assert_eq!(
+            dispatch.config.deadline_clamp.as_ref().unwrap().clamped(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn dispatch_batches_pending_requests() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        dispatch.config.max_batch_size = 2;
+
+        let (tx, mut rx1) = oneshot::channel();
+        let mut channel1 = channel.clone();
+        let _resp1 = send_request(&mut channel1, "one", tx, &mut rx1).await;
+        let (tx, mut rx2) = oneshot::channel();
+        let mut channel2 = channel.clone();
+        let _resp2 = send_request(&mut channel2, "two", tx, &mut rx2).await;
+        let (tx, mut rx3) = oneshot::channel();
+        let mut channel3 = channel.clone();
+        let _resp3 = send_request(&mut channel3, "three", tx, &mut rx3).await;
+
+        dispatch.as_mut().poll_write_request(cx).ready();
+        match server_channel.next().await.unwrap().unwrap() {
+            ClientMessage::Batch { requests } => assert_eq!(
+                requests
+                    .into_iter()
+                    .map(|request| request.message)
+                    .collect::<Vec<_>>(),
+                ["one", "two"]
+            ),
+            message => panic!("Unexpected message: {:?}", message),
+        }
+        // A lone request isn't batched.
+        dispatch.as_mut().poll_write_request(cx).ready();
+        assert_matches!(
+            server_channel.next().await.unwrap().unwrap(),
+            ClientMessage::Request(crate::Request { message, .. }) if message == "three"
+        );
+        assert_eq!(dispatch.in_flight_requests.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn request_ids_continue_across_reconnects() {
+        let config = Config::default();
+        let start_request = |config: &Config| {
+            let (client_channel, _server_channel) = transport::channel::unbounded();
+            let client: Channel<String, String> = new(config.clone(), client_channel).client;
+            client.start_request(&mut context::current(), &Span::none())
+        };
+
+        assert_eq!(start_request(&config), 0);
+        assert_eq!(start_request(&config), 1);
+    }
+
+    #[tokio::test]
+    async fn duplicate_request_id_fails_only_that_request() {
+        #[derive(Debug)]
+        struct Repeat;
+
+        impl RequestIdGenerator for Repeat {
+            fn next_request_id(&self) -> u64 {
+                7
+            }
+        }
+
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        channel.request_ids = Arc::new(Repeat);
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let (tx1, mut rx1) = oneshot::channel();
+        let (tx2, mut rx2) = oneshot::channel();
+        let mut channel2 = channel.clone();
+
+        let resp1 = send_request(&mut channel, "one", tx1, &mut rx1).await;
+        let resp2 = send_request(&mut channel2, "two", tx2, &mut rx2).await;
+        dispatch.as_mut().poll_write_request(cx).ready();
+        dispatch.as_mut().poll_write_request(cx).ready();
+        assert_matches!(
+            resp2.response().await,
+            Err(RpcError::Server(ServerError {
+                kind: io::ErrorKind::AlreadyExists,
+                ..
+            }))
+        );
+
+        // The dispatch keeps serving the request in flight under the ID.
+        assert_matches!(
+            server_channel.next().await.unwrap().unwrap(),
+            ClientMessage::Request(crate::Request { id: 7, message, .. }) if message == "one"
+        );
+        send_response(
+            &mut server_channel,
+            Response {
+                request_id: 7,
+                message: Ok("done".to_string()),
+            },
+        )
+        .await;
+        assert_matches!(dispatch.as_mut().poll(cx), Poll::Pending);
+        assert_eq!(resp1.response().await.unwrap(), "done");
+        assert!(dispatch.in_flight_requests.is_empty());
+    }
+
     fn set_up() -> (
         Pin<
             Box<
                 RequestDispatch<
                     String,
                     String,
-                    UnboundedChannel<Response<String>, ClientMessage<String>>,
+                    UnboundedChannel<ServerMessage<String>, ClientMessage<String>>,
                 >,
             >,
         >,
         Channel<String, String>,
-        UnboundedChannel<ClientMessage<String>, Response<String>>,
+        UnboundedChannel<ClientMessage<String>, ServerMessage<String>>,
     ) {
         let _ = tracing_subscriber::fmt().with_test_writer().try_init();
 
-        let (to_dispatch, pending_requests) = mpsc::channel(1);
+        let (to_dispatch, pending_requests) = dispatch_queue(3);
         let (cancellation, canceled_requests) = cancellations();
+        let (stream_credits_tx, stream_credits) = mpsc::unbounded_channel();
         let (client_channel, server_channel) = transport::channel::unbounded();
+        let (closed_tx, closed) = oneshot::channel();
+        let (pushes_tx, pushes) = mpsc::channel(PUSH_BUFFER);
 
         let dispatch = RequestDispatch::<String, String, _> {
             transport: client_channel.fuse(),
             pending_requests,
+            deferred_request: None,
             canceled_requests,
+            stream_credits,
+            pending_stream_credits: FnvHashMap::default(),
+            request_streams: SelectAll::new(),
             in_flight_requests: InFlightRequests::default(),
             config: Config::default(),
+            concurrency_limit: None,
+            closed: Some(closed_tx),
+            pushes: pushes_tx,
+            hello: None,
+            peer_capabilities: Arc::new(Mutex::new(None)),
+            span: Span::none(),
         };
 
         let channel = Channel {
             to_dispatch,
             cancellation,
-            next_request_id: Arc::new(AtomicUsize::new(0)),
+            stream_credits: stream_credits_tx,
+            request_ids: Arc::new(request_ids::Sequential::new()),
+            closed: closed.shared(),
+            class: None,
+            fallbacks: Arc::new([]),
+            pushes: Arc::new(Mutex::new(Some(pushes))),
+            peer_capabilities: dispatch.peer_capabilities.clone(),
+            request_sizer: None,
+            failover: None,
+            metrics: None,
         };
 
         (Box::pin(dispatch), channel, server_channel)
@@ -824,14 +2708,15 @@ mod tests {
         response_completion: oneshot::Sender<Result<Response<String>, DeadlineExceededError>>,
         response: &'a mut oneshot::Receiver<Result<Response<String>, DeadlineExceededError>>,
     ) -> ResponseGuard<'a, String> {
-        let request_id =
-            u64::try_from(channel.next_request_id.fetch_add(1, Ordering::Relaxed)).unwrap();
+        let request_id = channel.request_ids.next_request_id();
         let request = DispatchRequest {
             ctx: context::current(),
             span: Span::current(),
+            method: "",
             request_id,
             request: request.to_string(),
-            response_completion,
+            request_items: None,
+            response_completion: ResponseCompletion::Unary(response_completion),
         };
         let response_guard = ResponseGuard {
             response,
@@ -844,33 +2729,23 @@ mod tests {
     }
 
     async fn send_response(
-        channel: &mut UnboundedChannel<ClientMessage<String>, Response<String>>,
+        channel: &mut UnboundedChannel<ClientMessage<String>, ServerMessage<String>>,
         response: Response<String>,
     ) {
-        channel.send(response).await.unwrap();
+        channel.send(response.into()).await.unwrap();
     }
 
     trait PollTest {
         type T;
-        fn unwrap(self) -> Poll<Self::T>;
         fn ready(self) -> Self::T;
     }
 
     impl<T, E> PollTest for Poll<Option<Result<T, E>>>
-        where
-            E: ::std::fmt::Display,
+    where
+        E: ::std::fmt::Display,
     {
         type T = Option<T>;
 
-        fn unwrap(self) -> Poll<Option<T>> {
-            match self {
-                Poll::Ready(Some(Ok(t))) => Poll::Ready(Some(t)),
-                Poll::Ready(None) => Poll::Ready(None),
-                Poll::Ready(Some(Err(e))) => panic!("{}", e.to_string()),
-                Poll::Pending => Poll::Pending,
-            }
-        }
-
         fn ready(self) -> Option<T> {
             match self {
                 Poll::Ready(Some(Ok(t))) => Some(t),
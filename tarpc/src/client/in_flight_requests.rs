@@ -1,15 +1,22 @@
 use crate::{
+    client::RpcError,
     context,
-    util::{Compact, TimeUntil},
-    Response,
+    metrics::{Outcome, Recorder, Side},
+    util::{
+        time::{DelayQueue, Instant, Key},
+        Compact, TimeUntil,
+    },
+    Response, ServerError,
 };
 use fnv::FnvHashMap;
 use std::{
     collections::hash_map,
+    io,
+    sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
-use tokio::sync::oneshot;
-use tokio_util::time::delay_queue::{self, DelayQueue};
+use tokio::sync::{mpsc, oneshot};
 use tracing::Span;
 
 /// Requests already written to the wire that haven't yet received responses.
@@ -17,14 +24,12 @@ use tracing::Span;
 pub struct InFlightRequests<Resp> {
     request_data: FnvHashMap<u64, RequestData<Resp>>,
     deadlines: DelayQueue<u64>,
+    recorder: Option<Arc<dyn Recorder>>,
 }
 
 impl<Resp> Default for InFlightRequests<Resp> {
     fn default() -> Self {
-        Self {
-            request_data: Default::default(),
-            deadlines: Default::default(),
-        }
+        Self::new(None)
     }
 }
 
@@ -34,13 +39,60 @@ impl<Resp> Default for InFlightRequests<Resp> {
 #[error("the request exceeded its deadline")]
 pub struct DeadlineExceededError;
 
+/// Delivers the server's response to the client task that initiated the request.
+#[derive(Debug)]
+pub enum ResponseCompletion<Resp> {
+    /// Completed by a single response.
+    Unary(oneshot::Sender<Result<Response<Resp>, DeadlineExceededError>>),
+    /// Fed each item of a server-streaming response. `None` marks the end of the stream.
+    Stream(mpsc::UnboundedSender<Option<Result<Resp, RpcError>>>),
+    /// Nothing awaits a response: the request is [one-way](crate::ClientMessage::OneWay), so it
+    /// is never in flight.
+    OneWay,
+}
+
+impl<Resp> ResponseCompletion<Resp> {
+    /// Returns true iff the client task is no longer waiting for the response.
+    pub fn is_closed(&self) -> bool {
+        match self {
+            ResponseCompletion::Unary(tx) => tx.is_closed(),
+            ResponseCompletion::Stream(tx) => tx.is_closed(),
+            ResponseCompletion::OneWay => false,
+        }
+    }
+
+    fn complete(self, response: Result<Response<Resp>, DeadlineExceededError>) {
+        match self {
+            ResponseCompletion::Unary(tx) => {
+                let _ = tx.send(response);
+            }
+            ResponseCompletion::Stream(tx) => {
+                let item = match response {
+                    Ok(response) => response.message.map_err(RpcError::from),
+                    Err(e) => Err(e.into()),
+                };
+                let _ = tx.send(Some(item));
+                let _ = tx.send(None);
+            }
+            ResponseCompletion::OneWay => {}
+        }
+    }
+}
+
 #[derive(Debug)]
 struct RequestData<Resp> {
+    /// The name of the method called.
+    method: &'static str,
     ctx: context::Context,
     span: Span,
-    response_completion: oneshot::Sender<Result<Response<Resp>, DeadlineExceededError>>,
+    response_completion: ResponseCompletion<Resp>,
+    /// When the request was handed to the transport. Its latency is measured from then, so it
+    /// includes the time the request spent in the transport's buffers.
+    sent: Instant,
+    /// When the request expires; starts as the context deadline, and is extended by keep-alives.
+    deadline: Instant,
     /// The key to remove the timer for the request's deadline.
-    deadline_key: delay_queue::Key,
+    deadline_key: Key,
 }
 
 /// An error returned when an attempt is made to insert a request with an ID that is already in
@@ -49,6 +101,27 @@ struct RequestData<Resp> {
 pub struct AlreadyExistsError;
 
 impl<Resp> InFlightRequests<Resp> {
+    /// Returns an empty set of in-flight requests, which records the requests with `recorder`,
+    /// if set.
+    pub fn new(recorder: Option<Arc<dyn Recorder>>) -> Self {
+        Self {
+            request_data: Default::default(),
+            deadlines: Default::default(),
+            recorder,
+        }
+    }
+
+    fn record_completion(&self, request_data: &RequestData<Resp>, outcome: Outcome) {
+        if let Some(recorder) = &self.recorder {
+            recorder.request_completed(
+                Side::Client,
+                request_data.method,
+                request_data.sent.elapsed(),
+                outcome,
+            );
+        }
+    }
+
     /// Returns the number of in-flight requests.
     pub fn len(&self) -> usize {
         self.request_data.len()
@@ -59,19 +132,28 @@ impl<Resp> InFlightRequests<Resp> {
         self.request_data.is_empty()
     }
 
-    /// Starts a request, unless a request with the same ID is already in flight.
+    /// Starts a request, unless a request with the same ID is already in flight, in which case the
+    /// new request is failed with an [`AlreadyExists`](io::ErrorKind::AlreadyExists) error.
     pub fn insert_request(
         &mut self,
         request_id: u64,
+        method: &'static str,
         ctx: context::Context,
         span: Span,
-        response_completion: oneshot::Sender<Result<Response<Resp>, DeadlineExceededError>>,
+        response_completion: ResponseCompletion<Resp>,
     ) -> Result<(), AlreadyExistsError> {
         match self.request_data.entry(request_id) {
             hash_map::Entry::Vacant(vacant) => {
+                if let Some(recorder) = &self.recorder {
+                    recorder.request_started(Side::Client, method);
+                }
                 let timeout = ctx.deadline.time_until();
                 let deadline_key = self.deadlines.insert(request_id, timeout);
+                let now = Instant::now();
                 vacant.insert(RequestData {
+                    method,
+                    sent: now,
+                    deadline: now + timeout,
                     ctx,
                     span,
                     response_completion,
@@ -79,19 +161,34 @@ impl<Resp> InFlightRequests<Resp> {
                 });
                 Ok(())
             }
-            hash_map::Entry::Occupied(_) => Err(AlreadyExistsError),
+            hash_map::Entry::Occupied(_) => {
+                response_completion.complete(Ok(Response {
+                    request_id,
+                    message: Err(ServerError::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("a request with ID {} is already in flight", request_id),
+                    )),
+                }));
+                Err(AlreadyExistsError)
+            }
         }
     }
 
-    /// Removes a request without aborting. Returns true iff the request was found.
-    pub fn complete_request(&mut self, response: Response<Resp>) -> bool {
+    /// Removes a request without aborting. Returns how long the request was in flight, if the
+    /// request was found.
+    pub fn complete_request(&mut self, response: Response<Resp>) -> Option<Duration> {
         if let Some(request_data) = self.request_data.remove(&response.request_id) {
             let _entered = request_data.span.enter();
             tracing::info!("ReceiveResponse");
             self.request_data.compact(0.1);
             self.deadlines.remove(&request_data.deadline_key);
-            let _ = request_data.response_completion.send(Ok(response));
-            return true;
+            let outcome = match response.message {
+                Ok(_) => Outcome::Success,
+                Err(_) => Outcome::Error,
+            };
+            self.record_completion(&request_data, outcome);
+            request_data.response_completion.complete(Ok(response));
+            return Some(request_data.sent.elapsed());
         }
 
         tracing::debug!(
@@ -100,15 +197,100 @@ impl<Resp> InFlightRequests<Resp> {
         );
 
         // If the response completion was absent, then the request was already canceled.
+        None
+    }
+
+    /// Forwards an item of a server-streaming response to the client task that initiated the
+    /// request. Returns true iff a streaming request was found.
+    pub fn stream_item(&mut self, request_id: u64, item: Resp) -> bool {
+        match self.request_data.get(&request_id) {
+            Some(RequestData {
+                span,
+                response_completion: ResponseCompletion::Stream(tx),
+                ..
+            }) => {
+                let _entered = span.enter();
+                tracing::info!("ReceiveStreamItem");
+                let _ = tx.send(Some(Ok(item)));
+                true
+            }
+            Some(RequestData { span, .. }) => {
+                let _entered = span.enter();
+                tracing::warn!("Received a stream item for a request that is not streaming.");
+                false
+            }
+            None => {
+                tracing::debug!(
+                    "No in-flight request found for request_id = {}.",
+                    request_id
+                );
+                false
+            }
+        }
+    }
+
+    /// Completes a server-streaming request. Returns true iff the request was found.
+    pub fn end_stream(&mut self, request_id: u64) -> bool {
+        if let Some(request_data) = self.request_data.remove(&request_id) {
+            let _entered = request_data.span.enter();
+            tracing::info!("ReceiveStreamEnd");
+            self.request_data.compact(0.1);
+            self.deadlines.remove(&request_data.deadline_key);
+            self.record_completion(&request_data, Outcome::Success);
+            if let ResponseCompletion::Stream(tx) = request_data.response_completion {
+                let _ = tx.send(None);
+            }
+            return true;
+        }
+
+        tracing::debug!(
+            "No in-flight request found for request_id = {}.",
+            request_id
+        );
         false
     }
 
+    /// Extends the deadline of a request whose context sets
+    /// [`keep_alive`](context::Context::keep_alive) to `keep_alive` from now, unless it is already
+    /// later. Returns true iff the request was found.
+    pub fn keep_alive(&mut self, request_id: u64) -> bool {
+        match self.request_data.get_mut(&request_id) {
+            Some(request_data) => {
+                let _entered = request_data.span.enter();
+                tracing::trace!("ReceiveKeepAlive");
+                if let Some(keep_alive) = request_data.ctx.keep_alive {
+                    let deadline = Instant::now() + keep_alive;
+                    if deadline > request_data.deadline {
+                        request_data.deadline = deadline;
+                        self.deadlines.reset(&request_data.deadline_key, keep_alive);
+                    }
+                }
+                true
+            }
+            None => {
+                tracing::debug!(
+                    "No in-flight request found for request_id = {}.",
+                    request_id
+                );
+                false
+            }
+        }
+    }
+
+    /// Returns the span of an in-flight request, if found.
+    pub fn span(&self, request_id: u64) -> Option<&Span> {
+        self.request_data
+            .get(&request_id)
+            .map(|request_data| &request_data.span)
+    }
+
     /// Cancels a request without completing (typically used when a request handle was dropped
     /// before the request completed).
     pub fn cancel_request(&mut self, request_id: u64) -> Option<(context::Context, Span)> {
         if let Some(request_data) = self.request_data.remove(&request_id) {
             self.request_data.compact(0.1);
             self.deadlines.remove(&request_data.deadline_key);
+            self.record_completion(&request_data, Outcome::Canceled);
             Some((request_data.ctx, request_data.span))
         } else {
             None
@@ -124,9 +306,10 @@ impl<Resp> InFlightRequests<Resp> {
                 let _entered = request_data.span.enter();
                 tracing::error!("DeadlineExceeded");
                 self.request_data.compact(0.1);
-                let _ = request_data
+                self.record_completion(&request_data, Outcome::Error);
+                request_data
                     .response_completion
-                    .send(Err(DeadlineExceededError));
+                    .complete(Err(DeadlineExceededError));
             }
             Some(request_id)
         })
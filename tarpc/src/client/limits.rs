@@ -0,0 +1,413 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::metrics::Recorder;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+/// Settings of an adaptive limit on the number of requests a client has in flight, which adjusts
+/// to the latency of responses.
+///
+/// The limit compares the latency of each response to a long-term average latency. While
+/// latencies stay within [`rtt_tolerance`](Self::rtt_tolerance) of the average, the limit grows;
+/// once the server starts queueing requests, latencies rise and the limit shrinks in proportion.
+/// This is the gradient algorithm of Netflix's
+/// [concurrency-limits](https://github.com/Netflix/concurrency-limits) library.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Gradient {
+    /// The limit before any responses are received. Must be at least 1.
+    pub initial_limit: usize,
+    /// The lowest the limit goes. Must be at least 1, so that the client can always send a
+    /// request.
+    pub min_limit: usize,
+    /// The highest the limit goes. The limit never exceeds
+    /// [`Config::max_in_flight_requests`](crate::client::Config::max_in_flight_requests), either.
+    pub max_limit: usize,
+    /// How far the limit moves towards its new estimate on each response. Must be greater than 0
+    /// and at most 1.
+    pub smoothing: f64,
+    /// How much higher than the long-term average a latency can be before the limit shrinks.
+    pub rtt_tolerance: f64,
+    /// The number of responses the long-term average latency is computed over. Must be at least 1.
+    pub long_window: usize,
+    /// The factor the limit is multiplied by when a request exceeds its deadline. Must be greater
+    /// than 0 and less than 1.
+    pub backoff_ratio: f64,
+}
+
+impl Default for Gradient {
+    fn default() -> Self {
+        Gradient {
+            initial_limit: 20,
+            min_limit: 1,
+            max_limit: 1_000,
+            smoothing: 0.2,
+            rtt_tolerance: 1.5,
+            long_window: 600,
+            backoff_ratio: 0.9,
+        }
+    }
+}
+
+/// A policy that clamps the deadline of each request to between [`min`](Self::min) and
+/// [`max`](Self::max) from when the request is sent.
+///
+/// A caller passing a deadline hours away would otherwise have the server hold resources for the
+/// request until then, and a deadline a millisecond away mostly produces requests that expire
+/// before they could be served.
+///
+/// Clones of a policy share the count of [clamped](Self::clamped) deadlines, so a clone kept after
+/// configuring a client reports how often its callers' deadlines are clamped.
+#[derive(Clone, Debug)]
+pub struct DeadlineClamp {
+    min: Duration,
+    max: Duration,
+    clamped: Arc<AtomicU64>,
+}
+
+impl DeadlineClamp {
+    /// Returns a policy that clamps deadlines to between `min` and `max` from now.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min` is greater than `max`.
+    pub fn new(min: Duration, max: Duration) -> Self {
+        assert!(
+            min <= max,
+            "min deadline {:?} is greater than max deadline {:?}",
+            min,
+            max
+        );
+        Self {
+            min,
+            max,
+            clamped: Arc::default(),
+        }
+    }
+
+    /// The least time until a request's deadline.
+    pub fn min(&self) -> Duration {
+        self.min
+    }
+
+    /// The most time until a request's deadline.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns the number of deadlines that were clamped.
+    pub fn clamped(&self) -> u64 {
+        self.clamped.load(Ordering::Relaxed)
+    }
+
+    /// Returns `deadline`, clamped to between `min` and `max` after `now`. A bound past the
+    /// latest representable time doesn't apply.
+    pub(crate) fn clamp(&self, deadline: SystemTime, now: SystemTime) -> SystemTime {
+        let mut clamped = deadline;
+        if let Some(max) = now.checked_add(self.max) {
+            clamped = clamped.min(max);
+        }
+        if let Some(min) = now.checked_add(self.min) {
+            clamped = clamped.max(min);
+        }
+        if clamped != deadline {
+            self.clamped.fetch_add(1, Ordering::Relaxed);
+        }
+        clamped
+    }
+}
+
+/// The state of a [`Gradient`] limit.
+#[derive(Debug)]
+pub(crate) struct GradientLimit {
+    settings: Gradient,
+    limit: f64,
+    /// The long-term average latency, in seconds.
+    long_rtt: f64,
+    /// The number of responses averaged into `long_rtt`, up to the long window.
+    samples: usize,
+    /// Records the changes of the limit.
+    metrics: Option<Arc<dyn Recorder>>,
+}
+
+impl GradientLimit {
+    /// # Panics
+    ///
+    /// Panics if the initial or min limit or the long window of `settings` is 0, if its smoothing
+    /// isn't in (0, 1], or if its backoff ratio isn't in (0, 1).
+    pub fn new(settings: Gradient) -> Self {
+        assert!(
+            settings.initial_limit >= 1,
+            "initial limit must be at least 1"
+        );
+        assert!(settings.min_limit >= 1, "min limit must be at least 1");
+        assert!(settings.long_window >= 1, "long window must be at least 1");
+        assert!(
+            0. < settings.smoothing && settings.smoothing <= 1.,
+            "smoothing {} is not in (0, 1]",
+            settings.smoothing
+        );
+        assert!(
+            0. < settings.backoff_ratio && settings.backoff_ratio < 1.,
+            "backoff ratio {} is not in (0, 1)",
+            settings.backoff_ratio
+        );
+        Self {
+            limit: settings.initial_limit as f64,
+            settings,
+            long_rtt: 0.,
+            samples: 0,
+            metrics: None,
+        }
+    }
+
+    /// Records the changes of the limit with `metrics`, if set.
+    pub fn with_metrics(mut self, metrics: Option<Arc<dyn Recorder>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Returns the current limit.
+    pub fn limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    /// Updates the limit with the latency of a response. `in_flight` is the number of requests
+    /// that were in flight, including the completed one.
+    pub fn on_response(&mut self, rtt: Duration, in_flight: usize) {
+        let short_rtt = rtt.as_secs_f64();
+        if self.samples < self.settings.long_window {
+            self.samples += 1;
+        }
+        self.long_rtt += (short_rtt - self.long_rtt) / self.samples as f64;
+        // After a long period of high latency, the long-term average recovers faster than it
+        // would otherwise.
+        if self.long_rtt > short_rtt * 2. {
+            self.long_rtt *= 0.95;
+        }
+
+        // The limit isn't being tested, so latencies say nothing about whether it's too high.
+        if (in_flight as f64) < self.limit / 2. {
+            return;
+        }
+
+        let gradient = if short_rtt > 0. {
+            (self.settings.rtt_tolerance * self.long_rtt / short_rtt).clamp(0.5, 1.)
+        } else {
+            1.
+        };
+        let queue_size = self.limit.sqrt();
+        let estimate = self.limit * gradient + queue_size;
+        self.set_limit(
+            self.limit * (1. - self.settings.smoothing) + estimate * self.settings.smoothing,
+        );
+    }
+
+    /// Shrinks the limit after a request exceeded its deadline.
+    pub fn on_dropped(&mut self) {
+        self.set_limit(self.limit * self.settings.backoff_ratio);
+    }
+
+    fn set_limit(&mut self, limit: f64) {
+        let old_limit = self.limit();
+        self.limit = limit.clamp(
+            self.settings.min_limit as f64,
+            self.settings.max_limit.max(self.settings.min_limit) as f64,
+        );
+        if self.limit() != old_limit {
+            tracing::debug!("Concurrency limit: {} -> {}", old_limit, self.limit());
+            if let Some(metrics) = &self.metrics {
+                metrics.concurrency_limit(self.limit());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::EventRecorder;
+
+    fn new_limit() -> GradientLimit {
+        GradientLimit::new(Gradient {
+            initial_limit: 10,
+            max_limit: 100,
+            ..Gradient::default()
+        })
+    }
+
+    #[test]
+    fn deadline_clamp_counts_clamped_deadlines() {
+        let policy = DeadlineClamp::new(Duration::from_millis(100), Duration::from_secs(60));
+        let now = SystemTime::now();
+        let within = now + Duration::from_secs(1);
+        assert_eq!(policy.clamp(within, now), within);
+        assert_eq!(policy.clone().clamped(), 0);
+
+        assert_eq!(
+            policy.clamp(now + Duration::from_secs(36_000), now),
+            now + Duration::from_secs(60)
+        );
+        assert_eq!(
+            policy.clamp(now + Duration::from_millis(1), now),
+            now + Duration::from_millis(100)
+        );
+        assert_eq!(
+            policy.clamp(now - Duration::from_secs(1), now),
+            now + Duration::from_millis(100)
+        );
+        assert_eq!(policy.clamped(), 3);
+    }
+
+    #[test]
+    fn deadline_clamp_ignores_unrepresentable_max() {
+        let policy = DeadlineClamp::new(Duration::from_millis(100), Duration::MAX);
+        let now = SystemTime::now();
+        let far = now + Duration::from_secs(1 << 32);
+        assert_eq!(policy.clamp(far, now), far);
+        assert_eq!(policy.clamp(now, now), now + Duration::from_millis(100));
+        assert_eq!(policy.clamped(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "is greater than max deadline")]
+    fn deadline_clamp_rejects_inverted_range() {
+        DeadlineClamp::new(Duration::from_secs(2), Duration::from_secs(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "min limit must be at least 1")]
+    fn limit_rejects_zero_min_limit() {
+        GradientLimit::new(Gradient {
+            min_limit: 0,
+            ..Gradient::default()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "long window must be at least 1")]
+    fn limit_rejects_zero_long_window() {
+        GradientLimit::new(Gradient {
+            long_window: 0,
+            ..Gradient::default()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "smoothing 0 is not in (0, 1]")]
+    fn limit_rejects_zero_smoothing() {
+        GradientLimit::new(Gradient {
+            smoothing: 0.,
+            ..Gradient::default()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "smoothing 1.5 is not in (0, 1]")]
+    fn limit_rejects_smoothing_above_one() {
+        GradientLimit::new(Gradient {
+            smoothing: 1.5,
+            ..Gradient::default()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "backoff ratio 1 is not in (0, 1)")]
+    fn limit_rejects_backoff_ratio_of_one() {
+        GradientLimit::new(Gradient {
+            backoff_ratio: 1.,
+            ..Gradient::default()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "backoff ratio 0 is not in (0, 1)")]
+    fn limit_rejects_zero_backoff_ratio() {
+        GradientLimit::new(Gradient {
+            backoff_ratio: 0.,
+            ..Gradient::default()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "initial limit must be at least 1")]
+    fn limit_rejects_zero_initial_limit() {
+        GradientLimit::new(Gradient {
+            initial_limit: 0,
+            ..Gradient::default()
+        });
+    }
+
+    #[test]
+    fn limit_grows_while_latency_is_steady() {
+        let mut limit = new_limit();
+        for _ in 0..100 {
+            let in_flight = limit.limit();
+            limit.on_response(Duration::from_millis(10), in_flight);
+        }
+        assert_eq!(limit.limit(), 100);
+    }
+
+    #[test]
+    fn limit_shrinks_when_latency_rises() {
+        let mut limit = new_limit();
+        for _ in 0..100 {
+            limit.on_response(Duration::from_millis(10), 10);
+        }
+        let before = limit.limit();
+        for _ in 0..20 {
+            let in_flight = limit.limit();
+            limit.on_response(Duration::from_millis(100), in_flight);
+        }
+        assert!(
+            limit.limit() < before / 2,
+            "{} >= {}",
+            limit.limit(),
+            before / 2
+        );
+    }
+
+    #[test]
+    fn limit_ignores_latency_when_underused() {
+        let mut limit = new_limit();
+        for _ in 0..100 {
+            limit.on_response(Duration::from_millis(10), 1);
+        }
+        limit.on_response(Duration::from_secs(1), 1);
+        assert_eq!(limit.limit(), 10);
+    }
+
+    #[test]
+    fn limit_backs_off_on_drop() {
+        let mut limit = new_limit();
+        limit.on_dropped();
+        assert_eq!(limit.limit(), 9);
+        for _ in 0..100 {
+            limit.on_dropped();
+        }
+        assert_eq!(limit.limit(), 1);
+    }
+
+    #[test]
+    fn limit_records_changes() {
+        let recorder = Arc::new(EventRecorder::default());
+        let mut limit = new_limit().with_metrics(Some(recorder.clone()));
+        limit.on_dropped();
+        // A single request in flight doesn't test the limit, so its response doesn't change it.
+        limit.on_response(Duration::from_millis(10), 1);
+        limit.on_dropped();
+        assert_eq!(
+            recorder.events(),
+            ["client concurrency limit 9", "client concurrency limit 8"]
+        );
+    }
+}
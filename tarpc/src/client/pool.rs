@@ -0,0 +1,393 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::client::NewClient;
+use futures::{future::BoxFuture, prelude::*};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt, io,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, Weak,
+    },
+    time::Duration,
+};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+/// Settings that control the size of a [`Pool`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    /// The number of idle connections the pool keeps established. They are established when the
+    /// pool is created, and replaced in the background when they are checked out or close.
+    pub min_idle: usize,
+    /// The maximum number of connections, idle or checked out.
+    pub max_connections: usize,
+    /// How long to wait before retrying after a background connection attempt fails.
+    pub retry_interval: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            min_idle: 1,
+            max_connections: 16,
+            retry_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A pool of client connections.
+///
+/// Connections are established by a user-provided function returning a [`NewClient`], whose
+/// dispatch is spawned by the pool. A connection whose dispatch completes, e.g. because the
+/// server hung up, is discarded.
+pub struct Pool<C> {
+    shared: Arc<Shared<C>>,
+}
+
+impl<C> Clone for Pool<C> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<C> fmt::Debug for Pool<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("config", &self.shared.config)
+            .field("idle_connections", &self.idle_connections())
+            .finish()
+    }
+}
+
+struct Shared<C> {
+    config: Config,
+    connect: Box<dyn Fn() -> BoxFuture<'static, io::Result<Connection<C>>> + Send + Sync>,
+    idle: Mutex<VecDeque<Connection<C>>>,
+    /// One permit per connection that is checked out or being established.
+    checkouts: Arc<Semaphore>,
+    /// Wakes the background task that keeps `min_idle` connections established.
+    replenish: Arc<Notify>,
+}
+
+impl<C> Drop for Shared<C> {
+    fn drop(&mut self) {
+        // Lets the background task observe that the pool is gone.
+        self.replenish.notify_one();
+    }
+}
+
+struct Connection<C> {
+    client: C,
+    closed: Arc<AtomicBool>,
+}
+
+impl<C> Connection<C> {
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+impl<C> Pool<C> {
+    /// Returns the number of idle connections.
+    pub fn idle_connections(&self) -> usize {
+        self.shared.idle().len()
+    }
+}
+
+impl<C> Pool<C>
+where
+    C: Send + 'static,
+{
+    /// Returns a new pool that establishes connections with `connect`, after establishing
+    /// [`Config::min_idle`] connections. Fails if any of the initial connections fails.
+    pub async fn new<F, Fut, D, E>(config: Config, connect: F) -> io::Result<Self>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<NewClient<C, D>>> + Send + 'static,
+        D: Future<Output = Result<(), E>> + Send + 'static,
+        E: Error + Send + Sync + 'static,
+    {
+        let replenish = Arc::new(Notify::new());
+        let connect = {
+            let replenish = replenish.clone();
+            move || {
+                let new_client = connect();
+                let replenish = replenish.clone();
+                async move {
+                    let NewClient { client, dispatch } = new_client.await?;
+                    let closed = Arc::new(AtomicBool::new(false));
+                    let dispatch_closed = closed.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = dispatch.await {
+                            let e = anyhow::Error::new(e);
+                            tracing::warn!("Connection broken: {:?}", e);
+                        }
+                        dispatch_closed.store(true, Ordering::Release);
+                        replenish.notify_one();
+                    });
+                    Ok(Connection { client, closed })
+                }
+                .boxed()
+            }
+        };
+        let shared = Arc::new(Shared {
+            checkouts: Arc::new(Semaphore::new(config.max_connections)),
+            config,
+            connect: Box::new(connect),
+            idle: Mutex::new(VecDeque::new()),
+            replenish: replenish.clone(),
+        });
+
+        let initial = shared.config.min_idle.min(shared.config.max_connections);
+        let connections = future::try_join_all((0..initial).map(|_| (shared.connect)())).await?;
+        shared.idle().extend(connections);
+
+        tokio::spawn(maintain(Arc::downgrade(&shared), replenish));
+        Ok(Pool { shared })
+    }
+
+    /// Checks out a connection, establishing a new one if none is idle. If
+    /// [`Config::max_connections`] are already checked out, waits for one to be returned.
+    pub async fn get(&self) -> io::Result<Pooled<C>> {
+        let permit = self
+            .shared
+            .checkouts
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let idle = self.shared.pop_idle();
+        self.shared.replenish.notify_one();
+        let connection = match idle {
+            Some(connection) => connection,
+            None => (self.shared.connect)().await?,
+        };
+        Ok(Pooled {
+            connection: Some(connection),
+            shared: self.shared.clone(),
+            _permit: permit,
+        })
+    }
+}
+
+impl<C> Shared<C> {
+    /// Returns the idle connections, even if a panic poisoned their lock.
+    fn idle(&self) -> MutexGuard<'_, VecDeque<Connection<C>>> {
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn pop_idle(&self) -> Option<Connection<C>> {
+        let mut idle = self.idle();
+        while let Some(connection) = idle.pop_front() {
+            if !connection.is_closed() {
+                return Some(connection);
+            }
+        }
+        None
+    }
+
+    /// Establishes connections until `min_idle` are idle or `max_connections` exist. Returns how
+    /// long to wait before trying again, if a connection attempt failed.
+    async fn replenish(&self) -> Option<Duration> {
+        loop {
+            let permit = {
+                let mut idle = self.idle();
+                idle.retain(|connection| !connection.is_closed());
+                if idle.len() >= self.config.min_idle {
+                    return None;
+                }
+                let permit = match self.checkouts.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => return None,
+                };
+                let checked_out = self.config.max_connections - self.checkouts.available_permits();
+                if idle.len() + checked_out > self.config.max_connections {
+                    return None;
+                }
+                permit
+            };
+            match (self.connect)().await {
+                Ok(connection) => self.idle().push_back(connection),
+                Err(e) => {
+                    tracing::warn!("Could not establish idle connection: {:?}", e);
+                    return Some(self.config.retry_interval);
+                }
+            }
+            drop(permit);
+        }
+    }
+}
+
+async fn maintain<C>(shared: Weak<Shared<C>>, replenish: Arc<Notify>) {
+    loop {
+        // The pool must not be kept alive while waiting.
+        let retry = match shared.upgrade() {
+            Some(shared) => shared.replenish().await,
+            None => return,
+        };
+        match retry {
+            Some(retry) => tokio::time::sleep(retry).await,
+            None => replenish.notified().await,
+        }
+    }
+}
+
+/// A connection checked out of a [`Pool`]. The connection is returned to the pool when dropped,
+/// unless it has closed.
+pub struct Pooled<C> {
+    connection: Option<Connection<C>>,
+    shared: Arc<Shared<C>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<C> Pooled<C> {
+    /// Returns true if the connection's dispatch has completed, so requests will fail.
+    pub fn is_closed(&self) -> bool {
+        self.connection.as_ref().unwrap().is_closed()
+    }
+}
+
+impl<C> Deref for Pooled<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.connection.as_ref().unwrap().client
+    }
+}
+
+impl<C: fmt::Debug> fmt::Debug for Pooled<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Pooled").field(&**self).finish()
+    }
+}
+
+impl<C> Drop for Pooled<C> {
+    fn drop(&mut self) {
+        let connection = self.connection.take().unwrap();
+        if connection.is_closed() {
+            self.shared.replenish.notify_one();
+        } else {
+            self.shared.idle().push_back(connection);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::{self, Channel},
+        context,
+        server::{self, BaseChannel, Channel as _},
+        transport::channel::{self, UnboundedChannel},
+        ClientMessage, ServerMessage,
+    };
+    use std::sync::atomic::AtomicUsize;
+
+    type Transport = UnboundedChannel<ServerMessage<u32>, ClientMessage<u32>>;
+
+    async fn new_pool(
+        config: Config,
+    ) -> (
+        Pool<Channel<u32, u32>>,
+        Arc<AtomicUsize>,
+        Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
+    ) {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let servers = Arc::new(Mutex::new(vec![]));
+        let pool = Pool::new(config, {
+            let connects = connects.clone();
+            let servers = servers.clone();
+            move || {
+                connects.fetch_add(1, Ordering::SeqCst);
+                let (client_transport, server_transport): (Transport, _) = channel::unbounded();
+                let server = tokio::spawn(
+                    BaseChannel::new(server::Config::default(), server_transport)
+                        .execute(|_: context::Context, i: u32| future::ready(i + 1)),
+                );
+                servers.lock().unwrap().push(server);
+                future::ok(client::new(client::Config::default(), client_transport))
+            }
+        })
+        .await
+        .unwrap();
+        (pool, connects, servers)
+    }
+
+    async fn until(condition: impl Fn() -> bool) {
+        while !condition() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn pool_prewarms_and_replenishes_idle_connections() {
+        let (pool, connects, _servers) = new_pool(Config {
+            min_idle: 2,
+            max_connections: 3,
+            ..Config::default()
+        })
+        .await;
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.idle_connections(), 2);
+
+        let client = pool.get().await.unwrap();
+        assert_eq!(client.call(context::current(), "", 1).await.unwrap(), 2);
+        until(|| pool.idle_connections() == 2).await;
+        assert_eq!(connects.load(Ordering::SeqCst), 3);
+
+        // Returning the connection doesn't exceed max_connections.
+        drop(client);
+        assert_eq!(pool.idle_connections(), 3);
+        let _clients = [
+            pool.get().await.unwrap(),
+            pool.get().await.unwrap(),
+            pool.get().await.unwrap(),
+        ];
+        tokio::task::yield_now().await;
+        assert_eq!(connects.load(Ordering::SeqCst), 3);
+        assert_eq!(pool.idle_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn pool_replaces_closed_connections() {
+        let (pool, connects, servers) = new_pool(Config::default()).await;
+
+        for server in servers.lock().unwrap().drain(..) {
+            server.abort();
+        }
+        until(|| connects.load(Ordering::SeqCst) == 2 && pool.idle_connections() == 1).await;
+
+        let client = pool.get().await.unwrap();
+        assert!(!client.is_closed());
+        assert_eq!(client.call(context::current(), "", 1).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn pool_survives_a_panic_holding_the_idle_connections() {
+        let (pool, _connects, _servers) = new_pool(Config::default()).await;
+
+        let shared = pool.shared.clone();
+        let _ = std::thread::spawn(move || {
+            let _idle = shared.idle();
+            panic!("while holding the idle connections");
+        })
+        .join();
+        assert!(pool.shared.idle.is_poisoned());
+
+        let client = pool.get().await.unwrap();
+        assert_eq!(client.call(context::current(), "", 1).await.unwrap(), 2);
+        // The connection checked out is replaced, then returned.
+        drop(client);
+        until(|| pool.idle_connections() == 2).await;
+    }
+}
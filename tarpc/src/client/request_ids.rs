@@ -0,0 +1,181 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::server::ONE_WAY_REQUEST_ID;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, SystemTime},
+};
+
+/// Generates the IDs of the requests of a client, set in
+/// [`Config::request_ids`](crate::client::Config::request_ids).
+///
+/// IDs only need to be unique among the requests a channel has in flight, but a generator that
+/// keeps them unique for longer, e.g. across reconnects of the client, lets a server tell retries
+/// of a request on a new connection apart from new requests, and lets logs of several
+/// connections be correlated. [`u64::MAX`] is reserved, and must not be generated.
+pub trait RequestIdGenerator: fmt::Debug + Send + Sync + 'static {
+    /// Returns the ID of the next request.
+    fn next_request_id(&self) -> u64;
+}
+
+/// Generates sequential IDs, starting at 0, and wrapping around past the reserved [`u64::MAX`].
+/// The default.
+#[derive(Debug, Default)]
+pub struct Sequential {
+    next: AtomicU64,
+}
+
+impl Sequential {
+    /// Returns a generator whose first ID is 0.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl RequestIdGenerator for Sequential {
+    fn next_request_id(&self) -> u64 {
+        loop {
+            let id = self.next.fetch_add(1, Ordering::Relaxed);
+            if id != ONE_WAY_REQUEST_ID {
+                return id;
+            }
+        }
+    }
+}
+
+/// Generates random IDs, which are unique across clients and reconnects with high probability.
+#[derive(Debug, Default)]
+pub struct Random;
+
+impl RequestIdGenerator for Random {
+    fn next_request_id(&self) -> u64 {
+        loop {
+            let id = rand::random();
+            if id != ONE_WAY_REQUEST_ID {
+                return id;
+            }
+        }
+    }
+}
+
+/// Generates snowflake IDs, which embed the shard of the client and are unique across the
+/// clients of different shards, and across reconnects.
+///
+/// From the most significant bit, an ID is made of 41 bits of milliseconds since
+/// 2020-01-01T00:00:00Z, 10 bits of shard, and a 12 bit sequence number that distinguishes the
+/// IDs generated in the same millisecond. A generator that runs out of sequence numbers in a
+/// millisecond borrows from the next one, so IDs stay unique without blocking.
+#[derive(Debug)]
+pub struct Snowflake {
+    shard: u64,
+    /// The millisecond and sequence number of the last ID generated.
+    last: Mutex<(u64, u64)>,
+}
+
+impl Snowflake {
+    /// The largest shard an ID can embed.
+    pub const MAX_SHARD: u16 = (1 << SHARD_BITS) - 1;
+
+    /// Returns a generator of IDs embedding `shard`.
+    ///
+    /// # Panics
+    ///
+    /// If `shard` is larger than [`Snowflake::MAX_SHARD`].
+    pub fn new(shard: u16) -> Self {
+        assert!(
+            shard <= Self::MAX_SHARD,
+            "shard {} is larger than {}",
+            shard,
+            Self::MAX_SHARD
+        );
+        Self {
+            shard: u64::from(shard),
+            last: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Returns the shard embedded in `id`, if it was generated by a snowflake generator.
+    pub fn shard_of(id: u64) -> u16 {
+        ((id >> SEQUENCE_BITS) & u64::from(Self::MAX_SHARD)) as u16
+    }
+
+    fn millis_since_epoch() -> u64 {
+        crate::util::time::now()
+            .duration_since(SystemTime::UNIX_EPOCH + Duration::from_secs(EPOCH_SECS))
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+}
+
+/// The seconds from the Unix epoch to 2020-01-01T00:00:00Z.
+const EPOCH_SECS: u64 = 1_577_836_800;
+const SHARD_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+
+impl RequestIdGenerator for Snowflake {
+    fn next_request_id(&self) -> u64 {
+        let now = Self::millis_since_epoch();
+        let mut last = self.last.lock().unwrap_or_else(PoisonError::into_inner);
+        let (last_millis, last_sequence) = *last;
+        *last = if now > last_millis {
+            (now, 0)
+        } else if last_sequence + 1 < 1 << SEQUENCE_BITS {
+            (last_millis, last_sequence + 1)
+        } else {
+            (last_millis + 1, 0)
+        };
+        let (millis, sequence) = *last;
+        let millis = millis & ((1 << (64 - SHARD_BITS - SEQUENCE_BITS - 1)) - 1);
+        millis << (SHARD_BITS + SEQUENCE_BITS) | self.shard << SEQUENCE_BITS | sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn sequential_counts_from_zero() {
+        let ids = Sequential::new();
+        assert_eq!(
+            (0..3).map(|_| ids.next_request_id()).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn sequential_skips_one_way_request_id() {
+        let ids = Sequential {
+            next: AtomicU64::new(ONE_WAY_REQUEST_ID - 1),
+        };
+        assert_eq!(
+            (0..3).map(|_| ids.next_request_id()).collect::<Vec<_>>(),
+            [ONE_WAY_REQUEST_ID - 1, 0, 1]
+        );
+    }
+
+    #[test]
+    fn snowflake_ids_are_unique_and_embed_shard() {
+        let ids = Snowflake::new(Snowflake::MAX_SHARD);
+        // More than a millisecond's worth of sequence numbers.
+        let generated: HashSet<_> = (0..10_000).map(|_| ids.next_request_id()).collect();
+        assert_eq!(generated.len(), 10_000);
+        assert!(generated.iter().all(|&id| id != ONE_WAY_REQUEST_ID));
+        assert!(generated
+            .iter()
+            .all(|&id| Snowflake::shard_of(id) == Snowflake::MAX_SHARD));
+
+        let other_shard = Snowflake::new(3).next_request_id();
+        assert_eq!(Snowflake::shard_of(other_shard), 3);
+        assert!(!generated.contains(&other_shard));
+    }
+}
@@ -9,9 +9,12 @@
 
 use crate::trace::{self, TraceId};
 use opentelemetry::trace::TraceContextExt;
+use rand::Rng;
 use static_assertions::assert_impl_all;
 use std::{
     convert::TryFrom,
+    fmt,
+    net::SocketAddr,
     time::{Duration, SystemTime},
 };
 use tracing_opentelemetry::OpenTelemetrySpanExt;
@@ -24,18 +27,108 @@ use tracing_opentelemetry::OpenTelemetrySpanExt;
 #[derive(Clone, Copy, Debug)]
 #[non_exhaustive]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 pub struct Context {
     /// When the client expects the request to be complete by. The server should cancel the request
     /// if it is not complete by this time.
     #[cfg_attr(feature = "serde1", serde(default = "ten_seconds_from_now"))]
     // Serialized as a Duration to prevent clock skew issues.
     #[cfg_attr(feature = "serde1", serde(with = "absolute_to_relative_time"))]
+    #[cfg_attr(feature = "rkyv", with(crate::util::rkyv::RelativeTime))]
     pub deadline: SystemTime,
     /// Uniquely identifies requests originating from the same source.
     /// When a service handles a request by making requests itself, those requests should
     /// include the same `trace_id` as that included on the original request. This way,
     /// users can trace related actions across a distributed system.
     pub trace_context: trace::Context,
+    /// If set, the client consents to the server deferring its response past the deadline, as
+    /// long as the server keeps signaling that the request is still being worked on. While the
+    /// request is in progress, the server sends keep-alive frames every `keep_alive / 2`; each
+    /// frame extends the time the client and server wait for the request to `keep_alive` past
+    /// when the frame is sent or received. The deadline seen by the request handler is unchanged.
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub keep_alive: Option<Duration>,
+    /// The sizes the client advises the request and its response to have, so that the server can
+    /// decide whether to admit the request, and how much to allocate for it, before handling it.
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub size_hint: SizeHint,
+    /// Which class of transport a client with several transports should send the request over.
+    /// See [`Channel::with_transport_fallback`](crate::client::Channel::with_transport_fallback).
+    /// The hint is only used by the client and is not sent to the server.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    #[cfg_attr(feature = "rkyv", with(rkyv::with::Skip))]
+    pub transport: Option<TransportHint>,
+    /// The address of the client, on the server, if the [channel](crate::server::BaseChannel)
+    /// the request was received on was [given it](crate::server::BaseChannel::with_peer_addr).
+    /// The address is set by the server and is not sent by the client.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    #[cfg_attr(feature = "rkyv", with(rkyv::with::Skip))]
+    pub peer_addr: Option<SocketAddr>,
+}
+
+/// The serialized sizes, in bytes, of a request and of its response, as advised by the client.
+/// Servers can't rely on the sizes being accurate, only use them to plan ahead.
+///
+/// The request size is set by clients with a [request sizer](crate::client::Channel::with_request_sizer),
+/// and can be enforced by servers with
+/// [`max_request_size`](crate::server::Channel::max_request_size).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
+pub struct SizeHint {
+    /// The size of the request, if known.
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub request: Option<u64>,
+    /// The expected size of the response, if known.
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub response: Option<u64>,
+}
+
+/// A class of transport, such as a local socket or a connection to a remote leader, that a
+/// client [channel](crate::client::Channel) sends requests over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TransportClass(&'static str);
+
+impl TransportClass {
+    /// A transport to a server on the same host, such as a unix domain socket.
+    pub const LOCAL: Self = Self("local");
+    /// A transport to a server on another host.
+    pub const REMOTE: Self = Self("remote");
+
+    /// Returns a transport class with the given name. Classes with the same name are equal.
+    pub const fn new(name: &'static str) -> Self {
+        Self(name)
+    }
+
+    /// Returns the name of the transport class.
+    pub fn name(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl fmt::Display for TransportClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+/// Selects the class of transport a request is sent over, when the client has several.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TransportHint {
+    /// Send the request over a transport of the class if one is connected, and otherwise over
+    /// the first connected transport.
+    Prefer(TransportClass),
+    /// Send the request only over a transport of the class. The request fails if the client has
+    /// no connected transport of the class.
+    Require(TransportClass),
 }
 
 #[cfg(feature = "serde1")]
@@ -48,7 +141,7 @@ mod absolute_to_relative_time {
         S: Serializer,
     {
         let deadline = deadline
-            .duration_since(SystemTime::now())
+            .duration_since(crate::util::time::now())
             .unwrap_or(Duration::ZERO);
         deadline.serialize(serializer)
     }
@@ -58,7 +151,7 @@ mod absolute_to_relative_time {
         D: Deserializer<'de>,
     {
         let deadline = Duration::deserialize(deserializer)?;
-        Ok(SystemTime::now() + deadline)
+        Ok(crate::util::time::now() + deadline)
     }
 
     #[cfg(test)]
@@ -89,7 +182,7 @@ mod absolute_to_relative_time {
 assert_impl_all!(Context: Send, Sync);
 
 fn ten_seconds_from_now() -> SystemTime {
-    SystemTime::now() + Duration::from_secs(10)
+    crate::util::time::now() + Duration::from_secs(10)
 }
 
 /// Returns the context for the current request, or a default Context if no request is active.
@@ -119,6 +212,10 @@ impl Context {
                 .cloned()
                 .unwrap_or_default()
                 .0,
+            keep_alive: None,
+            size_hint: SizeHint::default(),
+            transport: None,
+            peer_addr: None,
         }
     }
 
@@ -128,8 +225,637 @@ impl Context {
     }
 }
 
+/// Mints root contexts for calls that don't originate from a request, such as those of cron jobs
+/// and queue consumers, so that they get the same deadlines and tracing as calls made while
+/// handling a request.
+///
+/// Each [`context`](Background::context) starts a new trace, sampled at the configured rate, with
+/// a deadline of the configured timeout from now. The job can also run in a
+/// [`span`](Background::span) that is the root of the trace and names the synthetic principal on
+/// whose behalf the job runs, so that [`current`] returns the job's context within it.
+#[derive(Clone, Debug)]
+pub struct Background {
+    principal: String,
+    timeout: Duration,
+    sample_rate: f64,
+}
+
+impl Background {
+    /// Returns a minter of contexts for jobs run on behalf of `principal`, e.g. `cron:cleanup`,
+    /// with a timeout of ten seconds and every trace sampled.
+    pub fn new(principal: impl Into<String>) -> Self {
+        Self {
+            principal: principal.into(),
+            timeout: Duration::from_secs(10),
+            sample_rate: 1.0,
+        }
+    }
+
+    /// Sets how long after being minted contexts expire.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the fraction of traces that are sampled.
+    ///
+    /// # Panics
+    ///
+    /// If `sample_rate` is not between 0 and 1.
+    pub fn with_sample_rate(mut self, sample_rate: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&sample_rate),
+            "sample_rate must be between 0 and 1"
+        );
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Returns the principal on whose behalf jobs run.
+    pub fn principal(&self) -> &str {
+        &self.principal
+    }
+
+    /// Returns a context that is the root of a new trace.
+    pub fn context(&self) -> Context {
+        let rng = &mut rand::thread_rng();
+        Context {
+            deadline: crate::util::time::now() + self.timeout,
+            trace_context: trace::Context {
+                trace_id: TraceId::random(rng),
+                span_id: trace::SpanId::random(rng),
+                sampling_decision: if rng.gen_bool(self.sample_rate) {
+                    trace::SamplingDecision::Sampled
+                } else {
+                    trace::SamplingDecision::Unsampled
+                },
+            },
+            keep_alive: None,
+            size_hint: SizeHint::default(),
+            transport: None,
+            peer_addr: None,
+        }
+    }
+
+    /// Returns a span in which to run a job, whose context is a new [`context`](Self::context).
+    pub fn span(&self) -> tracing::Span {
+        let context = self.context();
+        let span = tracing::info_span!(
+            "Background",
+            rpc.principal = %self.principal,
+            rpc.trace_id = %context.trace_id(),
+            rpc.deadline = %humantime::format_rfc3339(context.deadline),
+            otel.kind = "internal",
+        );
+        span.set_context(&context);
+        span
+    }
+}
+
+/// Converts contexts to and from HTTP headers, so that a request keeps one deadline and trace
+/// when it crosses between tarpc and HTTP services.
+///
+/// The trace context is carried in the W3C `traceparent` header, and the deadline in the
+/// [`TIMEOUT_HEADER`](http::TIMEOUT_HEADER) header as the number of milliseconds remaining.
+/// OpenTelemetry baggage is not part of the tarpc context, so the `baggage` header is left to
+/// OpenTelemetry's own propagators.
+#[cfg(feature = "http")]
+#[cfg_attr(docsrs, doc(cfg(feature = "http")))]
+pub mod http {
+    use super::Context;
+    use crate::trace;
+    use http::{HeaderMap, HeaderValue};
+    use std::time::Duration;
+
+    /// The W3C trace context header.
+    pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+    /// The header carrying the number of milliseconds remaining until the deadline.
+    pub const TIMEOUT_HEADER: &str = "tarpc-timeout";
+
+    /// Returns the context described by `headers`. Whatever is missing or malformed is taken from
+    /// [`Context::current`].
+    pub fn extract(headers: &HeaderMap) -> Context {
+        let mut context = Context::current();
+        if let Some(trace_context) = headers
+            .get(TRACEPARENT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| trace::Context::from_traceparent(value).ok())
+        {
+            context.trace_context = trace_context;
+        }
+        if let Some(timeout) = headers
+            .get(TIMEOUT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+        {
+            context.deadline = crate::util::time::now() + Duration::from_millis(timeout);
+        }
+        context
+    }
+
+    /// Sets the headers describing `context`, replacing any existing values.
+    pub fn inject(context: &Context, headers: &mut HeaderMap) {
+        if let Some(traceparent) = context.trace_context.to_traceparent() {
+            headers.insert(
+                TRACEPARENT_HEADER,
+                HeaderValue::from_str(&traceparent).expect("traceparent is ascii"),
+            );
+        }
+        let timeout = context
+            .deadline
+            .duration_since(crate::util::time::now())
+            .unwrap_or(Duration::ZERO);
+        headers.insert(
+            TIMEOUT_HEADER,
+            HeaderValue::from(timeout.as_millis() as u64),
+        );
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::trace::SamplingDecision;
+        use std::time::SystemTime;
+
+        #[test]
+        fn extract_parses_headers() {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                TRACEPARENT_HEADER,
+                HeaderValue::from_static("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+            );
+            headers.insert(TIMEOUT_HEADER, HeaderValue::from_static("5000"));
+
+            let context = extract(&headers);
+            assert_eq!(
+                context.trace_context,
+                trace::Context {
+                    trace_id: 0x0af7651916cd43dd8448eb211c80319c.into(),
+                    span_id: 0xb7ad6b7169203331.into(),
+                    sampling_decision: SamplingDecision::Sampled,
+                }
+            );
+            let remaining = context.deadline.duration_since(SystemTime::now()).unwrap();
+            assert!(remaining <= Duration::from_secs(5));
+            assert!(remaining > Duration::from_secs(4));
+        }
+
+        #[test]
+        fn extract_ignores_malformed_traceparent() {
+            for traceparent in [
+                "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+                "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b71692033-01",
+                "not a traceparent",
+            ] {
+                let mut headers = HeaderMap::new();
+                headers.insert(TRACEPARENT_HEADER, HeaderValue::from_static(traceparent));
+                assert!(extract(&headers).trace_context.trace_id.is_none());
+            }
+        }
+
+        #[test]
+        fn inject_then_extract_round_trips() {
+            let mut context = Context::current();
+            context.trace_context = trace::Context {
+                trace_id: 7.into(),
+                span_id: 8.into(),
+                sampling_decision: SamplingDecision::Unsampled,
+            };
+            context.deadline = SystemTime::now() + Duration::from_secs(3);
+
+            let mut headers = HeaderMap::new();
+            inject(&context, &mut headers);
+            assert_eq!(
+                headers[TRACEPARENT_HEADER],
+                "00-00000000000000000000000000000007-0000000000000008-00"
+            );
+
+            let extracted = extract(&headers);
+            assert_eq!(extracted.trace_context, context.trace_context);
+            assert!(extracted.deadline <= context.deadline);
+            assert!(extracted.deadline > context.deadline - Duration::from_secs(1));
+        }
+    }
+}
+
+/// A canonical, versioned binary encoding of contexts, for implementations of the tarpc protocol
+/// in other languages and for future versions of tarpc.
+///
+/// The serde encoding of [`Context`] follows from its fields and from the serialization format,
+/// so it changes whenever either does. This encoding is specified byte for byte instead. Version
+/// 1, all integers little-endian:
+///
+/// | Offset | Size | Field                                                                  |
+/// |-------:|-----:|------------------------------------------------------------------------|
+/// | 0      | 1    | The version, `1`.                                                      |
+/// | 1      | 8    | Nanoseconds until the deadline, saturating; `0` if it has passed.      |
+/// | 9      | 16   | The trace ID.                                                          |
+/// | 25     | 8    | The span ID.                                                           |
+/// | 33     | 1    | Flags: bit 0 is set iff the trace is sampled; bit 1 iff a keep-alive follows; bit 2 iff a request size hint follows; bit 3 iff a response size hint follows. Other bits are reserved, written as 0 and ignored when read. |
+/// | 34     | 8    | Nanoseconds of keep-alive, present iff flag bit 1 is set.              |
+/// | next   | 8    | Bytes of the request, present iff flag bit 2 is set.                   |
+/// | next   | 8    | Bytes of the response, present iff flag bit 3 is set.                  |
+///
+/// Decoders ignore bytes after the fields they know, so that fields can be appended within a
+/// version; changes that older decoders can't skip get a new version, which older decoders
+/// reject with [`WireError::UnsupportedVersion`](wire::WireError::UnsupportedVersion). The deadline is relative, like in the serde
+/// encoding, so that the clocks of client and server needn't agree.
+///
+/// Contexts can be sent in the canonical encoding by serde formats, by annotating a context
+/// field with `#[serde(with = "tarpc::context::wire::canonical")]`, or, to also accept contexts
+/// sent in the serde encoding, with `#[serde(with = "tarpc::context::wire::compat")]`.
+pub mod wire {
+    use super::{Context, SizeHint};
+    use crate::trace::{self, SamplingDecision};
+    use std::{
+        convert::TryInto,
+        time::{Duration, SystemTime},
+    };
+
+    /// The version of the encoding written by [`encode`].
+    pub const VERSION: u8 = 1;
+
+    /// The length of an encoded context without a keep-alive.
+    const MIN_LEN: usize = 34;
+    const SAMPLED: u8 = 1;
+    const KEEP_ALIVE: u8 = 1 << 1;
+    const REQUEST_SIZE: u8 = 1 << 2;
+    const RESPONSE_SIZE: u8 = 1 << 3;
+
+    /// An error decoding a context.
+    #[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+    #[non_exhaustive]
+    pub enum WireError {
+        /// The context was encoded with a version this decoder doesn't know.
+        #[error("context encoding version {0} is unsupported; the latest supported is {VERSION}")]
+        UnsupportedVersion(u8),
+        /// The encoded context ended before all of its fields.
+        #[error("encoded context is {len} bytes, but its fields need {needed}")]
+        Truncated {
+            /// The length of the encoded context.
+            len: usize,
+            /// The length its fields need.
+            needed: usize,
+        },
+    }
+
+    /// Encodes `context`.
+    pub fn encode(context: &Context) -> Vec<u8> {
+        encode_at(context, crate::util::time::now())
+    }
+
+    /// Decodes a context encoded by any supported version.
+    pub fn decode(bytes: &[u8]) -> Result<Context, WireError> {
+        decode_at(bytes, crate::util::time::now())
+    }
+
+    fn nanos(duration: Duration) -> u64 {
+        duration.as_nanos().try_into().unwrap_or(u64::MAX)
+    }
+
+    fn encode_at(context: &Context, now: SystemTime) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MIN_LEN + 8);
+        bytes.push(VERSION);
+        let until_deadline = context
+            .deadline
+            .duration_since(now)
+            .unwrap_or(Duration::ZERO);
+        bytes.extend_from_slice(&nanos(until_deadline).to_le_bytes());
+        bytes.extend_from_slice(&u128::from(context.trace_context.trace_id).to_le_bytes());
+        bytes.extend_from_slice(&u64::from(context.trace_context.span_id).to_le_bytes());
+        let mut flags = 0;
+        if context.trace_context.sampling_decision == SamplingDecision::Sampled {
+            flags |= SAMPLED;
+        }
+        if context.keep_alive.is_some() {
+            flags |= KEEP_ALIVE;
+        }
+        if context.size_hint.request.is_some() {
+            flags |= REQUEST_SIZE;
+        }
+        if context.size_hint.response.is_some() {
+            flags |= RESPONSE_SIZE;
+        }
+        bytes.push(flags);
+        if let Some(keep_alive) = context.keep_alive {
+            bytes.extend_from_slice(&nanos(keep_alive).to_le_bytes());
+        }
+        for size in [context.size_hint.request, context.size_hint.response]
+            .into_iter()
+            .flatten()
+        {
+            bytes.extend_from_slice(&size.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn decode_at(bytes: &[u8], now: SystemTime) -> Result<Context, WireError> {
+        let version = *bytes
+            .first()
+            .ok_or(WireError::Truncated { len: 0, needed: 1 })?;
+        if version != VERSION {
+            return Err(WireError::UnsupportedVersion(version));
+        }
+        let truncated = |needed| WireError::Truncated {
+            len: bytes.len(),
+            needed,
+        };
+        if bytes.len() < MIN_LEN {
+            return Err(truncated(MIN_LEN));
+        }
+        let u64_at = |offset: usize| {
+            u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("8 bytes"))
+        };
+        let flags = bytes[33];
+        // The optional fields follow each other, in the order of their flags.
+        let mut offset = MIN_LEN;
+        let mut optional = |flag: u8| {
+            if flags & flag == 0 {
+                return Ok(None);
+            }
+            if bytes.len() < offset + 8 {
+                return Err(truncated(offset + 8));
+            }
+            offset += 8;
+            Ok(Some(u64_at(offset - 8)))
+        };
+        let keep_alive = optional(KEEP_ALIVE)?.map(Duration::from_nanos);
+        let size_hint = SizeHint {
+            request: optional(REQUEST_SIZE)?,
+            response: optional(RESPONSE_SIZE)?,
+        };
+        Ok(Context {
+            deadline: now + Duration::from_nanos(u64_at(1)),
+            trace_context: trace::Context {
+                trace_id: u128::from_le_bytes(bytes[9..25].try_into().expect("16 bytes")).into(),
+                span_id: u64_at(25).into(),
+                sampling_decision: if flags & SAMPLED != 0 {
+                    SamplingDecision::Sampled
+                } else {
+                    SamplingDecision::Unsampled
+                },
+            },
+            keep_alive,
+            size_hint,
+            transport: None,
+            peer_addr: None,
+        })
+    }
+
+    /// Serializes a context as the bytes of its canonical encoding. For use with
+    /// `#[serde(with = "tarpc::context::wire::canonical")]`.
+    #[cfg(feature = "serde1")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "serde1")))]
+    pub mod canonical {
+        use super::super::Context;
+        use serde::{de, Deserializer, Serializer};
+        use std::fmt;
+
+        /// Serializes `context` as the bytes of its canonical encoding.
+        pub fn serialize<S>(context: &Context, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.serialize_bytes(&super::encode(context))
+        }
+
+        /// Deserializes a context from the bytes of its canonical encoding.
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Context, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_bytes(Visitor { compat: false })
+        }
+
+        /// Visits the bytes of a canonically-encoded context or, if `compat`, the serde encoding
+        /// of a context.
+        pub(super) struct Visitor {
+            pub compat: bool,
+        }
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Context;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                if self.compat {
+                    f.write_str("an encoded context, or a context struct")
+                } else {
+                    f.write_str("an encoded context")
+                }
+            }
+
+            fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Context, E> {
+                super::decode(bytes).map_err(E::custom)
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Context, A::Error>
+            where
+                A: de::SeqAccess<'de>,
+            {
+                // Formats without a bytes type, like JSON, serialize bytes as a sequence.
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                self.visit_bytes(&bytes)
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<Context, A::Error>
+            where
+                A: de::MapAccess<'de>,
+            {
+                if !self.compat {
+                    return Err(de::Error::invalid_type(de::Unexpected::Map, &self));
+                }
+                serde::Deserialize::deserialize(de::value::MapAccessDeserializer::new(map))
+            }
+        }
+    }
+
+    /// Serializes a context in its canonical encoding, and deserializes contexts in either the
+    /// canonical or the serde encoding. For use with
+    /// `#[serde(with = "tarpc::context::wire::compat")]`, while peers migrate to the canonical
+    /// encoding.
+    ///
+    /// Telling the encodings apart requires a self-describing format, like JSON or MessagePack.
+    #[cfg(feature = "serde1")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "serde1")))]
+    pub mod compat {
+        use super::super::Context;
+        use serde::Deserializer;
+
+        pub use super::canonical::serialize;
+
+        /// Deserializes a context from either its canonical or its serde encoding.
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Context, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_any(super::canonical::Visitor { compat: true })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use assert_matches::assert_matches;
+
+        fn context(now: SystemTime) -> Context {
+            Context {
+                deadline: now + Duration::from_millis(1500),
+                trace_context: trace::Context {
+                    trace_id: 0x0af7651916cd43dd8448eb211c80319c.into(),
+                    span_id: 0xb7ad6b7169203331.into(),
+                    sampling_decision: SamplingDecision::Sampled,
+                },
+                keep_alive: None,
+                size_hint: SizeHint::default(),
+                transport: None,
+                peer_addr: None,
+            }
+        }
+
+        /// The encoding of `context(now)` in version 1.
+        const V1: &[u8] = &[
+            1, // version
+            0x00, 0x2f, 0x68, 0x59, 0, 0, 0, 0, // 1.5s until the deadline
+            0x9c, 0x31, 0x80, 0x1c, 0x21, 0xeb, 0x48, 0x84, 0xdd, 0x43, 0xcd, 0x16, 0x19, 0x65,
+            0xf7, 0x0a, // trace ID
+            0x31, 0x33, 0x20, 0x69, 0x71, 0x6b, 0xad, 0xb7, // span ID
+            0b01, // flags: sampled
+        ];
+
+        #[test]
+        fn encoding_matches_spec() {
+            let now = SystemTime::now();
+            assert_eq!(encode_at(&context(now), now), V1);
+
+            let mut context = context(now);
+            context.keep_alive = Some(Duration::from_secs(2));
+            context.trace_context.sampling_decision = SamplingDecision::Unsampled;
+            let encoded = encode_at(&context, now);
+            assert_eq!(encoded[33], 0b10);
+            assert_eq!(encoded[34..], 2_000_000_000u64.to_le_bytes());
+
+            context.size_hint.response = Some(4096);
+            let encoded = encode_at(&context, now);
+            assert_eq!(encoded[33], 0b1010);
+            assert_eq!(encoded[34..42], 2_000_000_000u64.to_le_bytes());
+            assert_eq!(encoded[42..], 4096u64.to_le_bytes());
+        }
+
+        #[test]
+        fn decoding_matches_spec() {
+            let now = SystemTime::now();
+            let decoded = decode_at(V1, now).unwrap();
+            assert_eq!(decoded.deadline, context(now).deadline);
+            assert_eq!(decoded.trace_context, context(now).trace_context);
+            assert_eq!(decoded.keep_alive, None);
+
+            // Reserved flags and trailing bytes are ignored.
+            let mut extended = V1.to_vec();
+            extended[33] |= 0b1000_0000;
+            extended.extend_from_slice(b"future fields");
+            let decoded = decode_at(&extended, now).unwrap();
+            assert_eq!(decoded.trace_context, context(now).trace_context);
+        }
+
+        #[test]
+        fn round_trip() {
+            let now = SystemTime::now();
+            for keep_alive in [None, Some(Duration::from_millis(250))] {
+                for request in [None, Some(1 << 20)] {
+                    let mut context = context(now);
+                    context.keep_alive = keep_alive;
+                    context.size_hint = SizeHint {
+                        request,
+                        response: Some(64),
+                    };
+                    let decoded = decode_at(&encode_at(&context, now), now).unwrap();
+                    assert_eq!(decoded.deadline, context.deadline);
+                    assert_eq!(decoded.trace_context, context.trace_context);
+                    assert_eq!(decoded.keep_alive, context.keep_alive);
+                    assert_eq!(decoded.size_hint, context.size_hint);
+                }
+            }
+
+            // A deadline in the past is sent as expiring now.
+            let mut context = context(now);
+            context.deadline = now - Duration::from_secs(1);
+            assert_eq!(
+                decode_at(&encode_at(&context, now), now).unwrap().deadline,
+                now
+            );
+        }
+
+        #[test]
+        fn rejects_unknown_versions_and_truncation() {
+            assert_matches!(decode(&[]), Err(WireError::Truncated { len: 0, needed: 1 }));
+            assert_matches!(decode(&[2]), Err(WireError::UnsupportedVersion(2)));
+            assert_matches!(
+                decode(&V1[..20]),
+                Err(WireError::Truncated {
+                    len: 20,
+                    needed: 34
+                })
+            );
+            let mut keep_alive = V1.to_vec();
+            keep_alive[33] |= 0b10;
+            assert_matches!(
+                decode(&keep_alive),
+                Err(WireError::Truncated {
+                    len: 34,
+                    needed: 42
+                })
+            );
+            keep_alive[33] = 0b1100;
+            keep_alive.extend_from_slice(&[0; 12]);
+            assert_matches!(
+                decode(&keep_alive),
+                Err(WireError::Truncated {
+                    len: 46,
+                    needed: 50
+                })
+            );
+        }
+
+        #[cfg(feature = "serde1")]
+        #[test]
+        fn serde_adapters() {
+            #[derive(serde::Serialize, serde::Deserialize)]
+            struct Canonical(#[serde(with = "canonical")] Context);
+            #[derive(serde::Serialize, serde::Deserialize)]
+            struct Compat(#[serde(with = "compat")] Context);
+            #[derive(serde::Serialize)]
+            struct Legacy(Context);
+
+            let context = context(SystemTime::now());
+            let encoded = bincode::serialize(&Canonical(context)).unwrap();
+            let Canonical(decoded) = bincode::deserialize(&encoded).unwrap();
+            assert_eq!(decoded.trace_context, context.trace_context);
+
+            let canonical = serde_json::to_string(&Canonical(context)).unwrap();
+            let legacy = serde_json::to_string(&Legacy(context)).unwrap();
+            for json in [&canonical, &legacy] {
+                let Compat(decoded) = serde_json::from_str(json).unwrap();
+                assert_eq!(decoded.trace_context, context.trace_context);
+                assert!(decoded.deadline > SystemTime::now() + Duration::from_secs(1));
+            }
+            assert!(serde_json::from_str::<Canonical>(&legacy).is_err());
+        }
+    }
+}
+
 /// An extension trait for [`tracing::Span`] for propagating tarpc Contexts.
-pub(crate) trait SpanExt {
+///
+/// Servers make the span of each request a child of the client's span this way. A proxy that
+/// receives a context from elsewhere, e.g. from the `traceparent` header of an HTTP request, can
+/// do the same for the span it handles the request in: the spans of the tarpc requests it makes
+/// in that span are then exported as descendants of the caller's span, in the same trace.
+pub trait SpanExt {
     /// Sets the given context on this span. Newly-created spans will be children of the given
     /// context's trace context.
     fn set_context(&self, context: &Context);
@@ -139,14 +865,45 @@ impl SpanExt for tracing::Span {
     fn set_context(&self, context: &Context) {
         self.set_parent(
             opentelemetry::Context::new()
-                .with_remote_span_context(opentelemetry::trace::SpanContext::new(
-                    opentelemetry::trace::TraceId::from(context.trace_context.trace_id),
-                    opentelemetry::trace::SpanId::from(context.trace_context.span_id),
-                    opentelemetry::trace::TraceFlags::from(context.trace_context.sampling_decision),
-                    true,
-                    opentelemetry::trace::TraceState::default(),
-                ))
+                .with_remote_span_context(context.trace_context.into())
                 .with_value(Deadline(context.deadline)),
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn background_mints_root_contexts() {
+        let background = Background::new("cron:cleanup")
+            .with_timeout(Duration::from_secs(60))
+            .with_sample_rate(0.0);
+        assert_eq!(background.principal(), "cron:cleanup");
+
+        let context = background.context();
+        let remaining = context.deadline.duration_since(SystemTime::now()).unwrap();
+        assert!(remaining <= Duration::from_secs(60));
+        assert!(remaining > Duration::from_secs(59));
+        assert!(!context.trace_id().is_none());
+        assert!(!context.trace_context.span_id.is_none());
+        assert_eq!(
+            context.trace_context.sampling_decision,
+            trace::SamplingDecision::Unsampled
+        );
+        assert_ne!(background.context().trace_id(), context.trace_id());
+
+        let context = Background::new("queue:emails").context();
+        assert_eq!(
+            context.trace_context.sampling_decision,
+            trace::SamplingDecision::Sampled
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "sample_rate must be between 0 and 1")]
+    fn background_rejects_invalid_sample_rate() {
+        Background::new("cron:cleanup").with_sample_rate(2.0);
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides end-to-end encryption of individual rpc fields.
+//!
+//! Args and responses marked `#[encrypted]` in a [service](crate::service) are sent as
+//! [`Encrypted`] ciphertext, which the client stub and the serving function encrypt and decrypt
+//! with [`Keys`]. The fields stay protected when transport encryption terminates at a proxy, and
+//! the plaintext is never logged by tarpc, because encrypted fields are formatted without their
+//! contents.
+//!
+//! tarpc does not implement any cipher itself; the [`KeyProvider`] wraps the application's
+//! cipher and key management. Clients are given keys with the generated `with_keys` fn, and
+//! servers resolve them from the [`Dependencies`](crate::server::Dependencies) passed to
+//! `serve_with`.
+
+use std::{fmt, io, marker::PhantomData, sync::Arc};
+
+/// Encrypts and decrypts the fields of rpcs.
+///
+/// Each field is identified by name: an arg as `{Service}.{rpc}.{arg}`, and a response as
+/// `{Service}.{rpc}`. Providers can select keys by field, and should authenticate the field name
+/// as associated data, so that a ciphertext can't be replayed as the value of another field.
+pub trait KeyProvider: Send + Sync + 'static {
+    /// Encrypts the plaintext of a field.
+    fn encrypt(&self, field: &'static str, plaintext: Vec<u8>) -> Vec<u8>;
+
+    /// Decrypts the ciphertext of a field, failing if the ciphertext was not encrypted for the
+    /// field with a known key, or was tampered with.
+    fn decrypt(&self, field: &'static str, ciphertext: Vec<u8>) -> io::Result<Vec<u8>>;
+}
+
+/// A shared [`KeyProvider`].
+#[derive(Clone)]
+pub struct Keys(Arc<dyn KeyProvider>);
+
+impl Keys {
+    /// Returns keys that encrypt and decrypt fields with `provider`.
+    pub fn new<P: KeyProvider>(provider: P) -> Self {
+        Self(Arc::new(provider))
+    }
+
+    /// Encrypts `value` as the plaintext of `field`.
+    pub fn seal<T: Plaintext>(&self, field: &'static str, value: &T) -> Encrypted<T> {
+        Encrypted {
+            ciphertext: self.0.encrypt(field, value.to_plaintext()),
+            ghost: PhantomData,
+        }
+    }
+
+    /// Decrypts the value of `field`.
+    pub fn open<T: Plaintext>(
+        &self,
+        field: &'static str,
+        encrypted: Encrypted<T>,
+    ) -> io::Result<T> {
+        T::from_plaintext(self.0.decrypt(field, encrypted.ciphertext)?)
+    }
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keys").finish_non_exhaustive()
+    }
+}
+
+/// The ciphertext of a `T`, as sent over the wire.
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde1", serde(transparent, bound = ""))]
+pub struct Encrypted<T> {
+    ciphertext: Vec<u8>,
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    ghost: PhantomData<fn() -> T>,
+}
+
+impl<T> Encrypted<T> {
+    /// Returns the ciphertext.
+    pub fn ciphertext(&self) -> &[u8] {
+        &self.ciphertext
+    }
+}
+
+impl<T> Clone for Encrypted<T> {
+    fn clone(&self) -> Self {
+        Self {
+            ciphertext: self.ciphertext.clone(),
+            ghost: PhantomData,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Encrypted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Encrypted({} bytes)", self.ciphertext.len())
+    }
+}
+
+/// A type that can be encrypted, by converting it to and from bytes.
+pub trait Plaintext: Sized {
+    /// Returns the bytes to encrypt.
+    fn to_plaintext(&self) -> Vec<u8>;
+
+    /// Converts decrypted bytes back to a value.
+    fn from_plaintext(plaintext: Vec<u8>) -> io::Result<Self>;
+}
+
+impl Plaintext for Vec<u8> {
+    fn to_plaintext(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn from_plaintext(plaintext: Vec<u8>) -> io::Result<Self> {
+        Ok(plaintext)
+    }
+}
+
+impl Plaintext for String {
+    fn to_plaintext(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn from_plaintext(plaintext: Vec<u8>) -> io::Result<Self> {
+        String::from_utf8(plaintext).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+macro_rules! impl_plaintext_for_int {
+    ($($int:ty),*) => {
+        $(
+            impl Plaintext for $int {
+                fn to_plaintext(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn from_plaintext(plaintext: Vec<u8>) -> io::Result<Self> {
+                    plaintext.try_into().map(<$int>::from_le_bytes).map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            concat!("plaintext is not the size of a ", stringify!($int)),
+                        )
+                    })
+                }
+            }
+        )*
+    };
+}
+
+impl_plaintext_for_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    /// XORs with a key derived from the field name. Not a cipher!
+    struct Xor;
+
+    impl KeyProvider for Xor {
+        fn encrypt(&self, field: &'static str, plaintext: Vec<u8>) -> Vec<u8> {
+            let key = field.len() as u8;
+            plaintext.into_iter().map(|b| b ^ key).collect()
+        }
+
+        fn decrypt(&self, field: &'static str, ciphertext: Vec<u8>) -> io::Result<Vec<u8>> {
+            Ok(self.encrypt(field, ciphertext))
+        }
+    }
+
+    #[test]
+    fn sealed_values_open_to_plaintext() {
+        let keys = Keys::new(Xor);
+        let ssn = keys.seal("People.add.ssn", &String::from("123-45-6789"));
+        assert_ne!(ssn.ciphertext(), b"123-45-6789");
+        assert_eq!(format!("{:?}", ssn), "Encrypted(11 bytes)");
+        assert_eq!(keys.open("People.add.ssn", ssn).unwrap(), "123-45-6789");
+
+        let age = keys.seal("People.add.age", &42u32);
+        assert_eq!(keys.open("People.add.age", age.clone()).unwrap(), 42);
+        assert_matches!(
+            keys.open::<u64>("People.add.age", Encrypted { ciphertext: age.ciphertext, ghost: PhantomData }),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData
+        );
+    }
+}
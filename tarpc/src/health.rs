@@ -0,0 +1,206 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a health-checking service, so that orchestrators and load balancers can probe tarpc
+//! servers.
+//!
+//! A server keeps the serving status of each of its services in a [`HealthRegistry`], and serves
+//! the registry as a [`Health`] service, e.g. [mounted](crate::router::Router::mount) alongside
+//! its other services. The server as a whole is registered under the empty name, and is
+//! [`Serving`](ServingStatus::Serving) from the time the registry is created until it's
+//! [shut down](HealthRegistry::shutdown).
+//!
+//! ```
+//! use tarpc::health::{HealthRegistry, ServingStatus};
+//!
+//! let registry = HealthRegistry::new();
+//! registry.set_status("World", ServingStatus::Serving);
+//! assert_eq!(registry.status("World"), ServingStatus::Serving);
+//! assert_eq!(registry.status(""), ServingStatus::Serving);
+//! assert_eq!(registry.status("Hello"), ServingStatus::ServiceUnknown);
+//! ```
+
+use crate::context;
+use futures::{
+    future::{self, Ready},
+    prelude::*,
+    stream::{self, BoxStream},
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+use tokio::sync::watch;
+
+/// Whether a service is able to handle requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum ServingStatus {
+    /// The service is able to handle requests.
+    Serving,
+    /// The service is registered, but is not able to handle requests, e.g. because a backend it
+    /// depends on is down, or because the server is shutting down.
+    NotServing,
+    /// The service isn't registered.
+    ServiceUnknown,
+}
+
+/// Reports the serving status of the services of a server.
+#[tarpc_plugins::service]
+pub trait Health {
+    /// Returns the serving status of `service`, or of the server as a whole if `service` is
+    /// empty.
+    async fn check(service: String) -> ServingStatus;
+
+    /// Streams the serving status of `service`, or of the server as a whole if `service` is
+    /// empty: the current status, then each change of status.
+    async fn watch(service: String) -> impl Stream<Item = ServingStatus>;
+}
+
+/// The serving status of each of the services of a server, which the services update as their
+/// status changes. Cheap to clone; clones share their statuses.
+///
+/// A registry is served as a [`Health`] service with [`serve`](Health::serve).
+#[derive(Clone, Debug)]
+pub struct HealthRegistry {
+    services: Arc<Mutex<HashMap<String, watch::Sender<ServingStatus>>>>,
+}
+
+impl Default for HealthRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HealthRegistry {
+    /// Returns a registry in which the server as a whole is serving, and no services are
+    /// registered.
+    pub fn new() -> Self {
+        let registry = HealthRegistry {
+            services: Arc::default(),
+        };
+        registry.set_status("", ServingStatus::Serving);
+        registry
+    }
+
+    /// Sets the serving status of `service`, registering it if it isn't already, and notifies
+    /// the clients watching it if the status changed.
+    pub fn set_status(&self, service: impl Into<String>, status: ServingStatus) {
+        let mut services = self.services.lock().unwrap_or_else(PoisonError::into_inner);
+        let sender = services
+            .entry(service.into())
+            .or_insert_with(|| watch::channel(ServingStatus::ServiceUnknown).0);
+        sender.send_if_modified(|current| std::mem::replace(current, status) != status);
+    }
+
+    /// Unregisters `service`. Clients watching it are notified that it's unknown.
+    pub fn remove(&self, service: &str) {
+        self.set_status(service, ServingStatus::ServiceUnknown);
+    }
+
+    /// Returns the serving status of `service`.
+    pub fn status(&self, service: &str) -> ServingStatus {
+        let services = self.services.lock().unwrap_or_else(PoisonError::into_inner);
+        services
+            .get(service)
+            .map_or(ServingStatus::ServiceUnknown, |sender| *sender.borrow())
+    }
+
+    /// Marks the server and every registered service as not serving, e.g. while draining before
+    /// shutdown. Services updated afterwards can still become serving again.
+    pub fn shutdown(&self) {
+        let services = self.services.lock().unwrap_or_else(PoisonError::into_inner);
+        for sender in services.values() {
+            sender.send_if_modified(|status| {
+                let serving = *status == ServingStatus::Serving;
+                if serving {
+                    *status = ServingStatus::NotServing;
+                }
+                serving
+            });
+        }
+    }
+
+    fn subscribe(&self, service: String) -> watch::Receiver<ServingStatus> {
+        let mut services = self.services.lock().unwrap_or_else(PoisonError::into_inner);
+        services
+            .entry(service)
+            .or_insert_with(|| watch::channel(ServingStatus::ServiceUnknown).0)
+            .subscribe()
+    }
+}
+
+impl Health for HealthRegistry {
+    type CheckFut = Ready<ServingStatus>;
+
+    fn check(self, _: context::Context, service: String) -> Self::CheckFut {
+        future::ready(self.status(&service))
+    }
+
+    type WatchStream = BoxStream<'static, ServingStatus>;
+
+    fn watch(self, _: context::Context, service: String) -> Self::WatchStream {
+        let statuses = self.subscribe(service);
+        stream::unfold((statuses, true), |(mut statuses, first)| async move {
+            if !first {
+                statuses.changed().await.ok()?;
+            }
+            let status = *statuses.borrow();
+            Some((status, (statuses, false)))
+        })
+        .boxed()
+    }
+}
+
+#[cfg(all(test, feature = "tokio1"))]
+mod tests {
+    use super::*;
+    use crate::{
+        client,
+        server::{BaseChannel, Channel},
+        transport::channel,
+    };
+    use assert_matches::assert_matches;
+
+    #[tokio::test]
+    async fn check_and_watch() -> anyhow::Result<()> {
+        let registry = HealthRegistry::new();
+        let (tx, rx) = channel::unbounded();
+        tokio::spawn(BaseChannel::with_defaults(rx).execute(registry.clone().serve()));
+        let client = HealthClient::new(client::Config::default(), tx).spawn();
+
+        assert_eq!(
+            client.check(context::current(), "".into()).await?,
+            ServingStatus::Serving
+        );
+        assert_eq!(
+            client.check(context::current(), "World".into()).await?,
+            ServingStatus::ServiceUnknown
+        );
+
+        let mut statuses = client.watch(context::current(), "World".into()).await?;
+        assert_matches!(
+            statuses.next().await,
+            Some(Ok(ServingStatus::ServiceUnknown))
+        );
+        registry.set_status("World", ServingStatus::Serving);
+        assert_matches!(statuses.next().await, Some(Ok(ServingStatus::Serving)));
+        registry.set_status("World", ServingStatus::Serving);
+        registry.shutdown();
+        assert_matches!(statuses.next().await, Some(Ok(ServingStatus::NotServing)));
+        registry.remove("World");
+        assert_matches!(
+            statuses.next().await,
+            Some(Ok(ServingStatus::ServiceUnknown))
+        );
+
+        assert_eq!(
+            client.check(context::current(), "".into()).await?,
+            ServingStatus::NotServing
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,532 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Pre-composed stacks of tower middleware for clients and servers.
+//!
+//! [`standard_client`] wraps a client implementing [`tower_service::Service`], e.g. a generated
+//! service client, and [`standard_server`] wraps a [`Serve`](crate::server::Serve)
+//! implementation through [`Layered`](crate::server::tower::Layered). From the outermost layer
+//! in, the stacks record [metrics](crate::metrics), retry requests the server was
+//! [unavailable](crate::client::RpcError::is_unavailable) for within a retry [`Budget`] (clients
+//! only), time out requests, and limit the requests in flight at once:
+//!
+//! ```rust
+//! # use futures::future;
+//! # use tarpc::{context, layers, server::tower::Layered};
+//! # use std::time::Duration;
+//! let serve = |_: context::Context, i: i32| future::ready(i + 1);
+//! let serve = Layered::new(
+//!     layers::standard_server().with_timeout(Duration::from_secs(5)),
+//!     serve,
+//! );
+//! ```
+//!
+//! The middleware the stacks are made of is re-exported, so that stacks that need other
+//! behavior can be composed from the same parts with a [`ServiceBuilder`].
+
+use crate::{
+    client::RpcError,
+    metrics::{Outcome, Recorder, Side},
+    server::tower::ServeRequest,
+};
+use futures::{future, prelude::*, ready, task::*};
+use pin_project::{pin_project, pinned_drop};
+use std::{error::Error, pin::Pin, sync::Arc, time::Duration};
+use tokio::time::Instant;
+use tower_layer::Layer;
+use tower_service::Service;
+
+pub use tower::{
+    limit::{ConcurrencyLimit, ConcurrencyLimitLayer},
+    retry::{budget::Budget, Policy, Retry, RetryLayer},
+    timeout::{error::Elapsed, Timeout, TimeoutLayer},
+    ServiceBuilder,
+};
+
+/// Names the method a request calls, to label the metrics of the request.
+///
+/// Implemented by the `#[tarpc::service]` attribute for the service's request enum.
+pub trait Method {
+    /// Returns the name of the method the request calls, e.g. `World.hello`, if known.
+    fn method(&self) -> Option<&'static str>;
+}
+
+impl<Req: Method> Method for ServeRequest<Req> {
+    fn method(&self) -> Option<&'static str> {
+        self.message.method()
+    }
+}
+
+/// Returns the layers of a client with sane defaults: requests time out after 10 seconds, at
+/// most 100 are in flight at once, and unavailable errors are retried within the default
+/// [`Budget`], i.e. up to 20% of requests plus 10 per second. No metrics are recorded until a
+/// recorder is [set](StandardClient::with_metrics).
+pub fn standard_client() -> StandardClient {
+    StandardClient {
+        timeout: Duration::from_secs(10),
+        max_in_flight_requests: 100,
+        budget: Arc::new(Budget::default()),
+        metrics: None,
+    }
+}
+
+/// Returns the layers of a server with sane defaults: requests time out after 10 seconds, and
+/// at most 1000 are handled at once, across all channels using the same `Serve`. No metrics are
+/// recorded until a recorder is [set](StandardServer::with_metrics).
+pub fn standard_server() -> StandardServer {
+    StandardServer {
+        timeout: Duration::from_secs(10),
+        max_in_flight_requests: 1000,
+        metrics: None,
+    }
+}
+
+/// The layers of a client, returned by [`standard_client`].
+#[derive(Clone, Debug)]
+pub struct StandardClient {
+    timeout: Duration,
+    max_in_flight_requests: usize,
+    budget: Arc<Budget>,
+    metrics: Option<Arc<dyn Recorder>>,
+}
+
+impl StandardClient {
+    /// Sets how long a request is waited on before it fails with [`Elapsed`]. Each retry is
+    /// waited on for as long.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many requests may be in flight at once; more requests wait for a slot.
+    pub fn with_max_in_flight_requests(mut self, max_in_flight_requests: usize) -> Self {
+        self.max_in_flight_requests = max_in_flight_requests;
+        self
+    }
+
+    /// Sets the budget that bounds how many requests are retried.
+    pub fn with_retry_budget(mut self, budget: Budget) -> Self {
+        self.budget = Arc::new(budget);
+        self
+    }
+
+    /// Sets the recorder of the metrics of requests, and of their retries.
+    pub fn with_metrics(mut self, recorder: Arc<dyn Recorder>) -> Self {
+        self.metrics = Some(recorder);
+        self
+    }
+}
+
+impl<S> Layer<S> for StandardClient {
+    type Service = Metrics<Retry<RetryUnavailable, Timeout<ConcurrencyLimit<S>>>>;
+
+    fn layer(&self, client: S) -> Self::Service {
+        Metrics {
+            inner: Retry::new(
+                RetryUnavailable {
+                    budget: self.budget.clone(),
+                    metrics: self.metrics.clone(),
+                },
+                Timeout::new(
+                    ConcurrencyLimit::new(client, self.max_in_flight_requests),
+                    self.timeout,
+                ),
+            ),
+            side: Side::Client,
+            recorder: self.metrics.clone(),
+        }
+    }
+}
+
+/// The layers of a server, returned by [`standard_server`].
+#[derive(Clone, Debug)]
+pub struct StandardServer {
+    timeout: Duration,
+    max_in_flight_requests: usize,
+    metrics: Option<Arc<dyn Recorder>>,
+}
+
+impl StandardServer {
+    /// Sets how long a request is handled before it fails with [`Elapsed`].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many requests may be handled at once; more requests wait for a slot.
+    pub fn with_max_in_flight_requests(mut self, max_in_flight_requests: usize) -> Self {
+        self.max_in_flight_requests = max_in_flight_requests;
+        self
+    }
+
+    /// Sets the recorder of the metrics of requests. Note that a recorder set in
+    /// [`server::Config::metrics`](crate::server::Config::metrics) already records them.
+    pub fn with_metrics(mut self, recorder: Arc<dyn Recorder>) -> Self {
+        self.metrics = Some(recorder);
+        self
+    }
+}
+
+impl<S> Layer<S> for StandardServer {
+    type Service = Metrics<Timeout<ConcurrencyLimit<S>>>;
+
+    fn layer(&self, serve: S) -> Self::Service {
+        Metrics {
+            inner: Timeout::new(
+                ConcurrencyLimit::new(serve, self.max_in_flight_requests),
+                self.timeout,
+            ),
+            side: Side::Server,
+            recorder: self.metrics.clone(),
+        }
+    }
+}
+
+/// A retry [`Policy`] that retries requests that failed with an
+/// [unavailable](RpcError::is_unavailable) error, as long as its [`Budget`] allows.
+#[derive(Clone, Debug)]
+pub struct RetryUnavailable {
+    budget: Arc<Budget>,
+    metrics: Option<Arc<dyn Recorder>>,
+}
+
+impl RetryUnavailable {
+    /// Returns a policy that retries requests within `budget`. Successful requests deposit into
+    /// the budget, and retries withdraw from it.
+    pub fn new(budget: Arc<Budget>) -> Self {
+        Self {
+            budget,
+            metrics: None,
+        }
+    }
+
+    /// Sets the recorder of the requests retried, and of those not retried because the budget
+    /// is exhausted.
+    pub fn with_metrics(mut self, recorder: Arc<dyn Recorder>) -> Self {
+        self.metrics = Some(recorder);
+        self
+    }
+}
+
+impl<Req, Resp, E> Policy<Req, Resp, E> for RetryUnavailable
+where
+    Req: Method + Clone,
+    E: AsRef<dyn Error + Send + Sync>,
+{
+    type Future = future::Ready<Self>;
+
+    fn retry(&self, request: &Req, result: Result<&Resp, &E>) -> Option<Self::Future> {
+        match result {
+            Ok(_) => {
+                self.budget.deposit();
+                None
+            }
+            Err(e) => {
+                let unavailable = e
+                    .as_ref()
+                    .downcast_ref::<RpcError>()
+                    .map_or(false, RpcError::is_unavailable);
+                if !unavailable {
+                    return None;
+                }
+                let method = request.method().unwrap_or("");
+                if self.budget.withdraw().is_err() {
+                    if let Some(metrics) = &self.metrics {
+                        metrics.retry_budget_exhausted(method);
+                    }
+                    return None;
+                }
+                if let Some(metrics) = &self.metrics {
+                    metrics.retry(method);
+                }
+                Some(future::ready(self.clone()))
+            }
+        }
+    }
+
+    fn clone_request(&self, request: &Req) -> Option<Req> {
+        Some(request.clone())
+    }
+}
+
+/// A [`Layer`] that records the metrics of requests.
+#[derive(Clone, Debug)]
+pub struct MetricsLayer {
+    side: Side,
+    recorder: Arc<dyn Recorder>,
+}
+
+impl MetricsLayer {
+    /// Returns a layer that records the metrics of the requests of `side` with `recorder`.
+    pub fn new(side: Side, recorder: Arc<dyn Recorder>) -> Self {
+        Self { side, recorder }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = Metrics<S>;
+
+    fn layer(&self, inner: S) -> Metrics<S> {
+        Metrics {
+            inner,
+            side: self.side,
+            recorder: Some(self.recorder.clone()),
+        }
+    }
+}
+
+/// A [`Service`] that records when requests start and complete, and how.
+#[derive(Clone, Debug)]
+pub struct Metrics<S> {
+    inner: S,
+    side: Side,
+    recorder: Option<Arc<dyn Recorder>>,
+}
+
+impl<S, Req> Service<Req> for Metrics<S>
+where
+    S: Service<Req>,
+    Req: Method,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = MetricsFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Req) -> Self::Future {
+        let method = request.method().unwrap_or("");
+        if let Some(recorder) = &self.recorder {
+            recorder.request_started(self.side, method);
+        }
+        MetricsFuture {
+            inner: self.inner.call(request),
+            side: self.side,
+            method,
+            started: Instant::now(),
+            recorder: self.recorder.clone(),
+        }
+    }
+}
+
+/// The response future of [`Metrics`]. A request whose future is dropped before it completes is
+/// recorded as [canceled](Outcome::Canceled).
+#[pin_project(PinnedDrop)]
+#[derive(Debug)]
+pub struct MetricsFuture<F> {
+    #[pin]
+    inner: F,
+    side: Side,
+    method: &'static str,
+    started: Instant,
+    /// Taken once the outcome of the request is recorded.
+    recorder: Option<Arc<dyn Recorder>>,
+}
+
+impl<F> MetricsFuture<F> {
+    fn record(
+        recorder: Option<Arc<dyn Recorder>>,
+        side: Side,
+        method: &str,
+        started: Instant,
+        outcome: Outcome,
+    ) {
+        if let Some(recorder) = recorder {
+            recorder.request_completed(side, method, started.elapsed(), outcome);
+        }
+    }
+}
+
+impl<F, Resp, E> Future for MetricsFuture<F>
+where
+    F: Future<Output = Result<Resp, E>>,
+{
+    type Output = Result<Resp, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = ready!(this.inner.poll(cx));
+        let outcome = match result {
+            Ok(_) => Outcome::Success,
+            Err(_) => Outcome::Error,
+        };
+        Self::record(
+            this.recorder.take(),
+            *this.side,
+            this.method,
+            *this.started,
+            outcome,
+        );
+        Poll::Ready(result)
+    }
+}
+
+#[pinned_drop]
+impl<F> PinnedDrop for MetricsFuture<F> {
+    fn drop(self: Pin<&mut Self>) {
+        let this = self.project();
+        Self::record(
+            this.recorder.take(),
+            *this.side,
+            this.method,
+            *this.started,
+            Outcome::Canceled,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        context,
+        metrics::EventRecorder,
+        server::{tower::Layered, Serve},
+        ServerError,
+    };
+    use assert_matches::assert_matches;
+    use std::{
+        io,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use tower::ServiceExt;
+
+    #[derive(Clone, Debug)]
+    struct Add(i32);
+
+    impl Method for Add {
+        fn method(&self) -> Option<&'static str> {
+            Some("Calculator.add")
+        }
+    }
+
+    /// Returns a client that fails the first `failures` requests with `error`.
+    fn flaky_client(
+        failures: usize,
+        error: RpcError,
+    ) -> (
+        impl Service<Add, Response = i32, Error = RpcError, Future = impl Send> + Clone,
+        Arc<AtomicUsize>,
+    ) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let client = tower::service_fn({
+            let calls = calls.clone();
+            move |Add(i)| {
+                let result = if calls.fetch_add(1, Ordering::SeqCst) < failures {
+                    Err(error.clone())
+                } else {
+                    Ok(i + 1)
+                };
+                future::ready(result)
+            }
+        });
+        (client, calls)
+    }
+
+    #[tokio::test]
+    async fn standard_client_retries_unavailable_errors() {
+        let recorder = Arc::new(EventRecorder::default());
+        let (client, calls) = flaky_client(2, RpcError::Disconnected("gone".into()));
+        let client = standard_client()
+            .with_metrics(recorder.clone())
+            .layer(client);
+
+        assert_eq!(client.oneshot(Add(1)).await.unwrap(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            recorder.events(),
+            [
+                "client started Calculator.add",
+                "client retry Calculator.add",
+                "client retry Calculator.add",
+                "client completed Calculator.add success"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn standard_client_does_not_retry_other_errors() {
+        let (client, calls) = flaky_client(
+            1,
+            RpcError::Server(ServerError::new(io::ErrorKind::InvalidInput, "bad")),
+        );
+        let client = standard_client().layer(client);
+
+        let error = client.oneshot(Add(1)).await.unwrap_err();
+        assert_matches!(error.downcast_ref::<RpcError>(), Some(RpcError::Server(_)));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn standard_client_retries_within_budget() {
+        let recorder = Arc::new(EventRecorder::default());
+        let (client, calls) = flaky_client(usize::MAX, RpcError::Disconnected("gone".into()));
+        // A budget of 1 retry per second, and none for successful requests.
+        let budget = Budget::new(Duration::from_secs(1), 1, 0.0);
+        let client = standard_client()
+            .with_retry_budget(budget)
+            .with_metrics(recorder.clone())
+            .layer(client);
+
+        assert_matches!(client.oneshot(Add(1)).await, Err(_));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            recorder.events(),
+            [
+                "client started Calculator.add",
+                "client retry Calculator.add",
+                "client retry budget exhausted Calculator.add",
+                "client completed Calculator.add error"
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn standard_client_times_out() {
+        let client = tower::service_fn(|_: Add| future::pending::<Result<i32, RpcError>>());
+        let client = standard_client()
+            .with_timeout(Duration::from_secs(1))
+            .layer(client);
+
+        let error = client.oneshot(Add(1)).await.unwrap_err();
+        assert!(error.is::<Elapsed>());
+    }
+
+    #[tokio::test]
+    async fn metrics_record_canceled_requests() {
+        let recorder = Arc::new(EventRecorder::default());
+        let client = tower::service_fn(|_: Add| future::pending::<Result<i32, RpcError>>());
+        let mut client = MetricsLayer::new(Side::Client, recorder.clone()).layer(client);
+
+        let response = client.ready().await.unwrap().call(Add(1));
+        drop(response);
+        assert_eq!(
+            recorder.events(),
+            [
+                "client started Calculator.add",
+                "client completed Calculator.add canceled"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn standard_server_records_metrics() {
+        let recorder = Arc::new(EventRecorder::default());
+        let serve = |_: context::Context, Add(i)| future::ready(i + 1);
+        let serve = Layered::new(standard_server().with_metrics(recorder.clone()), serve);
+
+        assert_eq!(serve.serve(context::current(), Add(1)).await, Ok(2));
+        assert_eq!(
+            recorder.events(),
+            [
+                "server started Calculator.add",
+                "server completed Calculator.add success",
+            ]
+        );
+    }
+}
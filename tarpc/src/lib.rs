@@ -29,6 +29,11 @@
 //! Some other features of tarpc:
 //! - Pluggable transport: any type implementing `Stream<Item = Request> + Sink<Response>` can be
 //!   used as a transport to connect the client and server.
+//! - Server streaming: an rpc can respond with a stream of messages instead of a single one. Stream
+//!   items are flow-controlled, so a slow client applies backpressure to the server's handler.
+//! - Client streaming: an rpc can take a stream of messages from the client, which the client
+//!   stub sends through a `Sink`. Combined with server streaming, both sides of a single rpc can
+//!   exchange messages until either side closes its stream.
 //! - `Send + 'static` optional: if the transport doesn't require it, neither does tarpc!
 //! - Cascading cancellation: dropping a request will send a cancellation message to the server.
 //!   The server will cease any unfinished work on the request, subsequently cancelling any of its
@@ -46,9 +51,25 @@
 //!   server. Even for applications not connected to a distributed tracing collector, the
 //!   instrumentation can also be ingested by regular loggers like
 //!   [env_logger](https://github.com/env-logger-rs/env_logger/).
+//! - Metrics: clients and servers report the requests they start and complete to a
+//!   [`metrics::Recorder`]. Enabling the `prometheus` Cargo feature adds a recorder that renders
+//!   them in the Prometheus text format.
 //! - Serde serialization: enabling the `serde1` Cargo feature will make service requests and
 //!   responses `Serialize + Deserialize`. It's entirely optional, though: in-memory transports can
 //!   be used, as well, so the price of serialization doesn't have to be paid when it's not needed.
+//! - Tower integration: enabling the `tower` Cargo feature makes generated clients implement
+//!   `tower::Service`, so they can be wrapped in tower's retry, timeout, and load-balancing layers.
+//!   On the server, [`server::tower::Layered`] wraps any `Serve` implementation in tower
+//!   middleware.
+//! - Failpoints: enabling the `failpoints` Cargo feature adds [fail](https://docs.rs/fail)
+//!   failpoints to the request pipeline, so tests can inject failures by name. Besides the `fail`
+//!   crate's built-in actions like `panic` and `sleep`, the `return` action has the following
+//!   effect at each failpoint:
+//!   - `tarpc::client::before_send`: the request is not written to the transport.
+//!   - `tarpc::server::after_decode`: the received request is dropped.
+//!   - `tarpc::server::before_handler`: the request is rejected with a [`ServerError`] whose
+//!     detail is the action's argument.
+//!   - `tarpc::server::before_flush`: responses are held back until the failpoint is disabled.
 //!
 //! ## Usage
 //! Add to your `Cargo.toml` dependencies:
@@ -204,10 +225,24 @@
 #![allow(clippy::type_complexity)]
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
+// Lets the code generated for the services defined in this crate refer to `tarpc::`.
+extern crate self as tarpc;
+
 #[cfg(feature = "serde1")]
 #[doc(hidden)]
 pub use serde;
 
+#[cfg(feature = "serde1")]
+#[doc(hidden)]
+pub use crate::util::serde::VariantTag;
+
+#[doc(hidden)]
+pub use futures;
+
+#[cfg(feature = "tower")]
+#[doc(hidden)]
+pub use tower_service;
+
 #[cfg(feature = "serde-transport")]
 pub use {tokio_serde, tokio_util};
 
@@ -232,6 +267,115 @@ pub use tarpc_plugins::derive_serde;
 /// }
 /// ```
 ///
+/// An rpc whose return type is `impl Stream<Item = T>` is a server-streaming rpc: the server
+/// sends back any number of `T`s, and the client stub returns a stream of results:
+///
+/// ```
+/// #[tarpc::service]
+/// trait Service {
+/// /// Count from 0 up to n
+/// async fn count(n: u32) -> impl Stream<Item = u32>;
+/// }
+/// ```
+///
+/// An rpc with an arg of type `impl Stream<Item = T>` is a client-streaming rpc: the client stub
+/// returns a `Sink` for the `T`s along with a future of the response, and the service receives a
+/// [`RequestStream`](server::RequestStream) of the items:
+///
+/// ```
+/// #[tarpc::service]
+/// trait Service {
+/// /// Sum up all the numbers
+/// async fn sum(numbers: impl Stream<Item = u32>) -> u32;
+/// }
+/// ```
+///
+/// An rpc can be both client- and server-streaming, in which case both sides exchange items until
+/// either side closes its end of the stream.
+///
+/// An arg marked `#[inject]` is not sent by the client. Instead, the server resolves it by type
+/// from the [`Dependencies`](server::Dependencies) the serving function was created with via
+/// `serve_with`; a request whose dependencies are missing is rejected with a
+/// [`NotFound`](std::io::ErrorKind::NotFound) error. Injected args are cloned for each request,
+/// so they are usually `Arc`s:
+///
+/// ```
+/// # use std::sync::Arc;
+/// # struct Database;
+/// #[tarpc::service]
+/// trait Service {
+/// /// Look up a value
+/// async fn get(#[inject] db: Arc<Database>, key: String) -> Option<String>;
+/// }
+/// ```
+///
+/// An arg marked `#[encrypted]` is encrypted end to end, and so is the response of an rpc marked
+/// `#[encrypted]`; see [`encryption`]. The client stub encrypts and decrypts them with the keys
+/// given to its `with_keys` fn, and the serving function with the [`Keys`](encryption::Keys) in
+/// its dependencies. Encrypted types must implement [`Plaintext`](encryption::Plaintext), and
+/// encryption is only supported in rpcs that don't stream:
+///
+/// ```
+/// #[tarpc::service]
+/// trait Service {
+/// /// Look up a social security number
+/// #[encrypted]
+/// async fn ssn(#[encrypted] name: String) -> String;
+/// }
+/// ```
+///
+/// Requests and responses are serialized as enums with a variant per rpc, which binary formats,
+/// like bincode, identify by position, so adding or reordering rpcs breaks old clients. To keep
+/// them compatible, give every rpc a stable wire tag with `#[tarpc(id = N)]`, for an `N` below
+/// 2<sup>31</sup>; variants are then identified by their tags instead. Self-describing formats,
+/// like JSON, identify variants by name either way. Adding and reordering rpcs is safe in both
+/// kinds of formats, but tags don't make renaming an rpc safe in self-describing formats: old
+/// clients still send the old name, which the server rejects as an unknown variant. Keep the
+/// names of rpcs stable when peers use such a format:
+///
+/// ```
+/// #[tarpc::service]
+/// trait Service {
+/// #[tarpc(id = 2)]
+/// async fn hello(name: String) -> String;
+/// #[tarpc(id = 1)]
+/// async fn goodbye(name: String) -> String;
+/// }
+/// ```
+///
+/// An rpc marked `#[tarpc(paged)]` returns its response a page at a time; see [`paging`].
+///
+/// An rpc marked `#[tarpc(one_way)]` returns nothing, and its client stub resolves as soon as the
+/// request is handed off, without waiting for a response: the server runs the rpc without tracking
+/// it as in flight and sends nothing back, which suits high-volume calls such as telemetry.
+///
+/// An rpc marked `#[tarpc(app_error)]` returns a `Result<T, E>` whose `E` is an application
+/// error, sent as part of the response like `T`. Its client stub flattens the result into a
+/// `Result<T, CallError<E>>`, so that clients can match on the application error and on
+/// [`RpcError`](client::RpcError)s alike; see [`CallError`](client::CallError):
+///
+/// ```
+/// #[tarpc::service]
+/// trait Service {
+/// /// Withdraw from the balance, or explain why not
+/// #[tarpc(app_error)]
+/// async fn withdraw(amount: u64) -> Result<u64, String>;
+/// }
+/// ```
+///
+/// `#[tarpc::service(erased_client = true)]` also generates an `ErasedServiceClient`, which boxes
+/// its transport and the futures and streams its rpcs return, so that it is `Send + Sync +
+/// 'static` and can be stored where the transport type can't be named, such as a plugin registry:
+///
+/// ```
+/// #[tarpc::service(erased_client = true)]
+/// trait Service {
+/// async fn hello(name: String) -> String;
+/// }
+///
+/// let clients: Vec<ErasedServiceClient> = Vec::new();
+/// ```
+///
 /// Attributes can be attached to each rpc. These attributes
 /// will then be attached to the generated service traits'
 /// corresponding `fn`s, as well as to the client stubs' RPCs.
@@ -240,8 +384,11 @@ pub use tarpc_plugins::derive_serde;
 ///
 /// * `trait Service` -- defines the RPC service.
 ///   * `fn serve` -- turns a service impl into a request handler.
+///   * `fn serve_with` -- turns a service impl into a request handler with dependencies.
 /// * `Client` -- a client stub with a fn for each RPC.
 ///   * `fn new_stub` -- creates a new Client stub.
+///   * `fn closed` -- resolves once the client's connection terminates.
+/// * `ErasedClient` -- a type-erased client stub, with `erased_client = true`.
 pub use tarpc_plugins::service;
 
 /// A utility macro that can be used for RPC server implementations.
@@ -300,22 +447,41 @@ pub use tarpc_plugins::service;
 /// `async`, meaning that this should not break existing code.
 pub use tarpc_plugins::server;
 
+pub mod admin;
 pub(crate) mod cancellations;
+pub mod capabilities;
 pub mod client;
 pub mod context;
+pub mod encryption;
+pub mod health;
+#[cfg(feature = "tower")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tower")))]
+pub mod layers;
+pub mod metrics;
+pub mod paging;
+pub mod pubsub;
+#[cfg(feature = "reflection")]
+#[cfg_attr(docsrs, doc(cfg(feature = "reflection")))]
+pub mod reflection;
+#[cfg(feature = "serde1")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde1")))]
+pub mod router;
 pub mod server;
 pub mod transport;
 pub(crate) mod util;
 
 pub use crate::transport::sealed::Transport;
 
-use anyhow::Context as _;
-use futures::task::*;
-use std::{error::Error, fmt::Display, io, time::SystemTime};
+use std::{io, time::SystemTime};
 
 /// A message from a client to a server.
 #[derive(Debug)]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 #[non_exhaustive]
 pub enum ClientMessage<T> {
     /// A request initiated by a user. The server responds to a request by invoking a
@@ -337,12 +503,77 @@ pub enum ClientMessage<T> {
         /// The ID of the request to cancel.
         request_id: u64,
     },
+    /// Grants a server-streaming request handler permission to send more stream items,
+    /// automatically sent by the client as items of a response stream are consumed.
+    StreamCredit {
+        /// The ID of the streaming request.
+        request_id: u64,
+        /// The number of additional items the server may send.
+        credits: u32,
+    },
+    /// An item of a client-streaming request, sent after the request itself.
+    StreamItem {
+        /// The ID of the streaming request.
+        request_id: u64,
+        /// The stream item.
+        item: T,
+    },
+    /// Indicates that the client has no more items to send for a client-streaming request.
+    StreamEnd {
+        /// The ID of the streaming request.
+        request_id: u64,
+    },
+    /// A request that expects no response, sent by `#[tarpc(one_way)]` rpcs. It has no ID: the
+    /// server handles it without tracking it as in flight, and discards the handler's output.
+    OneWay {
+        /// Trace context, deadline, and other cross-cutting concerns.
+        context: context::Context,
+        /// The request body.
+        message: T,
+    },
+    /// Opens the handshake, announcing the capabilities of the client. Sent before any request by
+    /// clients configured with [`capabilities`](client::Config::capabilities); the server answers
+    /// with a [`ServerMessage::Hello`].
+    Hello {
+        /// The capabilities of the client.
+        capabilities: capabilities::Capabilities,
+    },
+    /// Several requests coalesced into one message, sent by clients configured with a
+    /// [`max_batch_size`](client::Config::max_batch_size) above one. The server handles each
+    /// request as if it had been sent on its own.
+    Batch {
+        /// The requests, in the order they were sent.
+        requests: Vec<Request<T>>,
+    },
+}
+
+impl<T> ClientMessage<T> {
+    /// Returns the ID of the request this message is associated with, or None if the message is
+    /// a [one-way](ClientMessage::OneWay) request, a [handshake](ClientMessage::Hello), or a
+    /// [batch](ClientMessage::Batch) of requests.
+    pub fn request_id(&self) -> Option<u64> {
+        match self {
+            ClientMessage::Request(request) => Some(request.id),
+            ClientMessage::Cancel { request_id, .. }
+            | ClientMessage::StreamCredit { request_id, .. }
+            | ClientMessage::StreamItem { request_id, .. }
+            | ClientMessage::StreamEnd { request_id } => Some(*request_id),
+            ClientMessage::OneWay { .. }
+            | ClientMessage::Hello { .. }
+            | ClientMessage::Batch { .. } => None,
+        }
+    }
 }
 
 /// A request from a client to a server.
 #[derive(Clone, Copy, Debug)]
 #[non_exhaustive]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 pub struct Request<T> {
     /// Trace context, deadline, and other cross-cutting concerns.
     pub context: context::Context,
@@ -356,6 +587,11 @@ pub struct Request<T> {
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 #[non_exhaustive]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 pub struct Response<T> {
     /// The ID of the request being responded to.
     pub request_id: u64,
@@ -363,11 +599,90 @@ pub struct Response<T> {
     pub message: Result<T, ServerError>,
 }
 
+/// A message from a server to a client.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
+#[non_exhaustive]
+pub enum ServerMessage<T> {
+    /// A response to a request. A response is always the last message sent for a request; for
+    /// server-streaming requests, an error response terminates the stream early.
+    Response(Response<T>),
+    /// An item produced by a server-streaming request handler.
+    StreamItem {
+        /// The ID of the request being responded to.
+        request_id: u64,
+        /// The stream item.
+        item: T,
+    },
+    /// Indicates that a server-streaming request handler has no more items to send.
+    StreamEnd {
+        /// The ID of the request being responded to.
+        request_id: u64,
+    },
+    /// Indicates that the request is still being handled. Only sent for requests whose context
+    /// sets [`keep_alive`](context::Context::keep_alive).
+    KeepAlive {
+        /// The ID of the request still being handled.
+        request_id: u64,
+    },
+    /// A message the server sends unsolicited, e.g. to notify the client that cached data is
+    /// stale. See [`Requests::pusher`](server::Requests::pusher).
+    Push(T),
+    /// Completes the handshake opened by a [`ClientMessage::Hello`], announcing the capabilities of
+    /// the server.
+    Hello {
+        /// The capabilities of the server.
+        capabilities: capabilities::Capabilities,
+    },
+    /// The last message of a channel, sent when the server tears the channel down before the
+    /// client closed it, e.g. because the server's [`Requests`](server::Requests) were dropped.
+    /// Only sent to clients that announced the
+    /// [`CLOSE`](capabilities::Capabilities::CLOSE) capability.
+    Close {
+        /// The IDs of the requests that were in flight, whose handlers were aborted. Nothing more
+        /// is sent for them.
+        aborted: Vec<u64>,
+    },
+}
+
+impl<T> ServerMessage<T> {
+    /// Returns the ID of the request this message is associated with, or None for
+    /// [pushed](ServerMessage::Push) messages, [handshakes](ServerMessage::Hello), and
+    /// [closes](ServerMessage::Close).
+    pub fn request_id(&self) -> Option<u64> {
+        match self {
+            ServerMessage::Response(response) => Some(response.request_id),
+            ServerMessage::StreamItem { request_id, .. }
+            | ServerMessage::StreamEnd { request_id }
+            | ServerMessage::KeepAlive { request_id } => Some(*request_id),
+            ServerMessage::Push(_) | ServerMessage::Hello { .. } | ServerMessage::Close { .. } => {
+                None
+            }
+        }
+    }
+}
+
+impl<T> From<Response<T>> for ServerMessage<T> {
+    fn from(response: Response<T>) -> Self {
+        ServerMessage::Response(response)
+    }
+}
+
 /// An error indicating the server aborted the request early, e.g., due to request throttling.
 #[derive(thiserror::Error, Clone, Debug, PartialEq, Eq, Hash)]
 #[error("{kind:?}: {detail}")]
 #[non_exhaustive]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 pub struct ServerError {
     #[cfg_attr(
         feature = "serde1",
@@ -377,46 +692,135 @@ pub struct ServerError {
         feature = "serde1",
         serde(deserialize_with = "util::serde::deserialize_io_error_kind_from_u32")
     )]
+    #[cfg_attr(feature = "rkyv", with(util::rkyv::IoErrorKindAsU32))]
     /// The type of error that occurred to fail the request.
     pub kind: io::ErrorKind,
     /// A message describing more detail about the error that occurred.
     pub detail: String,
+    #[cfg_attr(
+        feature = "serde1",
+        serde(
+            default,
+            serialize_with = "util::serde::serialize_server_error_cause_as_u32",
+            deserialize_with = "util::serde::deserialize_server_error_cause_from_u32"
+        )
+    )]
+    #[cfg_attr(feature = "rkyv", with(util::rkyv::ServerErrorCauseAsU32))]
+    cause: ServerErrorCause,
 }
 
-impl<T> Request<T> {
-    /// Returns the deadline for this request.
-    pub fn deadline(&self) -> &SystemTime {
-        &self.context.deadline
+impl ServerError {
+    /// Returns a new server error with the given kind and detail message, whose
+    /// [cause](ServerError::cause) is [`Other`](ServerErrorCause::Other).
+    pub fn new(kind: io::ErrorKind, detail: impl Into<String>) -> Self {
+        Self::with_cause(kind, ServerErrorCause::Other, detail)
+    }
+
+    pub(crate) fn with_cause(
+        kind: io::ErrorKind,
+        cause: ServerErrorCause,
+        detail: impl Into<String>,
+    ) -> Self {
+        ServerError {
+            kind,
+            detail: detail.into(),
+            cause,
+        }
+    }
+
+    /// Returns an error for a request the server is too loaded to handle, e.g. because it was
+    /// throttled.
+    pub fn overloaded(detail: impl Into<String>) -> Self {
+        Self::with_cause(
+            io::ErrorKind::WouldBlock,
+            ServerErrorCause::Overloaded,
+            detail,
+        )
+    }
+
+    /// Returns an error for a request of a method the server doesn't implement.
+    pub fn unimplemented(detail: impl Into<String>) -> Self {
+        Self::with_cause(
+            io::ErrorKind::Unsupported,
+            ServerErrorCause::Unimplemented,
+            detail,
+        )
+    }
+
+    /// Returns an error for a request, or its response, that is over a size limit of the server.
+    pub fn payload_too_large(detail: impl Into<String>) -> Self {
+        Self::with_cause(
+            io::ErrorKind::OutOfMemory,
+            ServerErrorCause::PayloadTooLarge,
+            detail,
+        )
+    }
+
+    /// Returns an error for a request the server aborted because it's shutting down.
+    pub fn shutdown(detail: impl Into<String>) -> Self {
+        Self::with_cause(
+            io::ErrorKind::ConnectionAborted,
+            ServerErrorCause::Shutdown,
+            detail,
+        )
+    }
+
+    /// Returns an error for a request whose deadline expired before the server responded to it.
+    pub fn deadline_exceeded(detail: impl Into<String>) -> Self {
+        Self::with_cause(
+            io::ErrorKind::TimedOut,
+            ServerErrorCause::DeadlineExceeded,
+            detail,
+        )
+    }
+
+    /// Returns the cause of the error, as set by the constructor that returned it, e.g.
+    /// [`overloaded`](ServerError::overloaded). The cause is sent over the wire with the error.
+    pub fn cause(&self) -> ServerErrorCause {
+        self.cause
     }
 }
 
-pub(crate) trait PollContext<T> {
-    fn context<C>(self, context: C) -> Poll<Option<anyhow::Result<T>>>
-    where
-        C: Display + Send + Sync + 'static;
+/// The cause of a [`ServerError`], which tells a client whether, and where, to retry the request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ServerErrorCause {
+    /// The server is shedding load. The request may succeed after a backoff, or on another
+    /// server.
+    Overloaded,
+    /// The server doesn't implement the method. Retrying the request won't help.
+    Unimplemented,
+    /// The request or its response is over a size limit of the server. Retrying the request
+    /// won't help.
+    PayloadTooLarge,
+    /// The server is shutting down. The request may succeed on another server.
+    Shutdown,
+    /// The deadline of the request expired before the server was done handling it.
+    DeadlineExceeded,
+    /// Any other failure, e.g. an error of the handler of the request.
+    Other,
+}
 
-    fn with_context<C, F>(self, f: F) -> Poll<Option<anyhow::Result<T>>>
-    where
-        C: Display + Send + Sync + 'static,
-        F: FnOnce() -> C;
+impl Default for ServerErrorCause {
+    fn default() -> Self {
+        ServerErrorCause::Other
+    }
 }
 
-impl<T, E> PollContext<T> for Poll<Option<Result<T, E>>>
-where
-    E: Error + Send + Sync + 'static,
-{
-    fn context<C>(self, context: C) -> Poll<Option<anyhow::Result<T>>>
-    where
-        C: Display + Send + Sync + 'static,
-    {
-        self.map(|o| o.map(|r| r.context(context)))
+impl ServerErrorCause {
+    /// Returns true if the request may succeed if it's retried, i.e. if the server was
+    /// [overloaded](ServerErrorCause::Overloaded) or [shutting down](ServerErrorCause::Shutdown).
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ServerErrorCause::Overloaded | ServerErrorCause::Shutdown
+        )
     }
+}
 
-    fn with_context<C, F>(self, f: F) -> Poll<Option<anyhow::Result<T>>>
-    where
-        C: Display + Send + Sync + 'static,
-        F: FnOnce() -> C,
-    {
-        self.map(|o| o.map(|r| r.with_context(f)))
+impl<T> Request<T> {
+    /// Returns the deadline for this request.
+    pub fn deadline(&self) -> &SystemTime {
+        &self.context.deadline
     }
 }
@@ -0,0 +1,729 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides hooks for recording metrics of clients and servers, e.g. to export them to a
+//! monitoring system.
+//!
+//! A [`Recorder`] set in [`client::Config::metrics`](crate::client::Config::metrics) is invoked
+//! by the client's dispatch, and one set in
+//! [`server::Config::metrics`](crate::server::Config::metrics) by the server's channels. The
+//! `prometheus` feature enables a recorder that renders the metrics in the
+//! [Prometheus text format](https://prometheus.io/docs/instrumenting/exposition_formats/).
+//!
+//! The client's resilience layers, i.e. [failover](crate::client::Channel::with_failover)
+//! channels, the [adaptive concurrency limit](crate::client::Config::adaptive_concurrency), and
+//! the retry budget of [standard clients](crate::layers::standard_client), record why requests
+//! are retried or held back. An [`Events`] recorder also publishes these as a stream of
+//! [`Event`]s, so that operators can see why calls are failing fast as it happens.
+
+use futures::{channel::mpsc, prelude::*};
+use std::{
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+/// The side of a connection that records a metric.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Side {
+    /// The client, which sends requests.
+    Client,
+    /// The server, which handles requests.
+    Server,
+}
+
+impl Side {
+    /// Returns the name of the side, e.g. for a metric label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Side::Client => "client",
+            Side::Server => "server",
+        }
+    }
+}
+
+/// How a request completed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum Outcome {
+    /// The request was responded to successfully.
+    Success,
+    /// The request failed, e.g. because the server responded with an error or the deadline
+    /// expired.
+    Error,
+    /// The request was canceled before it was responded to.
+    Canceled,
+}
+
+impl Outcome {
+    /// Returns the name of the outcome, e.g. for a metric label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Error => "error",
+            Outcome::Canceled => "canceled",
+        }
+    }
+}
+
+/// Records the metrics of clients and servers. Methods are called on the tasks that drive the
+/// clients and channels, so they should be cheap and must not block; all of them do nothing by
+/// default.
+///
+/// Methods are named by the name of the method called, e.g. `World.hello`, or the empty string if
+/// the server can't tell.
+pub trait Recorder: fmt::Debug + Send + Sync + 'static {
+    /// Records that a request was started: on the client, when it was written to the transport;
+    /// on the server, when its handler was started.
+    fn request_started(&self, side: Side, method: &str) {
+        let _ = (side, method);
+    }
+
+    /// Records that a request completed with `outcome`, `elapsed` after it started: on the
+    /// client, when its response was received, since it was written; on the server, when its
+    /// response was queued to be written, since the channel received it. `elapsed` is the
+    /// request's latency, e.g. to be recorded in a histogram per method.
+    fn request_completed(&self, side: Side, method: &str, elapsed: Duration, outcome: Outcome) {
+        let _ = (side, method, elapsed, outcome);
+    }
+
+    /// Records the number of messages waiting in a queue of a client or channel: on the client,
+    /// requests waiting to be written to the transport; on the server, responses waiting to be
+    /// written to the transport.
+    fn queue_depth(&self, side: Side, depth: usize) {
+        let _ = (side, depth);
+    }
+
+    /// Records that a client request failed with an unavailable error, and is retried on a
+    /// [failover](crate::client::Channel::with_failover) channel.
+    fn failover(&self, method: &str) {
+        let _ = method;
+    }
+
+    /// Records that a client request failed with an unavailable error, and is retried within the
+    /// retry budget of a [standard client](crate::layers::standard_client).
+    fn retry(&self, method: &str) {
+        let _ = method;
+    }
+
+    /// Records that a client request failed with an unavailable error, and isn't retried because
+    /// the retry budget of a [standard client](crate::layers::standard_client) is exhausted.
+    fn retry_budget_exhausted(&self, method: &str) {
+        let _ = method;
+    }
+
+    /// Records that the [adaptive concurrency limit](crate::client::Config::adaptive_concurrency)
+    /// of a client changed to `limit` requests in flight at once.
+    fn concurrency_limit(&self, limit: usize) {
+        let _ = limit;
+    }
+}
+
+/// A change of state of a client's resilience layers, published by [`Events`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A request failed with an unavailable error, and is retried on a
+    /// [failover](crate::client::Channel::with_failover) channel.
+    Failover {
+        /// The method the request calls.
+        method: String,
+    },
+    /// A request failed with an unavailable error, and is retried within the retry budget.
+    Retry {
+        /// The method the request calls.
+        method: String,
+    },
+    /// A request failed with an unavailable error, and isn't retried because the retry budget is
+    /// exhausted.
+    RetryBudgetExhausted {
+        /// The method the request calls.
+        method: String,
+    },
+    /// The adaptive concurrency limit changed.
+    ConcurrencyLimit {
+        /// The number of requests that can be in flight at once.
+        limit: usize,
+    },
+}
+
+/// A [`Recorder`] that publishes the [`Event`]s of the client's resilience layers to its
+/// [subscribers](Self::subscribe), and passes all metrics on to another recorder, if set.
+///
+/// ```
+/// use futures::prelude::*;
+/// use std::sync::Arc;
+/// use tarpc::{client, metrics::Events};
+///
+/// # async fn example() {
+/// let events = Arc::new(Events::new());
+/// let mut config = client::Config::default();
+/// config.metrics = Some(events.clone());
+/// let mut subscription = events.subscribe();
+/// // Later, e.g. in a task logging why calls fail fast:
+/// while let Some(event) = subscription.next().await {
+///     println!("{event:?}");
+/// }
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct Events {
+    recorder: Option<Arc<dyn Recorder>>,
+    subscribers: Mutex<Vec<mpsc::Sender<Event>>>,
+}
+
+impl Events {
+    /// The number of events buffered for each subscriber; events published while a
+    /// subscriber's buffer is full are dropped for that subscriber.
+    pub const BUFFER: usize = 100;
+
+    /// Returns a recorder with no subscribers, that only publishes events.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a recorder that publishes events, and passes all metrics on to `recorder`.
+    pub fn with_recorder(recorder: Arc<dyn Recorder>) -> Self {
+        Self {
+            recorder: Some(recorder),
+            subscribers: Mutex::default(),
+        }
+    }
+
+    /// Returns a stream of the events published from now on.
+    pub fn subscribe(&self) -> impl Stream<Item = Event> + Send + Unpin + 'static {
+        let (tx, rx) = mpsc::channel(Self::BUFFER);
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(tx);
+        rx
+    }
+
+    fn publish(&self, event: Event) {
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Subscribers that dropped their stream are forgotten.
+        subscribers.retain(|subscriber| !subscriber.is_closed());
+        for subscriber in subscribers.iter_mut() {
+            let _ = subscriber.try_send(event.clone());
+        }
+    }
+}
+
+impl Recorder for Events {
+    fn request_started(&self, side: Side, method: &str) {
+        if let Some(recorder) = &self.recorder {
+            recorder.request_started(side, method);
+        }
+    }
+
+    fn request_completed(&self, side: Side, method: &str, elapsed: Duration, outcome: Outcome) {
+        if let Some(recorder) = &self.recorder {
+            recorder.request_completed(side, method, elapsed, outcome);
+        }
+    }
+
+    fn queue_depth(&self, side: Side, depth: usize) {
+        if let Some(recorder) = &self.recorder {
+            recorder.queue_depth(side, depth);
+        }
+    }
+
+    fn failover(&self, method: &str) {
+        if let Some(recorder) = &self.recorder {
+            recorder.failover(method);
+        }
+        self.publish(Event::Failover {
+            method: method.to_string(),
+        });
+    }
+
+    fn retry(&self, method: &str) {
+        if let Some(recorder) = &self.recorder {
+            recorder.retry(method);
+        }
+        self.publish(Event::Retry {
+            method: method.to_string(),
+        });
+    }
+
+    fn retry_budget_exhausted(&self, method: &str) {
+        if let Some(recorder) = &self.recorder {
+            recorder.retry_budget_exhausted(method);
+        }
+        self.publish(Event::RetryBudgetExhausted {
+            method: method.to_string(),
+        });
+    }
+
+    fn concurrency_limit(&self, limit: usize) {
+        if let Some(recorder) = &self.recorder {
+            recorder.concurrency_limit(limit);
+        }
+        self.publish(Event::ConcurrencyLimit { limit });
+    }
+}
+
+/// A recorder that logs the metrics recorded, for tests.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct EventRecorder(std::sync::Mutex<Vec<String>>);
+
+#[cfg(test)]
+impl EventRecorder {
+    /// Returns the metrics recorded so far, in order.
+    pub(crate) fn events(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn push(&self, event: String) {
+        self.0.lock().unwrap().push(event);
+    }
+}
+
+#[cfg(test)]
+impl Recorder for EventRecorder {
+    fn request_started(&self, side: Side, method: &str) {
+        self.push(format!("{} started {}", side.as_str(), method));
+    }
+
+    fn request_completed(&self, side: Side, method: &str, _: Duration, outcome: Outcome) {
+        self.push(format!(
+            "{} completed {} {}",
+            side.as_str(),
+            method,
+            outcome.as_str()
+        ));
+    }
+
+    fn queue_depth(&self, side: Side, depth: usize) {
+        self.push(format!("{} queue {}", side.as_str(), depth));
+    }
+
+    fn failover(&self, method: &str) {
+        self.push(format!("client failover {}", method));
+    }
+
+    fn retry(&self, method: &str) {
+        self.push(format!("client retry {}", method));
+    }
+
+    fn retry_budget_exhausted(&self, method: &str) {
+        self.push(format!("client retry budget exhausted {}", method));
+    }
+
+    fn concurrency_limit(&self, limit: usize) {
+        self.push(format!("client concurrency limit {}", limit));
+    }
+}
+
+/// Provides a [`Recorder`] that renders metrics in the Prometheus text format.
+#[cfg(feature = "prometheus")]
+#[cfg_attr(docsrs, doc(cfg(feature = "prometheus")))]
+pub mod prometheus {
+    use super::{Outcome, Recorder, Side};
+    use std::{
+        collections::BTreeMap,
+        fmt::Write,
+        sync::{Mutex, PoisonError},
+        time::Duration,
+    };
+
+    /// A [`Recorder`] that keeps counters of requests, by side and method, and renders them in
+    /// the Prometheus text format, e.g. to be served on a `/metrics` endpoint:
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use tarpc::{metrics::prometheus::PrometheusRecorder, server};
+    ///
+    /// let recorder = Arc::new(PrometheusRecorder::new());
+    /// let config = server::Config::default().with_metrics(recorder.clone());
+    /// // Later, on a scrape:
+    /// let metrics: String = recorder.render();
+    /// ```
+    ///
+    /// The following metrics are rendered:
+    /// - `tarpc_requests_started_total{side, method}`: a counter of requests started.
+    /// - `tarpc_requests_completed_total{side, method, outcome}`: a counter of requests
+    ///   completed.
+    /// - `tarpc_request_duration_seconds{side, method}`: a histogram of the latencies of
+    ///   completed requests, with the [buckets](Self::with_buckets) of the recorder.
+    /// - `tarpc_queue_depth{side}`: a gauge of the queue depth most recently recorded.
+    /// - `tarpc_client_failovers_total{method}`: a counter of client requests retried on a
+    ///   failover channel.
+    /// - `tarpc_client_retries_total{method}`: a counter of client requests retried within the
+    ///   retry budget.
+    /// - `tarpc_client_retry_budget_exhausted_total{method}`: a counter of client requests not
+    ///   retried because the retry budget was exhausted.
+    /// - `tarpc_client_concurrency_limit`: a gauge of the adaptive concurrency limit most
+    ///   recently recorded.
+    #[derive(Debug)]
+    pub struct PrometheusRecorder {
+        buckets: Vec<f64>,
+        state: Mutex<State>,
+    }
+
+    impl Default for PrometheusRecorder {
+        fn default() -> Self {
+            Self {
+                buckets: Self::DEFAULT_BUCKETS.to_vec(),
+                state: Mutex::default(),
+            }
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct State {
+        methods: BTreeMap<Side, BTreeMap<String, Counters>>,
+        queue_depths: BTreeMap<Side, usize>,
+        failovers: BTreeMap<String, u64>,
+        retries: BTreeMap<String, u64>,
+        retry_budget_exhaustions: BTreeMap<String, u64>,
+        concurrency_limit: Option<usize>,
+    }
+
+    #[derive(Debug, Default)]
+    struct Counters {
+        started: u64,
+        completed: BTreeMap<Outcome, u64>,
+        /// The number of latencies at most each bucket's upper bound; not cumulative.
+        buckets: Vec<u64>,
+        duration: Duration,
+    }
+
+    impl State {
+        fn counters(&mut self, side: Side, method: &str) -> &mut Counters {
+            let methods = self.methods.entry(side).or_default();
+            // Avoids allocating the method name for each request, once it has been seen.
+            if !methods.contains_key(method) {
+                methods.insert(method.to_string(), Counters::default());
+            }
+            methods.get_mut(method).unwrap()
+        }
+
+        fn methods(&self) -> impl Iterator<Item = (Side, &str, &Counters)> {
+            self.methods.iter().flat_map(|(side, methods)| {
+                methods
+                    .iter()
+                    .map(move |(method, counters)| (*side, method.as_str(), counters))
+            })
+        }
+    }
+
+    impl PrometheusRecorder {
+        /// The upper bounds, in seconds, of the buckets latencies are counted in by default.
+        pub const DEFAULT_BUCKETS: &'static [f64] = &[
+            0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+        ];
+
+        /// Returns a recorder with no metrics recorded.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Sets the upper bounds, in seconds, of the buckets latencies are counted in. A bucket
+        /// for latencies over the largest bound is always added.
+        pub fn with_buckets(mut self, mut buckets: Vec<f64>) -> Self {
+            buckets.retain(|bound| bound.is_finite());
+            // Finite bounds are totally ordered.
+            buckets.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            buckets.dedup();
+            self.buckets = buckets;
+            self
+        }
+
+        /// Renders the metrics recorded so far in the Prometheus text format.
+        pub fn render(&self) -> String {
+            let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut out = String::new();
+            out.push_str("# HELP tarpc_requests_started_total Requests started.\n");
+            out.push_str("# TYPE tarpc_requests_started_total counter\n");
+            for (side, method, counters) in state.methods() {
+                let _ = writeln!(
+                    out,
+                    "tarpc_requests_started_total{{side=\"{}\",method=\"{}\"}} {}",
+                    side.as_str(),
+                    escape(method),
+                    counters.started
+                );
+            }
+            out.push_str("# HELP tarpc_requests_completed_total Requests completed.\n");
+            out.push_str("# TYPE tarpc_requests_completed_total counter\n");
+            for (side, method, counters) in state.methods() {
+                for (outcome, completed) in &counters.completed {
+                    let _ = writeln!(
+                        out,
+                        "tarpc_requests_completed_total{{side=\"{}\",method=\"{}\",outcome=\"{}\"}} {}",
+                        side.as_str(),
+                        escape(method),
+                        outcome.as_str(),
+                        completed
+                    );
+                }
+            }
+            out.push_str(
+                "# HELP tarpc_request_duration_seconds Latencies of completed requests.\n",
+            );
+            out.push_str("# TYPE tarpc_request_duration_seconds histogram\n");
+            for (side, method, counters) in state.methods() {
+                let labels = format!("side=\"{}\",method=\"{}\"", side.as_str(), escape(method));
+                let mut cumulative = 0;
+                for (i, bound) in self.buckets.iter().enumerate() {
+                    cumulative += counters.buckets.get(i).copied().unwrap_or_default();
+                    let _ = writeln!(
+                        out,
+                        "tarpc_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                        labels, bound, cumulative
+                    );
+                }
+                let count: u64 = counters.completed.values().sum();
+                let _ = writeln!(
+                    out,
+                    "tarpc_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                    labels, count
+                );
+                let _ = writeln!(
+                    out,
+                    "tarpc_request_duration_seconds_sum{{{}}} {}",
+                    labels,
+                    counters.duration.as_secs_f64()
+                );
+                let _ = writeln!(
+                    out,
+                    "tarpc_request_duration_seconds_count{{{}}} {}",
+                    labels, count
+                );
+            }
+            out.push_str("# HELP tarpc_queue_depth Messages waiting to be written.\n");
+            out.push_str("# TYPE tarpc_queue_depth gauge\n");
+            for (side, depth) in &state.queue_depths {
+                let _ = writeln!(
+                    out,
+                    "tarpc_queue_depth{{side=\"{}\"}} {}",
+                    side.as_str(),
+                    depth
+                );
+            }
+            out.push_str(
+                "# HELP tarpc_client_failovers_total Requests retried on a failover channel.\n",
+            );
+            out.push_str("# TYPE tarpc_client_failovers_total counter\n");
+            for (method, failovers) in &state.failovers {
+                let _ = writeln!(
+                    out,
+                    "tarpc_client_failovers_total{{method=\"{}\"}} {}",
+                    escape(method),
+                    failovers
+                );
+            }
+            out.push_str(
+                "# HELP tarpc_client_retries_total Requests retried within the retry budget.\n",
+            );
+            out.push_str("# TYPE tarpc_client_retries_total counter\n");
+            for (method, retries) in &state.retries {
+                let _ = writeln!(
+                    out,
+                    "tarpc_client_retries_total{{method=\"{}\"}} {}",
+                    escape(method),
+                    retries
+                );
+            }
+            out.push_str(
+                "# HELP tarpc_client_retry_budget_exhausted_total Requests not retried because \
+                 the retry budget was exhausted.\n",
+            );
+            out.push_str("# TYPE tarpc_client_retry_budget_exhausted_total counter\n");
+            for (method, exhaustions) in &state.retry_budget_exhaustions {
+                let _ = writeln!(
+                    out,
+                    "tarpc_client_retry_budget_exhausted_total{{method=\"{}\"}} {}",
+                    escape(method),
+                    exhaustions
+                );
+            }
+            out.push_str(
+                "# HELP tarpc_client_concurrency_limit Requests that can be in flight at once.\n",
+            );
+            out.push_str("# TYPE tarpc_client_concurrency_limit gauge\n");
+            if let Some(limit) = state.concurrency_limit {
+                let _ = writeln!(out, "tarpc_client_concurrency_limit {}", limit);
+            }
+            out
+        }
+
+        fn with_state<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+            f(&mut self.state.lock().unwrap_or_else(PoisonError::into_inner))
+        }
+    }
+
+    impl Recorder for PrometheusRecorder {
+        fn request_started(&self, side: Side, method: &str) {
+            self.with_state(|state| state.counters(side, method).started += 1);
+        }
+
+        fn request_completed(&self, side: Side, method: &str, elapsed: Duration, outcome: Outcome) {
+            let seconds = elapsed.as_secs_f64();
+            let bucket = self.buckets.iter().position(|bound| seconds <= *bound);
+            self.with_state(|state| {
+                let counters = state.counters(side, method);
+                *counters.completed.entry(outcome).or_default() += 1;
+                counters.duration += elapsed;
+                if let Some(bucket) = bucket {
+                    counters.buckets.resize(self.buckets.len(), 0);
+                    counters.buckets[bucket] += 1;
+                }
+            });
+        }
+
+        fn queue_depth(&self, side: Side, depth: usize) {
+            self.with_state(|state| {
+                state.queue_depths.insert(side, depth);
+            });
+        }
+
+        fn failover(&self, method: &str) {
+            self.with_state(|state| *state.failovers.entry(method.to_string()).or_default() += 1);
+        }
+
+        fn retry(&self, method: &str) {
+            self.with_state(|state| *state.retries.entry(method.to_string()).or_default() += 1);
+        }
+
+        fn retry_budget_exhausted(&self, method: &str) {
+            self.with_state(|state| {
+                *state
+                    .retry_budget_exhaustions
+                    .entry(method.to_string())
+                    .or_default() += 1
+            });
+        }
+
+        fn concurrency_limit(&self, limit: usize) {
+            self.with_state(|state| state.concurrency_limit = Some(limit));
+        }
+    }
+
+    /// Escapes a label value, as required by the text format.
+    fn escape(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn renders_recorded_metrics() {
+            let recorder = PrometheusRecorder::new().with_buckets(vec![0.5, 0.1]);
+            recorder.request_started(Side::Server, "World.hello");
+            recorder.request_started(Side::Server, "World.hello");
+            recorder.request_completed(
+                Side::Server,
+                "World.hello",
+                Duration::from_millis(250),
+                Outcome::Success,
+            );
+            recorder.request_completed(
+                Side::Server,
+                "World.hello",
+                Duration::from_secs(1),
+                Outcome::Error,
+            );
+            recorder.request_started(Side::Client, "say \"hi\"");
+            recorder.queue_depth(Side::Client, 3);
+            recorder.failover("World.hello");
+            recorder.retry("World.hello");
+            recorder.retry("World.hello");
+            recorder.retry_budget_exhausted("World.hello");
+            recorder.concurrency_limit(20);
+            recorder.concurrency_limit(18);
+
+            assert_eq!(
+                recorder.render(),
+                "\
+# HELP tarpc_requests_started_total Requests started.
+# TYPE tarpc_requests_started_total counter
+tarpc_requests_started_total{side=\"client\",method=\"say \\\"hi\\\"\"} 1
+tarpc_requests_started_total{side=\"server\",method=\"World.hello\"} 2
+# HELP tarpc_requests_completed_total Requests completed.
+# TYPE tarpc_requests_completed_total counter
+tarpc_requests_completed_total{side=\"server\",method=\"World.hello\",outcome=\"success\"} 1
+tarpc_requests_completed_total{side=\"server\",method=\"World.hello\",outcome=\"error\"} 1
+# HELP tarpc_request_duration_seconds Latencies of completed requests.
+# TYPE tarpc_request_duration_seconds histogram
+tarpc_request_duration_seconds_bucket{side=\"client\",method=\"say \\\"hi\\\"\",le=\"0.1\"} 0
+tarpc_request_duration_seconds_bucket{side=\"client\",method=\"say \\\"hi\\\"\",le=\"0.5\"} 0
+tarpc_request_duration_seconds_bucket{side=\"client\",method=\"say \\\"hi\\\"\",le=\"+Inf\"} 0
+tarpc_request_duration_seconds_sum{side=\"client\",method=\"say \\\"hi\\\"\"} 0
+tarpc_request_duration_seconds_count{side=\"client\",method=\"say \\\"hi\\\"\"} 0
+tarpc_request_duration_seconds_bucket{side=\"server\",method=\"World.hello\",le=\"0.1\"} 0
+tarpc_request_duration_seconds_bucket{side=\"server\",method=\"World.hello\",le=\"0.5\"} 1
+tarpc_request_duration_seconds_bucket{side=\"server\",method=\"World.hello\",le=\"+Inf\"} 2
+tarpc_request_duration_seconds_sum{side=\"server\",method=\"World.hello\"} 1.25
+tarpc_request_duration_seconds_count{side=\"server\",method=\"World.hello\"} 2
+# HELP tarpc_queue_depth Messages waiting to be written.
+# TYPE tarpc_queue_depth gauge
+tarpc_queue_depth{side=\"client\"} 3
+# HELP tarpc_client_failovers_total Requests retried on a failover channel.
+# TYPE tarpc_client_failovers_total counter
+tarpc_client_failovers_total{method=\"World.hello\"} 1
+# HELP tarpc_client_retries_total Requests retried within the retry budget.
+# TYPE tarpc_client_retries_total counter
+tarpc_client_retries_total{method=\"World.hello\"} 2
+# HELP tarpc_client_retry_budget_exhausted_total Requests not retried because the retry budget was exhausted.
+# TYPE tarpc_client_retry_budget_exhausted_total counter
+tarpc_client_retry_budget_exhausted_total{method=\"World.hello\"} 1
+# HELP tarpc_client_concurrency_limit Requests that can be in flight at once.
+# TYPE tarpc_client_concurrency_limit gauge
+tarpc_client_concurrency_limit 18
+"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn events_are_published_to_subscribers() {
+        let recorder = Arc::new(EventRecorder::default());
+        let events = Events::with_recorder(recorder.clone());
+        let subscription = events.subscribe();
+        drop(events.subscribe());
+
+        events.request_started(Side::Client, "World.hello");
+        events.retry("World.hello");
+        events.concurrency_limit(9);
+        drop(events);
+
+        assert_eq!(
+            block_on(subscription.collect::<Vec<_>>()),
+            [
+                Event::Retry {
+                    method: "World.hello".into()
+                },
+                Event::ConcurrencyLimit { limit: 9 },
+            ]
+        );
+        assert_eq!(
+            recorder.events(),
+            [
+                "client started World.hello",
+                "client retry World.hello",
+                "client concurrency limit 9",
+            ]
+        );
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides pagination of rpcs whose responses are too large to send at once.
+//!
+//! A paged rpc is marked `#[tarpc(paged)]`, takes an `Option<PageToken>` as its last arg, and
+//! returns a [`Page`]:
+//!
+//! ```
+//! use tarpc::paging::{Page, PageToken};
+//!
+//! #[tarpc::service]
+//! pub trait Inventory {
+//!     #[tarpc(paged)]
+//!     async fn items(category: String, page: Option<PageToken>) -> Page<String>;
+//! }
+//! ```
+//!
+//! The server computes the full response on the first call, and returns it a page at a time
+//! using a [`Pager`], which keeps the rest of the response until the client asks for it with the
+//! token of the next page. Besides the rpc method, the generated client has a method suffixed
+//! with `_pages`, e.g. `items_pages`, which returns a stream of the items of every page, fetching
+//! each page once the items of the previous page are consumed.
+//!
+//! Server-streaming rpcs stream large responses without holding them on the server; pagination
+//! suits transports and clients that only support unary rpcs.
+
+use crate::{client::RpcError, ServerError};
+use fnv::FnvHashMap;
+use futures::{prelude::*, stream};
+use std::{
+    fmt, io,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+    vec,
+};
+use tokio::time::Instant;
+
+/// Identifies the rest of a paged response held by a [`Pager`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct PageToken(u64);
+
+/// A page of a paged response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Page<T> {
+    items: Vec<T>,
+    continuation: Continuation,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+enum Continuation {
+    /// The page is the last page.
+    End,
+    /// The response continues on the page with the token.
+    Next(PageToken),
+    /// The page was requested with a token whose page is no longer held by the server.
+    Expired,
+}
+
+impl<T> Page<T> {
+    /// Returns a page holding the whole response.
+    pub fn last(items: Vec<T>) -> Self {
+        Self {
+            items,
+            continuation: Continuation::End,
+        }
+    }
+
+    /// Returns the items of the page.
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// Returns the items of the page.
+    pub fn into_items(self) -> Vec<T> {
+        self.items
+    }
+
+    /// Returns the token of the next page, unless this is the last page.
+    pub fn next_token(&self) -> Option<PageToken> {
+        match self.continuation {
+            Continuation::Next(token) => Some(token),
+            Continuation::End | Continuation::Expired => None,
+        }
+    }
+
+    /// Returns true if the page was requested with a token whose page expired or was evicted.
+    /// The page holds no items; the response must be requested again from the first page.
+    pub fn is_expired(&self) -> bool {
+        self.continuation == Continuation::Expired
+    }
+}
+
+/// Splits responses into pages, holding the rest of each response until its next page is
+/// requested. Cheap to clone; clones share the held responses, so a client can request the next
+/// page on any channel served with a clone.
+pub struct Pager<T> {
+    page_size: usize,
+    ttl: Duration,
+    max_responses: usize,
+    held: Arc<Mutex<FnvHashMap<PageToken, Held<T>>>>,
+}
+
+/// The rest of a paged response.
+struct Held<T> {
+    expires: Instant,
+    items: vec::IntoIter<T>,
+}
+
+impl<T> Clone for Pager<T> {
+    fn clone(&self) -> Self {
+        Self {
+            page_size: self.page_size,
+            ttl: self.ttl,
+            max_responses: self.max_responses,
+            held: self.held.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Pager<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pager")
+            .field("page_size", &self.page_size)
+            .field("ttl", &self.ttl)
+            .field("max_responses", &self.max_responses)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Pager<T> {
+    /// Returns a pager that returns up to `page_size` items per page.
+    ///
+    /// By default, the rest of a response is held for a minute after its last page was
+    /// returned, and up to 1024 responses are held at once.
+    ///
+    /// # Panics
+    ///
+    /// If `page_size` is zero.
+    pub fn new(page_size: usize) -> Self {
+        assert!(page_size > 0, "page_size must be positive");
+        Self {
+            page_size,
+            ttl: Duration::from_secs(60),
+            max_responses: 1024,
+            held: Arc::default(),
+        }
+    }
+
+    /// Sets how long the rest of a response is held after its last page was returned.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Sets how many responses are held at once. Once the limit is reached, the response that
+    /// expires soonest is evicted to hold another.
+    pub fn max_responses(mut self, max_responses: usize) -> Self {
+        self.max_responses = max_responses;
+        self
+    }
+
+    /// Returns the page of a response identified by `token`: the first page of the response
+    /// returned by `response` if `token` is None, or else the next page of the response held
+    /// under `token`.
+    pub fn page(&self, token: Option<PageToken>, response: impl FnOnce() -> Vec<T>) -> Page<T> {
+        let mut held = self.held.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        held.retain(|_, response| response.expires > now);
+        let mut items = match token {
+            None => {
+                drop(held);
+                let items = response().into_iter();
+                held = self.held.lock().unwrap_or_else(PoisonError::into_inner);
+                items
+            }
+            Some(token) => match held.remove(&token) {
+                Some(response) => response.items,
+                None => {
+                    tracing::info!(?token, "PageExpired");
+                    return Page {
+                        items: vec![],
+                        continuation: Continuation::Expired,
+                    };
+                }
+            },
+        };
+        let page = items.by_ref().take(self.page_size).collect();
+        if items.as_slice().is_empty() {
+            return Page::last(page);
+        }
+        if held.len() >= self.max_responses {
+            let soonest = held
+                .iter()
+                .min_by_key(|(_, response)| response.expires)
+                .map(|(&token, _)| token);
+            if let Some(token) = soonest {
+                tracing::info!(?token, "EvictPage");
+                held.remove(&token);
+            }
+        }
+        let mut token = PageToken(rand::random());
+        while held.contains_key(&token) {
+            token = PageToken(rand::random());
+        }
+        held.insert(
+            token,
+            Held {
+                expires: now + self.ttl,
+                items,
+            },
+        );
+        Page {
+            items: page,
+            continuation: Continuation::Next(token),
+        }
+    }
+}
+
+/// Returns a stream of the items of every page of a paged response, fetching each page with
+/// `fetch` once the items of the previous page are consumed. The first page is fetched with no
+/// token. Used by the `_pages` methods of generated clients.
+pub fn pages<'a, T, F, Fut>(fetch: F) -> impl Stream<Item = Result<T, RpcError>> + 'a
+where
+    T: 'a,
+    F: FnMut(Option<PageToken>) -> Fut + 'a,
+    Fut: Future<Output = Result<Page<T>, RpcError>> + 'a,
+{
+    stream::unfold((fetch, Some(None)), |(mut fetch, token)| async move {
+        let token = token?;
+        let (items, next) = match fetch(token).await {
+            Ok(Page {
+                continuation: Continuation::Expired,
+                ..
+            }) => (
+                vec![Err(RpcError::Server(ServerError::new(
+                    io::ErrorKind::NotFound,
+                    "the next page of the response expired",
+                )))],
+                None,
+            ),
+            Ok(page) => {
+                let next = page.next_token().map(Some);
+                (page.items.into_iter().map(Ok).collect(), next)
+            }
+            Err(e) => (vec![Err(e)], None),
+        };
+        Some((stream::iter(items), (fetch, next)))
+    })
+    .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn pager_pages_response() {
+        let pager = Pager::new(2);
+        let page = pager.page(None, || vec![1, 2, 3, 4, 5]);
+        assert_eq!(page.items(), [1, 2]);
+        let token = page.next_token().unwrap();
+
+        let page = pager.page(Some(token), || unreachable!());
+        assert_eq!(page.items(), [3, 4]);
+        let page = pager.page(page.next_token(), || unreachable!());
+        assert_eq!(page.items(), [5]);
+        assert_eq!(page.next_token(), None);
+
+        // Each token is used once.
+        assert!(pager.page(Some(token), || unreachable!()).is_expired());
+        assert!(pager.held.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn pager_returns_small_response_whole() {
+        let pager = Pager::new(2);
+        assert_eq!(pager.page(None, || vec![1, 2]), Page::last(vec![1, 2]));
+        assert!(pager.held.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn pager_expires_responses() {
+        tokio::time::pause();
+        let pager = Pager::new(1).ttl(Duration::from_secs(10));
+        let token = pager.page(None, || vec![1, 2]).next_token();
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert!(pager.page(token, || unreachable!()).is_expired());
+    }
+
+    #[test]
+    fn pager_evicts_responses_over_limit() {
+        let pager = Pager::new(1).max_responses(1);
+        let first = pager.page(None, || vec![1, 2]).next_token();
+        let second = pager.page(None, || vec![3, 4]).next_token();
+        assert!(pager.page(first, || unreachable!()).is_expired());
+        assert_eq!(pager.page(second, || unreachable!()).items(), [4]);
+    }
+
+    #[tokio::test]
+    async fn pages_streams_items_of_every_page() {
+        let pager = Pager::new(2);
+        let items = pages(|token| {
+            let page = pager.page(token, || vec![1, 2, 3, 4, 5]);
+            async move { Ok(page) }
+        });
+        assert_eq!(
+            items.map(Result::unwrap).collect::<Vec<_>>().await,
+            [1, 2, 3, 4, 5]
+        );
+    }
+
+    #[tokio::test]
+    async fn pages_fails_on_expired_page() {
+        let pager = Pager::new(1);
+        let mut items = Box::pin(pages(|token: Option<PageToken>| {
+            // Requests the next page with a token the pager doesn't hold.
+            let token = token.map(|PageToken(token)| PageToken(token.wrapping_add(1)));
+            let page = pager.page(token, || vec![1, 2]);
+            async move { Ok(page) }
+        }));
+        assert_matches!(items.next().await, Some(Ok(1)));
+        assert_matches!(
+            items.next().await,
+            Some(Err(RpcError::Server(e))) if e.kind == io::ErrorKind::NotFound
+        );
+        assert_matches!(items.next().await, None);
+    }
+}
@@ -0,0 +1,393 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides publish/subscribe of events over server-streaming rpcs.
+//!
+//! Clients subscribe to a topic by calling a server-streaming rpc that returns the
+//! [`Subscription`] of a [`Broker`]:
+//!
+//! ```
+//! use futures::prelude::*;
+//! use tarpc::{context, pubsub::{Broker, Subscription}};
+//!
+//! #[tarpc::service]
+//! pub trait Prices {
+//!     async fn subscribe(symbol: String) -> impl Stream<Item = u64>;
+//! }
+//!
+//! #[derive(Clone)]
+//! struct PricesServer(Broker<u64>);
+//!
+//! impl Prices for PricesServer {
+//!     type SubscribeStream = Subscription<u64>;
+//!
+//!     fn subscribe(self, _: context::Context, symbol: String) -> Subscription<u64> {
+//!         self.0.subscribe(symbol)
+//!     }
+//! }
+//!
+//! let broker = Broker::new(16);
+//! let _server = PricesServer(broker.clone());
+//! // Elsewhere on the server:
+//! broker.publish("GOOG", 100);
+//! ```
+//!
+//! The broker holds the subscribers of each topic, and queues each published event for every
+//! subscriber of the event's topic. A subscriber whose client falls behind doesn't hold up the
+//! others: once its queue is full, further events are handled according to the broker's
+//! [`Overflow`] policy. A subscriber is removed from its topic when its subscription is dropped,
+//! e.g. when the client cancels the rpc.
+
+use fnv::FnvHashMap;
+use futures::{prelude::*, task::*};
+use std::{
+    collections::VecDeque,
+    fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+};
+
+/// What a [`Broker`] does with an event for a subscriber whose queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Overflow {
+    /// Drops the oldest queued event to make room for the new event, so slow subscribers see the
+    /// latest events.
+    DropOldest,
+    /// Drops the new event, so slow subscribers see a prefix of the events.
+    DropNewest,
+    /// Ends the subscription, so that the client can resubscribe and catch up by other means.
+    Disconnect,
+}
+
+/// Holds the subscribers of each topic, and delivers the events published to a topic to its
+/// subscribers. Cheap to clone; clones share their subscribers.
+pub struct Broker<T> {
+    buffer: usize,
+    overflow: Overflow,
+    topics: Arc<Mutex<Topics<T>>>,
+}
+
+struct Topics<T> {
+    next_id: u64,
+    subscribers: FnvHashMap<String, FnvHashMap<u64, Arc<Subscriber<T>>>>,
+}
+
+/// The state a subscription shares with its broker.
+struct Subscriber<T> {
+    queue: Mutex<VecDeque<T>>,
+    waker: AtomicWaker,
+    closed: AtomicBool,
+    dropped: AtomicU64,
+}
+
+impl<T> Subscriber<T> {
+    fn queue(&self) -> MutexGuard<'_, VecDeque<T>> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+}
+
+impl<T> Clone for Broker<T> {
+    fn clone(&self) -> Self {
+        Self {
+            buffer: self.buffer,
+            overflow: self.overflow,
+            topics: self.topics.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Broker<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Broker")
+            .field("buffer", &self.buffer)
+            .field("overflow", &self.overflow)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Broker<T> {
+    /// Returns a broker that queues up to `buffer` events per subscriber. By default, the oldest
+    /// queued event is [dropped](Overflow::DropOldest) when a subscriber's queue is full.
+    ///
+    /// # Panics
+    ///
+    /// If `buffer` is zero.
+    pub fn new(buffer: usize) -> Self {
+        assert!(buffer > 0, "buffer must be positive");
+        Self {
+            buffer,
+            overflow: Overflow::DropOldest,
+            topics: Arc::new(Mutex::new(Topics {
+                next_id: 0,
+                subscribers: FnvHashMap::default(),
+            })),
+        }
+    }
+
+    /// Sets what is done with an event for a subscriber whose queue is full.
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Returns a new subscription to `topic`, which yields the events published to the topic
+    /// from now on.
+    pub fn subscribe(&self, topic: impl Into<String>) -> Subscription<T> {
+        let topic = topic.into();
+        let subscriber = Arc::new(Subscriber {
+            queue: Mutex::new(VecDeque::new()),
+            waker: AtomicWaker::new(),
+            closed: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+        });
+        let mut topics = self.lock();
+        let id = topics.next_id;
+        topics.next_id += 1;
+        topics
+            .subscribers
+            .entry(topic.clone())
+            .or_default()
+            .insert(id, subscriber.clone());
+        tracing::info!(%topic, subscription_id = id, "Subscribe");
+        Subscription {
+            broker: self.clone(),
+            topic,
+            id,
+            subscriber,
+        }
+    }
+
+    /// Returns the number of subscribers of `topic`.
+    pub fn subscribers(&self, topic: &str) -> usize {
+        self.lock()
+            .subscribers
+            .get(topic)
+            .map_or(0, |subscribers| subscribers.len())
+    }
+
+    /// Returns the topics with at least one subscriber.
+    pub fn topics(&self) -> Vec<String> {
+        self.lock().subscribers.keys().cloned().collect()
+    }
+
+    /// Ends the subscriptions to `topic` once they have yielded their queued events, and returns
+    /// how many there were.
+    pub fn close(&self, topic: &str) -> usize {
+        let subscribers = self.lock().subscribers.remove(topic).unwrap_or_default();
+        for subscriber in subscribers.values() {
+            subscriber.close();
+        }
+        subscribers.len()
+    }
+
+    fn unsubscribe(&self, topic: &str, id: u64) {
+        let mut topics = self.lock();
+        if let Some(subscribers) = topics.subscribers.get_mut(topic) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                topics.subscribers.remove(topic);
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Topics<T>> {
+        self.topics.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T: Clone> Broker<T> {
+    /// Queues `event` for every subscriber of `topic`, and returns how many subscribers queued
+    /// it. Subscribers whose queues are full are handled according to the broker's [`Overflow`]
+    /// policy.
+    pub fn publish(&self, topic: &str, event: T) -> usize {
+        let mut topics = self.lock();
+        let subscribers = match topics.subscribers.get_mut(topic) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+        let mut delivered = 0;
+        subscribers.retain(|&id, subscriber| {
+            let mut queue = subscriber.queue();
+            if queue.len() >= self.buffer {
+                subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+                match self.overflow {
+                    Overflow::DropOldest => {
+                        queue.pop_front();
+                    }
+                    Overflow::DropNewest => {
+                        tracing::debug!(%topic, subscription_id = id, "DropEvent");
+                        return true;
+                    }
+                    Overflow::Disconnect => {
+                        tracing::info!(%topic, subscription_id = id, "DisconnectSubscriber");
+                        drop(queue);
+                        subscriber.close();
+                        return false;
+                    }
+                }
+            }
+            queue.push_back(event.clone());
+            drop(queue);
+            subscriber.waker.wake();
+            delivered += 1;
+            true
+        });
+        if subscribers.is_empty() {
+            topics.subscribers.remove(topic);
+        }
+        delivered
+    }
+}
+
+/// A stream of the events published to a topic of a [`Broker`]. Returned by
+/// [`Broker::subscribe`]; unsubscribes from the topic when dropped.
+pub struct Subscription<T> {
+    broker: Broker<T>,
+    topic: String,
+    id: u64,
+    subscriber: Arc<Subscriber<T>>,
+}
+
+impl<T> Subscription<T> {
+    /// Returns the topic subscribed to.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Returns the number of events that didn't fit in the subscription's queue, and so were
+    /// either dropped or ended the subscription.
+    pub fn dropped(&self) -> u64 {
+        self.subscriber.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> fmt::Debug for Subscription<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Subscription")
+            .field("topic", &self.topic)
+            .field("id", &self.id)
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+impl<T> Stream for Subscription<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let subscriber = &self.subscriber;
+        if let Some(event) = subscriber.queue().pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        subscriber.waker.register(cx.waker());
+        // An event may have been queued, or the subscription closed, before the waker was
+        // registered.
+        if let Some(event) = subscriber.queue().pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        if subscriber.closed.load(Ordering::Acquire) {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        tracing::info!(topic = %self.topic, subscription_id = self.id, "Unsubscribe");
+        self.broker.unsubscribe(&self.topic, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use futures_test::task::noop_context;
+
+    fn poll<T>(subscription: &mut Subscription<T>) -> Poll<Option<T>> {
+        Pin::new(subscription).poll_next(&mut noop_context())
+    }
+
+    #[test]
+    fn publish_delivers_to_subscribers_of_topic() {
+        let broker = Broker::new(4);
+        let mut a1 = broker.subscribe("a");
+        let mut a2 = broker.subscribe("a");
+        let mut b = broker.subscribe("b");
+        assert_eq!(broker.subscribers("a"), 2);
+
+        assert_eq!(broker.publish("a", 1), 2);
+        assert_eq!(broker.publish("c", 2), 0);
+        assert_matches!(poll(&mut a1), Poll::Ready(Some(1)));
+        assert_matches!(poll(&mut a2), Poll::Ready(Some(1)));
+        assert_matches!(poll(&mut a1), Poll::Pending);
+        assert_matches!(poll(&mut b), Poll::Pending);
+
+        drop(a1);
+        assert_eq!(broker.subscribers("a"), 1);
+        drop((a2, b));
+        assert!(broker.topics().is_empty());
+    }
+
+    #[test]
+    fn full_queues_drop_oldest_by_default() {
+        let broker = Broker::new(2);
+        let mut sub = broker.subscribe("a");
+        for event in 0..4 {
+            broker.publish("a", event);
+        }
+        assert_eq!(sub.dropped(), 2);
+        assert_matches!(poll(&mut sub), Poll::Ready(Some(2)));
+        assert_matches!(poll(&mut sub), Poll::Ready(Some(3)));
+    }
+
+    #[test]
+    fn full_queues_drop_newest() {
+        let broker = Broker::new(2).overflow(Overflow::DropNewest);
+        let mut sub = broker.subscribe("a");
+        for event in 0..4 {
+            broker.publish("a", event);
+        }
+        assert_eq!(sub.dropped(), 2);
+        assert_matches!(poll(&mut sub), Poll::Ready(Some(0)));
+        assert_matches!(poll(&mut sub), Poll::Ready(Some(1)));
+        assert_matches!(poll(&mut sub), Poll::Pending);
+    }
+
+    #[test]
+    fn full_queues_disconnect_slow_subscribers() {
+        let broker = Broker::new(1).overflow(Overflow::Disconnect);
+        let mut slow = broker.subscribe("a");
+        broker.publish("a", 0);
+        let mut fast = broker.subscribe("a");
+        assert_eq!(broker.publish("a", 1), 1);
+        assert_eq!(broker.subscribers("a"), 1);
+
+        assert_matches!(poll(&mut slow), Poll::Ready(Some(0)));
+        assert_matches!(poll(&mut slow), Poll::Ready(None));
+        assert_matches!(poll(&mut fast), Poll::Ready(Some(1)));
+    }
+
+    #[test]
+    fn close_ends_subscriptions_after_queued_events() {
+        let broker = Broker::new(4);
+        let mut sub = broker.subscribe("a");
+        broker.publish("a", 0);
+        assert_eq!(broker.close("a"), 1);
+        assert_eq!(broker.subscribers("a"), 0);
+        assert_matches!(poll(&mut sub), Poll::Ready(Some(0)));
+        assert_matches!(poll(&mut sub), Poll::Ready(None));
+    }
+}
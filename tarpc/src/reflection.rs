@@ -0,0 +1,222 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides descriptions of services, and a reflection service that serves them, so that generic
+//! tooling can list the rpcs of a live server.
+//!
+//! With the `reflection` feature, [`#[tarpc::service]`](crate::service) implements [`Reflect`]
+//! for the request type of each service, e.g. `WorldRequest`. A server lists the services it
+//! serves in a [`ReflectionRegistry`], and serves the registry as a [`Reflection`] service, e.g.
+//! [mounted](crate::router::Router::mount) alongside the services:
+//!
+//! ```
+//! use tarpc::reflection::{Reflect, ReflectionRegistry};
+//!
+//! #[tarpc::service]
+//! pub trait World {
+//!     async fn hello(name: String) -> String;
+//! }
+//!
+//! let registry = ReflectionRegistry::new().service::<WorldRequest>();
+//! assert_eq!(registry.services()[0], WorldRequest::descriptor());
+//!
+//! let hello = &WorldRequest::descriptor().methods[0];
+//! assert_eq!(hello.name, "World.hello");
+//! assert_eq!(hello.args[0].ty, "String");
+//! assert_eq!(hello.response, "String");
+//! ```
+//!
+//! Types are named as written in the service definition, so they may not be fully qualified.
+
+use crate::context;
+use futures::future::{self, Ready};
+use std::{borrow::Cow, sync::Arc};
+
+/// Describes the rpcs of a service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct ServiceDescriptor {
+    /// The name of the service, e.g. `World`.
+    pub name: Cow<'static, str>,
+    /// The rpcs of the service, in the order they're defined.
+    pub methods: Vec<MethodDescriptor>,
+}
+
+/// Describes an rpc.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct MethodDescriptor {
+    /// The name of the rpc, named `{Service}.{rpc}`, e.g. `World.hello`.
+    pub name: Cow<'static, str>,
+    /// The args the client sends in the request. Args injected by the server aren't listed, nor is
+    /// the stream arg of a client-streaming rpc.
+    pub args: Vec<ArgDescriptor>,
+    /// The type of the items sent by the client after the request, if the rpc is
+    /// client-streaming.
+    pub request_item: Option<Cow<'static, str>>,
+    /// The type of the response, or of the items of the response stream if the rpc is
+    /// server-streaming.
+    pub response: Cow<'static, str>,
+    /// Whether the response is a stream.
+    pub server_streaming: bool,
+}
+
+/// Describes an arg of an rpc.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct ArgDescriptor {
+    /// The name of the arg.
+    pub name: Cow<'static, str>,
+    /// The type of the arg.
+    pub ty: Cow<'static, str>,
+}
+
+/// Describes the service of a request type. Implemented by
+/// [`#[tarpc::service]`](crate::service) with the `reflection` feature.
+pub trait Reflect {
+    /// Returns the description of the service.
+    fn descriptor() -> ServiceDescriptor;
+}
+
+/// Lists the services of a server.
+#[tarpc_plugins::service]
+pub trait Reflection {
+    /// Returns the names of the services of the server.
+    async fn list_services() -> Vec<String>;
+
+    /// Returns the description of the service named `service`, if it's a service of the server.
+    async fn describe(service: String) -> Option<ServiceDescriptor>;
+}
+
+/// The services of a server, described for the [`Reflection`] service. Cheap to clone.
+///
+/// A registry is served as a [`Reflection`] service with [`serve`](Reflection::serve).
+#[derive(Clone, Debug, Default)]
+pub struct ReflectionRegistry {
+    services: Arc<Vec<ServiceDescriptor>>,
+}
+
+impl ReflectionRegistry {
+    /// Returns a registry with no services.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the service of `Req`, e.g. `WorldRequest`.
+    pub fn service<Req: Reflect>(self) -> Self {
+        self.descriptor(Req::descriptor())
+    }
+
+    /// Adds a service described by `descriptor`, replacing any service of the same name.
+    pub fn descriptor(mut self, descriptor: ServiceDescriptor) -> Self {
+        let services = Arc::make_mut(&mut self.services);
+        services.retain(|service| service.name != descriptor.name);
+        services.push(descriptor);
+        self
+    }
+
+    /// Returns the descriptions of the services, in the order they were added.
+    pub fn services(&self) -> &[ServiceDescriptor] {
+        &self.services
+    }
+}
+
+impl Reflection for ReflectionRegistry {
+    type ListServicesFut = Ready<Vec<String>>;
+
+    fn list_services(self, _: context::Context) -> Self::ListServicesFut {
+        future::ready(
+            self.services
+                .iter()
+                .map(|service| service.name.to_string())
+                .collect(),
+        )
+    }
+
+    type DescribeFut = Ready<Option<ServiceDescriptor>>;
+
+    fn describe(self, _: context::Context, service: String) -> Self::DescribeFut {
+        future::ready(
+            self.services
+                .iter()
+                .find(|descriptor| descriptor.name == service)
+                .cloned(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The requests are only described, never sent.
+    #[allow(dead_code)]
+    #[tarpc_plugins::service(derive_serde = false)]
+    trait Kitchen {
+        async fn cook(
+            #[inject] oven: Arc<()>,
+            dish: Option<&'static str>,
+            sides: Vec<(u32, String)>,
+        ) -> Result<String, String>;
+        async fn order(orders: impl Stream<Item = String>) -> impl Stream<Item = u64>;
+    }
+
+    #[test]
+    fn descriptor_describes_rpcs() {
+        let arg = |name: &'static str, ty: &'static str| ArgDescriptor {
+            name: name.into(),
+            ty: ty.into(),
+        };
+        assert_eq!(
+            KitchenRequest::descriptor(),
+            ServiceDescriptor {
+                name: "Kitchen".into(),
+                methods: vec![
+                    MethodDescriptor {
+                        name: "Kitchen.cook".into(),
+                        args: vec![
+                            arg("dish", "Option<&'static str>"),
+                            arg("sides", "Vec<(u32, String)>"),
+                        ],
+                        request_item: None,
+                        response: "Result<String, String>".into(),
+                        server_streaming: false,
+                    },
+                    MethodDescriptor {
+                        name: "Kitchen.order".into(),
+                        args: vec![],
+                        request_item: Some("String".into()),
+                        response: "u64".into(),
+                        server_streaming: true,
+                    },
+                ],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn registry_lists_and_describes_services() {
+        let registry = ReflectionRegistry::new()
+            .service::<KitchenRequest>()
+            .service::<ReflectionRequest>()
+            .service::<KitchenRequest>();
+        assert_eq!(
+            registry.clone().list_services(context::current()).await,
+            ["Reflection", "Kitchen"]
+        );
+        assert_eq!(
+            registry
+                .clone()
+                .describe(context::current(), "Kitchen".into())
+                .await,
+            Some(KitchenRequest::descriptor())
+        );
+        assert_eq!(
+            registry.describe(context::current(), "Bakery".into()).await,
+            None
+        );
+    }
+}
@@ -0,0 +1,617 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides serving multiple services over a single channel.
+//!
+//! A [`Router`] mounts the [`Serve`] fns of two or more [services](crate::service), and
+//! dispatches each request to the service it's for. The requests and responses of a router are
+//! [`Mounted`], and are sent over the wire in an [`Envelope`] that carries the name of their
+//! service along with the message. A client of one of the services talks to a router over a
+//! transport adapted with [`routed`]:
+//!
+//! ```
+//! # use futures::future::{ready, Ready};
+//! use tarpc::{
+//!     client, context,
+//!     router::{self, Router},
+//!     server::{BaseChannel, Channel},
+//! };
+//!
+//! #[tarpc::service]
+//! trait Hello {
+//!     async fn hello(name: String) -> String;
+//! }
+//!
+//! #[tarpc::service]
+//! trait Add {
+//!     async fn add(x: i32, y: i32) -> i32;
+//! }
+//! # #[derive(Clone)]
+//! # struct HelloServer;
+//! # impl Hello for HelloServer {
+//! #     type HelloFut = Ready<String>;
+//! #     fn hello(self, _: context::Context, name: String) -> Self::HelloFut {
+//! #         ready(format!("Hello, {name}!"))
+//! #     }
+//! # }
+//! # #[derive(Clone)]
+//! # struct AddServer;
+//! # impl Add for AddServer {
+//! #     type AddFut = Ready<i32>;
+//! #     fn add(self, _: context::Context, x: i32, y: i32) -> Self::AddFut {
+//! #         ready(x + y)
+//! #     }
+//! # }
+//!
+//! # #[cfg(not(feature = "serde-transport-json"))]
+//! # fn main() {}
+//! # #[cfg(feature = "serde-transport-json")]
+//! # #[tokio::main]
+//! # async fn main() -> anyhow::Result<()> {
+//! use tarpc::serde_transport;
+//! use tokio_serde::formats::Json;
+//!
+//! let (client_io, server_io) = tokio::io::duplex(4096);
+//!
+//! let router = Router::new(HelloServer.serve()).mount(AddServer.serve());
+//! let server_transport = serde_transport::Transport::from((server_io, Json::default()));
+//! tokio::spawn(BaseChannel::with_defaults(server_transport).execute(router));
+//!
+//! let client_transport = serde_transport::Transport::from((client_io, Json::default()));
+//! let client = AddClient::new(client::Config::default(), router::routed(client_transport)).spawn();
+//! assert_eq!(client.add(context::current(), 1, 2).await?, 3);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests for a service that isn't mounted fail to deserialize, like any other malformed
+//! request. Client-streaming rpcs can't be served by a router: the items a client sends are
+//! dropped.
+//!
+//! A service can be mounted with its own [`Config`], overriding the channel's for the service's
+//! requests; see [`Router::mount_with_config`].
+//!
+//! A router can serve many tenants, each limited to its own namespace of services; see
+//! [`tenants`].
+
+use crate::{
+    server::{Config, RequestStream, Serve, Served},
+    ClientMessage, Request, Response, ServerMessage,
+};
+use futures::{
+    future::{self, Either},
+    prelude::*,
+};
+use serde::{
+    de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor},
+    ser::{SerializeStruct, Serializer},
+    Deserialize, Serialize,
+};
+use std::{borrow::Cow, fmt, marker::PhantomData};
+
+pub mod tenants;
+
+/// Names the service of a request or response type.
+///
+/// Implemented by the `#[tarpc::service]` attribute for the generated request and response enums.
+pub trait Named {
+    /// The name of the service, e.g. `World` for `trait World`.
+    const SERVICE: &'static str;
+}
+
+/// A message to or from the service named by `service`, as sent over the wire.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    /// The name of the service.
+    pub service: Cow<'static, str>,
+    /// The message.
+    pub message: T,
+}
+
+/// A request or response of a [`Router`]: either a message of the most recently mounted service,
+/// or one of the services mounted before it.
+///
+/// Serialized as an [`Envelope`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mounted<T, Rest> {
+    /// A message of the most recently mounted service.
+    Head(T),
+    /// A message of one of the services mounted before.
+    Tail(Rest),
+}
+
+/// A request or response of a [`Router`] without any services, which can't exist.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unmounted {}
+
+/// Deserializes the message of an [`Envelope`] sent to a [`Router`], given the name of its
+/// service. Implemented for [`Mounted`] messages.
+pub trait Route<'de>: Sized {
+    /// Deserializes the message for `service`.
+    fn deserialize_message<D>(service: &str, deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>;
+}
+
+impl<'de, T, Rest> Route<'de> for Mounted<T, Rest>
+where
+    T: Named + Deserialize<'de>,
+    Rest: Route<'de>,
+{
+    fn deserialize_message<D>(service: &str, deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if service == T::SERVICE {
+            T::deserialize(deserializer).map(Mounted::Head)
+        } else {
+            Rest::deserialize_message(service, deserializer).map(Mounted::Tail)
+        }
+    }
+}
+
+impl<'de> Route<'de> for Unmounted {
+    fn deserialize_message<D>(service: &str, _: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Err(de::Error::custom(format!("unknown service {service:?}")))
+    }
+}
+
+impl<T, Rest> Serialize for Mounted<T, Rest>
+where
+    T: Named + Serialize,
+    Rest: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Mounted::Head(message) => {
+                let mut envelope = serializer.serialize_struct("Envelope", 2)?;
+                envelope.serialize_field("service", T::SERVICE)?;
+                envelope.serialize_field("message", message)?;
+                envelope.end()
+            }
+            Mounted::Tail(rest) => rest.serialize(serializer),
+        }
+    }
+}
+
+impl Serialize for Unmounted {
+    fn serialize<S>(&self, _: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match *self {}
+    }
+}
+
+impl<'de, T, Rest> Deserialize<'de> for Mounted<T, Rest>
+where
+    Self: Route<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_struct(
+            "Envelope",
+            &["service", "message"],
+            EnvelopeVisitor(PhantomData),
+        )
+    }
+}
+
+struct EnvelopeVisitor<M>(PhantomData<fn() -> M>);
+
+impl<'de, M> Visitor<'de> for EnvelopeVisitor<M>
+where
+    M: Route<'de>,
+{
+    type Value = M;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("struct Envelope")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<M, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let service: Cow<'de, str> = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        seq.next_element_seed(MessageSeed(&service, PhantomData))?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))
+    }
+
+    /// The service must precede the message, as it does when serialized by tarpc.
+    fn visit_map<A>(self, mut map: A) -> Result<M, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        match map.next_key::<Cow<'de, str>>()?.as_deref() {
+            Some("service") => {}
+            _ => return Err(de::Error::missing_field("service")),
+        }
+        let service: Cow<'de, str> = map.next_value()?;
+        match map.next_key::<Cow<'de, str>>()?.as_deref() {
+            Some("message") => {}
+            _ => return Err(de::Error::missing_field("message")),
+        }
+        map.next_value_seed(MessageSeed(&service, PhantomData))
+    }
+}
+
+struct MessageSeed<'a, M>(&'a str, PhantomData<fn() -> M>);
+
+impl<'de, M> DeserializeSeed<'de> for MessageSeed<'_, M>
+where
+    M: Route<'de>,
+{
+    type Value = M;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<M, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        M::deserialize_message(self.0, deserializer)
+    }
+}
+
+/// Serves the requests for a number of services over a single channel. See the
+/// [module docs](self).
+#[derive(Clone, Debug)]
+pub struct Router<S, Rest = NotFound> {
+    service: S,
+    config: Option<Config>,
+    rest: Rest,
+}
+
+/// Serves the requests of a [`Router`] that no mounted service is for, of which there are none.
+#[derive(Clone, Copy, Debug)]
+pub struct NotFound;
+
+impl<S> Router<S> {
+    /// Returns a router that serves `service`.
+    pub fn new(service: S) -> Self {
+        Router {
+            service,
+            config: None,
+            rest: NotFound,
+        }
+    }
+
+    /// Returns a router that serves `service`, overriding the channel's config with `config` for
+    /// the service's requests. See [`Serve::config`] for the settings that are overridden.
+    pub fn with_config(service: S, config: Config) -> Self {
+        Router {
+            service,
+            config: Some(config),
+            rest: NotFound,
+        }
+    }
+}
+
+impl<S, Rest> Router<S, Rest> {
+    /// Returns a router that also serves `service`. Services must have distinct names.
+    pub fn mount<T>(self, service: T) -> Router<T, Self> {
+        Router {
+            service,
+            config: None,
+            rest: self,
+        }
+    }
+
+    /// Returns a router that also serves `service`, overriding the channel's config with `config`
+    /// for the service's requests. See [`Serve::config`] for the settings that are overridden.
+    pub fn mount_with_config<T>(self, service: T, config: Config) -> Router<T, Self> {
+        Router {
+            service,
+            config: Some(config),
+            rest: self,
+        }
+    }
+
+    /// Returns a router that serves only the requests of `tenant` that are in its namespace and
+    /// within its limits. See [`tenants`].
+    pub fn scoped(self, tenant: tenants::Tenant) -> tenants::Isolated<Self> {
+        tenants::Isolated::new(self, tenant)
+    }
+}
+
+/// Maps the responses of a mounted service to the responses of the router.
+fn route<Fut, Resp, RFut>(
+    served: Served<Fut>,
+    wrap: fn(Fut::Output) -> Resp,
+    fut: fn(future::Map<Fut, fn(Fut::Output) -> Resp>) -> RFut,
+) -> Served<RFut>
+where
+    Fut: Future,
+    Fut::Output: 'static,
+    RFut: Future<Output = Resp>,
+    Resp: 'static,
+{
+    match served {
+        Served::Response(response) => Served::Response(fut(response.map(wrap))),
+        Served::Stream(responses) => Served::Stream(Box::pin(responses.map(wrap))),
+        Served::Error(e) => Served::Error(e),
+        Served::Fallible(response) => Served::Fallible(Box::pin(response.map_ok(wrap))),
+    }
+}
+
+impl<S, Rest, Req, RestReq> Serve<Mounted<Req, RestReq>> for Router<S, Rest>
+where
+    S: Serve<Req>,
+    S::Resp: 'static,
+    Rest: Serve<RestReq>,
+    Rest::Resp: 'static,
+{
+    type Resp = Mounted<S::Resp, Rest::Resp>;
+    type Fut = Either<
+        future::Map<S::Fut, fn(S::Resp) -> Self::Resp>,
+        future::Map<Rest::Fut, fn(Rest::Resp) -> Self::Resp>,
+    >;
+
+    fn method(&self, request: &Mounted<Req, RestReq>) -> Option<&'static str> {
+        match request {
+            Mounted::Head(request) => self.service.method(request),
+            Mounted::Tail(request) => self.rest.method(request),
+        }
+    }
+
+    fn config(&self, request: &Mounted<Req, RestReq>) -> Option<&Config> {
+        match request {
+            Mounted::Head(request) => self
+                .config
+                .as_ref()
+                .or_else(|| self.service.config(request)),
+            Mounted::Tail(request) => self.rest.config(request),
+        }
+    }
+
+    fn serve_with_items(
+        self,
+        ctx: crate::context::Context,
+        request: Mounted<Req, RestReq>,
+        _: RequestStream<Mounted<Req, RestReq>>,
+    ) -> Served<Self::Fut> {
+        match request {
+            Mounted::Head(request) => route(
+                self.service
+                    .serve_with_items(ctx, request, RequestStream::empty()),
+                Mounted::Head,
+                Either::Left,
+            ),
+            Mounted::Tail(request) => route(
+                self.rest
+                    .serve_with_items(ctx, request, RequestStream::empty()),
+                Mounted::Tail,
+                Either::Right,
+            ),
+        }
+    }
+}
+
+impl Serve<Unmounted> for NotFound {
+    type Resp = Unmounted;
+    type Fut = future::Pending<Unmounted>;
+
+    fn serve_with_items(
+        self,
+        _: crate::context::Context,
+        request: Unmounted,
+        _: RequestStream<Unmounted>,
+    ) -> Served<Self::Fut> {
+        match request {}
+    }
+}
+
+/// Returns a transport over which a client of the service `Req` can make requests to a
+/// [`Router`], given a transport of [`Envelope`]s.
+pub fn routed<Req, Resp, T, E>(
+    transport: T,
+) -> impl Stream<Item = Result<ServerMessage<Resp>, E>> + Sink<ClientMessage<Req>, Error = E>
+where
+    T: Stream<Item = Result<ServerMessage<Envelope<Resp>>, E>>
+        + Sink<ClientMessage<Envelope<Req>>, Error = E>,
+    Req: Named,
+{
+    transport
+        .with(|message| future::ready(Ok(seal(message))))
+        .map_ok(open)
+}
+
+fn seal<Req: Named>(message: ClientMessage<Req>) -> ClientMessage<Envelope<Req>> {
+    let envelope = |message| Envelope {
+        service: Cow::Borrowed(Req::SERVICE),
+        message,
+    };
+    match message {
+        ClientMessage::Request(Request {
+            context,
+            id,
+            message,
+        }) => ClientMessage::Request(Request {
+            context,
+            id,
+            message: envelope(message),
+        }),
+        ClientMessage::Cancel {
+            trace_context,
+            request_id,
+        } => ClientMessage::Cancel {
+            trace_context,
+            request_id,
+        },
+        ClientMessage::StreamCredit {
+            request_id,
+            credits,
+        } => ClientMessage::StreamCredit {
+            request_id,
+            credits,
+        },
+        ClientMessage::StreamItem { request_id, item } => ClientMessage::StreamItem {
+            request_id,
+            item: envelope(item),
+        },
+        ClientMessage::StreamEnd { request_id } => ClientMessage::StreamEnd { request_id },
+        ClientMessage::OneWay { context, message } => ClientMessage::OneWay {
+            context,
+            message: envelope(message),
+        },
+        ClientMessage::Hello { capabilities } => ClientMessage::Hello { capabilities },
+        ClientMessage::Batch { requests } => ClientMessage::Batch {
+            requests: requests
+                .into_iter()
+                .map(|request| Request {
+                    context: request.context,
+                    id: request.id,
+                    message: envelope(request.message),
+                })
+                .collect(),
+        },
+    }
+}
+
+fn open<Resp>(message: ServerMessage<Envelope<Resp>>) -> ServerMessage<Resp> {
+    match message {
+        ServerMessage::Response(Response {
+            request_id,
+            message,
+        }) => ServerMessage::Response(Response {
+            request_id,
+            message: message.map(|envelope| envelope.message),
+        }),
+        ServerMessage::StreamItem { request_id, item } => ServerMessage::StreamItem {
+            request_id,
+            item: item.message,
+        },
+        ServerMessage::StreamEnd { request_id } => ServerMessage::StreamEnd { request_id },
+        ServerMessage::KeepAlive { request_id } => ServerMessage::KeepAlive { request_id },
+        ServerMessage::Push(message) => ServerMessage::Push(message.message),
+        ServerMessage::Hello { capabilities } => ServerMessage::Hello { capabilities },
+        ServerMessage::Close { aborted } => ServerMessage::Close { aborted },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        context,
+        server::{faults::FaultRule, BaseChannel, Channel, FaultInjector},
+        ServerError,
+    };
+    use assert_matches::assert_matches;
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct Hello(String);
+
+    impl Named for Hello {
+        const SERVICE: &'static str = "Hello";
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct Add(i32, i32);
+
+    impl Named for Add {
+        const SERVICE: &'static str = "Add";
+    }
+
+    type Requests = Mounted<Add, Mounted<Hello, Unmounted>>;
+
+    #[test]
+    fn mounted_serializes_as_envelope() {
+        let request: Requests = Mounted::Tail(Mounted::Head(Hello("Tim".into())));
+        let envelope = Envelope {
+            service: "Hello".into(),
+            message: Hello("Tim".into()),
+        };
+
+        let json = serde_json::to_string(&request).unwrap();
+        assert_eq!(json, serde_json::to_string(&envelope).unwrap());
+        assert_eq!(serde_json::from_str::<Requests>(&json).unwrap(), request);
+
+        let bytes = bincode::serialize(&request).unwrap();
+        assert_eq!(bytes, bincode::serialize(&envelope).unwrap());
+        assert_eq!(bincode::deserialize::<Requests>(&bytes).unwrap(), request);
+
+        let add = Envelope {
+            service: "Add".into(),
+            message: Add(1, 2),
+        };
+        let bytes = bincode::serialize(&add).unwrap();
+        assert_eq!(
+            bincode::deserialize::<Requests>(&bytes).unwrap(),
+            Mounted::Head(Add(1, 2))
+        );
+    }
+
+    #[test]
+    fn unknown_service_fails_to_deserialize() {
+        let json = r#"{"service":"Subtract","message":[1,2]}"#;
+        assert_matches!(
+            serde_json::from_str::<Requests>(json),
+            Err(e) if e.to_string().contains("unknown service \"Subtract\"")
+        );
+        let json = r#"{"message":[1,2],"service":"Add"}"#;
+        assert_matches!(serde_json::from_str::<Requests>(json), Err(_));
+    }
+
+    #[tokio::test]
+    async fn mounted_config_overrides_channel_config() {
+        let faults = FaultInjector::new();
+        faults.set_rules(vec![FaultRule::new(
+            std::io::ErrorKind::ConnectionRefused,
+            1.0,
+        )]);
+        let router = Router::new(|_: context::Context, Hello(name)| future::ready(name))
+            .mount_with_config(
+                |_: context::Context, Add(x, y)| future::ready(x + y),
+                Config::default().with_faults(faults),
+            );
+        let (mut tx, rx) = crate::transport::channel::unbounded();
+        tokio::spawn(
+            BaseChannel::with_defaults(rx)
+                .requests()
+                .for_each(move |request| request.unwrap().execute(router.clone())),
+        );
+
+        let requests: [Requests; 2] = [
+            Mounted::Tail(Mounted::Head(Hello("Tim".into()))),
+            Mounted::Head(Add(1, 2)),
+        ];
+        for (id, message) in (0..).zip(requests) {
+            let request = Request {
+                context: context::current(),
+                id,
+                message,
+            };
+            tx.send(ClientMessage::Request(request)).await.unwrap();
+        }
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(ServerMessage::Response(Response {
+                request_id: 0,
+                message: Ok(Mounted::Tail(Mounted::Head(name))),
+            }))) if name == "Tim"
+        );
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(ServerMessage::Response(Response {
+                request_id: 1,
+                message: Err(ServerError {
+                    kind: std::io::ErrorKind::ConnectionRefused,
+                    ..
+                }),
+            })))
+        );
+    }
+}
@@ -0,0 +1,381 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides isolation of the tenants of a multi-tenant server.
+//!
+//! Each [`Tenant`] has a [`Namespace`] of the services and methods it may call, and a limit on
+//! its in-flight requests. Once a channel is authenticated to a tenant — by whatever means the
+//! application authenticates clients, e.g. TLS client certificates — it's served by a router
+//! [scoped](super::Router::scoped) to the tenant, which rejects requests outside of the
+//! tenant's namespace with [`PermissionDenied`](std::io::ErrorKind::PermissionDenied), and
+//! requests over the tenant's limit with [`WouldBlock`](std::io::ErrorKind::WouldBlock).
+//!
+//! A tenant is shared by all of its channels, so that its limit applies to, and its
+//! [`TenantStats`] count, the requests of all of them.
+
+use crate::{
+    context,
+    router::Named,
+    server::{Config, RequestStream, Serve, Served},
+    ServerError,
+};
+use fnv::FnvHashSet;
+use futures::{prelude::*, task::*};
+use pin_project::pin_project;
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// The services and methods a tenant may call, and the limits of its requests.
+///
+/// The default namespace is empty, and admits no requests.
+#[derive(Clone, Debug, Default)]
+pub struct Namespace {
+    services: FnvHashSet<String>,
+    methods: FnvHashSet<String>,
+    max_in_flight_requests: Option<usize>,
+}
+
+impl Namespace {
+    /// Returns an empty namespace.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Admits requests for every method of the service of `M`, e.g. `WorldRequest`.
+    pub fn service<M: Named>(mut self) -> Self {
+        self.services.insert(M::SERVICE.to_string());
+        self
+    }
+
+    /// Admits requests for `method`, named `{Service}.{rpc}`, e.g. `World.hello`.
+    pub fn method(mut self, method: impl Into<String>) -> Self {
+        self.methods.insert(method.into());
+        self
+    }
+
+    /// Limits the requests of the tenant, across all of its channels, that are in flight at once.
+    /// By default, there is no limit.
+    pub fn max_in_flight_requests(mut self, max_in_flight_requests: usize) -> Self {
+        self.max_in_flight_requests = Some(max_in_flight_requests);
+        self
+    }
+
+    /// Returns true if the namespace admits requests for `method`.
+    pub fn admits(&self, method: &str) -> bool {
+        if self.methods.contains(method) {
+            return true;
+        }
+        match method.split_once('.') {
+            Some((service, _)) => self.services.contains(service),
+            None => false,
+        }
+    }
+}
+
+/// Counts the requests of a [`Tenant`].
+#[derive(Debug, Default)]
+pub struct TenantStats {
+    admitted: AtomicU64,
+    denied: AtomicU64,
+    throttled: AtomicU64,
+    in_flight: AtomicUsize,
+}
+
+impl TenantStats {
+    /// Returns the number of requests that were admitted and handled.
+    pub fn admitted(&self) -> u64 {
+        self.admitted.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests that were rejected for being outside of the tenant's
+    /// namespace.
+    pub fn denied(&self) -> u64 {
+        self.denied.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests that were rejected for exceeding the tenant's limit on
+    /// in-flight requests.
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests being handled.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+/// A tenant of a multi-tenant server. Cheap to clone; clones share their stats and limits.
+#[derive(Clone, Debug)]
+pub struct Tenant {
+    name: Arc<str>,
+    namespace: Arc<Namespace>,
+    stats: Arc<TenantStats>,
+}
+
+impl Tenant {
+    /// Returns a tenant named `name` that may make the requests admitted by `namespace`.
+    pub fn new(name: impl Into<Arc<str>>, namespace: Namespace) -> Self {
+        Self {
+            name: name.into(),
+            namespace: Arc::new(namespace),
+            stats: Arc::default(),
+        }
+    }
+
+    /// Returns the name of the tenant.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the namespace of the tenant.
+    pub fn namespace(&self) -> &Namespace {
+        &self.namespace
+    }
+
+    /// Returns the request counts of the tenant.
+    pub fn stats(&self) -> &TenantStats {
+        &self.stats
+    }
+
+    /// Admits a request for `method`, or returns why it's rejected.
+    fn admit(&self, method: Option<&str>) -> Result<InFlight, ServerError> {
+        let method = method.unwrap_or("<unknown>");
+        if !self.namespace.admits(method) {
+            self.stats.denied.fetch_add(1, Ordering::Relaxed);
+            tracing::info!(tenant = %self.name, method, "DenyRequest");
+            return Err(ServerError::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{method} is not in the namespace of tenant {}", self.name),
+            ));
+        }
+        let max = self.namespace.max_in_flight_requests.unwrap_or(usize::MAX);
+        let admitted =
+            self.stats
+                .in_flight
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_flight| {
+                    (in_flight < max).then(|| in_flight + 1)
+                });
+        if admitted.is_err() {
+            self.stats.throttled.fetch_add(1, Ordering::Relaxed);
+            tracing::info!(tenant = %self.name, method, "ThrottleRequest");
+            return Err(ServerError::overloaded(format!(
+                "tenant {} has too many requests in flight",
+                self.name
+            )));
+        }
+        self.stats.admitted.fetch_add(1, Ordering::Relaxed);
+        Ok(InFlight(self.stats.clone()))
+    }
+}
+
+/// Counts a request as in flight until dropped.
+#[derive(Debug)]
+struct InFlight(Arc<TenantStats>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Serves only the requests of a [`Tenant`] that are in its namespace and within its limits.
+/// See the [module docs](self).
+#[derive(Clone, Debug)]
+pub struct Isolated<S> {
+    serve: S,
+    tenant: Tenant,
+}
+
+impl<S> Isolated<S> {
+    /// Returns a serving function that serves the requests of `tenant` with `serve`.
+    pub fn new(serve: S, tenant: Tenant) -> Self {
+        Self { serve, tenant }
+    }
+
+    /// Returns the tenant whose requests are served.
+    pub fn tenant(&self) -> &Tenant {
+        &self.tenant
+    }
+}
+
+/// A response future or stream of an admitted request, which counts as in flight until dropped.
+#[pin_project]
+#[derive(Debug)]
+pub struct Tracked<T> {
+    #[pin]
+    inner: T,
+    in_flight: InFlight,
+}
+
+impl<F: Future> Future for Tracked<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        self.project().inner.poll(cx)
+    }
+}
+
+impl<St: Stream> Stream for Tracked<St> {
+    type Item = St::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<St::Item>> {
+        self.project().inner.poll_next(cx)
+    }
+}
+
+fn track<Fut>(served: Served<Fut>, in_flight: InFlight) -> Served<Tracked<Fut>>
+where
+    Fut: Future,
+    Fut::Output: 'static,
+{
+    match served {
+        Served::Response(inner) => Served::Response(Tracked { inner, in_flight }),
+        Served::Stream(inner) => Served::Stream(Box::pin(Tracked { inner, in_flight })),
+        Served::Error(e) => Served::Error(e),
+        Served::Fallible(inner) => Served::Fallible(Box::pin(Tracked { inner, in_flight })),
+    }
+}
+
+impl<S, Req> Serve<Req> for Isolated<S>
+where
+    S: Serve<Req>,
+    S::Resp: 'static,
+{
+    type Resp = S::Resp;
+    type Fut = Tracked<S::Fut>;
+
+    fn method(&self, request: &Req) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    fn config(&self, request: &Req) -> Option<&Config> {
+        self.serve.config(request)
+    }
+
+    fn serve_with_items(
+        self,
+        ctx: context::Context,
+        request: Req,
+        items: RequestStream<Req>,
+    ) -> Served<Self::Fut> {
+        match self.tenant.admit(self.serve.method(&request)) {
+            Ok(in_flight) => track(self.serve.serve_with_items(ctx, request, items), in_flight),
+            Err(e) => Served::Error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use futures::future::{pending, ready, Pending, Ready};
+
+    struct World;
+
+    impl Named for World {
+        const SERVICE: &'static str = "World";
+    }
+
+    #[derive(Clone)]
+    struct Methods;
+
+    impl Serve<&'static str> for Methods {
+        type Resp = ();
+        type Fut = future::Either<Ready<()>, Pending<()>>;
+
+        fn method(&self, request: &&'static str) -> Option<&'static str> {
+            Some(request)
+        }
+
+        fn serve_with_items(
+            self,
+            _: context::Context,
+            request: &'static str,
+            _: RequestStream<&'static str>,
+        ) -> Served<Self::Fut> {
+            Served::Response(if request.ends_with("wait") {
+                future::Either::Right(pending())
+            } else {
+                future::Either::Left(ready(()))
+            })
+        }
+    }
+
+    fn serve(
+        tenant: &Tenant,
+        method: &'static str,
+    ) -> Result<<Isolated<Methods> as Serve<&'static str>>::Fut, ServerError> {
+        match Isolated::new(Methods, tenant.clone()).serve_with_items(
+            context::current(),
+            method,
+            RequestStream::empty(),
+        ) {
+            Served::Response(response) => Ok(response),
+            Served::Error(e) => Err(e),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn namespace_admits_services_and_methods() {
+        let namespace = Namespace::new().service::<World>().method("Hello.hello");
+        assert!(namespace.admits("World.hello"));
+        assert!(namespace.admits("World.goodbye"));
+        assert!(namespace.admits("Hello.hello"));
+        assert!(!namespace.admits("Hello.goodbye"));
+        assert!(!namespace.admits("Worlds.hello"));
+        assert!(!namespace.admits("World"));
+    }
+
+    #[tokio::test]
+    async fn isolated_rejects_requests_outside_namespace() {
+        let tenant = Tenant::new("a", Namespace::new().service::<World>());
+
+        assert_matches!(serve(&tenant, "World.hello"), Ok(response) => response.await);
+        assert_matches!(
+            serve(&tenant, "Admin.shutdown"),
+            Err(e) if e.kind == io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(tenant.stats().admitted(), 1);
+        assert_eq!(tenant.stats().denied(), 1);
+        assert_eq!(tenant.stats().in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn isolated_limits_in_flight_requests_across_channels() {
+        let tenant = Tenant::new(
+            "a",
+            Namespace::new()
+                .service::<World>()
+                .max_in_flight_requests(1),
+        );
+        let other_channel = tenant.clone();
+
+        let waiting = serve(&tenant, "World.wait").unwrap();
+        assert_eq!(tenant.stats().in_flight(), 1);
+        assert_matches!(
+            serve(&other_channel, "World.hello"),
+            Err(e) if e.kind == io::ErrorKind::WouldBlock
+        );
+        assert_eq!(tenant.stats().throttled(), 1);
+
+        drop(waiting);
+        assert_eq!(tenant.stats().in_flight(), 0);
+        assert_matches!(serve(&other_channel, "World.hello"), Ok(response) => response.await);
+
+        let other_tenant = Tenant::new("b", Namespace::new().service::<World>());
+        let _waiting = serve(&tenant, "World.wait").unwrap();
+        assert_matches!(serve(&other_tenant, "World.hello"), Ok(_));
+    }
+}
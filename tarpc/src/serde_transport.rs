@@ -5,22 +5,44 @@
 // https://opensource.org/licenses/MIT.
 
 //! A generic Serde-based `Transport` that can serialize anything supported by `tokio-serde` via any medium that implements `AsyncRead` and `AsyncWrite`.
+//!
+//! The following features each enable a codec:
+//!
+//! - `serde-transport-json` and `serde-transport-bincode` enable the corresponding codecs in
+//!   `tokio_serde::formats`.
+//! - `serde-transport-messagepack` enables `tokio_serde::formats::MessagePack`. Like JSON, it is a
+//!   natural choice for interop with clients not written in Rust.
+//! - `serde-transport-cbor` enables `tokio_serde::formats::Cbor`. CBOR is compact enough for
+//!   constrained devices, and unlike bincode, it encodes field names, so fields can be added
+//!   without breaking older peers.
+//! - `serde-transport-postcard` enables a codec for postcard, the format of choice on embedded
+//!   devices.
+//!
+//! Despite the name, the transport works with any codec that implements `tokio_serde`'s
+//! [`Serializer`] and [`Deserializer`] traits, which are not tied to serde:
+//! `serde-transport-rkyv` enables a codec for rkyv, which writes large responses straight into
+//! the output buffer, without a serde pass. A [`mixed`] codec combines several codecs on one
+//! connection, picked per message.
 
 #![deny(missing_docs)]
 
-use futures::{prelude::*, task::*};
+use crate::transport::DecodeError;
+use bytes::{Bytes, BytesMut};
+use futures::{prelude::*, ready, task::*};
 use pin_project::pin_project;
-use serde::{Deserialize, Serialize};
-use std::{error::Error, io, pin::Pin};
-use tokio::io::{AsyncRead, AsyncWrite};
+use std::{collections::VecDeque, io, io::IoSlice, pin::Pin};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
 use tokio_serde::{Framed as SerdeFramed, *};
-use tokio_util::codec::{length_delimited::LengthDelimitedCodec, Framed};
+use tokio_util::codec::{
+    length_delimited::{self, LengthDelimitedCodec},
+    Encoder, Framed,
+};
 
 /// A transport that serializes to, and deserializes from, a byte stream.
 #[pin_project]
 pub struct Transport<S, Item, SinkItem, Codec> {
     #[pin]
-    inner: SerdeFramed<Framed<S, LengthDelimitedCodec>, Item, SinkItem, Codec>,
+    inner: SerdeFramed<Frames<S>, Item, SinkItem, Decoding<Codec>>,
 }
 
 impl<S, Item, SinkItem, Codec> Transport<S, Item, SinkItem, Codec> {
@@ -30,62 +52,41 @@ impl<S, Item, SinkItem, Codec> Transport<S, Item, SinkItem, Codec> {
     }
 }
 
-impl<S, Item, SinkItem, Codec, CodecError> Stream for Transport<S, Item, SinkItem, Codec>
+impl<S, Item, SinkItem, Codec> Stream for Transport<S, Item, SinkItem, Codec>
 where
     S: AsyncWrite + AsyncRead,
-    Item: for<'a> Deserialize<'a>,
     Codec: Deserializer<Item>,
-    CodecError: Into<Box<dyn std::error::Error + Send + Sync>>,
-    SerdeFramed<Framed<S, LengthDelimitedCodec>, Item, SinkItem, Codec>:
-        Stream<Item = Result<Item, CodecError>>,
+    io::Error: From<Codec::Error>,
 {
     type Item = io::Result<Item>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
-        self.project()
-            .inner
-            .poll_next(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        self.project().inner.poll_next(cx)
     }
 }
 
-impl<S, Item, SinkItem, Codec, CodecError> Sink<SinkItem> for Transport<S, Item, SinkItem, Codec>
+impl<S, Item, SinkItem, Codec> Sink<SinkItem> for Transport<S, Item, SinkItem, Codec>
 where
     S: AsyncWrite,
-    SinkItem: Serialize,
     Codec: Serializer<SinkItem>,
-    CodecError: Into<Box<dyn Error + Send + Sync>>,
-    SerdeFramed<Framed<S, LengthDelimitedCodec>, Item, SinkItem, Codec>:
-        Sink<SinkItem, Error = CodecError>,
+    Codec::Error: Into<io::Error>,
 {
     type Error = io::Error;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        self.project()
-            .inner
-            .poll_ready(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        self.project().inner.poll_ready(cx)
     }
 
     fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
-        self.project()
-            .inner
-            .start_send(item)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        self.project().inner.start_send(item)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        self.project()
-            .inner
-            .poll_flush(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        self.project().inner.poll_flush(cx)
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        self.project()
-            .inner
-            .poll_close(cx)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        self.project().inner.poll_close(cx)
     }
 }
 
@@ -96,20 +97,279 @@ pub fn new<S, Item, SinkItem, Codec>(
 ) -> Transport<S, Item, SinkItem, Codec>
 where
     S: AsyncWrite + AsyncRead,
-    Item: for<'de> Deserialize<'de>,
-    SinkItem: Serialize,
     Codec: Serializer<SinkItem> + Deserializer<Item>,
 {
     Transport {
-        inner: SerdeFramed::new(framed_io, codec),
+        inner: SerdeFramed::new(Frames::new(framed_io), Decoding(codec)),
+    }
+}
+
+/// Wraps the codec of a [`Transport`], so that the messages it fails to deserialize are reported
+/// as [`DecodeError`]s, which the transport can read past, rather than as errors reading frames.
+#[pin_project]
+struct Decoding<Codec>(#[pin] Codec);
+
+impl<Item, Codec> Deserializer<Item> for Decoding<Codec>
+where
+    Codec: Deserializer<Item>,
+    io::Error: From<Codec::Error>,
+{
+    type Error = DecodeError;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> Result<Item, DecodeError> {
+        self.project()
+            .0
+            .deserialize(src)
+            .map_err(|e| DecodeError::new(io::Error::from(e)))
+    }
+}
+
+impl<SinkItem, Codec> Serializer<SinkItem> for Decoding<Codec>
+where
+    Codec: Serializer<SinkItem>,
+{
+    type Error = Codec::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> Result<Bytes, Codec::Error> {
+        self.project().0.serialize(item)
+    }
+}
+
+/// The length-delimited frames beneath a [`Transport`].
+///
+/// Frames are read through [`Framed`], but when the byte stream supports vectored writes, they
+/// are not copied into its write buffer. Instead, each serialized message and its length prefix
+/// are queued as they are, and a flush hands all queued frames to a single
+/// [`poll_write_vectored`](AsyncWrite::poll_write_vectored) call, so a burst of small responses
+/// costs one syscall rather than one copy and write each. Byte streams without vectored writes,
+/// and framing with a layout the prefix can't be derived for, write through [`Framed`].
+#[pin_project]
+struct Frames<S> {
+    #[pin]
+    framed: Framed<S, LengthDelimitedCodec>,
+    /// The layout of length prefixes, if frames are written vectored.
+    prefix: Option<LengthPrefix>,
+    /// The length prefixes of queued frames, split off as they are written.
+    prefixes: BytesMut,
+    /// The prefixes and payloads of frames queued to be written, in order.
+    chunks: VecDeque<Bytes>,
+    /// The number of bytes in `chunks`.
+    queued: usize,
+}
+
+/// The number of queued bytes past which a transport writes them before accepting another frame.
+const BACKPRESSURE_BOUNDARY: usize = 64 * 1024;
+
+/// The most buffers handed to one vectored write; operating systems cap the count, typically at
+/// 1024.
+const MAX_IO_SLICES: usize = 64;
+
+/// How a [`LengthDelimitedCodec`] encodes the length of a frame.
+#[derive(Clone, Copy, Debug)]
+struct LengthPrefix {
+    len: usize,
+    big_endian: bool,
+    /// What the codec adds to the length of a frame before encoding it.
+    adjustment: i64,
+    max_frame_length: usize,
+}
+
+impl LengthPrefix {
+    /// Derives the layout of the codec's length prefixes by encoding two probe frames, since the
+    /// codec does not expose it. Returns `None` if the probes can't be encoded.
+    fn probe(codec: &LengthDelimitedCodec) -> Option<Self> {
+        let mut codec = codec.clone();
+        let mut encoded = BytesMut::new();
+        Encoder::<&[u8]>::encode(&mut codec, &[0], &mut encoded).ok()?;
+        let one = encoded.split_to(encoded.len() - 1);
+        Encoder::<&[u8]>::encode(&mut codec, &[0, 0], &mut encoded).ok()?;
+        let two = encoded.split_to(encoded.len() - 2);
+
+        let len = one.len();
+        let big_endian = match (one.as_ref(), two.as_ref()) {
+            (one, two) if Self::decode(one, true)?.checked_add(1) == Self::decode(two, true) => {
+                true
+            }
+            (one, two) if Self::decode(one, false)?.checked_add(1) == Self::decode(two, false) => {
+                false
+            }
+            _ => return None,
+        };
+        let adjustment = i64::try_from(Self::decode(&one, big_endian)?).ok()? - 1;
+        Some(LengthPrefix {
+            len,
+            big_endian,
+            adjustment,
+            max_frame_length: codec.max_frame_length(),
+        })
+    }
+
+    fn decode(prefix: &[u8], big_endian: bool) -> Option<u64> {
+        let mut bytes = [0; 8];
+        let len = prefix.len();
+        if len > 8 {
+            return None;
+        }
+        if big_endian {
+            bytes[8 - len..].copy_from_slice(prefix);
+            Some(u64::from_be_bytes(bytes))
+        } else {
+            bytes[..len].copy_from_slice(prefix);
+            Some(u64::from_le_bytes(bytes))
+        }
+    }
+
+    /// Appends the prefix of a frame of `frame_length` bytes to `dst`, failing as the codec
+    /// would.
+    fn encode(&self, frame_length: usize, dst: &mut BytesMut) -> io::Result<()> {
+        if frame_length > self.max_frame_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "frame of {} bytes is over the limit of {} bytes",
+                    frame_length, self.max_frame_length
+                ),
+            ));
+        }
+        let length = i64::try_from(frame_length)
+            .ok()
+            .and_then(|length| length.checked_add(self.adjustment))
+            .and_then(|length| u64::try_from(length).ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "provided length would overflow after adjustment",
+                )
+            })?;
+        let bytes = if self.big_endian {
+            length.to_be_bytes()
+        } else {
+            length.to_le_bytes()
+        };
+        if self.big_endian {
+            dst.extend_from_slice(&bytes[8 - self.len..]);
+        } else {
+            dst.extend_from_slice(&bytes[..self.len]);
+        }
+        Ok(())
+    }
+}
+
+impl<S> Frames<S>
+where
+    S: AsyncWrite,
+{
+    fn new(framed: Framed<S, LengthDelimitedCodec>) -> Self {
+        let prefix = if framed.get_ref().is_write_vectored() {
+            LengthPrefix::probe(framed.codec())
+        } else {
+            None
+        };
+        Frames {
+            framed,
+            prefix,
+            prefixes: BytesMut::new(),
+            chunks: VecDeque::new(),
+            queued: 0,
+        }
+    }
+
+    /// Writes queued frames until none are left.
+    fn poll_write_queued(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let mut io = this.framed.get_pin_mut();
+        while !this.chunks.is_empty() {
+            let slices: Vec<IoSlice> = this
+                .chunks
+                .iter()
+                .take(MAX_IO_SLICES)
+                .map(|chunk| IoSlice::new(chunk))
+                .collect();
+            let mut written = ready!(io.as_mut().poll_write_vectored(cx, &slices))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write frames to the transport",
+                )));
+            }
+            *this.queued -= written;
+            while written > 0 {
+                let chunk = this.chunks.front_mut().unwrap();
+                if written < chunk.len() {
+                    let _ = chunk.split_to(written);
+                    break;
+                }
+                written -= chunk.len();
+                this.chunks.pop_front();
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> Frames<S> {
+    fn get_ref(&self) -> &S {
+        self.framed.get_ref()
+    }
+}
+
+impl<S> Stream for Frames<S>
+where
+    S: AsyncRead,
+{
+    type Item = io::Result<BytesMut>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().framed.poll_next(cx)
+    }
+}
+
+impl<S> Sink<Bytes> for Frames<S>
+where
+    S: AsyncWrite,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.prefix.is_none() {
+            return Sink::<Bytes>::poll_ready(self.project().framed, cx);
+        }
+        if self.queued >= BACKPRESSURE_BOUNDARY {
+            ready!(self.as_mut().poll_write_queued(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Bytes) -> io::Result<()> {
+        let this = self.project();
+        let prefix = match this.prefix {
+            Some(prefix) => prefix,
+            None => return this.framed.start_send(frame),
+        };
+        prefix.encode(frame.len(), this.prefixes)?;
+        let prefix = this.prefixes.split().freeze();
+        *this.queued += prefix.len() + frame.len();
+        this.chunks.push_back(prefix);
+        if !frame.is_empty() {
+            this.chunks.push_back(frame);
+        }
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_write_queued(cx))?;
+        Sink::<Bytes>::poll_flush(self.project().framed, cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_write_queued(cx))?;
+        Sink::<Bytes>::poll_close(self.project().framed, cx)
     }
 }
 
 impl<S, Item, SinkItem, Codec> From<(S, Codec)> for Transport<S, Item, SinkItem, Codec>
 where
     S: AsyncWrite + AsyncRead,
-    Item: for<'de> Deserialize<'de>,
-    SinkItem: Serialize,
     Codec: Serializer<SinkItem> + Deserializer<Item>,
 {
     fn from((io, codec): (S, Codec)) -> Self {
@@ -117,470 +377,4370 @@ where
     }
 }
 
-#[cfg(feature = "tcp")]
-#[cfg_attr(docsrs, doc(cfg(feature = "tcp")))]
-/// TCP support for generic transport using Tokio.
-pub mod tcp {
-    use {
-        super::*,
-        futures::ready,
-        std::{marker::PhantomData, net::SocketAddr},
-        tokio::net::{TcpListener, TcpStream, ToSocketAddrs},
-        tokio_util::codec::length_delimited,
-    };
+/// Returns two transports connected by an in-memory byte stream, which buffers up to
+/// `max_buf_size` bytes in each direction. Unlike [`transport::channel`](crate::transport::channel),
+/// messages are serialized and framed as they would be over a socket, which makes the pair useful
+/// for tests.
+///
+/// ```
+/// use tarpc::{serde_transport, ClientMessage, ServerMessage};
+/// use tokio_serde::formats::Json;
+///
+/// let (client_transport, server_transport) = serde_transport::duplex(
+///     4096,
+///     Json::<ServerMessage<String>, ClientMessage<String>>::default(),
+///     Json::<ClientMessage<String>, ServerMessage<String>>::default(),
+/// );
+/// ```
+pub fn duplex<Item, SinkItem, Codec, PeerCodec>(
+    max_buf_size: usize,
+    codec: Codec,
+    peer_codec: PeerCodec,
+) -> (
+    Transport<DuplexStream, Item, SinkItem, Codec>,
+    Transport<DuplexStream, SinkItem, Item, PeerCodec>,
+)
+where
+    Codec: Serializer<SinkItem> + Deserializer<Item>,
+    PeerCodec: Serializer<Item> + Deserializer<SinkItem>,
+{
+    let (io, peer_io) = tokio::io::duplex(max_buf_size);
+    (
+        Transport::from((io, codec)),
+        Transport::from((peer_io, peer_codec)),
+    )
+}
 
-    impl<Item, SinkItem, Codec> Transport<TcpStream, Item, SinkItem, Codec> {
-        /// Returns the peer address of the underlying TcpStream.
-        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-            self.inner.get_ref().get_ref().peer_addr()
-        }
-        /// Returns the local address of the underlying TcpStream.
-        pub fn local_addr(&self) -> io::Result<SocketAddr> {
-            self.inner.get_ref().get_ref().local_addr()
+/// Configures transports in one place: their codec, their framing, and, for sockets, their socket
+/// options. A builder produces both sides of a connection, so the same configuration can be
+/// shared by a client and a server, or by a server and its tests.
+///
+/// ```
+/// # #[cfg(feature = "tcp")]
+/// # async fn example() -> std::io::Result<()> {
+/// use tarpc::{serde_transport::Builder, ClientMessage, ServerMessage};
+/// use tokio_serde::formats::Json;
+///
+/// let builder = Builder::new(Json::<ClientMessage<String>, ServerMessage<String>>::default)
+///     .max_frame_length(1 << 20)
+///     .nodelay(true);
+/// let listener = builder.tcp_listen("localhost:0").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Builder<CodecFn> {
+    codec_fn: CodecFn,
+    framing: length_delimited::Builder,
+    #[cfg(feature = "tcp")]
+    socket_options: tcp::SocketOptions,
+}
+
+impl<CodecFn> Builder<CodecFn> {
+    /// Returns a builder of transports whose codecs are made by `codec_fn`.
+    pub fn new(codec_fn: CodecFn) -> Self {
+        Self {
+            codec_fn,
+            framing: LengthDelimitedCodec::builder(),
+            #[cfg(feature = "tcp")]
+            socket_options: tcp::SocketOptions::default(),
         }
     }
 
-    /// A connection Future that also exposes the length-delimited framing config.
-    #[must_use]
-    #[pin_project]
-    pub struct Connect<T, Item, SinkItem, CodecFn> {
-        #[pin]
-        inner: T,
-        codec_fn: CodecFn,
-        config: length_delimited::Builder,
-        ghost: PhantomData<(fn(SinkItem), fn() -> Item)>,
+    /// Sets the maximum length of a frame. Larger frames are rejected with an error, both when
+    /// sent and when received. A received frame is rejected as soon as its length prefix is read,
+    /// before any buffer is allocated for it. Defaults to 8 MiB.
+    pub fn max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.framing.max_frame_length(max_frame_length);
+        self
     }
 
-    impl<T, Item, SinkItem, Codec, CodecFn> Future for Connect<T, Item, SinkItem, CodecFn>
+    /// Sets the number of bytes in the length prefix of each frame, from 1 to 8. Defaults to 4.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `length_field_length` is 0 or greater than 8.
+    pub fn length_field_length(mut self, length_field_length: usize) -> Self {
+        assert!(
+            (1..=8).contains(&length_field_length),
+            "length_field_length must be between 1 and 8, but was {length_field_length}"
+        );
+        self.framing.length_field_length(length_field_length);
+        self
+    }
+
+    /// Encodes the length prefix of each frame as big-endian. This is the default.
+    pub fn big_endian(mut self) -> Self {
+        self.framing.big_endian();
+        self
+    }
+
+    /// Encodes the length prefix of each frame as little-endian.
+    pub fn little_endian(mut self) -> Self {
+        self.framing.little_endian();
+        self
+    }
+
+    /// Returns a mutable reference to the length-delimited framing config, for settings beyond
+    /// the length prefix, like its offset or adjustment.
+    pub fn framing_mut(&mut self) -> &mut length_delimited::Builder {
+        &mut self.framing
+    }
+
+    /// Wraps `io` in a transport.
+    pub fn new_transport<S, Item, SinkItem, Codec>(
+        &self,
+        io: S,
+    ) -> Transport<S, Item, SinkItem, Codec>
     where
-        T: Future<Output = io::Result<TcpStream>>,
-        Item: for<'de> Deserialize<'de>,
-        SinkItem: Serialize,
+        S: AsyncWrite + AsyncRead,
         Codec: Serializer<SinkItem> + Deserializer<Item>,
         CodecFn: Fn() -> Codec,
     {
-        type Output = io::Result<Transport<TcpStream, Item, SinkItem, Codec>>;
+        new(self.framing.new_framed(io), (self.codec_fn)())
+    }
+}
 
-        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-            let io = ready!(self.as_mut().project().inner.poll(cx))?;
-            Poll::Ready(Ok(new(self.config.new_framed(io), (self.codec_fn)())))
-        }
+#[cfg(feature = "tcp")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tcp")))]
+impl<CodecFn> Builder<CodecFn> {
+    /// Sets the socket options of TCP connections, replacing any set before.
+    pub fn socket_options(mut self, socket_options: tcp::SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
     }
 
-    impl<T, Item, SinkItem, CodecFn> Connect<T, Item, SinkItem, CodecFn> {
-        /// Returns an immutable reference to the length-delimited codec's config.
-        pub fn config(&self) -> &length_delimited::Builder {
-            &self.config
-        }
+    /// Sets whether to disable Nagle's algorithm on TCP connections.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.socket_options.nodelay = Some(nodelay);
+        self
+    }
 
-        /// Returns a mutable reference to the length-delimited codec's config.
-        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
-            &mut self.config
-        }
+    /// Sets the time-to-live of packets sent over TCP connections.
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.socket_options.ttl = Some(ttl);
+        self
     }
 
-    /// Connects to `addr`, wrapping the connection in a TCP transport.
-    pub fn connect<A, Item, SinkItem, Codec, CodecFn>(
+    /// Connects to `addr` over TCP. See [`tcp::connect`].
+    pub fn tcp_connect<A, Item, SinkItem, Codec>(
+        &self,
         addr: A,
-        codec_fn: CodecFn,
-    ) -> Connect<impl Future<Output = io::Result<TcpStream>>, Item, SinkItem, CodecFn>
+    ) -> tcp::Connect<
+        impl Future<Output = io::Result<tokio::net::TcpStream>>,
+        Item,
+        SinkItem,
+        CodecFn,
+    >
     where
-        A: ToSocketAddrs,
-        Item: for<'de> Deserialize<'de>,
-        SinkItem: Serialize,
+        A: tokio::net::ToSocketAddrs,
         Codec: Serializer<SinkItem> + Deserializer<Item>,
-        CodecFn: Fn() -> Codec,
+        CodecFn: Fn() -> Codec + Clone,
     {
-        Connect {
-            inner: TcpStream::connect(addr),
-            codec_fn,
-            config: LengthDelimitedCodec::builder(),
-            ghost: PhantomData,
-        }
+        tcp::connect_with(
+            addr,
+            self.codec_fn.clone(),
+            self.framing,
+            self.socket_options,
+        )
     }
 
-    /// Listens on `addr`, wrapping accepted connections in TCP transports.
-    pub async fn listen<A, Item, SinkItem, Codec, CodecFn>(
+    /// Listens on `addr` for TCP connections. See [`tcp::listen`].
+    pub async fn tcp_listen<A, Item, SinkItem, Codec>(
+        &self,
         addr: A,
-        codec_fn: CodecFn,
-    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    ) -> io::Result<tcp::Incoming<Item, SinkItem, Codec, CodecFn>>
     where
-        A: ToSocketAddrs,
-        Item: for<'de> Deserialize<'de>,
+        A: tokio::net::ToSocketAddrs,
         Codec: Serializer<SinkItem> + Deserializer<Item>,
-        CodecFn: Fn() -> Codec,
+        CodecFn: Fn() -> Codec + Clone,
     {
-        let listener = TcpListener::bind(addr).await?;
-        let local_addr = listener.local_addr()?;
-        Ok(Incoming {
-            listener,
-            codec_fn,
-            local_addr,
-            config: LengthDelimitedCodec::builder(),
-            ghost: PhantomData,
-        })
+        tcp::listen_with(
+            addr,
+            self.codec_fn.clone(),
+            self.framing,
+            self.socket_options,
+        )
+        .await
     }
+}
 
-    /// A [`TcpListener`] that wraps connections in [transports](Transport).
-    #[pin_project]
-    #[derive(Debug)]
-    pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
-        listener: TcpListener,
-        local_addr: SocketAddr,
-        codec_fn: CodecFn,
-        config: length_delimited::Builder,
-        ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
+#[cfg(feature = "tls")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tls")))]
+impl<CodecFn> Builder<CodecFn> {
+    /// Connects to `addr` over TLS, verifying the server's certificate for `domain`. See
+    /// [`tls::connect`].
+    pub fn tls_connect<A, Item, SinkItem, Codec>(
+        &self,
+        addr: A,
+        domain: tls::rustls::ServerName,
+        tls_config: std::sync::Arc<tls::rustls::ClientConfig>,
+    ) -> tls::Connect<
+        impl Future<Output = io::Result<tokio_rustls::client::TlsStream<tokio::net::TcpStream>>>,
+        Item,
+        SinkItem,
+        CodecFn,
+    >
+    where
+        A: tokio::net::ToSocketAddrs,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec + Clone,
+    {
+        tls::connect_with(
+            addr,
+            domain,
+            tls_config,
+            self.codec_fn.clone(),
+            self.framing,
+            self.socket_options,
+        )
     }
 
-    impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
-        /// Returns the address being listened on.
-        pub fn local_addr(&self) -> SocketAddr {
-            self.local_addr
-        }
+    /// Listens on `addr` for TLS connections. See [`tls::listen`].
+    pub async fn tls_listen<A, Item, SinkItem, Codec>(
+        &self,
+        addr: A,
+        tls_config: std::sync::Arc<tls::rustls::ServerConfig>,
+    ) -> io::Result<tls::Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        A: tokio::net::ToSocketAddrs,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec + Clone,
+    {
+        tls::listen_with(
+            addr,
+            tls_config,
+            self.codec_fn.clone(),
+            self.framing,
+            self.socket_options,
+        )
+        .await
+    }
+}
 
-        /// Returns an immutable reference to the length-delimited codec's config.
-        pub fn config(&self) -> &length_delimited::Builder {
-            &self.config
+#[cfg(feature = "serde-transport-postcard")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde-transport-postcard")))]
+/// A codec for the [postcard](https://docs.rs/postcard) format, for peers on embedded devices.
+///
+/// Postcard messages don't encode their own length, so each message is sent in a frame prefixed
+/// by its length, like messages in any other format. Embedded peers commonly use narrower
+/// prefixes than the default of four big-endian bytes; the prefix is configured with a
+/// [`Builder`]:
+///
+/// ```
+/// use tarpc::serde_transport::{postcard::SymmetricalPostcard, Builder};
+///
+/// let builder = Builder::new(SymmetricalPostcard::<String>::default)
+///     .length_field_length(2)
+///     .little_endian()
+///     .max_frame_length(u16::MAX as usize);
+/// ```
+pub mod postcard {
+    use {
+        super::*,
+        bytes::{Bytes, BytesMut},
+        serde::{Deserialize, Serialize},
+        std::{fmt, marker::PhantomData},
+    };
+
+    /// A codec that serializes `SinkItem`s to, and deserializes `Item`s from, postcard.
+    pub struct Postcard<Item, SinkItem> {
+        ghost: PhantomData<(Item, SinkItem)>,
+    }
+
+    /// A [`Postcard`] codec that sends and receives the same type.
+    pub type SymmetricalPostcard<T> = Postcard<T, T>;
+
+    impl<Item, SinkItem> Default for Postcard<Item, SinkItem> {
+        fn default() -> Self {
+            Self { ghost: PhantomData }
         }
+    }
 
-        /// Returns a mutable reference to the length-delimited codec's config.
-        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
-            &mut self.config
+    impl<Item, SinkItem> fmt::Debug for Postcard<Item, SinkItem> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Postcard").finish()
         }
     }
 
-    impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
+    impl<Item, SinkItem> Deserializer<Item> for Postcard<Item, SinkItem>
     where
         Item: for<'de> Deserialize<'de>,
+    {
+        type Error = io::Error;
+
+        fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<Item> {
+            ::postcard::from_bytes(src).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+
+    impl<Item, SinkItem> Serializer<SinkItem> for Postcard<Item, SinkItem>
+    where
         SinkItem: Serialize,
-        Codec: Serializer<SinkItem> + Deserializer<Item>,
-        CodecFn: Fn() -> Codec,
     {
-        type Item = io::Result<Transport<TcpStream, Item, SinkItem, Codec>>;
+        type Error = io::Error;
 
-        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-            let conn: TcpStream =
-                ready!(Pin::new(&mut self.as_mut().project().listener).poll_accept(cx)?).0;
-            Poll::Ready(Some(Ok(new(
-                self.config.new_framed(conn),
-                (self.codec_fn)(),
-            ))))
+        fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> io::Result<Bytes> {
+            ::postcard::to_stdvec(item)
+                .map(Into::into)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
         }
     }
 }
 
-#[cfg(all(unix, feature = "unix"))]
-#[cfg_attr(docsrs, doc(cfg(all(unix, feature = "unix"))))]
-/// Unix Domain Socket support for generic transport using Tokio.
-pub mod unix {
+/// A codec for [rkyv](https://docs.rs/rkyv), a zero-copy format.
+///
+/// Messages are archived without an intermediate serde pass: the payloads of tarpc's messages are
+/// serialized directly into the archive. With the `rkyv` feature, tarpc's message types derive
+/// rkyv's traits, so a frame can also be inspected in place as an
+/// [`ArchivedClientMessage`](crate::ArchivedClientMessage) or
+/// [`ArchivedServerMessage`](crate::ArchivedServerMessage) with `rkyv::check_archived_root`.
+///
+/// Archives are validated before they are deserialized, so frames from untrusted peers are safe
+/// to decode.
+///
+/// ```
+/// use rkyv::{Archive, Deserialize, Serialize};
+/// use tarpc::{
+///     serde_transport::{self, rkyv::Rkyv},
+///     ClientMessage, ServerMessage,
+/// };
+///
+/// #[derive(Archive, Serialize, Deserialize)]
+/// #[archive(check_bytes)]
+/// struct Chunk {
+///     data: Vec<u8>,
+/// }
+///
+/// let (client_transport, server_transport) = serde_transport::duplex(
+///     4096,
+///     Rkyv::<ServerMessage<Chunk>, ClientMessage<Chunk>>::default(),
+///     Rkyv::<ClientMessage<Chunk>, ServerMessage<Chunk>>::default(),
+/// );
+/// ```
+#[cfg(feature = "serde-transport-rkyv")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde-transport-rkyv")))]
+pub mod rkyv {
     use {
         super::*,
-        futures::ready,
-        std::{marker::PhantomData, path::Path},
-        tokio::net::{unix::SocketAddr, UnixListener, UnixStream},
-        tokio_util::codec::length_delimited,
+        ::rkyv::{
+            de::deserializers::SharedDeserializeMap, ser::serializers::AllocSerializer,
+            validation::validators::DefaultValidator, AlignedVec, Archive, CheckBytes, Deserialize,
+            Serialize,
+        },
+        bytes::{Bytes, BytesMut},
+        std::{fmt, marker::PhantomData},
     };
 
-    impl<Item, SinkItem, Codec> Transport<UnixStream, Item, SinkItem, Codec> {
-        /// Returns the socket address of the remote half of the underlying [`UnixStream`].
-        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-            self.inner.get_ref().get_ref().peer_addr()
+    /// The bytes of scratch space preallocated for serializing a message.
+    const SCRATCH_SPACE: usize = 1024;
+
+    /// A codec that archives `SinkItem`s, and deserializes `Item`s from archives, with rkyv.
+    pub struct Rkyv<Item, SinkItem> {
+        ghost: PhantomData<(Item, SinkItem)>,
+    }
+
+    /// A [`Rkyv`] codec that sends and receives the same type.
+    pub type SymmetricalRkyv<T> = Rkyv<T, T>;
+
+    impl<Item, SinkItem> Default for Rkyv<Item, SinkItem> {
+        fn default() -> Self {
+            Self { ghost: PhantomData }
         }
-        /// Returns the socket address of the local half of the underlying [`UnixStream`].
-        pub fn local_addr(&self) -> io::Result<SocketAddr> {
-            self.inner.get_ref().get_ref().local_addr()
+    }
+
+    impl<Item, SinkItem> fmt::Debug for Rkyv<Item, SinkItem> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Rkyv").finish()
         }
     }
 
-    /// A connection Future that also exposes the length-delimited framing config.
-    #[must_use]
-    #[pin_project]
-    pub struct Connect<T, Item, SinkItem, CodecFn> {
-        #[pin]
-        inner: T,
-        codec_fn: CodecFn,
-        config: length_delimited::Builder,
-        ghost: PhantomData<(fn(SinkItem), fn() -> Item)>,
+    impl<Item, SinkItem> Deserializer<Item> for Rkyv<Item, SinkItem>
+    where
+        Item: Archive,
+        Item::Archived:
+            for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<Item, SharedDeserializeMap>,
+    {
+        type Error = io::Error;
+
+        fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<Item> {
+            // Archives must be aligned to be accessed, which frames are not guaranteed to be.
+            let mut archive = AlignedVec::with_capacity(src.len());
+            archive.extend_from_slice(src);
+            ::rkyv::from_bytes(&archive).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the frame is not a valid archive of the message",
+                )
+            })
+        }
     }
 
-    impl<T, Item, SinkItem, Codec, CodecFn> Future for Connect<T, Item, SinkItem, CodecFn>
+    impl<Item, SinkItem> Serializer<SinkItem> for Rkyv<Item, SinkItem>
     where
-        T: Future<Output = io::Result<UnixStream>>,
-        Item: for<'de> Deserialize<'de>,
-        SinkItem: Serialize,
-        Codec: Serializer<SinkItem> + Deserializer<Item>,
-        CodecFn: Fn() -> Codec,
+        SinkItem: Serialize<AllocSerializer<SCRATCH_SPACE>>,
     {
-        type Output = io::Result<Transport<UnixStream, Item, SinkItem, Codec>>;
+        type Error = io::Error;
 
-        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-            let io = ready!(self.as_mut().project().inner.poll(cx))?;
-            Poll::Ready(Ok(new(self.config.new_framed(io), (self.codec_fn)())))
+        fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> io::Result<Bytes> {
+            ::rkyv::to_bytes(item)
+                .map(|archive| Bytes::copy_from_slice(&archive))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
         }
     }
+}
 
-    impl<T, Item, SinkItem, CodecFn> Connect<T, Item, SinkItem, CodecFn> {
-        /// Returns an immutable reference to the length-delimited codec's config.
-        pub fn config(&self) -> &length_delimited::Builder {
-            &self.config
-        }
+/// A codec that lets each message pick its format from a set of codecs, so that a mostly-bincode
+/// service can serve occasional JSON requests from debugging tools on the same connection.
+///
+/// Each frame starts with the one-byte [id](mixed::CodecId) of the codec that serialized it. Both
+/// peers configure the same ids for the same formats. A [`Mixed`](mixed::Mixed) codec
+/// deserializes each frame with the codec its id names, and serializes messages with its first
+/// codec, except on the server: responses, stream items and keep-alives are serialized with the
+/// codec of their request, and other messages with the codec of the last message received. The
+/// handler only ever sees the deserialized request, whichever format it arrived in.
+///
+/// ```
+/// use tarpc::{
+///     serde_transport::{self, mixed::Mixed},
+///     ClientMessage, ServerMessage,
+/// };
+/// use tokio_serde::formats::{Bincode, Json};
+///
+/// const BINCODE: u8 = 0;
+/// const JSON: u8 = 1;
+///
+/// // A debugging tool sending JSON to a server that mostly receives bincode.
+/// let (tool_transport, server_transport) = serde_transport::duplex(
+///     4096,
+///     Mixed::new(JSON, Json::<ServerMessage<String>, ClientMessage<String>>::default()),
+///     Mixed::new(
+///         BINCODE,
+///         Bincode::<ClientMessage<String>, ServerMessage<String>>::default(),
+///     )
+///     .with_codec(JSON, Json::default()),
+/// );
+/// ```
+pub mod mixed {
+    use {
+        super::*,
+        crate::{ClientMessage, ServerMessage},
+        bytes::{BufMut, Bytes, BytesMut},
+        fnv::FnvHashMap,
+        std::fmt,
+    };
 
-        /// Returns a mutable reference to the length-delimited codec's config.
-        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
-            &mut self.config
-        }
+    /// Identifies a codec of a [`Mixed`] codec on the wire, as the first byte of each frame.
+    pub type CodecId = u8;
+
+    /// A codec serializing and deserializing the messages of a connection with one of several
+    /// codecs, picked per message. See the [module docs](self).
+    pub struct Mixed<Item, SinkItem> {
+        /// The codecs, the first of which serializes messages not tied to a codec.
+        codecs: Vec<(CodecId, Box<dyn ErasedCodec<Item, SinkItem>>)>,
+        /// The codec of each request in flight whose codec isn't the first.
+        requests: FnvHashMap<u64, CodecId>,
+        /// The codec of the last message received.
+        last_received: CodecId,
     }
 
-    /// Connects to socket named by `path`, wrapping the connection in a Unix Domain Socket
-    /// transport.
-    pub fn connect<P, Item, SinkItem, Codec, CodecFn>(
-        path: P,
-        codec_fn: CodecFn,
-    ) -> Connect<impl Future<Output = io::Result<UnixStream>>, Item, SinkItem, CodecFn>
-    where
-        P: AsRef<Path>,
-        Item: for<'de> Deserialize<'de>,
-        SinkItem: Serialize,
-        Codec: Serializer<SinkItem> + Deserializer<Item>,
-        CodecFn: Fn() -> Codec,
-    {
-        Connect {
-            inner: UnixStream::connect(path),
-            codec_fn,
-            config: LengthDelimitedCodec::builder(),
-            ghost: PhantomData,
+    impl<Item, SinkItem> Mixed<Item, SinkItem> {
+        /// Returns a codec serializing and deserializing messages with `codec`, identified by `id`.
+        pub fn new<Codec>(id: CodecId, codec: Codec) -> Self
+        where
+            Codec: Serializer<SinkItem> + Deserializer<Item> + Unpin + Send + 'static,
+            <Codec as Serializer<SinkItem>>::Error: Into<io::Error>,
+            io::Error: From<<Codec as Deserializer<Item>>::Error>,
+        {
+            Self {
+                codecs: vec![(id, Box::new(codec))],
+                requests: FnvHashMap::default(),
+                last_received: id,
+            }
         }
-    }
 
-    /// Listens on the socket named by `path`, wrapping accepted connections in Unix Domain Socket
-    /// transports.
-    pub async fn listen<P, Item, SinkItem, Codec, CodecFn>(
-        path: P,
-        codec_fn: CodecFn,
-    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
-    where
-        P: AsRef<Path>,
-        Item: for<'de> Deserialize<'de>,
-        Codec: Serializer<SinkItem> + Deserializer<Item>,
-        CodecFn: Fn() -> Codec,
-    {
-        let listener = UnixListener::bind(path)?;
-        let local_addr = listener.local_addr()?;
-        Ok(Incoming {
-            listener,
-            codec_fn,
-            local_addr,
-            config: LengthDelimitedCodec::builder(),
-            ghost: PhantomData,
-        })
-    }
+        /// Adds `codec`, identified by `id`, to the codecs messages can be deserialized with.
+        ///
+        /// # Panics
+        ///
+        /// If a codec is already identified by `id`.
+        pub fn with_codec<Codec>(mut self, id: CodecId, codec: Codec) -> Self
+        where
+            Codec: Serializer<SinkItem> + Deserializer<Item> + Unpin + Send + 'static,
+            <Codec as Serializer<SinkItem>>::Error: Into<io::Error>,
+            io::Error: From<<Codec as Deserializer<Item>>::Error>,
+        {
+            assert!(
+                self.codecs.iter().all(|(codec_id, _)| *codec_id != id),
+                "codec id {} is already used",
+                id
+            );
+            self.codecs.push((id, Box::new(codec)));
+            self
+        }
 
-    /// A [`UnixListener`] that wraps connections in [transports](Transport).
-    #[pin_project]
-    #[derive(Debug)]
-    pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
-        listener: UnixListener,
-        local_addr: SocketAddr,
-        codec_fn: CodecFn,
-        config: length_delimited::Builder,
-        ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
-    }
+        fn codec(&mut self, id: CodecId) -> io::Result<&mut dyn ErasedCodec<Item, SinkItem>> {
+            match self.codecs.iter_mut().find(|(codec_id, _)| *codec_id == id) {
+                Some((_, codec)) => Ok(codec.as_mut()),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown codec id {}", id),
+                )),
+            }
+        }
 
-    impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
-        /// Returns the the socket address being listened on.
-        pub fn local_addr(&self) -> &SocketAddr {
-            &self.local_addr
+        /// Deserializes a frame with the codec its first byte names, returning the codec id.
+        fn deserialize_tagged(&mut self, src: &BytesMut) -> io::Result<(CodecId, Item)> {
+            let (&id, message) = src.split_first().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "frame is missing its codec id")
+            })?;
+            let item = self.codec(id)?.deserialize(&BytesMut::from(message))?;
+            self.last_received = id;
+            Ok((id, item))
         }
 
-        /// Returns an immutable reference to the length-delimited codec's config.
-        pub fn config(&self) -> &length_delimited::Builder {
-            &self.config
+        /// Serializes `item` with the codec identified by `id`, preceded by the id.
+        fn serialize_tagged(&mut self, id: CodecId, item: &SinkItem) -> io::Result<Bytes> {
+            let message = self.codec(id)?.serialize(item)?;
+            let mut frame = BytesMut::with_capacity(message.len() + 1);
+            frame.put_u8(id);
+            frame.extend_from_slice(&message);
+            Ok(frame.freeze())
         }
 
-        /// Returns a mutable reference to the length-delimited codec's config.
-        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
-            &mut self.config
+        fn default_id(&self) -> CodecId {
+            self.codecs[0].0
         }
     }
 
-    impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
-    where
-        Item: for<'de> Deserialize<'de>,
-        SinkItem: Serialize,
-        Codec: Serializer<SinkItem> + Deserializer<Item>,
-        CodecFn: Fn() -> Codec,
+    impl<Item, SinkItem> fmt::Debug for Mixed<Item, SinkItem> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Mixed")
+                .field(
+                    "codecs",
+                    &self.codecs.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+                )
+                .finish()
+        }
+    }
+
+    /// A server deserializes requests, remembering the codec of each.
+    impl<Req, Resp> Deserializer<ClientMessage<Req>>
+        for Mixed<ClientMessage<Req>, ServerMessage<Resp>>
     {
-        type Item = io::Result<Transport<UnixStream, Item, SinkItem, Codec>>;
+        type Error = io::Error;
 
-        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-            let conn: UnixStream = ready!(self.as_mut().project().listener.poll_accept(cx)?).0;
-            Poll::Ready(Some(Ok(new(
-                self.config.new_framed(conn),
-                (self.codec_fn)(),
-            ))))
+        fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<ClientMessage<Req>> {
+            let this = self.get_mut();
+            let (id, message) = this.deserialize_tagged(src)?;
+            let default_id = this.default_id();
+            let mut track = |request_id| {
+                if id != default_id {
+                    this.requests.insert(request_id, id);
+                }
+            };
+            match &message {
+                ClientMessage::Request(request) => track(request.id),
+                ClientMessage::Batch { requests } => {
+                    requests.iter().for_each(|request| track(request.id))
+                }
+                ClientMessage::Cancel { request_id, .. } => {
+                    this.requests.remove(request_id);
+                }
+                _ => {}
+            }
+            Ok(message)
         }
     }
 
-    /// A temporary `PathBuf` that lives in `std::env::temp_dir` and is removed on drop.
-    pub struct TempPathBuf(std::path::PathBuf);
+    /// A server serializes the messages of a request with the codec of the request.
+    impl<Req, Resp> Serializer<ServerMessage<Resp>> for Mixed<ClientMessage<Req>, ServerMessage<Resp>> {
+        type Error = io::Error;
 
-    impl TempPathBuf {
-        /// A named socket that results in `<tempdir>/<name>`
-        pub fn new<S: AsRef<str>>(name: S) -> Self {
-            let mut sock = std::env::temp_dir();
-            sock.push(name.as_ref());
-            Self(sock)
+        fn serialize(self: Pin<&mut Self>, item: &ServerMessage<Resp>) -> io::Result<Bytes> {
+            let this = self.get_mut();
+            let id = match item {
+                // A response is the last message of its request.
+                ServerMessage::Response(response) => this
+                    .requests
+                    .remove(&response.request_id)
+                    .unwrap_or_else(|| this.default_id()),
+                ServerMessage::StreamEnd { request_id } => this
+                    .requests
+                    .remove(request_id)
+                    .unwrap_or_else(|| this.default_id()),
+                ServerMessage::StreamItem { request_id, .. }
+                | ServerMessage::KeepAlive { request_id } => this
+                    .requests
+                    .get(request_id)
+                    .copied()
+                    .unwrap_or_else(|| this.default_id()),
+                _ => this.last_received,
+            };
+            this.serialize_tagged(id, item)
         }
+    }
 
-        /// Appends a random hex string to the socket name resulting in
-        /// `<tempdir>/<name>_<xxxxx>`
-        pub fn with_random<S: AsRef<str>>(name: S) -> Self {
-            Self::new(format!("{}_{:016x}", name.as_ref(), rand::random::<u64>()))
+    /// A client deserializes responses with the codec they name.
+    impl<Req, Resp> Deserializer<ServerMessage<Resp>>
+        for Mixed<ServerMessage<Resp>, ClientMessage<Req>>
+    {
+        type Error = io::Error;
+
+        fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<ServerMessage<Resp>> {
+            Ok(self.get_mut().deserialize_tagged(src)?.1)
         }
     }
 
-    impl AsRef<std::path::Path> for TempPathBuf {
-        fn as_ref(&self) -> &std::path::Path {
-            self.0.as_path()
+    /// A client serializes its messages with its first codec.
+    impl<Req, Resp> Serializer<ClientMessage<Req>> for Mixed<ServerMessage<Resp>, ClientMessage<Req>> {
+        type Error = io::Error;
+
+        fn serialize(self: Pin<&mut Self>, item: &ClientMessage<Req>) -> io::Result<Bytes> {
+            let this = self.get_mut();
+            let id = this.default_id();
+            this.serialize_tagged(id, item)
         }
     }
 
-    impl Drop for TempPathBuf {
-        fn drop(&mut self) {
-            // This will remove the file pointed to by this PathBuf if it exists, however Err's can
-            // be returned such as attempting to remove a non-existing file, or one which we don't
-            // have permission to remove. In these cases the Err is swallowed
-            let _ = std::fs::remove_file(&self.0);
+    /// A codec, with its error types and pinning erased.
+    trait ErasedCodec<Item, SinkItem>: Send {
+        fn deserialize(&mut self, src: &BytesMut) -> io::Result<Item>;
+        fn serialize(&mut self, item: &SinkItem) -> io::Result<Bytes>;
+    }
+
+    impl<Codec, Item, SinkItem> ErasedCodec<Item, SinkItem> for Codec
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item> + Unpin + Send,
+        <Codec as Serializer<SinkItem>>::Error: Into<io::Error>,
+        io::Error: From<<Codec as Deserializer<Item>>::Error>,
+    {
+        fn deserialize(&mut self, src: &BytesMut) -> io::Result<Item> {
+            Ok(Deserializer::deserialize(Pin::new(self), src)?)
+        }
+
+        fn serialize(&mut self, item: &SinkItem) -> io::Result<Bytes> {
+            Serializer::serialize(Pin::new(self), item).map_err(Into::into)
         }
     }
 
     #[cfg(test)]
     mod tests {
         use super::*;
-        use tokio_serde::formats::SymmetricalJson;
+        use crate::{context, Request, Response};
+        use tokio_serde::formats::{Bincode, Json};
 
-        #[test]
-        fn temp_path_buf_non_random() {
-            let sock = TempPathBuf::new("test");
-            let mut good = std::env::temp_dir();
-            good.push("test");
-            assert_eq!(sock.as_ref(), good);
-            assert_eq!(sock.as_ref().file_name().unwrap(), "test");
-        }
+        const BINCODE: CodecId = 0;
+        const JSON: CodecId = 1;
 
-        #[test]
-        fn temp_path_buf_random() {
-            let sock = TempPathBuf::with_random("test");
-            let good = std::env::temp_dir();
-            assert!(sock.as_ref().starts_with(good));
-            // Since there are 16 random characters we just assert the file_name has the right name
-            // and starts with the correct string 'test_'
-            // file name: test_xxxxxxxxxxxxxxxx
-            // test  = 4
-            // _     = 1
-            // <hex> = 16
-            // total = 21
-            let fname = sock.as_ref().file_name().unwrap().to_string_lossy();
-            assert!(fname.starts_with("test_"));
-            assert_eq!(fname.len(), 21);
-        }
+        type ServerCodec = Mixed<ClientMessage<String>, ServerMessage<String>>;
+        type ClientCodec = Mixed<ServerMessage<String>, ClientMessage<String>>;
 
-        #[test]
-        fn temp_path_buf_non_existing() {
-            let sock = TempPathBuf::with_random("test");
-            let sock_path = std::path::PathBuf::from(sock.as_ref());
+        fn server_codec() -> Pin<Box<ServerCodec>> {
+            Box::pin(Mixed::new(BINCODE, Bincode::default()).with_codec(JSON, Json::default()))
+        }
 
-            // No actual file has been created yet
-            assert!(!sock_path.exists());
-            // Should not panic
-            std::mem::drop(sock);
-            assert!(!sock_path.exists());
+        fn request(id: u64) -> ClientMessage<String> {
+            ClientMessage::Request(Request {
+                context: context::current(),
+                id,
+                message: "ping".into(),
+            })
         }
 
-        #[test]
-        fn temp_path_buf_existing_file() {
-            let sock = TempPathBuf::with_random("test");
-            let sock_path = std::path::PathBuf::from(sock.as_ref());
-            let _file = std::fs::File::create(&sock).unwrap();
-            assert!(sock_path.exists());
-            std::mem::drop(sock);
-            assert!(!sock_path.exists());
+        fn response(request_id: u64) -> ServerMessage<String> {
+            ServerMessage::Response(Response {
+                request_id,
+                message: Ok("pong".into()),
+            })
         }
 
         #[test]
-        fn temp_path_buf_preexisting_file() {
-            let mut pre_existing = std::env::temp_dir();
-            pre_existing.push("test");
-            let _file = std::fs::File::create(&pre_existing).unwrap();
-            let sock = TempPathBuf::new("test");
-            let sock_path = std::path::PathBuf::from(sock.as_ref());
-            assert!(sock_path.exists());
-            std::mem::drop(sock);
-            assert!(!sock_path.exists());
+        fn server_responds_in_the_format_of_each_request() -> io::Result<()> {
+            let mut server = server_codec();
+            let mut json_tool: Pin<Box<ClientCodec>> = Box::pin(Mixed::new(JSON, Json::default()));
+            let mut bincode_client: Pin<Box<ClientCodec>> =
+                Box::pin(Mixed::new(BINCODE, Bincode::default()));
+
+            let frame = json_tool.as_mut().serialize(&request(1))?;
+            assert_eq!(frame[0], JSON);
+            assert!(serde_json::from_slice::<serde_json::Value>(&frame[1..]).is_ok());
+            let frame = BytesMut::from(&frame[..]);
+            assert_matches::assert_matches!(
+                server.as_mut().deserialize(&frame)?,
+                ClientMessage::Request(Request { id: 1, .. })
+            );
+            let frame = bincode_client.as_mut().serialize(&request(2))?;
+            assert_eq!(frame[0], BINCODE);
+            server.as_mut().deserialize(&BytesMut::from(&frame[..]))?;
+
+            let frame = server.as_mut().serialize(&response(1))?;
+            assert_eq!(frame[0], JSON);
+            assert_matches::assert_matches!(
+                json_tool.as_mut().deserialize(&BytesMut::from(&frame[..]))?,
+                ServerMessage::Response(Response { request_id: 1, message: Ok(pong) }) if pong == "pong"
+            );
+            let frame = server.as_mut().serialize(&response(2))?;
+            assert_eq!(frame[0], BINCODE);
+            bincode_client
+                .as_mut()
+                .deserialize(&BytesMut::from(&frame[..]))?;
+            // The request is done, so a late message for it isn't sent as JSON.
+            let frame = server
+                .as_mut()
+                .serialize(&ServerMessage::KeepAlive { request_id: 1 })?;
+            assert_eq!(frame[0], BINCODE);
+            Ok(())
         }
 
-        #[tokio::test]
-        async fn temp_path_buf_for_socket() {
-            let sock = TempPathBuf::with_random("test");
-            // Save path for testing after drop
-            let sock_path = std::path::PathBuf::from(sock.as_ref());
-            // create the actual socket
-            let _ = listen(&sock, SymmetricalJson::<String>::default).await;
-            assert!(sock_path.exists());
-            std::mem::drop(sock);
-            assert!(!sock_path.exists());
+        #[test]
+        fn unknown_codec_id_is_invalid_data() {
+            let mut server = server_codec();
+            let error = server
+                .as_mut()
+                .deserialize(&BytesMut::from(&[7u8, 0][..]))
+                .unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            let error = server.as_mut().deserialize(&BytesMut::new()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::Transport;
-    use assert_matches::assert_matches;
-    use futures::{task::*, Sink, Stream};
-    use pin_utils::pin_mut;
-    use std::{
-        io::{self, Cursor},
-        pin::Pin,
+/// Measures the cost of serializing and deserializing messages, per rpc, so that the rpcs that
+/// would benefit most from a cheaper codec or a zero-copy path can be found.
+///
+/// An [`Instrumented`](metrics::Instrumented) codec wraps a codec, and reports the duration and
+/// payload size of each message it serializes or deserializes to a
+/// [`Recorder`](metrics::Recorder), e.g. a [`CodecStats`](metrics::CodecStats) that sums them per
+/// rpc. With the `codec-metrics` feature, [`#[tarpc::service]`](crate::service) implements
+/// [`Method`](metrics::Method) for the request and response types of each service, so messages
+/// are attributed to their rpcs. Messages that don't belong to an rpc, such as cancellations, and
+/// error responses, aren't recorded.
+///
+/// ```
+/// use tarpc::{
+///     serde_transport::{
+///         self,
+///         metrics::{CodecStats, Instrumented, Operation},
+///     },
+///     ClientMessage, ServerMessage,
+/// };
+/// use tokio_serde::formats::Json;
+///
+/// #[tarpc::service]
+/// pub trait World {
+///     async fn hello(name: String) -> String;
+/// }
+///
+/// let stats = CodecStats::new();
+/// let (client_transport, server_transport) = serde_transport::duplex(
+///     4096,
+///     Instrumented::new(
+///         Json::<ServerMessage<WorldResponse>, ClientMessage<WorldRequest>>::default(),
+///         stats.clone(),
+///     ),
+///     Json::<ClientMessage<WorldRequest>, ServerMessage<WorldResponse>>::default(),
+/// );
+/// // After the client sends requests:
+/// for ((method, operation), cost) in stats.costs() {
+///     println!("{method} {operation:?}: {} bytes in {:?}", cost.bytes, cost.duration);
+/// }
+/// ```
+#[cfg(feature = "codec-metrics")]
+#[cfg_attr(docsrs, doc(cfg(feature = "codec-metrics")))]
+pub mod metrics {
+    use {
+        super::*,
+        crate::{ClientMessage, ServerMessage},
+        bytes::{Bytes, BytesMut},
+        std::{
+            collections::BTreeMap,
+            sync::{Arc, Mutex, PoisonError},
+            time::{Duration, Instant},
+        },
     };
-    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-    use tokio_serde::formats::SymmetricalJson;
 
-    fn ctx() -> Context<'static> {
-        Context::from_waker(noop_waker_ref())
+    /// Names the rpc a message belongs to. Implemented by [`#[tarpc::service]`](crate::service)
+    /// for the request and response types of each service.
+    pub trait Method {
+        /// Returns the name of the rpc the message belongs to, named `{Service}.{rpc}`, or None
+        /// if it doesn't belong to an rpc.
+        fn method(&self) -> Option<&'static str>;
     }
 
-    struct TestIo(Cursor<Vec<u8>>);
-
-    impl AsyncRead for TestIo {
-        fn poll_read(
-            mut self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
-            buf: &mut ReadBuf<'_>,
-        ) -> Poll<io::Result<()>> {
-            AsyncRead::poll_read(Pin::new(&mut self.0), cx, buf)
+    impl<T: Method> Method for ClientMessage<T> {
+        fn method(&self) -> Option<&'static str> {
+            match self {
+                ClientMessage::Request(request) => request.message.method(),
+                ClientMessage::StreamItem { item, .. }
+                | ClientMessage::OneWay { message: item, .. } => item.method(),
+                ClientMessage::Cancel { .. }
+                | ClientMessage::StreamCredit { .. }
+                | ClientMessage::StreamEnd { .. }
+                | ClientMessage::Hello { .. }
+                | ClientMessage::Batch { .. } => None,
+            }
         }
     }
 
-    impl AsyncWrite for TestIo {
-        fn poll_write(
-            mut self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
-            buf: &[u8],
-        ) -> Poll<io::Result<usize>> {
-            AsyncWrite::poll_write(Pin::new(&mut self.0), cx, buf)
+    impl<T: Method> Method for ServerMessage<T> {
+        fn method(&self) -> Option<&'static str> {
+            match self {
+                ServerMessage::Response(response) => response.message.as_ref().ok()?.method(),
+                ServerMessage::StreamItem { item, .. } | ServerMessage::Push(item) => item.method(),
+                ServerMessage::StreamEnd { .. }
+                | ServerMessage::KeepAlive { .. }
+                | ServerMessage::Hello { .. }
+                | ServerMessage::Close { .. } => None,
+            }
         }
+    }
 
-        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-            AsyncWrite::poll_flush(Pin::new(&mut self.0), cx)
-        }
+    /// Whether a message was serialized or deserialized.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub enum Operation {
+        /// The message was serialized, to be sent.
+        Serialize,
+        /// The message was deserialized, after being received.
+        Deserialize,
+    }
 
-        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-            AsyncWrite::poll_shutdown(Pin::new(&mut self.0), cx)
-        }
+    /// The cost of serializing or deserializing a message.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    #[non_exhaustive]
+    pub struct CodecEvent {
+        /// The rpc the message belongs to.
+        pub method: &'static str,
+        /// Whether the message was serialized or deserialized.
+        pub operation: Operation,
+        /// How long the codec took.
+        pub duration: Duration,
+        /// The size of the serialized message.
+        pub bytes: usize,
     }
 
-    #[test]
+    /// Receives the cost of each message serialized or deserialized by an [`Instrumented`]
+    /// codec. Implemented for closures taking a [`CodecEvent`].
+    pub trait Recorder {
+        /// Records the cost of a message.
+        fn record(&self, event: CodecEvent);
+    }
+
+    impl<F: Fn(CodecEvent)> Recorder for F {
+        fn record(&self, event: CodecEvent) {
+            self(event)
+        }
+    }
+
+    /// A codec that reports the cost of each message it serializes or deserializes.
+    #[pin_project]
+    #[derive(Debug)]
+    pub struct Instrumented<Codec, R> {
+        #[pin]
+        codec: Codec,
+        recorder: R,
+    }
+
+    impl<Codec, R> Instrumented<Codec, R> {
+        /// Wraps `codec`, reporting the cost of each message to `recorder`.
+        pub fn new(codec: Codec, recorder: R) -> Self {
+            Self { codec, recorder }
+        }
+
+        /// Returns the wrapped codec.
+        pub fn into_inner(self) -> Codec {
+            self.codec
+        }
+    }
+
+    impl<Codec, R, Item> Deserializer<Item> for Instrumented<Codec, R>
+    where
+        Codec: Deserializer<Item>,
+        R: Recorder,
+        Item: Method,
+    {
+        type Error = Codec::Error;
+
+        fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> Result<Item, Codec::Error> {
+            let this = self.project();
+            let start = Instant::now();
+            let item = this.codec.deserialize(src)?;
+            let duration = start.elapsed();
+            if let Some(method) = item.method() {
+                this.recorder.record(CodecEvent {
+                    method,
+                    operation: Operation::Deserialize,
+                    duration,
+                    bytes: src.len(),
+                });
+            }
+            Ok(item)
+        }
+    }
+
+    impl<Codec, R, SinkItem> Serializer<SinkItem> for Instrumented<Codec, R>
+    where
+        Codec: Serializer<SinkItem>,
+        R: Recorder,
+        SinkItem: Method,
+    {
+        type Error = Codec::Error;
+
+        fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> Result<Bytes, Codec::Error> {
+            let this = self.project();
+            let start = Instant::now();
+            let bytes = this.codec.serialize(item)?;
+            let duration = start.elapsed();
+            if let Some(method) = item.method() {
+                this.recorder.record(CodecEvent {
+                    method,
+                    operation: Operation::Serialize,
+                    duration,
+                    bytes: bytes.len(),
+                });
+            }
+            Ok(bytes)
+        }
+    }
+
+    /// The summed cost of the messages of an rpc, in one direction.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    #[non_exhaustive]
+    pub struct CodecCost {
+        /// The number of messages.
+        pub messages: u64,
+        /// The time spent in the codec.
+        pub duration: Duration,
+        /// The total size of the serialized messages.
+        pub bytes: u64,
+        /// The size of the largest serialized message.
+        pub max_bytes: usize,
+    }
+
+    /// A [`Recorder`] that sums the cost of the messages of each rpc. Cheap to clone; clones share
+    /// their sums, so one `CodecStats` can be given to the codecs of every connection.
+    #[derive(Clone, Debug, Default)]
+    pub struct CodecStats {
+        costs: Arc<Mutex<BTreeMap<(&'static str, Operation), CodecCost>>>,
+    }
+
+    impl CodecStats {
+        /// Returns stats without any messages recorded.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Returns the summed cost of the messages of each rpc and operation, ordered by rpc.
+        pub fn costs(&self) -> BTreeMap<(&'static str, Operation), CodecCost> {
+            self.costs
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone()
+        }
+    }
+
+    impl Recorder for CodecStats {
+        fn record(&self, event: CodecEvent) {
+            let mut costs = self.costs.lock().unwrap_or_else(PoisonError::into_inner);
+            let cost = costs.entry((event.method, event.operation)).or_default();
+            cost.messages += 1;
+            cost.duration += event.duration;
+            cost.bytes += event.bytes as u64;
+            cost.max_bytes = cost.max_bytes.max(event.bytes);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{context, Request, Response};
+        use tokio_serde::formats::SymmetricalJson;
+
+        #[tarpc_plugins::service]
+        trait Kitchen {
+            async fn cook(dish: String) -> String;
+            async fn clean() -> ();
+        }
+
+        #[test]
+        fn instrumented_codec_records_cost_per_rpc() {
+            let stats = CodecStats::new();
+            let mut client_codec = Box::pin(Instrumented::new(
+                SymmetricalJson::<ClientMessage<KitchenRequest>>::default(),
+                stats.clone(),
+            ));
+            let mut server_codec = Box::pin(Instrumented::new(
+                SymmetricalJson::<ServerMessage<KitchenResponse>>::default(),
+                stats.clone(),
+            ));
+
+            let request = ClientMessage::Request(Request {
+                context: context::current(),
+                id: 0,
+                message: KitchenRequest::Cook {
+                    dish: "soup".into(),
+                },
+            });
+            let bytes = client_codec.as_mut().serialize(&request).unwrap();
+            client_codec
+                .as_mut()
+                .deserialize(&BytesMut::from(&bytes[..]))
+                .unwrap();
+            let cancel = ClientMessage::Cancel {
+                trace_context: Default::default(),
+                request_id: 0,
+            };
+            client_codec.as_mut().serialize(&cancel).unwrap();
+            let response = ServerMessage::Response(Response {
+                request_id: 0,
+                message: Ok(KitchenResponse::Cook("soup".into())),
+            });
+            server_codec.as_mut().serialize(&response).unwrap();
+
+            let costs = stats.costs();
+            assert_eq!(
+                costs.keys().collect::<Vec<_>>(),
+                [
+                    &("Kitchen.cook", Operation::Serialize),
+                    &("Kitchen.cook", Operation::Deserialize)
+                ]
+            );
+            let serialized = costs[&("Kitchen.cook", Operation::Serialize)];
+            assert_eq!(serialized.messages, 2);
+            assert!(serialized.bytes > bytes.len() as u64);
+            let deserialized = costs[&("Kitchen.cook", Operation::Deserialize)];
+            assert_eq!(deserialized.messages, 1);
+            assert_eq!(deserialized.bytes, bytes.len() as u64);
+        }
+    }
+}
+
+#[cfg(feature = "tcp")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tcp")))]
+/// TCP support for generic transport using Tokio.
+pub mod tcp {
+    use {
+        super::*,
+        futures::ready,
+        std::{marker::PhantomData, net::SocketAddr},
+        tokio::net::{TcpListener, TcpStream, ToSocketAddrs},
+        tokio_util::codec::length_delimited,
+    };
+
+    impl<Item, SinkItem, Codec> Transport<TcpStream, Item, SinkItem, Codec> {
+        /// Returns the peer address of the underlying TcpStream.
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.get_ref().get_ref().peer_addr()
+        }
+        /// Returns the local address of the underlying TcpStream.
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.get_ref().get_ref().local_addr()
+        }
+    }
+
+    /// Options set on each TCP socket. Options left unset keep the operating system's defaults.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    #[non_exhaustive]
+    pub struct SocketOptions {
+        /// Whether to disable Nagle's algorithm, which delays small writes to coalesce them.
+        pub nodelay: Option<bool>,
+        /// The time-to-live of outgoing IP packets.
+        pub ttl: Option<u32>,
+    }
+
+    impl SocketOptions {
+        pub(crate) fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+            if let Some(nodelay) = self.nodelay {
+                stream.set_nodelay(nodelay)?;
+            }
+            if let Some(ttl) = self.ttl {
+                stream.set_ttl(ttl)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// A connection Future that also exposes the length-delimited framing config.
+    #[must_use]
+    #[pin_project]
+    pub struct Connect<T, Item, SinkItem, CodecFn> {
+        #[pin]
+        inner: T,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        options: SocketOptions,
+        ghost: PhantomData<(fn(SinkItem), fn() -> Item)>,
+    }
+
+    impl<T, Item, SinkItem, Codec, CodecFn> Future for Connect<T, Item, SinkItem, CodecFn>
+    where
+        T: Future<Output = io::Result<TcpStream>>,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Output = io::Result<Transport<TcpStream, Item, SinkItem, Codec>>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            let io = ready!(self.as_mut().project().inner.poll(cx))?;
+            self.options.apply(&io)?;
+            Poll::Ready(Ok(new(self.config.new_framed(io), (self.codec_fn)())))
+        }
+    }
+
+    impl<T, Item, SinkItem, CodecFn> Connect<T, Item, SinkItem, CodecFn> {
+        /// Returns an immutable reference to the length-delimited codec's config.
+        pub fn config(&self) -> &length_delimited::Builder {
+            &self.config
+        }
+
+        /// Returns a mutable reference to the length-delimited codec's config.
+        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+            &mut self.config
+        }
+    }
+
+    /// Connects to `addr`, wrapping the connection in a TCP transport.
+    pub fn connect<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        codec_fn: CodecFn,
+    ) -> Connect<impl Future<Output = io::Result<TcpStream>>, Item, SinkItem, CodecFn>
+    where
+        A: ToSocketAddrs,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        connect_with(
+            addr,
+            codec_fn,
+            LengthDelimitedCodec::builder(),
+            SocketOptions::default(),
+        )
+    }
+
+    pub(crate) fn connect_with<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        options: SocketOptions,
+    ) -> Connect<impl Future<Output = io::Result<TcpStream>>, Item, SinkItem, CodecFn>
+    where
+        A: ToSocketAddrs,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        Connect {
+            inner: TcpStream::connect(addr),
+            codec_fn,
+            config,
+            options,
+            ghost: PhantomData,
+        }
+    }
+
+    /// Listens on `addr`, wrapping accepted connections in TCP transports.
+    pub async fn listen<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        A: ToSocketAddrs,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        listen_with(
+            addr,
+            codec_fn,
+            LengthDelimitedCodec::builder(),
+            SocketOptions::default(),
+        )
+        .await
+    }
+
+    pub(crate) async fn listen_with<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        options: SocketOptions,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        A: ToSocketAddrs,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        Ok(Incoming {
+            listener,
+            codec_fn,
+            local_addr,
+            config,
+            options,
+            ghost: PhantomData,
+        })
+    }
+
+    /// Wraps connections accepted by a listener that is already bound, e.g. one passed by the
+    /// service manager via socket activation, in TCP transports.
+    ///
+    /// Must be called within a Tokio runtime.
+    pub fn from_std<Item, SinkItem, Codec, CodecFn>(
+        listener: std::net::TcpListener,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let local_addr = listener.local_addr()?;
+        Ok(Incoming {
+            listener,
+            codec_fn,
+            local_addr,
+            config: LengthDelimitedCodec::builder(),
+            options: SocketOptions::default(),
+            ghost: PhantomData,
+        })
+    }
+
+    /// A [`TcpListener`] that wraps connections in [transports](Transport).
+    #[pin_project]
+    #[derive(Debug)]
+    pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
+        listener: TcpListener,
+        local_addr: SocketAddr,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        options: SocketOptions,
+        ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
+        /// Returns the address being listened on.
+        pub fn local_addr(&self) -> SocketAddr {
+            self.local_addr
+        }
+
+        /// Returns an immutable reference to the length-delimited codec's config.
+        pub fn config(&self) -> &length_delimited::Builder {
+            &self.config
+        }
+
+        /// Returns a mutable reference to the length-delimited codec's config.
+        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+            &mut self.config
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Item = io::Result<Transport<TcpStream, Item, SinkItem, Codec>>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let conn: TcpStream =
+                ready!(Pin::new(&mut self.as_mut().project().listener).poll_accept(cx)?).0;
+            self.options.apply(&conn)?;
+            Poll::Ready(Some(Ok(new(
+                self.config.new_framed(conn),
+                (self.codec_fn)(),
+            ))))
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "unix"))]
+#[cfg_attr(docsrs, doc(cfg(all(unix, feature = "unix"))))]
+/// Unix Domain Socket support for generic transport using Tokio.
+pub mod unix {
+    use {
+        super::*,
+        futures::ready,
+        std::{marker::PhantomData, path::Path},
+        tokio::net::{unix::SocketAddr, UnixListener, UnixStream},
+        tokio_util::codec::length_delimited,
+    };
+
+    impl<Item, SinkItem, Codec> Transport<UnixStream, Item, SinkItem, Codec> {
+        /// Returns the socket address of the remote half of the underlying [`UnixStream`].
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.get_ref().get_ref().peer_addr()
+        }
+        /// Returns the socket address of the local half of the underlying [`UnixStream`].
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.get_ref().get_ref().local_addr()
+        }
+    }
+
+    /// A connection Future that also exposes the length-delimited framing config.
+    #[must_use]
+    #[pin_project]
+    pub struct Connect<T, Item, SinkItem, CodecFn> {
+        #[pin]
+        inner: T,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        ghost: PhantomData<(fn(SinkItem), fn() -> Item)>,
+    }
+
+    impl<T, Item, SinkItem, Codec, CodecFn> Future for Connect<T, Item, SinkItem, CodecFn>
+    where
+        T: Future<Output = io::Result<UnixStream>>,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Output = io::Result<Transport<UnixStream, Item, SinkItem, Codec>>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            let io = ready!(self.as_mut().project().inner.poll(cx))?;
+            Poll::Ready(Ok(new(self.config.new_framed(io), (self.codec_fn)())))
+        }
+    }
+
+    impl<T, Item, SinkItem, CodecFn> Connect<T, Item, SinkItem, CodecFn> {
+        /// Returns an immutable reference to the length-delimited codec's config.
+        pub fn config(&self) -> &length_delimited::Builder {
+            &self.config
+        }
+
+        /// Returns a mutable reference to the length-delimited codec's config.
+        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+            &mut self.config
+        }
+    }
+
+    /// Connects to socket named by `path`, wrapping the connection in a Unix Domain Socket
+    /// transport.
+    pub fn connect<P, Item, SinkItem, Codec, CodecFn>(
+        path: P,
+        codec_fn: CodecFn,
+    ) -> Connect<impl Future<Output = io::Result<UnixStream>>, Item, SinkItem, CodecFn>
+    where
+        P: AsRef<Path>,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        Connect {
+            inner: UnixStream::connect(path),
+            codec_fn,
+            config: LengthDelimitedCodec::builder(),
+            ghost: PhantomData,
+        }
+    }
+
+    /// Listens on the socket named by `path`, wrapping accepted connections in Unix Domain Socket
+    /// transports.
+    pub async fn listen<P, Item, SinkItem, Codec, CodecFn>(
+        path: P,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        P: AsRef<Path>,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        let listener = UnixListener::bind(path)?;
+        let local_addr = listener.local_addr()?;
+        Ok(Incoming {
+            listener,
+            codec_fn,
+            local_addr,
+            config: LengthDelimitedCodec::builder(),
+            ghost: PhantomData,
+        })
+    }
+
+    /// Returns a nonblocking socket bound or connected, by `op`, to the socket named `name` in
+    /// the abstract namespace.
+    ///
+    /// The address is built here rather than passed to tokio as a path starting with a null
+    /// byte, which some tokio versions reject.
+    #[cfg(all(
+        feature = "abstract-socket",
+        any(target_os = "linux", target_os = "android")
+    ))]
+    fn abstract_socket(
+        name: &[u8],
+        op: unsafe extern "C" fn(
+            std::os::raw::c_int,
+            *const libc::sockaddr,
+            libc::socklen_t,
+        ) -> std::os::raw::c_int,
+    ) -> io::Result<std::os::unix::io::RawFd> {
+        // Safety: all zeroes is a valid sockaddr_un.
+        let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        // The first byte of the path stays null, which puts the name in the abstract namespace.
+        if name.len() >= addr.sun_path.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the name of an abstract socket must be at most 107 bytes long",
+            ));
+        }
+        for (byte, &name_byte) in addr.sun_path[1..].iter_mut().zip(name) {
+            *byte = name_byte as std::os::raw::c_char;
+        }
+        let len = std::mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+        // Safety: the fd is closed if the socket can't be bound or connected, and otherwise
+        // returned to a single owner.
+        unsafe {
+            let fd = libc::socket(
+                libc::AF_UNIX,
+                libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if op(
+                fd,
+                (&addr as *const libc::sockaddr_un).cast(),
+                len as libc::socklen_t,
+            ) < 0
+            {
+                let error = io::Error::last_os_error();
+                libc::close(fd);
+                return Err(error);
+            }
+            Ok(fd)
+        }
+    }
+
+    /// Connects to the socket named `name` in the abstract namespace, wrapping the connection in a
+    /// Unix Domain Socket transport.
+    ///
+    /// Abstract sockets have no file, so they can be used by sandboxed processes without
+    /// filesystem access, and they vanish when the listener is closed.
+    #[cfg(all(
+        feature = "abstract-socket",
+        any(target_os = "linux", target_os = "android")
+    ))]
+    #[cfg_attr(
+        docsrs,
+        doc(cfg(all(
+            feature = "abstract-socket",
+            any(target_os = "linux", target_os = "android")
+        )))
+    )]
+    pub fn connect_abstract<N, Item, SinkItem, Codec, CodecFn>(
+        name: N,
+        codec_fn: CodecFn,
+    ) -> Connect<impl Future<Output = io::Result<UnixStream>>, Item, SinkItem, CodecFn>
+    where
+        N: AsRef<[u8]>,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        use std::os::unix::io::FromRawFd;
+
+        let fd = abstract_socket(name.as_ref(), libc::connect);
+        Connect {
+            // Safety: the socket was just opened, and is owned by nothing else.
+            inner: async move {
+                UnixStream::from_std(unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd?) })
+            },
+            codec_fn,
+            config: LengthDelimitedCodec::builder(),
+            ghost: PhantomData,
+        }
+    }
+
+    /// Listens on the socket named `name` in the abstract namespace, wrapping accepted connections
+    /// in Unix Domain Socket transports.
+    #[cfg(all(
+        feature = "abstract-socket",
+        any(target_os = "linux", target_os = "android")
+    ))]
+    #[cfg_attr(
+        docsrs,
+        doc(cfg(all(
+            feature = "abstract-socket",
+            any(target_os = "linux", target_os = "android")
+        )))
+    )]
+    pub async fn listen_abstract<N, Item, SinkItem, Codec, CodecFn>(
+        name: N,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        N: AsRef<[u8]>,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        use std::os::unix::io::FromRawFd;
+
+        let fd = abstract_socket(name.as_ref(), libc::bind)?;
+        // Safety: the socket was just opened, and is owned by nothing else.
+        let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+        // Safety: the fd is a socket, owned by the listener.
+        if unsafe { libc::listen(fd, 1024) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let listener = UnixListener::from_std(listener)?;
+        let local_addr = listener.local_addr()?;
+        Ok(Incoming {
+            listener,
+            codec_fn,
+            local_addr,
+            config: LengthDelimitedCodec::builder(),
+            ghost: PhantomData,
+        })
+    }
+
+    /// Returns a pair of transports connected to each other by an unnamed pair of sockets, e.g.
+    /// for a client and a server in a test fixture. One end can be handed to a child process,
+    /// which wraps it with [`from_std`].
+    pub fn pair<Item, SinkItem, Codec, PeerCodec>(
+        codec: Codec,
+        peer_codec: PeerCodec,
+    ) -> io::Result<(
+        Transport<UnixStream, Item, SinkItem, Codec>,
+        Transport<UnixStream, SinkItem, Item, PeerCodec>,
+    )>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        PeerCodec: Serializer<Item> + Deserializer<SinkItem>,
+    {
+        let (io, peer_io) = UnixStream::pair()?;
+        Ok((
+            new(LengthDelimitedCodec::builder().new_framed(io), codec),
+            new(
+                LengthDelimitedCodec::builder().new_framed(peer_io),
+                peer_codec,
+            ),
+        ))
+    }
+
+    /// Wraps a connected standard library socket in a Unix Domain Socket transport, e.g. the end
+    /// of a [pair] inherited by a child process. Must be called from within a tokio runtime.
+    pub fn from_std<Item, SinkItem, Codec>(
+        io: std::os::unix::net::UnixStream,
+        codec: Codec,
+    ) -> io::Result<Transport<UnixStream, Item, SinkItem, Codec>>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+    {
+        io.set_nonblocking(true)?;
+        let io = UnixStream::from_std(io)?;
+        Ok(new(LengthDelimitedCodec::builder().new_framed(io), codec))
+    }
+
+    /// A [`UnixListener`] that wraps connections in [transports](Transport).
+    #[pin_project]
+    #[derive(Debug)]
+    pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
+        listener: UnixListener,
+        local_addr: SocketAddr,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
+        /// Returns the the socket address being listened on.
+        pub fn local_addr(&self) -> &SocketAddr {
+            &self.local_addr
+        }
+
+        /// Returns an immutable reference to the length-delimited codec's config.
+        pub fn config(&self) -> &length_delimited::Builder {
+            &self.config
+        }
+
+        /// Returns a mutable reference to the length-delimited codec's config.
+        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+            &mut self.config
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Item = io::Result<Transport<UnixStream, Item, SinkItem, Codec>>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let conn: UnixStream = ready!(self.as_mut().project().listener.poll_accept(cx)?).0;
+            Poll::Ready(Some(Ok(new(
+                self.config.new_framed(conn),
+                (self.codec_fn)(),
+            ))))
+        }
+    }
+
+    /// A temporary `PathBuf` that lives in `std::env::temp_dir` and is removed on drop.
+    pub struct TempPathBuf(std::path::PathBuf);
+
+    impl TempPathBuf {
+        /// A named socket that results in `<tempdir>/<name>`
+        pub fn new<S: AsRef<str>>(name: S) -> Self {
+            let mut sock = std::env::temp_dir();
+            sock.push(name.as_ref());
+            Self(sock)
+        }
+
+        /// Appends a random hex string to the socket name resulting in
+        /// `<tempdir>/<name>_<xxxxx>`
+        pub fn with_random<S: AsRef<str>>(name: S) -> Self {
+            Self::new(format!("{}_{:016x}", name.as_ref(), rand::random::<u64>()))
+        }
+    }
+
+    impl AsRef<std::path::Path> for TempPathBuf {
+        fn as_ref(&self) -> &std::path::Path {
+            self.0.as_path()
+        }
+    }
+
+    impl Drop for TempPathBuf {
+        fn drop(&mut self) {
+            // This will remove the file pointed to by this PathBuf if it exists, however Err's can
+            // be returned such as attempting to remove a non-existing file, or one which we don't
+            // have permission to remove. In these cases the Err is swallowed
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use tokio_serde::formats::SymmetricalJson;
+
+        #[test]
+        fn temp_path_buf_non_random() {
+            let sock = TempPathBuf::new("test");
+            let mut good = std::env::temp_dir();
+            good.push("test");
+            assert_eq!(sock.as_ref(), good);
+            assert_eq!(sock.as_ref().file_name().unwrap(), "test");
+        }
+
+        #[test]
+        fn temp_path_buf_random() {
+            let sock = TempPathBuf::with_random("test");
+            let good = std::env::temp_dir();
+            assert!(sock.as_ref().starts_with(good));
+            // Since there are 16 random characters we just assert the file_name has the right name
+            // and starts with the correct string 'test_'
+            // file name: test_xxxxxxxxxxxxxxxx
+            // test  = 4
+            // _     = 1
+            // <hex> = 16
+            // total = 21
+            let fname = sock.as_ref().file_name().unwrap().to_string_lossy();
+            assert!(fname.starts_with("test_"));
+            assert_eq!(fname.len(), 21);
+        }
+
+        #[test]
+        fn temp_path_buf_non_existing() {
+            let sock = TempPathBuf::with_random("test");
+            let sock_path = std::path::PathBuf::from(sock.as_ref());
+
+            // No actual file has been created yet
+            assert!(!sock_path.exists());
+            // Should not panic
+            std::mem::drop(sock);
+            assert!(!sock_path.exists());
+        }
+
+        #[test]
+        fn temp_path_buf_existing_file() {
+            let sock = TempPathBuf::with_random("test");
+            let sock_path = std::path::PathBuf::from(sock.as_ref());
+            let _file = std::fs::File::create(&sock).unwrap();
+            assert!(sock_path.exists());
+            std::mem::drop(sock);
+            assert!(!sock_path.exists());
+        }
+
+        #[test]
+        fn temp_path_buf_preexisting_file() {
+            let mut pre_existing = std::env::temp_dir();
+            pre_existing.push("test");
+            let _file = std::fs::File::create(&pre_existing).unwrap();
+            let sock = TempPathBuf::new("test");
+            let sock_path = std::path::PathBuf::from(sock.as_ref());
+            assert!(sock_path.exists());
+            std::mem::drop(sock);
+            assert!(!sock_path.exists());
+        }
+
+        #[tokio::test]
+        async fn pair_transports_are_connected() -> io::Result<()> {
+            let (mut client, mut server) = pair(
+                SymmetricalJson::<String>::default(),
+                SymmetricalJson::<String>::default(),
+            )?;
+            client.send("ping".into()).await?;
+            assert_eq!(server.next().await.transpose()?, Some("ping".into()));
+            server.send("pong".into()).await?;
+            assert_eq!(client.next().await.transpose()?, Some("pong".into()));
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn from_std_wraps_inherited_sockets() -> io::Result<()> {
+            let (io, peer_io) = std::os::unix::net::UnixStream::pair()?;
+            let mut client = from_std(io, SymmetricalJson::<String>::default())?;
+            let mut server = from_std(peer_io, SymmetricalJson::<String>::default())?;
+            client.send("ping".into()).await?;
+            assert_eq!(server.next().await.transpose()?, Some("ping".into()));
+            Ok(())
+        }
+
+        #[cfg(all(
+            feature = "abstract-socket",
+            any(target_os = "linux", target_os = "android")
+        ))]
+        #[tokio::test]
+        async fn abstract_socket_has_no_file() -> io::Result<()> {
+            let name = format!("tarpc_test_{:016x}", rand::random::<u64>());
+            let mut incoming = listen_abstract(&name, SymmetricalJson::<String>::default).await?;
+            assert!(incoming.local_addr().as_pathname().is_none());
+
+            let mut client = connect_abstract(&name, SymmetricalJson::<String>::default).await?;
+            let mut server = incoming.next().await.unwrap()?;
+            client.send("ping".into()).await?;
+            assert_eq!(server.next().await.transpose()?, Some("ping".into()));
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn temp_path_buf_for_socket() {
+            let sock = TempPathBuf::with_random("test");
+            // Save path for testing after drop
+            let sock_path = std::path::PathBuf::from(sock.as_ref());
+            // create the actual socket
+            let _ = listen(&sock, SymmetricalJson::<String>::default).await;
+            assert!(sock_path.exists());
+            std::mem::drop(sock);
+            assert!(!sock_path.exists());
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "socket-activation"))]
+#[cfg_attr(docsrs, doc(cfg(all(unix, feature = "socket-activation"))))]
+/// Support for listeners passed to the process by the service manager, so that a daemon can be
+/// started on demand, on its first connection, and can listen on a privileged port without
+/// running as root.
+///
+/// On Linux, systemd passes the sockets of the daemon's socket units as file descriptors starting
+/// at 3, described by the `LISTEN_PID`, `LISTEN_FDS`, and `LISTEN_FDNAMES` environment variables.
+/// On macOS, launchd passes the sockets of an entry of the `Sockets` dictionary of the daemon's
+/// property list, looked up by the entry's name.
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use tarpc::serde_transport::socket_activation;
+/// use tokio_serde::formats::Json;
+///
+/// let incoming = socket_activation::listen(Json::<String, String>::default)?;
+/// # Ok(())
+/// # }
+/// ```
+pub mod socket_activation {
+    use {
+        super::*,
+        std::{
+            env,
+            net::TcpListener,
+            os::unix::io::{FromRawFd, RawFd},
+            process,
+        },
+    };
+
+    /// The first file descriptor passed by systemd.
+    const LISTEN_FDS_START: RawFd = 3;
+
+    /// Returns the listeners passed by systemd, each with the name of its socket, in the order
+    /// they were passed. Returns no listeners if the process wasn't started by socket activation.
+    ///
+    /// The environment variables describing the listeners are unset, so that child processes
+    /// don't mistake them for their own, and so the listeners are only returned once.
+    pub fn systemd_listeners() -> io::Result<Vec<(String, TcpListener)>> {
+        let pid = env::var("LISTEN_PID").ok();
+        let fds = env::var("LISTEN_FDS").ok();
+        let names = env::var("LISTEN_FDNAMES").ok();
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        parse_listen_fds(process::id(), pid, fds, names)?
+            .into_iter()
+            .map(|(name, fd)| Ok((name, adopt(fd)?)))
+            .collect()
+    }
+
+    /// Returns the listeners passed by launchd for the entry named `name` of the `Sockets`
+    /// dictionary of the daemon's property list.
+    #[cfg(target_os = "macos")]
+    #[cfg_attr(docsrs, doc(cfg(target_os = "macos")))]
+    pub fn launchd_listeners(name: &str) -> io::Result<Vec<TcpListener>> {
+        use std::{ffi::CString, os::raw::c_char, ptr, slice};
+
+        extern "C" {
+            fn launch_activate_socket(
+                name: *const c_char,
+                fds: *mut *mut libc::c_int,
+                cnt: *mut libc::size_t,
+            ) -> libc::c_int;
+        }
+
+        let name =
+            CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut fds = ptr::null_mut();
+        let mut cnt = 0;
+        // Safety: launchd allocates the array of fds, which is freed below.
+        let errno = unsafe { launch_activate_socket(name.as_ptr(), &mut fds, &mut cnt) };
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        // Safety: launchd returned an array of `cnt` fds.
+        let owned = unsafe { slice::from_raw_parts(fds, cnt) }.to_vec();
+        // Safety: the array was allocated with malloc, and isn't used again.
+        unsafe { libc::free(fds.cast()) };
+        owned.into_iter().map(adopt).collect()
+    }
+
+    /// Wraps connections accepted by the first listener passed by systemd in TCP transports.
+    /// Fails with [`NotFound`](io::ErrorKind::NotFound) if no listener was passed.
+    ///
+    /// Must be called within a Tokio runtime.
+    pub fn listen<Item, SinkItem, Codec, CodecFn>(
+        codec_fn: CodecFn,
+    ) -> io::Result<tcp::Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        let (_, listener) = systemd_listeners()?.into_iter().next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "no listener was passed by socket activation",
+            )
+        })?;
+        tcp::from_std(listener, codec_fn)
+    }
+
+    /// Returns the names and fds of the listeners described by the environment, if they were
+    /// passed to the process `pid`.
+    fn parse_listen_fds(
+        pid: u32,
+        listen_pid: Option<String>,
+        listen_fds: Option<String>,
+        listen_fdnames: Option<String>,
+    ) -> io::Result<Vec<(String, RawFd)>> {
+        let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+            (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+            _ => return Ok(vec![]),
+        };
+        let invalid = |var: &str, e: std::num::ParseIntError| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid {}: {}", var, e),
+            )
+        };
+        let listen_pid: u32 = listen_pid.parse().map_err(|e| invalid("LISTEN_PID", e))?;
+        if listen_pid != pid {
+            // The listeners were passed to the parent process.
+            return Ok(vec![]);
+        }
+        let listen_fds: RawFd = listen_fds.parse().map_err(|e| invalid("LISTEN_FDS", e))?;
+        let mut names = listen_fdnames
+            .as_deref()
+            .unwrap_or_default()
+            .split(':')
+            .filter(|name| !name.is_empty());
+        Ok((LISTEN_FDS_START..LISTEN_FDS_START + listen_fds)
+            .map(|fd| {
+                let name = names.next().unwrap_or("unknown").to_string();
+                (name, fd)
+            })
+            .collect())
+    }
+
+    /// Takes ownership of a listener passed to the process.
+    fn adopt(fd: RawFd) -> io::Result<TcpListener> {
+        // Safety: the fd was passed to the process, which doesn't use it for anything else.
+        // Unlike the fds opened by std, it isn't closed on exec unless the flag is set here.
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(TcpListener::from_raw_fd(fd))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use assert_matches::assert_matches;
+        use tokio_serde::formats::SymmetricalJson;
+
+        fn parse(
+            pid: Option<&str>,
+            fds: Option<&str>,
+            names: Option<&str>,
+        ) -> io::Result<Vec<(String, RawFd)>> {
+            parse_listen_fds(
+                7,
+                pid.map(String::from),
+                fds.map(String::from),
+                names.map(String::from),
+            )
+        }
+
+        #[test]
+        fn parse_listen_fds_with_names() {
+            assert_eq!(
+                parse(Some("7"), Some("2"), Some("rpc:admin")).unwrap(),
+                [("rpc".to_string(), 3), ("admin".to_string(), 4)]
+            );
+            assert_eq!(
+                parse(Some("7"), Some("2"), None).unwrap(),
+                [("unknown".to_string(), 3), ("unknown".to_string(), 4)]
+            );
+        }
+
+        #[test]
+        fn parse_listen_fds_of_other_process() {
+            assert_eq!(parse(None, None, None).unwrap(), []);
+            assert_eq!(parse(Some("8"), Some("1"), None).unwrap(), []);
+        }
+
+        #[test]
+        fn parse_listen_fds_invalid() {
+            assert_matches!(
+                parse(Some("seven"), Some("1"), None),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData
+            );
+            assert_matches!(
+                parse(Some("7"), Some("one"), None),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData
+            );
+        }
+
+        #[tokio::test]
+        async fn adopted_listener_accepts_connections() -> io::Result<()> {
+            use std::os::unix::io::IntoRawFd;
+
+            let fd = TcpListener::bind("localhost:0")?.into_raw_fd();
+            let listener = adopt(fd)?;
+            let mut incoming = tcp::from_std(listener, SymmetricalJson::<String>::default)?;
+            let addr = incoming.local_addr();
+            let mut client = tcp::connect(addr, SymmetricalJson::<String>::default).await?;
+            client.send("ping".to_string()).await?;
+            let mut server = incoming.next().await.unwrap()?;
+            assert_matches!(server.next().await, Some(Ok(ping)) if ping == "ping");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "vsock"))]
+#[cfg_attr(docsrs, doc(cfg(all(target_os = "linux", feature = "vsock"))))]
+/// [Virtio socket](https://man7.org/linux/man-pages/man7/vsock.7.html) support for generic
+/// transport using Tokio, for communication between virtual machines and their host.
+///
+/// Sockets are addressed by a context ID (CID), which identifies the machine, and a port.
+pub mod vsock {
+    pub use tokio_vsock::{SockAddr, VsockAddr};
+    use {
+        super::*,
+        futures::ready,
+        std::marker::PhantomData,
+        tokio_util::codec::length_delimited,
+        tokio_vsock::{VsockListener, VsockStream},
+    };
+
+    /// The CID to listen on to accept connections addressed to any CID of the local machine.
+    pub const VMADDR_CID_ANY: u32 = u32::MAX;
+    /// The CID of the host, from the point of view of a virtual machine.
+    pub const VMADDR_CID_HOST: u32 = 2;
+    /// The CID of the local machine, to connect to a listener on the same machine.
+    pub const VMADDR_CID_LOCAL: u32 = 1;
+
+    impl<Item, SinkItem, Codec> Transport<VsockStream, Item, SinkItem, Codec> {
+        /// Returns the socket address of the remote half of the underlying [`VsockStream`].
+        pub fn peer_addr(&self) -> io::Result<SockAddr> {
+            self.inner.get_ref().get_ref().peer_addr()
+        }
+        /// Returns the socket address of the local half of the underlying [`VsockStream`].
+        pub fn local_addr(&self) -> io::Result<SockAddr> {
+            self.inner.get_ref().get_ref().local_addr()
+        }
+    }
+
+    /// A connection Future that also exposes the length-delimited framing config.
+    #[must_use]
+    #[pin_project]
+    pub struct Connect<T, Item, SinkItem, CodecFn> {
+        #[pin]
+        inner: T,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        ghost: PhantomData<(fn(SinkItem), fn() -> Item)>,
+    }
+
+    impl<T, Item, SinkItem, Codec, CodecFn> Future for Connect<T, Item, SinkItem, CodecFn>
+    where
+        T: Future<Output = io::Result<VsockStream>>,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Output = io::Result<Transport<VsockStream, Item, SinkItem, Codec>>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            let io = ready!(self.as_mut().project().inner.poll(cx))?;
+            Poll::Ready(Ok(new(self.config.new_framed(io), (self.codec_fn)())))
+        }
+    }
+
+    impl<T, Item, SinkItem, CodecFn> Connect<T, Item, SinkItem, CodecFn> {
+        /// Returns an immutable reference to the length-delimited codec's config.
+        pub fn config(&self) -> &length_delimited::Builder {
+            &self.config
+        }
+
+        /// Returns a mutable reference to the length-delimited codec's config.
+        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+            &mut self.config
+        }
+    }
+
+    /// Connects to `port` of the machine identified by `cid`, wrapping the connection in a vsock
+    /// transport.
+    pub fn connect<Item, SinkItem, Codec, CodecFn>(
+        cid: u32,
+        port: u32,
+        codec_fn: CodecFn,
+    ) -> Connect<impl Future<Output = io::Result<VsockStream>>, Item, SinkItem, CodecFn>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        Connect {
+            inner: VsockStream::connect(cid, port),
+            codec_fn,
+            config: LengthDelimitedCodec::builder(),
+            ghost: PhantomData,
+        }
+    }
+
+    /// Listens on `port` for connections addressed to `cid`, wrapping accepted connections in
+    /// vsock transports.
+    pub async fn listen<Item, SinkItem, Codec, CodecFn>(
+        cid: u32,
+        port: u32,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        let listener = VsockListener::bind(cid, port)?;
+        let local_addr = listener.local_addr()?;
+        Ok(Incoming {
+            listener,
+            codec_fn,
+            local_addr,
+            config: LengthDelimitedCodec::builder(),
+            ghost: PhantomData,
+        })
+    }
+
+    /// A [`VsockListener`] that wraps connections in [transports](Transport).
+    #[pin_project]
+    #[derive(Debug)]
+    pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
+        listener: VsockListener,
+        local_addr: SockAddr,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
+        /// Returns the socket address being listened on.
+        pub fn local_addr(&self) -> &SockAddr {
+            &self.local_addr
+        }
+
+        /// Returns an immutable reference to the length-delimited codec's config.
+        pub fn config(&self) -> &length_delimited::Builder {
+            &self.config
+        }
+
+        /// Returns a mutable reference to the length-delimited codec's config.
+        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+            &mut self.config
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Item = io::Result<Transport<VsockStream, Item, SinkItem, Codec>>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let conn: VsockStream = ready!(self.as_mut().project().listener.poll_accept(cx)?).0;
+            Poll::Ready(Some(Ok(new(
+                self.config.new_framed(conn),
+                (self.codec_fn)(),
+            ))))
+        }
+    }
+}
+
+#[cfg(feature = "stdio")]
+#[cfg_attr(docsrs, doc(cfg(feature = "stdio")))]
+/// Support for transports over the standard input and output of a process, to drive a plugin
+/// subprocess the way editors drive language servers.
+///
+/// The parent process [spawns](stdio::spawn) the subprocess with a transport over the subprocess's stdin
+/// and stdout, and the subprocess serves requests over a transport over [its own](stdio::own) stdin and
+/// stdout. The subprocess's stderr is untouched, so it remains usable for logging.
+pub mod stdio {
+    use {
+        super::*,
+        futures::ready,
+        std::{fmt, process::Stdio},
+        tokio::{
+            io::{stdin, stdout, ReadBuf},
+            process::{Child, ChildStdin, ChildStdout, Command},
+        },
+    };
+
+    /// Reads from one stream and writes to another. The stream written to is dropped once shut
+    /// down, so that a pipe is closed.
+    #[pin_project]
+    pub struct Duplex<R, W> {
+        #[pin]
+        reader: R,
+        writer: Option<W>,
+    }
+
+    impl<R, W> Duplex<R, W> {
+        /// Returns the stream read from.
+        pub fn reader(&self) -> &R {
+            &self.reader
+        }
+
+        /// Returns the stream written to, unless it was shut down.
+        pub fn writer(&self) -> Option<&W> {
+            self.writer.as_ref()
+        }
+    }
+
+    impl<R, W> fmt::Debug for Duplex<R, W> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Duplex").finish()
+        }
+    }
+
+    impl<R: AsyncRead, W> AsyncRead for Duplex<R, W> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            self.project().reader.poll_read(cx, buf)
+        }
+    }
+
+    impl<R, W: Unpin> Duplex<R, W> {
+        fn writer_pin_mut(self: Pin<&mut Self>) -> io::Result<Pin<&mut W>> {
+            match self.project().writer {
+                Some(writer) => Ok(Pin::new(writer)),
+                None => Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "the stream was shut down",
+                )),
+            }
+        }
+    }
+
+    impl<R, W: AsyncWrite + Unpin> AsyncWrite for Duplex<R, W> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.writer_pin_mut()?.poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.writer_pin_mut()?.poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let writer = self.project().writer;
+            if let Some(w) = writer {
+                ready!(Pin::new(w).poll_shutdown(cx))?;
+                *writer = None;
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// The stdout and stdin of a subprocess.
+    pub type ChildStdio = Duplex<ChildStdout, ChildStdin>;
+
+    /// The stdin and stdout of the current process.
+    pub type OwnStdio = Duplex<tokio::io::Stdin, tokio::io::Stdout>;
+
+    /// Spawns `command` as a subprocess, wrapping its stdin and stdout in a transport. Returns the
+    /// subprocess along with the transport, so that the caller can wait for it to exit.
+    ///
+    /// Closing the transport closes the subprocess's stdin, which is expected to make it exit.
+    pub fn spawn<Item, SinkItem, Codec, CodecFn>(
+        command: &mut Command,
+        codec_fn: CodecFn,
+    ) -> io::Result<(Child, Transport<ChildStdio, Item, SinkItem, Codec>)>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: FnOnce() -> Codec,
+    {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let io = Duplex {
+            reader: child.stdout.take().expect("stdout is piped"),
+            writer: child.stdin.take(),
+        };
+        Ok((child, Transport::from((io, codec_fn()))))
+    }
+
+    /// Wraps the stdin and stdout of the current process in a transport.
+    ///
+    /// Nothing else may read from stdin or write to stdout while the transport is in use, as that
+    /// would corrupt the messages.
+    pub fn own<Item, SinkItem, Codec>(codec: Codec) -> Transport<OwnStdio, Item, SinkItem, Codec>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+    {
+        let io = Duplex {
+            reader: stdin(),
+            writer: Some(stdout()),
+        };
+        Transport::from((io, codec))
+    }
+}
+
+/// The accept loop shared by the listeners that perform a handshake on each connection before
+/// it can carry messages, e.g. a TLS or WebSocket handshake.
+#[cfg(any(feature = "tls", feature = "native-tls", feature = "websocket"))]
+mod handshake {
+    use {
+        super::*,
+        futures::{future::BoxFuture, stream::FuturesUnordered},
+        std::{fmt, net::SocketAddr, time::Duration},
+        tokio::net::{TcpListener, TcpStream, ToSocketAddrs},
+    };
+
+    /// How long a client has to complete its handshake, by default.
+    pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+    /// The maximum number of handshakes in progress at once, by default.
+    pub(crate) const DEFAULT_MAX_PENDING: usize = 1024;
+
+    /// Accepts the connections of a [`TcpListener`] and performs a handshake on each of them.
+    ///
+    /// Handshakes are performed concurrently, so a slow client does not delay other connections.
+    /// A handshake that doesn't complete within the timeout fails, and no more connections are
+    /// accepted while the maximum number of handshakes are in progress, so that clients stalling
+    /// their handshakes can't exhaust the server's sockets.
+    pub(crate) struct Handshakes<S> {
+        listener: TcpListener,
+        local_addr: SocketAddr,
+        pending: FuturesUnordered<BoxFuture<'static, io::Result<S>>>,
+        timeout: Duration,
+        max_pending: usize,
+    }
+
+    impl<S> fmt::Debug for Handshakes<S> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Handshakes")
+                .field("listener", &self.listener)
+                .field("local_addr", &self.local_addr)
+                .field("pending", &self.pending.len())
+                .field("timeout", &self.timeout)
+                .field("max_pending", &self.max_pending)
+                .finish()
+        }
+    }
+
+    impl<S> Handshakes<S> {
+        pub(crate) async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+            let listener = TcpListener::bind(addr).await?;
+            let local_addr = listener.local_addr()?;
+            Ok(Self {
+                listener,
+                local_addr,
+                pending: FuturesUnordered::new(),
+                timeout: DEFAULT_TIMEOUT,
+                max_pending: DEFAULT_MAX_PENDING,
+            })
+        }
+
+        pub(crate) fn local_addr(&self) -> SocketAddr {
+            self.local_addr
+        }
+
+        pub(crate) fn set_timeout(&mut self, timeout: Duration) {
+            self.timeout = timeout;
+        }
+
+        pub(crate) fn set_max_pending(&mut self, max_pending: usize) {
+            assert!(max_pending > 0, "max_pending must be greater than 0");
+            self.max_pending = max_pending;
+        }
+
+        /// Accepts connections while there's room for more handshakes, starting the handshake of
+        /// each with `handshake`, and returns the next connection whose handshake completed.
+        pub(crate) fn poll_next<F, Fut>(
+            &mut self,
+            cx: &mut Context<'_>,
+            mut handshake: F,
+        ) -> Poll<io::Result<S>>
+        where
+            F: FnMut(TcpStream) -> io::Result<Fut>,
+            Fut: Future<Output = io::Result<S>> + Send + 'static,
+        {
+            while self.pending.len() < self.max_pending {
+                let conn = match self.listener.poll_accept(cx) {
+                    Poll::Ready(conn) => conn?.0,
+                    Poll::Pending => break,
+                };
+                let handshake = tokio::time::timeout(self.timeout, handshake(conn)?);
+                self.pending.push(Box::pin(async move {
+                    handshake.await.unwrap_or_else(|_| {
+                        Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "the client didn't complete its handshake in time",
+                        ))
+                    })
+                }));
+            }
+            match ready!(self.pending.poll_next_unpin(cx)) {
+                Some(conn) => Poll::Ready(conn),
+                // The listener is registered to wake this task on the next connection.
+                None => Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tls")))]
+/// TLS support for generic transport using [rustls](https://docs.rs/rustls) over TCP.
+pub mod tls {
+    pub use tokio_rustls::rustls;
+    use {
+        super::*,
+        crate::server::PeerIdentity,
+        handshake::Handshakes,
+        rustls::{ClientConfig, ServerConfig, ServerName},
+        std::{fmt, marker::PhantomData, net::SocketAddr, sync::Arc, time::Duration},
+        tcp::SocketOptions,
+        tokio::net::{TcpStream, ToSocketAddrs},
+        tokio_rustls::{client, server, TlsAcceptor, TlsConnector},
+        tokio_util::codec::length_delimited,
+    };
+
+    impl<Item, SinkItem, Codec> Transport<client::TlsStream<TcpStream>, Item, SinkItem, Codec> {
+        /// Returns the peer address of the underlying TcpStream.
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.get_ref().get_ref().get_ref().0.peer_addr()
+        }
+        /// Returns the local address of the underlying TcpStream.
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.get_ref().get_ref().get_ref().0.local_addr()
+        }
+    }
+
+    impl<Item, SinkItem, Codec> Transport<server::TlsStream<TcpStream>, Item, SinkItem, Codec> {
+        /// Returns the peer address of the underlying TcpStream.
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.get_ref().get_ref().get_ref().0.peer_addr()
+        }
+        /// Returns the local address of the underlying TcpStream.
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.get_ref().get_ref().get_ref().0.local_addr()
+        }
+        /// Returns the certificate chain presented by the client, if the server config requests
+        /// client authentication.
+        pub fn peer_certificates(&self) -> Option<&[rustls::Certificate]> {
+            self.inner
+                .get_ref()
+                .get_ref()
+                .get_ref()
+                .1
+                .peer_certificates()
+        }
+        /// Returns the identity of the client, read from the certificate it authenticated with, or
+        /// None if the server config doesn't request client authentication. A channel
+        /// [given](crate::server::BaseChannel::with_peer_identity) the identity makes it available
+        /// to request handlers.
+        pub fn peer_identity(&self) -> io::Result<Option<PeerIdentity>> {
+            self.peer_certificates()
+                .and_then(|chain| chain.first())
+                .map(|certificate| PeerIdentity::from_der(&certificate.0))
+                .transpose()
+        }
+    }
+
+    /// A connection Future that also exposes the length-delimited framing config.
+    #[must_use]
+    #[pin_project]
+    pub struct Connect<T, Item, SinkItem, CodecFn> {
+        #[pin]
+        inner: T,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        ghost: PhantomData<(fn(SinkItem), fn() -> Item)>,
+    }
+
+    impl<T, Item, SinkItem, Codec, CodecFn> Future for Connect<T, Item, SinkItem, CodecFn>
+    where
+        T: Future<Output = io::Result<client::TlsStream<TcpStream>>>,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Output = io::Result<Transport<client::TlsStream<TcpStream>, Item, SinkItem, Codec>>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            let io = ready!(self.as_mut().project().inner.poll(cx))?;
+            Poll::Ready(Ok(new(self.config.new_framed(io), (self.codec_fn)())))
+        }
+    }
+
+    impl<T, Item, SinkItem, CodecFn> Connect<T, Item, SinkItem, CodecFn> {
+        /// Returns an immutable reference to the length-delimited codec's config.
+        pub fn config(&self) -> &length_delimited::Builder {
+            &self.config
+        }
+
+        /// Returns a mutable reference to the length-delimited codec's config.
+        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+            &mut self.config
+        }
+    }
+
+    /// Connects to `addr`, verifying the server's certificate for `domain`, and wraps the
+    /// connection in a TLS transport.
+    pub fn connect<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        domain: ServerName,
+        tls_config: Arc<ClientConfig>,
+        codec_fn: CodecFn,
+    ) -> Connect<
+        impl Future<Output = io::Result<client::TlsStream<TcpStream>>>,
+        Item,
+        SinkItem,
+        CodecFn,
+    >
+    where
+        A: ToSocketAddrs,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        connect_with(
+            addr,
+            domain,
+            tls_config,
+            codec_fn,
+            LengthDelimitedCodec::builder(),
+            SocketOptions::default(),
+        )
+    }
+
+    pub(crate) fn connect_with<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        domain: ServerName,
+        tls_config: Arc<ClientConfig>,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        options: SocketOptions,
+    ) -> Connect<
+        impl Future<Output = io::Result<client::TlsStream<TcpStream>>>,
+        Item,
+        SinkItem,
+        CodecFn,
+    >
+    where
+        A: ToSocketAddrs,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        Connect {
+            inner: async move {
+                let conn = TcpStream::connect(addr).await?;
+                options.apply(&conn)?;
+                TlsConnector::from(tls_config).connect(domain, conn).await
+            },
+            codec_fn,
+            config,
+            ghost: PhantomData,
+        }
+    }
+
+    /// Listens on `addr`, wrapping accepted connections in TLS transports.
+    pub async fn listen<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        tls_config: Arc<ServerConfig>,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        A: ToSocketAddrs,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        listen_with(
+            addr,
+            tls_config,
+            codec_fn,
+            LengthDelimitedCodec::builder(),
+            SocketOptions::default(),
+        )
+        .await
+    }
+
+    pub(crate) async fn listen_with<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        tls_config: Arc<ServerConfig>,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        options: SocketOptions,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        A: ToSocketAddrs,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        Ok(Incoming {
+            handshakes: Handshakes::bind(addr).await?,
+            acceptor: TlsAcceptor::from(tls_config),
+            codec_fn,
+            config,
+            options,
+            ghost: PhantomData,
+        })
+    }
+
+    /// A [`TcpListener`](tokio::net::TcpListener) that wraps connections in TLS
+    /// [transports](Transport).
+    ///
+    /// TLS handshakes are performed concurrently, so a slow client does not delay other
+    /// connections. A failed handshake is yielded as an error; the listener keeps accepting
+    /// connections afterwards. Clients have 10 seconds to complete their handshakes, and at most
+    /// 1024 handshakes are in progress at once; see [`Incoming::with_handshake_timeout`] and
+    /// [`Incoming::with_max_pending_handshakes`].
+    #[pin_project]
+    pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
+        handshakes: Handshakes<server::TlsStream<TcpStream>>,
+        acceptor: TlsAcceptor,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        options: SocketOptions,
+        ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> fmt::Debug for Incoming<Item, SinkItem, Codec, CodecFn> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Incoming")
+                .field("handshakes", &self.handshakes)
+                .finish()
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
+        /// Returns the address being listened on.
+        pub fn local_addr(&self) -> SocketAddr {
+            self.handshakes.local_addr()
+        }
+
+        /// Returns an immutable reference to the length-delimited codec's config.
+        pub fn config(&self) -> &length_delimited::Builder {
+            &self.config
+        }
+
+        /// Returns a mutable reference to the length-delimited codec's config.
+        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+            &mut self.config
+        }
+
+        /// Sets how long a client has to complete its TLS handshake before its connection is
+        /// dropped.
+        pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+            self.handshakes.set_timeout(timeout);
+            self
+        }
+
+        /// Sets the maximum number of TLS handshakes in progress at once. Once reached, no more
+        /// connections are accepted until a handshake completes.
+        ///
+        /// # Panics
+        ///
+        /// If `max_pending_handshakes` is zero.
+        pub fn with_max_pending_handshakes(mut self, max_pending_handshakes: usize) -> Self {
+            self.handshakes.set_max_pending(max_pending_handshakes);
+            self
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Item = io::Result<Transport<server::TlsStream<TcpStream>, Item, SinkItem, Codec>>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let this = self.project();
+            let (acceptor, options) = (&*this.acceptor, &*this.options);
+            let conn = ready!(this.handshakes.poll_next(cx, |conn| {
+                options.apply(&conn)?;
+                Ok(acceptor.accept(conn))
+            }));
+            Poll::Ready(Some(
+                conn.map(|conn| new(this.config.new_framed(conn), (this.codec_fn)())),
+            ))
+        }
+    }
+}
+
+#[cfg(feature = "native-tls")]
+#[cfg_attr(docsrs, doc(cfg(feature = "native-tls")))]
+/// TLS support for generic transport using [native-tls](https://docs.rs/native-tls) over TCP,
+/// which uses the platform's TLS implementation and trust store.
+pub mod native_tls {
+    pub use tokio_native_tls::native_tls;
+    use {
+        super::*,
+        crate::server::PeerIdentity,
+        handshake::Handshakes,
+        std::{fmt, marker::PhantomData, net::SocketAddr, time::Duration},
+        tokio::net::{TcpStream, ToSocketAddrs},
+        tokio_native_tls::{TlsAcceptor, TlsConnector, TlsStream},
+        tokio_util::codec::length_delimited,
+    };
+
+    impl<Item, SinkItem, Codec> Transport<TlsStream<TcpStream>, Item, SinkItem, Codec> {
+        /// Returns the peer address of the underlying TcpStream.
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.tcp_stream().peer_addr()
+        }
+        /// Returns the local address of the underlying TcpStream.
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.tcp_stream().local_addr()
+        }
+        /// Returns the certificate presented by the peer, if any.
+        pub fn peer_certificate(&self) -> io::Result<Option<native_tls::Certificate>> {
+            self.inner
+                .get_ref()
+                .get_ref()
+                .get_ref()
+                .peer_certificate()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        }
+        /// Returns the identity of the peer, read from the certificate it presented, if any. A
+        /// channel [given](crate::server::BaseChannel::with_peer_identity) the identity of a client
+        /// makes it available to request handlers.
+        pub fn peer_identity(&self) -> io::Result<Option<PeerIdentity>> {
+            match self.peer_certificate()? {
+                Some(certificate) => {
+                    let der = certificate
+                        .to_der()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    PeerIdentity::from_der(&der).map(Some)
+                }
+                None => Ok(None),
+            }
+        }
+
+        fn tcp_stream(&self) -> &TcpStream {
+            self.inner.get_ref().get_ref().get_ref().get_ref().get_ref()
+        }
+    }
+
+    /// A connection Future that also exposes the length-delimited framing config.
+    #[must_use]
+    #[pin_project]
+    pub struct Connect<T, Item, SinkItem, CodecFn> {
+        #[pin]
+        inner: T,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        ghost: PhantomData<(fn(SinkItem), fn() -> Item)>,
+    }
+
+    impl<T, Item, SinkItem, Codec, CodecFn> Future for Connect<T, Item, SinkItem, CodecFn>
+    where
+        T: Future<Output = io::Result<TlsStream<TcpStream>>>,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Output = io::Result<Transport<TlsStream<TcpStream>, Item, SinkItem, Codec>>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            let io = ready!(self.as_mut().project().inner.poll(cx))?;
+            Poll::Ready(Ok(new(self.config.new_framed(io), (self.codec_fn)())))
+        }
+    }
+
+    impl<T, Item, SinkItem, CodecFn> Connect<T, Item, SinkItem, CodecFn> {
+        /// Returns an immutable reference to the length-delimited codec's config.
+        pub fn config(&self) -> &length_delimited::Builder {
+            &self.config
+        }
+
+        /// Returns a mutable reference to the length-delimited codec's config.
+        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+            &mut self.config
+        }
+    }
+
+    /// Connects to `addr`, verifying the server's certificate for `domain`, and wraps the
+    /// connection in a TLS transport.
+    pub fn connect<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        domain: String,
+        connector: native_tls::TlsConnector,
+        codec_fn: CodecFn,
+    ) -> Connect<impl Future<Output = io::Result<TlsStream<TcpStream>>>, Item, SinkItem, CodecFn>
+    where
+        A: ToSocketAddrs,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        Connect {
+            inner: async move {
+                let conn = TcpStream::connect(addr).await?;
+                TlsConnector::from(connector)
+                    .connect(&domain, conn)
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            },
+            codec_fn,
+            config: LengthDelimitedCodec::builder(),
+            ghost: PhantomData,
+        }
+    }
+
+    /// Listens on `addr`, wrapping accepted connections in TLS transports.
+    pub async fn listen<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        acceptor: native_tls::TlsAcceptor,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        A: ToSocketAddrs,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        Ok(Incoming {
+            handshakes: Handshakes::bind(addr).await?,
+            acceptor: TlsAcceptor::from(acceptor),
+            codec_fn,
+            config: LengthDelimitedCodec::builder(),
+            ghost: PhantomData,
+        })
+    }
+
+    /// A [`TcpListener`](tokio::net::TcpListener) that wraps connections in TLS
+    /// [transports](Transport).
+    ///
+    /// TLS handshakes are performed concurrently, so a slow client does not delay other
+    /// connections. A failed handshake is yielded as an error; the listener keeps accepting
+    /// connections afterwards. Clients have 10 seconds to complete their handshakes, and at most
+    /// 1024 handshakes are in progress at once; see [`Incoming::with_handshake_timeout`] and
+    /// [`Incoming::with_max_pending_handshakes`].
+    #[pin_project]
+    pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
+        handshakes: Handshakes<TlsStream<TcpStream>>,
+        acceptor: TlsAcceptor,
+        codec_fn: CodecFn,
+        config: length_delimited::Builder,
+        ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> fmt::Debug for Incoming<Item, SinkItem, Codec, CodecFn> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Incoming")
+                .field("handshakes", &self.handshakes)
+                .finish()
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
+        /// Returns the address being listened on.
+        pub fn local_addr(&self) -> SocketAddr {
+            self.handshakes.local_addr()
+        }
+
+        /// Returns an immutable reference to the length-delimited codec's config.
+        pub fn config(&self) -> &length_delimited::Builder {
+            &self.config
+        }
+
+        /// Returns a mutable reference to the length-delimited codec's config.
+        pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+            &mut self.config
+        }
+
+        /// Sets how long a client has to complete its TLS handshake before its connection is
+        /// dropped.
+        pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+            self.handshakes.set_timeout(timeout);
+            self
+        }
+
+        /// Sets the maximum number of TLS handshakes in progress at once. Once reached, no more
+        /// connections are accepted until a handshake completes.
+        ///
+        /// # Panics
+        ///
+        /// If `max_pending_handshakes` is zero.
+        pub fn with_max_pending_handshakes(mut self, max_pending_handshakes: usize) -> Self {
+            self.handshakes.set_max_pending(max_pending_handshakes);
+            self
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Item = io::Result<Transport<TlsStream<TcpStream>, Item, SinkItem, Codec>>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let this = self.project();
+            let acceptor = &*this.acceptor;
+            let conn = ready!(this.handshakes.poll_next(cx, |conn| {
+                let acceptor = acceptor.clone();
+                Ok(async move {
+                    acceptor
+                        .accept(conn)
+                        .await
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                })
+            }));
+            Poll::Ready(Some(
+                conn.map(|conn| new(this.config.new_framed(conn), (this.codec_fn)())),
+            ))
+        }
+    }
+}
+
+#[cfg(feature = "http-upgrade")]
+#[cfg_attr(docsrs, doc(cfg(feature = "http-upgrade")))]
+/// Support for establishing transports over an existing [hyper](https://docs.rs/hyper) server,
+/// so that RPC and regular HTTP traffic can share one port.
+///
+/// A connection is switched over to tarpc with an HTTP/1.1 `Upgrade: tarpc` request or an HTTP/2
+/// extended CONNECT request with `:protocol` set to `tarpc`. The latter requires the server to be
+/// built with `http2_enable_connect_protocol`. Clients created by [`http_upgrade::connect`] use
+/// HTTP/1.1.
+///
+/// Since axum handlers receive hyper requests, [`http_upgrade::accept`] can be called from an axum
+/// handler as well.
+pub mod http_upgrade {
+    use {
+        super::*,
+        hyper::{
+            client::connect::Connect,
+            header::{self, HeaderValue},
+            upgrade::Upgraded,
+            Body, Client, Method, Request, Response, StatusCode, Uri, Version,
+        },
+    };
+
+    /// The protocol name used in `Upgrade` headers and HTTP/2 `:protocol` pseudo-headers.
+    pub const PROTOCOL: &str = "tarpc";
+
+    /// Returns true if the request asks to switch the connection to a tarpc transport.
+    pub fn is_upgrade_request<B>(request: &Request<B>) -> bool {
+        if request.version() == Version::HTTP_2 {
+            return request.method() == Method::CONNECT
+                && request
+                    .extensions()
+                    .get::<hyper::ext::Protocol>()
+                    .map_or(false, |protocol| protocol.as_str() == PROTOCOL);
+        }
+        let has_token = |name, token: &str| {
+            request.headers().get_all(name).iter().any(|value| {
+                value.to_str().map_or(false, |value| {
+                    value
+                        .split(',')
+                        .any(|v| v.trim().eq_ignore_ascii_case(token))
+                })
+            })
+        };
+        has_token(header::CONNECTION, "upgrade") && has_token(header::UPGRADE, PROTOCOL)
+    }
+
+    /// Accepts a request to switch the connection to a tarpc transport.
+    ///
+    /// Returns the response to send back to the client, and a future that resolves to the
+    /// transport once hyper has handed over the connection. The future only completes after the
+    /// response has been sent, so it should be spawned or otherwise polled separately from the
+    /// request handler.
+    pub fn accept<B, Item, SinkItem, Codec, CodecFn>(
+        request: &mut Request<B>,
+        codec_fn: CodecFn,
+    ) -> io::Result<(
+        Response<Body>,
+        impl Future<Output = io::Result<Transport<Upgraded, Item, SinkItem, Codec>>>,
+    )>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: FnOnce() -> Codec,
+    {
+        if !is_upgrade_request(request) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "request is not a tarpc upgrade request",
+            ));
+        }
+        let mut response = Response::new(Body::empty());
+        if request.version() != Version::HTTP_2 {
+            *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+            let headers = response.headers_mut();
+            headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+            headers.insert(header::UPGRADE, HeaderValue::from_static(PROTOCOL));
+        }
+        let on_upgrade = hyper::upgrade::on(request);
+        let transport = async move {
+            let upgraded = on_upgrade
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            Ok(Transport::from((upgraded, codec_fn())))
+        };
+        Ok((response, transport))
+    }
+
+    /// Connects to a tarpc endpoint served by [`accept`] at the given uri, using an HTTP/1.1
+    /// upgrade.
+    pub async fn connect<C, Item, SinkItem, Codec, CodecFn>(
+        client: &Client<C>,
+        uri: Uri,
+        codec_fn: CodecFn,
+    ) -> io::Result<Transport<Upgraded, Item, SinkItem, Codec>>
+    where
+        C: Connect + Clone + Send + Sync + 'static,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: FnOnce() -> Codec,
+    {
+        let request = Request::builder()
+            .uri(uri)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, PROTOCOL)
+            .body(Body::empty())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let response = client
+            .request(request)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!(
+                    "server did not upgrade the connection: {}",
+                    response.status()
+                ),
+            ));
+        }
+        let upgraded = hyper::upgrade::on(response)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(Transport::from((upgraded, codec_fn())))
+    }
+}
+
+#[cfg(feature = "websocket")]
+#[cfg_attr(docsrs, doc(cfg(feature = "websocket")))]
+/// Support for transports over [WebSocket](https://docs.rs/tokio-tungstenite), so that services
+/// can be reached through HTTP-aware load balancers and proxies, and from browsers.
+///
+/// Each message is serialized into a single binary WebSocket message, without a length prefix.
+/// Text messages are rejected, and control messages are handled by the WebSocket implementation.
+pub mod websocket {
+    pub use tokio_tungstenite::tungstenite;
+    use {
+        super::*,
+        bytes::{Bytes, BytesMut},
+        futures::ready,
+        handshake::Handshakes,
+        std::{fmt, marker::PhantomData, net::SocketAddr, time::Duration},
+        tokio::net::{TcpStream, ToSocketAddrs},
+        tokio_tungstenite::{
+            tungstenite::{client::IntoClientRequest, Message},
+            MaybeTlsStream, WebSocketStream,
+        },
+    };
+
+    /// A transport that sends each message in a binary WebSocket message.
+    #[pin_project]
+    pub struct Transport<S, Item, SinkItem, Codec> {
+        #[pin]
+        inner: SerdeFramed<Messages<S>, Item, SinkItem, Codec>,
+    }
+
+    impl<S, Item, SinkItem, Codec> Transport<S, Item, SinkItem, Codec> {
+        /// Returns the inner WebSocket over which messages are sent and received.
+        pub fn get_ref(&self) -> &WebSocketStream<S> {
+            &self.inner.get_ref().0
+        }
+    }
+
+    impl<Item, SinkItem, Codec> Transport<TcpStream, Item, SinkItem, Codec> {
+        /// Returns the peer address of the underlying TcpStream.
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.get_ref().get_ref().peer_addr()
+        }
+        /// Returns the local address of the underlying TcpStream.
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.get_ref().get_ref().local_addr()
+        }
+    }
+
+    impl<S, Item, SinkItem, Codec> fmt::Debug for Transport<S, Item, SinkItem, Codec>
+    where
+        S: fmt::Debug,
+    {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_tuple("Transport").field(self.get_ref()).finish()
+        }
+    }
+
+    impl<S, Item, SinkItem, Codec> Stream for Transport<S, Item, SinkItem, Codec>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        Codec: Deserializer<Item>,
+        io::Error: From<Codec::Error>,
+    {
+        type Item = io::Result<Item>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
+            self.project().inner.poll_next(cx)
+        }
+    }
+
+    impl<S, Item, SinkItem, Codec> Sink<SinkItem> for Transport<S, Item, SinkItem, Codec>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        Codec: Serializer<SinkItem>,
+        Codec::Error: Into<io::Error>,
+    {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.project().inner.poll_ready(cx)
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+            self.project().inner.start_send(item)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.project().inner.poll_flush(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.project().inner.poll_close(cx)
+        }
+    }
+
+    /// Constructs a new transport from an established WebSocket and a serialization codec.
+    pub fn new<S, Item, SinkItem, Codec>(
+        websocket: WebSocketStream<S>,
+        codec: Codec,
+    ) -> Transport<S, Item, SinkItem, Codec>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+    {
+        Transport {
+            inner: SerdeFramed::new(Messages(websocket), codec),
+        }
+    }
+
+    /// Adapts a WebSocket to the stream of frames and sink of frames expected by tokio-serde.
+    #[derive(Debug)]
+    struct Messages<S>(WebSocketStream<S>);
+
+    fn into_io_error(e: tungstenite::Error) -> io::Error {
+        match e {
+            tungstenite::Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::Other, e),
+        }
+    }
+
+    impl<S> Stream for Messages<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        type Item = io::Result<BytesMut>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            loop {
+                return match ready!(self.0.poll_next_unpin(cx)) {
+                    Some(Ok(Message::Binary(message))) => {
+                        Poll::Ready(Some(Ok(BytesMut::from(&message[..]))))
+                    }
+                    Some(Ok(Message::Text(_))) => Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "received a text message; expected a binary message",
+                    )))),
+                    Some(Ok(Message::Close(_))) | None => Poll::Ready(None),
+                    Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                    Some(Err(
+                        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed,
+                    )) => Poll::Ready(None),
+                    Some(Err(e)) => Poll::Ready(Some(Err(into_io_error(e)))),
+                };
+            }
+        }
+    }
+
+    impl<S> Sink<Bytes> for Messages<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        type Error = io::Error;
+
+        fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.0.poll_ready_unpin(cx).map_err(into_io_error)
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> io::Result<()> {
+            self.0
+                .start_send_unpin(Message::Binary(item.to_vec()))
+                .map_err(into_io_error)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.0.poll_flush_unpin(cx).map_err(into_io_error)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.0.poll_close_unpin(cx).map_err(into_io_error)
+        }
+    }
+
+    /// Connects to the WebSocket endpoint at `request`, e.g. a `ws://` or `wss://` url, and wraps
+    /// the connection in a WebSocket transport.
+    pub async fn connect<R, Item, SinkItem, Codec, CodecFn>(
+        request: R,
+        codec_fn: CodecFn,
+    ) -> io::Result<Transport<MaybeTlsStream<TcpStream>, Item, SinkItem, Codec>>
+    where
+        R: IntoClientRequest + Unpin,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: FnOnce() -> Codec,
+    {
+        let (websocket, _) = tokio_tungstenite::connect_async(request)
+            .await
+            .map_err(into_io_error)?;
+        Ok(new(websocket, codec_fn()))
+    }
+
+    /// Performs the server side of the WebSocket handshake over `stream`, e.g. a connection
+    /// accepted by a listener owned by the caller, and wraps it in a WebSocket transport.
+    pub async fn accept<S, Item, SinkItem, Codec, CodecFn>(
+        stream: S,
+        codec_fn: CodecFn,
+    ) -> io::Result<Transport<S, Item, SinkItem, Codec>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: FnOnce() -> Codec,
+    {
+        let websocket = tokio_tungstenite::accept_async(stream)
+            .await
+            .map_err(into_io_error)?;
+        Ok(new(websocket, codec_fn()))
+    }
+
+    /// Listens on `addr`, wrapping accepted connections in WebSocket transports.
+    pub async fn listen<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        A: ToSocketAddrs,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        Ok(Incoming {
+            handshakes: Handshakes::bind(addr).await?,
+            codec_fn,
+            ghost: PhantomData,
+        })
+    }
+
+    /// A [`TcpListener`](tokio::net::TcpListener) that wraps connections in WebSocket
+    /// [transports](Transport).
+    ///
+    /// WebSocket handshakes are performed concurrently, so a slow client does not delay other
+    /// connections. A failed handshake is yielded as an error; the listener keeps accepting
+    /// connections afterwards. Clients have 10 seconds to complete their handshakes, and at most
+    /// 1024 handshakes are in progress at once; see [`Incoming::with_handshake_timeout`] and
+    /// [`Incoming::with_max_pending_handshakes`].
+    #[pin_project]
+    pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
+        handshakes: Handshakes<WebSocketStream<TcpStream>>,
+        codec_fn: CodecFn,
+        ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> fmt::Debug for Incoming<Item, SinkItem, Codec, CodecFn> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Incoming")
+                .field("handshakes", &self.handshakes)
+                .finish()
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
+        /// Returns the address being listened on.
+        pub fn local_addr(&self) -> SocketAddr {
+            self.handshakes.local_addr()
+        }
+
+        /// Sets how long a client has to complete its WebSocket handshake before its connection
+        /// is dropped.
+        pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+            self.handshakes.set_timeout(timeout);
+            self
+        }
+
+        /// Sets the maximum number of WebSocket handshakes in progress at once. Once reached, no
+        /// more connections are accepted until a handshake completes.
+        ///
+        /// # Panics
+        ///
+        /// If `max_pending_handshakes` is zero.
+        pub fn with_max_pending_handshakes(mut self, max_pending_handshakes: usize) -> Self {
+            self.handshakes.set_max_pending(max_pending_handshakes);
+            self
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
+    where
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Item = io::Result<Transport<TcpStream, Item, SinkItem, Codec>>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let this = self.project();
+            let websocket = ready!(this.handshakes.poll_next(cx, |conn| {
+                Ok(tokio_tungstenite::accept_async(conn).map_err(into_io_error))
+            }));
+            Poll::Ready(Some(
+                websocket.map(|websocket| new(websocket, (this.codec_fn)())),
+            ))
+        }
+    }
+}
+
+/// A message associated with a request, for transports that handle the messages of each request
+/// separately.
+#[cfg(any(feature = "quic", feature = "udp"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "quic", feature = "udp"))))]
+pub trait RequestMessage {
+    /// Returns the ID of the request the message is associated with, or None if the message isn't
+    /// part of a request.
+    fn request_id(&self) -> Option<u64>;
+
+    /// Returns true if the message is the first message of a request.
+    fn starts_request(&self) -> bool;
+
+    /// Returns true if no more messages are sent for the request after this message, by the
+    /// side sending it and by the side receiving it.
+    fn ends_request(&self) -> bool;
+}
+
+#[cfg(any(feature = "quic", feature = "udp"))]
+impl<T> RequestMessage for crate::ClientMessage<T> {
+    fn request_id(&self) -> Option<u64> {
+        crate::ClientMessage::request_id(self)
+    }
+
+    fn starts_request(&self) -> bool {
+        matches!(self, crate::ClientMessage::Request(_))
+    }
+
+    fn ends_request(&self) -> bool {
+        matches!(self, crate::ClientMessage::Cancel { .. })
+    }
+}
+
+#[cfg(any(feature = "quic", feature = "udp"))]
+impl<T> RequestMessage for crate::ServerMessage<T> {
+    fn request_id(&self) -> Option<u64> {
+        crate::ServerMessage::request_id(self)
+    }
+
+    fn starts_request(&self) -> bool {
+        false
+    }
+
+    fn ends_request(&self) -> bool {
+        matches!(
+            self,
+            crate::ServerMessage::Response(_) | crate::ServerMessage::StreamEnd { .. }
+        )
+    }
+}
+
+#[cfg(feature = "quic")]
+#[cfg_attr(docsrs, doc(cfg(feature = "quic")))]
+/// Support for transports over [QUIC](https://docs.rs/quinn), which provides TLS and connection
+/// migration.
+///
+/// Each channel maps to a QUIC connection, and the messages of each request are sent on their own
+/// unidirectional QUIC stream, in each direction. A large response therefore doesn't delay the
+/// messages of other requests, as it would on a single byte stream.
+pub mod quic {
+    pub use quinn;
+    use {
+        super::*,
+        bytes::Bytes,
+        fnv::FnvHashMap,
+        futures::{
+            future::BoxFuture,
+            ready,
+            stream::{FuturesUnordered, SelectAll},
+        },
+        quinn::{Connecting, Connection, ConnectionError, Endpoint, RecvStream},
+        std::{error::Error, fmt, marker::PhantomData, net::SocketAddr, sync::Arc},
+        tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+        tokio_util::{
+            codec::{FramedRead, FramedWrite},
+            sync::PollSemaphore,
+        },
+    };
+
+    /// The number of messages a [`Transport`] accepts before they are written to their streams.
+    /// Once reached, the transport stops accepting messages until the writes catch up.
+    pub const MAX_UNWRITTEN_MESSAGES: usize = 64;
+
+    type AcceptUni = BoxFuture<'static, Result<RecvStream, ConnectionError>>;
+
+    /// A frame waiting to be written, holding its share of the unwritten messages budget.
+    type Frame = (Bytes, OwnedSemaphorePermit);
+
+    /// A transport that sends the messages of each request on their own QUIC stream.
+    ///
+    /// Messages are handed to a task per request stream, which writes them in the background; the
+    /// connection closes once the transport is dropped and all messages are acknowledged. At most
+    /// [`MAX_UNWRITTEN_MESSAGES`] messages wait to be written at once, so the transport applies
+    /// backpressure when the peer doesn't keep up with the stream writes. A failed read of one of
+    /// the peer's streams is yielded as an error; the transport keeps reading the other streams
+    /// afterwards.
+    #[pin_project]
+    pub struct Transport<Item, SinkItem, Codec> {
+        connection: Connection,
+        /// Accepts the next stream opened by the peer. None once the connection is closed.
+        accept: Option<AcceptUni>,
+        /// Reads frames off the streams opened by the peer.
+        streams: SelectAll<FramedRead<RecvStream, LengthDelimitedCodec>>,
+        /// Forwards frames to the tasks writing the streams of this side, by request ID.
+        requests: FnvHashMap<u64, mpsc::Sender<Frame>>,
+        /// Holds a permit for each message that can be accepted before the writes catch up.
+        unwritten: PollSemaphore,
+        /// The permit acquired by the last call to `poll_ready`, for the next message sent.
+        permit: Option<OwnedSemaphorePermit>,
+        #[pin]
+        codec: Codec,
+        ghost: PhantomData<(fn() -> Item, fn(SinkItem))>,
+    }
+
+    impl<Item, SinkItem, Codec> Transport<Item, SinkItem, Codec> {
+        /// Returns the QUIC connection over which messages are sent and received.
+        pub fn connection(&self) -> &Connection {
+            &self.connection
+        }
+
+        /// Returns the current address of the peer, which changes if the peer migrates.
+        pub fn peer_addr(&self) -> SocketAddr {
+            self.connection.remote_address()
+        }
+    }
+
+    impl<Item, SinkItem, Codec> fmt::Debug for Transport<Item, SinkItem, Codec> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Transport")
+                .field("peer_addr", &self.peer_addr())
+                .field("streams", &self.streams.len())
+                .field("requests", &self.requests.len())
+                .field(
+                    "unwritten",
+                    &(MAX_UNWRITTEN_MESSAGES - self.unwritten.available_permits()),
+                )
+                .finish()
+        }
+    }
+
+    fn accept_uni(connection: &Connection) -> AcceptUni {
+        let connection = connection.clone();
+        Box::pin(async move { connection.accept_uni().await })
+    }
+
+    async fn write_request(connection: Connection, mut frames: mpsc::Receiver<Frame>) {
+        let write = async {
+            let stream = connection.open_uni().await?;
+            let mut stream = FramedWrite::new(stream, LengthDelimitedCodec::new());
+            // The permit of a frame is released once QUIC's flow control accepted the frame.
+            while let Some((frame, _permit)) = frames.recv().await {
+                stream.send(frame).await?;
+            }
+            stream.into_inner().finish().await?;
+            Ok::<_, io::Error>(())
+        };
+        if let Err(e) = write.await {
+            tracing::warn!("Failed to write request stream: {}", e);
+        }
+    }
+
+    /// Constructs a new transport from an established QUIC connection and a serialization codec.
+    pub fn new<Item, SinkItem, Codec>(
+        connection: Connection,
+        codec: Codec,
+    ) -> Transport<Item, SinkItem, Codec>
+    where
+        Item: RequestMessage,
+        SinkItem: RequestMessage,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+    {
+        Transport {
+            accept: Some(accept_uni(&connection)),
+            connection,
+            streams: SelectAll::new(),
+            requests: FnvHashMap::default(),
+            unwritten: PollSemaphore::new(Arc::new(Semaphore::new(MAX_UNWRITTEN_MESSAGES))),
+            permit: None,
+            codec,
+            ghost: PhantomData,
+        }
+    }
+
+    impl<Item, SinkItem, Codec> Stream for Transport<Item, SinkItem, Codec>
+    where
+        Item: RequestMessage,
+        Codec: Deserializer<Item>,
+        Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        type Item = io::Result<Item>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
+            let mut this = self.project();
+            while let Some(accept) = this.accept {
+                match accept.poll_unpin(cx) {
+                    Poll::Ready(Ok(stream)) => {
+                        this.streams
+                            .push(FramedRead::new(stream, LengthDelimitedCodec::new()));
+                        *accept = accept_uni(this.connection);
+                    }
+                    Poll::Ready(Err(
+                        ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed,
+                    )) => *this.accept = None,
+                    Poll::Ready(Err(e)) => {
+                        *this.accept = None;
+                        return Poll::Ready(Some(Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            e,
+                        ))));
+                    }
+                    Poll::Pending => break,
+                }
+            }
+            loop {
+                if this.streams.is_empty() {
+                    return match this.accept {
+                        Some(_) => Poll::Pending,
+                        None => Poll::Ready(None),
+                    };
+                }
+                let frame = match ready!(this.streams.poll_next_unpin(cx)) {
+                    Some(Ok(frame)) => frame,
+                    // Only the request whose stream failed is affected, so the other streams are
+                    // still read on the next poll.
+                    Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                    None => continue,
+                };
+                let item = this
+                    .codec
+                    .as_mut()
+                    .deserialize(&frame)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                if let (true, Some(request_id)) = (item.ends_request(), item.request_id()) {
+                    this.requests.remove(&request_id);
+                }
+                return Poll::Ready(Some(Ok(item)));
+            }
+        }
+    }
+
+    impl<Item, SinkItem, Codec> Sink<SinkItem> for Transport<Item, SinkItem, Codec>
+    where
+        SinkItem: RequestMessage,
+        Codec: Serializer<SinkItem>,
+        Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let this = self.project();
+            if let Some(e) = this.connection.close_reason() {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionAborted, e)));
+            }
+            if this.permit.is_none() {
+                // The semaphore is never closed.
+                *this.permit = ready!(this.unwritten.poll_acquire(cx));
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+            let this = self.project();
+            let permit = this
+                .permit
+                .take()
+                .expect("poll_ready must be called before start_send");
+            let frame = this
+                .codec
+                .serialize(&item)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            let connection = this.connection;
+            let open_stream = || {
+                // A request can't have more frames waiting than the transport as a whole.
+                let (frames_tx, frames) = mpsc::channel(MAX_UNWRITTEN_MESSAGES);
+                tokio::spawn(write_request(connection.clone(), frames));
+                frames_tx
+            };
+            let request_id = match item.request_id() {
+                Some(request_id) => request_id,
+                // A message that isn't part of a request is sent on a stream of its own.
+                None => {
+                    let _ = open_stream().try_send((frame, permit));
+                    return Ok(());
+                }
+            };
+            let request = this.requests.entry(request_id).or_insert_with(open_stream);
+            // The channel can't be full, since each frame in it holds a permit. The task only
+            // stops early if the stream failed, which it already logged.
+            let _ = request.try_send((frame, permit));
+            if item.ends_request() {
+                // Finishes the stream once the task has written the frames sent so far.
+                this.requests.remove(&request_id);
+            }
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            // The frames sent so far are written once the permits they hold are all released.
+            let this = self.project();
+            let released = MAX_UNWRITTEN_MESSAGES - usize::from(this.permit.is_some());
+            ready!(this.unwritten.poll_acquire_many(cx, released as u32));
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.project().requests.clear();
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Connects to `addr` using `endpoint`, verifying the server's certificate for `server_name`,
+    /// and wraps the connection in a QUIC transport. The endpoint must have a default client
+    /// config.
+    pub async fn connect<Item, SinkItem, Codec, CodecFn>(
+        endpoint: &Endpoint,
+        addr: SocketAddr,
+        server_name: &str,
+        codec_fn: CodecFn,
+    ) -> io::Result<Transport<Item, SinkItem, Codec>>
+    where
+        Item: RequestMessage,
+        SinkItem: RequestMessage,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: FnOnce() -> Codec,
+    {
+        let connection = endpoint
+            .connect(addr, server_name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?;
+        Ok(new(connection, codec_fn()))
+    }
+
+    /// Listens on `addr`, wrapping accepted connections in QUIC transports.
+    pub fn listen<Item, SinkItem, Codec, CodecFn>(
+        addr: SocketAddr,
+        config: quinn::ServerConfig,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        Item: RequestMessage,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        let endpoint = Endpoint::server(config, addr)?;
+        Ok(Incoming {
+            local_addr: endpoint.local_addr()?,
+            accept: Some(accept(&endpoint)),
+            endpoint,
+            handshakes: FuturesUnordered::new(),
+            codec_fn,
+            ghost: PhantomData,
+        })
+    }
+
+    type Accept = BoxFuture<'static, Option<Connecting>>;
+
+    fn accept(endpoint: &Endpoint) -> Accept {
+        let endpoint = endpoint.clone();
+        Box::pin(async move { endpoint.accept().await })
+    }
+
+    /// A QUIC [`Endpoint`] that wraps connections in QUIC [transports](Transport).
+    ///
+    /// Handshakes are performed concurrently, so a slow client does not delay other connections. A
+    /// failed handshake is yielded as an error; the listener keeps accepting connections
+    /// afterwards.
+    #[pin_project]
+    pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
+        endpoint: Endpoint,
+        local_addr: SocketAddr,
+        /// Accepts the next connection. None once the endpoint is closed.
+        accept: Option<Accept>,
+        handshakes: FuturesUnordered<Connecting>,
+        codec_fn: CodecFn,
+        ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> fmt::Debug for Incoming<Item, SinkItem, Codec, CodecFn> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Incoming")
+                .field("local_addr", &self.local_addr)
+                .field("handshakes", &self.handshakes.len())
+                .finish()
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
+        /// Returns the address being listened on.
+        pub fn local_addr(&self) -> SocketAddr {
+            self.local_addr
+        }
+
+        /// Returns the endpoint accepting connections, e.g. to close it.
+        pub fn endpoint(&self) -> &Endpoint {
+            &self.endpoint
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
+    where
+        Item: RequestMessage,
+        SinkItem: RequestMessage,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Item = io::Result<Transport<Item, SinkItem, Codec>>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let this = self.project();
+            while let Some(next) = this.accept {
+                match next.poll_unpin(cx) {
+                    Poll::Ready(Some(connecting)) => {
+                        this.handshakes.push(connecting);
+                        *next = accept(this.endpoint);
+                    }
+                    Poll::Ready(None) => *this.accept = None,
+                    Poll::Pending => break,
+                }
+            }
+            match ready!(this.handshakes.poll_next_unpin(cx)) {
+                Some(connection) => Poll::Ready(Some(
+                    connection
+                        .map(|connection| new(connection, (this.codec_fn)()))
+                        .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e)),
+                )),
+                None if this.accept.is_none() => Poll::Ready(None),
+                // The endpoint is registered to wake this task on the next connection.
+                None => Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "udp")]
+#[cfg_attr(docsrs, doc(cfg(feature = "udp")))]
+/// Support for best-effort transports over UDP, for small RPCs where the cost of setting up a
+/// connection dominates, e.g. service discovery.
+///
+/// Each message is sent in a single datagram, so messages larger than
+/// [`MAX_DATAGRAM_SIZE`](udp::MAX_DATAGRAM_SIZE) can't be sent. Datagrams can be lost,
+/// duplicated, or reordered; responses are matched to requests by request ID, and clients can
+/// retransmit unanswered requests with
+/// [`Transport::with_retransmit`](udp::Transport::with_retransmit). As a server may then process
+/// a request more than once, only idempotent requests should be sent over UDP.
+///
+/// A listener demultiplexes the datagrams it receives into a transport per peer address. The
+/// transport of a peer ends once no datagrams were received from the peer for
+/// [`IDLE_TIMEOUT`](udp::IDLE_TIMEOUT); a later datagram from the peer starts a new transport.
+/// Datagrams from new peers are dropped while the listener tracks
+/// [`Incoming::set_max_peers`](udp::Incoming::set_max_peers) peers.
+pub mod udp {
+    use {
+        super::*,
+        bytes::{Bytes, BytesMut},
+        fnv::FnvHashMap,
+        futures::{
+            future::{self, Either},
+            pin_mut, ready,
+        },
+        std::{
+            collections::{hash_map, VecDeque},
+            convert::TryFrom,
+            error::Error,
+            fmt,
+            marker::PhantomData,
+            net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+            time::Duration,
+        },
+        tokio::{
+            io::ReadBuf,
+            net::{lookup_host, ToSocketAddrs, UdpSocket},
+            sync::mpsc::{self, error::TrySendError},
+            time::{sleep, Instant, Sleep},
+        },
+        tokio_util::time::delay_queue::{self, DelayQueue},
+    };
+
+    /// The size of the largest message that fits in a UDP datagram.
+    pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+    /// How long the transport of a peer lasts without receiving datagrams from the peer.
+    pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// The number of datagrams buffered per peer before a listener drops datagrams from the peer.
+    const PEER_BUFFER: usize = 100;
+
+    /// The default maximum number of peers a listener tracks at once.
+    pub const DEFAULT_MAX_PEERS: usize = 10_000;
+
+    /// Settings for retransmitting requests that aren't answered.
+    #[derive(Clone, Debug)]
+    #[non_exhaustive]
+    pub struct Retransmit {
+        /// How long to wait for a message answering a request before retransmitting the request.
+        /// The wait doubles after each retransmission.
+        pub interval: Duration,
+        /// The most times a request is retransmitted.
+        pub max_retransmits: usize,
+    }
+
+    impl Default for Retransmit {
+        fn default() -> Self {
+            Retransmit {
+                interval: Duration::from_millis(200),
+                max_retransmits: 3,
+            }
+        }
+    }
+
+    /// Tracks the requests that aren't answered yet, to retransmit them.
+    #[derive(Debug)]
+    struct Retransmits {
+        settings: Retransmit,
+        unanswered: FnvHashMap<u64, Unanswered>,
+        timers: DelayQueue<u64>,
+    }
+
+    #[derive(Debug)]
+    struct Unanswered {
+        datagram: Bytes,
+        retransmits: usize,
+        timer: delay_queue::Key,
+    }
+
+    impl Retransmits {
+        fn new(settings: Retransmit) -> Self {
+            Retransmits {
+                settings,
+                unanswered: FnvHashMap::default(),
+                timers: DelayQueue::new(),
+            }
+        }
+
+        /// Retransmits `datagram` until the request is answered.
+        fn insert(&mut self, request_id: u64, datagram: Bytes) {
+            let timer = self.timers.insert(request_id, self.settings.interval);
+            let unanswered = Unanswered {
+                datagram,
+                retransmits: 0,
+                timer,
+            };
+            if let Some(old) = self.unanswered.insert(request_id, unanswered) {
+                self.timers.remove(&old.timer);
+            }
+        }
+
+        /// Stops retransmitting a request.
+        fn remove(&mut self, request_id: u64) {
+            if let Some(unanswered) = self.unanswered.remove(&request_id) {
+                self.timers.remove(&unanswered.timer);
+            }
+        }
+
+        /// Yields the datagram of the next request due to be retransmitted.
+        fn poll_due(&mut self, cx: &mut Context<'_>) -> Poll<Bytes> {
+            loop {
+                if self.timers.is_empty() {
+                    // The transport is polled again after requests are sent, which registers
+                    // their timers.
+                    return Poll::Pending;
+                }
+                let request_id = match ready!(self.timers.poll_expired(cx)) {
+                    Some(expired) => expired.into_inner(),
+                    None => return Poll::Pending,
+                };
+                let mut entry = match self.unanswered.entry(request_id) {
+                    hash_map::Entry::Occupied(entry) => entry,
+                    hash_map::Entry::Vacant(_) => continue,
+                };
+                if entry.get().retransmits >= self.settings.max_retransmits {
+                    entry.remove();
+                    continue;
+                }
+                let unanswered = entry.get_mut();
+                unanswered.retransmits += 1;
+                let backoff =
+                    2u32.saturating_pow(u32::try_from(unanswered.retransmits).unwrap_or(u32::MAX));
+                unanswered.timer = self
+                    .timers
+                    .insert(request_id, self.settings.interval.saturating_mul(backoff));
+                tracing::debug!("Retransmitting request {}.", request_id);
+                return Poll::Ready(unanswered.datagram.clone());
+            }
+        }
+    }
+
+    /// Where a transport receives datagrams from.
+    enum Datagrams {
+        /// A socket connected to the peer.
+        Socket { buf: Box<[u8]> },
+        /// A listener demultiplexing the datagrams of its peers.
+        Listener {
+            datagrams: mpsc::Receiver<BytesMut>,
+            idle: Pin<Box<Sleep>>,
+        },
+    }
+
+    /// Sends datagrams to the peer.
+    #[derive(Debug)]
+    struct Outgoing {
+        socket: Arc<UdpSocket>,
+        peer_addr: SocketAddr,
+        /// Whether the socket is connected to the peer, rather than shared by a listener.
+        connected: bool,
+        datagrams: VecDeque<Bytes>,
+    }
+
+    impl Outgoing {
+        fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            while let Some(datagram) = self.datagrams.front() {
+                let sent = if self.connected {
+                    ready!(self.socket.poll_send(cx, datagram))
+                } else {
+                    ready!(self.socket.poll_send_to(cx, datagram, self.peer_addr))
+                };
+                match sent {
+                    Ok(_) => {}
+                    // Like any lost datagram, the message is dropped; the peer may not be
+                    // listening yet.
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                        tracing::warn!("Failed to send datagram to {}: {}", self.peer_addr, e);
+                    }
+                    Err(e) => return Poll::Ready(Err(e)),
+                }
+                self.datagrams.pop_front();
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// A transport that sends each message in a UDP datagram.
+    #[pin_project]
+    pub struct Transport<Item, SinkItem, Codec> {
+        datagrams: Datagrams,
+        outgoing: Outgoing,
+        /// Set if unanswered requests are retransmitted.
+        retransmits: Option<Retransmits>,
+        #[pin]
+        codec: Codec,
+        ghost: PhantomData<(fn() -> Item, fn(SinkItem))>,
+    }
+
+    impl<Item, SinkItem, Codec> Transport<Item, SinkItem, Codec> {
+        /// Returns the address of the peer.
+        pub fn peer_addr(&self) -> SocketAddr {
+            self.outgoing.peer_addr
+        }
+
+        /// Returns the local address the transport sends from.
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.outgoing.socket.local_addr()
+        }
+
+        /// Retransmits requests until a message answering them is received, or until `retransmit`
+        /// gives up on them. Only the first message of each request is retransmitted, so this
+        /// is meant for clients sending unary requests.
+        pub fn with_retransmit(mut self, retransmit: Retransmit) -> Self {
+            self.retransmits = Some(Retransmits::new(retransmit));
+            self
+        }
+    }
+
+    impl<Item, SinkItem, Codec> fmt::Debug for Transport<Item, SinkItem, Codec> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Transport")
+                .field("peer_addr", &self.outgoing.peer_addr)
+                .field("outgoing", &self.outgoing.datagrams.len())
+                .field("retransmits", &self.retransmits)
+                .finish()
+        }
+    }
+
+    /// Constructs a new transport from a socket connected to the peer and a serialization codec.
+    pub fn new<Item, SinkItem, Codec>(
+        socket: UdpSocket,
+        codec: Codec,
+    ) -> io::Result<Transport<Item, SinkItem, Codec>>
+    where
+        Item: RequestMessage,
+        SinkItem: RequestMessage,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+    {
+        Ok(Transport {
+            datagrams: Datagrams::Socket {
+                buf: vec![0; MAX_DATAGRAM_SIZE].into_boxed_slice(),
+            },
+            outgoing: Outgoing {
+                peer_addr: socket.peer_addr()?,
+                socket: Arc::new(socket),
+                connected: true,
+                datagrams: VecDeque::new(),
+            },
+            retransmits: None,
+            codec,
+            ghost: PhantomData,
+        })
+    }
+
+    impl<Item, SinkItem, Codec> Stream for Transport<Item, SinkItem, Codec>
+    where
+        Item: RequestMessage,
+        Codec: Deserializer<Item>,
+        Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        type Item = io::Result<Item>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
+            let mut this = self.project();
+            if let Some(retransmits) = this.retransmits {
+                while let Poll::Ready(datagram) = retransmits.poll_due(cx) {
+                    this.outgoing.datagrams.push_back(datagram);
+                }
+                if let Poll::Ready(Err(e)) = this.outgoing.poll_flush(cx) {
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+            loop {
+                let datagram = match this.datagrams {
+                    Datagrams::Socket { buf } => {
+                        let mut buf = ReadBuf::new(buf);
+                        match ready!(this.outgoing.socket.poll_recv(cx, &mut buf)) {
+                            Ok(()) => BytesMut::from(buf.filled()),
+                            // Reported when a previously sent datagram wasn't delivered.
+                            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                                tracing::warn!(
+                                    "Failed to send datagram to {}: {}",
+                                    this.outgoing.peer_addr,
+                                    e
+                                );
+                                continue;
+                            }
+                            Err(e) => return Poll::Ready(Some(Err(e))),
+                        }
+                    }
+                    Datagrams::Listener { datagrams, idle } => match datagrams.poll_recv(cx) {
+                        Poll::Ready(Some(datagram)) => {
+                            idle.as_mut().reset(Instant::now() + IDLE_TIMEOUT);
+                            datagram
+                        }
+                        Poll::Ready(None) => return Poll::Ready(None),
+                        Poll::Pending => {
+                            ready!(idle.as_mut().poll(cx));
+                            return Poll::Ready(None);
+                        }
+                    },
+                };
+                let item = match this.codec.as_mut().deserialize(&datagram) {
+                    Ok(item) => item,
+                    Err(e) => {
+                        // A stray datagram shouldn't end the transport.
+                        tracing::warn!(
+                            "Dropped undecodable datagram from {}: {}",
+                            this.outgoing.peer_addr,
+                            e.into()
+                        );
+                        continue;
+                    }
+                };
+                if let (Some(retransmits), Some(request_id)) = (this.retransmits, item.request_id())
+                {
+                    retransmits.remove(request_id);
+                }
+                return Poll::Ready(Some(Ok(item)));
+            }
+        }
+    }
+
+    impl<Item, SinkItem, Codec> Sink<SinkItem> for Transport<Item, SinkItem, Codec>
+    where
+        SinkItem: RequestMessage,
+        Codec: Serializer<SinkItem>,
+        Codec::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.project().outgoing.poll_flush(cx)
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+            let this = self.project();
+            let datagram = this
+                .codec
+                .serialize(&item)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            if datagram.len() > MAX_DATAGRAM_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "message of {} bytes exceeds the maximum datagram size of {} bytes",
+                        datagram.len(),
+                        MAX_DATAGRAM_SIZE
+                    ),
+                ));
+            }
+            if let (Some(retransmits), Some(request_id)) = (this.retransmits, item.request_id()) {
+                if item.starts_request() {
+                    retransmits.insert(request_id, datagram.clone());
+                } else if item.ends_request() {
+                    retransmits.remove(request_id);
+                }
+            }
+            this.outgoing.datagrams.push_back(datagram);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.project().outgoing.poll_flush(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.project().outgoing.poll_flush(cx)
+        }
+    }
+
+    /// Binds a socket connected to `addr`, and wraps it in a UDP transport.
+    pub async fn connect<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        codec_fn: CodecFn,
+    ) -> io::Result<Transport<Item, SinkItem, Codec>>
+    where
+        A: ToSocketAddrs,
+        Item: RequestMessage,
+        SinkItem: RequestMessage,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: FnOnce() -> Codec,
+    {
+        let peer_addr = lookup_host(addr).await?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "could not resolve address")
+        })?;
+        let local_addr: SocketAddr = match peer_addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local_addr).await?;
+        socket.connect(peer_addr).await?;
+        new(socket, codec_fn())
+    }
+
+    /// Listens on `addr`, wrapping the datagrams of each peer in a UDP transport.
+    pub async fn listen<A, Item, SinkItem, Codec, CodecFn>(
+        addr: A,
+        codec_fn: CodecFn,
+    ) -> io::Result<Incoming<Item, SinkItem, Codec, CodecFn>>
+    where
+        A: ToSocketAddrs,
+        Item: RequestMessage,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let local_addr = socket.local_addr()?;
+        let (new_peers, peers) = mpsc::unbounded_channel();
+        let max_peers = Arc::new(AtomicUsize::new(DEFAULT_MAX_PEERS));
+        tokio::spawn(demultiplex(socket.clone(), new_peers, max_peers.clone()));
+        Ok(Incoming {
+            socket,
+            local_addr,
+            peers,
+            max_peers,
+            codec_fn,
+            ghost: PhantomData,
+        })
+    }
+
+    type NewPeer = (SocketAddr, mpsc::Receiver<BytesMut>);
+
+    /// The sending half of a peer's transport, as tracked by a listener.
+    struct Peer {
+        datagrams: mpsc::Sender<BytesMut>,
+        last_received: Instant,
+    }
+
+    impl Peer {
+        /// Whether the transport of the peer ended, or soon will for lack of datagrams.
+        fn is_expired(&self, now: Instant) -> bool {
+            self.datagrams.is_closed() || now.duration_since(self.last_received) >= IDLE_TIMEOUT
+        }
+    }
+
+    /// Forwards each datagram received by `socket` to the transport of its peer, until the
+    /// listener is dropped. Datagrams from new peers are dropped while `max_peers` peers are
+    /// tracked.
+    async fn demultiplex(
+        socket: Arc<UdpSocket>,
+        new_peers: mpsc::UnboundedSender<NewPeer>,
+        max_peers: Arc<AtomicUsize>,
+    ) {
+        let mut peers = FnvHashMap::<SocketAddr, Peer>::default();
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let mut last_expiry = Instant::now();
+        let listener_dropped = new_peers.closed();
+        pin_mut!(listener_dropped);
+        loop {
+            let (len, peer_addr) = {
+                let recv = socket.recv_from(&mut buf);
+                pin_mut!(recv);
+                match future::select(listener_dropped.as_mut(), recv).await {
+                    Either::Left(_) => return,
+                    Either::Right((Ok(received), _)) => received,
+                    Either::Right((Err(e), _)) => {
+                        tracing::warn!("Failed to receive datagram: {}", e);
+                        continue;
+                    }
+                }
+            };
+            let now = Instant::now();
+            if now.duration_since(last_expiry) >= IDLE_TIMEOUT {
+                peers.retain(|_, peer| !peer.is_expired(now));
+                last_expiry = now;
+            }
+            let datagram = BytesMut::from(&buf[..len]);
+            let datagram = match peers.get_mut(&peer_addr) {
+                Some(peer) => match peer.datagrams.try_send(datagram) {
+                    Ok(()) => {
+                        peer.last_received = now;
+                        continue;
+                    }
+                    Err(TrySendError::Full(_)) => {
+                        tracing::warn!("Dropped datagram from {}: buffer is full.", peer_addr);
+                        continue;
+                    }
+                    // The transport of the peer ended, so the datagram starts a new one.
+                    Err(TrySendError::Closed(datagram)) => {
+                        peers.remove(&peer_addr);
+                        datagram
+                    }
+                },
+                None => datagram,
+            };
+            if peers.len() >= max_peers.load(Ordering::Relaxed) {
+                peers.retain(|_, peer| !peer.is_expired(now));
+                last_expiry = now;
+                if peers.len() >= max_peers.load(Ordering::Relaxed) {
+                    tracing::warn!(
+                        "Dropped datagram from {}: too many peers ({}).",
+                        peer_addr,
+                        peers.len()
+                    );
+                    continue;
+                }
+            }
+            let (peer, datagrams) = mpsc::channel(PEER_BUFFER);
+            let _ = peer.try_send(datagram);
+            if new_peers.send((peer_addr, datagrams)).is_ok() {
+                peers.insert(
+                    peer_addr,
+                    Peer {
+                        datagrams: peer,
+                        last_received: now,
+                    },
+                );
+            }
+        }
+    }
+
+    /// A UDP socket that demultiplexes the datagrams it receives into a UDP
+    /// [transport](Transport) per peer.
+    ///
+    /// Transports stop receiving datagrams once the listener is dropped.
+    #[pin_project]
+    pub struct Incoming<Item, SinkItem, Codec, CodecFn> {
+        socket: Arc<UdpSocket>,
+        local_addr: SocketAddr,
+        peers: mpsc::UnboundedReceiver<NewPeer>,
+        max_peers: Arc<AtomicUsize>,
+        codec_fn: CodecFn,
+        ghost: PhantomData<(fn() -> Item, fn(SinkItem), Codec)>,
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> fmt::Debug for Incoming<Item, SinkItem, Codec, CodecFn> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Incoming")
+                .field("local_addr", &self.local_addr)
+                .finish()
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Incoming<Item, SinkItem, Codec, CodecFn> {
+        /// Returns the address being listened on.
+        pub fn local_addr(&self) -> SocketAddr {
+            self.local_addr
+        }
+
+        /// Sets the maximum number of peers tracked at once; datagrams from new peers are dropped
+        /// until the transport of a tracked peer ends or idles for [`IDLE_TIMEOUT`]. Defaults to
+        /// [`DEFAULT_MAX_PEERS`].
+        ///
+        /// # Panics
+        ///
+        /// If `max_peers` is 0.
+        pub fn set_max_peers(&mut self, max_peers: usize) {
+            assert!(max_peers > 0, "max_peers must be greater than 0");
+            self.max_peers.store(max_peers, Ordering::Relaxed);
+        }
+    }
+
+    impl<Item, SinkItem, Codec, CodecFn> Stream for Incoming<Item, SinkItem, Codec, CodecFn>
+    where
+        Item: RequestMessage,
+        SinkItem: RequestMessage,
+        Codec: Serializer<SinkItem> + Deserializer<Item>,
+        CodecFn: Fn() -> Codec,
+    {
+        type Item = io::Result<Transport<Item, SinkItem, Codec>>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let this = self.project();
+            let (peer_addr, datagrams) = match ready!(this.peers.poll_recv(cx)) {
+                Some(peer) => peer,
+                None => return Poll::Ready(None),
+            };
+            Poll::Ready(Some(Ok(Transport {
+                datagrams: Datagrams::Listener {
+                    datagrams,
+                    idle: Box::pin(sleep(IDLE_TIMEOUT)),
+                },
+                outgoing: Outgoing {
+                    socket: this.socket.clone(),
+                    peer_addr,
+                    connected: false,
+                    datagrams: VecDeque::new(),
+                },
+                retransmits: None,
+                codec: (this.codec_fn)(),
+                ghost: PhantomData,
+            })))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Transport;
+    use crate::transport::DecodeError;
+    use assert_matches::assert_matches;
+    use futures::{task::*, Sink, Stream};
+    use pin_utils::pin_mut;
+    use std::{
+        io::{self, Cursor},
+        pin::Pin,
+    };
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio_serde::formats::SymmetricalJson;
+
+    fn ctx() -> Context<'static> {
+        Context::from_waker(noop_waker_ref())
+    }
+
+    struct TestIo(Cursor<Vec<u8>>);
+
+    impl AsyncRead for TestIo {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            AsyncRead::poll_read(Pin::new(&mut self.0), cx, buf)
+        }
+    }
+
+    impl AsyncWrite for TestIo {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            AsyncWrite::poll_write(Pin::new(&mut self.0), cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            AsyncWrite::poll_flush(Pin::new(&mut self.0), cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            AsyncWrite::poll_shutdown(Pin::new(&mut self.0), cx)
+        }
+    }
+
+    #[test]
     fn close() {
         let (tx, _rx) = crate::transport::channel::bounded::<(), ()>(0);
         pin_mut!(tx);
@@ -589,67 +4749,1080 @@ mod tests {
     }
 
     #[test]
-    fn test_stream() {
-        let data: &[u8] = b"\x00\x00\x00\x18\"Test one, check check.\"";
-        let transport = Transport::from((
-            TestIo(Cursor::new(Vec::from(data))),
+    fn test_stream() {
+        let data: &[u8] = b"\x00\x00\x00\x18\"Test one, check check.\"";
+        let transport = Transport::from((
+            TestIo(Cursor::new(Vec::from(data))),
+            SymmetricalJson::<String>::default(),
+        ));
+        pin_mut!(transport);
+
+        assert_matches!(
+            transport.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(Ok(ref s))) if s == "Test one, check check.");
+        assert_matches!(transport.as_mut().poll_next(&mut ctx()), Poll::Ready(None));
+    }
+
+    #[test]
+    fn test_stream_reads_past_undecodable_frames() {
+        let data: &[u8] = b"\x00\x00\x00\x05oops!\x00\x00\x00\x0a\"Test two\"";
+        let transport = Transport::from((
+            TestIo(Cursor::new(Vec::from(data))),
+            SymmetricalJson::<String>::default(),
+        ));
+        pin_mut!(transport);
+
+        assert_matches!(
+            transport.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(Err(ref e))) if DecodeError::find(e).is_some());
+        assert_matches!(
+            transport.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(Ok(ref s))) if s == "Test two");
+        assert_matches!(transport.as_mut().poll_next(&mut ctx()), Poll::Ready(None));
+    }
+
+    #[test]
+    fn test_sink() {
+        let writer = Cursor::new(vec![]);
+        let mut transport = Box::pin(Transport::from((
+            TestIo(writer),
+            SymmetricalJson::<String>::default(),
+        )));
+
+        assert_matches!(
+            transport.as_mut().poll_ready(&mut ctx()),
+            Poll::Ready(Ok(()))
+        );
+        assert_matches!(
+            transport
+                .as_mut()
+                .start_send("Test one, check check.".into()),
+            Ok(())
+        );
+        assert_matches!(
+            transport.as_mut().poll_flush(&mut ctx()),
+            Poll::Ready(Ok(()))
+        );
+        assert_eq!(
+            transport.get_ref().0.get_ref(),
+            b"\x00\x00\x00\x18\"Test one, check check.\""
+        );
+    }
+
+    /// A byte stream that supports vectored writes, and writes at most `max_write` bytes at a
+    /// time.
+    struct VectoredIo {
+        written: Vec<u8>,
+        writes: usize,
+        max_write: usize,
+    }
+
+    impl VectoredIo {
+        fn new(max_write: usize) -> Self {
+            VectoredIo {
+                written: vec![],
+                writes: 0,
+                max_write,
+            }
+        }
+    }
+
+    impl AsyncRead for VectoredIo {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for VectoredIo {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.poll_write_vectored(cx, &[io::IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            bufs: &[io::IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            let this = &mut *self;
+            this.writes += 1;
+            let mut written = 0;
+            for buf in bufs {
+                let len = buf.len().min(this.max_write - written);
+                this.written.extend_from_slice(&buf[..len]);
+                written += len;
+            }
+            Poll::Ready(Ok(written))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn send_all<S>(transport: Pin<&mut Transport<S, String, String, SymmetricalJson<String>>>)
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        pin_mut!(transport);
+        for message in ["one", "two", "three"] {
+            assert_matches!(
+                transport.as_mut().poll_ready(&mut ctx()),
+                Poll::Ready(Ok(()))
+            );
+            assert_matches!(transport.as_mut().start_send(message.into()), Ok(()));
+        }
+        assert_matches!(
+            transport.as_mut().poll_flush(&mut ctx()),
+            Poll::Ready(Ok(()))
+        );
+    }
+
+    #[test]
+    fn vectored_write_coalesces_frames() {
+        let mut transport = Box::pin(Transport::from((
+            VectoredIo::new(usize::MAX),
             SymmetricalJson::<String>::default(),
-        ));
-        pin_mut!(transport);
-
-        assert_matches!(
-            transport.as_mut().poll_next(&mut ctx()),
-            Poll::Ready(Some(Ok(ref s))) if s == "Test one, check check.");
-        assert_matches!(transport.as_mut().poll_next(&mut ctx()), Poll::Ready(None));
+        )));
+        send_all(transport.as_mut());
+        assert_eq!(transport.get_ref().writes, 1);
+        assert_eq!(
+            transport.get_ref().written,
+            b"\x00\x00\x00\x05\"one\"\x00\x00\x00\x05\"two\"\x00\x00\x00\x07\"three\""
+        );
     }
 
     #[test]
-    fn test_sink() {
-        let writer = Cursor::new(vec![]);
+    fn vectored_write_resumes_partial_writes() {
         let mut transport = Box::pin(Transport::from((
-            TestIo(writer),
+            VectoredIo::new(3),
             SymmetricalJson::<String>::default(),
         )));
+        send_all(transport.as_mut());
+        assert_eq!(transport.get_ref().writes, 10);
+        assert_eq!(
+            transport.get_ref().written,
+            b"\x00\x00\x00\x05\"one\"\x00\x00\x00\x05\"two\"\x00\x00\x00\x07\"three\""
+        );
+    }
 
-        assert_matches!(
-            transport.as_mut().poll_ready(&mut ctx()),
-            Poll::Ready(Ok(()))
+    #[test]
+    fn vectored_write_matches_framing() {
+        use super::Builder;
+
+        let mut little_endian = Builder::new(SymmetricalJson::<String>::default)
+            .length_field_length(2)
+            .little_endian();
+        little_endian.framing_mut().length_adjustment(-2);
+        let mut adjusted = Builder::new(SymmetricalJson::<String>::default);
+        adjusted.framing_mut().length_adjustment(1);
+        for builder in [little_endian, adjusted] {
+            let mut framed = Box::pin(builder.new_transport(TestIo(Cursor::new(vec![]))));
+            send_all(framed.as_mut());
+            let mut vectored = Box::pin(builder.new_transport(VectoredIo::new(usize::MAX)));
+            send_all(vectored.as_mut());
+            assert_eq!(vectored.get_ref().writes, 1);
+            assert_eq!(&vectored.get_ref().written, framed.get_ref().0.get_ref());
+        }
+    }
+
+    /// Defines a test that `$new_transport` frames a string as `$encoded`, reads it back, and
+    /// that tarpc's own messages round-trip through `$codec`.
+    macro_rules! codec_round_trip_test {
+        (
+            $(#[$attr:meta])*
+            $name:ident,
+            encoded: $encoded:expr,
+            new_transport: |$io:ident| $new_transport:expr,
+            codec: $codec:expr $(,)?
+        ) => {
+            $(#[$attr])*
+            #[tokio::test]
+            async fn $name() {
+                use crate::{
+                    client, context,
+                    server::{BaseChannel, Channel},
+                };
+                use futures::future;
+
+                let new_transport = |$io: TestIo| $new_transport;
+                let encoded: &[u8] = $encoded;
+                let mut transport = Box::pin(new_transport(TestIo(Cursor::new(vec![]))));
+                assert_matches!(
+                    transport
+                        .as_mut()
+                        .start_send("Test one, check check.".into()),
+                    Ok(())
+                );
+                assert_matches!(
+                    transport.as_mut().poll_flush(&mut ctx()),
+                    Poll::Ready(Ok(()))
+                );
+                assert_eq!(transport.get_ref().0.get_ref(), encoded);
+
+                let transport = new_transport(TestIo(Cursor::new(Vec::from(encoded))));
+                pin_mut!(transport);
+                assert_matches!(
+                    transport.as_mut().poll_next(&mut ctx()),
+                    Poll::Ready(Some(Ok(ref s))) if s == "Test one, check check.");
+                assert_matches!(transport.as_mut().poll_next(&mut ctx()), Poll::Ready(None));
+
+                let (client_transport, server_transport) = super::duplex(1024, $codec, $codec);
+                tokio::spawn(
+                    BaseChannel::with_defaults(server_transport)
+                        .execute(|_: context::Context, message: String| future::ready(message)),
+                );
+                let client = client::new(client::Config::default(), client_transport).spawn();
+                let response = client
+                    .call(context::current(), "", String::from("hello"))
+                    .await;
+                assert_eq!(response.unwrap(), "hello");
+            }
+        };
+    }
+
+    codec_round_trip_test!(
+        #[cfg(feature = "serde-transport-messagepack")]
+        messagepack,
+        encoded: b"\x00\x00\x00\x17\xb6Test one, check check.",
+        new_transport: |io| Transport::from((
+            io,
+            tokio_serde::formats::SymmetricalMessagePack::<String>::default(),
+        )),
+        codec: tokio_serde::formats::MessagePack::default(),
+    );
+
+    codec_round_trip_test!(
+        #[cfg(feature = "serde-transport-cbor")]
+        cbor,
+        encoded: b"\x00\x00\x00\x17\x76Test one, check check.",
+        new_transport: |io| Transport::from((
+            io,
+            tokio_serde::formats::SymmetricalCbor::<String>::default(),
+        )),
+        codec: tokio_serde::formats::Cbor::default(),
+    );
+
+    codec_round_trip_test!(
+        #[cfg(feature = "serde-transport-postcard")]
+        postcard,
+        // A two-byte, little-endian length prefix, as an embedded peer might send it.
+        encoded: b"\x17\x00\x16Test one, check check.",
+        new_transport: |io| super::Builder::new(
+            super::postcard::SymmetricalPostcard::<String>::default,
+        )
+        .length_field_length(2)
+        .little_endian()
+        .new_transport(io),
+        codec: super::postcard::Postcard::default(),
+    );
+
+    #[cfg(feature = "serde-transport-rkyv")]
+    #[tokio::test]
+    async fn rkyv() {
+        use super::rkyv::{Rkyv, SymmetricalRkyv};
+        use crate::{
+            client, context,
+            server::{BaseChannel, Channel},
+            Response, ServerError, ServerMessage,
+        };
+        use bytes::BytesMut;
+        use futures::future;
+        use tokio_serde::{Deserializer, Serializer};
+
+        let mut codec = Box::pin(SymmetricalRkyv::<ServerMessage<String>>::default());
+        let response = ServerMessage::Response(Response {
+            request_id: 1,
+            message: Err(ServerError::deadline_exceeded("slow")),
+        });
+        let archive = codec.as_mut().serialize(&response).unwrap();
+        assert_eq!(
+            codec
+                .as_mut()
+                .deserialize(&BytesMut::from(&archive[..]))
+                .unwrap(),
+            response
         );
         assert_matches!(
-            transport
+            codec.as_mut().deserialize(&BytesMut::from(&b"\xff\xff"[..])),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData
+        );
+
+        let (client_transport, server_transport) =
+            super::duplex(1024, Rkyv::default(), Rkyv::default());
+        tokio::spawn(
+            BaseChannel::with_defaults(server_transport)
+                .execute(|_: context::Context, data: Vec<u8>| future::ready(data.len())),
+        );
+        let client = client::new(client::Config::default(), client_transport).spawn();
+        let response = client.call(context::current(), "", vec![7; 4096]).await;
+        assert_eq!(response.unwrap(), 4096);
+    }
+
+    #[test]
+    fn server_error_causes_round_trip() {
+        use crate::{ServerError, ServerErrorCause};
+        use bytes::BytesMut;
+        use tokio_serde::{
+            formats::{SymmetricalBincode, SymmetricalJson},
+            Deserializer, Serializer,
+        };
+
+        let errors = [
+            (
+                ServerError::overloaded("busy"),
+                ServerErrorCause::Overloaded,
+            ),
+            (
+                ServerError::unimplemented("no"),
+                ServerErrorCause::Unimplemented,
+            ),
+            (
+                ServerError::payload_too_large("big"),
+                ServerErrorCause::PayloadTooLarge,
+            ),
+            (ServerError::shutdown("bye"), ServerErrorCause::Shutdown),
+            (
+                ServerError::deadline_exceeded("late"),
+                ServerErrorCause::DeadlineExceeded,
+            ),
+            // Errors of handlers with the kind of an overloaded error aren't taken for one.
+            (
+                ServerError::new(io::ErrorKind::WouldBlock, "handler"),
+                ServerErrorCause::Other,
+            ),
+        ];
+        for (error, cause) in errors {
+            let mut json = Box::pin(SymmetricalJson::<ServerError>::default());
+            let bytes = json.as_mut().serialize(&error).unwrap();
+            let decoded = json
                 .as_mut()
-                .start_send("Test one, check check.".into()),
-            Ok(())
+                .deserialize(&BytesMut::from(&bytes[..]))
+                .unwrap();
+            assert_eq!(decoded.cause(), cause);
+            assert_eq!(decoded, error);
+
+            let mut bincode = Box::pin(SymmetricalBincode::<ServerError>::default());
+            let bytes = bincode.as_mut().serialize(&error).unwrap();
+            let decoded = bincode
+                .as_mut()
+                .deserialize(&BytesMut::from(&bytes[..]))
+                .unwrap();
+            assert_eq!(decoded.cause(), cause);
+            assert_eq!(decoded, error);
+        }
+    }
+
+    #[tokio::test]
+    async fn duplex() -> io::Result<()> {
+        use crate::{
+            client, context,
+            server::{BaseChannel, Channel},
+            ClientMessage, ServerMessage,
+        };
+        use futures::future;
+        use tokio_serde::formats::Json;
+
+        let (client_transport, server_transport) = super::duplex(
+            1024,
+            Json::<ServerMessage<String>, ClientMessage<String>>::default(),
+            Json::<ClientMessage<String>, ServerMessage<String>>::default(),
+        );
+        tokio::spawn(
+            BaseChannel::with_defaults(server_transport)
+                .execute(|_: context::Context, message: String| future::ready(message)),
         );
+        let client = client::new(client::Config::default(), client_transport).spawn();
+        // Larger than the buffer, so that the request and response are written in parts.
+        let message = "x".repeat(4096);
+        let response = client.call(context::current(), "", message.clone()).await;
+        assert_eq!(response.unwrap(), message);
+        Ok(())
+    }
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test]
+    async fn tcp() -> io::Result<()> {
+        use super::tcp;
+        use futures::{SinkExt, StreamExt};
+
+        let mut listener = tcp::listen("0.0.0.0:0", SymmetricalJson::<String>::default).await?;
+        let addr = listener.local_addr();
+        tokio::spawn(async move {
+            let mut transport = listener.next().await.unwrap().unwrap();
+            let message = transport.next().await.unwrap().unwrap();
+            transport.send(message).await.unwrap();
+        });
+        let mut transport = tcp::connect(addr, SymmetricalJson::<String>::default).await?;
+        transport.send(String::from("test")).await?;
+        assert_matches!(transport.next().await, Some(Ok(s)) if s == "test");
+        assert_matches!(transport.next().await, None);
+        Ok(())
+    }
+
+    #[test]
+    fn builder_framing() {
+        use super::Builder;
+
+        let builder = Builder::new(SymmetricalJson::<String>::default)
+            .length_field_length(2)
+            .little_endian()
+            .max_frame_length(16);
+        let mut transport = Box::pin(builder.new_transport(TestIo(Cursor::new(vec![]))));
+        assert_matches!(transport.as_mut().start_send("Test".into()), Ok(()));
         assert_matches!(
             transport.as_mut().poll_flush(&mut ctx()),
             Poll::Ready(Ok(()))
         );
+        assert_eq!(transport.get_ref().0.get_ref(), b"\x06\x00\"Test\"");
+
+        // An oversized frame is rejected by its length prefix alone.
+        let transport = builder.new_transport(TestIo(Cursor::new(b"\xff\xff".to_vec())));
+        pin_mut!(transport);
+        assert_matches!(
+            transport.as_mut().poll_next(&mut ctx()),
+            Poll::Ready(Some(Err(_)))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "length_field_length must be between 1 and 8")]
+    fn builder_rejects_wide_length_field() {
+        let _ = super::Builder::new(SymmetricalJson::<String>::default).length_field_length(9);
+    }
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test]
+    async fn builder() -> io::Result<()> {
+        use super::Builder;
+        use futures::{SinkExt, StreamExt};
+
+        let builder = Builder::new(SymmetricalJson::<String>::default)
+            .max_frame_length(16)
+            .nodelay(true);
+        let mut listener = builder.tcp_listen("0.0.0.0:0").await?;
+        let addr = listener.local_addr();
+        tokio::spawn(async move {
+            let mut transport = listener.next().await.unwrap().unwrap();
+            assert!(transport.get_ref().nodelay().unwrap());
+            let message = transport.next().await.unwrap().unwrap();
+            transport.send(message).await.unwrap();
+        });
+        let mut transport = builder.tcp_connect(addr).await?;
+        assert!(transport.get_ref().nodelay()?);
+        assert_matches!(transport.send("x".repeat(16)).await, Err(_));
+        transport.send(String::from("test")).await?;
+        assert_matches!(transport.next().await, Some(Ok(s)) if s == "test");
+        Ok(())
+    }
+
+    #[cfg(feature = "http-upgrade")]
+    #[tokio::test]
+    async fn http_upgrade() -> io::Result<()> {
+        use super::http_upgrade;
+        use futures::{SinkExt, StreamExt};
+        use hyper::{
+            service::{make_service_fn, service_fn},
+            Body, Client, Request, Response, Server, StatusCode,
+        };
+        use std::convert::Infallible;
+
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|mut request: Request<Body>| async move {
+                if !http_upgrade::is_upgrade_request(&request) {
+                    let mut response = Response::new(Body::from("not rpc"));
+                    *response.status_mut() = StatusCode::NOT_FOUND;
+                    return Ok::<_, Infallible>(response);
+                }
+                let (response, transport) =
+                    http_upgrade::accept(&mut request, SymmetricalJson::<String>::default).unwrap();
+                tokio::spawn(async move {
+                    let mut transport = transport.await.unwrap();
+                    let message = transport.next().await.unwrap().unwrap();
+                    transport.send(message).await.unwrap();
+                });
+                Ok(response)
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let client = Client::new();
+        let uri: hyper::Uri = format!("http://{addr}/rpc").parse().unwrap();
+        let response = client.get(uri.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let mut transport =
+            http_upgrade::connect(&client, uri, SymmetricalJson::<String>::default).await?;
+        transport.send(String::from("test")).await?;
+        assert_matches!(transport.next().await, Some(Ok(s)) if s == "test");
+        Ok(())
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn websocket() -> io::Result<()> {
+        use super::websocket;
+        use futures::{SinkExt, StreamExt};
+
+        let mut listener =
+            websocket::listen("localhost:0", SymmetricalJson::<String>::default).await?;
+        let addr = listener.local_addr();
+        tokio::spawn(async move {
+            let mut transport = listener.next().await.unwrap().unwrap();
+            let message = transport.next().await.unwrap().unwrap();
+            transport.send(message).await.unwrap();
+            transport.close().await.unwrap();
+        });
+        let mut transport = websocket::connect(
+            format!("ws://{addr}/rpc"),
+            SymmetricalJson::<String>::default,
+        )
+        .await?;
+        transport.send(String::from("test")).await?;
+        assert_matches!(transport.next().await, Some(Ok(s)) if s == "test");
+        assert_matches!(transport.next().await, None);
+        Ok(())
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn websocket_handshake_times_out() -> io::Result<()> {
+        use super::websocket;
+        use futures::StreamExt;
+        use std::time::Duration;
+        use tokio::net::TcpStream;
+
+        let mut listener = websocket::listen("localhost:0", SymmetricalJson::<String>::default)
+            .await?
+            .with_handshake_timeout(Duration::from_millis(10));
+        // Connects without ever sending the HTTP upgrade request.
+        let _conn = TcpStream::connect(listener.local_addr()).await?;
+        let error = listener.next().await.unwrap().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        Ok(())
+    }
+
+    #[cfg(feature = "quic")]
+    #[tokio::test]
+    async fn quic() -> io::Result<()> {
+        use super::quic::{self, quinn};
+        use crate::{
+            client, context,
+            server::{BaseChannel, Channel},
+            ClientMessage, ServerMessage,
+        };
+        use futures::{future, StreamExt};
+        use tokio_serde::formats::Json;
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+        let server_config = quinn::ServerConfig::with_single_cert(
+            vec![cert_der.clone()],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )
+        .unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&cert_der).unwrap();
+
+        let mut listener = quic::listen(
+            ([127, 0, 0, 1], 0).into(),
+            server_config,
+            Json::<ClientMessage<String>, ServerMessage<String>>::default,
+        )?;
+        let addr = listener.local_addr();
+        tokio::spawn(async move {
+            let transport = listener.next().await.unwrap().unwrap();
+            BaseChannel::with_defaults(transport)
+                .execute(|_: context::Context, message: String| future::ready(message))
+                .await;
+        });
+
+        let mut endpoint = quinn::Endpoint::client(([127, 0, 0, 1], 0).into())?;
+        endpoint.set_default_client_config(quinn::ClientConfig::with_root_certificates(roots));
+        let transport = quic::connect(
+            &endpoint,
+            addr,
+            "localhost",
+            Json::<ServerMessage<String>, ClientMessage<String>>::default,
+        )
+        .await?;
+        let client = client::new(client::Config::default(), transport).spawn();
+        let (large, small) = future::join(
+            client.call(context::current(), "", "x".repeat(1 << 20)),
+            client.call(context::current(), "", String::from("test")),
+        )
+        .await;
+        assert_eq!(large.unwrap().len(), 1 << 20);
+        assert_eq!(small.unwrap(), "test");
+        Ok(())
+    }
+
+    #[cfg(feature = "quic")]
+    #[tokio::test]
+    async fn quic_bounds_unwritten_messages() -> io::Result<()> {
+        use super::quic::{self, quinn, MAX_UNWRITTEN_MESSAGES};
+        use crate::{context, ClientMessage, Request, ServerMessage};
+        use futures::{future, SinkExt, StreamExt};
+        use tokio_serde::formats::Json;
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+        let server_config = quinn::ServerConfig::with_single_cert(
+            vec![cert_der.clone()],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )
+        .unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&cert_der).unwrap();
+
+        let mut listener = quic::listen(
+            ([127, 0, 0, 1], 0).into(),
+            server_config,
+            Json::<ClientMessage<String>, ServerMessage<String>>::default,
+        )?;
+        let addr = listener.local_addr();
+        tokio::spawn(async move {
+            let _transport = listener.next().await;
+            future::pending::<()>().await
+        });
+
+        let mut endpoint = quinn::Endpoint::client(([127, 0, 0, 1], 0).into())?;
+        endpoint.set_default_client_config(quinn::ClientConfig::with_root_certificates(roots));
+        let mut transport = quic::connect(
+            &endpoint,
+            addr,
+            "localhost",
+            Json::<ServerMessage<String>, ClientMessage<String>>::default,
+        )
+        .await?;
+
+        // The stream writes don't run until the test yields, so the transport fills up.
+        let mut sent = 0;
+        while future::poll_fn(|cx| Poll::Ready(transport.poll_ready_unpin(cx)))
+            .await
+            .is_ready()
+        {
+            transport.start_send_unpin(ClientMessage::Request(Request {
+                context: context::current(),
+                id: sent,
+                message: String::from("test"),
+            }))?;
+            sent += 1;
+        }
+        assert_eq!(sent, MAX_UNWRITTEN_MESSAGES as u64);
+
+        transport.flush().await?;
+        assert_matches!(
+            future::poll_fn(|cx| Poll::Ready(transport.poll_ready_unpin(cx))).await,
+            Poll::Ready(Ok(()))
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test]
+    async fn udp() -> io::Result<()> {
+        use super::udp;
+        use crate::{
+            client, context,
+            server::{BaseChannel, Channel},
+            ClientMessage, ServerMessage,
+        };
+        use futures::{future, StreamExt};
+        use tokio_serde::formats::Json;
+
+        let mut listener = udp::listen(
+            "localhost:0",
+            Json::<ClientMessage<String>, ServerMessage<String>>::default,
+        )
+        .await?;
+        let addr = listener.local_addr();
+        tokio::spawn(async move {
+            while let Some(transport) = listener.next().await {
+                tokio::spawn(
+                    BaseChannel::with_defaults(transport.unwrap())
+                        .execute(|_: context::Context, message: String| future::ready(message)),
+                );
+            }
+        });
+
+        for _ in 0..2 {
+            let transport = udp::connect(
+                addr,
+                Json::<ServerMessage<String>, ClientMessage<String>>::default,
+            )
+            .await?;
+            let client = client::new(client::Config::default(), transport).spawn();
+            let response = client
+                .call(context::current(), "", String::from("test"))
+                .await;
+            assert_eq!(response.unwrap(), "test");
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test]
+    async fn udp_listener_caps_peers() -> io::Result<()> {
+        use super::udp;
+        use crate::{ClientMessage, ServerMessage};
+        use futures::StreamExt;
+        use std::time::Duration;
+        use tokio::{net::UdpSocket, time::timeout};
+        use tokio_serde::formats::Json;
+
+        let mut listener = udp::listen(
+            "localhost:0",
+            Json::<ClientMessage<String>, ServerMessage<String>>::default,
+        )
+        .await?;
+        listener.set_max_peers(1);
+        let addr = listener.local_addr();
+
+        let first = UdpSocket::bind("localhost:0").await?;
+        first.send_to(b"{}", addr).await?;
+        let transport = listener.next().await.unwrap()?;
+        assert_eq!(transport.peer_addr(), first.local_addr()?);
+
+        let second = UdpSocket::bind("localhost:0").await?;
+        second.send_to(b"{}", addr).await?;
+        assert!(timeout(Duration::from_millis(50), listener.next())
+            .await
+            .is_err());
+
+        // Once the first transport ends, the second peer is let in.
+        drop(transport);
+        second.send_to(b"{}", addr).await?;
+        let transport = listener.next().await.unwrap()?;
+        assert_eq!(transport.peer_addr(), second.local_addr()?);
+        Ok(())
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test]
+    async fn udp_retransmits_unanswered_requests() -> io::Result<()> {
+        use super::udp;
+        use crate::{client, context, ClientMessage, Response, ServerMessage};
+        use bytes::BytesMut;
+        use std::time::Duration;
+        use tokio::net::UdpSocket;
+        use tokio_serde::{formats::Json, Deserializer, Serializer};
+
+        let server = UdpSocket::bind("localhost:0").await?;
+        let transport = udp::connect(
+            server.local_addr()?,
+            Json::<ServerMessage<String>, ClientMessage<String>>::default,
+        )
+        .await?
+        .with_retransmit(udp::Retransmit {
+            interval: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let client = client::new(client::Config::default(), transport).spawn();
+        let response = tokio::spawn(async move {
+            client
+                .call(context::current(), "", String::from("test"))
+                .await
+        });
+
+        // The first datagram is lost.
+        let mut buf = vec![0; udp::MAX_DATAGRAM_SIZE];
+        let (len, client_addr) = server.recv_from(&mut buf).await?;
+        let request = BytesMut::from(&buf[..len]);
+        let (len, _) = server.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..len], &request[..]);
+
+        let mut codec = Json::<ClientMessage<String>, ServerMessage<String>>::default();
+        let request_id = Pin::new(&mut codec)
+            .deserialize(&request)?
+            .request_id()
+            .unwrap();
+        let response_datagram =
+            Pin::new(&mut codec).serialize(&ServerMessage::Response(Response {
+                request_id,
+                message: Ok(String::from("test")),
+            }))?;
+        server.send_to(&response_datagram, client_addr).await?;
+
+        assert_eq!(response.await.unwrap().unwrap(), "test");
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "stdio"))]
+    #[tokio::test]
+    async fn stdio() -> io::Result<()> {
+        use super::stdio;
+        use futures::{SinkExt, StreamExt};
+        use tokio::process::Command;
+
+        // cat echoes the frames written to its stdin.
+        let (mut child, mut transport) =
+            stdio::spawn(&mut Command::new("cat"), SymmetricalJson::<String>::default)?;
+        transport.send(String::from("test")).await?;
+        assert_matches!(transport.next().await, Some(Ok(s)) if s == "test");
+        transport.close().await?;
+        assert_matches!(transport.next().await, None);
+        assert!(child.wait().await?.success());
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn tls() -> io::Result<()> {
+        use super::tls::{self, rustls};
+        use futures::{SinkExt, StreamExt};
+        use std::{convert::TryFrom, sync::Arc};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert_der.clone()],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&cert_der).unwrap();
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let mut listener = tls::listen(
+            "localhost:0",
+            Arc::new(server_config),
+            SymmetricalJson::<String>::default,
+        )
+        .await?;
+        let addr = listener.local_addr();
+        tokio::spawn(async move {
+            let mut transport = listener.next().await.unwrap().unwrap();
+            let message = transport.next().await.unwrap().unwrap();
+            transport.send(message).await.unwrap();
+            // Sends the TLS close_notify alert, so the client sees a clean end of stream.
+            transport.close().await.unwrap();
+        });
+        let mut transport = tls::connect(
+            addr,
+            rustls::ServerName::try_from("localhost").unwrap(),
+            Arc::new(client_config),
+            SymmetricalJson::<String>::default,
+        )
+        .await?;
+        transport.send(String::from("test")).await?;
+        assert_matches!(transport.next().await, Some(Ok(s)) if s == "test");
+        assert_matches!(transport.next().await, None);
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn tls_handshake_times_out() -> io::Result<()> {
+        use super::tls::{self, rustls};
+        use futures::StreamExt;
+        use std::{sync::Arc, time::Duration};
+        use tokio::net::TcpStream;
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+
+        let mut listener = tls::listen(
+            "localhost:0",
+            Arc::new(server_config),
+            SymmetricalJson::<String>::default,
+        )
+        .await?
+        .with_handshake_timeout(Duration::from_millis(10));
+        // Connects without ever starting the handshake.
+        let _conn = TcpStream::connect(listener.local_addr()).await?;
+        let error = listener.next().await.unwrap().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn tls_client_auth_identifies_client() -> io::Result<()> {
+        use super::tls::{self, rustls};
+        use crate::{
+            client, context,
+            server::{BaseChannel, Channel, PeerIdentity},
+            ClientMessage, ServerMessage,
+        };
+        use futures::{future, StreamExt};
+        use rcgen::{Certificate, CertificateParams, DnType};
+        use std::{convert::TryFrom, sync::Arc};
+        use tokio_serde::formats::Json;
+
+        let server_cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let server_cert_der = rustls::Certificate(server_cert.serialize_der().unwrap());
+        let mut params = CertificateParams::new(vec!["client.example.com".into()]);
+        params.distinguished_name.push(DnType::CommonName, "client");
+        let client_cert = Certificate::from_params(params).unwrap();
+        let client_cert_der = rustls::Certificate(client_cert.serialize_der().unwrap());
+
+        let mut client_roots = rustls::RootCertStore::empty();
+        client_roots.add(&client_cert_der).unwrap();
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(
+                client_roots,
+            ))
+            .with_single_cert(
+                vec![server_cert_der.clone()],
+                rustls::PrivateKey(server_cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let mut server_roots = rustls::RootCertStore::empty();
+        server_roots.add(&server_cert_der).unwrap();
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(server_roots)
+            .with_single_cert(
+                vec![client_cert_der],
+                rustls::PrivateKey(client_cert.serialize_private_key_der()),
+            )
+            .unwrap();
+
+        let mut listener = tls::listen(
+            "localhost:0",
+            Arc::new(server_config),
+            Json::<ClientMessage<()>, ServerMessage<String>>::default,
+        )
+        .await?;
+        let addr = listener.local_addr();
+        tokio::spawn(async move {
+            let transport = listener.next().await.unwrap().unwrap();
+            let identity = transport.peer_identity().unwrap().unwrap();
+            BaseChannel::with_defaults(transport)
+                .with_peer_identity(identity)
+                .execute(|_: context::Context, ()| {
+                    let identity = PeerIdentity::current().unwrap();
+                    future::ready(format!(
+                        "{} {}",
+                        identity.common_name.as_deref().unwrap_or(""),
+                        identity.dns_names.join(",")
+                    ))
+                })
+                .await;
+        });
+        let transport = tls::connect(
+            addr,
+            rustls::ServerName::try_from("localhost").unwrap(),
+            Arc::new(client_config),
+            Json::<ServerMessage<String>, ClientMessage<()>>::default,
+        )
+        .await?;
+        let client = client::new(client::Config::default(), transport).spawn();
         assert_eq!(
-            transport.get_ref().0.get_ref(),
-            b"\x00\x00\x00\x18\"Test one, check check.\""
+            client.call(context::current(), "", ()).await.unwrap(),
+            "client client.example.com"
         );
+        Ok(())
     }
 
-    #[cfg(tcp)]
+    #[cfg(feature = "native-tls")]
     #[tokio::test]
-    async fn tcp() -> io::Result<()> {
-        use super::tcp;
+    async fn native_tls() -> io::Result<()> {
+        use super::native_tls::{self, native_tls::*};
+        use futures::{SinkExt, StreamExt};
 
-        let mut listener = tcp::listen("0.0.0.0:0", SymmetricalJson::<String>::default).await?;
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        let identity = Identity::from_pkcs8(
+            cert_pem.as_bytes(),
+            cert.serialize_private_key_pem().as_bytes(),
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::new(identity).unwrap();
+        let connector = TlsConnector::builder()
+            .add_root_certificate(Certificate::from_pem(cert_pem.as_bytes()).unwrap())
+            .build()
+            .unwrap();
+
+        let mut listener =
+            native_tls::listen("localhost:0", acceptor, SymmetricalJson::<String>::default).await?;
         let addr = listener.local_addr();
         tokio::spawn(async move {
             let mut transport = listener.next().await.unwrap().unwrap();
             let message = transport.next().await.unwrap().unwrap();
             transport.send(message).await.unwrap();
+            transport.close().await.unwrap();
         });
-        let mut transport = tcp::connect(addr, SymmetricalJson::<String>::default).await?;
+        let mut transport = native_tls::connect(
+            addr,
+            "localhost".into(),
+            connector,
+            SymmetricalJson::<String>::default,
+        )
+        .await?;
+        assert!(transport.peer_certificate()?.is_some());
         transport.send(String::from("test")).await?;
         assert_matches!(transport.next().await, Some(Ok(s)) if s == "test");
         assert_matches!(transport.next().await, None);
         Ok(())
     }
 
+    #[cfg(feature = "native-tls")]
+    #[tokio::test]
+    async fn native_tls_caps_pending_handshakes() -> io::Result<()> {
+        use super::native_tls::{self, native_tls::*};
+        use futures::StreamExt;
+        use std::time::Duration;
+        use tokio::net::TcpStream;
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let identity = Identity::from_pkcs8(
+            cert.serialize_pem().unwrap().as_bytes(),
+            cert.serialize_private_key_pem().as_bytes(),
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::new(identity).unwrap();
+
+        let mut listener =
+            native_tls::listen("localhost:0", acceptor, SymmetricalJson::<String>::default)
+                .await?
+                .with_handshake_timeout(Duration::from_millis(50))
+                .with_max_pending_handshakes(1);
+        // Neither client starts its handshake. The second one isn't accepted until the first
+        // one's handshake times out, so both time out in turn.
+        let _stalled = TcpStream::connect(listener.local_addr()).await?;
+        let _queued = TcpStream::connect(listener.local_addr()).await?;
+        let start = tokio::time::Instant::now();
+        for _ in 0..2 {
+            let error = listener.next().await.unwrap().err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        Ok(())
+    }
+
     #[cfg(all(unix, feature = "unix"))]
     #[tokio::test]
     async fn uds() -> io::Result<()> {
@@ -669,4 +5842,34 @@ mod tests {
         assert_matches!(transport.next().await, None);
         Ok(())
     }
+
+    #[cfg(all(target_os = "linux", feature = "vsock"))]
+    #[tokio::test]
+    #[ignore = "requires vsock loopback, e.g. the vsock_loopback kernel module"]
+    async fn vsock() -> io::Result<()> {
+        use super::vsock;
+        use futures::{SinkExt, StreamExt};
+
+        let mut listener = vsock::listen(
+            vsock::VMADDR_CID_ANY,
+            56790,
+            SymmetricalJson::<String>::default,
+        )
+        .await?;
+        tokio::spawn(async move {
+            let mut transport = listener.next().await.unwrap().unwrap();
+            let message = transport.next().await.unwrap().unwrap();
+            transport.send(message).await.unwrap();
+        });
+        let mut transport = vsock::connect(
+            vsock::VMADDR_CID_LOCAL,
+            56790,
+            SymmetricalJson::<String>::default,
+        )
+        .await?;
+        transport.send(String::from("test")).await?;
+        assert_matches!(transport.next().await, Some(Ok(s)) if s == "test");
+        assert_matches!(transport.next().await, None);
+        Ok(())
+    }
 }
@@ -8,12 +8,19 @@
 
 use crate::{
     cancellations::{cancellations, CanceledRequests, RequestCancellation},
+    capabilities::Capabilities,
     context::{self, SpanExt},
-    trace, ClientMessage, Request, Response, Transport,
+    metrics::{Outcome, Recorder, Side},
+    trace,
+    transport::{DecodeError, ProtocolError},
+    util::{self, Compact, TimeUntil},
+    ClientMessage, Request, Response, ServerError, ServerMessage, Transport,
 };
-use ::tokio::sync::mpsc;
+use ::tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use ::tokio_util::sync::{CancellationToken, PollSemaphore};
+use fnv::FnvHashMap;
 use futures::{
-    future::{AbortRegistration, Abortable},
+    future::{AbortHandle, AbortRegistration, Abortable, Aborted, Either},
     prelude::*,
     ready,
     stream::Fuse,
@@ -21,49 +28,482 @@ use futures::{
 };
 use in_flight_requests::{AlreadyExistsError, InFlightRequests};
 use pin_project::pin_project;
-use std::{convert::TryFrom, error::Error, fmt, marker::PhantomData, pin::Pin};
+use rejections::{RejectReason, Rejection};
+use std::{
+    any::{Any, TypeId},
+    cell::RefCell,
+    collections::VecDeque,
+    convert::TryFrom,
+    error::Error,
+    fmt, io,
+    marker::PhantomData,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{Duration, SystemTime},
+};
 use tracing::{info_span, instrument::Instrument, Span};
 
 mod in_flight_requests;
+mod shutdown;
 #[cfg(test)]
 mod testing;
 
+pub use faults::FaultInjector;
+pub use identity::PeerIdentity;
+pub use rejections::RejectionLog;
+pub use shutdown::{DrainTimeout, Shutdown};
+
 /// Provides functionality to apply server limits.
 pub mod limits;
 
 /// Provides helper methods for streams of Channels.
 pub mod incoming;
 
+/// Provides a registry of live channels, keyed by a user-provided id.
+pub mod registry;
+
+/// Provides recording of request/response samples into a golden corpus, and verification of
+/// later server builds against the corpus.
+pub mod capture;
+
+/// Provides attribution of panics in request handlers to the requests being handled.
+pub mod panics;
+
+/// Provides the verified identities of clients, such as the subjects of their TLS certificates.
+pub mod identity;
+
+/// Provides a bounded log of rejected requests.
+pub mod rejections;
+
+/// Provides injection of errors into a fraction of requests, e.g. to validate the retries of
+/// canary clients in production.
+pub mod faults;
+
+/// Provides mirroring of requests to a shadow server, to compare its responses with those of the
+/// primary server.
+#[cfg(feature = "tokio1")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tokio1")))]
+pub mod mirror;
+
+/// Provides support for wrapping [`Serve`] implementations in tower middleware.
+#[cfg(feature = "tower")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tower")))]
+pub mod tower;
+
+#[cfg(feature = "json-rpc")]
+#[cfg_attr(docsrs, doc(cfg(feature = "json-rpc")))]
+pub mod json_rpc;
+
 /// Provides convenience functionality for tokio-enabled applications.
 #[cfg(feature = "tokio1")]
 #[cfg_attr(docsrs, doc(cfg(feature = "tokio1")))]
 pub mod tokio;
 
 /// Settings that control the behavior of [channels](Channel).
+///
+/// New settings may be added in minor releases, so configs are built by overriding the defaults
+/// with setters:
+///
+/// ```rust
+/// # use tarpc::server::Config;
+/// # use std::time::Duration;
+/// let config = Config::default()
+///     .with_stream_window(8)
+///     .with_deadline_notice(Duration::from_millis(100));
+/// ```
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct Config {
     /// Controls the buffer size of the in-process channel over which a server's handlers send
     /// responses to the [`Channel`]. In other words, this is the number of responses that can sit
     /// in the outbound queue before request handlers begin blocking.
     pub pending_response_buffer: usize,
+    /// The number of items a server-streaming request handler can send before waiting for the
+    /// client to consume them. The client grants one more item each time it consumes an item, so
+    /// this is the maximum number of stream items in flight per request.
+    ///
+    /// It also bounds the items of a client-streaming request that are buffered until the handler
+    /// takes them; once a request's buffer is full, the channel stops reading until there's room.
+    pub stream_window: usize,
+    /// If set, tunables set on the handle override those of channels created with this config,
+    /// and of the limits wrapping them. Updates to the handle apply to running channels.
+    pub config_handle: Option<ConfigHandle>,
+    /// How long a request handler has to wind down after the request's deadline expires, before
+    /// it is aborted. During this window, the request's [`Cancellation`] is triggered. If zero,
+    /// handlers are aborted as soon as their deadlines expire.
+    pub deadline_notice: Duration,
+    /// If set, channels created with this config abort the in-flight requests of the traces
+    /// canceled through the canceler.
+    pub trace_canceler: Option<TraceCanceler>,
+    /// If set, channels created with this config stop reading requests once the server is
+    /// [shut down](Shutdown::shutdown), and close once their in-flight requests are done.
+    pub shutdown: Option<Shutdown>,
+    /// If set, channels created with this config record the requests they reject, e.g. because
+    /// they were throttled or their deadlines expired, in the log.
+    pub rejections: Option<RejectionLog>,
+    /// If set, channels created with this config fail the requests matched by the injector's
+    /// rules with the rules' errors, before they are handled.
+    pub faults: Option<FaultInjector>,
+    /// If set, channels created with this config record the requests they handle with the
+    /// recorder.
+    pub metrics: Option<Arc<dyn Recorder>>,
+    /// The capabilities channels announce to clients that open a
+    /// [handshake](crate::capabilities).
+    pub capabilities: Capabilities,
+    /// When the [`Requests`] of channels created with this config flush the responses written to
+    /// the transport.
+    pub flush_policy: FlushPolicy,
+    /// Whether channels created with this config create spans for requests whose trace the
+    /// client didn't [sample](trace::SamplingDecision). If false, the requests of unsampled traces
+    /// are handled without a span, so that a high-volume service only traces the fraction of its
+    /// requests that its clients sample; their events are still logged, outside of any request
+    /// span, and the calls their handlers make stay unsampled.
+    pub trace_unsampled: bool,
+    /// What channels created with this config do with a message the transport fails to decode.
+    pub decode_errors: DecodeErrorPolicy,
+    /// Whether the requests of channels created with this config catch the panics of their
+    /// handlers when [executed](InFlightRequest::execute). If true, a request whose handler
+    /// panics is failed with a [`ServerError`], which frees its in-flight slot, rather than
+    /// leaving the client to wait on it until its deadline. Panics are reported to the panic hook
+    /// either way.
+    pub catch_panics: bool,
+    /// Whether channels created with this config cancel requests cooperatively. If true, a
+    /// request canceled by its client, or through the [`TraceCanceler`], has its
+    /// [`Cancellation`] triggered rather than its handler aborted, so that the handler can clean
+    /// up, e.g. roll back a transaction, before returning. The request stays in flight until the
+    /// handler returns, and its handler is still aborted once its deadline expires.
+    pub cooperative_cancellation: bool,
+    /// Whether channels created with this config respond to requests whose deadlines expire with
+    /// a [deadline exceeded](ServerError::deadline_exceeded) error. Clients give up on requests
+    /// at their deadlines, so they don't need the response, unless their clocks run behind the
+    /// server's: then they learn the outcome promptly, rather than at their own deadlines.
+    pub respond_to_expired_requests: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             pending_response_buffer: 100,
+            stream_window: 32,
+            config_handle: None,
+            deadline_notice: Duration::ZERO,
+            trace_canceler: None,
+            shutdown: None,
+            rejections: None,
+            faults: None,
+            metrics: None,
+            capabilities: Capabilities::SUPPORTED,
+            flush_policy: FlushPolicy::WhenIdle,
+            trace_unsampled: true,
+            decode_errors: DecodeErrorPolicy::Close,
+            catch_panics: false,
+            cooperative_cancellation: false,
+            respond_to_expired_requests: false,
         }
     }
 }
 
+/// When [`Requests`] flush the responses written to the transport. Responses are always flushed
+/// when the transport's buffer is full, and when the channel closes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FlushPolicy {
+    /// Flushes as soon as no more responses are ready to be written. Minimizes latency, but a
+    /// server writing responses one at a time flushes after each of them.
+    WhenIdle,
+    /// Once no more responses are ready to be written, defers the flush until `responses`
+    /// responses are unflushed, or until `delay` has passed since the first unflushed response
+    /// was written, whichever comes first. Amortizes flushes over several responses, at the cost
+    /// of up to `delay` of latency.
+    Deferred {
+        /// The number of unflushed responses that triggers a flush.
+        responses: usize,
+        /// The longest a response is left unflushed.
+        delay: Duration,
+    },
+}
+
+/// What a [`BaseChannel`] does with a message its transport fails to
+/// [decode](crate::transport::DecodeError), e.g. a request sent by a client built with a newer
+/// version of the service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DecodeErrorPolicy {
+    /// Closes the channel with the transport error, aborting the requests in flight on it.
+    Close,
+    /// Skips the message, counting it in [`BaseChannel::undecodable_messages`], and keeps serving
+    /// the requests in flight. If the transport recovered the ID of the request the message
+    /// carried, the request is failed with an [`InvalidData`](io::ErrorKind::InvalidData) error,
+    /// so that the client doesn't wait on it until its deadline. Errors that leave the transport
+    /// unusable still close the channel.
+    Skip,
+}
+
+/// A handle to update tunables on running channels and limits without restarting them, e.g. from
+/// a file watcher or a remote config system.
+///
+/// Updates are applied atomically, and take effect the next time a tunable is read: for
+/// example, a new stream window applies to requests started after the update.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigHandle(Arc<RwLock<Tunables>>);
+
+/// Tunables that can be updated through a [`ConfigHandle`]. Unset tunables leave the setting of
+/// the channel or limit as configured at construction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Tunables {
+    /// Overrides [`Config::stream_window`].
+    pub stream_window: Option<usize>,
+    /// Overrides the limit of [`MaxRequests`](limits::requests_per_channel::MaxRequests).
+    pub max_requests_per_channel: Option<usize>,
+    /// Overrides the limit of
+    /// [`MaxChannelsPerKey`](limits::channels_per_key::MaxChannelsPerKey).
+    pub max_channels_per_key: Option<u32>,
+}
+
+impl ConfigHandle {
+    /// Returns a handle with no tunables set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the current tunables.
+    pub fn tunables(&self) -> Tunables {
+        *self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Atomically updates the tunables.
+    pub fn update(&self, f: impl FnOnce(&mut Tunables)) {
+        let mut tunables = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        f(&mut tunables);
+        tracing::info!(tunables = ?*tunables, "UpdateTunables");
+    }
+}
+
+/// A handle to cancel all the in-flight requests belonging to a trace, across all the channels
+/// created with a [`Config`] holding the handle, e.g. when an upstream user abandons an operation
+/// that fanned out into many requests.
+///
+/// As when a client cancels a request, the handlers of the requests are aborted, and no responses
+/// are sent.
+#[derive(Clone, Debug, Default)]
+pub struct TraceCanceler(Arc<Mutex<Vec<mpsc::UnboundedSender<trace::TraceId>>>>);
+
+impl TraceCanceler {
+    /// Returns a canceler not yet used by any channels.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the requests of the trace identified by `trace_id` that are in flight on any of
+    /// the channels. The requests are canceled the next time each channel is polled.
+    pub fn cancel(&self, trace_id: trace::TraceId) {
+        let mut channels = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        channels.retain(|channel| channel.send(trace_id).is_ok());
+        tracing::info!(rpc.trace_id = %trace_id, channels = channels.len(), "CancelTrace");
+    }
+
+    fn register(&self) -> mpsc::UnboundedReceiver<trace::TraceId> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut channels = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        channels.retain(|channel| !channel.is_closed());
+        channels.push(tx);
+        rx
+    }
+}
+
+impl Config {
+    /// Sets [`Config::pending_response_buffer`].
+    pub fn with_pending_response_buffer(mut self, pending_response_buffer: usize) -> Self {
+        self.pending_response_buffer = pending_response_buffer;
+        self
+    }
+
+    /// Sets [`Config::stream_window`].
+    pub fn with_stream_window(mut self, stream_window: usize) -> Self {
+        self.stream_window = stream_window;
+        self
+    }
+
+    /// Sets [`Config::config_handle`].
+    pub fn with_config_handle(mut self, config_handle: ConfigHandle) -> Self {
+        self.config_handle = Some(config_handle);
+        self
+    }
+
+    /// Sets [`Config::deadline_notice`].
+    pub fn with_deadline_notice(mut self, deadline_notice: Duration) -> Self {
+        self.deadline_notice = deadline_notice;
+        self
+    }
+
+    /// Sets [`Config::trace_canceler`].
+    pub fn with_trace_canceler(mut self, trace_canceler: TraceCanceler) -> Self {
+        self.trace_canceler = Some(trace_canceler);
+        self
+    }
+
+    /// Sets [`Config::shutdown`].
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Sets [`Config::rejections`].
+    pub fn with_rejections(mut self, rejections: RejectionLog) -> Self {
+        self.rejections = Some(rejections);
+        self
+    }
+
+    /// Sets [`Config::faults`].
+    pub fn with_faults(mut self, faults: FaultInjector) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// Sets [`Config::metrics`].
+    pub fn with_metrics(mut self, metrics: Arc<dyn Recorder>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Sets [`Config::capabilities`].
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Sets [`Config::flush_policy`].
+    pub fn with_flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    /// Sets [`Config::trace_unsampled`].
+    pub fn with_trace_unsampled(mut self, trace_unsampled: bool) -> Self {
+        self.trace_unsampled = trace_unsampled;
+        self
+    }
+
+    /// Sets [`Config::decode_errors`].
+    pub fn with_decode_errors(mut self, decode_errors: DecodeErrorPolicy) -> Self {
+        self.decode_errors = decode_errors;
+        self
+    }
+
+    /// Sets [`Config::catch_panics`].
+    pub fn with_catch_panics(mut self, catch_panics: bool) -> Self {
+        self.catch_panics = catch_panics;
+        self
+    }
+
+    /// Sets [`Config::cooperative_cancellation`].
+    pub fn with_cooperative_cancellation(mut self, cooperative_cancellation: bool) -> Self {
+        self.cooperative_cancellation = cooperative_cancellation;
+        self
+    }
+
+    /// Sets [`Config::respond_to_expired_requests`].
+    pub fn with_respond_to_expired_requests(mut self, respond_to_expired_requests: bool) -> Self {
+        self.respond_to_expired_requests = respond_to_expired_requests;
+        self
+    }
+
+    /// Returns the stream window, as overridden by the config handle, if any.
+    pub(crate) fn current_stream_window(&self) -> usize {
+        self.config_handle
+            .as_ref()
+            .and_then(|handle| handle.tunables().stream_window)
+            .unwrap_or(self.stream_window)
+    }
+}
+
+/// The minimal state of a [`BaseChannel`] needed to resume serving its connection in another
+/// process, e.g. one that inherited the connection's file descriptor during a live upgrade.
+/// Created by [`BaseChannel::snapshot`] and consumed by [`BaseChannel::restore`].
+///
+/// This API is experimental and may change in backwards-incompatible ways.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ChannelSnapshot {
+    /// The [`Config::pending_response_buffer`] of the channel.
+    pub pending_response_buffer: usize,
+    /// The [stream window](Config::stream_window) in effect for the channel.
+    pub stream_window: usize,
+    /// The requests awaiting responses.
+    pub in_flight: Vec<InFlightSnapshot>,
+    /// The capabilities the client announced in its handshake, if it did.
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub peer_capabilities: Option<Capabilities>,
+}
+
+/// A request awaiting a response, as recorded in a [`ChannelSnapshot`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct InFlightSnapshot {
+    /// The ID of the request.
+    pub request_id: u64,
+    /// When the request expires, including any keep-alive extensions so far.
+    pub deadline: SystemTime,
+    /// How far each keep-alive extends the deadline, if the client consented to keep-alives.
+    pub keep_alive: Option<Duration>,
+}
+
 impl Config {
     /// Returns a channel backed by `transport` and configured with `self`.
     pub fn channel<Req, Resp, T>(self, transport: T) -> BaseChannel<Req, Resp, T>
     where
-        T: Transport<Response<Resp>, ClientMessage<Req>>,
+        T: Transport<ServerMessage<Resp>, ClientMessage<Req>>,
     {
         BaseChannel::new(self, transport)
     }
+
+    /// Returns a stream of channels, one for each transport yielded by `transports` (e.g. a
+    /// listener), configured with `self`.
+    ///
+    /// This allows each listener of a server to use its own config. The settings applied when
+    /// requests are executed can be overridden for the services of a
+    /// [`Router`](crate::router::Router) (see [`Serve::config`]):
+    ///
+    /// ```rust
+    /// # #[cfg(not(all(feature = "serde1", feature = "tokio1")))]
+    /// # fn main() {}
+    /// # #[cfg(all(feature = "serde1", feature = "tokio1"))]
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// # use futures::{future, prelude::*};
+    /// # use tarpc::{context, router::Router, server::{Channel, Config}, transport::channel};
+    /// # let (_client, transport) = channel::unbounded();
+    /// # let listener = stream::iter(vec![transport]);
+    /// let hello = |_: context::Context, name: String| future::ready(format!("Hello, {name}!"));
+    /// let add = |_: context::Context, (x, y): (i32, i32)| future::ready(x + y);
+    ///
+    /// let listener_config = Config::default();
+    /// // The panics of `add` are caught, while `hello` is served with the listener's config.
+    /// let add_config = listener_config.clone().with_catch_panics(true);
+    /// let router = Router::new(hello).mount_with_config(add, add_config);
+    /// listener_config
+    ///     .channels(listener)
+    ///     .for_each(|channel| {
+    ///         tokio::spawn(channel.execute(router.clone()));
+    ///         future::ready(())
+    ///     })
+    ///     .await;
+    /// # }
+    /// ```
+    pub fn channels<Req, Resp, T, S>(
+        self,
+        transports: S,
+    ) -> impl Stream<Item = BaseChannel<Req, Resp, T>>
+    where
+        S: Stream<Item = T>,
+        T: Transport<ServerMessage<Resp>, ClientMessage<Req>>,
+    {
+        transports.map(move |transport| BaseChannel::new(self.clone(), transport))
+    }
 }
 
 /// Equivalent to a `FnOnce(Req) -> impl Future<Output = Resp>`.
@@ -79,8 +519,35 @@ pub trait Serve<Req> {
         None
     }
 
-    /// Responds to a single request.
-    fn serve(self, ctx: context::Context, req: Req) -> Self::Fut;
+    /// Returns the config overriding the channel's for the request, if any, e.g. that of the
+    /// service a [`Router`](crate::router::Router) dispatches the request to. Only the settings
+    /// applied when the request is [executed](InFlightRequest::execute) are overridden:
+    /// [`rejections`](Config::rejections), [`faults`](Config::faults),
+    /// [`metrics`](Config::metrics), and [`catch_panics`](Config::catch_panics). The others are
+    /// settings of the channel as a whole.
+    fn config(&self, _request: &Req) -> Option<&Config> {
+        None
+    }
+
+    /// Responds to a single request, or fails with the error the request was rejected or failed
+    /// with. A request answered with a stream fails, since it has no single response.
+    fn serve(self, ctx: context::Context, req: Req) -> SingleResponse<Self::Fut>
+    where
+        Self: Sized,
+    {
+        self.serve_with_items(ctx, req, RequestStream::empty())
+            .into()
+    }
+
+    /// Handles a single request, given the stream of items the client sends after a
+    /// client-streaming request. The request can be answered with a single response or a stream
+    /// of responses, or rejected without being handled; see [`Served`].
+    fn serve_with_items(
+        self,
+        ctx: context::Context,
+        req: Req,
+        items: RequestStream<Req>,
+    ) -> Served<Self::Fut>;
 }
 
 impl<Req, Resp, Fut, F> Serve<Req> for F
@@ -91,8 +558,350 @@ where
     type Resp = Resp;
     type Fut = Fut;
 
-    fn serve(self, ctx: context::Context, req: Req) -> Self::Fut {
-        self(ctx, req)
+    fn serve_with_items(
+        self,
+        ctx: context::Context,
+        req: Req,
+        items: RequestStream<Req>,
+    ) -> Served<Self::Fut> {
+        drop(items);
+        Served::Response(self(ctx, req))
+    }
+}
+
+/// The handler for a single request, returned by [`Serve::serve_with_items`].
+pub enum Served<Fut: Future> {
+    /// A future that resolves to the single response to the request.
+    Response(Fut),
+    /// A stream of responses to a server-streaming request.
+    Stream(Pin<Box<dyn Stream<Item = Fut::Output> + Send>>),
+    /// The request is rejected with an error without being handled, e.g. because it is invalid.
+    Error(ServerError),
+    /// A future that resolves to either the single response or an error, e.g. one returned by
+    /// tower middleware around the handler.
+    Fallible(Pin<Box<dyn Future<Output = Result<Fut::Output, ServerError>> + Send>>),
+}
+
+impl<Fut> fmt::Debug for Served<Fut>
+where
+    Fut: Future + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Served::Response(response) => f.debug_tuple("Response").field(response).finish(),
+            Served::Stream(_) => f.debug_tuple("Stream").finish(),
+            Served::Error(error) => f.debug_tuple("Error").field(error).finish(),
+            Served::Fallible(_) => f.debug_tuple("Fallible").finish(),
+        }
+    }
+}
+
+impl<Fut: Future> Served<Fut> {
+    /// Maps the future of a single response with `f`, leaving other handlers as they are.
+    pub fn map_response<F, G>(self, f: F) -> Served<G>
+    where
+        F: FnOnce(Fut) -> G,
+        G: Future<Output = Fut::Output>,
+    {
+        match self {
+            Served::Response(response) => Served::Response(f(response)),
+            Served::Stream(responses) => Served::Stream(responses),
+            Served::Error(error) => Served::Error(error),
+            Served::Fallible(response) => Served::Fallible(response),
+        }
+    }
+}
+
+/// The future returned by [`Serve::serve`], which resolves to the single response to a request,
+/// or to the error the request was rejected or failed with. A request answered with a stream
+/// fails with an [`Unsupported`](io::ErrorKind::Unsupported) error, since only
+/// [`Serve::serve_with_items`] can answer it.
+#[pin_project]
+pub struct SingleResponse<Fut: Future>(#[pin] SingleResponseInner<Fut>);
+
+#[pin_project(project = SingleResponseProj)]
+enum SingleResponseInner<Fut: Future> {
+    Response(#[pin] Fut),
+    Fallible(Pin<Box<dyn Future<Output = Result<Fut::Output, ServerError>> + Send>>),
+    Error(Option<ServerError>),
+}
+
+impl<Fut: Future> From<Served<Fut>> for SingleResponse<Fut> {
+    fn from(served: Served<Fut>) -> Self {
+        SingleResponse(match served {
+            Served::Response(response) => SingleResponseInner::Response(response),
+            Served::Fallible(response) => SingleResponseInner::Fallible(response),
+            Served::Error(error) => SingleResponseInner::Error(Some(error)),
+            Served::Stream(_) => SingleResponseInner::Error(Some(ServerError::new(
+                io::ErrorKind::Unsupported,
+                "the request was answered with a stream, but only a single response was expected",
+            ))),
+        })
+    }
+}
+
+impl<Fut: Future> Future for SingleResponse<Fut> {
+    type Output = Result<Fut::Output, ServerError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project().0.project() {
+            SingleResponseProj::Response(response) => response.poll(cx).map(Ok),
+            SingleResponseProj::Fallible(response) => response.as_mut().poll(cx),
+            SingleResponseProj::Error(error) => Poll::Ready(Err(error
+                .take()
+                .expect("SingleResponse polled after completion"))),
+        }
+    }
+}
+
+impl<Fut: Future> fmt::Debug for SingleResponse<Fut> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SingleResponse").finish_non_exhaustive()
+    }
+}
+
+/// Values shared with request handlers, keyed by type.
+///
+/// The serving functions generated by [`tarpc::service`](crate::service) resolve the `#[inject]`
+/// args of rpcs from the dependencies they're created with. Dependencies are cheap to clone, so a
+/// server can hold one set of dependencies and extend a clone of it for each connection, e.g.
+/// with the address of the client.
+#[derive(Clone, Default)]
+pub struct Dependencies(FnvHashMap<TypeId, Arc<dyn Any + Send + Sync>>);
+
+impl Dependencies {
+    /// Returns an empty set of dependencies.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a dependency, replacing any dependency of the same type.
+    pub fn insert<T: Clone + Send + Sync + 'static>(&mut self, dependency: T) -> &mut Self {
+        self.0.insert(TypeId::of::<T>(), Arc::new(dependency));
+        self
+    }
+
+    /// Returns a clone of the dependency of type `T`, if any.
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.0.get(&TypeId::of::<T>())?.downcast_ref().cloned()
+    }
+}
+
+impl fmt::Debug for Dependencies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dependencies")
+            .field("len", &self.0.len())
+            .finish()
+    }
+}
+
+/// The items a client sends after a client-streaming request, in the order they were sent.
+///
+/// The stream ends when the client closes its end of the stream. Items are received as requests
+/// and converted to `Item`s; see [`RequestStream::items`]. At most
+/// [`stream_window`](Config::stream_window) items are buffered; beyond that, the channel stops
+/// reading until the handler catches up.
+pub struct RequestStream<Req, Item = Req> {
+    items: mpsc::UnboundedReceiver<(Req, OwnedSemaphorePermit)>,
+    extract: fn(Req) -> Option<Item>,
+}
+
+impl<Req> RequestStream<Req> {
+    /// Returns a stream that buffers up to `capacity` items, and the sender feeding it.
+    fn bounded(capacity: usize) -> (RequestStreamSender<Req>, Self) {
+        let (items_tx, items) = mpsc::unbounded_channel();
+        let capacity = Semaphore::new(capacity.clamp(1, Semaphore::MAX_PERMITS));
+        let sender = RequestStreamSender {
+            items: items_tx,
+            capacity: PollSemaphore::new(Arc::new(capacity)),
+        };
+        let stream = Self {
+            items,
+            extract: Some,
+        };
+        (sender, stream)
+    }
+
+    /// Returns a stream without any items.
+    pub fn empty() -> Self {
+        Self::bounded(1).1
+    }
+
+    /// Converts each item with `extract`, skipping the items for which it returns `None`.
+    pub fn items<Item>(self, extract: fn(Req) -> Option<Item>) -> RequestStream<Req, Item> {
+        RequestStream {
+            items: self.items,
+            extract,
+        }
+    }
+}
+
+impl<Req, Item> Stream for RequestStream<Req, Item> {
+    type Item = Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Item>> {
+        let this = self.get_mut();
+        loop {
+            // The permit is released as soon as the item is taken off the buffer.
+            match ready!(this.items.poll_recv(cx)) {
+                Some((item, _permit)) => {
+                    if let Some(item) = (this.extract)(item) {
+                        return Poll::Ready(Some(item));
+                    }
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl<Req, Item> fmt::Debug for RequestStream<Req, Item> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RequestStream")
+    }
+}
+
+/// Feeds the items of a client-streaming request to its [`RequestStream`].
+struct RequestStreamSender<Req> {
+    items: mpsc::UnboundedSender<(Req, OwnedSemaphorePermit)>,
+    /// Holds a permit for each free slot of the stream's buffer.
+    capacity: PollSemaphore,
+}
+
+impl<Req> RequestStreamSender<Req> {
+    /// Sends `item` if there's room for it in the buffer, otherwise returns it.
+    fn try_send(&mut self, item: Req) -> Result<(), Req> {
+        match self.capacity.clone_inner().try_acquire_owned() {
+            Ok(permit) => {
+                // The handler may have stopped listening for items, which is fine.
+                let _ = self.items.send((item, permit));
+                Ok(())
+            }
+            Err(_) => Err(item),
+        }
+    }
+
+    /// Sends `item` once there's room for it in the buffer. Returns the item if there isn't yet.
+    fn poll_send(&mut self, cx: &mut Context<'_>, item: Req) -> Result<(), Req> {
+        if self.items.is_closed() {
+            return Ok(());
+        }
+        match self.capacity.poll_acquire(cx) {
+            Poll::Ready(Some(permit)) => {
+                let _ = self.items.send((item, permit));
+                Ok(())
+            }
+            // The semaphore is never closed.
+            Poll::Ready(None) => Ok(()),
+            Poll::Pending => Err(item),
+        }
+    }
+}
+
+/// The number of items a server-streaming request handler is allowed to send before waiting for
+/// the client to grant more. See [`Config::stream_window`].
+#[derive(Clone, Debug)]
+pub struct StreamCredits(Arc<Semaphore>);
+
+impl StreamCredits {
+    pub(crate) fn new(credits: usize) -> Self {
+        Self(Arc::new(Semaphore::new(
+            credits.min(Semaphore::MAX_PERMITS),
+        )))
+    }
+
+    /// Allows `credits` more items to be sent.
+    pub(crate) fn grant(&self, credits: usize) {
+        let available = Semaphore::MAX_PERMITS - self.0.available_permits();
+        self.0.add_permits(credits.min(available));
+    }
+
+    /// Waits until an item is allowed to be sent, and consumes the credit.
+    pub(crate) async fn acquire(&self) {
+        // The semaphore is never closed.
+        if let Ok(permit) = self.0.acquire().await {
+            permit.forget();
+        }
+    }
+}
+
+impl Default for StreamCredits {
+    fn default() -> Self {
+        Self::new(Config::default().stream_window)
+    }
+}
+
+/// Asks a request handler to stop while it can still clean up, e.g. persist partial progress or
+/// release external resources.
+///
+/// When a request's deadline expires and [`Config::deadline_notice`] is set, the request's
+/// cancellation is triggered, and the handler is aborted only once the notice has passed, too.
+/// When [`Config::cooperative_cancellation`] is set, the cancellation is also triggered when the
+/// client cancels the request, instead of the handler being aborted. A handler gets the
+/// cancellation of its request from [`Cancellation::current`].
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation(CancellationToken);
+
+thread_local! {
+    static CURRENT_CANCELLATION: RefCell<Option<Cancellation>> = const { RefCell::new(None) };
+}
+
+impl Cancellation {
+    /// Returns the cancellation of the request whose handler is being polled on the current
+    /// thread, if any. Handlers should call this before awaiting anything, e.g. at the top of an
+    /// `async fn`.
+    pub fn current() -> Option<Self> {
+        CURRENT_CANCELLATION.with(|current| current.borrow().clone())
+    }
+
+    /// Returns true if the request was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.is_cancelled()
+    }
+
+    /// Waits until the request is cancelled.
+    pub async fn cancelled(&self) {
+        self.0.cancelled().await
+    }
+
+    /// Returns a token that is cancelled along with the request, to pass to code built on
+    /// [`CancellationToken`]s.
+    pub fn token(&self) -> CancellationToken {
+        self.0.child_token()
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.0.cancel()
+    }
+}
+
+/// A future that makes a [`Cancellation`] current while it is polled.
+#[pin_project]
+struct WithCancellation<F> {
+    #[pin]
+    inner: F,
+    cancellation: Cancellation,
+}
+
+impl<F: Future> Future for WithCancellation<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.project();
+        let previous =
+            CURRENT_CANCELLATION.with(|current| current.replace(Some(this.cancellation.clone())));
+        let _guard = RestoreCancellation(previous);
+        this.inner.poll(cx)
+    }
+}
+
+/// Restores the cancellation of an enclosing scope, even when the handler unwinds.
+struct RestoreCancellation(Option<Cancellation>);
+
+impl Drop for RestoreCancellation {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT_CANCELLATION.with(|current| *current.borrow_mut() = previous);
     }
 }
 
@@ -105,7 +914,9 @@ where
 /// Besides requests, the other type of client message handled by `BaseChannel` is [cancellation
 /// messages](ClientMessage::Cancel). `BaseChannel` does not allow direct access to cancellation
 /// messages. Instead, it internally handles them by cancelling corresponding requests (removing
-/// the corresponding in-flight requests and aborting their handlers).
+/// the corresponding in-flight requests and aborting their handlers, or, if the config
+/// [cancels cooperatively](Config::cooperative_cancellation), triggering their
+/// [`Cancellation`]s).
 #[pin_project]
 pub struct BaseChannel<Req, Resp, T> {
     config: Config,
@@ -117,25 +928,82 @@ pub struct BaseChannel<Req, Resp, T> {
     canceled_requests: CanceledRequests,
     /// Notifies `canceled_requests` when a request is canceled.
     request_cancellation: RequestCancellation,
+    /// Traces canceled through the [`TraceCanceler`] of the config, if any.
+    canceled_traces: Option<mpsc::UnboundedReceiver<trace::TraceId>>,
+    /// Completes when the server is shut down through the [`Shutdown`] of the config, if any.
+    shutdown: Option<shutdown::Signal>,
+    /// Counts the channel as open until it's dropped.
+    _shutdown_guard: Option<shutdown::ChannelGuard>,
+    /// The address of the client, set on the context of each request.
+    peer_addr: Option<SocketAddr>,
+    /// The identity of the client, made current for the handler of each request.
+    peer_identity: Option<Arc<PeerIdentity>>,
     /// Holds data necessary to clean up in-flight requests.
     in_flight_requests: InFlightRequests,
+    /// Forwards request items to the handlers of in-flight requests.
+    request_streams: FnvHashMap<u64, RequestStreamSender<Req>>,
+    /// A request item that didn't fit in the buffer of its request stream. No more messages are
+    /// read until it does.
+    blocked_request_item: Option<(u64, Req)>,
+    /// The number of messages dropped because their request was already responded to.
+    duplicate_responses: u64,
+    /// The number of messages skipped because they failed to decode.
+    undecodable_messages: u64,
+    /// Errors to send for requests the channel failed itself, e.g. because they failed to decode,
+    /// before any other message.
+    error_responses: VecDeque<Response<Resp>>,
+    /// The capabilities the client announced in its handshake, if it did.
+    peer_capabilities: Option<Capabilities>,
+    /// True if the client opened a handshake that hasn't been answered yet.
+    answer_hello: bool,
+    /// Requests unpacked from a batch that haven't been started yet.
+    batched_requests: VecDeque<Request<Req>>,
+    /// Wakes the channel to check the `before_flush` failpoint again while it holds back
+    /// responses.
+    #[cfg(feature = "failpoints")]
+    failpoint_backoff: Option<Pin<Box<::tokio::time::Sleep>>>,
+    /// The span of the channel, the parent of the spans of its requests.
+    span: Span,
     /// Types the request and response.
     ghost: PhantomData<(Req, Resp)>,
 }
 
 impl<Req, Resp, T> BaseChannel<Req, Resp, T>
 where
-    T: Transport<Response<Resp>, ClientMessage<Req>>,
+    T: Transport<ServerMessage<Resp>, ClientMessage<Req>>,
 {
     /// Creates a new channel backed by `transport` and configured with `config`.
     pub fn new(config: Config, transport: T) -> Self {
         let (request_cancellation, canceled_requests) = cancellations();
+        let canceled_traces = config.trace_canceler.as_ref().map(TraceCanceler::register);
+        let shutdown = config.shutdown.as_ref().map(Shutdown::signal);
+        let _shutdown_guard = config.shutdown.as_ref().map(Shutdown::track_channel);
         BaseChannel {
             config,
             transport: transport.fuse(),
             canceled_requests,
             request_cancellation,
+            canceled_traces,
+            shutdown,
+            _shutdown_guard,
+            peer_addr: None,
+            peer_identity: None,
             in_flight_requests: InFlightRequests::default(),
+            request_streams: FnvHashMap::default(),
+            blocked_request_item: None,
+            duplicate_responses: 0,
+            undecodable_messages: 0,
+            error_responses: VecDeque::new(),
+            peer_capabilities: None,
+            answer_hello: false,
+            batched_requests: VecDeque::new(),
+            #[cfg(feature = "failpoints")]
+            failpoint_backoff: None,
+            span: info_span!(
+                "Channel",
+                rpc.peer_addr = tracing::field::Empty,
+                otel.kind = "server",
+            ),
             ghost: PhantomData,
         }
     }
@@ -145,11 +1013,116 @@ where
         Self::new(Config::default(), transport)
     }
 
+    /// Sets the address of the client, which request handlers read from the
+    /// [`peer_addr`](context::Context::peer_addr) of the request context. The address is typically
+    /// read from the transport before the channel is created, e.g. with
+    /// [`Transport::peer_addr`](crate::serde_transport::Transport::peer_addr) for TCP transports.
+    pub fn with_peer_addr(mut self, peer_addr: SocketAddr) -> Self {
+        self.peer_addr = Some(peer_addr);
+        self.span
+            .record("rpc.peer_addr", tracing::field::display(peer_addr));
+        self
+    }
+
+    /// Returns the address of the client, if it was set.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Sets the verified identity of the client, which request handlers read from
+    /// [`PeerIdentity::current`]. The identity is typically read from the transport before the
+    /// channel is created, e.g. with
+    /// [`Transport::peer_identity`](crate::serde_transport::Transport::peer_identity) for TLS
+    /// transports that authenticate clients.
+    pub fn with_peer_identity(mut self, peer_identity: PeerIdentity) -> Self {
+        self.peer_identity = Some(Arc::new(peer_identity));
+        self
+    }
+
+    /// Returns the identity of the client, if it was set.
+    pub fn peer_identity(&self) -> Option<&PeerIdentity> {
+        self.peer_identity.as_deref()
+    }
+
+    /// Captures the settings of the channel and the requests awaiting responses, so that the
+    /// connection can be [restored](Self::restore) in another process without failing in-flight
+    /// calls.
+    ///
+    /// This API is experimental and may change in backwards-incompatible ways.
+    pub fn snapshot(&self) -> ChannelSnapshot {
+        ChannelSnapshot {
+            pending_response_buffer: self.config.pending_response_buffer,
+            stream_window: self.config.current_stream_window(),
+            in_flight: self
+                .in_flight_requests
+                .iter()
+                .map(|(request_id, deadline, keep_alive)| InFlightSnapshot {
+                    request_id,
+                    deadline,
+                    keep_alive,
+                })
+                .collect(),
+            peer_capabilities: self.peer_capabilities,
+        }
+    }
+
+    /// Creates a new channel backed by `transport` that resumes the channel captured by
+    /// `snapshot`. The settings of the snapshot override those of `config`.
+    ///
+    /// The requests of the snapshot are tracked as in flight, so that a response sent for one of
+    /// them via [`Sink::start_send`] reaches the client, and so that they are cleaned up on
+    /// cancellation or when their deadline expires, as if they had been received by this channel.
+    /// Request items sent by the client after the snapshot was taken are dropped.
+    ///
+    /// This API is experimental and may change in backwards-incompatible ways.
+    pub fn restore(mut config: Config, transport: T, snapshot: ChannelSnapshot) -> Self {
+        config.pending_response_buffer = snapshot.pending_response_buffer;
+        config.stream_window = snapshot.stream_window;
+        let mut channel = Self::new(config, transport);
+        channel.peer_capabilities = snapshot.peer_capabilities;
+        for request in snapshot.in_flight {
+            let span = info_span!(
+                parent: &channel.span,
+                "RPC",
+                rpc.request_id = request.request_id,
+                rpc.deadline = %humantime::format_rfc3339(request.deadline),
+                otel.kind = "server",
+            );
+            span.in_scope(|| tracing::info!("RestoreRequest"));
+            let stream_credits = StreamCredits::new(snapshot.stream_window);
+            // The handler of the request runs elsewhere, so there's nothing to abort.
+            // The trace of the request isn't recorded, so it can't be canceled by trace.
+            let _ = channel.in_flight_requests.start_request(
+                request.request_id,
+                trace::TraceId::default(),
+                request.deadline,
+                request.keep_alive,
+                stream_credits,
+                Cancellation::default(),
+                span,
+            );
+        }
+        channel
+    }
+
     /// Returns the inner transport over which messages are sent and received.
     pub fn get_ref(&self) -> &T {
         self.transport.get_ref()
     }
 
+    /// Returns the number of responses dropped because their request was already responded to,
+    /// e.g. by a handler that sent two responses. Such responses are never written to the wire,
+    /// where they could be mistaken for the response to a later request.
+    pub fn duplicate_responses(&self) -> u64 {
+        self.duplicate_responses
+    }
+
+    /// Returns the number of messages skipped because they failed to decode, when the config
+    /// [skips](DecodeErrorPolicy::Skip) them.
+    pub fn undecodable_messages(&self) -> u64 {
+        self.undecodable_messages
+    }
+
     /// Returns the inner transport over which messages are sent and received.
     pub fn get_pin_ref(self: Pin<&mut Self>) -> Pin<&mut T> {
         self.project().transport.get_pin_mut()
@@ -165,47 +1138,170 @@ where
         self.as_mut().project().canceled_requests
     }
 
-    fn transport_pin_mut<'a>(self: &'a mut Pin<&mut Self>) -> Pin<&'a mut Fuse<T>> {
-        self.as_mut().project().transport
-    }
-
-    fn start_request(
-        mut self: Pin<&mut Self>,
-        mut request: Request<Req>,
-    ) -> Result<TrackedRequest<Req>, AlreadyExistsError> {
+    /// Cancels the in-flight requests of the next trace canceled through the config's
+    /// [`TraceCanceler`].
+    fn poll_canceled_traces(self: &mut Pin<&mut Self>, cx: &mut Context) -> Poll<Option<()>> {
+        let trace_id = match self.as_mut().project().canceled_traces {
+            Some(canceled_traces) => ready!(canceled_traces.poll_recv(cx)),
+            None => None,
+        };
+        Poll::Ready(trace_id.map(|trace_id| {
+            let cooperative = self.config.cooperative_cancellation;
+            for request_id in self
+                .in_flight_requests_mut()
+                .cancel_trace(trace_id, cooperative)
+            {
+                self.end_request_stream(request_id);
+            }
+        }))
+    }
+
+    /// Returns true if the server is shut down through the config's [`Shutdown`].
+    fn poll_shutdown(self: &mut Pin<&mut Self>, cx: &mut Context) -> bool {
+        match self.as_mut().project().shutdown {
+            Some(shutdown) => shutdown.poll(cx),
+            None => false,
+        }
+    }
+
+    fn transport_pin_mut<'a>(self: &'a mut Pin<&mut Self>) -> Pin<&'a mut Fuse<T>> {
+        self.as_mut().project().transport
+    }
+
+    fn request_streams_mut<'a>(
+        self: &'a mut Pin<&mut Self>,
+    ) -> &'a mut FnvHashMap<u64, RequestStreamSender<Req>> {
+        self.as_mut().project().request_streams
+    }
+
+    /// Forwards `item` to the stream of request `request_id`, or blocks it if the stream's buffer
+    /// is full.
+    fn forward_request_item(self: &mut Pin<&mut Self>, request_id: u64, item: Req) {
+        let this = self.as_mut().project();
+        match this.request_streams.get_mut(&request_id) {
+            Some(request_stream) => {
+                if let Err(item) = request_stream.try_send(item) {
+                    tracing::trace!(
+                        rpc.request_id = request_id,
+                        "Request stream is full; pausing reads until the handler catches up.",
+                    );
+                    *this.blocked_request_item = Some((request_id, item));
+                }
+            }
+            None => tracing::trace!(
+                rpc.request_id = request_id,
+                "Received request stream item, but request is not in flight.",
+            ),
+        }
+    }
+
+    /// Forwards the blocked request item, if any. Returns Pending while it's still blocked.
+    fn poll_blocked_request_item(self: &mut Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.as_mut().project();
+        let (request_id, item) = match this.blocked_request_item.take() {
+            Some(blocked) => blocked,
+            None => return Poll::Ready(()),
+        };
+        // If the request is no longer in flight, the item is dropped.
+        if let Some(request_stream) = this.request_streams.get_mut(&request_id) {
+            if let Err(item) = request_stream.poll_send(cx, item) {
+                *this.blocked_request_item = Some((request_id, item));
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(())
+    }
+
+    /// Ends the stream of request items of a request that is no longer in flight.
+    fn end_request_stream(self: &mut Pin<&mut Self>, request_id: u64) {
+        if self.request_streams_mut().remove(&request_id).is_some() {
+            self.request_streams_mut().compact(0.1);
+        }
+    }
+
+    /// Fails the requests of a batch that haven't started, because the server is shutting down.
+    fn fail_batched_requests(self: Pin<&mut Self>) {
+        let this = self.project();
+        for request in this.batched_requests.drain(..) {
+            tracing::info!(rpc.request_id = request.id, "ShutdownBatchedRequest");
+            this.error_responses.push_back(Response {
+                request_id: request.id,
+                message: Err(ServerError::shutdown(
+                    "the server shut down before starting the request",
+                )),
+            });
+        }
+    }
+
+    /// Returns the span of the request `request_id` received in `context`, a child of the span of
+    /// the channel, and makes the context a child of it. Returns a disabled span if the config
+    /// doesn't [trace unsampled](Config::trace_unsampled) requests and the request is unsampled.
+    fn request_span(&self, request_id: u64, context: &mut context::Context) -> Span {
+        context.peer_addr = self.peer_addr;
+        if !self.config.trace_unsampled && !context.trace_context.is_sampled() {
+            context.trace_context = context.trace_context.new_child();
+            return Span::none();
+        }
         let span = info_span!(
+            parent: &self.span,
             "RPC",
-            rpc.trace_id = %request.context.trace_id(),
-            rpc.deadline = %humantime::format_rfc3339(request.context.deadline),
+            rpc.trace_id = %context.trace_id(),
+            rpc.request_id = request_id,
+            rpc.deadline = %humantime::format_rfc3339(context.deadline),
+            rpc.method = tracing::field::Empty,
             otel.kind = "server",
             otel.name = tracing::field::Empty,
         );
-        span.set_context(&request.context);
-        request.context.trace_context = trace::Context::try_from(&span).unwrap_or_else(|_| {
+        span.set_context(context);
+        context.trace_context = trace::Context::try_from(&span).unwrap_or_else(|_| {
             tracing::trace!(
                 "OpenTelemetry subscriber not installed; making unsampled \
                             child context."
             );
-            request.context.trace_context.new_child()
+            context.trace_context.new_child()
         });
+        span
+    }
+
+    fn start_request(
+        mut self: Pin<&mut Self>,
+        mut request: Request<Req>,
+    ) -> Result<TrackedRequest<Req>, AlreadyExistsError> {
+        let span = self.request_span(request.id, &mut request.context);
         let entered = span.enter();
         tracing::info!("ReceiveRequest");
+        let stream_credits = StreamCredits::new(self.config.current_stream_window());
+        let cancellation = Cancellation::default();
         let start = self.in_flight_requests_mut().start_request(
             request.id,
+            *request.context.trace_id(),
             request.context.deadline,
+            request.context.keep_alive,
+            stream_credits.clone(),
+            cancellation.clone(),
             span.clone(),
         );
         match start {
             Ok(abort_registration) => {
                 drop(entered);
+                let (request_items_tx, request_items) =
+                    RequestStream::bounded(self.config.current_stream_window());
+                self.request_streams_mut()
+                    .insert(request.id, request_items_tx);
                 Ok(TrackedRequest {
                     abort_registration,
+                    request_items,
+                    stream_credits,
+                    cancellation,
+                    peer_identity: self.peer_identity.clone(),
+                    received: util::time::now(),
                     span,
                     response_guard: ResponseGuard {
                         request_id: request.id,
                         request_cancellation: self.request_cancellation.clone(),
                         cancel: false,
                     },
+                    one_way: false,
                     request,
                 })
             }
@@ -215,8 +1311,72 @@ where
             }
         }
     }
+
+    /// Skips a message that failed to decode: counts it, records a rejection if the config keeps
+    /// a [log](Config::rejections), and fails the request the message carried if its ID is known
+    /// and no request with the same ID is in flight.
+    fn skip_undecodable_message(self: Pin<&mut Self>, error: &DecodeError) {
+        let this = self.project();
+        *this.undecodable_messages += 1;
+        tracing::warn!(
+            rpc.request_id = error.request_id(),
+            %error,
+            "SkipUndecodableMessage"
+        );
+        if let Some(rejections) = &this.config.rejections {
+            let mut rejection =
+                Rejection::new(RejectReason::Invalid).with_detail(error.to_string());
+            if let Some(peer_addr) = this.peer_addr {
+                rejection = rejection.with_peer(*peer_addr);
+            }
+            rejections.record(rejection);
+        }
+        if let Some(request_id) = error.request_id() {
+            if this.in_flight_requests.span(request_id).is_none() {
+                this.error_responses.push_back(Response {
+                    request_id,
+                    message: Err(ServerError::new(
+                        io::ErrorKind::InvalidData,
+                        error.to_string(),
+                    )),
+                });
+            }
+        }
+    }
+
+    /// Returns a [one-way](ClientMessage::OneWay) request, which the channel doesn't track: it
+    /// can't be canceled, and nothing is responded to it.
+    fn start_one_way(&self, mut context: context::Context, message: Req) -> TrackedRequest<Req> {
+        let span = self.request_span(ONE_WAY_REQUEST_ID, &mut context);
+        span.in_scope(|| tracing::info!("ReceiveOneWayRequest"));
+        let (_, abort_registration) = AbortHandle::new_pair();
+        TrackedRequest {
+            request: Request {
+                context,
+                id: ONE_WAY_REQUEST_ID,
+                message,
+            },
+            abort_registration,
+            request_items: RequestStream::empty(),
+            stream_credits: StreamCredits::default(),
+            cancellation: Cancellation::default(),
+            peer_identity: self.peer_identity.clone(),
+            received: util::time::now(),
+            span,
+            response_guard: ResponseGuard {
+                request_id: ONE_WAY_REQUEST_ID,
+                request_cancellation: self.request_cancellation.clone(),
+                cancel: false,
+            },
+            one_way: true,
+        }
+    }
 }
 
+/// The [ID](Request::id) of every [one-way](TrackedRequest::one_way) request, which has none on
+/// the wire.
+pub const ONE_WAY_REQUEST_ID: u64 = u64::MAX;
+
 impl<Req, Resp, T> fmt::Debug for BaseChannel<Req, Resp, T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "BaseChannel")
@@ -231,10 +1391,24 @@ pub struct TrackedRequest<Req> {
     /// A registration to abort a future when the [`Channel`] that produced this request stops
     /// tracking it.
     pub abort_registration: AbortRegistration,
+    /// The items the client sends after the request, if the request is client-streaming.
+    pub request_items: RequestStream<Req>,
+    /// The number of stream items the handler may send, if the request is server-streaming.
+    /// Replenished by the [`Channel`] as the client consumes items.
+    pub stream_credits: StreamCredits,
+    /// Triggered when the request's deadline expires, if [`Config::deadline_notice`] is set.
+    pub cancellation: Cancellation,
+    /// The identity of the client, if the [`Channel`] was given one.
+    pub peer_identity: Option<Arc<PeerIdentity>>,
+    /// When the [`Channel`] received the request.
+    pub received: SystemTime,
     /// A span representing the server processing of this request.
     pub span: Span,
     /// An inert response guard. Becomes active in an InFlightRequest.
     pub response_guard: ResponseGuard,
+    /// True if the request is [one-way](ClientMessage::OneWay): the channel doesn't track it, and
+    /// nothing is responded to it. Its ID is [`ONE_WAY_REQUEST_ID`].
+    pub one_way: bool,
 }
 
 /// The server end of an open connection with a client, receiving requests from, and sending
@@ -253,18 +1427,22 @@ pub struct TrackedRequest<Req> {
 ///    [`Sink::send`](futures::sink::SinkExt::send) - A user is free to manually read requests
 ///    from, and send responses into, a Channel in lieu of the previous methods. Channels stream
 ///    [`TrackedRequests`](TrackedRequest), which, in addition to the request itself, contains the
-///    server [`Span`], request lifetime [`AbortRegistration`], and an inert [`ResponseGuard`].
+///    server [`Span`], request lifetime [`AbortRegistration`], [`StreamCredits`], and an inert
+///    [`ResponseGuard`].
 ///    Wrapping response logic in an [`Abortable`] future using the abort registration will ensure
 ///    that the response does not execute longer than the request deadline. The `Channel` itself
 ///    will clean up request state once either the deadline expires, or the response guard is
-///    dropped, or a response is sent.
+///    dropped, or a response is sent. A server-streaming request may be answered with any number
+///    of [`StreamItem`](ServerMessage::StreamItem) messages, followed by a
+///    [`StreamEnd`](ServerMessage::StreamEnd); each item should only be sent after acquiring one
+///    of the request's stream credits.
 ///
 /// Channels must be implemented using the decorator pattern: the only way to create a
 /// `TrackedRequest` is to get one from another `Channel`. Ultimately, all `TrackedRequests` are
 /// created by [`BaseChannel`].
 pub trait Channel
 where
-    Self: Transport<Response<<Self as Channel>::Resp>, TrackedRequest<<Self as Channel>::Req>>,
+    Self: Transport<ServerMessage<<Self as Channel>::Resp>, TrackedRequest<<Self as Channel>::Req>>,
 {
     /// Type of request item.
     type Req;
@@ -284,6 +1462,20 @@ where
     /// Returns the transport underlying the channel.
     fn transport(&self) -> &Self::Transport;
 
+    /// Returns the capabilities the client announced in its [handshake](crate::capabilities), or
+    /// None if it hasn't opened one. Channels that wrap another channel should return the
+    /// capabilities of the inner channel.
+    fn peer_capabilities(&self) -> Option<Capabilities> {
+        None
+    }
+
+    /// Aborts the handlers of every request in flight, e.g. because the channel is being torn
+    /// down, and returns the IDs of the aborted requests. Channels that wrap another channel
+    /// should abort the requests of the inner channel.
+    fn abort_in_flight_requests(self: Pin<&mut Self>) -> Vec<u64> {
+        Vec::new()
+    }
+
     /// Caps the number of concurrent requests to `limit`. An error will be returned for requests
     /// over the concurrency limit.
     ///
@@ -301,6 +1493,30 @@ where
         limits::requests_per_channel::MaxRequests::new(self, limit)
     }
 
+    /// Rejects requests whose [advised size](crate::context::SizeHint::request) is over `limit`
+    /// bytes, before they are handled. Requests that don't advise their size are admitted.
+    fn max_request_size(self, limit: u64) -> limits::request_size::MaxRequestSize<Self>
+    where
+        Self: Sized,
+    {
+        limits::request_size::MaxRequestSize::new(self, limit)
+    }
+
+    /// Replaces responses whose serialized size is over `limit` bytes with an error, instead of
+    /// sending them. `sizer` computes the serialized size of a response, e.g. with the codec of
+    /// the transport.
+    fn max_response_size<F>(
+        self,
+        limit: u64,
+        sizer: F,
+    ) -> limits::response_size::MaxResponseSize<Self, F>
+    where
+        Self: Sized,
+        F: Fn(&Self::Resp) -> u64,
+    {
+        limits::response_size::MaxResponseSize::new(self, limit, sizer)
+    }
+
     /// Returns a stream of requests that automatically handle request cancellation and response
     /// routing.
     ///
@@ -311,12 +1527,17 @@ where
     where
         Self: Sized,
     {
-        let (responses_tx, responses) = mpsc::channel(self.config().pending_response_buffer);
+        let response_buffer = self.config().pending_response_buffer;
+        let (responses_tx, responses) = mpsc::channel(response_buffer);
 
         Requests {
             channel: self,
             pending_responses: responses,
             responses_tx,
+            response_buffer,
+            unflushed: 0,
+            flush_timer: None,
+            terminated: false,
         }
     }
 
@@ -337,27 +1558,60 @@ where
     }
 }
 
-/// Critical errors that result in a Channel disconnecting.
+/// Critical errors that result in a Channel disconnecting. Each carries the error of the
+/// transport that caused it.
+///
+/// A [shutdown](Shutdown) isn't an error: the channel stops reading requests and ends once the
+/// requests in flight are responded to.
 #[derive(thiserror::Error, Debug)]
 pub enum ChannelError<E>
 where
     E: Error + Send + Sync + 'static,
 {
-    /// An error occurred reading from, or writing to, the transport.
-    #[error("an error occurred in the transport: {0}")]
-    Transport(#[source] E),
+    /// Could not read a message from the transport.
+    #[error("could not read from the transport: {0}")]
+    Read(#[source] E),
+    /// Read a message that could not be [decoded](DecodeError), and the config doesn't
+    /// [skip](DecodeErrorPolicy::Skip) such messages.
+    #[error("could not decode a message: {0}")]
+    Decode(#[source] E),
+    /// Read a message that [violates the protocol](ProtocolError).
+    #[error("the client violated the protocol: {0}")]
+    Protocol(#[source] E),
+    /// Could not write a message to, flush, or close the transport.
+    #[error("could not write to the transport: {0}")]
+    Write(#[source] E),
     /// An error occurred while polling expired requests.
     #[error("an error occurred while polling expired requests: {0}")]
     Timer(#[source] ::tokio::time::error::Error),
 }
 
+impl<E> ChannelError<E>
+where
+    E: Error + Send + Sync + 'static,
+{
+    /// Classifies an error reading from the transport by the error in its chain.
+    fn read(e: E) -> Self {
+        if ProtocolError::find(&e).is_some() {
+            ChannelError::Protocol(e)
+        } else if DecodeError::find(&e).is_some() {
+            ChannelError::Decode(e)
+        } else {
+            ChannelError::Read(e)
+        }
+    }
+}
+
 impl<Req, Resp, T> Stream for BaseChannel<Req, Resp, T>
 where
-    T: Transport<Response<Resp>, ClientMessage<Req>>,
+    T: Transport<ServerMessage<Resp>, ClientMessage<Req>>,
 {
     type Item = Result<TrackedRequest<Req>, ChannelError<T::Error>>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let span = self.span.clone();
+        let _entered = span.enter();
+
         #[derive(Clone, Copy, Debug)]
         enum ReceiverStatus {
             Ready,
@@ -381,6 +1635,7 @@ where
         loop {
             let cancellation_status = match self.canceled_requests_pin_mut().poll_recv(cx) {
                 Poll::Ready(Some(request_id)) => {
+                    self.end_request_stream(request_id);
                     if let Some(span) = self.in_flight_requests_mut().remove_request(request_id) {
                         let _entered = span.enter();
                         tracing::info!("ResponseCancelled");
@@ -396,21 +1651,79 @@ where
                 Poll::Pending | Poll::Ready(None) => Closed,
             };
 
-            let expiration_status = match self.in_flight_requests_mut().poll_expired(cx) {
-                // No need to send a response, since the client wouldn't be waiting for one
-                // anymore.
-                Poll::Ready(Some(_)) => Ready,
+            // Like pending cancellations, pending trace cancellations don't block Channel closure.
+            //
+            // Ready(None) can't happen while the config holds the TraceCanceler.
+            let trace_cancellation_status = match self.poll_canceled_traces(cx) {
+                Poll::Ready(Some(())) => Ready,
+                Poll::Pending | Poll::Ready(None) => Closed,
+            };
+
+            let deadline_notice = self.config.deadline_notice;
+            let expiration_status = match self
+                .in_flight_requests_mut()
+                .poll_expired(cx, deadline_notice)
+            {
+                // Unless the config says otherwise, no response is sent, since the client
+                // wouldn't be waiting for one anymore.
+                Poll::Ready(Some(request_id)) => {
+                    self.end_request_stream(request_id);
+                    if self.config.respond_to_expired_requests {
+                        self.as_mut().project().error_responses.push_back(Response {
+                            request_id,
+                            message: Err(ServerError::deadline_exceeded(format!(
+                                "request {} expired before it was responded to",
+                                request_id
+                            ))),
+                        });
+                    }
+                    if let Some(rejections) = &self.config.rejections {
+                        let mut rejection = Rejection::new(RejectReason::DeadlineExceeded)
+                            .with_detail(format!("request {} expired", request_id));
+                        if let Some(peer_addr) = self.peer_addr {
+                            rejection = rejection.with_peer(peer_addr);
+                        }
+                        rejections.record(rejection);
+                    }
+                    Ready
+                }
                 Poll::Ready(None) => Closed,
                 Poll::Pending => Pending,
             };
 
-            let request_status = match self
-                .transport_pin_mut()
-                .poll_next(cx)
-                .map_err(ChannelError::Transport)?
-            {
+            // Requests of a batch are started before reading the next message. Once the server is
+            // shutting down, the channel starts no more requests and reads no more messages, as if
+            // the client had closed its write half.
+            let message = if self.poll_blocked_request_item(cx).is_pending() {
+                Poll::Pending
+            } else if self.poll_shutdown(cx) {
+                self.as_mut().fail_batched_requests();
+                Poll::Ready(None)
+            } else if let Some(request) = self.as_mut().project().batched_requests.pop_front() {
+                Poll::Ready(Some(ClientMessage::Request(request)))
+            } else {
+                match self.transport_pin_mut().poll_next(cx) {
+                    Poll::Ready(Some(Ok(message))) => Poll::Ready(Some(message)),
+                    Poll::Ready(Some(Err(e))) => {
+                        if self.config.decode_errors == DecodeErrorPolicy::Skip {
+                            if let Some(e) = DecodeError::find(&e) {
+                                self.as_mut().skip_undecodable_message(e);
+                                continue;
+                            }
+                        }
+                        return Poll::Ready(Some(Err(ChannelError::read(e))));
+                    }
+                    Poll::Ready(None) => Poll::Ready(None),
+                    Poll::Pending => Poll::Pending,
+                }
+            };
+            let request_status = match message {
                 Poll::Ready(Some(message)) => match message {
                     ClientMessage::Request(request) => {
+                        if util::fail_point("tarpc::server::after_decode", |_| ()).is_some() {
+                            // Drops the request, as if it were lost in transit.
+                            continue;
+                        }
                         match self.as_mut().start_request(request) {
                             Ok(request) => return Poll::Ready(Some(Ok(request))),
                             Err(AlreadyExistsError) => {
@@ -426,7 +1739,13 @@ where
                         trace_context,
                         request_id,
                     } => {
-                        if !self.in_flight_requests_mut().cancel_request(request_id) {
+                        self.end_request_stream(request_id);
+                        let found = if self.config.cooperative_cancellation {
+                            self.in_flight_requests_mut().notify_cancel(request_id)
+                        } else {
+                            self.in_flight_requests_mut().cancel_request(request_id)
+                        };
+                        if !found {
                             tracing::trace!(
                                 rpc.trace_id = %trace_context.trace_id,
                                 "Received cancellation, but response handler is already complete.",
@@ -434,6 +1753,47 @@ where
                         }
                         Ready
                     }
+                    ClientMessage::StreamCredit {
+                        request_id,
+                        credits,
+                    } => {
+                        if !self
+                            .in_flight_requests_mut()
+                            .grant_stream_credits(request_id, credits as usize)
+                        {
+                            tracing::trace!(
+                                rpc.request_id = request_id,
+                                "Received stream credits, but response handler is already complete.",
+                            );
+                        }
+                        Ready
+                    }
+                    ClientMessage::StreamItem { request_id, item } => {
+                        self.forward_request_item(request_id, item);
+                        Ready
+                    }
+                    ClientMessage::StreamEnd { request_id } => {
+                        self.end_request_stream(request_id);
+                        Ready
+                    }
+                    ClientMessage::OneWay { context, message } => {
+                        if util::fail_point("tarpc::server::after_decode", |_| ()).is_some() {
+                            continue;
+                        }
+                        return Poll::Ready(Some(Ok(self.start_one_way(context, message))));
+                    }
+                    ClientMessage::Hello { capabilities } => {
+                        tracing::info!(?capabilities, "ReceiveHello");
+                        let this = self.as_mut().project();
+                        *this.peer_capabilities = Some(capabilities);
+                        *this.answer_hello = true;
+                        Ready
+                    }
+                    ClientMessage::Batch { requests } => {
+                        tracing::trace!(requests = requests.len(), "ReceiveBatch");
+                        self.as_mut().project().batched_requests.extend(requests);
+                        Ready
+                    }
                 },
                 Poll::Ready(None) => Closed,
                 Poll::Pending => Pending,
@@ -445,6 +1805,7 @@ where
                 request_status
             );
             match cancellation_status
+                .combine(trace_cancellation_status)
                 .combine(expiration_status)
                 .combine(request_status)
             {
@@ -456,32 +1817,95 @@ where
     }
 }
 
-impl<Req, Resp, T> Sink<Response<Resp>> for BaseChannel<Req, Resp, T>
+impl<Req, Resp, T> Sink<ServerMessage<Resp>> for BaseChannel<Req, Resp, T>
 where
-    T: Transport<Response<Resp>, ClientMessage<Req>>,
+    T: Transport<ServerMessage<Resp>, ClientMessage<Req>>,
     T::Error: Error,
 {
     type Error = ChannelError<T::Error>;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        self.project()
-            .transport
-            .poll_ready(cx)
-            .map_err(ChannelError::Transport)
+        let mut this = self.project();
+        ready!(this.transport.as_mut().poll_ready(cx)).map_err(ChannelError::Write)?;
+        // The handshake is answered before any other message is sent.
+        if *this.answer_hello {
+            *this.answer_hello = false;
+            let capabilities = this.config.capabilities;
+            this.transport
+                .as_mut()
+                .start_send(ServerMessage::Hello { capabilities })
+                .map_err(ChannelError::Write)?;
+            tracing::info!(?capabilities, "SendHello");
+            return this.transport.poll_ready(cx).map_err(ChannelError::Write);
+        }
+        // Then requests the channel failed itself are responded to, since they're no longer in
+        // flight.
+        while let Some(response) = this.error_responses.pop_front() {
+            let _entered = this.span.enter();
+            tracing::info!(rpc.request_id = response.request_id, "SendErrorResponse");
+            this.transport
+                .as_mut()
+                .start_send(ServerMessage::Response(response))
+                .map_err(ChannelError::Write)?;
+            ready!(this.transport.as_mut().poll_ready(cx)).map_err(ChannelError::Write)?;
+        }
+        Poll::Ready(Ok(()))
     }
 
-    fn start_send(mut self: Pin<&mut Self>, response: Response<Resp>) -> Result<(), Self::Error> {
-        if let Some(span) = self
-            .in_flight_requests_mut()
-            .remove_request(response.request_id)
-        {
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        message: ServerMessage<Resp>,
+    ) -> Result<(), Self::Error> {
+        let request_id = match message.request_id() {
+            Some(request_id) => request_id,
+            // Pushed messages and closes aren't part of a request.
+            None => {
+                if let ServerMessage::Close { aborted } = &message {
+                    tracing::info!(aborted = aborted.len(), "SendClose");
+                } else {
+                    tracing::info!("SendPush");
+                }
+                return self
+                    .project()
+                    .transport
+                    .start_send(message)
+                    .map_err(ChannelError::Write);
+            }
+        };
+        let span = match message {
+            // More items may follow, so the request stays in flight.
+            ServerMessage::StreamItem { .. } => {
+                self.in_flight_requests_mut().span(request_id).cloned()
+            }
+            ServerMessage::KeepAlive { .. } => self
+                .in_flight_requests_mut()
+                .keep_alive(request_id)
+                .cloned(),
+            _ => {
+                self.end_request_stream(request_id);
+                self.in_flight_requests_mut().remove_request(request_id)
+            }
+        };
+        if let Some(span) = span {
             let _entered = span.enter();
-            tracing::info!("SendResponse");
+            match message {
+                ServerMessage::StreamItem { .. } => tracing::info!("SendStreamItem"),
+                ServerMessage::StreamEnd { .. } => tracing::info!("SendStreamEnd"),
+                ServerMessage::KeepAlive { .. } => tracing::trace!("SendKeepAlive"),
+                _ => tracing::info!("SendResponse"),
+            }
             self.project()
                 .transport
-                .start_send(response)
-                .map_err(ChannelError::Transport)
+                .start_send(message)
+                .map_err(ChannelError::Write)
         } else {
+            if self.in_flight_requests.completed_recently(request_id) {
+                *self.as_mut().project().duplicate_responses += 1;
+                tracing::warn!(
+                    rpc.request_id = request_id,
+                    "DuplicateResponse: dropped a message for a request already responded to"
+                );
+            }
             // If the request isn't tracked anymore, there's no need to send the response.
             Ok(())
         }
@@ -489,17 +1913,32 @@ where
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
         tracing::trace!("poll_flush");
-        self.project()
-            .transport
-            .poll_flush(cx)
-            .map_err(ChannelError::Transport)
+        let this = self.project();
+        if util::fail_point("tarpc::server::before_flush", |_| ()).is_some() {
+            // Holds back buffered responses until the failpoint is turned off, checking it again
+            // every millisecond rather than spinning.
+            #[cfg(feature = "failpoints")]
+            {
+                const BACKOFF: Duration = Duration::from_millis(1);
+                let backoff = this
+                    .failpoint_backoff
+                    .get_or_insert_with(|| Box::pin(::tokio::time::sleep(BACKOFF)));
+                while backoff.as_mut().poll(cx).is_ready() {
+                    backoff
+                        .as_mut()
+                        .reset(::tokio::time::Instant::now() + BACKOFF);
+                }
+            }
+            return Poll::Pending;
+        }
+        this.transport.poll_flush(cx).map_err(ChannelError::Write)
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
         self.project()
             .transport
             .poll_close(cx)
-            .map_err(ChannelError::Transport)
+            .map_err(ChannelError::Write)
     }
 }
 
@@ -511,7 +1950,7 @@ impl<Req, Resp, T> AsRef<T> for BaseChannel<Req, Resp, T> {
 
 impl<Req, Resp, T> Channel for BaseChannel<Req, Resp, T>
 where
-    T: Transport<Response<Resp>, ClientMessage<Req>>,
+    T: Transport<ServerMessage<Resp>, ClientMessage<Req>>,
 {
     type Req = Req;
     type Resp = Resp;
@@ -528,11 +1967,32 @@ where
     fn transport(&self) -> &Self::Transport {
         self.get_ref()
     }
+
+    fn peer_capabilities(&self) -> Option<Capabilities> {
+        self.peer_capabilities
+    }
+
+    fn abort_in_flight_requests(mut self: Pin<&mut Self>) -> Vec<u64> {
+        self.request_streams_mut().clear();
+        self.in_flight_requests_mut().abort_all()
+    }
 }
 
 /// A stream of requests coming over a channel. `Requests` also drives the sending of responses, so
 /// it must be continually polled to ensure progress.
-#[pin_project]
+///
+/// If `Requests` is dropped before the channel closed, it tears the channel down, in order:
+///
+/// 1. The responses already staged by request handlers are written to the channel, as long as it
+///    accepts them without waiting.
+/// 2. The handlers of the requests still in flight are aborted.
+/// 3. If the client announced the [`CLOSE`](Capabilities::CLOSE) capability, a
+///    [`Close`](ServerMessage::Close) message listing the aborted requests is written, again
+///    only if the channel accepts it without waiting.
+/// 4. The channel is flushed and closed, as far as it can be without waiting.
+///
+/// Responses staged later, e.g. by handlers running on other threads, are dropped.
+#[pin_project(PinnedDrop)]
 pub struct Requests<C>
 where
     C: Channel,
@@ -540,9 +2000,18 @@ where
     #[pin]
     channel: C,
     /// Responses waiting to be written to the wire.
-    pending_responses: mpsc::Receiver<Response<C::Resp>>,
+    pending_responses: mpsc::Receiver<ServerMessage<C::Resp>>,
     /// Handed out to request handlers to fan in responses.
-    responses_tx: mpsc::Sender<Response<C::Resp>>,
+    responses_tx: mpsc::Sender<ServerMessage<C::Resp>>,
+    /// The number of responses `pending_responses` holds.
+    response_buffer: usize,
+    /// The number of responses written since the last flush.
+    unflushed: usize,
+    /// Fires when a [deferred](FlushPolicy::Deferred) flush is due, armed when the first unflushed
+    /// response is written.
+    flush_timer: Option<Pin<Box<::tokio::time::Sleep>>>,
+    /// Whether the stream of requests has ended, in which case there's nothing to tear down.
+    terminated: bool,
 }
 
 impl<C> Requests<C>
@@ -559,10 +2028,19 @@ where
         self.as_mut().project().channel
     }
 
+    /// Returns a handle for sending the client messages that don't respond to any request, e.g.
+    /// cache invalidations. Pushed messages are interleaved with responses and are received by
+    /// the client's [`pushes`](crate::client::Channel::pushes) stream.
+    pub fn pusher(&self) -> Pusher<C::Resp> {
+        Pusher {
+            response_tx: self.responses_tx.clone(),
+        }
+    }
+
     /// Returns the inner channel over which messages are sent and received.
     pub fn pending_responses_mut<'a>(
         self: &'a mut Pin<&mut Self>,
-    ) -> &'a mut mpsc::Receiver<Response<C::Resp>> {
+    ) -> &'a mut mpsc::Receiver<ServerMessage<C::Resp>> {
         self.as_mut().project().pending_responses
     }
 
@@ -574,17 +2052,34 @@ where
             |TrackedRequest {
                  request,
                  abort_registration,
+                 request_items,
+                 stream_credits,
+                 cancellation,
+                 peer_identity,
+                 received,
                  span,
                  mut response_guard,
+                 one_way,
              }| {
-                // The response guard becomes active once in an InFlightRequest.
-                response_guard.cancel = true;
+                // The response guard becomes active once in an InFlightRequest, unless the
+                // channel doesn't track the request.
+                response_guard.cancel = !one_way;
                 InFlightRequest {
                     request,
                     abort_registration,
+                    request_items,
+                    stream_credits,
+                    cancellation,
+                    peer_identity,
+                    received,
+                    rejections: self.channel.config().rejections.clone(),
+                    faults: self.channel.config().faults.clone(),
+                    metrics: self.channel.config().metrics.clone(),
+                    catch_panics: self.channel.config().catch_panics,
                     span,
                     response_guard,
                     response_tx: self.responses_tx.clone(),
+                    one_way,
                 }
             },
         )
@@ -600,21 +2095,29 @@ where
                 // A Ready result from poll_next_response means the Channel is ready to be written
                 // to. Therefore, we can call start_send without worry of a full buffer.
                 self.channel_pin_mut().start_send(response)?;
+                self.on_unflushed_response();
                 Poll::Ready(Some(Ok(())))
             }
             Poll::Ready(None) => {
                 // Shutdown can't be done before we finish pumping out remaining responses.
                 ready!(self.channel_pin_mut().poll_flush(cx)?);
+                *self.as_mut().project().unflushed = 0;
                 Poll::Ready(None)
             }
             Poll::Pending => {
-                // No more requests to process, so flush any requests buffered in the transport.
+                // If the read half is closed and there are no in-flight requests, then the write
+                // half can be closed once all written responses are fully flushed.
+                let closing = read_half_closed && self.channel.in_flight_requests() == 0;
+
+                // No more requests to process, so flush any requests buffered in the transport,
+                // once the flush policy allows it.
+                if !closing {
+                    ready!(self.poll_flush_due(cx));
+                }
                 ready!(self.channel_pin_mut().poll_flush(cx)?);
+                *self.as_mut().project().unflushed = 0;
 
-                // Being here means there are no staged requests and all written responses are
-                // fully flushed. So, if the read half is closed and there are no in-flight
-                // requests, then we can close the write half.
-                if read_half_closed && self.channel.in_flight_requests() == 0 {
+                if closing {
                     Poll::Ready(None)
                 } else {
                     Poll::Pending
@@ -623,6 +2126,40 @@ where
         }
     }
 
+    /// Counts a response written to the channel, arming the flush timer if the response is the
+    /// first unflushed one.
+    fn on_unflushed_response(self: &mut Pin<&mut Self>) {
+        let delay = match self.channel.config().flush_policy {
+            FlushPolicy::WhenIdle => return,
+            FlushPolicy::Deferred { delay, .. } => delay,
+        };
+        let this = self.as_mut().project();
+        *this.unflushed += 1;
+        if *this.unflushed == 1 {
+            let deadline = ::tokio::time::Instant::now() + delay;
+            match this.flush_timer {
+                Some(timer) => timer.as_mut().reset(deadline),
+                None => *this.flush_timer = Some(Box::pin(::tokio::time::sleep_until(deadline))),
+            }
+        }
+    }
+
+    /// Returns Ready once the flush policy allows flushing the responses written so far.
+    fn poll_flush_due(self: &mut Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let responses = match self.channel.config().flush_policy {
+            FlushPolicy::WhenIdle => return Poll::Ready(()),
+            FlushPolicy::Deferred { responses, .. } => responses,
+        };
+        let this = self.as_mut().project();
+        if *this.unflushed == 0 || *this.unflushed >= responses {
+            return Poll::Ready(());
+        }
+        match this.flush_timer {
+            Some(timer) => timer.as_mut().poll(cx),
+            None => Poll::Ready(()),
+        }
+    }
+
     /// Yields a response ready to be written to the Channel sink.
     ///
     /// Note that a response will only be yielded if the Channel is *ready* to be written to (i.e.
@@ -630,11 +2167,19 @@ where
     fn poll_next_response(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-    ) -> Poll<Option<Result<Response<C::Resp>, C::Error>>> {
+    ) -> Poll<Option<Result<ServerMessage<C::Resp>, C::Error>>> {
         ready!(self.ensure_writeable(cx)?);
 
         match ready!(self.pending_responses_mut().poll_recv(cx)) {
-            Some(response) => Poll::Ready(Some(Ok(response))),
+            Some(response) => {
+                if let Some(metrics) = &self.channel.config().metrics {
+                    // The slots of the buffer not available to senders hold responses, or are
+                    // reserved for responses about to be sent.
+                    let depth = self.response_buffer - self.responses_tx.capacity();
+                    metrics.queue_depth(Side::Server, depth);
+                }
+                Poll::Ready(Some(Ok(response)))
+            }
             None => {
                 // This branch likely won't happen, since the Requests stream is holding a Sender.
                 Poll::Ready(None)
@@ -642,6 +2187,59 @@ where
         }
     }
 
+    /// Writes the responses staged so far and flushes them, without reading requests.
+    fn poll_write_responses(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), C::Error>> {
+        loop {
+            if let Some(Err(e)) = ready!(self.ensure_writeable(cx)) {
+                return Poll::Ready(Err(e));
+            }
+            match self.pending_responses_mut().try_recv() {
+                Ok(response) => self.channel_pin_mut().start_send(response)?,
+                Err(_) => break,
+            }
+        }
+        ready!(self.channel_pin_mut().poll_flush(cx)?);
+        *self.as_mut().project().unflushed = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Tears the channel down without waiting on it; see [`Requests`] for the order of the steps.
+    fn tear_down(mut self: Pin<&mut Self>) {
+        let cx = &mut Context::from_waker(futures::task::noop_waker_ref());
+        let mut writeable =
+            |this: &mut Pin<&mut Self>| this.channel_pin_mut().poll_ready(cx).is_ready();
+
+        while writeable(&mut self) {
+            let response = match self.pending_responses_mut().try_recv() {
+                Ok(response) => response,
+                Err(_) => break,
+            };
+            if self.channel_pin_mut().start_send(response).is_err() {
+                break;
+            }
+        }
+
+        let aborted = self.channel_pin_mut().abort_in_flight_requests();
+        tracing::info!(aborted = aborted.len(), "TearDown");
+
+        let close = self
+            .channel
+            .peer_capabilities()
+            .map_or(false, |capabilities| {
+                capabilities.contains(Capabilities::CLOSE)
+            });
+        if close && writeable(&mut self) {
+            let _ = self
+                .channel_pin_mut()
+                .start_send(ServerMessage::Close { aborted });
+        }
+        let _ = self.channel_pin_mut().poll_flush(cx);
+        let _ = self.channel_pin_mut().poll_close(cx);
+    }
+
     /// Returns Ready if writing a message to the Channel would not fail due to a full buffer. If
     /// the Channel is not ready to be written to, flushes it until it is ready.
     fn ensure_writeable<'a>(
@@ -655,6 +2253,18 @@ where
     }
 }
 
+#[pin_project::pinned_drop]
+impl<C> PinnedDrop for Requests<C>
+where
+    C: Channel,
+{
+    fn drop(mut self: Pin<&mut Self>) {
+        if !self.terminated {
+            self.tear_down();
+        }
+    }
+}
+
 impl<C> fmt::Debug for Requests<C>
 where
     C: Channel,
@@ -689,9 +2299,19 @@ impl Drop for ResponseGuard {
 pub struct InFlightRequest<Req, Res> {
     request: Request<Req>,
     abort_registration: AbortRegistration,
+    request_items: RequestStream<Req>,
+    stream_credits: StreamCredits,
+    cancellation: Cancellation,
+    peer_identity: Option<Arc<PeerIdentity>>,
+    received: SystemTime,
+    rejections: Option<RejectionLog>,
+    faults: Option<FaultInjector>,
+    metrics: Option<Arc<dyn Recorder>>,
+    catch_panics: bool,
     response_guard: ResponseGuard,
     span: Span,
-    response_tx: mpsc::Sender<Response<Res>>,
+    response_tx: mpsc::Sender<ServerMessage<Res>>,
+    one_way: bool,
 }
 
 impl<Req, Res> InFlightRequest<Req, Res> {
@@ -700,6 +2320,31 @@ impl<Req, Res> InFlightRequest<Req, Res> {
         &self.request
     }
 
+    /// Returns the address of the client, if the channel the request was received on was
+    /// [given it](BaseChannel::with_peer_addr).
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.request.context.peer_addr
+    }
+
+    /// Returns the identity of the client, if the channel the request was received on was
+    /// [given it](BaseChannel::with_peer_identity).
+    pub fn peer_identity(&self) -> Option<&PeerIdentity> {
+        self.peer_identity.as_deref()
+    }
+
+    /// Returns the cancellation of the request, which is triggered when the request's deadline
+    /// expires, if [`Config::deadline_notice`] is set, and when the client cancels the request,
+    /// if [`Config::cooperative_cancellation`] is set.
+    pub fn cancellation(&self) -> &Cancellation {
+        &self.cancellation
+    }
+
+    /// Returns true if the request is [one-way](ClientMessage::OneWay): nothing is responded to
+    /// it.
+    pub fn is_one_way(&self) -> bool {
+        self.one_way
+    }
+
     /// Returns a [future](Future) that executes the request using the given [service
     /// function](Serve). The service function's output is automatically sent back to the [Channel]
     /// that yielded this request. The request will be executed in the scope of this request's
@@ -709,19 +2354,49 @@ impl<Req, Res> InFlightRequest<Req, Res> {
     ///
     /// 1. The channel that yielded this request receives a [cancellation
     ///    message](ClientMessage::Cancel) for this request.
-    /// 2. The request [deadline](crate::context::Context::deadline) is reached.
+    /// 2. The request [deadline](crate::context::Context::deadline) is reached, plus the
+    ///    [notice](Config::deadline_notice) given to the service function, if any.
     /// 3. The service function completes.
     ///
+    /// For server-streaming requests, each stream item is sent as soon as the client has granted
+    /// credit for it (see [`Config::stream_window`]), and the end of the stream is signaled once the
+    /// stream completes.
+    ///
     /// If the returned Future is dropped before completion, a cancellation message will be sent to
     /// the Channel to clean up associated request state.
+    ///
+    /// The output of a [one-way](Self::is_one_way) request is discarded; the service function
+    /// stops executing at the request deadline.
     pub async fn execute<S>(self, serve: S)
     where
         S: Serve<Req, Resp = Res>,
+    {
+        self.execute_with_cleanup(serve, future::ready(())).await
+    }
+
+    /// Like [`execute`](Self::execute), but runs `on_cancel` once the service function is aborted
+    /// before completing, i.e. because the client canceled the request, or its deadline expired,
+    /// so that the server can tear down what the service function left behind, e.g. release
+    /// locks it held. `on_cancel` isn't run if the service function completes, nor if the
+    /// returned future is dropped.
+    pub async fn execute_with_cleanup<S, C>(self, serve: S, on_cancel: C)
+    where
+        S: Serve<Req, Resp = Res>,
+        C: Future<Output = ()>,
     {
         let Self {
             response_tx,
             mut response_guard,
             abort_registration,
+            request_items,
+            stream_credits,
+            cancellation,
+            peer_identity,
+            received,
+            rejections,
+            faults,
+            metrics,
+            catch_panics,
             span,
             request:
                 Request {
@@ -729,46 +2404,547 @@ impl<Req, Res> InFlightRequest<Req, Res> {
                     message,
                     id: request_id,
                 },
+            one_way,
         } = self;
+        // The channel doesn't track one-way requests, so they aren't responded to, and are bounded
+        // by their deadline here rather than by the channel.
+        let (response_tx, deadline) = if one_way {
+            (mpsc::channel(1).0, Some(context.deadline))
+        } else {
+            (response_tx, None)
+        };
         let method = serve.method(&message);
-        // TODO(https://github.com/rust-lang/rust-clippy/issues/9111)
-        // remove when clippy is fixed
-        #[allow(clippy::needless_borrow)]
-        span.record("otel.name", &method.unwrap_or(""));
-        let _ = Abortable::new(
-            async move {
-                tracing::info!("BeginRequest");
-                let response = serve.serve(context, message).await;
-                tracing::info!("CompleteRequest");
-                let response = Response {
-                    request_id,
-                    message: Ok(response),
-                };
-                let _ = response_tx.send(response).await;
-                tracing::info!("BufferResponse");
-            },
-            abort_registration,
-        )
-        .instrument(span)
-        .await;
+        let (rejections, faults, metrics, catch_panics) = match serve.config(&message) {
+            Some(config) => (
+                config.rejections.clone(),
+                config.faults.clone(),
+                config.metrics.clone(),
+                config.catch_panics,
+            ),
+            None => (rejections, faults, metrics, catch_panics),
+        };
+        span.record("otel.name", method.unwrap_or(""));
+        if let Some(method) = method {
+            span.record("rpc.method", method);
+        }
+        let injected_fault = faults
+            .and_then(|faults| faults.inject(method, peer_identity.as_deref(), context.peer_addr));
+        let panic_context = panics::PanicContext {
+            trace_id: context.trace_context.trace_id,
+            request_id,
+            method,
+        };
+        let panic_response_tx = catch_panics.then(|| response_tx.clone());
+        let dispatch = Dispatch {
+            request_id,
+            context,
+            message,
+            request_items,
+            stream_credits,
+            injected_fault,
+            rejections,
+            method,
+            response_tx,
+        };
+
+        // From the inside out, the handler is wrapped so that panics are attributed to the request
+        // and, if caught, responded to; the client's identity and the request's cancellation are
+        // current while it runs; and it's aborted when the channel cancels the request. Keep-alives
+        // and stream credits are handled by the dispatch itself, and the deadline of a one-way
+        // request bounds all of it.
+        let handler = dispatch.run(serve);
+        let handler = panics::Scoped::new(handler, panic_context);
+        let handler = panics::catch(handler, request_id, panic_response_tx);
+        let handler = identity::Scoped::new(handler, peer_identity);
+        let handler = WithCancellation {
+            cancellation,
+            inner: handler,
+        };
+        let handler = Abortable::new(handler, abort_registration).instrument(span.clone());
+
+        if let Some(metrics) = &metrics {
+            metrics.request_started(Side::Server, method.unwrap_or(""));
+        }
+        let outcome = with_one_way_deadline(handler, deadline, &span).await;
+        if let Some(metrics) = &metrics {
+            metrics.request_completed(
+                Side::Server,
+                method.unwrap_or(""),
+                util::time::now()
+                    .duration_since(received)
+                    .unwrap_or_default(),
+                outcome.unwrap_or(Outcome::Canceled),
+            );
+        }
         // Request processing has completed, meaning either the channel canceled the request or
         // a request was sent back to the channel. Either way, the channel will clean up the
         // request data, so the request does not need to be canceled.
         response_guard.cancel = false;
+        if outcome.is_err() {
+            on_cancel.instrument(span.clone()).await;
+            let _entered = span.enter();
+            tracing::info!("CleanedUpCanceledRequest");
+        }
     }
-}
 
-impl<C> Stream for Requests<C>
-where
-    C: Channel,
-{
-    type Item = Result<InFlightRequest<C::Req, C::Resp>, C::Error>;
+    /// Splits the request into the request itself and a [`ResponseHandle`] that can be used to
+    /// send the response. Unlike [`execute`](InFlightRequest::execute), the response can be
+    /// produced by any task or thread: the handle can be moved to another component (e.g. an
+    /// actor) that completes the request later.
+    ///
+    /// If the handle is dropped without responding, a cancellation message will be sent to the
+    /// Channel to clean up associated request state.
+    pub fn split(self) -> (Request<Req>, ResponseHandle<Res>) {
+        let Self {
+            request,
+            abort_registration,
+            response_guard,
+            span,
+            response_tx,
+            ..
+        } = self;
+        let response_handle = ResponseHandle {
+            request_id: request.id,
+            abort_registration,
+            response_guard,
+            span,
+            response_tx,
+        };
+        (request, response_handle)
+    }
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        loop {
-            let read = self.as_mut().pump_read(cx)?;
-            let read_closed = matches!(read, Poll::Ready(None));
-            match (read, self.as_mut().pump_write(cx, read_closed)?) {
+    /// Converts the request into a [`StreamingInFlightRequest`], which exchanges items with the
+    /// client in both directions until either side closes its end: it yields the items the client
+    /// sends after the request, and sends stream items back to the client.
+    pub fn into_streaming(self) -> StreamingInFlightRequest<Req, Res> {
+        let Self {
+            request,
+            abort_registration,
+            request_items,
+            stream_credits,
+            response_guard,
+            span,
+            response_tx,
+            ..
+        } = self;
+        StreamingInFlightRequest {
+            request,
+            aborted: Box::pin(Abortable::new(future::pending(), abort_registration)),
+            request_items,
+            stream_credits,
+            response_guard,
+            span,
+            response_tx,
+        }
+    }
+}
+
+/// A request being executed by [`InFlightRequest::execute`], along with where its responses are
+/// sent.
+struct Dispatch<Req, Res> {
+    request_id: u64,
+    context: context::Context,
+    message: Req,
+    request_items: RequestStream<Req>,
+    stream_credits: StreamCredits,
+    injected_fault: Option<ServerError>,
+    rejections: Option<RejectionLog>,
+    method: Option<&'static str>,
+    response_tx: mpsc::Sender<ServerMessage<Res>>,
+}
+
+impl<Req, Res> Dispatch<Req, Res> {
+    /// Serves the request with `serve`, unless a fault is injected, and sends the response, the
+    /// stream of responses, or the error the request was rejected with to the client.
+    async fn run<S>(self, serve: S) -> Outcome
+    where
+        S: Serve<Req, Resp = Res>,
+    {
+        let Self {
+            request_id,
+            context,
+            message,
+            request_items,
+            stream_credits,
+            injected_fault,
+            rejections,
+            method,
+            response_tx,
+        } = self;
+        let keep_alive = context.keep_alive;
+        let peer_addr = context.peer_addr;
+        tracing::info!("BeginRequest");
+        let injected_error = util::fail_point("tarpc::server::before_handler", |detail| {
+            ServerError::new(
+                io::ErrorKind::Other,
+                detail.unwrap_or_else(|| "failpoint tarpc::server::before_handler".into()),
+            )
+        });
+        let (served, reject_reason) = match injected_error.or(injected_fault) {
+            Some(error) => (Served::Error(error), RejectReason::Injected),
+            None => (
+                serve.serve_with_items(context, message, request_items),
+                RejectReason::Invalid,
+            ),
+        };
+        let (message, outcome) = match served {
+            Served::Response(response) => {
+                let response =
+                    with_keep_alive(response, request_id, keep_alive, &response_tx).await;
+                tracing::info!("CompleteRequest");
+                (Ok(response), Outcome::Success)
+            }
+            Served::Stream(mut items) => {
+                // The credit is acquired before the next item is pulled, so that no item is
+                // produced past the window.
+                while let Some(item) = with_keep_alive(
+                    async {
+                        stream_credits.acquire().await;
+                        items.next().await
+                    },
+                    request_id,
+                    keep_alive,
+                    &response_tx,
+                )
+                .await
+                {
+                    let item = ServerMessage::StreamItem { request_id, item };
+                    if response_tx.send(item).await.is_err() {
+                        return Outcome::Canceled;
+                    }
+                }
+                tracing::info!("CompleteRequest");
+                let _ = response_tx
+                    .send(ServerMessage::StreamEnd { request_id })
+                    .await;
+                tracing::info!("BufferResponse");
+                return Outcome::Success;
+            }
+            Served::Fallible(response) => {
+                let response =
+                    with_keep_alive(response, request_id, keep_alive, &response_tx).await;
+                tracing::info!("CompleteRequest");
+                let outcome = match response {
+                    Ok(_) => Outcome::Success,
+                    Err(_) => Outcome::Error,
+                };
+                (response, outcome)
+            }
+            Served::Error(error) => {
+                tracing::info!("RejectRequest");
+                if let Some(rejections) = rejections {
+                    let mut rejection =
+                        Rejection::new(reject_reason).with_detail(error.detail.clone());
+                    if let Some(method) = method {
+                        rejection = rejection.with_method(method);
+                    }
+                    if let Some(peer_addr) = peer_addr {
+                        rejection = rejection.with_peer(peer_addr);
+                    }
+                    rejections.record(rejection);
+                }
+                (Err(error), Outcome::Error)
+            }
+        };
+        let response = Response {
+            request_id,
+            message,
+        };
+        let _ = response_tx.send(response.into()).await;
+        tracing::info!("BufferResponse");
+        outcome
+    }
+}
+
+/// Awaits the handler of a request. A one-way request is given until its `deadline`; when it
+/// expires, the handler is aborted.
+async fn with_one_way_deadline<F>(
+    handler: F,
+    deadline: Option<SystemTime>,
+    span: &Span,
+) -> Result<Outcome, Aborted>
+where
+    F: Future<Output = Result<Outcome, Aborted>>,
+{
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return handler.await,
+    };
+    match ::tokio::time::timeout(deadline.time_until(), handler).await {
+        Ok(outcome) => outcome,
+        Err(_) => {
+            let _entered = span.enter();
+            tracing::info!("OneWayRequestExpired");
+            Err(Aborted)
+        }
+    }
+}
+
+/// Waits for `fut` to complete. If the client consented to keep-alives, a keep-alive message is
+/// sent for the request every `keep_alive / 2` in the meantime.
+async fn with_keep_alive<F, Res>(
+    fut: F,
+    request_id: u64,
+    keep_alive: Option<Duration>,
+    response_tx: &mpsc::Sender<ServerMessage<Res>>,
+) -> F::Output
+where
+    F: Future,
+{
+    let keep_alive = match keep_alive {
+        Some(keep_alive) => keep_alive,
+        None => return fut.await,
+    };
+    // Interval panics on a zero period.
+    let period = (keep_alive / 2).max(Duration::from_millis(1));
+    let mut interval = ::tokio::time::interval_at(::tokio::time::Instant::now() + period, period);
+    interval.set_missed_tick_behavior(::tokio::time::MissedTickBehavior::Delay);
+    futures::pin_mut!(fut);
+    future::poll_fn(|cx| {
+        if let Poll::Ready(output) = fut.as_mut().poll(cx) {
+            return Poll::Ready(output);
+        }
+        while interval.poll_tick(cx).is_ready() {
+            // If the response buffer is full, the channel is not keeping up with responses; a
+            // keep-alive can safely be skipped, as the next one will follow shortly.
+            let _ = response_tx.try_send(ServerMessage::KeepAlive { request_id });
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// A handle used to respond to a request outside of the handler future. Returned by
+/// [`InFlightRequest::split`].
+///
+/// If dropped without calling [`respond`](ResponseHandle::respond), a cancellation message will be
+/// sent to the Channel to clean up associated request state.
+#[derive(Debug)]
+pub struct ResponseHandle<Res> {
+    request_id: u64,
+    abort_registration: AbortRegistration,
+    response_guard: ResponseGuard,
+    span: Span,
+    response_tx: mpsc::Sender<ServerMessage<Res>>,
+}
+
+impl<Res> ResponseHandle<Res> {
+    /// Returns the ID of the request being responded to.
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+
+    /// Sends the response back to the [Channel] that yielded the request.
+    ///
+    /// The response is discarded if the request was canceled or its deadline passed before the
+    /// response could be buffered.
+    pub async fn respond(self, response: Res) {
+        let Self {
+            request_id,
+            abort_registration,
+            mut response_guard,
+            span,
+            response_tx,
+        } = self;
+        let _ = Abortable::new(
+            async move {
+                let response = Response {
+                    request_id,
+                    message: Ok(response),
+                };
+                let _ = response_tx.send(response.into()).await;
+                tracing::info!("BufferResponse");
+            },
+            abort_registration,
+        )
+        .instrument(span)
+        .await;
+        // Either the response was buffered or the channel canceled the request. Either way, the
+        // channel will clean up the request data.
+        response_guard.cancel = false;
+    }
+}
+
+/// Sends the client of a channel messages that don't respond to any request. Returned by
+/// [`Requests::pusher`].
+///
+/// Pushed messages share the buffer of pending responses, so pushing waits while the buffer is
+/// full. Once the channel's [`Requests`] is dropped, pushing fails with [`PushError::Closed`].
+#[derive(Debug)]
+pub struct Pusher<Res> {
+    response_tx: mpsc::Sender<ServerMessage<Res>>,
+}
+
+impl<Res> Clone for Pusher<Res> {
+    fn clone(&self) -> Self {
+        Self {
+            response_tx: self.response_tx.clone(),
+        }
+    }
+}
+
+impl<Res> Pusher<Res> {
+    /// Buffers a message to be sent to the client, waiting for room in the buffer if necessary.
+    pub async fn push(&self, message: Res) -> Result<(), PushError> {
+        self.response_tx
+            .send(ServerMessage::Push(message))
+            .await
+            .map_err(|_| PushError::Closed)?;
+        tracing::info!("BufferPush");
+        Ok(())
+    }
+
+    /// Buffers a message to be sent to the client, failing if the buffer is full.
+    pub fn try_push(&self, message: Res) -> Result<(), PushError> {
+        self.response_tx
+            .try_send(ServerMessage::Push(message))
+            .map_err(|e| match e {
+                mpsc::error::TrySendError::Full(_) => PushError::Full,
+                mpsc::error::TrySendError::Closed(_) => PushError::Closed,
+            })
+    }
+
+    /// Returns true if the channel no longer accepts pushed messages.
+    pub fn is_closed(&self) -> bool {
+        self.response_tx.is_closed()
+    }
+}
+
+/// The reasons a message could not be pushed to a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum PushError {
+    /// The channel is no longer serving the client.
+    #[error("the channel is closed")]
+    Closed,
+    /// The buffer of pending responses is full.
+    #[error("the buffer of pending responses is full")]
+    Full,
+}
+
+/// A request that exchanges streams of items with the client, outside of a handler future.
+/// Returned by [`InFlightRequest::into_streaming`].
+///
+/// Polling it as a [`Stream`] yields the items the client sends after the request, ending when
+/// the client closes its end of the stream. Items are sent back to the client with
+/// [`send`](StreamingInFlightRequest::send), and the request completes with
+/// [`end`](StreamingInFlightRequest::end). If dropped without calling `end`, a cancellation
+/// message will be sent to the Channel to clean up associated request state.
+#[derive(Debug)]
+pub struct StreamingInFlightRequest<Req, Res> {
+    request: Request<Req>,
+    /// Completes when the channel aborts the request.
+    aborted: Pin<Box<Abortable<future::Pending<()>>>>,
+    request_items: RequestStream<Req>,
+    stream_credits: StreamCredits,
+    response_guard: ResponseGuard,
+    span: Span,
+    response_tx: mpsc::Sender<ServerMessage<Res>>,
+}
+
+impl<Req, Res> StreamingInFlightRequest<Req, Res> {
+    /// Returns a reference to the request.
+    pub fn get(&self) -> &Request<Req> {
+        &self.request
+    }
+
+    /// Sends a stream item back to the [Channel] that yielded the request, once the client has
+    /// granted credit for it (see [`Config::stream_window`]).
+    ///
+    /// Returns an error if the channel canceled the request or its deadline passed.
+    pub async fn send(&mut self, item: Res) -> Result<(), Aborted> {
+        let Self {
+            request,
+            aborted,
+            stream_credits,
+            span,
+            response_tx,
+            ..
+        } = self;
+        let request_id = request.id;
+        let send = async move {
+            stream_credits.acquire().await;
+            response_tx
+                .send(ServerMessage::StreamItem { request_id, item })
+                .await
+                .map_err(|_| Aborted)
+        }
+        .instrument(span.clone());
+        futures::pin_mut!(send);
+        // Checks for abortion first, so that nothing is sent for a request no longer in flight.
+        match future::select(aborted.as_mut(), send).await {
+            Either::Left(_) => Err(Aborted),
+            Either::Right((result, _)) => result,
+        }
+    }
+
+    /// Signals the end of the stream to the [Channel] that yielded the request, completing the
+    /// request.
+    ///
+    /// The end of the stream is discarded if the request was canceled or its deadline passed
+    /// before it could be buffered.
+    pub async fn end(self) {
+        let Self {
+            request,
+            aborted,
+            mut response_guard,
+            span,
+            response_tx,
+            ..
+        } = self;
+        let request_id = request.id;
+        let end = async move {
+            let _ = response_tx
+                .send(ServerMessage::StreamEnd { request_id })
+                .await;
+            tracing::info!("BufferResponse");
+        }
+        .instrument(span);
+        futures::pin_mut!(end);
+        future::select(aborted, end).await;
+        // Either the end of the stream was buffered or the channel canceled the request. Either
+        // way, the channel will clean up the request data.
+        response_guard.cancel = false;
+    }
+}
+
+// No fields are structurally pinned.
+impl<Req, Res> Unpin for StreamingInFlightRequest<Req, Res> {}
+
+impl<Req, Res> Stream for StreamingInFlightRequest<Req, Res> {
+    type Item = Req;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Req>> {
+        self.request_items.poll_next_unpin(cx)
+    }
+}
+
+impl<C> Stream for Requests<C>
+where
+    C: Channel,
+{
+    type Item = Result<InFlightRequest<C::Req, C::Resp>, C::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = ready!(self.as_mut().poll_next_request(cx));
+        if !matches!(next, Some(Ok(_))) {
+            *self.project().terminated = true;
+        }
+        Poll::Ready(next)
+    }
+}
+
+impl<C> Requests<C>
+where
+    C: Channel,
+{
+    fn poll_next_request(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<InFlightRequest<C::Req, C::Resp>, C::Error>>> {
+        loop {
+            let read = self.as_mut().pump_read(cx)?;
+            let read_closed = matches!(read, Poll::Ready(None));
+            match (read, self.as_mut().pump_write(cx, read_closed)?) {
                 (Poll::Ready(None), Poll::Ready(None)) => {
                     return Poll::Ready(None);
                 }
@@ -784,26 +2960,183 @@ where
     }
 }
 
+/// Runs `channel` until completion, executing its requests with `serve` on the current task.
+///
+/// Nothing is spawned: request handlers are driven concurrently by the returned future itself,
+/// which also routes their responses back over the channel. Neither the channel nor the handlers
+/// need to be `Send`, which suits servers embedded in an event loop, like that of a game or
+/// simulator, that poll the future once per tick. Handlers share the task, so they shouldn't
+/// block.
+///
+/// ```
+/// use futures::{future, prelude::*, task::noop_waker_ref};
+/// use std::task::{Context, Poll};
+/// use tarpc::{client, context, server::{self, BaseChannel}, transport::channel};
+///
+/// # #[cfg(not(feature = "tokio1"))]
+/// # fn main() {}
+/// # #[cfg(feature = "tokio1")]
+/// # fn main() {
+/// let (client_transport, server_transport) = channel::unbounded();
+/// let mut server = Box::pin(server::serve_single_threaded(
+///     BaseChannel::with_defaults(server_transport),
+///     |_, i: u32| future::ready(i + 1),
+/// ));
+/// # let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+/// # runtime.block_on(async {
+/// let client = client::new(client::Config::default(), client_transport).spawn();
+/// let mut response = Box::pin(client.call(context::current(), "AddOne", 1));
+///
+/// // The event loop polls the server each tick, alongside its other work.
+/// let mut cx = Context::from_waker(noop_waker_ref());
+/// let response = loop {
+///     let _ = server.as_mut().poll(&mut cx);
+///     if let Poll::Ready(response) = response.as_mut().poll(&mut cx) {
+///         break response;
+///     }
+///     tokio::task::yield_now().await;
+/// };
+/// assert_eq!(response.unwrap(), 2);
+/// # });
+/// # }
+/// ```
+pub fn serve_single_threaded<C, S>(channel: C, serve: S) -> SingleThreadedExecutor<C, S>
+where
+    C: Channel,
+    S: Serve<C::Req, Resp = C::Resp> + Clone,
+{
+    SingleThreadedExecutor {
+        requests: channel.requests(),
+        serve,
+        handlers: stream::FuturesUnordered::new(),
+        requests_done: false,
+        read_failed: false,
+    }
+}
+
+/// A future that runs a channel and its request handlers on the current task. Returned by
+/// [`serve_single_threaded`].
+#[must_use]
+#[pin_project]
+pub struct SingleThreadedExecutor<C, S>
+where
+    C: Channel,
+{
+    #[pin]
+    requests: Requests<C>,
+    serve: S,
+    handlers: stream::FuturesUnordered<future::LocalBoxFuture<'static, ()>>,
+    /// Whether the requests stream has completed; it must not be polled again.
+    requests_done: bool,
+    /// Whether reading requests failed, after which only the responses of the handlers still
+    /// running are written.
+    read_failed: bool,
+}
+
+impl<C, S> SingleThreadedExecutor<C, S>
+where
+    C: Channel,
+{
+    /// Returns the number of request handlers currently running.
+    pub fn running_handlers(&self) -> usize {
+        self.handlers.len()
+    }
+}
+
+impl<C, S> fmt::Debug for SingleThreadedExecutor<C, S>
+where
+    C: Channel,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SingleThreadedExecutor")
+            .field("running_handlers", &self.handlers.len())
+            .field("requests_done", &self.requests_done)
+            .field("read_failed", &self.read_failed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C, S> Future for SingleThreadedExecutor<C, S>
+where
+    C: Channel,
+    C::Req: 'static,
+    C::Resp: 'static,
+    S: Serve<C::Req, Resp = C::Resp> + Clone + 'static,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut this = self.project();
+        loop {
+            // Handlers are polled first, so that the responses they send are written below.
+            while let Poll::Ready(Some(())) = this.handlers.poll_next_unpin(cx) {}
+            if *this.requests_done {
+                break;
+            }
+            if *this.read_failed {
+                match this.requests.as_mut().poll_write_responses(cx) {
+                    Poll::Ready(Ok(())) if this.handlers.is_empty() => *this.requests_done = true,
+                    Poll::Ready(Ok(())) | Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(e)) => {
+                        tracing::warn!("Failed to write responses: {}", e);
+                        *this.requests_done = true;
+                    }
+                }
+                continue;
+            }
+            match this.requests.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(request))) => {
+                    this.handlers
+                        .push(request.execute(this.serve.clone()).boxed_local());
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    tracing::warn!("Requests stream errored out: {}", e);
+                    *this.read_failed = true;
+                }
+                Poll::Ready(None) => *this.requests_done = true,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        if this.handlers.is_empty() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{in_flight_requests::AlreadyExistsError, BaseChannel, Channel, Config, Requests};
+    use super::{
+        faults::FaultRule, in_flight_requests::AlreadyExistsError, BaseChannel, Cancellation,
+        Channel, Config, ConfigHandle, DrainTimeout, FaultInjector, FlushPolicy, PeerIdentity,
+        PushError, RejectReason, RejectionLog, RequestStream, Requests, Serve, Served, Shutdown,
+        TraceCanceler, TrackedRequest,
+    };
     use crate::{
-        context, trace,
+        capabilities::Capabilities,
+        context,
+        metrics::EventRecorder,
+        trace,
         transport::channel::{self, UnboundedChannel},
-        ClientMessage, Request, Response,
+        ClientMessage, Request, Response, ServerError, ServerMessage,
     };
     use assert_matches::assert_matches;
     use futures::{
         future::{pending, AbortRegistration, Abortable, Aborted},
         prelude::*,
-        Future,
+        stream, Future,
     };
     use futures_test::task::noop_context;
-    use std::{pin::Pin, task::Poll};
+    use std::{
+        pin::Pin,
+        task::Poll,
+        time::{Duration, SystemTime},
+    };
 
     fn test_channel<Req, Resp>() -> (
-        Pin<Box<BaseChannel<Req, Resp, UnboundedChannel<ClientMessage<Req>, Response<Resp>>>>>,
-        UnboundedChannel<Response<Resp>, ClientMessage<Req>>,
+        Pin<Box<BaseChannel<Req, Resp, UnboundedChannel<ClientMessage<Req>, ServerMessage<Resp>>>>>,
+        UnboundedChannel<ServerMessage<Resp>, ClientMessage<Req>>,
     ) {
         let (tx, rx) = crate::transport::channel::unbounded();
         (Box::pin(BaseChannel::new(Config::default(), rx)), tx)
@@ -813,11 +3146,15 @@ mod tests {
         Pin<
             Box<
                 Requests<
-                    BaseChannel<Req, Resp, UnboundedChannel<ClientMessage<Req>, Response<Resp>>>,
+                    BaseChannel<
+                        Req,
+                        Resp,
+                        UnboundedChannel<ClientMessage<Req>, ServerMessage<Resp>>,
+                    >,
                 >,
             >,
         >,
-        UnboundedChannel<Response<Resp>, ClientMessage<Req>>,
+        UnboundedChannel<ServerMessage<Resp>, ClientMessage<Req>>,
     ) {
         let (tx, rx) = crate::transport::channel::unbounded();
         (
@@ -832,16 +3169,21 @@ mod tests {
         Pin<
             Box<
                 Requests<
-                    BaseChannel<Req, Resp, channel::Channel<ClientMessage<Req>, Response<Resp>>>,
+                    BaseChannel<
+                        Req,
+                        Resp,
+                        channel::Channel<ClientMessage<Req>, ServerMessage<Resp>>,
+                    >,
                 >,
             >,
         >,
-        channel::Channel<Response<Resp>, ClientMessage<Req>>,
+        channel::Channel<ServerMessage<Resp>, ClientMessage<Req>>,
     ) {
         let (tx, rx) = crate::transport::channel::bounded(capacity);
         // Add 1 because capacity 0 is not supported (but is supported by transport::channel::bounded).
         let config = Config {
             pending_response_buffer: capacity + 1,
+            ..Config::default()
         };
         (Box::pin(BaseChannel::new(config, rx).requests()), tx)
     }
@@ -861,10 +3203,34 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn base_channel_start_send_duplicate_request_returns_error() {
-        let (mut channel, _tx) = test_channel::<(), ()>();
+    async fn config_channels_configures_each_channel() {
+        let config = Config {
+            stream_window: 1,
+            ..Config::default()
+        };
+        let (_tx1, rx1) =
+            crate::transport::channel::unbounded::<ServerMessage<()>, ClientMessage<()>>();
+        let (_tx2, rx2) = crate::transport::channel::unbounded();
+        let channels: Vec<_> = config.channels(stream::iter([rx1, rx2])).collect().await;
+        assert_eq!(channels.len(), 2);
+        for channel in channels {
+            assert_eq!(channel.config().stream_window, 1);
+        }
+    }
 
-        channel
+    #[tokio::test]
+    async fn base_channel_start_request_uses_config_handle_stream_window() {
+        let config_handle = ConfigHandle::new();
+        let config = Config {
+            config_handle: Some(config_handle.clone()),
+            ..Config::default()
+        };
+        let (_tx, rx) =
+            crate::transport::channel::unbounded::<ServerMessage<()>, ClientMessage<()>>();
+        let mut channel = Box::pin(BaseChannel::new(config, rx));
+        config_handle.update(|tunables| tunables.stream_window = Some(0));
+
+        let request = channel
             .as_mut()
             .start_request(Request {
                 id: 0,
@@ -872,36 +3238,99 @@ mod tests {
                 message: (),
             })
             .unwrap();
-        assert_matches!(
-            channel.as_mut().start_request(Request {
-                id: 0,
-                context: context::current(),
-                message: ()
-            }),
-            Err(AlreadyExistsError)
-        );
+        let mut acquire = Box::pin(request.stream_credits.acquire());
+        assert_matches!(acquire.as_mut().poll(&mut noop_context()), Poll::Pending);
     }
 
     #[tokio::test]
-    async fn base_channel_poll_next_aborts_multiple_requests() {
-        let (mut channel, _tx) = test_channel::<(), ()>();
-
-        tokio::time::pause();
-        let req0 = channel
+    async fn base_channel_restore_resumes_in_flight_requests() {
+        let (mut channel, _tx) = test_channel::<(), u32>();
+        let _request = channel
             .as_mut()
             .start_request(Request {
-                id: 0,
+                id: 7,
                 context: context::current(),
                 message: (),
             })
             .unwrap();
-        let req1 = channel
+        let snapshot = channel.snapshot();
+        assert_eq!(snapshot.stream_window, Config::default().stream_window);
+        assert_eq!(
+            snapshot
+                .in_flight
+                .iter()
+                .map(|request| request.request_id)
+                .collect::<Vec<_>>(),
+            vec![7]
+        );
+        drop(channel);
+
+        let (mut tx, rx) = crate::transport::channel::unbounded();
+        let mut channel = Box::pin(BaseChannel::<(), u32, _>::restore(
+            Config::default(),
+            rx,
+            snapshot,
+        ));
+        assert_eq!(channel.in_flight_requests(), 1);
+        channel
             .as_mut()
-            .start_request(Request {
-                id: 1,
-                context: context::current(),
-                message: (),
-            })
+            .start_send(ServerMessage::Response(Response {
+                request_id: 7,
+                message: Ok(1),
+            }))
+            .unwrap();
+        assert_eq!(channel.in_flight_requests(), 0);
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(ServerMessage::Response(Response {
+                request_id: 7,
+                message: Ok(1)
+            })))
+        );
+    }
+
+    #[tokio::test]
+    async fn base_channel_start_send_duplicate_request_returns_error() {
+        let (mut channel, _tx) = test_channel::<(), ()>();
+
+        channel
+            .as_mut()
+            .start_request(Request {
+                id: 0,
+                context: context::current(),
+                message: (),
+            })
+            .unwrap();
+        assert_matches!(
+            channel.as_mut().start_request(Request {
+                id: 0,
+                context: context::current(),
+                message: ()
+            }),
+            Err(AlreadyExistsError)
+        );
+    }
+
+    #[tokio::test]
+    async fn base_channel_poll_next_aborts_multiple_requests() {
+        let (mut channel, _tx) = test_channel::<(), ()>();
+
+        tokio::time::pause();
+        let req0 = channel
+            .as_mut()
+            .start_request(Request {
+                id: 0,
+                context: context::current(),
+                message: (),
+            })
+            .unwrap();
+        let req1 = channel
+            .as_mut()
+            .start_request(Request {
+                id: 1,
+                context: context::current(),
+                message: (),
+            })
             .unwrap();
         tokio::time::advance(std::time::Duration::from_secs(1000)).await;
 
@@ -942,6 +3371,219 @@ mod tests {
         assert_matches!(test_abortable(req.abort_registration).await, Err(Aborted));
     }
 
+    #[tokio::test]
+    async fn base_channel_poll_next_notifies_canceled_request_cooperatively() {
+        let (mut tx, rx) = crate::transport::channel::unbounded();
+        let config = Config {
+            cooperative_cancellation: true,
+            ..Config::default()
+        };
+        let mut channel = Box::pin(BaseChannel::<(), (), _>::new(config, rx));
+
+        let req = channel
+            .as_mut()
+            .start_request(Request {
+                id: 0,
+                context: context::current(),
+                message: (),
+            })
+            .unwrap();
+
+        tx.send(ClientMessage::Cancel {
+            trace_context: trace::Context::default(),
+            request_id: 0,
+        })
+        .await
+        .unwrap();
+
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        assert!(req.cancellation.is_cancelled());
+        // The handler is left to return on its own, so the request stays in flight.
+        assert_eq!(channel.in_flight_requests(), 1);
+        let cleanup = Abortable::new(
+            async move {
+                req.cancellation.cancelled().await;
+                "cleaned up"
+            },
+            req.abort_registration,
+        );
+        assert_eq!(cleanup.await, Ok("cleaned up"));
+    }
+
+    #[tokio::test]
+    async fn trace_canceler_aborts_requests_of_trace_across_channels() {
+        let trace_canceler = TraceCanceler::new();
+        let config = Config {
+            trace_canceler: Some(trace_canceler.clone()),
+            ..Config::default()
+        };
+        let (_tx0, rx0) = crate::transport::channel::unbounded::<ServerMessage<()>, _>();
+        let (_tx1, rx1) = crate::transport::channel::unbounded::<ServerMessage<()>, _>();
+        let mut channel0 = Box::pin(BaseChannel::new(config.clone(), rx0));
+        let mut channel1 = Box::pin(BaseChannel::new(config, rx1));
+
+        let canceled_trace = trace::TraceId::random(&mut rand::thread_rng());
+        let other_trace = trace::TraceId::random(&mut rand::thread_rng());
+        let start_request = |channel: &mut Pin<Box<BaseChannel<(), (), _>>>, id, trace_id| {
+            let mut context = context::current();
+            context.trace_context.trace_id = trace_id;
+            channel
+                .as_mut()
+                .start_request(Request {
+                    id,
+                    context,
+                    message: (),
+                })
+                .unwrap()
+        };
+        let req0 = start_request(&mut channel0, 0, canceled_trace);
+        let req1 = start_request(&mut channel0, 1, other_trace);
+        let req2 = start_request(&mut channel1, 0, canceled_trace);
+
+        trace_canceler.cancel(canceled_trace);
+        for channel in [&mut channel0, &mut channel1] {
+            assert_matches!(
+                channel.as_mut().poll_next(&mut noop_context()),
+                Poll::Pending
+            );
+        }
+
+        assert_matches!(test_abortable(req0.abort_registration).await, Err(Aborted));
+        assert_matches!(test_abortable(req2.abort_registration).await, Err(Aborted));
+        assert_matches!(test_abortable(req1.abort_registration).now_or_never(), None);
+        assert_eq!(channel0.in_flight_requests(), 1);
+        assert_eq!(channel1.in_flight_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn base_channel_sets_peer_addr_of_requests() {
+        let peer_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let (_tx, rx) = crate::transport::channel::unbounded::<ServerMessage<()>, _>();
+        let channel = BaseChannel::with_defaults(rx).with_peer_addr(peer_addr);
+        assert_eq!(channel.peer_addr(), Some(peer_addr));
+        let mut channel = Box::pin(channel);
+
+        let mut context = context::current();
+        context.peer_addr = Some("127.0.0.1:9090".parse().unwrap());
+        let req = channel
+            .as_mut()
+            .start_request(Request {
+                id: 0,
+                context,
+                message: (),
+            })
+            .unwrap();
+        assert_eq!(req.request.context.peer_addr, Some(peer_addr));
+
+        let (_tx, rx) = crate::transport::channel::unbounded::<ServerMessage<()>, _>();
+        let mut channel = Box::pin(BaseChannel::with_defaults(rx));
+        let req = channel
+            .as_mut()
+            .start_request(Request {
+                id: 0,
+                context,
+                message: (),
+            })
+            .unwrap();
+        assert_eq!(req.request.context.peer_addr, None);
+    }
+
+    #[tokio::test]
+    async fn base_channel_request_spans_are_children_of_channel_span() {
+        use std::{
+            fmt::{self, Write},
+            sync::{Arc, Mutex},
+        };
+        use tracing::{field::Field, span};
+        use tracing_subscriber::{layer, prelude::*, registry::LookupSpan};
+
+        #[derive(Clone, Default)]
+        struct Spans(Arc<Mutex<Vec<String>>>);
+
+        impl tracing::field::Visit for Spans {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                let mut spans = self.0.lock().unwrap();
+                let span = spans.last_mut().unwrap();
+                write!(span, " {}={:?}", field.name(), value).unwrap();
+            }
+        }
+
+        impl<S> tracing_subscriber::Layer<S> for Spans
+        where
+            S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+        {
+            fn on_new_span(&self, attrs: &span::Attributes, id: &span::Id, ctx: layer::Context<S>) {
+                let parent = ctx.span(id).and_then(|span| span.parent());
+                self.0.lock().unwrap().push(format!(
+                    "{} parent={}",
+                    attrs.metadata().name(),
+                    parent.map_or("none", |parent| parent.name())
+                ));
+                attrs.record(&mut self.clone());
+            }
+        }
+
+        let spans = Spans::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(spans.clone()));
+
+        let peer_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let (_tx, rx) = crate::transport::channel::unbounded::<ServerMessage<()>, _>();
+        let mut channel = Box::pin(BaseChannel::with_defaults(rx).with_peer_addr(peer_addr));
+        channel
+            .as_mut()
+            .start_request(Request {
+                id: 7,
+                context: context::current(),
+                message: (),
+            })
+            .unwrap();
+
+        let spans = spans.0.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert!(spans[0].starts_with("Channel parent=none"), "{}", spans[0]);
+        assert!(spans[1].starts_with("RPC parent=Channel"), "{}", spans[1]);
+        assert!(spans[1].contains(" rpc.request_id=7"), "{}", spans[1]);
+    }
+
+    #[tokio::test]
+    async fn base_channel_honors_sampling_decision() {
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry());
+        let (_tx, rx) = crate::transport::channel::unbounded::<ServerMessage<()>, _>();
+        let config = Config {
+            trace_unsampled: false,
+            ..Config::default()
+        };
+        let mut channel = Box::pin(BaseChannel::new(config, rx));
+
+        for (id, sampling_decision) in [
+            (0, trace::SamplingDecision::Sampled),
+            (1, trace::SamplingDecision::Unsampled),
+        ] {
+            let mut context = context::current();
+            context.trace_context = trace::Context {
+                trace_id: 7.into(),
+                span_id: 8.into(),
+                sampling_decision,
+            };
+            let req = channel
+                .as_mut()
+                .start_request(Request {
+                    id,
+                    context,
+                    message: (),
+                })
+                .unwrap();
+            let trace_context = req.request.context.trace_context;
+            assert_eq!(req.span.is_none(), !trace_context.is_sampled());
+            assert_eq!(trace_context.trace_id, 7.into());
+            assert_eq!(trace_context.sampling_decision, sampling_decision);
+        }
+    }
+
     #[tokio::test]
     async fn base_channel_with_closed_transport_and_in_flight_request_returns_pending() {
         let (mut channel, tx) = test_channel::<(), ()>();
@@ -1023,14 +3665,56 @@ mod tests {
         assert_eq!(channel.in_flight_requests(), 1);
         channel
             .as_mut()
-            .start_send(Response {
-                request_id: 0,
-                message: Ok(()),
-            })
+            .start_send(
+                Response {
+                    request_id: 0,
+                    message: Ok(()),
+                }
+                .into(),
+            )
             .unwrap();
         assert_eq!(channel.in_flight_requests(), 0);
     }
 
+    #[tokio::test]
+    async fn base_channel_drops_duplicate_responses() {
+        let (mut channel, mut tx) = test_channel::<(), i32>();
+
+        for request_id in [0, 1] {
+            channel
+                .as_mut()
+                .start_request(Request {
+                    id: request_id,
+                    context: context::current(),
+                    message: (),
+                })
+                .unwrap();
+        }
+        let response = |request_id, message| {
+            ServerMessage::from(Response {
+                request_id,
+                message: Ok(message),
+            })
+        };
+        channel.as_mut().start_send(response(0, 1)).unwrap();
+        channel.as_mut().start_send(response(0, 2)).unwrap();
+        assert_eq!(channel.duplicate_responses(), 1);
+
+        // A response to a canceled request isn't a duplicate.
+        channel.in_flight_requests.cancel_request(1);
+        channel.as_mut().start_send(response(1, 3)).unwrap();
+        assert_eq!(channel.duplicate_responses(), 1);
+
+        assert_matches!(
+            tx.next().now_or_never(),
+            Some(Some(Ok(ServerMessage::Response(Response {
+                request_id: 0,
+                message: Ok(1)
+            }))))
+        );
+        assert_matches!(tx.next().now_or_never(), None);
+    }
+
     #[tokio::test]
     async fn in_flight_request_drop_cancels_request() {
         let (mut requests, mut tx) = test_requests::<(), ()>();
@@ -1051,6 +3735,90 @@ mod tests {
         assert_eq!(in_flight_requests, 0);
     }
 
+    #[tokio::test]
+    async fn serve_single_threaded_drives_non_send_handlers() {
+        use std::{cell::Cell, rc::Rc};
+
+        let (mut tx, rx) = crate::transport::channel::unbounded();
+        let served = Rc::new(Cell::new(0));
+        let mut executor = Box::pin(super::serve_single_threaded(
+            BaseChannel::new(Config::default(), rx),
+            {
+                let served = served.clone();
+                move |_: context::Context, i: u32| {
+                    let served = served.clone();
+                    async move {
+                        served.set(served.get() + 1);
+                        i * 2
+                    }
+                }
+            },
+        ));
+        tx.send(fake_request(1)).await.unwrap();
+        tx.send(ClientMessage::Request(Request {
+            context: context::current(),
+            id: 1,
+            message: 2,
+        }))
+        .await
+        .unwrap();
+
+        assert_matches!(executor.as_mut().poll(&mut noop_context()), Poll::Pending);
+        assert_eq!(served.get(), 2);
+        assert_eq!(executor.running_handlers(), 0);
+        let mut responses = vec![];
+        while let Some(Some(Ok(ServerMessage::Response(response)))) = tx.next().now_or_never() {
+            responses.push((response.request_id, response.message.unwrap()));
+        }
+        responses.sort_unstable();
+        assert_eq!(responses, [(0, 2), (1, 4)]);
+
+        drop(tx);
+        assert_matches!(executor.as_mut().poll(&mut noop_context()), Poll::Ready(()));
+    }
+
+    #[tokio::test]
+    async fn serve_single_threaded_writes_responses_after_read_error() {
+        use super::testing::FakeChannel;
+        use futures::channel::oneshot;
+        use std::io;
+
+        let mut channel = FakeChannel::default::<u32, u32>();
+        channel.push_req(0, 1);
+        if let Some(Ok(request)) = channel.stream.back_mut() {
+            request.request.context.deadline = SystemTime::now() + Duration::from_secs(60);
+        }
+        channel
+            .stream
+            .push_back(Err(io::Error::from(io::ErrorKind::ConnectionReset)));
+        let (done_tx, done_rx) = oneshot::channel::<()>();
+        let done_rx = done_rx.shared();
+        let mut executor = Box::pin(super::serve_single_threaded(
+            channel,
+            move |_: context::Context, i: u32| {
+                let done_rx = done_rx.clone();
+                async move {
+                    let _ = done_rx.await;
+                    i + 1
+                }
+            },
+        ));
+
+        // The read fails while the handler is still running.
+        assert_matches!(executor.as_mut().poll(&mut noop_context()), Poll::Pending);
+        assert_eq!(executor.running_handlers(), 1);
+
+        done_tx.send(()).unwrap();
+        assert_matches!(executor.as_mut().poll(&mut noop_context()), Poll::Ready(()));
+        assert_matches!(
+            executor.requests.channel().sink.front(),
+            Some(ServerMessage::Response(Response {
+                request_id: 0,
+                message: Ok(2)
+            }))
+        );
+    }
+
     #[tokio::test]
     async fn in_flight_requests_successful_execute_doesnt_cancel_request() {
         let (mut requests, mut tx) = test_requests::<(), ()>();
@@ -1069,6 +3837,601 @@ mod tests {
             .is_pending());
     }
 
+    #[tokio::test]
+    async fn execute_with_cleanup_cleans_up_canceled_request() {
+        let (mut requests, mut tx) = test_requests::<(), ()>();
+        tx.send(fake_request(())).await.unwrap();
+
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        let (cleaned_up_tx, cleaned_up) = tokio::sync::oneshot::channel();
+        let execution = tokio::spawn(request.execute_with_cleanup(
+            |_, _| pending::<()>(),
+            async move {
+                let _ = cleaned_up_tx.send(());
+            },
+        ));
+
+        tx.send(ClientMessage::Cancel {
+            trace_context: trace::Context::default(),
+            request_id: 0,
+        })
+        .await
+        .unwrap();
+        assert_matches!(
+            requests.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        execution.await.unwrap();
+        assert_matches!(cleaned_up.await, Ok(()));
+    }
+
+    #[cfg(feature = "tokio1")]
+    #[tokio::test]
+    async fn execute_inline_threshold_responds_without_spawning() {
+        let (mut tx, rx) = crate::transport::channel::unbounded();
+        let mut executor = Box::pin(
+            BaseChannel::new(Config::default(), rx)
+                .execute(|_: context::Context, ()| async { 1 })
+                .inline_threshold(1),
+        );
+        tx.send(fake_request(())).await.unwrap();
+
+        // The test runtime is single-threaded, so a spawned handler couldn't have run yet.
+        assert_matches!(executor.as_mut().poll(&mut noop_context()), Poll::Pending);
+        assert_matches!(
+            tx.next().now_or_never(),
+            Some(Some(Ok(ServerMessage::Response(Response {
+                request_id: 0,
+                message: Ok(1)
+            }))))
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_gives_handler_deadline_notice() {
+        tokio::time::pause();
+        let (mut tx, rx) = crate::transport::channel::unbounded();
+        let config = Config {
+            deadline_notice: Duration::from_secs(1),
+            ..Config::default()
+        };
+        let mut requests = Box::pin(BaseChannel::new(config, rx).requests());
+        tx.send(ClientMessage::Request(Request {
+            context: context::Context {
+                deadline: SystemTime::now(),
+                ..context::current()
+            },
+            id: 0,
+            message: (),
+        }))
+        .await
+        .unwrap();
+
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        tokio::spawn(request.execute(|_, ()| async {
+            let cancellation = Cancellation::current().unwrap();
+            cancellation.cancelled().await;
+            // Respond with partial progress.
+            7
+        }));
+        tokio::spawn(requests.for_each(|_| async {}));
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(ServerMessage::Response(Response {
+                request_id: 0,
+                message: Ok(7)
+            })))
+        );
+    }
+
+    #[tokio::test]
+    async fn base_channel_records_expired_requests() {
+        tokio::time::pause();
+        let (mut tx, rx) =
+            crate::transport::channel::unbounded::<ServerMessage<()>, ClientMessage<()>>();
+        let rejections = RejectionLog::default();
+        let config = Config {
+            rejections: Some(rejections.clone()),
+            ..Config::default()
+        };
+        let peer_addr: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mut channel = Box::pin(BaseChannel::new(config, rx).with_peer_addr(peer_addr));
+        tx.send(ClientMessage::Request(Request {
+            context: context::Context {
+                deadline: SystemTime::now(),
+                ..context::current()
+            },
+            id: 0,
+            message: (),
+        }))
+        .await
+        .unwrap();
+
+        let _request = match channel.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        let recent = rejections.recent(10);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].reason, RejectReason::DeadlineExceeded);
+        assert_eq!(recent[0].peer.as_deref(), Some("127.0.0.1:8080"));
+    }
+
+    #[tokio::test]
+    async fn base_channel_responds_to_expired_requests() {
+        tokio::time::pause();
+        let (mut tx, rx) =
+            crate::transport::channel::unbounded::<ServerMessage<()>, ClientMessage<()>>();
+        let config = Config {
+            respond_to_expired_requests: true,
+            ..Config::default()
+        };
+        let mut channel = Box::pin(BaseChannel::new(config, rx));
+        tx.send(ClientMessage::Request(Request {
+            context: context::Context {
+                deadline: SystemTime::now(),
+                ..context::current()
+            },
+            id: 0,
+            message: (),
+        }))
+        .await
+        .unwrap();
+
+        let _request = match channel.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_eq!(channel.in_flight_requests(), 0);
+
+        assert_matches!(
+            channel.as_mut().poll_ready(&mut noop_context()),
+            Poll::Ready(Ok(()))
+        );
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(ServerMessage::Response(Response {
+                request_id: 0,
+                message: Err(ServerError {
+                    kind: std::io::ErrorKind::TimedOut,
+                    ..
+                }),
+            })))
+        );
+    }
+
+    #[tokio::test]
+    async fn base_channel_answers_hello() {
+        let (mut tx, rx) =
+            crate::transport::channel::unbounded::<ServerMessage<()>, ClientMessage<()>>();
+        let mut channel = Box::pin(BaseChannel::with_defaults(rx));
+        let capabilities = Capabilities::STREAMING | Capabilities::COMPRESSION;
+        tx.send(ClientMessage::Hello { capabilities })
+            .await
+            .unwrap();
+        tx.send(ClientMessage::Request(Request {
+            context: context::current(),
+            id: 0,
+            message: (),
+        }))
+        .await
+        .unwrap();
+
+        assert_eq!(channel.peer_capabilities(), None);
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(Ok(_)))
+        );
+        assert_eq!(channel.peer_capabilities(), Some(capabilities));
+        assert_eq!(channel.snapshot().peer_capabilities, Some(capabilities));
+
+        // The hello is answered before the response.
+        assert_matches!(
+            channel.as_mut().poll_ready(&mut noop_context()),
+            Poll::Ready(Ok(()))
+        );
+        channel
+            .as_mut()
+            .start_send(ServerMessage::Response(Response {
+                request_id: 0,
+                message: Ok(()),
+            }))
+            .unwrap();
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(ServerMessage::Hello { capabilities })) if capabilities == Capabilities::SUPPORTED
+        );
+        assert_matches!(tx.next().await, Some(Ok(ServerMessage::Response(_))));
+    }
+
+    #[tokio::test]
+    async fn base_channel_unpacks_batches() {
+        let (mut tx, rx) =
+            crate::transport::channel::unbounded::<ServerMessage<u32>, ClientMessage<u32>>();
+        let mut channel = Box::pin(BaseChannel::with_defaults(rx));
+        let request = |id| Request {
+            context: context::current(),
+            id,
+            message: id as u32,
+        };
+        tx.send(ClientMessage::Batch {
+            requests: vec![request(0), request(1)],
+        })
+        .await
+        .unwrap();
+        tx.send(ClientMessage::Request(request(2))).await.unwrap();
+
+        for id in 0..3 {
+            assert_matches!(
+                channel.as_mut().poll_next(&mut noop_context()),
+                Poll::Ready(Some(Ok(request))) if request.request.id == id
+            );
+        }
+        assert_eq!(channel.in_flight_requests.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn base_channel_fails_batched_requests_on_shutdown() {
+        let (mut tx, rx) =
+            crate::transport::channel::unbounded::<ServerMessage<u32>, ClientMessage<u32>>();
+        let shutdown = Shutdown::new();
+        let mut channel = Box::pin(BaseChannel::new(
+            Config::default().with_shutdown(shutdown.clone()),
+            rx,
+        ));
+        let request = |id| Request {
+            context: context::current(),
+            id,
+            message: id as u32,
+        };
+        tx.send(ClientMessage::Batch {
+            requests: vec![request(0), request(1), request(2)],
+        })
+        .await
+        .unwrap();
+
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(Ok(request))) if request.request.id == 0
+        );
+        assert_matches!(
+            shutdown.shutdown(Duration::ZERO).await,
+            Err(DrainTimeout { channels: 1 })
+        );
+        // The channel waits on the request in flight, but doesn't start the rest of the batch.
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_eq!(channel.in_flight_requests.len(), 1);
+
+        assert_matches!(
+            channel.as_mut().poll_ready(&mut noop_context()),
+            Poll::Ready(Ok(()))
+        );
+        for id in [1, 2] {
+            assert_matches!(
+                tx.next().await,
+                Some(Ok(ServerMessage::Response(Response {
+                    request_id,
+                    message: Err(e),
+                }))) if request_id == id && e.cause() == crate::ServerErrorCause::Shutdown
+            );
+        }
+    }
+
+    #[derive(Clone)]
+    struct Reject;
+
+    impl Serve<()> for Reject {
+        type Resp = ();
+        type Fut = future::Ready<()>;
+
+        fn method(&self, _: &()) -> Option<&'static str> {
+            Some("Reject.call")
+        }
+
+        fn serve_with_items(
+            self,
+            _: context::Context,
+            _: (),
+            _: RequestStream<()>,
+        ) -> Served<Self::Fut> {
+            Served::Error(crate::ServerError::new(
+                std::io::ErrorKind::InvalidInput,
+                "malformed request",
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_resolves_to_rejection() {
+        assert_matches!(
+            Reject.serve(context::current(), ()).await,
+            Err(ServerError {
+                kind: std::io::ErrorKind::InvalidInput,
+                ..
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_records_rejected_requests() {
+        let (mut tx, rx) = crate::transport::channel::unbounded();
+        let rejections = RejectionLog::default();
+        let config = Config {
+            rejections: Some(rejections.clone()),
+            ..Config::default()
+        };
+        let mut requests = Box::pin(BaseChannel::new(config, rx).requests());
+        tx.send(fake_request(())).await.unwrap();
+
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+
+        tokio::spawn(request.execute(Reject));
+        tokio::spawn(requests.for_each(|_| async {}));
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(ServerMessage::Response(Response {
+                request_id: 0,
+                message: Err(_)
+            })))
+        );
+        let recent = rejections.recent(10);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].reason, RejectReason::Invalid);
+        assert_eq!(recent[0].method.as_deref(), Some("Reject.call"));
+        assert_eq!(recent[0].detail, "malformed request");
+    }
+
+    #[tokio::test]
+    async fn execute_injects_faults() {
+        let (mut tx, rx) = crate::transport::channel::unbounded();
+        let rejections = RejectionLog::default();
+        let faults = FaultInjector::new();
+        faults.set_rules(vec![FaultRule::new(
+            std::io::ErrorKind::ConnectionRefused,
+            1.0,
+        )]);
+        let config = Config {
+            rejections: Some(rejections.clone()),
+            faults: Some(faults),
+            ..Config::default()
+        };
+        let mut requests = Box::pin(BaseChannel::new(config, rx).requests());
+        tx.send(fake_request(())).await.unwrap();
+
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+
+        tokio::spawn(request.execute(|_, _: ()| async { panic!("handled an injected fault") }));
+        tokio::spawn(requests.for_each(|_| async {}));
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(ServerMessage::Response(Response {
+                request_id: 0,
+                message: Err(ServerError {
+                    kind: std::io::ErrorKind::ConnectionRefused,
+                    ..
+                })
+            })))
+        );
+        let recent = rejections.recent(10);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].reason, RejectReason::Injected);
+    }
+
+    #[tokio::test]
+    async fn execute_catches_panics() {
+        let (mut tx, rx) = crate::transport::channel::unbounded();
+        let config = Config {
+            catch_panics: true,
+            ..Config::default()
+        };
+        let mut requests = Box::pin(BaseChannel::new(config, rx).requests());
+        tx.send(fake_request(())).await.unwrap();
+
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+
+        let handler = tokio::spawn(request.execute(|_, _: ()| async { panic!("oops") }));
+        // The panic doesn't unwind past the request.
+        assert_matches!(handler.await, Ok(()));
+        tokio::spawn(requests.for_each(|_| async {}));
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(ServerMessage::Response(Response {
+                request_id: 0,
+                message: Err(ServerError {
+                    kind: std::io::ErrorKind::Other,
+                    detail,
+                    ..
+                })
+            }))) if detail == "the handler panicked: oops"
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_records_metrics() {
+        let (mut tx, rx) = crate::transport::channel::unbounded();
+        let recorder = std::sync::Arc::new(EventRecorder::default());
+        let config = Config {
+            metrics: Some(recorder.clone()),
+            ..Config::default()
+        };
+        let mut requests = Box::pin(BaseChannel::new(config, rx).requests());
+        tx.send(fake_request(())).await.unwrap();
+
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        request.execute(|_, _: ()| async {}).await;
+        assert_matches!(
+            requests.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(ServerMessage::Response(Response {
+                request_id: 0,
+                message: Ok(())
+            })))
+        );
+        assert_eq!(
+            recorder.events(),
+            [
+                "server started ",
+                "server completed  success",
+                "server queue 0"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_gives_handler_peer_identity() {
+        let (mut tx, rx) = crate::transport::channel::unbounded();
+        let identity = PeerIdentity {
+            common_name: Some("client".into()),
+            ..PeerIdentity::default()
+        };
+        let channel = BaseChannel::with_defaults(rx).with_peer_identity(identity);
+        assert_eq!(
+            channel
+                .peer_identity()
+                .and_then(|i| i.common_name.as_deref()),
+            Some("client")
+        );
+        let mut requests = Box::pin(channel.requests());
+        tx.send(fake_request(())).await.unwrap();
+
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        tokio::spawn(request.execute(|_, ()| async {
+            PeerIdentity::current().and_then(|identity| identity.common_name.clone())
+        }));
+        tokio::spawn(requests.for_each(|_| async {}));
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(ServerMessage::Response(Response {
+                request_id: 0,
+                message: Ok(Some(name))
+            }))) if name == "client"
+        );
+    }
+
+    #[tokio::test]
+    async fn pusher_buffers_pushes_until_requests_dropped() {
+        let (mut requests, _tx) = test_requests::<(), u32>();
+        let pusher = requests.pusher();
+        pusher.push(1).await.unwrap();
+        pusher.clone().try_push(2).unwrap();
+
+        assert_matches!(
+            requests.as_mut().pending_responses_mut().recv().await,
+            Some(ServerMessage::Push(1))
+        );
+        assert_matches!(
+            requests.as_mut().pending_responses_mut().recv().await,
+            Some(ServerMessage::Push(2))
+        );
+        drop(requests);
+        assert!(pusher.is_closed());
+        assert_eq!(pusher.push(3).await, Err(PushError::Closed));
+        assert_eq!(pusher.try_push(3), Err(PushError::Closed));
+    }
+
+    #[tokio::test]
+    async fn pusher_fails_when_buffer_full() {
+        let (tx, rx) =
+            crate::transport::channel::unbounded::<ServerMessage<u32>, ClientMessage<()>>();
+        let _tx = tx;
+        let requests = BaseChannel::new(
+            Config {
+                pending_response_buffer: 1,
+                ..Config::default()
+            },
+            rx,
+        )
+        .requests();
+        let pusher = requests.pusher();
+        pusher.try_push(1).unwrap();
+        assert_eq!(pusher.try_push(2), Err(PushError::Full));
+    }
+
+    #[tokio::test]
+    async fn response_handle_responds_from_another_task() {
+        let (mut requests, mut tx) = test_requests::<(), u32>();
+        tx.send(fake_request(())).await.unwrap();
+
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        let (request, response_handle) = request.split();
+        assert_eq!(response_handle.request_id(), request.id);
+        tokio::spawn(response_handle.respond(7)).await.unwrap();
+
+        assert_matches!(
+            requests.as_mut().pending_responses_mut().recv().await,
+            Some(ServerMessage::Response(Response {
+                request_id: 0,
+                message: Ok(7)
+            }))
+        );
+        assert!(requests
+            .as_mut()
+            .channel_pin_mut()
+            .canceled_requests
+            .poll_recv(&mut noop_context())
+            .is_pending());
+    }
+
+    #[tokio::test]
+    async fn response_handle_drop_cancels_request() {
+        let (mut requests, mut tx) = test_requests::<(), ()>();
+        tx.send(fake_request(())).await.unwrap();
+
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        let (_, response_handle) = request.split();
+        drop(response_handle);
+
+        let poll = requests
+            .as_mut()
+            .channel_pin_mut()
+            .poll_next(&mut noop_context());
+        assert!(poll.is_pending());
+        assert_eq!(requests.channel().in_flight_requests(), 0);
+    }
+
     #[tokio::test]
     async fn requests_poll_next_response_returns_pending_when_buffer_full() {
         let (mut requests, _tx) = test_bounded_requests::<(), ()>(0);
@@ -1086,10 +4449,13 @@ mod tests {
         requests
             .as_mut()
             .channel_pin_mut()
-            .start_send(Response {
-                request_id: 0,
-                message: Ok(()),
-            })
+            .start_send(
+                Response {
+                    request_id: 0,
+                    message: Ok(()),
+                }
+                .into(),
+            )
             .unwrap();
 
         // Response waiting to be written.
@@ -1097,10 +4463,13 @@ mod tests {
             .as_mut()
             .project()
             .responses_tx
-            .send(Response {
-                request_id: 1,
-                message: Ok(()),
-            })
+            .send(
+                Response {
+                    request_id: 1,
+                    message: Ok(()),
+                }
+                .into(),
+            )
             .await
             .unwrap();
 
@@ -1137,10 +4506,13 @@ mod tests {
         requests
             .as_mut()
             .channel_pin_mut()
-            .start_send(Response {
-                request_id: 0,
-                message: Ok(()),
-            })
+            .start_send(
+                Response {
+                    request_id: 0,
+                    message: Ok(()),
+                }
+                .into(),
+            )
             .unwrap();
 
         // Response waiting to be written.
@@ -1157,10 +4529,13 @@ mod tests {
             .as_mut()
             .project()
             .responses_tx
-            .send(Response {
-                request_id: 1,
-                message: Ok(()),
-            })
+            .send(
+                Response {
+                    request_id: 1,
+                    message: Ok(()),
+                }
+                .into(),
+            )
             .await
             .unwrap();
 
@@ -1175,6 +4550,146 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn requests_pump_write_defers_flush() {
+        tokio::time::pause();
+        let (_tx, rx) = crate::transport::channel::unbounded::<ServerMessage<()>, _>();
+        let config = Config {
+            flush_policy: FlushPolicy::Deferred {
+                responses: 2,
+                delay: Duration::from_secs(1),
+            },
+            ..Config::default()
+        };
+        let mut requests = Box::pin(BaseChannel::new(config, rx).requests());
+        for id in 0..3 {
+            requests
+                .as_mut()
+                .channel_pin_mut()
+                .start_request(Request {
+                    id,
+                    context: context::current(),
+                    message: (),
+                })
+                .unwrap();
+        }
+        let respond = |request_id| {
+            ServerMessage::Response(Response {
+                request_id,
+                message: Ok(()),
+            })
+        };
+
+        // The flush is deferred until the delay passes.
+        requests.responses_tx.send(respond(0)).await.unwrap();
+        assert_matches!(
+            requests.as_mut().pump_write(&mut noop_context(), false),
+            Poll::Ready(Some(Ok(())))
+        );
+        assert_matches!(
+            requests.as_mut().pump_write(&mut noop_context(), false),
+            Poll::Pending
+        );
+        assert_eq!(requests.unflushed, 1);
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_matches!(
+            requests.as_mut().pump_write(&mut noop_context(), false),
+            Poll::Pending
+        );
+        assert_eq!(requests.unflushed, 0);
+
+        // Or until enough responses are unflushed.
+        for request_id in 1..3 {
+            requests
+                .responses_tx
+                .send(respond(request_id))
+                .await
+                .unwrap();
+            assert_matches!(
+                requests.as_mut().pump_write(&mut noop_context(), false),
+                Poll::Ready(Some(Ok(())))
+            );
+        }
+        assert_matches!(
+            requests.as_mut().pump_write(&mut noop_context(), false),
+            Poll::Pending
+        );
+        assert_eq!(requests.unflushed, 0);
+    }
+
+    #[tokio::test]
+    async fn requests_dropped_mid_stream_tear_down_channel() {
+        let (mut tx, rx) = crate::transport::channel::unbounded::<ServerMessage<()>, _>();
+        let mut requests = Box::pin(BaseChannel::with_defaults(rx).requests());
+        tx.send(ClientMessage::Hello {
+            capabilities: Capabilities::CLOSE,
+        })
+        .await
+        .unwrap();
+        for id in 0..2 {
+            tx.send(ClientMessage::Request(Request {
+                context: context::current(),
+                id,
+                message: (),
+            }))
+            .await
+            .unwrap();
+        }
+        let mut in_flight = Vec::new();
+        for _ in 0..2 {
+            match requests.as_mut().poll_next(&mut noop_context()) {
+                Poll::Ready(Some(Ok(request))) => in_flight.push(request),
+                poll => panic!("Unexpected poll: {:?}", poll.map(|_| ())),
+            }
+        }
+        let mut executing = Box::pin(in_flight.remove(0).execute(|_, ()| pending::<()>()));
+        assert_matches!(executing.as_mut().poll(&mut noop_context()), Poll::Pending);
+        // The response to request 1 is staged, but not written, when requests is dropped.
+        requests
+            .responses_tx
+            .send(ServerMessage::Response(Response {
+                request_id: 1,
+                message: Ok(()),
+            }))
+            .await
+            .unwrap();
+
+        drop(requests);
+        assert_matches!(
+            executing.as_mut().poll(&mut noop_context()),
+            Poll::Ready(())
+        );
+        assert_matches!(tx.next().await, Some(Ok(ServerMessage::Hello { .. })));
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(ServerMessage::Response(Response { request_id: 1, .. })))
+        );
+        assert_matches!(
+            tx.next().await,
+            Some(Ok(ServerMessage::Close { aborted })) if aborted == [0]
+        );
+        assert_matches!(tx.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn requests_dropped_mid_stream_send_no_close_to_clients_without_capability() {
+        let (mut requests, mut tx) = test_requests::<(), ()>();
+        tx.send(fake_request(())).await.unwrap();
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            poll => panic!("Unexpected poll: {:?}", poll.map(|_| ())),
+        };
+        let mut executing = Box::pin(request.execute(|_, ()| pending::<()>()));
+        assert_matches!(executing.as_mut().poll(&mut noop_context()), Poll::Pending);
+
+        drop(requests);
+        assert_matches!(
+            executing.as_mut().poll(&mut noop_context()),
+            Poll::Ready(())
+        );
+        assert_matches!(tx.next().await, None);
+    }
+
     #[tokio::test]
     async fn requests_pump_read() {
         let (mut requests, mut tx) = test_requests::<(), ()>();
@@ -1188,4 +4703,284 @@ mod tests {
         );
         assert_eq!(requests.channel.in_flight_requests(), 1);
     }
+
+    #[derive(Clone)]
+    struct CountUpTo;
+
+    impl Serve<u32> for CountUpTo {
+        type Resp = u32;
+        type Fut = future::Ready<u32>;
+
+        fn serve_with_items(
+            self,
+            _: context::Context,
+            n: u32,
+            _: RequestStream<u32>,
+        ) -> Served<Self::Fut> {
+            Served::Stream(Box::pin(stream::iter(0..n)))
+        }
+    }
+
+    #[tokio::test]
+    async fn base_channel_poll_next_routes_stream_items_to_request() {
+        let (mut channel, mut tx) = test_channel::<i32, ()>();
+
+        tx.send(fake_request(0)).await.unwrap();
+        tx.send(ClientMessage::StreamItem {
+            request_id: 0,
+            item: 1,
+        })
+        .await
+        .unwrap();
+        tx.send(ClientMessage::StreamItem {
+            request_id: 0,
+            item: 2,
+        })
+        .await
+        .unwrap();
+        tx.send(ClientMessage::StreamEnd { request_id: 0 })
+            .await
+            .unwrap();
+
+        let request = channel.as_mut().next().await.unwrap().unwrap();
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        let items: Vec<_> = request.request_items.collect().await;
+        assert_eq!(items, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn base_channel_poll_next_pauses_reads_while_request_stream_is_full() {
+        let (tx, rx) = crate::transport::channel::unbounded();
+        let config = Config {
+            stream_window: 1,
+            ..Config::default()
+        };
+        let mut channel = Box::pin(BaseChannel::<i32, (), _>::new(config, rx));
+        let mut tx: UnboundedChannel<ServerMessage<()>, ClientMessage<i32>> = tx;
+
+        tx.send(fake_request(0)).await.unwrap();
+        for item in [1, 2] {
+            tx.send(ClientMessage::StreamItem {
+                request_id: 0,
+                item,
+            })
+            .await
+            .unwrap();
+        }
+        tx.send(ClientMessage::Request(Request {
+            context: context::current(),
+            id: 1,
+            message: 0,
+        }))
+        .await
+        .unwrap();
+
+        let mut request = channel.as_mut().next().await.unwrap().unwrap();
+        // The second item doesn't fit, so the second request isn't read yet.
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_eq!(channel.in_flight_requests(), 1);
+
+        assert_eq!(request.request_items.next().await, Some(1));
+        assert_matches!(
+            channel.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(Ok(TrackedRequest { .. })))
+        );
+        assert_matches!(
+            request.request_items.poll_next_unpin(&mut noop_context()),
+            Poll::Ready(Some(2))
+        );
+    }
+
+    #[tokio::test]
+    async fn base_channel_start_send_stream_item_keeps_in_flight_request() {
+        let (mut channel, _tx) = test_channel::<(), ()>();
+
+        channel
+            .as_mut()
+            .start_request(Request {
+                id: 0,
+                context: context::current(),
+                message: (),
+            })
+            .unwrap();
+        channel
+            .as_mut()
+            .start_send(ServerMessage::StreamItem {
+                request_id: 0,
+                item: (),
+            })
+            .unwrap();
+        assert_eq!(channel.in_flight_requests(), 1);
+        channel
+            .as_mut()
+            .start_send(ServerMessage::StreamEnd { request_id: 0 })
+            .unwrap();
+        assert_eq!(channel.in_flight_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn streaming_in_flight_request_exchanges_items() {
+        let (mut requests, mut tx) = test_requests::<u32, u32>();
+        tx.send(fake_request(0)).await.unwrap();
+        tx.send(ClientMessage::StreamItem {
+            request_id: 0,
+            item: 1,
+        })
+        .await
+        .unwrap();
+        tx.send(ClientMessage::StreamEnd { request_id: 0 })
+            .await
+            .unwrap();
+
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        let mut request = request.into_streaming();
+        assert_matches!(
+            requests.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_eq!(request.next().await, Some(1));
+        assert_eq!(request.next().await, None);
+        request.send(2).await.unwrap();
+        request.end().await;
+
+        let mut requests = requests.as_mut();
+        let pending_responses = requests.pending_responses_mut();
+        assert_matches!(
+            pending_responses.recv().await,
+            Some(ServerMessage::StreamItem {
+                request_id: 0,
+                item: 2
+            })
+        );
+        assert_matches!(
+            pending_responses.recv().await,
+            Some(ServerMessage::StreamEnd { request_id: 0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn streaming_in_flight_request_send_fails_when_canceled() {
+        let (mut requests, mut tx) = test_requests::<u32, u32>();
+        tx.send(fake_request(0)).await.unwrap();
+
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        let mut request = request.into_streaming();
+        tx.send(ClientMessage::Cancel {
+            trace_context: trace::Context::default(),
+            request_id: 0,
+        })
+        .await
+        .unwrap();
+        assert_matches!(
+            requests.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_matches!(request.send(2).await, Err(_));
+    }
+
+    #[tokio::test]
+    async fn in_flight_request_execute_streams_items() {
+        let (mut requests, mut tx) = test_requests::<u32, u32>();
+        tx.send(fake_request(2)).await.unwrap();
+
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        request.execute(CountUpTo).await;
+
+        let mut requests = requests.as_mut();
+        let pending_responses = requests.pending_responses_mut();
+        assert_matches!(
+            pending_responses.recv().await,
+            Some(ServerMessage::StreamItem {
+                request_id: 0,
+                item: 0
+            })
+        );
+        assert_matches!(
+            pending_responses.recv().await,
+            Some(ServerMessage::StreamItem {
+                request_id: 0,
+                item: 1
+            })
+        );
+        assert_matches!(
+            pending_responses.recv().await,
+            Some(ServerMessage::StreamEnd { request_id: 0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn in_flight_request_execute_waits_for_stream_credits() {
+        let (tx, rx) = crate::transport::channel::unbounded();
+        let config = Config {
+            stream_window: 1,
+            ..Config::default()
+        };
+        let mut requests = Box::pin(BaseChannel::new(config, rx).requests());
+        let mut tx: UnboundedChannel<ServerMessage<u32>, ClientMessage<u32>> = tx;
+        tx.send(fake_request(2)).await.unwrap();
+
+        let request = match requests.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(Ok(request))) => request,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        let mut execute = Box::pin(request.execute(CountUpTo));
+        assert_matches!(execute.as_mut().poll(&mut noop_context()), Poll::Pending);
+        assert_matches!(
+            requests.as_mut().pending_responses_mut().try_recv(),
+            Ok(ServerMessage::StreamItem { item: 0, .. })
+        );
+        assert!(requests
+            .as_mut()
+            .pending_responses_mut()
+            .try_recv()
+            .is_err());
+
+        tx.send(ClientMessage::StreamCredit {
+            request_id: 0,
+            credits: 1,
+        })
+        .await
+        .unwrap();
+        assert_matches!(
+            requests.as_mut().pump_read(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_matches!(execute.as_mut().poll(&mut noop_context()), Poll::Pending);
+        assert_matches!(
+            requests.as_mut().pending_responses_mut().try_recv(),
+            Ok(ServerMessage::StreamItem { item: 1, .. })
+        );
+
+        // The end of the stream is only observed after pulling once more, which takes a credit.
+        tx.send(ClientMessage::StreamCredit {
+            request_id: 0,
+            credits: 1,
+        })
+        .await
+        .unwrap();
+        assert_matches!(
+            requests.as_mut().pump_read(&mut noop_context()),
+            Poll::Pending
+        );
+        execute.await;
+        assert_matches!(
+            requests.as_mut().pending_responses_mut().try_recv(),
+            Ok(ServerMessage::StreamEnd { .. })
+        );
+    }
 }
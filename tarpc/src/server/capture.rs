@@ -0,0 +1,468 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{
+    context,
+    server::{Config, RequestStream, Serve, Served},
+    ServerError,
+};
+use futures::{prelude::*, ready, task::*};
+use pin_project::pin_project;
+use rand::Rng;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// A request and the response the server sent for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Sample<Req, Resp> {
+    /// The request message.
+    pub request: Req,
+    /// The response message.
+    pub response: Resp,
+}
+
+/// A golden corpus of request/response samples, keyed by method name.
+///
+/// With the `serde1` feature enabled, the corpus can be serialized, e.g. to be checked in next to
+/// the service definition and [verified](verify) against later server builds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Corpus<Req, Resp> {
+    /// The samples recorded for each method. Requests whose method is unknown are recorded
+    /// under the empty string.
+    pub methods: BTreeMap<String, Vec<Sample<Req, Resp>>>,
+}
+
+impl<Req, Resp> Default for Corpus<Req, Resp> {
+    fn default() -> Self {
+        Self {
+            methods: BTreeMap::new(),
+        }
+    }
+}
+
+/// Records a bounded, uniformly random sample of the request/response pairs handled by a
+/// [`Serve`] implementation, per method. Cloned recorders share their samples.
+///
+/// Only single responses are recorded; server-streaming requests and rejected requests are not.
+pub struct Recorder<Req, Resp> {
+    samples_per_method: usize,
+    redact: Option<Arc<dyn Fn(&mut Sample<Req, Resp>) + Send + Sync>>,
+    state: Arc<Mutex<RecorderState<Req, Resp>>>,
+}
+
+struct RecorderState<Req, Resp> {
+    /// The number of requests seen for each method.
+    seen: HashMap<&'static str, usize>,
+    /// The samples of each method, indexed by their position in the reservoir.
+    slots: HashMap<&'static str, Vec<Slot<Req, Resp>>>,
+}
+
+/// A position in the reservoir of samples of a method.
+struct Slot<Req, Resp> {
+    /// The reservation of the request whose sample belongs in the slot. A request that reserved
+    /// the slot earlier, but whose response comes later, doesn't overwrite it.
+    reservation: usize,
+    sample: Option<Sample<Req, Resp>>,
+}
+
+/// The right of a sampled request to store its sample in a slot of the reservoir.
+#[derive(Clone, Copy, Debug)]
+struct Reservation {
+    method: &'static str,
+    index: usize,
+    /// The number of requests for the method seen before this one.
+    id: usize,
+}
+
+impl<Req, Resp> Clone for Recorder<Req, Resp> {
+    fn clone(&self) -> Self {
+        Self {
+            samples_per_method: self.samples_per_method,
+            redact: self.redact.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Recorder<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("samples_per_method", &self.samples_per_method)
+            .finish()
+    }
+}
+
+impl<Req, Resp> Recorder<Req, Resp> {
+    /// Returns a recorder that keeps at most `samples_per_method` samples for each method.
+    pub fn new(samples_per_method: usize) -> Self {
+        Self {
+            samples_per_method,
+            redact: None,
+            state: Arc::new(Mutex::new(RecorderState {
+                seen: HashMap::new(),
+                slots: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Sets a function that scrubs sensitive data from each sample before it is stored.
+    pub fn with_redaction<F>(mut self, redact: F) -> Self
+    where
+        F: Fn(&mut Sample<Req, Resp>) + Send + Sync + 'static,
+    {
+        self.redact = Some(Arc::new(redact));
+        self
+    }
+
+    /// Returns a [`Serve`] implementation that records the requests handled by `serve`.
+    pub fn serve<S>(&self, serve: S) -> Recording<S, Req, Resp> {
+        Recording {
+            serve,
+            recorder: self.clone(),
+        }
+    }
+
+    /// Returns a copy of the samples recorded so far.
+    pub fn corpus(&self) -> Corpus<Req, Resp>
+    where
+        Req: Clone,
+        Resp: Clone,
+    {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let methods = state
+            .slots
+            .iter()
+            .filter_map(|(method, slots)| {
+                let samples: Vec<_> = slots
+                    .iter()
+                    .filter_map(|slot| slot.sample.clone())
+                    .collect();
+                (!samples.is_empty()).then(|| (method.to_string(), samples))
+            })
+            .collect();
+        Corpus { methods }
+    }
+
+    /// Decides whether the next request for `method` is sampled, using reservoir sampling.
+    /// Returns the slot in which the sample should be stored.
+    fn reserve(&self, method: &'static str) -> Option<Reservation> {
+        if self.samples_per_method == 0 {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let seen = state.seen.entry(method).or_default();
+        let id = *seen;
+        let index = if id < self.samples_per_method {
+            id
+        } else {
+            rand::thread_rng().gen_range(0..=id)
+        };
+        *seen += 1;
+        if index >= self.samples_per_method {
+            return None;
+        }
+        let slots = state.slots.entry(method).or_default();
+        match slots.get_mut(index) {
+            // The sample in the slot is kept until the response of this request replaces it.
+            Some(slot) => slot.reservation = id,
+            None => slots.push(Slot {
+                reservation: id,
+                sample: None,
+            }),
+        }
+        Some(Reservation { method, index, id })
+    }
+
+    fn record(&self, reservation: Reservation, mut sample: Sample<Req, Resp>) {
+        if let Some(redact) = &self.redact {
+            redact(&mut sample);
+        }
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let slot = state
+            .slots
+            .get_mut(reservation.method)
+            .and_then(|slots| slots.get_mut(reservation.index));
+        if let Some(slot) = slot {
+            // A request sampled after this one took over the slot.
+            if slot.reservation == reservation.id {
+                slot.sample = Some(sample);
+            }
+        }
+    }
+}
+
+/// A [`Serve`] implementation that records sampled requests and their responses. Created by
+/// [`Recorder::serve`].
+pub struct Recording<S, Req, Resp> {
+    serve: S,
+    recorder: Recorder<Req, Resp>,
+}
+
+impl<S, Req, Resp> Clone for Recording<S, Req, Resp>
+where
+    S: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            serve: self.serve.clone(),
+            recorder: self.recorder.clone(),
+        }
+    }
+}
+
+impl<S, Req, Resp> fmt::Debug for Recording<S, Req, Resp>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recording")
+            .field("serve", &self.serve)
+            .field("recorder", &self.recorder)
+            .finish()
+    }
+}
+
+impl<S, Req> Recording<S, Req, S::Resp>
+where
+    S: Serve<Req>,
+    Req: Clone,
+{
+    fn sample(&self, req: &Req) -> Option<(Recorder<Req, S::Resp>, Reservation, Req)> {
+        let method = self.serve.method(req).unwrap_or("");
+        self.recorder
+            .reserve(method)
+            .map(|reservation| (self.recorder.clone(), reservation, req.clone()))
+    }
+}
+
+impl<S, Req> Serve<Req> for Recording<S, Req, S::Resp>
+where
+    S: Serve<Req>,
+    S::Resp: Clone + Send + 'static,
+    Req: Clone + Send + 'static,
+{
+    type Resp = S::Resp;
+    type Fut = Record<S::Fut, Req, S::Resp>;
+
+    fn method(&self, request: &Req) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    fn config(&self, request: &Req) -> Option<&Config> {
+        self.serve.config(request)
+    }
+
+    fn serve_with_items(
+        self,
+        ctx: context::Context,
+        req: Req,
+        items: RequestStream<Req>,
+    ) -> Served<Self::Fut> {
+        let sample = self.sample(&req);
+        match self.serve.serve_with_items(ctx, req, items) {
+            Served::Response(response) => Served::Response(Record { response, sample }),
+            Served::Fallible(response) => Served::Fallible(Box::pin(async move {
+                let response = response.await?;
+                if let Some((recorder, reservation, request)) = sample {
+                    let sample = Sample {
+                        request,
+                        response: response.clone(),
+                    };
+                    recorder.record(reservation, sample);
+                }
+                Ok(response)
+            })),
+            Served::Stream(stream) => Served::Stream(stream),
+            Served::Error(error) => Served::Error(error),
+        }
+    }
+}
+
+/// A response future that records the response once it completes.
+#[pin_project]
+pub struct Record<Fut, Req, Resp> {
+    #[pin]
+    response: Fut,
+    sample: Option<(Recorder<Req, Resp>, Reservation, Req)>,
+}
+
+impl<Fut, Req, Resp> fmt::Debug for Record<Fut, Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Record")
+            .field("sampled", &self.sample.is_some())
+            .finish()
+    }
+}
+
+impl<Fut, Req, Resp> Future for Record<Fut, Req, Resp>
+where
+    Fut: Future<Output = Resp>,
+    Resp: Clone,
+{
+    type Output = Resp;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Resp> {
+        let this = self.project();
+        let response = ready!(this.response.poll(cx));
+        if let Some((recorder, reservation, request)) = this.sample.take() {
+            let sample = Sample {
+                request,
+                response: response.clone(),
+            };
+            recorder.record(reservation, sample);
+        }
+        Poll::Ready(response)
+    }
+}
+
+/// A sample whose replayed response differs from the recorded one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch<Req, Resp> {
+    /// The method of the request.
+    pub method: String,
+    /// The recorded sample.
+    pub sample: Sample<Req, Resp>,
+    /// The response returned by the server under test.
+    pub actual: Result<Resp, ServerError>,
+}
+
+/// Replays every request in `corpus` against `serve`, and returns the samples for which `serve`
+/// did not respond with the recorded response. An empty result means the server under test is
+/// compatible with the corpus.
+///
+/// Requests are replayed one at a time, with a fresh [context](context::current).
+pub async fn verify<S, Req, Resp>(corpus: &Corpus<Req, Resp>, serve: S) -> Vec<Mismatch<Req, Resp>>
+where
+    S: Serve<Req, Resp = Resp> + Clone,
+    Req: Clone,
+    Resp: Clone + PartialEq,
+{
+    let mut mismatches = vec![];
+    for (method, samples) in &corpus.methods {
+        for sample in samples {
+            let actual = serve
+                .clone()
+                .serve(context::current(), sample.request.clone())
+                .await;
+            if actual.as_ref() != Ok(&sample.response) {
+                mismatches.push(Mismatch {
+                    method: method.clone(),
+                    sample: sample.clone(),
+                    actual,
+                });
+            }
+        }
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    async fn handle<S>(serve: S, req: u32) -> u32
+    where
+        S: Serve<u32, Resp = u32>,
+    {
+        serve.serve(context::current(), req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn recorder_keeps_bounded_samples() {
+        let recorder = Recorder::new(3);
+        let serve = recorder.serve(|_: context::Context, i: u32| future::ready(i * 2));
+        for i in 0..100 {
+            assert_eq!(handle(serve.clone(), i).await, i * 2);
+        }
+
+        let corpus = recorder.corpus();
+        assert_eq!(corpus.methods.len(), 1);
+        let samples = &corpus.methods[""];
+        assert_eq!(samples.len(), 3);
+        for sample in samples {
+            assert_eq!(sample.response, sample.request * 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn recorder_keeps_samples_of_requests_completed_out_of_order() {
+        let recorder = Recorder::new(2);
+        let serve = recorder.serve(|_: context::Context, i: u32| future::ready(i));
+        let first = serve.clone().serve(context::current(), 1);
+        let second = serve.serve(context::current(), 2);
+        assert_eq!(second.await, Ok(2));
+        assert_eq!(first.await, Ok(1));
+
+        let mut samples = recorder.corpus().methods.remove("").unwrap();
+        samples.sort_by_key(|sample| sample.request);
+        assert_eq!(
+            samples,
+            vec![
+                Sample {
+                    request: 1,
+                    response: 1
+                },
+                Sample {
+                    request: 2,
+                    response: 2
+                }
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn recorder_redacts_samples() {
+        let recorder = Recorder::new(1).with_redaction(|sample: &mut Sample<u32, u32>| {
+            sample.request = 0;
+        });
+        handle(
+            recorder.serve(|_: context::Context, i: u32| future::ready(i)),
+            7,
+        )
+        .await;
+
+        assert_eq!(
+            recorder.corpus().methods[""],
+            vec![Sample {
+                request: 0,
+                response: 7
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_reports_changed_responses() {
+        let recorder = Recorder::new(10);
+        let serve = recorder.serve(|_: context::Context, i: u32| future::ready(i + 1));
+        for i in 0..5 {
+            handle(serve.clone(), i).await;
+        }
+        let corpus = recorder.corpus();
+
+        let compatible = |_: context::Context, i: u32| future::ready(i + 1);
+        assert_eq!(verify(&corpus, compatible).await, vec![]);
+
+        let breaking = |_: context::Context, i: u32| future::ready(if i == 3 { 0 } else { i + 1 });
+        assert_eq!(
+            verify(&corpus, breaking).await,
+            vec![Mismatch {
+                method: "".into(),
+                sample: Sample {
+                    request: 3,
+                    response: 4
+                },
+                actual: Ok(0),
+            }]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{server::PeerIdentity, ServerError};
+use rand::Rng;
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+/// A rule that fails a random fraction of the requests it matches with an error, before they are
+/// handled.
+///
+/// A rule matches the requests of one method, of the channels of one peer, or both. For example,
+/// a rule failing 1% of the calls to `World.hello` from the client named `canary` validates that
+/// client's retries in production, without affecting other clients:
+///
+/// ```
+/// use std::io;
+/// use tarpc::server::faults::FaultRule;
+///
+/// let rule = FaultRule::new(io::ErrorKind::ConnectionRefused, 0.01)
+///     .with_method("World.hello")
+///     .with_peer("canary");
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct FaultRule {
+    /// The name of the method whose requests the rule matches, e.g. `World.hello`, or None to
+    /// match the requests of all methods.
+    pub method: Option<String>,
+    /// The peer whose channels the rule matches, or None to match the channels of all peers. A
+    /// peer matches if this is one of the names of its [identity](PeerIdentity::has_name), or the
+    /// IP address of the client.
+    pub peer: Option<String>,
+    /// The kind of error the matched requests fail with.
+    #[cfg_attr(
+        feature = "serde1",
+        serde(serialize_with = "crate::util::serde::serialize_io_error_kind_as_u32")
+    )]
+    #[cfg_attr(
+        feature = "serde1",
+        serde(deserialize_with = "crate::util::serde::deserialize_io_error_kind_from_u32")
+    )]
+    pub kind: io::ErrorKind,
+    /// The fraction of the matched requests that fail, from 0 to 1.
+    pub probability: f64,
+}
+
+impl FaultRule {
+    /// Returns a rule failing `probability` of all requests with an error of `kind`.
+    pub fn new(kind: io::ErrorKind, probability: f64) -> Self {
+        Self {
+            method: None,
+            peer: None,
+            kind,
+            probability,
+        }
+    }
+
+    /// Restricts the rule to the requests of `method`.
+    pub fn with_method(mut self, method: impl Into<String>) -> Self {
+        self.method = Some(method.into());
+        self
+    }
+
+    /// Restricts the rule to the channels of `peer`.
+    pub fn with_peer(mut self, peer: impl Into<String>) -> Self {
+        self.peer = Some(peer.into());
+        self
+    }
+
+    fn matches(
+        &self,
+        method: Option<&str>,
+        peer_identity: Option<&PeerIdentity>,
+        peer_addr: Option<SocketAddr>,
+    ) -> bool {
+        let method_matches = match &self.method {
+            Some(rule_method) => method == Some(rule_method.as_str()),
+            None => true,
+        };
+        let peer_matches = match &self.peer {
+            Some(peer) => {
+                peer_identity.map_or(false, |identity| identity.has_name(peer))
+                    || peer_addr.map_or(false, |addr| addr.ip().to_string() == *peer)
+            }
+            None => true,
+        };
+        method_matches && peer_matches
+    }
+}
+
+/// The fault rules of a server, which channels apply to the requests they read. Cheap to clone;
+/// clones share their rules, so rules can be changed while the server runs, e.g. through the
+/// [`Admin`](crate::admin::Admin) service.
+///
+/// Channels apply the rules set in [`Config::faults`](crate::server::Config::faults). The first
+/// rule matching a request decides whether it fails, so a rule can be overridden for some methods
+/// or peers by a more specific rule placed before it. Failed requests are recorded as
+/// [injected](crate::server::rejections::RejectReason::Injected) rejections.
+#[derive(Clone, Default)]
+pub struct FaultInjector {
+    rules: Arc<RwLock<Vec<FaultRule>>>,
+}
+
+impl FaultInjector {
+    /// Returns an injector with no rules, which fails no requests.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the rules of the injector.
+    pub fn set_rules(&self, rules: Vec<FaultRule>) {
+        *self.rules.write().unwrap_or_else(PoisonError::into_inner) = rules;
+    }
+
+    /// Returns the rules of the injector, in the order they are matched.
+    pub fn rules(&self) -> Vec<FaultRule> {
+        self.rules
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Returns the error a request to `method` from the given peer fails with, if the first rule
+    /// matching the request says it fails.
+    pub fn inject(
+        &self,
+        method: Option<&str>,
+        peer_identity: Option<&PeerIdentity>,
+        peer_addr: Option<SocketAddr>,
+    ) -> Option<ServerError> {
+        let rules = self.rules.read().unwrap_or_else(PoisonError::into_inner);
+        let rule = rules
+            .iter()
+            .find(|rule| rule.matches(method, peer_identity, peer_addr))?;
+        // Unlike `gen_bool`, a comparison doesn't panic on probabilities out of range.
+        if rand::thread_rng().gen::<f64>() >= rule.probability {
+            return None;
+        }
+        Some(ServerError::new(
+            rule.kind,
+            format!(
+                "injected fault for method {}",
+                method.unwrap_or("<unknown>")
+            ),
+        ))
+    }
+}
+
+impl fmt::Debug for FaultInjector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FaultInjector")
+            .field("rules", &self.rules())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let faults = FaultInjector::new();
+        faults.set_rules(vec![
+            FaultRule::new(io::ErrorKind::Other, 0.0).with_method("World.hello"),
+            FaultRule::new(io::ErrorKind::ConnectionRefused, 1.0),
+        ]);
+
+        assert_eq!(faults.inject(Some("World.hello"), None, None), None);
+        let error = faults.inject(Some("World.bye"), None, None).unwrap();
+        assert_eq!(error.kind, io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn peer_matches_identity_names_and_addresses() {
+        let faults = FaultInjector::new();
+        faults.set_rules(vec![
+            FaultRule::new(io::ErrorKind::Other, 1.0).with_peer("canary"),
+            FaultRule::new(io::ErrorKind::Other, 1.0).with_peer("10.0.0.1"),
+        ]);
+        let canary = PeerIdentity {
+            common_name: Some("canary".into()),
+            ..PeerIdentity::default()
+        };
+        let other = PeerIdentity {
+            common_name: Some("other".into()),
+            ..PeerIdentity::default()
+        };
+
+        assert!(faults.inject(None, Some(&canary), None).is_some());
+        assert!(faults.inject(None, Some(&other), None).is_none());
+        assert!(faults
+            .inject(None, None, Some("10.0.0.1:8080".parse().unwrap()))
+            .is_some());
+        assert!(faults
+            .inject(None, None, Some("10.0.0.2:8080".parse().unwrap()))
+            .is_none());
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use futures::{prelude::*, task::*};
+use pin_project::pin_project;
+use std::{cell::RefCell, net::IpAddr, pin::Pin, sync::Arc};
+
+/// The verified identity of a client, such as the subject and subject alternative names of the
+/// certificate it authenticated with over mutual TLS.
+///
+/// A channel is [given](crate::server::BaseChannel::with_peer_identity) the identity of its
+/// client once, when the connection is established. Handlers of the channel's requests read it
+/// from [`PeerIdentity::current`], e.g. to authorize requests by certificate.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// The common name of the subject.
+    pub common_name: Option<String>,
+    /// The DNS names among the subject alternative names.
+    pub dns_names: Vec<String>,
+    /// The IP addresses among the subject alternative names.
+    pub ip_addrs: Vec<IpAddr>,
+    /// The URIs among the subject alternative names, e.g. SPIFFE IDs.
+    pub uris: Vec<String>,
+    /// The email addresses among the subject alternative names.
+    pub emails: Vec<String>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<PeerIdentity>>> = const { RefCell::new(None) };
+}
+
+impl PeerIdentity {
+    /// Returns the identity of the client of the request whose handler is being polled on the
+    /// current thread, if the request's channel was given one. Handlers should call this before
+    /// awaiting anything, e.g. at the top of an `async fn`.
+    pub fn current() -> Option<Arc<Self>> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// Returns true if `name` is the common name or one of the DNS names of the identity.
+    pub fn has_name(&self, name: &str) -> bool {
+        self.common_name.as_deref() == Some(name) || self.dns_names.iter().any(|n| n == name)
+    }
+
+    /// Reads the identity from the subject and subject alternative names of a DER-encoded X.509
+    /// certificate. The certificate is expected to have been verified already, e.g. by the TLS
+    /// handshake that received it.
+    #[cfg(any(feature = "tls", feature = "native-tls"))]
+    #[cfg_attr(docsrs, doc(cfg(any(feature = "tls", feature = "native-tls"))))]
+    pub fn from_der(certificate: &[u8]) -> std::io::Result<Self> {
+        x509::parse(certificate).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid certificate: {}", e),
+            )
+        })
+    }
+}
+
+/// Parses the few fields of X.509 certificates ([RFC 5280]) that identify the subject.
+///
+/// [RFC 5280]: https://www.rfc-editor.org/rfc/rfc5280#section-4.1
+#[cfg(any(feature = "tls", feature = "native-tls"))]
+mod x509 {
+    use super::PeerIdentity;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use yasna::{models::ObjectIdentifier, ASN1Error, ASN1ErrorKind, ASN1Result, BERReader, Tag};
+
+    const COMMON_NAME: &[u64] = &[2, 5, 4, 3];
+    const SUBJECT_ALT_NAME: &[u64] = &[2, 5, 29, 17];
+
+    pub(super) fn parse(certificate: &[u8]) -> ASN1Result<PeerIdentity> {
+        yasna::parse_der(certificate, |r| {
+            r.read_sequence(|certificate| {
+                let identity = certificate.next().read_sequence(|tbs| {
+                    // version
+                    tbs.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_der()))?;
+                    // serialNumber, signature, issuer, validity
+                    for _ in 0..4 {
+                        tbs.next().read_der()?;
+                    }
+                    let mut identity = PeerIdentity {
+                        common_name: read_common_name(tbs.next())?,
+                        ..PeerIdentity::default()
+                    };
+                    // subjectPublicKeyInfo
+                    tbs.next().read_der()?;
+                    // issuerUniqueID, subjectUniqueID, extensions
+                    while let Some(field) = tbs.read_optional(|r| r.read_tagged_der())? {
+                        if field.tag() == Tag::context(3) {
+                            read_extensions(field.value(), &mut identity)?;
+                        }
+                    }
+                    Ok(identity)
+                })?;
+                // signatureAlgorithm, signatureValue
+                certificate.next().read_der()?;
+                certificate.next().read_der()?;
+                Ok(identity)
+            })
+        })
+    }
+
+    /// Reads the first common name of a distinguished name.
+    fn read_common_name(r: BERReader) -> ASN1Result<Option<String>> {
+        let mut common_name = None;
+        r.read_sequence_of(|r| {
+            r.read_set_of(|r| {
+                r.read_sequence(|attribute| {
+                    let oid = attribute.next().read_oid()?;
+                    let value = attribute.next().read_tagged_der()?;
+                    if common_name.is_none() && is(&oid, COMMON_NAME) {
+                        common_name = Some(
+                            value
+                                .as_str()
+                                .ok_or_else(|| ASN1Error::new(ASN1ErrorKind::Invalid))?
+                                .to_string(),
+                        );
+                    }
+                    Ok(())
+                })
+            })
+        })?;
+        Ok(common_name)
+    }
+
+    fn read_extensions(extensions: &[u8], identity: &mut PeerIdentity) -> ASN1Result<()> {
+        yasna::parse_der(extensions, |r| {
+            r.read_sequence_of(|r| {
+                r.read_sequence(|extension| {
+                    let oid = extension.next().read_oid()?;
+                    // critical
+                    extension.read_optional(|r| r.read_bool())?;
+                    let value = extension.next().read_bytes()?;
+                    if is(&oid, SUBJECT_ALT_NAME) {
+                        read_subject_alt_names(&value, identity)?;
+                    }
+                    Ok(())
+                })
+            })
+        })
+    }
+
+    fn read_subject_alt_names(names: &[u8], identity: &mut PeerIdentity) -> ASN1Result<()> {
+        yasna::parse_der(names, |r| {
+            r.read_sequence_of(|r| {
+                let name = r.read_tagged_der()?;
+                let value = name.value();
+                let text = || {
+                    String::from_utf8(value.to_vec())
+                        .map_err(|_| ASN1Error::new(ASN1ErrorKind::Invalid))
+                };
+                match name.tag() {
+                    tag if tag == Tag::context(1) => identity.emails.push(text()?),
+                    tag if tag == Tag::context(2) => identity.dns_names.push(text()?),
+                    tag if tag == Tag::context(6) => identity.uris.push(text()?),
+                    tag if tag == Tag::context(7) => {
+                        let ip = if let Ok(octets) = <[u8; 4]>::try_from(value) {
+                            Ipv4Addr::from(octets).into()
+                        } else if let Ok(octets) = <[u8; 16]>::try_from(value) {
+                            Ipv6Addr::from(octets).into()
+                        } else {
+                            return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+                        };
+                        identity.ip_addrs.push(ip);
+                    }
+                    // Other names, e.g. directory names, don't identify the client.
+                    _ => {}
+                }
+                Ok(())
+            })
+        })
+    }
+
+    fn is(oid: &ObjectIdentifier, components: &[u64]) -> bool {
+        oid.components().as_slice() == components
+    }
+}
+
+/// A future that makes a [`PeerIdentity`] current while it is polled.
+#[pin_project]
+#[derive(Debug)]
+pub(crate) struct Scoped<F> {
+    #[pin]
+    inner: F,
+    identity: Option<Arc<PeerIdentity>>,
+}
+
+impl<F> Scoped<F> {
+    pub fn new(inner: F, identity: Option<Arc<PeerIdentity>>) -> Self {
+        Self { inner, identity }
+    }
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.project();
+        let _guard = ScopeGuard(CURRENT.with(|current| current.replace(this.identity.clone())));
+        this.inner.poll(cx)
+    }
+}
+
+/// Restores the identity of an enclosing scope, even when the handler unwinds.
+struct ScopeGuard(Option<Arc<PeerIdentity>>);
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use futures::future::poll_fn;
+    use futures_test::task::noop_context;
+
+    #[test]
+    fn current_identity_is_set_only_while_polled() {
+        let identity = Arc::new(PeerIdentity {
+            common_name: Some("client".into()),
+            ..Default::default()
+        });
+        let mut scoped = Box::pin(Scoped::new(
+            poll_fn(|_| Poll::Ready(PeerIdentity::current())),
+            Some(identity.clone()),
+        ));
+        assert_eq!(PeerIdentity::current(), None);
+        assert_matches!(
+            scoped.as_mut().poll(&mut noop_context()),
+            Poll::Ready(Some(current)) if current == identity
+        );
+        assert_eq!(PeerIdentity::current(), None);
+    }
+
+    #[test]
+    fn has_name_matches_common_name_and_dns_names() {
+        let identity = PeerIdentity {
+            common_name: Some("client".into()),
+            dns_names: vec!["client.example.com".into()],
+            ..Default::default()
+        };
+        assert!(identity.has_name("client"));
+        assert!(identity.has_name("client.example.com"));
+        assert!(!identity.has_name("server.example.com"));
+    }
+
+    #[cfg(any(feature = "tls", feature = "native-tls"))]
+    #[test]
+    fn from_der_reads_subject_and_subject_alt_names() {
+        use rcgen::{Certificate, CertificateParams, DnType, SanType};
+
+        let mut params = CertificateParams::new(vec!["client.example.com".into()]);
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "tarpc");
+        params.distinguished_name.push(DnType::CommonName, "client");
+        params.subject_alt_names.extend([
+            SanType::IpAddress([10, 0, 0, 1].into()),
+            SanType::URI("spiffe://example.com/client".into()),
+            SanType::Rfc822Name("client@example.com".into()),
+        ]);
+        let certificate = Certificate::from_params(params).unwrap();
+
+        let identity = PeerIdentity::from_der(&certificate.serialize_der().unwrap()).unwrap();
+        assert_eq!(
+            identity,
+            PeerIdentity {
+                common_name: Some("client".into()),
+                dns_names: vec!["client.example.com".into()],
+                ip_addrs: vec![[10, 0, 0, 1].into()],
+                uris: vec!["spiffe://example.com/client".into()],
+                emails: vec!["client@example.com".into()],
+            }
+        );
+        assert_matches!(PeerIdentity::from_der(b"not a certificate"), Err(_));
+    }
+}
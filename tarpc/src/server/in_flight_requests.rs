@@ -1,11 +1,17 @@
-use crate::util::{Compact, TimeUntil};
-use fnv::FnvHashMap;
+use super::{Cancellation, StreamCredits};
+use crate::{
+    trace::TraceId,
+    util::{Compact, TimeUntil},
+};
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::future::{AbortHandle, AbortRegistration};
+use futures::ready;
 use std::{
-    collections::hash_map,
+    collections::{hash_map, VecDeque},
     task::{Context, Poll},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
+use tokio::time::Instant;
 use tokio_util::time::delay_queue::{self, DelayQueue};
 use tracing::Span;
 
@@ -15,6 +21,37 @@ use tracing::Span;
 pub struct InFlightRequests {
     request_data: FnvHashMap<u64, RequestData>,
     deadlines: DelayQueue<u64>,
+    /// The requests most recently completed, to tell responses sent twice apart from responses
+    /// to requests that were canceled.
+    completed: RecentlyCompleted,
+}
+
+/// The IDs of the last [`RecentlyCompleted::CAPACITY`] requests completed.
+#[derive(Debug, Default)]
+struct RecentlyCompleted {
+    ids: FnvHashSet<u64>,
+    order: VecDeque<u64>,
+}
+
+impl RecentlyCompleted {
+    const CAPACITY: usize = 1024;
+
+    fn insert(&mut self, request_id: u64) {
+        if self.order.len() == Self::CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        if self.ids.insert(request_id) {
+            self.order.push_back(request_id);
+        }
+    }
+
+    fn remove(&mut self, request_id: u64) {
+        if self.ids.remove(&request_id) {
+            self.order.retain(|&id| id != request_id);
+        }
+    }
 }
 
 /// Data needed to clean up a single in-flight request.
@@ -22,8 +59,19 @@ pub struct InFlightRequests {
 struct RequestData {
     /// Aborts the response handler for the associated request.
     abort_handle: AbortHandle,
+    /// The trace the request belongs to.
+    trace_id: TraceId,
+    /// When the request expires; starts as the context deadline, and is extended by keep-alives.
+    deadline: Instant,
+    /// How far each keep-alive extends the deadline, if the client consented to keep-alives.
+    keep_alive: Option<Duration>,
     /// The key to remove the timer for the request's deadline.
     deadline_key: delay_queue::Key,
+    /// Replenished when the client consumes items of a server-streaming response.
+    stream_credits: StreamCredits,
+    /// Triggered when the deadline expires, if the handler is given notice before it's aborted,
+    /// or when the request is canceled cooperatively.
+    cancellation: Cancellation,
     /// The client span.
     span: Span,
 }
@@ -40,20 +88,33 @@ impl InFlightRequests {
     }
 
     /// Starts a request, unless a request with the same ID is already in flight.
+    #[allow(clippy::too_many_arguments)]
     pub fn start_request(
         &mut self,
         request_id: u64,
+        trace_id: TraceId,
         deadline: SystemTime,
+        keep_alive: Option<Duration>,
+        stream_credits: StreamCredits,
+        cancellation: Cancellation,
         span: Span,
     ) -> Result<AbortRegistration, AlreadyExistsError> {
         match self.request_data.entry(request_id) {
             hash_map::Entry::Vacant(vacant) => {
+                // A late response to the completed request would now be taken as a response to
+                // this one, so the completed request is forgotten.
+                self.completed.remove(request_id);
                 let timeout = deadline.time_until();
                 let (abort_handle, abort_registration) = AbortHandle::new_pair();
                 let deadline_key = self.deadlines.insert(request_id, timeout);
                 vacant.insert(RequestData {
                     abort_handle,
+                    trace_id,
+                    deadline: Instant::now() + timeout,
+                    keep_alive,
                     deadline_key,
+                    stream_credits,
+                    cancellation,
                     span,
                 });
                 Ok(abort_registration)
@@ -68,6 +129,7 @@ impl InFlightRequests {
             span,
             abort_handle,
             deadline_key,
+            ..
         }) = self.request_data.remove(&request_id)
         {
             let _entered = span.enter();
@@ -81,10 +143,106 @@ impl InFlightRequests {
         }
     }
 
+    /// Triggers the cancellation of an in-flight request, leaving its handler to return on its
+    /// own. The request stays in flight until it's responded to or its deadline expires. Returns
+    /// true iff the request was found.
+    pub fn notify_cancel(&mut self, request_id: u64) -> bool {
+        match self.request_data.get(&request_id) {
+            Some(request_data) => {
+                let _entered = request_data.span.enter();
+                request_data.cancellation.cancel();
+                tracing::info!("ReceiveCancel");
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancels the in-flight requests belonging to a trace, either by aborting their handlers,
+    /// or, if `cooperative`, by [notifying](Self::notify_cancel) them. Returns the IDs of the
+    /// requests canceled. Requests without a trace are never canceled by trace.
+    pub fn cancel_trace(&mut self, trace_id: TraceId, cooperative: bool) -> Vec<u64> {
+        if trace_id.is_none() {
+            return vec![];
+        }
+        let request_ids: Vec<u64> = self
+            .request_data
+            .iter()
+            .filter(|(_, request_data)| request_data.trace_id == trace_id)
+            .map(|(&request_id, _)| request_id)
+            .collect();
+        for &request_id in &request_ids {
+            if cooperative {
+                self.notify_cancel(request_id);
+            } else {
+                self.cancel_request(request_id);
+            }
+        }
+        request_ids
+    }
+
+    /// Aborts every in-flight request, e.g. because the channel is being torn down. Returns the
+    /// IDs of the requests aborted, in ascending order.
+    pub fn abort_all(&mut self) -> Vec<u64> {
+        let mut request_ids: Vec<u64> = self
+            .request_data
+            .drain()
+            .map(|(request_id, request_data)| {
+                let _entered = request_data.span.enter();
+                request_data.abort_handle.abort();
+                tracing::info!("AbortRequest");
+                request_id
+            })
+            .collect();
+        self.deadlines.clear();
+        request_ids.sort_unstable();
+        request_ids
+    }
+
+    /// Returns true iff the request is among the last requests removed by
+    /// [`remove_request`](Self::remove_request), i.e. it was responded to, and no request with the
+    /// same ID has started since.
+    pub fn completed_recently(&self, request_id: u64) -> bool {
+        self.completed.ids.contains(&request_id)
+    }
+
+    /// Returns the span of an in-flight request, if found.
+    pub fn span(&self, request_id: u64) -> Option<&Span> {
+        self.request_data
+            .get(&request_id)
+            .map(|request_data| &request_data.span)
+    }
+
+    /// Extends the deadline of a request whose client consented to keep-alives to `keep_alive`
+    /// from now, unless it is already later. Returns the span of the request, if found.
+    pub fn keep_alive(&mut self, request_id: u64) -> Option<&Span> {
+        let request_data = self.request_data.get_mut(&request_id)?;
+        if let Some(keep_alive) = request_data.keep_alive {
+            let deadline = Instant::now() + keep_alive;
+            if deadline > request_data.deadline {
+                request_data.deadline = deadline;
+                self.deadlines.reset(&request_data.deadline_key, keep_alive);
+            }
+        }
+        Some(&request_data.span)
+    }
+
+    /// Allows a server-streaming request to send `credits` more items. Returns true iff the
+    /// request was found.
+    pub fn grant_stream_credits(&mut self, request_id: u64, credits: usize) -> bool {
+        if let Some(request_data) = self.request_data.get(&request_id) {
+            request_data.stream_credits.grant(credits);
+            true
+        } else {
+            false
+        }
+    }
+
     /// Removes a request without aborting. Returns true iff the request was found.
     /// This method should be used when a response is being sent.
     pub fn remove_request(&mut self, request_id: u64) -> Option<Span> {
         if let Some(request_data) = self.request_data.remove(&request_id) {
+            self.completed.insert(request_id);
             self.request_data.compact(0.1);
             self.deadlines.remove(&request_data.deadline_key);
             Some(request_data.span)
@@ -93,26 +251,51 @@ impl InFlightRequests {
         }
     }
 
+    /// Returns the ID, current deadline, and keep-alive of each in-flight request.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, SystemTime, Option<Duration>)> + '_ {
+        let now = Instant::now();
+        let system_now = SystemTime::now();
+        self.request_data.iter().map(move |(&request_id, data)| {
+            let deadline = system_now + data.deadline.saturating_duration_since(now);
+            (request_id, deadline, data.keep_alive)
+        })
+    }
+
     /// Yields a request that has expired, aborting any ongoing processing of that request.
-    pub fn poll_expired(&mut self, cx: &mut Context) -> Poll<Option<u64>> {
-        if self.deadlines.is_empty() {
-            // TODO(https://github.com/tokio-rs/tokio/issues/4161)
-            // This is a workaround for DelayQueue not always treating this case correctly.
-            return Poll::Ready(None);
-        }
-        self.deadlines.poll_expired(cx).map(|expired| {
-            let expired = expired?;
-            if let Some(RequestData {
-                abort_handle, span, ..
-            }) = self.request_data.remove(expired.get_ref())
-            {
+    ///
+    /// If `notice` is nonzero, a request whose deadline expires first has its cancellation
+    /// triggered, and is aborted and yielded only once `notice` has passed, too.
+    pub fn poll_expired(&mut self, cx: &mut Context, notice: Duration) -> Poll<Option<u64>> {
+        loop {
+            if self.deadlines.is_empty() {
+                // TODO(https://github.com/tokio-rs/tokio/issues/4161)
+                // This is a workaround for DelayQueue not always treating this case correctly.
+                return Poll::Ready(None);
+            }
+            let expired = match ready!(self.deadlines.poll_expired(cx)) {
+                Some(expired) => expired.into_inner(),
+                None => return Poll::Ready(None),
+            };
+            if let hash_map::Entry::Occupied(mut entry) = self.request_data.entry(expired) {
+                let request_data = entry.get_mut();
+                if notice > Duration::ZERO && !request_data.cancellation.is_cancelled() {
+                    let _entered = request_data.span.enter();
+                    request_data.cancellation.cancel();
+                    request_data.deadline = Instant::now() + notice;
+                    request_data.deadline_key = self.deadlines.insert(expired, notice);
+                    tracing::warn!("DeadlineNotice");
+                    continue;
+                }
+                let RequestData {
+                    abort_handle, span, ..
+                } = entry.remove();
                 let _entered = span.enter();
                 self.request_data.compact(0.1);
                 abort_handle.abort();
                 tracing::error!("DeadlineExceeded");
             }
-            Some(expired.into_inner())
-        })
+            return Poll::Ready(Some(expired));
+        }
     }
 }
 
@@ -141,16 +324,78 @@ mod tests {
         let mut in_flight_requests = InFlightRequests::default();
         assert_eq!(in_flight_requests.len(), 0);
         in_flight_requests
-            .start_request(0, SystemTime::now(), Span::current())
+            .start_request(
+                0,
+                TraceId::default(),
+                SystemTime::now(),
+                None,
+                StreamCredits::default(),
+                Cancellation::default(),
+                Span::current(),
+            )
             .unwrap();
         assert_eq!(in_flight_requests.len(), 1);
     }
 
+    #[tokio::test]
+    async fn remove_request_remembers_completion() {
+        let mut in_flight_requests = InFlightRequests::default();
+        for request_id in 0..=RecentlyCompleted::CAPACITY as u64 {
+            in_flight_requests
+                .start_request(
+                    request_id,
+                    TraceId::default(),
+                    SystemTime::now() + Duration::from_secs(60),
+                    None,
+                    StreamCredits::default(),
+                    Cancellation::default(),
+                    Span::current(),
+                )
+                .unwrap();
+        }
+        assert!(in_flight_requests.cancel_request(0));
+        assert!(!in_flight_requests.completed_recently(0));
+        for request_id in 1..=RecentlyCompleted::CAPACITY as u64 {
+            assert!(in_flight_requests.remove_request(request_id).is_some());
+        }
+        assert!(in_flight_requests.completed_recently(1));
+
+        // Completions are forgotten when a request reuses the ID, and when they're old.
+        for request_id in [1, 2000, 2001] {
+            in_flight_requests
+                .start_request(
+                    request_id,
+                    TraceId::default(),
+                    SystemTime::now() + Duration::from_secs(60),
+                    None,
+                    StreamCredits::default(),
+                    Cancellation::default(),
+                    Span::current(),
+                )
+                .unwrap();
+        }
+        assert!(!in_flight_requests.completed_recently(1));
+        for request_id in [1, 2000, 2001] {
+            assert!(in_flight_requests.remove_request(request_id).is_some());
+        }
+        assert!(in_flight_requests.completed_recently(1));
+        assert!(!in_flight_requests.completed_recently(3));
+        assert!(in_flight_requests.completed_recently(4));
+    }
+
     #[tokio::test]
     async fn polling_expired_aborts() {
         let mut in_flight_requests = InFlightRequests::default();
         let abort_registration = in_flight_requests
-            .start_request(0, SystemTime::now(), Span::current())
+            .start_request(
+                0,
+                TraceId::default(),
+                SystemTime::now(),
+                None,
+                StreamCredits::default(),
+                Cancellation::default(),
+                Span::current(),
+            )
             .unwrap();
         let mut abortable_future = Box::new(Abortable::new(pending::<()>(), abort_registration));
 
@@ -158,7 +403,7 @@ mod tests {
         tokio::time::advance(std::time::Duration::from_secs(1000)).await;
 
         assert_matches!(
-            in_flight_requests.poll_expired(&mut noop_context()),
+            in_flight_requests.poll_expired(&mut noop_context(), Duration::ZERO),
             Poll::Ready(Some(_))
         );
         assert_matches!(
@@ -168,11 +413,49 @@ mod tests {
         assert_eq!(in_flight_requests.len(), 0);
     }
 
+    #[tokio::test]
+    async fn keep_alive_extends_deadline() {
+        tokio::time::pause();
+        let mut in_flight_requests = InFlightRequests::default();
+        in_flight_requests
+            .start_request(
+                0,
+                TraceId::default(),
+                SystemTime::now(),
+                Some(Duration::from_secs(10)),
+                StreamCredits::default(),
+                Cancellation::default(),
+                Span::current(),
+            )
+            .unwrap();
+        assert!(in_flight_requests.keep_alive(0).is_some());
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_matches!(
+            in_flight_requests.poll_expired(&mut noop_context(), Duration::ZERO),
+            Poll::Pending
+        );
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert_matches!(
+            in_flight_requests.poll_expired(&mut noop_context(), Duration::ZERO),
+            Poll::Ready(Some(0))
+        );
+        assert!(in_flight_requests.keep_alive(0).is_none());
+    }
+
     #[tokio::test]
     async fn cancel_request_aborts() {
         let mut in_flight_requests = InFlightRequests::default();
         let abort_registration = in_flight_requests
-            .start_request(0, SystemTime::now(), Span::current())
+            .start_request(
+                0,
+                TraceId::default(),
+                SystemTime::now(),
+                None,
+                StreamCredits::default(),
+                Cancellation::default(),
+                Span::current(),
+            )
             .unwrap();
         let mut abortable_future = Box::new(Abortable::new(pending::<()>(), abort_registration));
 
@@ -184,6 +467,81 @@ mod tests {
         assert_eq!(in_flight_requests.len(), 0);
     }
 
+    #[tokio::test]
+    async fn abort_all_aborts_every_request() {
+        let mut in_flight_requests = InFlightRequests::default();
+        let mut abortable_futures = Vec::new();
+        for request_id in [2, 0, 1] {
+            let abort_registration = in_flight_requests
+                .start_request(
+                    request_id,
+                    TraceId::default(),
+                    SystemTime::now() + Duration::from_secs(10),
+                    None,
+                    StreamCredits::default(),
+                    Cancellation::default(),
+                    Span::current(),
+                )
+                .unwrap();
+            abortable_futures.push(Box::new(Abortable::new(
+                pending::<()>(),
+                abort_registration,
+            )));
+        }
+
+        assert_eq!(in_flight_requests.abort_all(), [0, 1, 2]);
+        for abortable_future in &mut abortable_futures {
+            assert_matches!(
+                abortable_future.poll_unpin(&mut noop_context()),
+                Poll::Ready(Err(_))
+            );
+        }
+        assert_eq!(in_flight_requests.len(), 0);
+        assert!(in_flight_requests.deadlines.is_empty());
+    }
+
+    #[tokio::test]
+    async fn polling_expired_gives_notice_before_aborting() {
+        tokio::time::pause();
+        let mut in_flight_requests = InFlightRequests::default();
+        let cancellation = Cancellation::default();
+        let abort_registration = in_flight_requests
+            .start_request(
+                0,
+                TraceId::default(),
+                SystemTime::now(),
+                None,
+                StreamCredits::default(),
+                cancellation.clone(),
+                Span::current(),
+            )
+            .unwrap();
+        let mut abortable_future = Box::new(Abortable::new(pending::<()>(), abort_registration));
+
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_matches!(
+            in_flight_requests.poll_expired(&mut noop_context(), Duration::from_secs(1)),
+            Poll::Pending
+        );
+        assert!(cancellation.is_cancelled());
+        assert_matches!(
+            abortable_future.poll_unpin(&mut noop_context()),
+            Poll::Pending
+        );
+        assert_eq!(in_flight_requests.len(), 1);
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_matches!(
+            in_flight_requests.poll_expired(&mut noop_context(), Duration::from_secs(1)),
+            Poll::Ready(Some(0))
+        );
+        assert_matches!(
+            abortable_future.poll_unpin(&mut noop_context()),
+            Poll::Ready(Err(_))
+        );
+        assert_eq!(in_flight_requests.len(), 0);
+    }
+
     #[tokio::test]
     async fn remove_request_doesnt_abort() {
         let mut in_flight_requests = InFlightRequests::default();
@@ -192,7 +550,11 @@ mod tests {
         let abort_registration = in_flight_requests
             .start_request(
                 0,
+                TraceId::default(),
                 SystemTime::now() + std::time::Duration::from_secs(10),
+                None,
+                StreamCredits::default(),
+                Cancellation::default(),
                 Span::current(),
             )
             .unwrap();
@@ -200,7 +562,7 @@ mod tests {
 
         // Precondition: Pending expiration
         assert_matches!(
-            in_flight_requests.poll_expired(&mut noop_context()),
+            in_flight_requests.poll_expired(&mut noop_context(), Duration::ZERO),
             Poll::Pending
         );
         assert!(!in_flight_requests.deadlines.is_empty());
@@ -209,7 +571,7 @@ mod tests {
         // Postcondition: No pending expirations
         assert!(in_flight_requests.deadlines.is_empty());
         assert_matches!(
-            in_flight_requests.poll_expired(&mut noop_context()),
+            in_flight_requests.poll_expired(&mut noop_context(), Duration::ZERO),
             Poll::Ready(None)
         );
         assert_matches!(
@@ -218,4 +580,28 @@ mod tests {
         );
         assert_eq!(in_flight_requests.len(), 0);
     }
+
+    #[tokio::test]
+    async fn grant_stream_credits_replenishes_credits() {
+        let mut in_flight_requests = InFlightRequests::default();
+        let stream_credits = StreamCredits::new(0);
+        in_flight_requests
+            .start_request(
+                0,
+                TraceId::default(),
+                SystemTime::now(),
+                None,
+                stream_credits.clone(),
+                Cancellation::default(),
+                Span::current(),
+            )
+            .unwrap();
+
+        let mut acquire = Box::pin(stream_credits.acquire());
+        assert_matches!(acquire.poll_unpin(&mut noop_context()), Poll::Pending);
+
+        assert!(in_flight_requests.grant_stream_credits(0, 1));
+        assert_matches!(acquire.poll_unpin(&mut noop_context()), Poll::Ready(()));
+        assert!(!in_flight_requests.grant_stream_credits(1, 1));
+    }
 }
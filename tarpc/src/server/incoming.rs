@@ -1,9 +1,14 @@
 use super::{
-    limits::{channels_per_key::MaxChannelsPerKey, requests_per_channel::MaxRequestsPerChannel},
+    limits::{
+        channels_per_key::MaxChannelsPerKey,
+        peers::{FilterPeers, PeerPolicy},
+        requests_per_channel::MaxRequestsPerChannel,
+    },
+    registry::{Register, Registry},
     Channel,
 };
 use futures::prelude::*;
-use std::{fmt, hash::Hash};
+use std::{fmt, hash::Hash, net::IpAddr};
 
 #[cfg(feature = "tokio1")]
 use super::{tokio::TokioServerExecutor, Serve};
@@ -23,6 +28,24 @@ where
         MaxChannelsPerKey::new(self, n, keymaker)
     }
 
+    /// Drops new channels whose peers are not admitted by `policy`. `peer` returns the address of
+    /// a channel's peer; channels whose peer is unknown are dropped, too.
+    fn filter_peers<F>(self, policy: PeerPolicy, peer: F) -> FilterPeers<Self, F>
+    where
+        F: Fn(&C) -> Option<IpAddr>,
+    {
+        FilterPeers::new(self, policy, peer)
+    }
+
+    /// Registers each channel in `registry`, under the key returned by `keymaker`.
+    fn register<K, KF>(self, registry: Registry<K>, keymaker: KF) -> Register<Self, K, KF>
+    where
+        K: Eq + Hash + Clone,
+        KF: Fn(&C) -> K,
+    {
+        Register::new(self, registry, keymaker)
+    }
+
     /// Caps the number of concurrent requests per channel.
     fn max_concurrent_requests_per_channel(self, n: usize) -> MaxRequestsPerChannel<Self> {
         MaxRequestsPerChannel::new(self, n)
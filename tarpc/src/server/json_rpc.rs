@@ -0,0 +1,575 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A transport adaptor that serves tarpc services to [JSON-RPC 2.0](https://www.jsonrpc.org/specification)
+//! clients.
+//!
+//! [`JsonRpc`] wraps a [transport of frames](crate::transport::frames) whose frames are JSON-RPC
+//! request and response objects. It presents the frames as [`ClientMessage`]s and
+//! [`ServerMessage`]s of a service's generated request and response enums, so it can be served
+//! by a [`BaseChannel`](super::BaseChannel) like any other transport.
+//!
+//! A JSON-RPC method is the name of an rpc, e.g. `hello` for `async fn hello(name: String)`.
+//! Params are given either by name, e.g. `{"name": "Jim"}`, or by position, e.g. `["Jim"]`.
+//! The result of a successful call is the rpc's return value.
+//!
+//! Methods are looked up by the [`Methods`] impl that the `#[tarpc::service]` attribute generates
+//! for the request enum when the `json-rpc` feature is enabled and the service is
+//! `derive_serde`. Streaming rpcs and rpcs with `#[encrypted]` args can't be called over
+//! JSON-RPC. Batch requests aren't supported, and are answered with an
+//! [`INVALID_REQUEST`] error.
+//!
+//! Requests are handled with the default [`context`](crate::context::current), as JSON-RPC has
+//! no way to carry a deadline or a trace context.
+
+use crate::{context, ClientMessage, Request, ServerMessage};
+use bytes::{Bytes, BytesMut};
+use fnv::FnvHashMap;
+use futures::{prelude::*, ready};
+use pin_project::pin_project;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::VecDeque,
+    fmt,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The error code of a request that isn't valid JSON.
+pub const PARSE_ERROR: i64 = -32700;
+/// The error code of a request that isn't a valid JSON-RPC request object.
+pub const INVALID_REQUEST: i64 = -32600;
+/// The error code of a request for a method the service doesn't have.
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// The error code of a request whose params don't match the method's args.
+pub const INVALID_PARAMS: i64 = -32602;
+/// The error code of a response that couldn't be serialized.
+pub const INTERNAL_ERROR: i64 = -32603;
+/// The error code of a request that failed with a [`ServerError`](crate::ServerError), e.g.
+/// because its deadline expired. The error's `data` holds the
+/// [`ErrorKind`](std::io::ErrorKind) of the server error.
+pub const SERVER_ERROR: i64 = -32000;
+
+/// Maps JSON-RPC methods to the variants of a service's request enum.
+///
+/// Implemented by the `#[tarpc::service]` attribute; see the [module docs](self).
+pub trait Methods {
+    /// Returns the name of the request variant for `method`, and the names of its fields in the
+    /// order of the rpc's args, or `None` if the service has no such method.
+    fn json_rpc_method(method: &str) -> Option<(&'static str, &'static [&'static str])>;
+}
+
+/// A transport that speaks JSON-RPC 2.0. See the [module docs](self).
+#[pin_project]
+pub struct JsonRpc<T, Req, Resp> {
+    #[pin]
+    inner: T,
+    next_request_id: u64,
+    /// The JSON-RPC ids of in-flight requests, by request ID. Notifications have no id, and are
+    /// presented as one-way requests, which aren't responded to.
+    ids: FnvHashMap<u64, Value>,
+    /// Error responses written by the adaptor itself, for requests that never reach the server.
+    errors: VecDeque<Bytes>,
+    needs_flush: bool,
+    ghost: PhantomData<fn(Resp) -> Req>,
+}
+
+impl<T, Req, Resp> fmt::Debug for JsonRpc<T, Req, Resp>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonRpc")
+            .field("inner", &self.inner)
+            .field("in_flight_requests", &self.ids.len())
+            .field("queued_errors", &self.errors.len())
+            .finish()
+    }
+}
+
+impl<T, Req, Resp> JsonRpc<T, Req, Resp> {
+    /// Returns a transport that reads JSON-RPC requests from, and writes JSON-RPC responses to,
+    /// the frames of `inner`.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            next_request_id: 0,
+            ids: FnvHashMap::default(),
+            errors: VecDeque::new(),
+            needs_flush: false,
+            ghost: PhantomData,
+        }
+    }
+
+    /// Returns the inner transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+/// A JSON-RPC request that failed before reaching the server. `id` is `None` for notifications,
+/// which are never answered.
+struct Rejection {
+    id: Option<Value>,
+    code: i64,
+    message: String,
+}
+
+impl Rejection {
+    fn new(id: Option<Value>, code: i64, message: impl Into<String>) -> Self {
+        Self {
+            id,
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+fn error_response(id: Value, code: i64, message: String, data: Option<Value>) -> Bytes {
+    let mut error = json!({ "code": code, "message": message });
+    if let Some(data) = data {
+        error["data"] = data;
+    }
+    json!({ "jsonrpc": "2.0", "error": error, "id": id })
+        .to_string()
+        .into()
+}
+
+/// Parses a JSON-RPC request into its id and request enum.
+fn parse_request<Req>(frame: &[u8]) -> Result<(Option<Value>, Req), Rejection>
+where
+    Req: Methods + DeserializeOwned,
+{
+    let request: Value = serde_json::from_slice(frame)
+        .map_err(|e| Rejection::new(Some(Value::Null), PARSE_ERROR, e.to_string()))?;
+    let mut request = match request {
+        Value::Object(request) => request,
+        Value::Array(_) => {
+            return Err(Rejection::new(
+                Some(Value::Null),
+                INVALID_REQUEST,
+                "batch requests are not supported",
+            ))
+        }
+        _ => {
+            return Err(Rejection::new(
+                Some(Value::Null),
+                INVALID_REQUEST,
+                "request is not an object",
+            ))
+        }
+    };
+    let id = match request.remove("id") {
+        Some(id @ (Value::Null | Value::Number(_) | Value::String(_))) => Some(id),
+        Some(_) => {
+            return Err(Rejection::new(
+                Some(Value::Null),
+                INVALID_REQUEST,
+                "id must be a string, number, or null",
+            ))
+        }
+        None => None,
+    };
+    if request.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return Err(Rejection::new(
+            id.or(Some(Value::Null)),
+            INVALID_REQUEST,
+            "jsonrpc must be \"2.0\"",
+        ));
+    }
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method,
+        None => {
+            return Err(Rejection::new(
+                id.or(Some(Value::Null)),
+                INVALID_REQUEST,
+                "method must be a string",
+            ))
+        }
+    };
+    let (variant, fields) = match Req::json_rpc_method(method) {
+        Some(variant_and_fields) => variant_and_fields,
+        None => {
+            return Err(Rejection::new(
+                id,
+                METHOD_NOT_FOUND,
+                format!("method {method:?} not found"),
+            ))
+        }
+    };
+    let params = match request.remove("params") {
+        None => Map::new(),
+        Some(Value::Object(params)) => params,
+        Some(Value::Array(params)) if params.len() <= fields.len() => fields
+            .iter()
+            .map(|field| field.to_string())
+            .zip(params)
+            .collect(),
+        Some(Value::Array(params)) => {
+            return Err(Rejection::new(
+                id,
+                INVALID_PARAMS,
+                format!(
+                    "expected at most {} params, got {}",
+                    fields.len(),
+                    params.len()
+                ),
+            ))
+        }
+        Some(_) => {
+            return Err(Rejection::new(
+                id,
+                INVALID_PARAMS,
+                "params must be an object or an array",
+            ))
+        }
+    };
+    let message = serde_json::from_value(json!({ variant: params }))
+        .map_err(|e| Rejection::new(id.clone(), INVALID_PARAMS, e.to_string()))?;
+    Ok((id, message))
+}
+
+impl<T, Req, Resp, E> JsonRpc<T, Req, Resp>
+where
+    T: Sink<Bytes, Error = E>,
+{
+    /// Writes the queued error responses, and flushes them if the frames were written.
+    fn poll_write_errors(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        let mut this = self.project();
+        while !this.errors.is_empty() {
+            ready!(this.inner.as_mut().poll_ready(cx))?;
+            let error = this.errors.pop_front().unwrap();
+            this.inner.as_mut().start_send(error)?;
+            *this.needs_flush = true;
+        }
+        if *this.needs_flush {
+            ready!(this.inner.poll_flush(cx))?;
+            *this.needs_flush = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T, Req, Resp, E> Stream for JsonRpc<T, Req, Resp>
+where
+    T: Stream<Item = Result<BytesMut, E>> + Sink<Bytes, Error = E>,
+    Req: Methods + DeserializeOwned,
+{
+    type Item = Result<ClientMessage<Req>, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            // Pending is fine: the error responses are written when the sink is next ready.
+            if let Poll::Ready(Err(e)) = self.as_mut().poll_write_errors(cx) {
+                return Poll::Ready(Some(Err(e)));
+            }
+            let frame = match ready!(self.as_mut().project().inner.poll_next(cx)?) {
+                Some(frame) => frame,
+                None => return Poll::Ready(None),
+            };
+            let this = self.as_mut().project();
+            match parse_request(&frame) {
+                Ok((None, message)) => {
+                    return Poll::Ready(Some(Ok(ClientMessage::OneWay {
+                        context: context::current(),
+                        message,
+                    })));
+                }
+                Ok((Some(id), message)) => {
+                    let request_id = *this.next_request_id;
+                    *this.next_request_id += 1;
+                    this.ids.insert(request_id, id);
+                    return Poll::Ready(Some(Ok(ClientMessage::Request(Request {
+                        context: context::current(),
+                        id: request_id,
+                        message,
+                    }))));
+                }
+                Err(Rejection { id: None, .. }) => {
+                    tracing::debug!("DropNotification");
+                }
+                Err(Rejection {
+                    id: Some(id),
+                    code,
+                    message,
+                }) => {
+                    tracing::debug!(code, %message, "RejectRequest");
+                    this.errors
+                        .push_back(error_response(id, code, message, None));
+                }
+            }
+        }
+    }
+}
+
+impl<T, Req, Resp, E> Sink<ServerMessage<Resp>> for JsonRpc<T, Req, Resp>
+where
+    T: Sink<Bytes, Error = E>,
+    Resp: Serialize,
+{
+    type Error = E;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        ready!(self.as_mut().poll_write_errors(cx))?;
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: ServerMessage<Resp>) -> Result<(), E> {
+        let this = self.project();
+        // JSON-RPC has no equivalent of keep-alives or stream items, which are only sent for
+        // requests that can't be made over JSON-RPC anyway.
+        let response = match message {
+            ServerMessage::Response(response) => response,
+            _ => return Ok(()),
+        };
+        let id = match this.ids.remove(&response.request_id) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        let frame = match response.message {
+            Ok(message) => match serde_json::to_value(message) {
+                // The response enum serializes as `{"Variant": result}`.
+                Ok(Value::Object(message)) if message.len() == 1 => {
+                    let result = message.into_iter().next().unwrap().1;
+                    json!({ "jsonrpc": "2.0", "result": result, "id": id })
+                        .to_string()
+                        .into()
+                }
+                Ok(_) => error_response(
+                    id,
+                    INTERNAL_ERROR,
+                    "response is not a service response".into(),
+                    None,
+                ),
+                Err(e) => error_response(id, INTERNAL_ERROR, e.to_string(), None),
+            },
+            Err(e) => error_response(
+                id,
+                SERVER_ERROR,
+                e.detail,
+                Some(json!({ "kind": format!("{:?}", e.kind) })),
+            ),
+        };
+        this.inner.start_send(frame)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        ready!(self.as_mut().poll_write_errors(cx))?;
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        ready!(self.as_mut().poll_write_errors(cx))?;
+        self.project().inner.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transport::frames::framed, Response, ServerError};
+    use assert_matches::assert_matches;
+    use std::io;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    enum TestRequest {
+        Add { x: i32, y: Option<i32> },
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    enum TestResponse {
+        Add(i32),
+    }
+
+    impl Methods for TestRequest {
+        fn json_rpc_method(method: &str) -> Option<(&'static str, &'static [&'static str])> {
+            match method {
+                "add" => Some(("Add", &["x", "y"])),
+                _ => None,
+            }
+        }
+    }
+
+    type Frames = Framed<DuplexStream, LengthDelimitedCodec>;
+
+    fn transports() -> (Frames, JsonRpc<Frames, TestRequest, TestResponse>) {
+        let (a, b) = duplex(4096);
+        (framed(a), JsonRpc::new(framed(b)))
+    }
+
+    async fn send(client: &mut Frames, request: Value) -> io::Result<()> {
+        client.send(Bytes::from(request.to_string())).await
+    }
+
+    async fn recv(client: &mut Frames) -> io::Result<Value> {
+        let frame = client.next().await.unwrap()?;
+        Ok(serde_json::from_slice(&frame)?)
+    }
+
+    async fn request(
+        server: &mut JsonRpc<Frames, TestRequest, TestResponse>,
+    ) -> io::Result<Request<TestRequest>> {
+        match server.next().await.unwrap()? {
+            ClientMessage::Request(request) => Ok(request),
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn params_by_name_and_position() -> io::Result<()> {
+        let (mut client, mut server) = transports();
+
+        send(
+            &mut client,
+            json!({"jsonrpc": "2.0", "method": "add", "params": {"x": 1, "y": 2}, "id": "a"}),
+        )
+        .await?;
+        let a = request(&mut server).await?;
+        assert_matches!(a.message, TestRequest::Add { x: 1, y: Some(2) });
+
+        send(
+            &mut client,
+            json!({"jsonrpc": "2.0", "method": "add", "params": [3], "id": 7}),
+        )
+        .await?;
+        let b = request(&mut server).await?;
+        assert_matches!(b.message, TestRequest::Add { x: 3, y: None });
+
+        server
+            .send(ServerMessage::Response(Response {
+                request_id: b.id,
+                message: Ok(TestResponse::Add(3)),
+            }))
+            .await?;
+        server
+            .send(ServerMessage::Response(Response {
+                request_id: a.id,
+                message: Err(ServerError::new(
+                    io::ErrorKind::TimedOut,
+                    "deadline expired",
+                )),
+            }))
+            .await?;
+        assert_eq!(
+            recv(&mut client).await?,
+            json!({"jsonrpc": "2.0", "result": 3, "id": 7})
+        );
+        assert_eq!(
+            recv(&mut client).await?,
+            json!({
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": SERVER_ERROR,
+                    "message": "deadline expired",
+                    "data": {"kind": "TimedOut"},
+                },
+                "id": "a",
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notifications_are_not_answered() -> io::Result<()> {
+        let (mut client, mut server) = transports();
+
+        send(
+            &mut client,
+            json!({"jsonrpc": "2.0", "method": "subtract", "params": [1]}),
+        )
+        .await?;
+        send(
+            &mut client,
+            json!({"jsonrpc": "2.0", "method": "add", "params": [1, 2]}),
+        )
+        .await?;
+        assert_matches!(
+            server.next().await.unwrap()?,
+            ClientMessage::OneWay {
+                message: TestRequest::Add { x: 1, y: Some(2) },
+                ..
+            }
+        );
+
+        send(
+            &mut client,
+            json!({"jsonrpc": "2.0", "method": "add", "params": [2, 2], "id": 1}),
+        )
+        .await?;
+        let request = request(&mut server).await?;
+        server
+            .send(ServerMessage::Response(Response {
+                request_id: request.id,
+                message: Ok(TestResponse::Add(4)),
+            }))
+            .await?;
+        assert_eq!(
+            recv(&mut client).await?,
+            json!({"jsonrpc": "2.0", "result": 4, "id": 1})
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_requests() -> io::Result<()> {
+        let (mut client, mut server) = transports();
+
+        client.send(Bytes::from_static(b"{")).await?;
+        send(
+            &mut client,
+            json!([{"jsonrpc": "2.0", "method": "add", "id": 1}]),
+        )
+        .await?;
+        send(
+            &mut client,
+            json!({"jsonrpc": "1.0", "method": "add", "id": 2}),
+        )
+        .await?;
+        send(
+            &mut client,
+            json!({"jsonrpc": "2.0", "method": "sub", "id": 3}),
+        )
+        .await?;
+        send(
+            &mut client,
+            json!({"jsonrpc": "2.0", "method": "add", "params": [1, 2, 3], "id": 4}),
+        )
+        .await?;
+        send(
+            &mut client,
+            json!({"jsonrpc": "2.0", "method": "add", "params": {"y": 1}, "id": 5}),
+        )
+        .await?;
+
+        // Reading past the invalid requests writes their error responses.
+        tokio::select! {
+            _ = server.next() => panic!("no valid requests were sent"),
+            _ = tokio::time::sleep(std::time::Duration::from_millis(100)) => {}
+        }
+
+        let mut codes = vec![];
+        for _ in 0..6 {
+            let response = recv(&mut client).await?;
+            codes.push((response["id"].clone(), response["error"]["code"].clone()));
+        }
+        assert_eq!(
+            codes,
+            [
+                (Value::Null, json!(PARSE_ERROR)),
+                (Value::Null, json!(INVALID_REQUEST)),
+                (json!(2), json!(INVALID_REQUEST)),
+                (json!(3), json!(METHOD_NOT_FOUND)),
+                (json!(4), json!(INVALID_PARAMS)),
+                (json!(5), json!(INVALID_PARAMS)),
+            ]
+        );
+        Ok(())
+    }
+}
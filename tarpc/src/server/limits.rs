@@ -3,3 +3,13 @@ pub mod channels_per_key;
 
 /// Provides a [channel](crate::server::Channel) that limits the number of in-flight requests.
 pub mod requests_per_channel;
+
+/// Provides functionality to reject channels by the address of their peer.
+pub mod peers;
+
+/// Provides a [channel](crate::server::Channel) that rejects requests advised to be too large.
+pub mod request_size;
+
+/// Provides a [channel](crate::server::Channel) that replaces responses that are too large with an
+/// error.
+pub mod response_size;
@@ -5,7 +5,8 @@
 // https://opensource.org/licenses/MIT.
 
 use crate::{
-    server::{self, Channel},
+    capabilities::Capabilities,
+    server::{self, Channel, ConfigHandle},
     util::Compact,
 };
 use fnv::FnvHashMap;
@@ -32,6 +33,7 @@ where
     #[pin]
     listener: Fuse<S>,
     channels_per_key: u32,
+    config_handle: Option<ConfigHandle>,
     dropped_keys: mpsc::UnboundedReceiver<K>,
     dropped_keys_tx: mpsc::UnboundedSender<K>,
     key_counts: FnvHashMap<K, Weak<Tracker<K>>>,
@@ -116,6 +118,14 @@ where
         self.inner.in_flight_requests()
     }
 
+    fn peer_capabilities(&self) -> Option<Capabilities> {
+        self.inner.peer_capabilities()
+    }
+
+    fn abort_in_flight_requests(self: Pin<&mut Self>) -> Vec<u64> {
+        self.project().inner.abort_in_flight_requests()
+    }
+
     fn transport(&self) -> &Self::Transport {
         self.inner.transport()
     }
@@ -145,12 +155,29 @@ where
         MaxChannelsPerKey {
             listener: listener.fuse(),
             channels_per_key,
+            config_handle: None,
             dropped_keys,
             dropped_keys_tx,
             key_counts: FnvHashMap::default(),
             keymaker,
         }
     }
+
+    /// Reads the limit from `config_handle` when it sets
+    /// [`max_channels_per_key`](server::Tunables::max_channels_per_key), so that it can be updated while
+    /// the listener is running.
+    pub fn with_config_handle(mut self, config_handle: ConfigHandle) -> Self {
+        self.config_handle = Some(config_handle);
+        self
+    }
+
+    /// Returns the limit, as overridden by the config handle, if any.
+    fn channels_per_key(&self) -> u32 {
+        self.config_handle
+            .as_ref()
+            .and_then(|handle| handle.tunables().max_channels_per_key)
+            .unwrap_or(self.channels_per_key)
+    }
 }
 
 impl<S, K, F> MaxChannelsPerKey<S, K, F>
@@ -173,7 +200,7 @@ where
         trace!(
             channel_filter_key = %key,
             open_channels = Arc::strong_count(&tracker),
-            max_open_channels = self.channels_per_key,
+            max_open_channels = self.channels_per_key(),
             "Opening channel");
 
         Ok(TrackedChannel {
@@ -183,6 +210,7 @@ where
     }
 
     fn increment_channels_for_key(self: Pin<&mut Self>, key: K) -> Result<Arc<Tracker<K>>, K> {
+        let channels_per_key = self.channels_per_key();
         let self_ = self.project();
         let dropped_keys = self_.dropped_keys_tx;
         match self_.key_counts.entry(key.clone()) {
@@ -197,11 +225,11 @@ where
             }
             Entry::Occupied(mut o) => {
                 let count = o.get().strong_count();
-                if count >= TryFrom::try_from(*self_.channels_per_key).unwrap() {
+                if count >= TryFrom::try_from(channels_per_key).unwrap() {
                     info!(
                         channel_filter_key = %key,
                         open_channels = count,
-                        max_open_channels = *self_.channels_per_key,
+                        max_open_channels = channels_per_key,
                         "At open channel limit");
                     Err(key)
                 } else {
@@ -336,7 +364,7 @@ fn tracked_channel_sink() {
     assert_matches!(channel.as_mut().poll_ready(&mut ctx()), Poll::Ready(Ok(())));
     assert_matches!(channel.as_mut().start_send("test"), Ok(()));
     assert_matches!(channel.as_mut().poll_flush(&mut ctx()), Poll::Ready(Ok(())));
-    assert_matches!(chan_rx.try_next(), Ok(Some("test")));
+    assert_matches!(chan_rx.try_recv(), Ok("test"));
 }
 
 #[test]
@@ -359,6 +387,24 @@ fn channel_filter_increment_channels_for_key() {
     assert_eq!(Arc::strong_count(&tracker1), 1);
 }
 
+#[test]
+fn channel_filter_increment_channels_for_key_uses_config_handle_limit() {
+    use assert_matches::assert_matches;
+    use pin_utils::pin_mut;
+
+    struct TestChannel {
+        key: &'static str,
+    }
+    let config_handle = ConfigHandle::new();
+    let (_, listener) = futures::channel::mpsc::unbounded();
+    let filter = MaxChannelsPerKey::new(listener, 2, |chan: &TestChannel| chan.key)
+        .with_config_handle(config_handle.clone());
+    pin_mut!(filter);
+    let _tracker = filter.as_mut().increment_channels_for_key("key").unwrap();
+    config_handle.update(|tunables| tunables.max_channels_per_key = Some(1));
+    assert_matches!(filter.increment_channels_for_key("key"), Err("key"));
+}
+
 #[test]
 fn channel_filter_handle_new_channel() {
     use assert_matches::assert_matches;
@@ -0,0 +1,397 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::server::rejections::{self, RejectReason, RejectionLog};
+use futures::{prelude::*, ready, task::*};
+use pin_project::pin_project;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+};
+use tracing::{info, trace};
+
+/// A block of IP addresses in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`.
+///
+/// IPv4-mapped IPv6 addresses, e.g. `::ffff:10.0.0.1`, are treated as the IPv4 addresses they
+/// map to, so that IPv4 blocks match peers of dual-stack listeners.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+/// The error returned when a [`Cidr`] is malformed.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("invalid CIDR block: {0}")]
+pub struct InvalidCidr(String);
+
+impl Cidr {
+    /// Returns the block of addresses whose first `prefix_len` bits equal those of `addr`.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, InvalidCidr> {
+        let addr = canonical(addr);
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return Err(InvalidCidr(format!("{}/{}", addr, prefix_len)));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    /// Returns true if `addr` is in the block.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, canonical(addr)) {
+            (IpAddr::V4(block), IpAddr::V4(addr)) => prefix_eq(
+                u32::from(block).into(),
+                u32::from(addr).into(),
+                self.prefix_len,
+                32,
+            ),
+            (IpAddr::V6(block), IpAddr::V6(addr)) => {
+                prefix_eq(block.into(), addr.into(), self.prefix_len, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for Cidr {
+    /// Returns the block containing only `addr`.
+    fn from(addr: IpAddr) -> Self {
+        let addr = canonical(addr);
+        let prefix_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        Self { addr, prefix_len }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    /// Parses `addr/prefix_len`, or a bare address as a block of one.
+    fn from_str(s: &str) -> Result<Self, InvalidCidr> {
+        let invalid = || InvalidCidr(s.into());
+        match s.split_once('/') {
+            Some((addr, prefix_len)) => Cidr::new(
+                addr.parse().map_err(|_| invalid())?,
+                prefix_len.parse().map_err(|_| invalid())?,
+            ),
+            None => Ok(Cidr::from(s.parse::<IpAddr>().map_err(|_| invalid())?)),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Returns the IPv4 address an IPv4-mapped IPv6 address maps to, or else `addr`.
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+            }
+            _ => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+fn prefix_eq(a: u128, b: u128, prefix_len: u8, bits: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = u32::from(bits - prefix_len);
+    a >> shift == b >> shift
+}
+
+/// Decides which peers may open channels.
+///
+/// A peer is rejected if it is in any [denied](Self::deny) block. Otherwise, if any blocks are
+/// [allowed](Self::allow), the peer must be in one of them, and if a [callback](Self::allow_if)
+/// is set, it must return true for the peer. The default policy admits every peer.
+#[derive(Clone, Default)]
+pub struct PeerPolicy {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    callback: Option<Arc<dyn Fn(IpAddr) -> bool + Send + Sync>>,
+    rejections: Option<RejectionLog>,
+}
+
+impl fmt::Debug for PeerPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeerPolicy")
+            .field("allow", &self.allow)
+            .field("deny", &self.deny)
+            .field("callback", &self.callback.as_ref().map(|_| ".."))
+            .field("rejections", &self.rejections)
+            .finish()
+    }
+}
+
+impl PeerPolicy {
+    /// Admits peers in `block`, and no longer admits peers outside of all allowed blocks.
+    pub fn allow(mut self, block: Cidr) -> Self {
+        self.allow.push(block);
+        self
+    }
+
+    /// Rejects peers in `block`, even if they are also in an allowed block.
+    pub fn deny(mut self, block: Cidr) -> Self {
+        self.deny.push(block);
+        self
+    }
+
+    /// Admits only peers for which `callback` returns true, e.g. to consult a list that changes
+    /// while the server is running. The callback is called after the allow and deny lists are
+    /// checked, and only for peers they admit.
+    pub fn allow_if<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(IpAddr) -> bool + Send + Sync + 'static,
+    {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Records the channels the policy rejects in `log`.
+    pub fn record_rejections(mut self, log: RejectionLog) -> Self {
+        self.rejections = Some(log);
+        self
+    }
+
+    /// Returns why `peer` is rejected, or `Ok` if it is admitted.
+    fn check(&self, peer: IpAddr) -> Result<(), Rejection> {
+        if self.deny.iter().any(|block| block.contains(peer)) {
+            return Err(Rejection::Denied);
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|block| block.contains(peer)) {
+            return Err(Rejection::NotAllowed);
+        }
+        match &self.callback {
+            Some(callback) if !callback(peer) => Err(Rejection::Callback),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Why a [`FilterPeers`] stream rejected a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Rejection {
+    UnknownPeer,
+    Denied,
+    NotAllowed,
+    Callback,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rejection::UnknownPeer => "peer address unknown",
+            Rejection::Denied => "peer denied",
+            Rejection::NotAllowed => "peer not allowed",
+            Rejection::Callback => "peer rejected by callback",
+        })
+    }
+}
+
+/// An [`Incoming`](crate::server::incoming::Incoming) stream that drops new channels whose peers
+/// are not admitted by a [`PeerPolicy`].
+///
+/// The decision is made when the channel materializes, before any of its frames are read. Since
+/// it is made by peer address alone, it's no substitute for authentication, but it sheds obviously
+/// unauthorized peers cheaply.
+#[pin_project]
+#[derive(Debug)]
+pub struct FilterPeers<S, F> {
+    #[pin]
+    listener: S,
+    policy: PeerPolicy,
+    peer: F,
+    admitted: u64,
+    rejected: u64,
+}
+
+impl<S, F> FilterPeers<S, F>
+where
+    S: Stream,
+    F: Fn(&S::Item) -> Option<IpAddr>,
+{
+    pub(crate) fn new(listener: S, policy: PeerPolicy, peer: F) -> Self {
+        Self {
+            listener,
+            policy,
+            peer,
+            admitted: 0,
+            rejected: 0,
+        }
+    }
+}
+
+impl<S, F> FilterPeers<S, F> {
+    /// Returns the number of channels admitted so far.
+    pub fn admitted(&self) -> u64 {
+        self.admitted
+    }
+
+    /// Returns the number of channels rejected so far.
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+}
+
+impl<S, F> Stream for FilterPeers<S, F>
+where
+    S: Stream,
+    F: Fn(&S::Item) -> Option<IpAddr>,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let mut this = self.project();
+        loop {
+            let channel = match ready!(this.listener.as_mut().poll_next(cx)) {
+                Some(channel) => channel,
+                None => return Poll::Ready(None),
+            };
+            let peer = (this.peer)(&channel);
+            match peer.map_or(Err(Rejection::UnknownPeer), |peer| this.policy.check(peer)) {
+                Ok(()) => {
+                    *this.admitted += 1;
+                    trace!(peer = ?peer, "Admitting channel");
+                    return Poll::Ready(Some(channel));
+                }
+                Err(reason) => {
+                    *this.rejected += 1;
+                    if let Some(log) = &this.policy.rejections {
+                        let mut rejection = rejections::Rejection::new(RejectReason::Filtered)
+                            .with_detail(reason.to_string());
+                        if let Some(peer) = peer {
+                            rejection = rejection.with_peer(peer);
+                        }
+                        log.record(rejection);
+                    }
+                    info!(
+                        peer = ?peer,
+                        %reason,
+                        rejected_channels = *this.rejected,
+                        "Rejecting channel");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use futures_test::task::noop_context;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_contains() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.0")));
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(cidr("0.0.0.0/0").contains(ip("1.2.3.4")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("fd00::1")));
+        assert!(cidr("fd00::/8").contains(ip("fd12::1")));
+        assert!(!cidr("fd00::/8").contains(ip("fe00::1")));
+        assert!(cidr("192.168.1.1").contains(ip("192.168.1.1")));
+        assert!(!cidr("192.168.1.1").contains(ip("192.168.1.2")));
+    }
+
+    #[test]
+    fn cidr_parse_errors() {
+        assert_matches!("10.0.0.0/33".parse::<Cidr>(), Err(_));
+        assert_matches!("fd00::/129".parse::<Cidr>(), Err(_));
+        assert_matches!("10.0.0.0/x".parse::<Cidr>(), Err(_));
+        assert_matches!("example.com".parse::<Cidr>(), Err(_));
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+    }
+
+    #[test]
+    fn policy_denies_before_allowing() {
+        let policy = PeerPolicy::default()
+            .allow(cidr("10.0.0.0/8"))
+            .deny(cidr("10.0.0.0/16"));
+        assert_eq!(policy.check(ip("10.1.0.1")), Ok(()));
+        assert_eq!(policy.check(ip("10.0.0.1")), Err(Rejection::Denied));
+        assert_eq!(policy.check(ip("11.0.0.1")), Err(Rejection::NotAllowed));
+        assert_eq!(PeerPolicy::default().check(ip("11.0.0.1")), Ok(()));
+    }
+
+    #[test]
+    fn policy_callback_is_consulted_on_each_check() {
+        let open = Arc::new(AtomicBool::new(false));
+        let policy = PeerPolicy::default().allow_if({
+            let open = open.clone();
+            move |_| open.load(Ordering::SeqCst)
+        });
+        assert_eq!(policy.check(ip("10.0.0.1")), Err(Rejection::Callback));
+        open.store(true, Ordering::SeqCst);
+        assert_eq!(policy.check(ip("10.0.0.1")), Ok(()));
+    }
+
+    #[test]
+    fn filter_peers_drops_rejected_channels() {
+        let (tx, listener) = futures::channel::mpsc::unbounded();
+        let log = RejectionLog::default();
+        let filter = FilterPeers::new(
+            listener,
+            PeerPolicy::default()
+                .deny(cidr("10.0.0.0/8"))
+                .record_rejections(log.clone()),
+            |peer: &Option<IpAddr>| *peer,
+        );
+        futures::pin_mut!(filter);
+
+        tx.unbounded_send(Some(ip("10.0.0.1"))).unwrap();
+        tx.unbounded_send(None).unwrap();
+        tx.unbounded_send(Some(ip("11.0.0.1"))).unwrap();
+        assert_matches!(
+            filter.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(Some(peer))) if peer == ip("11.0.0.1")
+        );
+        assert_eq!(filter.admitted(), 1);
+        assert_eq!(filter.rejected(), 2);
+        let recent = log.recent(10);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].peer, None);
+        assert_eq!(recent[0].detail, "peer address unknown");
+        assert_eq!(recent[1].peer.as_deref(), Some("10.0.0.1"));
+        assert_eq!(recent[1].reason, RejectReason::Filtered);
+
+        tx.unbounded_send(Some(ip("10.0.0.2"))).unwrap();
+        assert_matches!(
+            filter.as_mut().poll_next(&mut noop_context()),
+            Poll::Pending
+        );
+        drop(tx);
+        assert_matches!(
+            filter.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(None)
+        );
+        assert_eq!(filter.rejected(), 3);
+    }
+}
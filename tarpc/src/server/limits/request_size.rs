@@ -0,0 +1,238 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{
+    capabilities::Capabilities,
+    server::{
+        rejections::{RejectReason, Rejection},
+        Channel, Config,
+    },
+    Response, ServerError, ServerMessage,
+};
+use futures::{prelude::*, ready, task::*};
+use pin_project::pin_project;
+use std::pin::Pin;
+
+/// A [`Channel`] that rejects requests whose [advised size](crate::context::SizeHint::request)
+/// is over a limit, before they are handled.
+///
+/// The advised size is only a hint from the client, so the limit protects handlers that allocate
+/// by the hint, not the transport: requests that don't advise their size, or that advise a size
+/// smaller than their own, are admitted.
+#[pin_project]
+#[derive(Debug)]
+pub struct MaxRequestSize<C> {
+    max_request_size: u64,
+    #[pin]
+    inner: C,
+}
+
+impl<C> MaxRequestSize<C> {
+    /// Returns the inner channel.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C> MaxRequestSize<C>
+where
+    C: Channel,
+{
+    /// Returns a new `MaxRequestSize` that wraps the given channel and rejects requests advised to
+    /// be over `max_request_size` bytes.
+    pub fn new(inner: C, max_request_size: u64) -> Self {
+        MaxRequestSize {
+            max_request_size,
+            inner,
+        }
+    }
+}
+
+impl<C> Stream for MaxRequestSize<C>
+where
+    C: Channel,
+{
+    type Item = <C as Stream>::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            // Rejecting a request writes its response, so the channel must be ready to be written
+            // to before a request is read.
+            ready!(self.as_mut().project().inner.poll_ready(cx)?);
+
+            let r = match ready!(self.as_mut().project().inner.poll_next(cx)?) {
+                Some(r) => r,
+                None => return Poll::Ready(None),
+            };
+            let request_size = match r.request.context.size_hint.request {
+                Some(request_size) if request_size > self.max_request_size => request_size,
+                _ => return Poll::Ready(Some(Ok(r))),
+            };
+
+            let _entered = r.span.enter();
+            let max_request_size = self.max_request_size;
+            tracing::info!(request_size, max_request_size, "RejectLargeRequest");
+            if let Some(rejections) = &self.inner.config().rejections {
+                let mut rejection = Rejection::new(RejectReason::TooLarge).with_detail(format!(
+                    "request of {} bytes is over the limit of {} bytes",
+                    request_size, max_request_size
+                ));
+                if let Some(peer_addr) = r.request.context.peer_addr {
+                    rejection = rejection.with_peer(peer_addr);
+                }
+                rejections.record(rejection);
+            }
+            // Nothing awaits the response to a one-way request.
+            if r.one_way {
+                continue;
+            }
+
+            self.as_mut().start_send(
+                Response {
+                    request_id: r.request.id,
+                    message: Err(ServerError::payload_too_large(format!(
+                        "request of {} bytes is over the server's limit of {} bytes.",
+                        request_size, max_request_size
+                    ))),
+                }
+                .into(),
+            )?;
+        }
+    }
+}
+
+impl<C> Sink<ServerMessage<<C as Channel>::Resp>> for MaxRequestSize<C>
+where
+    C: Channel,
+{
+    type Error = C::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        item: ServerMessage<<C as Channel>::Resp>,
+    ) -> Result<(), Self::Error> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<C> AsRef<C> for MaxRequestSize<C> {
+    fn as_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C> Channel for MaxRequestSize<C>
+where
+    C: Channel,
+{
+    type Req = <C as Channel>::Req;
+    type Resp = <C as Channel>::Resp;
+    type Transport = <C as Channel>::Transport;
+
+    fn in_flight_requests(&self) -> usize {
+        self.inner.in_flight_requests()
+    }
+
+    fn peer_capabilities(&self) -> Option<Capabilities> {
+        self.inner.peer_capabilities()
+    }
+
+    fn abort_in_flight_requests(self: Pin<&mut Self>) -> Vec<u64> {
+        self.project().inner.abort_in_flight_requests()
+    }
+
+    fn config(&self) -> &Config {
+        self.inner.config()
+    }
+
+    fn transport(&self) -> &Self::Transport {
+        self.inner.transport()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::server::testing::{self, FakeChannel, PollExt};
+    use crate::ServerErrorCause;
+    use pin_utils::pin_mut;
+    use std::io;
+
+    #[test]
+    fn admits_requests_within_limit() -> io::Result<()> {
+        let channel = MaxRequestSize::new(FakeChannel::default::<isize, isize>(), 10);
+
+        pin_mut!(channel);
+        channel.inner.push_req(0, 1);
+        channel.inner.push_req(1, 2);
+        channel.inner.stream[1]
+            .as_mut()
+            .unwrap()
+            .request
+            .context
+            .size_hint
+            .request = Some(10);
+        for expected in [(0, 1), (1, 2)] {
+            assert_eq!(
+                channel
+                    .as_mut()
+                    .poll_next(&mut testing::cx())?
+                    .map(|r| r.map(|r| (r.request.id, r.request.message))),
+                Poll::Ready(Some(expected))
+            );
+        }
+        assert!(channel.inner.sink.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_requests_over_limit() {
+        let mut channel = MaxRequestSize::new(FakeChannel::default::<isize, isize>(), 10);
+        let rejections = crate::server::RejectionLog::default();
+        channel.inner.config.rejections = Some(rejections.clone());
+
+        pin_mut!(channel);
+        channel.inner.push_req(1, 1);
+        channel.inner.stream[0]
+            .as_mut()
+            .unwrap()
+            .request
+            .context
+            .size_hint
+            .request = Some(11);
+        assert!(channel.as_mut().poll_next(&mut testing::cx()).is_done());
+        assert_eq!(channel.inner.sink.len(), 1);
+        let resp = channel.inner.sink.front().unwrap();
+        assert_eq!(resp.request_id(), Some(1));
+        assert!(matches!(
+            resp,
+            ServerMessage::Response(Response {
+                message: Err(e),
+                ..
+            }) if e.cause() == ServerErrorCause::PayloadTooLarge && !e.cause().is_retryable()
+        ));
+        let recent = rejections.recent(10);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].reason, RejectReason::TooLarge);
+        assert_eq!(
+            recent[0].detail,
+            "request of 11 bytes is over the limit of 10 bytes"
+        );
+    }
+}
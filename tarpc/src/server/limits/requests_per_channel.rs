@@ -5,12 +5,16 @@
 // https://opensource.org/licenses/MIT.
 
 use crate::{
-    server::{Channel, Config},
-    Response, ServerError,
+    capabilities::Capabilities,
+    server::{
+        rejections::{RejectReason, Rejection},
+        Channel, Config,
+    },
+    Response, ServerError, ServerMessage,
 };
 use futures::{prelude::*, ready, task::*};
 use pin_project::pin_project;
-use std::{io, pin::Pin};
+use std::pin::Pin;
 
 /// A [`Channel`] that limits the number of concurrent requests by throttling.
 ///
@@ -46,6 +50,21 @@ where
     }
 }
 
+impl<C> MaxRequests<C>
+where
+    C: Channel,
+{
+    /// Returns the limit, as overridden by the channel's config handle, if any.
+    fn max_in_flight_requests(&self) -> usize {
+        self.inner
+            .config()
+            .config_handle
+            .as_ref()
+            .and_then(|handle| handle.tunables().max_requests_per_channel)
+            .unwrap_or(self.max_in_flight_requests)
+    }
+}
+
 impl<C> Stream for MaxRequests<C>
 where
     C: Channel,
@@ -53,25 +72,34 @@ where
     type Item = <C as Stream>::Item;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        while self.as_mut().in_flight_requests() >= *self.as_mut().project().max_in_flight_requests
-        {
+        while self.as_mut().in_flight_requests() >= self.max_in_flight_requests() {
             ready!(self.as_mut().project().inner.poll_ready(cx)?);
 
             match ready!(self.as_mut().project().inner.poll_next(cx)?) {
                 Some(r) => {
                     let _entered = r.span.enter();
-                    tracing::info!(
-                        in_flight_requests = self.as_mut().in_flight_requests(),
-                        "ThrottleRequest",
-                    );
-
-                    self.as_mut().start_send(Response {
-                        request_id: r.request.id,
-                        message: Err(ServerError {
-                            kind: io::ErrorKind::WouldBlock,
-                            detail: "server throttled the request.".into(),
-                        }),
-                    })?;
+                    let in_flight_requests = self.as_mut().in_flight_requests();
+                    tracing::info!(in_flight_requests, "ThrottleRequest");
+                    if let Some(rejections) = &self.inner.config().rejections {
+                        let mut rejection = Rejection::new(RejectReason::Throttled)
+                            .with_detail(format!("{} requests were in flight", in_flight_requests));
+                        if let Some(peer_addr) = r.request.context.peer_addr {
+                            rejection = rejection.with_peer(peer_addr);
+                        }
+                        rejections.record(rejection);
+                    }
+                    // Nothing awaits the response to a one-way request.
+                    if r.one_way {
+                        continue;
+                    }
+
+                    self.as_mut().start_send(
+                        Response {
+                            request_id: r.request.id,
+                            message: Err(ServerError::overloaded("server throttled the request.")),
+                        }
+                        .into(),
+                    )?;
                 }
                 None => return Poll::Ready(None),
             }
@@ -80,7 +108,7 @@ where
     }
 }
 
-impl<C> Sink<Response<<C as Channel>::Resp>> for MaxRequests<C>
+impl<C> Sink<ServerMessage<<C as Channel>::Resp>> for MaxRequests<C>
 where
     C: Channel,
 {
@@ -92,7 +120,7 @@ where
 
     fn start_send(
         self: Pin<&mut Self>,
-        item: Response<<C as Channel>::Resp>,
+        item: ServerMessage<<C as Channel>::Resp>,
     ) -> Result<(), Self::Error> {
         self.project().inner.start_send(item)
     }
@@ -124,6 +152,14 @@ where
         self.inner.in_flight_requests()
     }
 
+    fn peer_capabilities(&self) -> Option<Capabilities> {
+        self.inner.peer_capabilities()
+    }
+
+    fn abort_in_flight_requests(self: Pin<&mut Self>) -> Vec<u64> {
+        self.project().inner.abort_in_flight_requests()
+    }
+
     fn config(&self) -> &Config {
         self.inner.config()
     }
@@ -180,10 +216,12 @@ mod tests {
 
     use crate::server::{
         testing::{self, FakeChannel, PollExt},
-        TrackedRequest,
+        Cancellation, ConfigHandle, StreamCredits, TrackedRequest,
     };
+    use crate::trace::TraceId;
     use pin_utils::pin_mut;
     use std::{
+        io,
         marker::PhantomData,
         time::{Duration, SystemTime},
     };
@@ -203,7 +241,11 @@ mod tests {
                 .in_flight_requests
                 .start_request(
                     i,
+                    TraceId::default(),
                     SystemTime::now() + Duration::from_secs(1),
+                    None,
+                    StreamCredits::default(),
+                    Cancellation::default(),
                     Span::current(),
                 )
                 .unwrap();
@@ -222,6 +264,29 @@ mod tests {
         assert!(throttler.as_mut().poll_next(&mut testing::cx()).is_done());
     }
 
+    #[test]
+    fn throttler_poll_next_uses_config_handle_limit() -> io::Result<()> {
+        let mut throttler = MaxRequests {
+            max_in_flight_requests: 0,
+            inner: FakeChannel::default::<isize, isize>(),
+        };
+        let config_handle = ConfigHandle::new();
+        throttler.inner.config.config_handle = Some(config_handle.clone());
+        config_handle.update(|tunables| tunables.max_requests_per_channel = Some(1));
+
+        pin_mut!(throttler);
+        throttler.inner.push_req(0, 1);
+        assert!(throttler.as_mut().poll_ready(&mut testing::cx()).is_ready());
+        assert_eq!(
+            throttler
+                .as_mut()
+                .poll_next(&mut testing::cx())?
+                .map(|r| r.map(|r| (r.request.id, r.request.message))),
+            Poll::Ready(Some((0, 1)))
+        );
+        Ok(())
+    }
+
     #[test]
     fn throttler_poll_next_some() -> io::Result<()> {
         let throttler = MaxRequests {
@@ -253,9 +318,33 @@ mod tests {
         throttler.inner.push_req(1, 1);
         assert!(throttler.as_mut().poll_next(&mut testing::cx()).is_done());
         assert_eq!(throttler.inner.sink.len(), 1);
-        let resp = throttler.inner.sink.get(0).unwrap();
-        assert_eq!(resp.request_id, 1);
-        assert!(resp.message.is_err());
+        let resp = throttler.inner.sink.front().unwrap();
+        assert_eq!(resp.request_id(), Some(1));
+        assert!(matches!(
+            resp,
+            ServerMessage::Response(Response {
+                message: Err(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn throttler_records_throttled_requests() {
+        let mut throttler = MaxRequests {
+            max_in_flight_requests: 0,
+            inner: FakeChannel::default::<isize, isize>(),
+        };
+        let rejections = crate::server::RejectionLog::default();
+        throttler.inner.config.rejections = Some(rejections.clone());
+
+        pin_mut!(throttler);
+        throttler.inner.push_req(1, 1);
+        assert!(throttler.as_mut().poll_next(&mut testing::cx()).is_done());
+        let recent = rejections.recent(10);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].reason, RejectReason::Throttled);
+        assert_eq!(recent[0].detail, "0 requests were in flight");
     }
 
     #[test]
@@ -269,11 +358,15 @@ mod tests {
 
         struct PendingSink<In, Out> {
             ghost: PhantomData<fn(Out) -> In>,
+            config: Config,
         }
         impl PendingSink<(), ()> {
             pub fn default<Req, Resp>(
-            ) -> PendingSink<io::Result<TrackedRequest<Req>>, Response<Resp>> {
-                PendingSink { ghost: PhantomData }
+            ) -> PendingSink<io::Result<TrackedRequest<Req>>, ServerMessage<Resp>> {
+                PendingSink {
+                    ghost: PhantomData,
+                    config: Config::default(),
+                }
             }
         }
         impl<In, Out> Stream for PendingSink<In, Out> {
@@ -297,12 +390,12 @@ mod tests {
                 Poll::Pending
             }
         }
-        impl<Req, Resp> Channel for PendingSink<io::Result<TrackedRequest<Req>>, Response<Resp>> {
+        impl<Req, Resp> Channel for PendingSink<io::Result<TrackedRequest<Req>>, ServerMessage<Resp>> {
             type Req = Req;
             type Resp = Resp;
             type Transport = ();
             fn config(&self) -> &Config {
-                unimplemented!()
+                &self.config
             }
             fn in_flight_requests(&self) -> usize {
                 0
@@ -326,24 +419,31 @@ mod tests {
             .in_flight_requests
             .start_request(
                 0,
+                TraceId::default(),
                 SystemTime::now() + Duration::from_secs(1),
+                None,
+                StreamCredits::default(),
+                Cancellation::default(),
                 Span::current(),
             )
             .unwrap();
         throttler
             .as_mut()
-            .start_send(Response {
-                request_id: 0,
-                message: Ok(1),
-            })
+            .start_send(
+                Response {
+                    request_id: 0,
+                    message: Ok(1),
+                }
+                .into(),
+            )
             .unwrap();
         assert_eq!(throttler.inner.in_flight_requests.len(), 0);
         assert_eq!(
-            throttler.inner.sink.get(0),
-            Some(&Response {
+            throttler.inner.sink.front(),
+            Some(&ServerMessage::Response(Response {
                 request_id: 0,
                 message: Ok(1),
-            })
+            }))
         );
     }
 }
@@ -0,0 +1,271 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{
+    capabilities::Capabilities,
+    server::{
+        rejections::{RejectReason, Rejection},
+        Channel, Config,
+    },
+    Response, ServerError, ServerMessage,
+};
+use futures::{prelude::*, task::*};
+use pin_project::pin_project;
+use std::{fmt, pin::Pin};
+
+/// A [`Channel`] that replaces responses whose serialized size is over a limit with an error, so
+/// that a handler producing a huge response neither kills the channel nor ships the response.
+///
+/// Serialization happens in the transport, so the channel sizes responses with a function given
+/// by the caller, e.g. one computing the size with the codec of the transport. A response over the
+/// limit is replaced by a [payload too large](ServerError::payload_too_large) [`ServerError`],
+/// whose detail holds the size of the response. A stream item over the limit is replaced the same
+/// way, which ends the stream.
+#[pin_project]
+pub struct MaxResponseSize<C, F> {
+    max_response_size: u64,
+    sizer: F,
+    #[pin]
+    inner: C,
+}
+
+impl<C, F> MaxResponseSize<C, F> {
+    /// Returns the inner channel.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C, F> MaxResponseSize<C, F>
+where
+    C: Channel,
+    F: Fn(&C::Resp) -> u64,
+{
+    /// Returns a new `MaxResponseSize` that wraps the given channel and replaces responses that
+    /// `sizer` sizes over `max_response_size` bytes with an error.
+    pub fn new(inner: C, max_response_size: u64, sizer: F) -> Self {
+        MaxResponseSize {
+            max_response_size,
+            sizer,
+            inner,
+        }
+    }
+}
+
+impl<C, F> fmt::Debug for MaxResponseSize<C, F>
+where
+    C: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MaxResponseSize")
+            .field("max_response_size", &self.max_response_size)
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<C, F> Stream for MaxResponseSize<C, F>
+where
+    C: Channel,
+{
+    type Item = <C as Stream>::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.project().inner.poll_next(cx)
+    }
+}
+
+impl<C, F> Sink<ServerMessage<<C as Channel>::Resp>> for MaxResponseSize<C, F>
+where
+    C: Channel,
+    F: Fn(&C::Resp) -> u64,
+{
+    type Error = C::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        item: ServerMessage<<C as Channel>::Resp>,
+    ) -> Result<(), Self::Error> {
+        let this = self.project();
+        let (request_id, response_size) = match &item {
+            ServerMessage::Response(Response {
+                request_id,
+                message: Ok(resp),
+            })
+            | ServerMessage::StreamItem {
+                request_id,
+                item: resp,
+            } => (*request_id, (this.sizer)(resp)),
+            _ => return this.inner.start_send(item),
+        };
+        let max_response_size = *this.max_response_size;
+        if response_size <= max_response_size {
+            return this.inner.start_send(item);
+        }
+
+        tracing::warn!(
+            rpc.request_id = request_id,
+            response_size,
+            max_response_size,
+            "RejectLargeResponse"
+        );
+        if let Some(rejections) = &this.inner.config().rejections {
+            rejections.record(
+                Rejection::new(RejectReason::ResponseTooLarge).with_detail(format!(
+                    "response of {} bytes is over the limit of {} bytes",
+                    response_size, max_response_size
+                )),
+            );
+        }
+        this.inner.start_send(
+            Response {
+                request_id,
+                message: Err(ServerError::payload_too_large(format!(
+                    "response of {} bytes is over the server's limit of {} bytes.",
+                    response_size, max_response_size
+                ))),
+            }
+            .into(),
+        )
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<C, F> AsRef<C> for MaxResponseSize<C, F> {
+    fn as_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C, F> Channel for MaxResponseSize<C, F>
+where
+    C: Channel,
+    F: Fn(&C::Resp) -> u64,
+{
+    type Req = <C as Channel>::Req;
+    type Resp = <C as Channel>::Resp;
+    type Transport = <C as Channel>::Transport;
+
+    fn in_flight_requests(&self) -> usize {
+        self.inner.in_flight_requests()
+    }
+
+    fn peer_capabilities(&self) -> Option<Capabilities> {
+        self.inner.peer_capabilities()
+    }
+
+    fn abort_in_flight_requests(self: Pin<&mut Self>) -> Vec<u64> {
+        self.project().inner.abort_in_flight_requests()
+    }
+
+    fn config(&self) -> &Config {
+        self.inner.config()
+    }
+
+    fn transport(&self) -> &Self::Transport {
+        self.inner.transport()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::server::testing::FakeChannel;
+    use pin_utils::pin_mut;
+    use std::io;
+
+    #[test]
+    fn sends_responses_within_limit() -> io::Result<()> {
+        let channel = MaxResponseSize::new(
+            FakeChannel::default::<isize, String>(),
+            5,
+            |resp: &String| resp.len() as u64,
+        );
+
+        pin_mut!(channel);
+        channel.as_mut().start_send(
+            Response {
+                request_id: 0,
+                message: Ok("hello".into()),
+            }
+            .into(),
+        )?;
+        channel.as_mut().start_send(ServerMessage::StreamItem {
+            request_id: 1,
+            item: "world".into(),
+        })?;
+        assert_eq!(
+            channel.inner.sink,
+            [
+                ServerMessage::Response(Response {
+                    request_id: 0,
+                    message: Ok("hello".into()),
+                }),
+                ServerMessage::StreamItem {
+                    request_id: 1,
+                    item: "world".into(),
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn replaces_responses_over_limit() -> io::Result<()> {
+        let mut channel = MaxResponseSize::new(
+            FakeChannel::default::<isize, String>(),
+            4,
+            |resp: &String| resp.len() as u64,
+        );
+        let rejections = crate::server::RejectionLog::default();
+        channel.inner.config.rejections = Some(rejections.clone());
+
+        pin_mut!(channel);
+        channel.as_mut().start_send(
+            Response {
+                request_id: 0,
+                message: Ok("hello".into()),
+            }
+            .into(),
+        )?;
+        channel.as_mut().start_send(ServerMessage::StreamItem {
+            request_id: 1,
+            item: "world".into(),
+        })?;
+        assert_eq!(channel.inner.sink.len(), 2);
+        for (resp, request_id) in channel.inner.sink.iter().zip([0, 1]) {
+            assert_eq!(
+                *resp,
+                ServerMessage::Response(Response {
+                    request_id,
+                    message: Err(ServerError::payload_too_large(
+                        "response of 5 bytes is over the server's limit of 4 bytes."
+                    )),
+                })
+            );
+        }
+        let recent = rejections.recent(10);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].reason, RejectReason::ResponseTooLarge);
+        assert_eq!(
+            recent[0].detail,
+            "response of 5 bytes is over the limit of 4 bytes"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,725 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{
+    context,
+    server::{Config, RequestStream, Serve, Served},
+    ServerError,
+};
+use futures::{prelude::*, ready, task::*};
+use pin_project::pin_project;
+use rand::Rng;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::oneshot;
+
+/// A request whose shadow response differs from the response of the primary server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence<Req, Resp> {
+    /// The method of the request, or the empty string if it's unknown.
+    pub method: &'static str,
+    /// The request message.
+    pub request: Req,
+    /// The response of the primary server, which was sent to the client.
+    pub primary: Resp,
+    /// The response of the shadow server, which was discarded.
+    pub shadow: Result<Resp, ServerError>,
+    /// How the shadow response differs from the primary response, as found by the
+    /// [comparator](Mirror::with_comparator) of the mirror. Empty if the mirror has no comparator
+    /// or the shadow server failed.
+    pub differences: Vec<Difference>,
+}
+
+/// A part of a response that differs between the primary and shadow responses.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Difference {
+    /// The path of the part within the response, e.g. `user.emails.0` for the first email of
+    /// the user field, or the empty string for the whole response.
+    pub path: String,
+    /// The part of the primary response, or None if the primary response lacks it.
+    pub primary: Option<String>,
+    /// The part of the shadow response, or None if the shadow response lacks it.
+    pub shadow: Option<String>,
+}
+
+/// Finds the differences between a primary and a shadow response. A shadow response is considered
+/// to match the primary response if no differences are found, so a comparator can also ignore
+/// differences that are expected, e.g. in timestamps.
+pub trait Comparator<Resp>: Send + Sync + 'static {
+    /// Returns the differences between the responses.
+    fn compare(&self, primary: &Resp, shadow: &Resp) -> Vec<Difference>;
+}
+
+impl<Resp, F> Comparator<Resp> for F
+where
+    F: Fn(&Resp, &Resp) -> Vec<Difference> + Send + Sync + 'static,
+{
+    fn compare(&self, primary: &Resp, shadow: &Resp) -> Vec<Difference> {
+        self(primary, shadow)
+    }
+}
+
+/// Counts of the requests mirrored to a shadow server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MirrorStats {
+    /// The number of requests sent to the shadow server.
+    pub mirrored: u64,
+    /// The number of shadow responses equal to the primary response.
+    pub matched: u64,
+    /// The number of shadow responses that differ from the primary response.
+    pub diverged: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    mirrored: AtomicU64,
+    matched: AtomicU64,
+    diverged: AtomicU64,
+    /// The number of divergences per path of difference.
+    differences: Mutex<BTreeMap<String, u64>>,
+}
+
+/// Duplicates a random fraction of the requests handled by a primary [`Serve`] implementation to
+/// a shadow implementation, e.g. a rewrite of the service being validated against production
+/// traffic, and compares their responses. Cloned mirrors share their stats.
+///
+/// The client only ever receives the primary response: shadow requests run in their own tasks, so
+/// they don't delay the primary response, and shadow responses are discarded once compared. A
+/// client can be used as the shadow by serving its requests with a closure.
+///
+/// Only requests answered with a single response are mirrored; the shadow request is sent without
+/// the items of client-streaming requests. Requests that shouldn't run twice, e.g. because they
+/// aren't idempotent, can be excluded with [`with_filter`](Self::with_filter).
+///
+/// Responses are compared for equality, unless the mirror has a
+/// [comparator](Self::with_comparator), which also reports which parts of the responses differ.
+pub struct Mirror<Req, Resp> {
+    fraction: f64,
+    filter: Option<Arc<dyn Fn(&Req) -> bool + Send + Sync>>,
+    comparator: Option<Arc<dyn Comparator<Resp>>>,
+    on_divergence: Option<Arc<dyn Fn(Divergence<Req, Resp>) + Send + Sync>>,
+    divergence_fraction: f64,
+    counters: Arc<Counters>,
+}
+
+impl<Req, Resp> Clone for Mirror<Req, Resp> {
+    fn clone(&self) -> Self {
+        Self {
+            fraction: self.fraction,
+            filter: self.filter.clone(),
+            comparator: self.comparator.clone(),
+            on_divergence: self.on_divergence.clone(),
+            divergence_fraction: self.divergence_fraction,
+            counters: self.counters.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Mirror<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mirror")
+            .field("fraction", &self.fraction)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl<Req, Resp> Mirror<Req, Resp> {
+    /// Returns a mirror that duplicates each request with probability `fraction`.
+    ///
+    /// # Panics
+    ///
+    /// If `fraction` is not between 0 and 1.
+    pub fn new(fraction: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "fraction must be between 0 and 1"
+        );
+        Self {
+            fraction,
+            filter: None,
+            comparator: None,
+            on_divergence: None,
+            divergence_fraction: 1.0,
+            counters: Arc::default(),
+        }
+    }
+
+    /// Sets a function that decides whether a request may be mirrored. Requests for which it
+    /// returns false are only handled by the primary server.
+    pub fn with_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&Req) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Sets the comparator that finds the differences between primary and shadow responses,
+    /// e.g. a [`JsonDiff`].
+    pub fn with_comparator<C>(mut self, comparator: C) -> Self
+    where
+        C: Comparator<Resp>,
+    {
+        self.comparator = Some(Arc::new(comparator));
+        self
+    }
+
+    /// Sets a function called with each request whose shadow response differs from the primary
+    /// response, e.g. to log it.
+    pub fn on_divergence<F>(mut self, on_divergence: F) -> Self
+    where
+        F: Fn(Divergence<Req, Resp>) + Send + Sync + 'static,
+    {
+        self.on_divergence = Some(Arc::new(on_divergence));
+        self
+    }
+
+    /// Passes only a random fraction of divergences to the [`on_divergence`](Self::on_divergence)
+    /// function, to bound the cost of recording them. Divergences are still counted in full.
+    ///
+    /// # Panics
+    ///
+    /// If `fraction` is not between 0 and 1.
+    pub fn sample_divergences(mut self, fraction: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "fraction must be between 0 and 1"
+        );
+        self.divergence_fraction = fraction;
+        self
+    }
+
+    /// Returns a [`Serve`] implementation that responds with `primary`, and mirrors requests to
+    /// `shadow`.
+    pub fn serve<P, S>(&self, primary: P, shadow: S) -> Mirroring<P, S, Req, Resp> {
+        Mirroring {
+            primary,
+            shadow,
+            mirror: self.clone(),
+        }
+    }
+
+    /// Returns the counts of the requests mirrored so far.
+    pub fn stats(&self) -> MirrorStats {
+        MirrorStats {
+            mirrored: self.counters.mirrored.load(Ordering::Relaxed),
+            matched: self.counters.matched.load(Ordering::Relaxed),
+            diverged: self.counters.diverged.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the number of divergences so far in which each path differed, as found by the
+    /// comparator of the mirror.
+    pub fn differences(&self) -> BTreeMap<String, u64> {
+        self.counters
+            .differences
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn sampled(&self, request: &Req) -> bool {
+        rand::thread_rng().gen_bool(self.fraction)
+            && self.filter.as_ref().map_or(true, |filter| filter(request))
+    }
+}
+
+/// A [`Serve`] implementation that mirrors sampled requests to a shadow server. Created by
+/// [`Mirror::serve`].
+pub struct Mirroring<P, S, Req, Resp> {
+    primary: P,
+    shadow: S,
+    mirror: Mirror<Req, Resp>,
+}
+
+impl<P, S, Req, Resp> Clone for Mirroring<P, S, Req, Resp>
+where
+    P: Clone,
+    S: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            primary: self.primary.clone(),
+            shadow: self.shadow.clone(),
+            mirror: self.mirror.clone(),
+        }
+    }
+}
+
+impl<P, S, Req, Resp> fmt::Debug for Mirroring<P, S, Req, Resp>
+where
+    P: fmt::Debug,
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mirroring")
+            .field("primary", &self.primary)
+            .field("shadow", &self.shadow)
+            .field("mirror", &self.mirror)
+            .finish()
+    }
+}
+
+impl<P, S, Req> Serve<Req> for Mirroring<P, S, Req, P::Resp>
+where
+    P: Serve<Req>,
+    P::Resp: Clone + PartialEq + Send + 'static,
+    S: Serve<Req, Resp = P::Resp>,
+    S::Fut: Send + 'static,
+    Req: Clone + Send + 'static,
+{
+    type Resp = P::Resp;
+    type Fut = MirrorResponse<P::Fut, P::Resp>;
+
+    fn method(&self, request: &Req) -> Option<&'static str> {
+        self.primary.method(request)
+    }
+
+    fn config(&self, request: &Req) -> Option<&Config> {
+        self.primary.config(request)
+    }
+
+    fn serve_with_items(
+        self,
+        ctx: context::Context,
+        req: Req,
+        items: RequestStream<Req>,
+    ) -> Served<Self::Fut> {
+        let mirrored = if self.mirror.sampled(&req) {
+            Some((self.primary.method(&req).unwrap_or(""), req.clone()))
+        } else {
+            None
+        };
+        let served = self.primary.serve_with_items(ctx, req, items);
+        let primary_tx = match (mirrored, &served) {
+            (Some((method, request)), Served::Response(_) | Served::Fallible(_)) => {
+                Some(self.mirror.spawn_shadow(self.shadow, ctx, method, request))
+            }
+            _ => None,
+        };
+        match served {
+            Served::Response(response) => Served::Response(MirrorResponse {
+                response,
+                primary_tx,
+            }),
+            Served::Fallible(response) => match primary_tx {
+                Some(primary_tx) => Served::Fallible(Box::pin(async move {
+                    let response = response.await?;
+                    let _ = primary_tx.send(response.clone());
+                    Ok(response)
+                })),
+                None => Served::Fallible(response),
+            },
+            Served::Stream(stream) => Served::Stream(stream),
+            Served::Error(error) => Served::Error(error),
+        }
+    }
+}
+
+impl<Req, Resp> Mirror<Req, Resp>
+where
+    Req: Send + 'static,
+    Resp: PartialEq + Send + 'static,
+{
+    /// Sends `request` to `shadow` in a new task, which compares the shadow response with the
+    /// primary response sent on the returned channel.
+    fn spawn_shadow<S>(
+        self,
+        shadow: S,
+        ctx: context::Context,
+        method: &'static str,
+        request: Req,
+    ) -> oneshot::Sender<Resp>
+    where
+        S: Serve<Req, Resp = Resp>,
+        S::Fut: Send + 'static,
+        Req: Clone,
+    {
+        let shadow = shadow.serve(ctx, request.clone());
+        self.counters.mirrored.fetch_add(1, Ordering::Relaxed);
+        let (primary_tx, primary_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let shadow = shadow.await;
+            // The primary request was canceled or failed, so there's nothing to compare to.
+            let primary = match primary_rx.await {
+                Ok(primary) => primary,
+                Err(_) => return,
+            };
+            let (matched, differences) = match (&shadow, &self.comparator) {
+                (Ok(shadow), Some(comparator)) => {
+                    let differences = comparator.compare(&primary, shadow);
+                    (differences.is_empty(), differences)
+                }
+                (Ok(shadow), None) => (*shadow == primary, vec![]),
+                (Err(_), _) => (false, vec![]),
+            };
+            if matched {
+                self.counters.matched.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            self.counters.diverged.fetch_add(1, Ordering::Relaxed);
+            {
+                let mut counts = self
+                    .counters
+                    .differences
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner());
+                for difference in &differences {
+                    *counts.entry(difference.path.clone()).or_default() += 1;
+                }
+            }
+            tracing::info!(method, differences = differences.len(), "MirrorDiverged");
+            if let Some(on_divergence) = &self.on_divergence {
+                if rand::thread_rng().gen_bool(self.divergence_fraction) {
+                    on_divergence(Divergence {
+                        method,
+                        request,
+                        primary,
+                        shadow,
+                        differences,
+                    });
+                }
+            }
+        });
+        primary_tx
+    }
+}
+
+/// A response future that hands a copy of the primary response to the comparison with the
+/// shadow response, if the request was mirrored.
+#[pin_project]
+pub struct MirrorResponse<Fut, Resp> {
+    #[pin]
+    response: Fut,
+    primary_tx: Option<oneshot::Sender<Resp>>,
+}
+
+impl<Fut, Resp> fmt::Debug for MirrorResponse<Fut, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MirrorResponse")
+            .field("mirrored", &self.primary_tx.is_some())
+            .finish()
+    }
+}
+
+impl<Fut, Resp> Future for MirrorResponse<Fut, Resp>
+where
+    Fut: Future<Output = Resp>,
+    Resp: Clone,
+{
+    type Output = Resp;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Resp> {
+        let this = self.project();
+        let response = ready!(this.response.poll(cx));
+        if let Some(primary_tx) = this.primary_tx.take() {
+            let _ = primary_tx.send(response.clone());
+        }
+        Poll::Ready(response)
+    }
+}
+
+/// A [`Comparator`] that serializes responses to JSON and reports each field that differs,
+/// e.g. `{"user": {"name": "a"}}` and `{"user": {"name": "b"}}` differ at `user.name`.
+///
+/// Fields can be [ignored](Self::ignore), e.g. timestamps expected to differ, and the values of
+/// sensitive fields can be [redacted](Self::redact) from the reported differences.
+#[cfg(feature = "mirror-diff")]
+#[cfg_attr(docsrs, doc(cfg(feature = "mirror-diff")))]
+#[derive(Clone, Debug, Default)]
+pub struct JsonDiff {
+    ignored: std::collections::BTreeSet<String>,
+    redacted: std::collections::BTreeSet<String>,
+}
+
+#[cfg(feature = "mirror-diff")]
+impl JsonDiff {
+    /// Returns a comparator that reports every differing field.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Skips fields named `field`, at any depth, when comparing responses.
+    pub fn ignore(mut self, field: impl Into<String>) -> Self {
+        self.ignored.insert(field.into());
+        self
+    }
+
+    /// Replaces the values of fields named `field`, at any depth, and of everything within them,
+    /// with `<redacted>` in the reported differences. The fields are still compared.
+    pub fn redact(mut self, field: impl Into<String>) -> Self {
+        self.redacted.insert(field.into());
+        self
+    }
+
+    fn diff(
+        &self,
+        differences: &mut Vec<Difference>,
+        path: String,
+        primary: Option<&serde_json::Value>,
+        shadow: Option<&serde_json::Value>,
+        redacted: bool,
+    ) {
+        use serde_json::Value;
+
+        let child = |key: &str| {
+            if path.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", path, key)
+            }
+        };
+        match (primary, shadow) {
+            (Some(Value::Object(primary)), Some(Value::Object(shadow))) => {
+                let keys: std::collections::BTreeSet<_> =
+                    primary.keys().chain(shadow.keys()).collect();
+                for key in keys.into_iter().filter(|key| !self.ignored.contains(*key)) {
+                    self.diff(
+                        differences,
+                        child(key),
+                        primary.get(key),
+                        shadow.get(key),
+                        redacted || self.redacted.contains(key),
+                    );
+                }
+            }
+            (Some(Value::Array(primary)), Some(Value::Array(shadow))) => {
+                for i in 0..primary.len().max(shadow.len()) {
+                    self.diff(
+                        differences,
+                        child(&i.to_string()),
+                        primary.get(i),
+                        shadow.get(i),
+                        redacted,
+                    );
+                }
+            }
+            (primary, shadow) if primary == shadow => {}
+            (primary, shadow) => {
+                let render = |value: &Value| {
+                    if redacted {
+                        "<redacted>".to_string()
+                    } else {
+                        value.to_string()
+                    }
+                };
+                differences.push(Difference {
+                    path,
+                    primary: primary.map(render),
+                    shadow: shadow.map(render),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(feature = "mirror-diff")]
+impl<Resp> Comparator<Resp> for JsonDiff
+where
+    Resp: serde::Serialize,
+{
+    fn compare(&self, primary: &Resp, shadow: &Resp) -> Vec<Difference> {
+        let mut differences = vec![];
+        match (serde_json::to_value(primary), serde_json::to_value(shadow)) {
+            (Ok(primary), Ok(shadow)) => {
+                self.diff(
+                    &mut differences,
+                    String::new(),
+                    Some(&primary),
+                    Some(&shadow),
+                    false,
+                );
+            }
+            // Responses that can't be compared are reported as differing entirely.
+            (primary, shadow) => differences.push(Difference {
+                path: String::new(),
+                primary: primary.err().map(|e| format!("<unserializable: {}>", e)),
+                shadow: shadow.err().map(|e| format!("<unserializable: {}>", e)),
+            }),
+        }
+        differences
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    async fn handle<S>(serve: S, req: u32) -> u32
+    where
+        S: Serve<u32, Resp = u32>,
+    {
+        serve.serve(context::current(), req).await.unwrap()
+    }
+
+    /// Waits for the spawned comparisons to complete.
+    async fn compared(mirror: &Mirror<u32, u32>) -> MirrorStats {
+        loop {
+            let stats = mirror.stats();
+            if stats.matched + stats.diverged == stats.mirrored {
+                return stats;
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn mirror_compares_shadow_responses() {
+        let divergences = Arc::new(Mutex::new(vec![]));
+        let mirror = Mirror::new(1.0).on_divergence({
+            let divergences = divergences.clone();
+            move |divergence| divergences.lock().unwrap().push(divergence)
+        });
+        let serve = mirror.serve(
+            |_: context::Context, i: u32| future::ready(i * 2),
+            |_: context::Context, i: u32| future::ready(if i == 3 { 0 } else { i * 2 }),
+        );
+        for i in 0..5 {
+            assert_eq!(handle(serve.clone(), i).await, i * 2);
+        }
+
+        assert_eq!(
+            compared(&mirror).await,
+            MirrorStats {
+                mirrored: 5,
+                matched: 4,
+                diverged: 1,
+            }
+        );
+        assert_eq!(
+            *divergences.lock().unwrap(),
+            [Divergence {
+                method: "",
+                request: 3,
+                primary: 6,
+                shadow: Ok(0),
+                differences: vec![],
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn mirror_skips_filtered_and_unsampled_requests() {
+        let mirror = Mirror::new(1.0).with_filter(|i: &u32| i % 2 == 0);
+        let serve = mirror.serve(
+            |_: context::Context, i: u32| future::ready(i),
+            |_: context::Context, i: u32| future::ready(i),
+        );
+        for i in 0..4 {
+            handle(serve.clone(), i).await;
+        }
+        assert_eq!(compared(&mirror).await.mirrored, 2);
+
+        let mirror = Mirror::new(0.0);
+        let serve = mirror.serve(
+            |_: context::Context, i: u32| future::ready(i),
+            |_: context::Context, _: u32| -> future::Ready<u32> { unreachable!() },
+        );
+        handle(serve, 1).await;
+        assert_eq!(mirror.stats(), MirrorStats::default());
+    }
+
+    #[tokio::test]
+    async fn mirror_counts_differences_found_by_comparator() {
+        let divergences = Arc::new(Mutex::new(vec![]));
+        let mirror = Mirror::new(1.0)
+            // Only the parity of the response matters.
+            .with_comparator(|primary: &u32, shadow: &u32| {
+                if primary % 2 == shadow % 2 {
+                    vec![]
+                } else {
+                    vec![Difference {
+                        path: "parity".into(),
+                        primary: Some((primary % 2).to_string()),
+                        shadow: Some((shadow % 2).to_string()),
+                    }]
+                }
+            })
+            .on_divergence({
+                let divergences = divergences.clone();
+                move |divergence| divergences.lock().unwrap().push(divergence)
+            })
+            .sample_divergences(0.0);
+        let serve = mirror.serve(
+            |_: context::Context, i: u32| future::ready(i),
+            |_: context::Context, i: u32| future::ready(if i == 1 { 2 } else { i + 2 }),
+        );
+        for i in 0..4 {
+            handle(serve.clone(), i).await;
+        }
+
+        assert_eq!(
+            compared(&mirror).await,
+            MirrorStats {
+                mirrored: 4,
+                matched: 3,
+                diverged: 1,
+            }
+        );
+        assert_eq!(
+            mirror.differences(),
+            BTreeMap::from([("parity".to_string(), 1)])
+        );
+        assert!(divergences.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "mirror-diff")]
+    #[test]
+    fn json_diff_reports_ignores_and_redacts_fields() {
+        use serde_json::json;
+
+        let diff = JsonDiff::new().ignore("updated").redact("secret");
+        let primary = json!({
+            "name": "a",
+            "tags": ["x", "y"],
+            "updated": 1,
+            "secret": {"token": "t1"},
+        });
+        let shadow = json!({
+            "name": "b",
+            "tags": ["x"],
+            "updated": 2,
+            "secret": {"token": "t2"},
+            "extra": null,
+        });
+        assert_eq!(
+            diff.compare(&primary, &shadow),
+            [
+                Difference {
+                    path: "extra".into(),
+                    primary: None,
+                    shadow: Some("null".into()),
+                },
+                Difference {
+                    path: "name".into(),
+                    primary: Some("\"a\"".into()),
+                    shadow: Some("\"b\"".into()),
+                },
+                Difference {
+                    path: "secret.token".into(),
+                    primary: Some("<redacted>".into()),
+                    shadow: Some("<redacted>".into()),
+                },
+                Difference {
+                    path: "tags.1".into(),
+                    primary: Some("\"y\"".into()),
+                    shadow: None,
+                },
+            ]
+        );
+        assert!(diff.compare(&primary, &primary).is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{metrics::Outcome, trace, Response, ServerError, ServerMessage};
+use futures::{prelude::*, task::*};
+use pin_project::pin_project;
+use std::{any::Any, cell::RefCell, io, panic, pin::Pin};
+use tokio::sync::mpsc;
+
+/// The request a handler was serving when it panicked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PanicContext {
+    /// The ID of the trace the request belongs to.
+    pub trace_id: trace::TraceId,
+    /// The ID of the request, unique within its channel.
+    pub request_id: u64,
+    /// The name of the method being served, if the service names its methods.
+    pub method: Option<&'static str>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<PanicContext>> = const { RefCell::new(None) };
+}
+
+/// Returns the request whose handler is being polled on the current thread, if any.
+///
+/// Panic hooks, such as those of error reporters, can call this to attribute a panic to the
+/// request that caused it.
+pub fn current_request() -> Option<PanicContext> {
+    CURRENT.with(|current| *current.borrow())
+}
+
+/// Installs a panic hook that reports the trace ID, request ID, and method of the request whose
+/// handler panicked, then calls the previously installed hook.
+///
+/// The report is logged as a [`tracing`] error event. Panics outside of request handlers are
+/// passed straight to the previous hook. Error reporters that install their own panic hook
+/// should be installed first, so that their hook runs while [`current_request`] still returns
+/// the panicking request.
+pub fn install_hook() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if let Some(request) = current_request() {
+            tracing::error!(
+                trace_id = %request.trace_id,
+                request_id = request.request_id,
+                method = request.method.unwrap_or(""),
+                "Handler panicked: {}",
+                info
+            );
+        }
+        previous(info);
+    }));
+}
+
+/// Awaits the handler of the request `request_id`. If `response_tx` is set and the handler
+/// panics, the request is failed with a [`ServerError`] sent through `response_tx`, rather than
+/// the panic unwinding further.
+pub(crate) async fn catch<F, Resp>(
+    handler: F,
+    request_id: u64,
+    response_tx: Option<mpsc::Sender<ServerMessage<Resp>>>,
+) -> Outcome
+where
+    F: Future<Output = Outcome>,
+{
+    let response_tx = match response_tx {
+        Some(response_tx) => response_tx,
+        None => return handler.await,
+    };
+    let detail = match panic::AssertUnwindSafe(handler).catch_unwind().await {
+        Ok(outcome) => return outcome,
+        Err(panic) => format!("the handler panicked: {}", message(&*panic)),
+    };
+    tracing::error!("HandlerPanicked: {}", detail);
+    let response = Response {
+        request_id,
+        message: Err(ServerError::new(io::ErrorKind::Other, detail)),
+    };
+    let _ = response_tx.send(response.into()).await;
+    tracing::info!("BufferResponse");
+    Outcome::Error
+}
+
+/// Returns the message of a panic, as the default panic hook prints it.
+fn message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&'static str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("Box<dyn Any>", String::as_str),
+    }
+}
+
+/// A future that marks the current thread as serving a request while it is polled.
+#[pin_project]
+#[derive(Debug)]
+pub(crate) struct Scoped<F> {
+    #[pin]
+    inner: F,
+    context: PanicContext,
+}
+
+impl<F> Scoped<F> {
+    pub fn new(inner: F, context: PanicContext) -> Self {
+        Self { inner, context }
+    }
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.project();
+        let _guard = ScopeGuard(CURRENT.with(|current| current.replace(Some(*this.context))));
+        this.inner.poll(cx)
+    }
+}
+
+/// Restores the request of an enclosing scope, even when the handler unwinds.
+struct ScopeGuard(Option<PanicContext>);
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use futures::future::poll_fn;
+    use futures_test::task::noop_context;
+
+    #[test]
+    fn current_request_is_set_only_while_polled() {
+        let context = PanicContext {
+            trace_id: trace::TraceId::from(7),
+            request_id: 3,
+            method: Some("Hello"),
+        };
+        let mut scoped = Box::pin(Scoped::new(
+            poll_fn(|_| Poll::Ready(current_request())),
+            context,
+        ));
+        assert_eq!(current_request(), None);
+        assert_matches!(
+            scoped.as_mut().poll(&mut noop_context()),
+            Poll::Ready(Some(c)) if c == context
+        );
+        assert_eq!(current_request(), None);
+    }
+
+    #[test]
+    fn current_request_is_cleared_when_handler_panics() {
+        let context = PanicContext {
+            trace_id: trace::TraceId::from(7),
+            request_id: 3,
+            method: None,
+        };
+        let mut scoped = Box::pin(Scoped::new(
+            poll_fn(|_| -> Poll<()> { panic!("handler panicked") }),
+            context,
+        ));
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            scoped.as_mut().poll(&mut noop_context())
+        }));
+        assert!(result.is_err());
+        assert_eq!(current_request(), None);
+    }
+}
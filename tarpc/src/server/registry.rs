@@ -0,0 +1,588 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{
+    capabilities::Capabilities,
+    server::{self, Channel, Pusher},
+};
+use fnv::FnvHashMap;
+use futures::{prelude::*, ready, task::*};
+use pin_project::pin_project;
+use std::{
+    any::Any,
+    fmt,
+    hash::Hash,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::SystemTime,
+};
+
+/// A registered channel, as seen through the [`Registry`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ChannelInfo {
+    /// Identifies the channel, unique within its registry.
+    pub id: u64,
+    /// When the channel was registered.
+    pub registered_at: SystemTime,
+}
+
+/// Tracks the live channels of a server, keyed by a user-provided id, e.g. the authenticated
+/// user of each channel, so the server can tell who is connected and
+/// [disconnect](Registry::disconnect) specific clients. Cloned registries share their channels.
+///
+/// Channels are registered with [`Incoming::register`](crate::server::incoming::Incoming::register)
+/// or [`Registry::register`], and are removed from the registry when dropped. Channels that
+/// [attach a pusher](RegisteredChannel::attach_pusher) can be sent unsolicited messages with
+/// [`Registry::push`] and [`Registry::broadcast`].
+pub struct Registry<K> {
+    inner: Arc<Mutex<Inner<K>>>,
+}
+
+struct Inner<K> {
+    next_id: u64,
+    channels: FnvHashMap<K, FnvHashMap<u64, Entry>>,
+}
+
+struct Entry {
+    info: ChannelInfo,
+    disconnect: Arc<Disconnect>,
+    /// A type-erased [`Pusher`], if the channel attached one.
+    pusher: Option<Box<dyn Any + Send>>,
+}
+
+impl Entry {
+    /// Pushes a clone of `message` to the channel, returning true if it was buffered.
+    fn push<Resp>(&self, message: &Resp) -> bool
+    where
+        Resp: Clone + Send + 'static,
+    {
+        match self
+            .pusher
+            .as_ref()
+            .and_then(|pusher| pusher.downcast_ref::<Pusher<Resp>>())
+        {
+            Some(pusher) => match pusher.try_push(message.clone()) {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::warn!(channel_id = self.info.id, "DroppedPush: {}", e);
+                    false
+                }
+            },
+            None => false,
+        }
+    }
+}
+
+/// Signals a registered channel to stop reading requests.
+#[derive(Debug, Default)]
+struct Disconnect {
+    requested: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl<K> Clone for Registry<K> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K> Default for Registry<K> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                next_id: 0,
+                channels: FnvHashMap::default(),
+            })),
+        }
+    }
+}
+
+impl<K> fmt::Debug for Registry<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field("count", &self.count())
+            .finish()
+    }
+}
+
+impl<K> Registry<K> {
+    /// Returns an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of live channels.
+    pub fn count(&self) -> usize {
+        self.lock()
+            .channels
+            .values()
+            .map(|channels| channels.len())
+            .sum()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<K>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<K> Registry<K>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Registers `channel` under `key`. The channel is removed from the registry when the returned
+    /// channel is dropped.
+    pub fn register<C>(&self, key: K, channel: C) -> RegisteredChannel<C, K> {
+        let disconnect = Arc::new(Disconnect::default());
+        let mut inner = self.lock();
+        let info = ChannelInfo {
+            id: inner.next_id,
+            registered_at: crate::util::time::now(),
+        };
+        inner.next_id += 1;
+        inner.channels.entry(key.clone()).or_default().insert(
+            info.id,
+            Entry {
+                info,
+                disconnect: disconnect.clone(),
+                pusher: None,
+            },
+        );
+        RegisteredChannel {
+            inner: channel,
+            registration: Registration {
+                registry: self.clone(),
+                key: Some(key),
+                id: info.id,
+            },
+            disconnect,
+        }
+    }
+
+    /// Returns the channels registered under `key`.
+    pub fn lookup(&self, key: &K) -> Vec<ChannelInfo> {
+        self.lock()
+            .channels
+            .get(key)
+            .map(|channels| channels.values().map(|entry| entry.info).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of channels registered under `key`.
+    pub fn count_of(&self, key: &K) -> usize {
+        self.lock()
+            .channels
+            .get(key)
+            .map_or(0, |channels| channels.len())
+    }
+
+    /// Returns the keys with at least one live channel.
+    pub fn keys(&self) -> Vec<K> {
+        self.lock().channels.keys().cloned().collect()
+    }
+
+    /// Asks the channels registered under `key` to stop reading requests, and returns how many
+    /// there were. Requests already in flight are still responded to; the channels are closed,
+    /// and removed from the registry, once they're dropped.
+    pub fn disconnect(&self, key: &K) -> usize {
+        let inner = self.lock();
+        let channels = match inner.channels.get(key) {
+            Some(channels) => channels,
+            None => return 0,
+        };
+        for entry in channels.values() {
+            entry.disconnect.requested.store(true, Ordering::Release);
+            entry.disconnect.waker.wake();
+        }
+        channels.len()
+    }
+
+    /// Pushes `message` to the channels registered under `key`, and returns how many buffered it.
+    /// Channels that didn't [attach](RegisteredChannel::attach_pusher) a pusher of `Resp`, or
+    /// whose buffer of pending responses is full, are skipped.
+    pub fn push<Resp>(&self, key: &K, message: Resp) -> usize
+    where
+        Resp: Clone + Send + 'static,
+    {
+        self.lock().channels.get(key).map_or(0, |channels| {
+            channels
+                .values()
+                .filter(|entry| entry.push(&message))
+                .count()
+        })
+    }
+
+    /// Pushes `message` to every registered channel, and returns how many buffered it. Like
+    /// [`Registry::push`], channels without a pusher of `Resp` or with a full buffer are skipped.
+    pub fn broadcast<Resp>(&self, message: Resp) -> usize
+    where
+        Resp: Clone + Send + 'static,
+    {
+        self.lock()
+            .channels
+            .values()
+            .flat_map(|channels| channels.values())
+            .filter(|entry| entry.push(&message))
+            .count()
+    }
+
+    fn attach_pusher(&self, key: &K, id: u64, pusher: Box<dyn Any + Send>) {
+        if let Some(entry) = self
+            .lock()
+            .channels
+            .get_mut(key)
+            .and_then(|channels| channels.get_mut(&id))
+        {
+            entry.pusher = Some(pusher);
+        }
+    }
+
+    fn unregister(&self, key: &K, id: u64) {
+        let mut inner = self.lock();
+        if let Some(channels) = inner.channels.get_mut(key) {
+            channels.remove(&id);
+            if channels.is_empty() {
+                inner.channels.remove(key);
+            }
+        }
+    }
+}
+
+/// Removes a channel from its registry when dropped.
+struct Registration<K>
+where
+    K: Eq + Hash + Clone,
+{
+    registry: Registry<K>,
+    key: Option<K>,
+    id: u64,
+}
+
+impl<K> Drop for Registration<K>
+where
+    K: Eq + Hash + Clone,
+{
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.registry.unregister(&key, self.id);
+        }
+    }
+}
+
+/// A channel that is tracked by a [`Registry`]. Once [disconnected](Registry::disconnect), the
+/// channel yields no more requests.
+#[pin_project]
+pub struct RegisteredChannel<C, K>
+where
+    K: Eq + Hash + Clone,
+{
+    #[pin]
+    inner: C,
+    registration: Registration<K>,
+    disconnect: Arc<Disconnect>,
+}
+
+impl<C, K> fmt::Debug for RegisteredChannel<C, K>
+where
+    C: fmt::Debug,
+    K: Eq + Hash + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisteredChannel")
+            .field("inner", &self.inner)
+            .field("id", &self.registration.id)
+            .finish()
+    }
+}
+
+impl<C, K> RegisteredChannel<C, K>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Returns the key the channel is registered under.
+    pub fn key(&self) -> &K {
+        self.registration.key.as_ref().unwrap()
+    }
+
+    /// Returns the id of the channel within its registry.
+    pub fn id(&self) -> u64 {
+        self.registration.id
+    }
+
+    /// Returns the inner channel.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    /// Lets the registry [push](Registry::push) messages to the channel, typically with the
+    /// pusher of the channel's [`Requests`](crate::server::Requests):
+    ///
+    /// ```ignore
+    /// let requests = channel.requests();
+    /// requests.channel().attach_pusher(requests.pusher());
+    /// ```
+    pub fn attach_pusher<Resp>(&self, pusher: Pusher<Resp>)
+    where
+        Resp: Send + 'static,
+    {
+        self.registration
+            .registry
+            .attach_pusher(self.key(), self.id(), Box::new(pusher));
+    }
+}
+
+impl<C, K> Stream for RegisteredChannel<C, K>
+where
+    C: Stream,
+    K: Eq + Hash + Clone,
+{
+    type Item = C::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<C::Item>> {
+        let this = self.project();
+        this.disconnect.waker.register(cx.waker());
+        if this.disconnect.requested.load(Ordering::Acquire) {
+            tracing::info!(channel_id = this.registration.id, "Disconnected");
+            return Poll::Ready(None);
+        }
+        this.inner.poll_next(cx)
+    }
+}
+
+impl<C, I, K> Sink<I> for RegisteredChannel<C, K>
+where
+    C: Sink<I>,
+    K: Eq + Hash + Clone,
+{
+    type Error = C::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: I) -> Result<(), Self::Error> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<C, K> AsRef<C> for RegisteredChannel<C, K>
+where
+    K: Eq + Hash + Clone,
+{
+    fn as_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C, K> Channel for RegisteredChannel<C, K>
+where
+    C: Channel,
+    K: Eq + Hash + Clone,
+{
+    type Req = C::Req;
+    type Resp = C::Resp;
+    type Transport = C::Transport;
+
+    fn config(&self) -> &server::Config {
+        self.inner.config()
+    }
+
+    fn in_flight_requests(&self) -> usize {
+        self.inner.in_flight_requests()
+    }
+
+    fn peer_capabilities(&self) -> Option<Capabilities> {
+        self.inner.peer_capabilities()
+    }
+
+    fn abort_in_flight_requests(self: Pin<&mut Self>) -> Vec<u64> {
+        self.project().inner.abort_in_flight_requests()
+    }
+
+    fn transport(&self) -> &Self::Transport {
+        self.inner.transport()
+    }
+}
+
+/// An [`Incoming`](crate::server::incoming::Incoming) stream that registers each channel in a
+/// [`Registry`].
+#[pin_project]
+pub struct Register<S, K, F> {
+    #[pin]
+    listener: S,
+    registry: Registry<K>,
+    keymaker: F,
+}
+
+impl<S, K, F> fmt::Debug for Register<S, K, F>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Register")
+            .field("listener", &self.listener)
+            .field("registry", &self.registry)
+            .finish()
+    }
+}
+
+impl<S, K, F> Register<S, K, F> {
+    pub(crate) fn new(listener: S, registry: Registry<K>, keymaker: F) -> Self {
+        Self {
+            listener,
+            registry,
+            keymaker,
+        }
+    }
+}
+
+impl<S, K, F> Stream for Register<S, K, F>
+where
+    S: Stream,
+    K: Eq + Hash + Clone,
+    F: Fn(&S::Item) -> K,
+{
+    type Item = RegisteredChannel<S::Item, K>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let channel = ready!(this.listener.poll_next(cx));
+        Poll::Ready(channel.map(|channel| {
+            let key = (this.keymaker)(&channel);
+            this.registry.register(key, channel)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use futures_test::task::noop_context;
+
+    #[test]
+    fn registry_tracks_live_channels() {
+        let registry = Registry::new();
+        let alice1 = registry.register("alice", stream::pending::<()>());
+        let alice2 = registry.register("alice", stream::pending::<()>());
+        let bob = registry.register("bob", stream::pending::<()>());
+        assert_eq!(registry.count(), 3);
+        assert_eq!(registry.count_of(&"alice"), 2);
+        assert_eq!(registry.count_of(&"carol"), 0);
+        let mut keys = registry.keys();
+        keys.sort_unstable();
+        assert_eq!(keys, ["alice", "bob"]);
+        let mut ids: Vec<_> = registry.lookup(&"alice").iter().map(|c| c.id).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, [alice1.id(), alice2.id()]);
+
+        drop(alice1);
+        assert_eq!(registry.count_of(&"alice"), 1);
+        drop((alice2, bob));
+        assert_eq!(registry.count(), 0);
+        assert!(registry.keys().is_empty());
+    }
+
+    #[test]
+    fn disconnect_ends_channels_of_key() {
+        let registry = Registry::new();
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let mut alice = Box::pin(registry.register("alice", rx));
+        let mut bob = Box::pin(registry.register("bob", stream::pending::<()>()));
+
+        tx.unbounded_send(()).unwrap();
+        assert_matches!(
+            alice.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(Some(()))
+        );
+        assert_eq!(registry.disconnect(&"alice"), 1);
+        assert_eq!(registry.disconnect(&"carol"), 0);
+        tx.unbounded_send(()).unwrap();
+        assert_matches!(
+            alice.as_mut().poll_next(&mut noop_context()),
+            Poll::Ready(None)
+        );
+        assert_matches!(bob.as_mut().poll_next(&mut noop_context()), Poll::Pending);
+    }
+
+    #[tokio::test]
+    async fn push_and_broadcast_reach_channels_with_pushers() {
+        use crate::{
+            server::{BaseChannel, Requests},
+            transport::channel::{self, UnboundedChannel},
+            ClientMessage, ServerMessage,
+        };
+
+        type Transport = UnboundedChannel<ClientMessage<()>, ServerMessage<String>>;
+        fn requests(
+            registry: &Registry<&'static str>,
+            key: &'static str,
+        ) -> Requests<RegisteredChannel<BaseChannel<(), String, Transport>, &'static str>> {
+            let (_, rx) = channel::unbounded();
+            let requests = registry
+                .register(key, BaseChannel::with_defaults(rx))
+                .requests();
+            requests.channel().attach_pusher(requests.pusher());
+            requests
+        }
+
+        let registry = Registry::new();
+        let mut alice = requests(&registry, "alice");
+        let mut bob = requests(&registry, "bob");
+        // Without a pusher, the channel can't be pushed to.
+        let (_, rx) = channel::unbounded::<ServerMessage<String>, ClientMessage<()>>();
+        let _carol = registry.register("carol", BaseChannel::with_defaults(rx));
+
+        assert_eq!(registry.push(&"alice", String::from("hi alice")), 1);
+        assert_eq!(registry.push(&"carol", String::from("hi carol")), 0);
+        // Pushers of other message types are skipped.
+        assert_eq!(registry.broadcast(7u32), 0);
+        assert_eq!(registry.broadcast(String::from("hi all")), 2);
+
+        let mut alice = Pin::new(&mut alice);
+        assert_matches!(
+            alice.pending_responses_mut().recv().await,
+            Some(ServerMessage::Push(msg)) if msg == "hi alice"
+        );
+        assert_matches!(
+            alice.pending_responses_mut().recv().await,
+            Some(ServerMessage::Push(msg)) if msg == "hi all"
+        );
+        assert_matches!(
+            Pin::new(&mut bob).pending_responses_mut().recv().await,
+            Some(ServerMessage::Push(msg)) if msg == "hi all"
+        );
+    }
+
+    #[test]
+    fn register_keys_incoming_channels() {
+        let registry = Registry::new();
+        let incoming = stream::iter(vec!["alice", "bob"]);
+        let mut register = Box::pin(Register::new(incoming, registry.clone(), |c: &&str| {
+            c.to_string()
+        }));
+        let alice = match register.as_mut().poll_next(&mut noop_context()) {
+            Poll::Ready(Some(channel)) => channel,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        assert_eq!(alice.key(), "alice");
+        assert_eq!(*alice.get_ref(), "alice");
+        assert_eq!(registry.keys(), ["alice".to_string()]);
+    }
+}
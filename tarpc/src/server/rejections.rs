@@ -0,0 +1,231 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+/// Why a request, or a channel, was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum RejectReason {
+    /// A limit on in-flight requests was reached.
+    Throttled,
+    /// The peer was not admitted by a [`PeerPolicy`](crate::server::limits::peers::PeerPolicy).
+    Filtered,
+    /// The service rejected the request before handling it, e.g. because it was malformed or
+    /// addressed to an unknown service.
+    Invalid,
+    /// The request's deadline expired before it was responded to.
+    DeadlineExceeded,
+    /// The client advised the request to be larger than the server admits.
+    TooLarge,
+    /// The request was failed on purpose, by a [fault rule](crate::server::faults::FaultRule) or
+    /// a failpoint.
+    Injected,
+    /// The response was larger than the server sends, so an error was sent in its place.
+    ResponseTooLarge,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RejectReason::Throttled => "throttled",
+            RejectReason::Filtered => "filtered",
+            RejectReason::Invalid => "invalid",
+            RejectReason::DeadlineExceeded => "deadline exceeded",
+            RejectReason::TooLarge => "too large",
+            RejectReason::Injected => "injected",
+            RejectReason::ResponseTooLarge => "response too large",
+        })
+    }
+}
+
+/// A request, or a channel, that was rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rejection {
+    /// The name of the method called, if known when the request was rejected.
+    pub method: Option<String>,
+    /// The address of the client, if known.
+    pub peer: Option<String>,
+    /// Why the request was rejected.
+    pub reason: RejectReason,
+    /// A description of the rejection, e.g. the error sent to the client.
+    pub detail: String,
+    /// When the request was rejected.
+    pub at: SystemTime,
+}
+
+impl Rejection {
+    /// Returns a rejection for `reason` made now, with no method, peer, or detail.
+    pub fn new(reason: RejectReason) -> Self {
+        Self {
+            method: None,
+            peer: None,
+            reason,
+            detail: String::new(),
+            at: crate::util::time::now(),
+        }
+    }
+
+    /// Sets the name of the method called.
+    pub fn with_method(mut self, method: impl Into<String>) -> Self {
+        self.method = Some(method.into());
+        self
+    }
+
+    /// Sets the address of the client.
+    pub fn with_peer(mut self, peer: impl fmt::Display) -> Self {
+        self.peer = Some(peer.to_string());
+        self
+    }
+
+    /// Sets the description of the rejection.
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = detail.into();
+        self
+    }
+}
+
+/// A bounded log of the most recent rejected requests, so that investigating disappearing calls
+/// doesn't require debug logging. Once full, each rejection evicts the oldest one. Cheap to
+/// clone; clones share their rejections.
+///
+/// Channels record their rejections in the log set in
+/// [`Config::rejections`](crate::server::Config::rejections), and
+/// [peer filters](crate::server::limits::peers::PeerPolicy::record_rejections) in the log they
+/// are given. The log is queried remotely through the [`Admin`](crate::admin::Admin) service.
+#[derive(Clone)]
+pub struct RejectionLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    capacity: usize,
+    rejections: VecDeque<Rejection>,
+    total: u64,
+}
+
+impl RejectionLog {
+    /// The number of rejections a [default](RejectionLog::default) log keeps.
+    pub const DEFAULT_CAPACITY: usize = 256;
+
+    /// Returns an empty log that keeps the last `capacity` rejections.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                rejections: VecDeque::with_capacity(capacity),
+                total: 0,
+            })),
+        }
+    }
+
+    /// Records `rejection`, evicting the oldest rejection if the log is full.
+    pub fn record(&self, rejection: Rejection) {
+        let mut inner = self.lock();
+        inner.total += 1;
+        if inner.capacity == 0 {
+            return;
+        }
+        if inner.rejections.len() == inner.capacity {
+            inner.rejections.pop_front();
+        }
+        inner.rejections.push_back(rejection);
+    }
+
+    /// Returns up to `limit` of the most recent rejections, newest first.
+    pub fn recent(&self, limit: usize) -> Vec<Rejection> {
+        self.lock()
+            .rejections
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the number of rejections in the log.
+    pub fn len(&self) -> usize {
+        self.lock().rejections.len()
+    }
+
+    /// Returns true if the log holds no rejections.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of rejections recorded since the log was created, including those
+    /// evicted since.
+    pub fn total(&self) -> u64 {
+        self.lock().total
+    }
+
+    /// Removes every rejection from the log.
+    pub fn clear(&self) {
+        self.lock().rejections.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for RejectionLog {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+impl fmt::Debug for RejectionLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.lock();
+        f.debug_struct("RejectionLog")
+            .field("capacity", &inner.capacity)
+            .field("len", &inner.rejections.len())
+            .field("total", &inner.total)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_keeps_most_recent_rejections() {
+        let log = RejectionLog::new(2);
+        assert!(log.is_empty());
+        for method in ["a", "b", "c"] {
+            log.record(Rejection::new(RejectReason::Throttled).with_method(method));
+        }
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.total(), 3);
+        let methods: Vec<_> = log
+            .recent(10)
+            .into_iter()
+            .map(|r| r.method.unwrap())
+            .collect();
+        assert_eq!(methods, ["c", "b"]);
+        assert_eq!(log.recent(1)[0].method.as_deref(), Some("c"));
+
+        log.clear();
+        assert!(log.is_empty());
+        assert_eq!(log.total(), 3);
+    }
+
+    #[test]
+    fn zero_capacity_log_only_counts() {
+        let log = RejectionLog::new(0);
+        log.record(Rejection::new(RejectReason::Invalid));
+        assert!(log.is_empty());
+        assert_eq!(log.total(), 1);
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides graceful shutdown of servers.
+
+use futures::{future::BoxFuture, prelude::*, task::*};
+use std::{fmt, sync::Arc, time::Duration};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+/// A handle to gracefully shut down a server: once [`shutdown`](Self::shutdown) is called, the
+/// server stops accepting channels, and each channel stops reading requests, finishes its
+/// in-flight requests, flushes their responses, and closes.
+///
+/// The handle applies to the channels created with a [`Config`](super::Config) holding it, and to
+/// a [`TokioServerExecutor`](super::tokio::TokioServerExecutor) given it with
+/// [`with_shutdown`](super::tokio::TokioServerExecutor::with_shutdown).
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    signal: CancellationToken,
+    /// The number of channels that haven't closed.
+    channels: Arc<watch::Sender<usize>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returned by [`Shutdown::shutdown`] when channels are still draining once the timeout elapses.
+#[derive(Clone, Copy, Debug, thiserror::Error)]
+#[error("{channels} channels were still draining when the shutdown timed out")]
+#[non_exhaustive]
+pub struct DrainTimeout {
+    /// The number of channels that hadn't closed.
+    pub channels: usize,
+}
+
+impl Shutdown {
+    /// Returns a handle for a server that isn't shutting down.
+    pub fn new() -> Self {
+        Self {
+            signal: CancellationToken::new(),
+            channels: Arc::new(watch::channel(0).0),
+        }
+    }
+
+    /// Returns true if the server is shutting down.
+    pub fn is_shutting_down(&self) -> bool {
+        self.signal.is_cancelled()
+    }
+
+    /// Returns the number of channels that haven't closed.
+    pub fn channels(&self) -> usize {
+        *self.channels.borrow()
+    }
+
+    /// Shuts down the server, and waits for every channel to close, or for `timeout` to elapse.
+    ///
+    /// Requests still in flight when the timeout elapses keep running; they're aborted when their
+    /// channels are dropped.
+    pub async fn shutdown(&self, timeout: Duration) -> Result<(), DrainTimeout> {
+        self.signal.cancel();
+        tracing::info!(channels = self.channels(), "Shutdown");
+        let mut channels = self.channels.subscribe();
+        let drained = async move {
+            while *channels.borrow_and_update() > 0 {
+                // The sender is held by `self`, so this can't fail.
+                let _ = channels.changed().await;
+            }
+        };
+        match tokio::time::timeout(timeout, drained).await {
+            Ok(()) => {
+                tracing::info!("Drained");
+                Ok(())
+            }
+            Err(_) => {
+                let channels = self.channels();
+                tracing::warn!(channels, "DrainTimeout");
+                Err(DrainTimeout { channels })
+            }
+        }
+    }
+
+    /// Returns the shutdown signal, to be polled by a channel or server.
+    pub(crate) fn signal(&self) -> Signal {
+        let signal = self.signal.clone();
+        Signal {
+            signaled: false,
+            wait: async move { signal.cancelled().await }.boxed(),
+        }
+    }
+
+    /// Counts a channel as open until the returned guard is dropped.
+    pub(crate) fn track_channel(&self) -> ChannelGuard {
+        self.channels.send_modify(|channels| *channels += 1);
+        ChannelGuard(self.channels.clone())
+    }
+}
+
+/// Completes when the server is shut down.
+pub(crate) struct Signal {
+    signaled: bool,
+    wait: BoxFuture<'static, ()>,
+}
+
+impl Signal {
+    /// Returns true if the server is shutting down, or else registers the task to be woken when
+    /// it is.
+    pub(crate) fn poll(&mut self, cx: &mut Context<'_>) -> bool {
+        if !self.signaled {
+            self.signaled = self.wait.poll_unpin(cx).is_ready();
+        }
+        self.signaled
+    }
+}
+
+impl fmt::Debug for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Signal")
+            .field("signaled", &self.signaled)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Counts a channel as open until dropped.
+#[derive(Debug)]
+pub(crate) struct ChannelGuard(Arc<watch::Sender<usize>>);
+
+impl Drop for ChannelGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|channels| *channels -= 1);
+    }
+}
@@ -7,8 +7,10 @@
 use crate::{
     cancellations::{cancellations, CanceledRequests, RequestCancellation},
     context,
-    server::{Channel, Config, ResponseGuard, TrackedRequest},
-    Request, Response,
+    server::{
+        Cancellation, Channel, Config, RequestStream, ResponseGuard, StreamCredits, TrackedRequest,
+    },
+    Request, ServerMessage,
 };
 use futures::{task::*, Sink, Stream};
 use pin_project::pin_project;
@@ -38,18 +40,26 @@ where
     }
 }
 
-impl<In, Resp> Sink<Response<Resp>> for FakeChannel<In, Response<Resp>> {
+impl<In, Resp> Sink<ServerMessage<Resp>> for FakeChannel<In, ServerMessage<Resp>> {
     type Error = io::Error;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
         self.project().sink.poll_ready(cx).map_err(|e| match e {})
     }
 
-    fn start_send(mut self: Pin<&mut Self>, response: Response<Resp>) -> Result<(), Self::Error> {
-        self.as_mut()
-            .project()
-            .in_flight_requests
-            .remove_request(response.request_id);
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        response: ServerMessage<Resp>,
+    ) -> Result<(), Self::Error> {
+        if let (false, Some(request_id)) = (
+            matches!(response, ServerMessage::StreamItem { .. }),
+            response.request_id(),
+        ) {
+            self.as_mut()
+                .project()
+                .in_flight_requests
+                .remove_request(request_id);
+        }
         self.project()
             .sink
             .start_send(response)
@@ -65,7 +75,7 @@ impl<In, Resp> Sink<Response<Resp>> for FakeChannel<In, Response<Resp>> {
     }
 }
 
-impl<Req, Resp> Channel for FakeChannel<io::Result<TrackedRequest<Req>>, Response<Resp>>
+impl<Req, Resp> Channel for FakeChannel<io::Result<TrackedRequest<Req>>, ServerMessage<Resp>>
 where
     Req: Unpin,
 {
@@ -86,7 +96,7 @@ where
     }
 }
 
-impl<Req, Resp> FakeChannel<io::Result<TrackedRequest<Req>>, Response<Resp>> {
+impl<Req, Resp> FakeChannel<io::Result<TrackedRequest<Req>>, ServerMessage<Resp>> {
     pub fn push_req(&mut self, id: u64, message: Req) {
         let (_, abort_registration) = futures::future::AbortHandle::new_pair();
         let (request_cancellation, _) = cancellations();
@@ -95,23 +105,34 @@ impl<Req, Resp> FakeChannel<io::Result<TrackedRequest<Req>>, Response<Resp>> {
                 context: context::Context {
                     deadline: SystemTime::UNIX_EPOCH,
                     trace_context: Default::default(),
+                    keep_alive: None,
+                    size_hint: Default::default(),
+                    transport: None,
+                    peer_addr: None,
                 },
                 id,
                 message,
             },
             abort_registration,
+            request_items: RequestStream::empty(),
+            stream_credits: StreamCredits::default(),
+            cancellation: Cancellation::default(),
+            peer_identity: None,
+            received: SystemTime::UNIX_EPOCH,
             span: Span::none(),
             response_guard: ResponseGuard {
                 request_cancellation,
                 request_id: id,
                 cancel: false,
             },
+            one_way: false,
         }));
     }
 }
 
 impl FakeChannel<(), ()> {
-    pub fn default<Req, Resp>() -> FakeChannel<io::Result<TrackedRequest<Req>>, Response<Resp>> {
+    pub fn default<Req, Resp>() -> FakeChannel<io::Result<TrackedRequest<Req>>, ServerMessage<Resp>>
+    {
         let (request_cancellation, canceled_requests) = cancellations();
         FakeChannel {
             stream: Default::default(),
@@ -1,23 +1,231 @@
-use super::{Channel, Requests, Serve};
+use super::{shutdown::Signal, Channel, Requests, Serve, Shutdown};
 use futures::{prelude::*, ready, task::*};
 use pin_project::pin_project;
-use std::pin::Pin;
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt,
+    hash::{Hash, Hasher},
+    pin::Pin,
+    sync::Arc,
+};
+use tokio::{
+    runtime::Handle,
+    sync::{watch, OwnedSemaphorePermit, Semaphore},
+};
+use tokio_util::sync::PollSemaphore;
 
 /// A future that drives the server by [spawning](tokio::spawn) a [`TokioChannelExecutor`](TokioChannelExecutor)
 /// for each new channel. Returned by
 /// [`Incoming::execute`](crate::server::incoming::Incoming::execute).
+///
+/// By default, channels are spawned on the runtime polling the executor. They can instead be
+/// [spread](TokioServerExecutor::spread) across several runtimes, e.g. to isolate noisy tenants
+/// on a runtime of their own, or to pin the channels of a listener to dedicated cores.
+///
+/// By default, every channel accepted is spawned. The number of channels served at once can be
+/// [bounded](TokioServerExecutor::max_channels), so that a small instance isn't overwhelmed by
+/// connections, and the [channel tasks](TokioServerExecutor::channel_tasks) can be awaited once the
+/// executor completes.
 #[must_use]
 #[pin_project]
 #[derive(Debug)]
-pub struct TokioServerExecutor<T, S> {
+pub struct TokioServerExecutor<T, S, P = RoundRobin> {
     #[pin]
     inner: T,
     serve: S,
+    inline_threshold: usize,
+    /// Completes when the server is shut down, if the executor was given a [`Shutdown`].
+    shutdown: Option<Signal>,
+    /// The runtimes channels are spawned on; if empty, the runtime polling the executor.
+    runtimes: Vec<Handle>,
+    placement: P,
+    /// Bounds the number of channels served at once, if set.
+    limit: Option<ChannelLimit>,
+    tasks: ChannelTasks,
+}
+
+/// What a [`TokioServerExecutor`] does with new channels when it already serves its
+/// [maximum](TokioServerExecutor::max_channels) number of channels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Overflow {
+    /// Stops accepting channels until a channel closes, leaving new connections queued in the
+    /// incoming stream, e.g. in the backlog of a listener.
+    Queue,
+    /// Accepts new channels, and drops them right away, closing their connections.
+    Refuse,
+}
+
+#[derive(Debug)]
+struct ChannelLimit {
+    semaphore: PollSemaphore,
+    overflow: Overflow,
+    /// A permit acquired for the next channel, while it's awaited.
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+/// A handle to the channel tasks spawned by a [`TokioServerExecutor`], to await them.
+#[derive(Clone, Debug)]
+pub struct ChannelTasks {
+    /// The number of channel tasks that haven't completed.
+    running: Arc<watch::Sender<usize>>,
+}
+
+impl ChannelTasks {
+    fn new() -> Self {
+        Self {
+            running: Arc::new(watch::channel(0).0),
+        }
+    }
+
+    /// Returns the number of channel tasks that haven't completed.
+    pub fn running(&self) -> usize {
+        *self.running.borrow()
+    }
+
+    /// Waits for every channel task to complete. Channels spawned while waiting are waited for
+    /// too, so this is usually called once the executor has completed, e.g. after a
+    /// [shutdown](TokioServerExecutor::with_shutdown).
+    pub async fn join(&self) {
+        let mut running = self.running.subscribe();
+        while *running.borrow_and_update() > 0 {
+            // The sender is held by `self`, so this can't fail.
+            let _ = running.changed().await;
+        }
+    }
+
+    /// Counts a channel task as running until the returned guard is dropped.
+    fn track(&self) -> ChannelTaskGuard {
+        self.running.send_modify(|running| *running += 1);
+        ChannelTaskGuard(self.running.clone())
+    }
+}
+
+struct ChannelTaskGuard(Arc<watch::Sender<usize>>);
+
+impl Drop for ChannelTaskGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|running| *running -= 1);
+    }
+}
+
+/// Decides which of the runtimes of a [`TokioServerExecutor`] each new channel is spawned on.
+pub trait Placement<C> {
+    /// Returns the index of the runtime `channel` is spawned on, less than `runtimes`, which is
+    /// never 0.
+    fn place(&mut self, channel: &C, runtimes: usize) -> usize;
+}
+
+/// Spawns channels on each runtime in turn.
+#[derive(Clone, Debug, Default)]
+pub struct RoundRobin {
+    next: usize,
+}
+
+impl<C> Placement<C> for RoundRobin {
+    fn place(&mut self, _: &C, runtimes: usize) -> usize {
+        let runtime = self.next % runtimes;
+        self.next = runtime + 1;
+        runtime
+    }
+}
+
+/// Spawns channels on the runtime picked by hashing the key returned by a keymaker, so that all
+/// channels with the same key, e.g. of the same tenant, run on the same runtime.
+#[derive(Clone)]
+pub struct ByKey<KF> {
+    keymaker: KF,
+}
+
+impl<KF> fmt::Debug for ByKey<KF> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ByKey")
+    }
+}
+
+impl<C, K, KF> Placement<C> for ByKey<KF>
+where
+    K: Hash,
+    KF: Fn(&C) -> K,
+{
+    fn place(&mut self, channel: &C, runtimes: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        (self.keymaker)(channel).hash(&mut hasher);
+        (hasher.finish() % runtimes as u64) as usize
+    }
 }
 
 impl<T, S> TokioServerExecutor<T, S> {
     pub(crate) fn new(inner: T, serve: S) -> Self {
-        Self { inner, serve }
+        Self {
+            inner,
+            serve,
+            inline_threshold: 0,
+            shutdown: None,
+            runtimes: Vec::new(),
+            placement: RoundRobin::default(),
+            limit: None,
+            tasks: ChannelTasks::new(),
+        }
+    }
+}
+
+impl<T, S, P> TokioServerExecutor<T, S, P> {
+    /// Spawns each channel, and the handlers of its requests, on one of `runtimes`, in turn
+    /// unless the executor places channels [by key](TokioServerExecutor::spread_by_key).
+    /// If `runtimes` is empty, channels are spawned on the runtime polling the executor.
+    pub fn spread(mut self, runtimes: Vec<Handle>) -> Self {
+        self.runtimes = runtimes;
+        self
+    }
+
+    /// Spawns each channel, and the handlers of its requests, on one of `runtimes` picked by the
+    /// key `keymaker` returns for the channel. Channels with the same key run on the same runtime.
+    pub fn spread_by_key<KF>(
+        self,
+        runtimes: Vec<Handle>,
+        keymaker: KF,
+    ) -> TokioServerExecutor<T, S, ByKey<KF>> {
+        TokioServerExecutor {
+            inner: self.inner,
+            serve: self.serve,
+            inline_threshold: self.inline_threshold,
+            shutdown: self.shutdown,
+            runtimes,
+            placement: ByKey { keymaker },
+            limit: self.limit,
+            tasks: self.tasks,
+        }
+    }
+
+    /// Serves at most `max_channels` channels at once. Once reached, new channels are queued or
+    /// refused, per `overflow`, until a channel closes.
+    pub fn max_channels(mut self, max_channels: usize, overflow: Overflow) -> Self {
+        self.limit = Some(ChannelLimit {
+            semaphore: PollSemaphore::new(Arc::new(Semaphore::new(max_channels))),
+            overflow,
+            permit: None,
+        });
+        self
+    }
+
+    /// Returns a handle to await the channel tasks spawned by the executor.
+    pub fn channel_tasks(&self) -> ChannelTasks {
+        self.tasks.clone()
+    }
+
+    /// Stops accepting channels once `shutdown` is [shut down](Shutdown::shutdown), completing
+    /// the executor. The channels already accepted keep running until they've drained, if they
+    /// were created with a [`Config`](super::Config) holding `shutdown`.
+    pub fn with_shutdown(mut self, shutdown: &Shutdown) -> Self {
+        self.shutdown = Some(shutdown.signal());
+        self
+    }
+
+    /// Sets the [inline threshold](TokioChannelExecutor::inline_threshold) of each channel.
+    pub fn inline_threshold(mut self, inline_threshold: usize) -> Self {
+        self.inline_threshold = inline_threshold;
+        self
     }
 }
 
@@ -31,9 +239,11 @@ pub struct TokioChannelExecutor<T, S> {
     #[pin]
     inner: T,
     serve: S,
+    /// The most requests per poll whose handlers are polled inline before being spawned.
+    inline_threshold: usize,
 }
 
-impl<T, S> TokioServerExecutor<T, S> {
+impl<T, S, P> TokioServerExecutor<T, S, P> {
     fn inner_pin_mut<'a>(self: &'a mut Pin<&mut Self>) -> Pin<&'a mut T> {
         self.as_mut().project().inner
     }
@@ -43,6 +253,17 @@ impl<T, S> TokioChannelExecutor<T, S> {
     fn inner_pin_mut<'a>(self: &'a mut Pin<&mut Self>) -> Pin<&'a mut T> {
         self.as_mut().project().inner
     }
+
+    /// Sets how many requests, each time the executor is polled, have their handler polled once
+    /// inline before it is spawned. A handler that completes on its first poll, e.g. a lookup in
+    /// an in-memory map, then doesn't pay for spawning a task. Handlers polled inline run on the
+    /// channel's task, delaying other requests of the channel, so they shouldn't block.
+    ///
+    /// Defaults to 0, i.e. every handler is spawned.
+    pub fn inline_threshold(mut self, inline_threshold: usize) -> Self {
+        self.inline_threshold = inline_threshold;
+        self
+    }
 }
 
 // Send + 'static execution helper methods.
@@ -59,11 +280,15 @@ where
     where
         S: Serve<C::Req, Resp = C::Resp> + Send + 'static,
     {
-        TokioChannelExecutor { inner: self, serve }
+        TokioChannelExecutor {
+            inner: self,
+            serve,
+            inline_threshold: 0,
+        }
     }
 }
 
-impl<St, C, Se> Future for TokioServerExecutor<St, Se>
+impl<St, C, Se, P> Future for TokioServerExecutor<St, Se, P>
 where
     St: Sized + Stream<Item = C>,
     C: Channel + Send + 'static,
@@ -71,12 +296,60 @@ where
     C::Resp: Send + 'static,
     Se: Serve<C::Req, Resp = C::Resp> + Send + 'static + Clone,
     Se::Fut: Send,
+    P: Placement<C>,
 {
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
-        while let Some(channel) = ready!(self.inner_pin_mut().poll_next(cx)) {
-            tokio::spawn(channel.execute(self.serve.clone()));
+        loop {
+            if let Some(shutdown) = self.as_mut().project().shutdown {
+                if shutdown.poll(cx) {
+                    break;
+                }
+            }
+            if let Some(limit) = self.as_mut().project().limit {
+                if limit.overflow == Overflow::Queue && limit.permit.is_none() {
+                    // The semaphore is never closed.
+                    limit.permit = ready!(limit.semaphore.poll_acquire(cx));
+                }
+            }
+            let channel = match ready!(self.inner_pin_mut().poll_next(cx)) {
+                Some(channel) => channel,
+                None => break,
+            };
+            let permit = match self.as_mut().project().limit {
+                Some(limit) => match limit.permit.take() {
+                    Some(permit) => Some(permit),
+                    None => match limit.semaphore.clone_inner().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            tracing::warn!("RefuseChannel");
+                            continue;
+                        }
+                    },
+                },
+                None => None,
+            };
+            let guard = self.tasks.track();
+            let runtime = match self.runtimes.len() {
+                0 => None,
+                runtimes => {
+                    let this = self.as_mut().project();
+                    Some(this.placement.place(&channel, runtimes))
+                }
+            };
+            let executor = channel
+                .execute(self.serve.clone())
+                .inline_threshold(self.inline_threshold)
+                .map(move |()| drop((permit, guard)));
+            match runtime {
+                Some(runtime) => {
+                    self.runtimes[runtime].spawn(executor);
+                }
+                None => {
+                    tokio::spawn(executor);
+                }
+            }
         }
         tracing::info!("Server shutting down.");
         Poll::Ready(())
@@ -94,8 +367,18 @@ where
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inlined = 0;
         while let Some(response_handler) = ready!(self.inner_pin_mut().poll_next(cx)) {
             match response_handler {
+                Ok(resp) if inlined < self.inline_threshold => {
+                    inlined += 1;
+                    let mut execute = Box::pin(resp.execute(self.serve.clone()));
+                    // A handler still pending is polled again by its task once spawned, which
+                    // registers the task to be woken in place of this one.
+                    if execute.poll_unpin(cx).is_pending() {
+                        tokio::spawn(execute);
+                    }
+                }
                 Ok(resp) => {
                     let server = self.serve.clone();
                     tokio::spawn(async move {
@@ -0,0 +1,222 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{
+    context,
+    server::{Config, RequestStream, Serve, Served},
+    ServerError,
+};
+use futures::{future, prelude::*, task::*};
+use std::{error::Error, io, pin::Pin};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// A request as seen by tower middleware around a [`Serve`] implementation.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ServeRequest<Req> {
+    /// The request context.
+    pub context: context::Context,
+    /// The request message.
+    pub message: Req,
+    /// The items the client sends after a client-streaming request.
+    pub items: RequestStream<Req>,
+}
+
+/// A tower [`Service`] that handles requests with a [`Serve`] implementation. It is the
+/// innermost service of the middleware stack built by [`Layered::new`].
+///
+/// Server-streaming requests cannot be expressed as a single tower response, so they are rejected
+/// with [`io::ErrorKind::Unsupported`].
+#[derive(Clone, Debug)]
+pub struct ServeService<S> {
+    serve: S,
+}
+
+impl<S> ServeService<S> {
+    /// Returns a new service that handles requests with `serve`.
+    pub fn new(serve: S) -> Self {
+        Self { serve }
+    }
+}
+
+impl<S, Req> Service<ServeRequest<Req>> for ServeService<S>
+where
+    S: Serve<Req> + Clone,
+    S::Resp: Send + 'static,
+    S::Fut: Send + 'static,
+{
+    type Response = S::Resp;
+    type Error = ServerError;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Resp, ServerError>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), ServerError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: ServeRequest<Req>) -> Self::Future {
+        let ServeRequest {
+            context,
+            message,
+            items,
+        } = request;
+        match self.serve.clone().serve_with_items(context, message, items) {
+            Served::Response(response) => Box::pin(response.map(Ok)),
+            Served::Fallible(response) => response,
+            Served::Error(error) => Box::pin(future::ready(Err(error))),
+            Served::Stream(_) => Box::pin(future::ready(Err(ServerError::unimplemented(
+                "server-streaming requests cannot be handled by tower middleware",
+            )))),
+        }
+    }
+}
+
+/// A [`Serve`] implementation that passes requests through a stack of tower middleware before
+/// handing them to the wrapped [`Serve`] implementation.
+///
+/// ```rust
+/// # use futures::future;
+/// # use tarpc::{context, server::tower::Layered};
+/// # use tower::limit::ConcurrencyLimitLayer;
+/// let serve = |_: context::Context, i: i32| future::ready(i + 1);
+/// // At most 10 requests are handled at once, across all channels using this Serve.
+/// let serve = Layered::new(ConcurrencyLimitLayer::new(10), serve);
+/// ```
+///
+/// Errors returned by middleware are sent to the client as a [`ServerError`]. A `ServerError`
+/// returned by middleware is sent as is; any other error is sent with
+/// [`io::ErrorKind::Other`] and the error's message. [`Serve::serve`] resolves to the same
+/// error, so stacks can be nested.
+#[derive(Clone, Debug)]
+pub struct Layered<S, T> {
+    serve: S,
+    service: T,
+}
+
+impl<S, T> Layered<S, T> {
+    /// Returns a new `Serve` that wraps `serve` in the middleware produced by `layer`.
+    pub fn new<L>(layer: L, serve: S) -> Self
+    where
+        S: Clone,
+        L: Layer<ServeService<S>, Service = T>,
+    {
+        let service = layer.layer(ServeService::new(serve.clone()));
+        Self { serve, service }
+    }
+}
+
+impl<S, T, Req> Serve<Req> for Layered<S, T>
+where
+    S: Serve<Req>,
+    S::Resp: 'static,
+    T: Service<ServeRequest<Req>, Response = S::Resp> + Send + 'static,
+    T::Error: Into<Box<dyn Error + Send + Sync>>,
+    T::Future: Send,
+    Req: Send + 'static,
+{
+    type Resp = S::Resp;
+    type Fut = future::Pending<S::Resp>;
+
+    fn method(&self, request: &Req) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    fn config(&self, request: &Req) -> Option<&Config> {
+        self.serve.config(request)
+    }
+
+    fn serve_with_items(
+        self,
+        ctx: context::Context,
+        req: Req,
+        items: RequestStream<Req>,
+    ) -> Served<Self::Fut> {
+        Served::Fallible(self.call(ctx, req, items))
+    }
+}
+
+impl<S, T> Layered<S, T> {
+    fn call<Req>(
+        self,
+        ctx: context::Context,
+        req: Req,
+        items: RequestStream<Req>,
+    ) -> Pin<Box<dyn Future<Output = Result<T::Response, ServerError>> + Send>>
+    where
+        T: Service<ServeRequest<Req>> + Send + 'static,
+        T::Error: Into<Box<dyn Error + Send + Sync>>,
+        T::Future: Send,
+        Req: Send + 'static,
+    {
+        let mut service = self.service;
+        Box::pin(async move {
+            future::poll_fn(|cx| service.poll_ready(cx))
+                .await
+                .map_err(into_server_error)?;
+            service
+                .call(ServeRequest {
+                    context: ctx,
+                    message: req,
+                    items,
+                })
+                .await
+                .map_err(into_server_error)
+        })
+    }
+}
+
+fn into_server_error(error: impl Into<Box<dyn Error + Send + Sync>>) -> ServerError {
+    match error.into().downcast::<ServerError>() {
+        Ok(error) => *error,
+        Err(error) => ServerError::new(io::ErrorKind::Other, error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tower::{limit::ConcurrencyLimitLayer, timeout::TimeoutLayer};
+
+    #[tokio::test]
+    async fn layered_serve_responds() {
+        let serve = |_: context::Context, i: i32| future::ready(i + 1);
+        let serve = Layered::new(ConcurrencyLimitLayer::new(1), serve);
+
+        assert_eq!(serve.serve(context::current(), 1).await, Ok(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn layered_serve_converts_middleware_errors() {
+        let serve = |_: context::Context, ()| future::pending::<()>();
+        let serve = Layered::new(TimeoutLayer::new(Duration::from_secs(1)), serve);
+
+        match serve.serve_with_items(context::current(), (), RequestStream::empty()) {
+            Served::Fallible(response) => {
+                let error = response.await.unwrap_err();
+                assert_eq!(error.kind, io::ErrorKind::Other);
+            }
+            served => panic!("unexpected {served:?}"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn layered_serve_times_out_under_serve() {
+        let serve = |_: context::Context, ()| future::pending::<()>();
+        let serve = Layered::new(TimeoutLayer::new(Duration::from_secs(1)), serve);
+        let error = serve
+            .clone()
+            .serve(context::current(), ())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind, io::ErrorKind::Other);
+
+        // The timeout of an inner stack is sent through the outer stack.
+        let serve = Layered::new(ConcurrencyLimitLayer::new(1), serve);
+        let error = serve.serve(context::current(), ()).await.unwrap_err();
+        assert_eq!(error.kind, io::ErrorKind::Other);
+    }
+}
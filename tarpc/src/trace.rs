@@ -31,6 +31,11 @@ use tracing_opentelemetry::OpenTelemetrySpanExt;
 /// that triggered the current span, and a trace with which all related spans are associated.
 #[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 pub struct Context {
     /// An identifier of the trace associated with the current context. A trace ID is typically
     /// created at a root span and passed along through all causal events.
@@ -53,11 +58,21 @@ pub struct Context {
 /// same trace ID.
 #[derive(Default, PartialEq, Eq, Hash, Clone, Copy)]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 pub struct TraceId(#[cfg_attr(feature = "serde1", serde(with = "u128_serde"))] u128);
 
 /// A 64-bit identifier of a span within a trace. The identifier is unique within the span's trace.
 #[derive(Default, PartialEq, Eq, Hash, Clone, Copy)]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 pub struct SpanId(u64);
 
 /// Indicates whether a sampler has decided whether or not to sample the trace associated with the
@@ -68,6 +83,11 @@ pub struct SpanId(u64);
 /// Otherwise, the full trace would not be able to be reconstructed reliably.
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize),
+    archive(check_bytes)
+)]
 #[repr(u8)]
 pub enum SamplingDecision {
     /// The associated span was sampled by its creating process. Child spans must also be sampled.
@@ -85,6 +105,61 @@ impl Context {
             sampling_decision: self.sampling_decision,
         }
     }
+
+    /// Returns true if the trace was [sampled](SamplingDecision::Sampled) by an upstream process.
+    pub fn is_sampled(&self) -> bool {
+        self.sampling_decision == SamplingDecision::Sampled
+    }
+
+    /// Returns the context as a [W3C `traceparent`](https://www.w3.org/TR/trace-context/#traceparent-header)
+    /// header value, e.g. `00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01`, or None if
+    /// the trace or span ID is none, which `traceparent` can't represent.
+    pub fn to_traceparent(&self) -> Option<String> {
+        if self.trace_id.is_none() || self.span_id.is_none() {
+            return None;
+        }
+        let flags = match self.sampling_decision {
+            SamplingDecision::Sampled => 1,
+            SamplingDecision::Unsampled => 0,
+        };
+        Some(format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id.0, self.span_id.0, flags
+        ))
+    }
+
+    /// Parses a [W3C `traceparent`](https://www.w3.org/TR/trace-context/#traceparent-header)
+    /// header value. Fields appended by versions after `00` are ignored.
+    pub fn from_traceparent(traceparent: &str) -> Result<Self, InvalidTraceparent> {
+        let mut parts = traceparent.trim().split('-');
+        let mut next = || parts.next().ok_or(InvalidTraceparent);
+        let (version, trace_id, span_id, flags) = (next()?, next()?, next()?, next()?);
+        // Version 00 has exactly four parts; later versions may append more.
+        if version.len() != 2
+            || version == "ff"
+            || (version == "00" && parts.next().is_some())
+            || trace_id.len() != 32
+            || span_id.len() != 16
+            || flags.len() != 2
+        {
+            return Err(InvalidTraceparent);
+        }
+        let hex = |part| u128::from_str_radix(part, 16).map_err(|_| InvalidTraceparent);
+        hex(version)?;
+        let (trace_id, span_id, flags) = (hex(trace_id)?, hex(span_id)?, hex(flags)?);
+        if trace_id == 0 || span_id == 0 {
+            return Err(InvalidTraceparent);
+        }
+        Ok(Self {
+            trace_id: TraceId(trace_id),
+            span_id: SpanId(span_id as u64),
+            sampling_decision: if flags & 1 == 1 {
+                SamplingDecision::Sampled
+            } else {
+                SamplingDecision::Unsampled
+            },
+        })
+    }
 }
 
 impl TraceId {
@@ -175,15 +250,33 @@ impl TryFrom<&tracing::Span> for Context {
 
 impl From<opentelemetry::trace::SpanRef<'_>> for Context {
     fn from(span: opentelemetry::trace::SpanRef<'_>) -> Self {
-        let otel_ctx = span.span_context();
+        Self::from(span.span_context())
+    }
+}
+
+impl From<&opentelemetry::trace::SpanContext> for Context {
+    fn from(span_context: &opentelemetry::trace::SpanContext) -> Self {
         Self {
-            trace_id: TraceId::from(otel_ctx.trace_id()),
-            span_id: SpanId::from(otel_ctx.span_id()),
-            sampling_decision: SamplingDecision::from(otel_ctx),
+            trace_id: TraceId::from(span_context.trace_id()),
+            span_id: SpanId::from(span_context.span_id()),
+            sampling_decision: SamplingDecision::from(span_context),
         }
     }
 }
 
+impl From<Context> for opentelemetry::trace::SpanContext {
+    /// Returns a remote span context, as the context of a span created by another process.
+    fn from(context: Context) -> Self {
+        opentelemetry::trace::SpanContext::new(
+            opentelemetry::trace::TraceId::from(context.trace_id),
+            opentelemetry::trace::SpanId::from(context.span_id),
+            opentelemetry::trace::TraceFlags::from(context.sampling_decision),
+            true,
+            opentelemetry::trace::TraceState::default(),
+        )
+    }
+}
+
 impl From<SamplingDecision> for opentelemetry::trace::TraceFlags {
     fn from(decision: SamplingDecision) -> Self {
         match decision {
@@ -213,6 +306,11 @@ impl Default for SamplingDecision {
 #[derive(Debug)]
 pub struct NoActiveSpan;
 
+/// Returned when a [`Context`] cannot be parsed from a `traceparent` header value.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[error("invalid traceparent")]
+pub struct InvalidTraceparent;
+
 impl fmt::Display for TraceId {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
         write!(f, "{:02x}", self.0)?;
@@ -259,3 +357,54 @@ mod u128_serde {
         )?))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparent_round_trips() {
+        let context =
+            Context::from_traceparent("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+                .unwrap();
+        assert_eq!(
+            context,
+            Context {
+                trace_id: TraceId(0x0af7651916cd43dd8448eb211c80319c),
+                span_id: SpanId(0xb7ad6b7169203331),
+                sampling_decision: SamplingDecision::Sampled,
+            }
+        );
+        assert_eq!(
+            context.to_traceparent().unwrap(),
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+        );
+        // Later versions may append fields.
+        assert_eq!(
+            Context::from_traceparent(
+                "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00-extra"
+            )
+            .unwrap()
+            .sampling_decision,
+            SamplingDecision::Unsampled
+        );
+        assert_eq!(Context::default().to_traceparent(), None);
+        assert_eq!(
+            Context::from_traceparent("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331"),
+            Err(InvalidTraceparent)
+        );
+    }
+
+    #[test]
+    fn span_context_round_trips() {
+        let context = Context {
+            trace_id: TraceId(7),
+            span_id: SpanId(8),
+            sampling_decision: SamplingDecision::Sampled,
+        };
+        let span_context = opentelemetry::trace::SpanContext::from(context);
+        assert!(span_context.is_remote());
+        assert!(span_context.is_sampled());
+        assert_eq!(Context::from(&span_context), context);
+    }
+}
@@ -10,6 +10,151 @@
 //! can be plugged in, using whatever protocol it wants.
 
 pub mod channel;
+#[cfg(feature = "checksum")]
+#[cfg_attr(docsrs, doc(cfg(feature = "checksum")))]
+pub mod checksum;
+#[cfg(any(feature = "compression-zstd", feature = "compression-lz4"))]
+#[cfg_attr(
+    docsrs,
+    doc(cfg(any(feature = "compression-zstd", feature = "compression-lz4")))
+)]
+pub mod compression;
+#[cfg(any(
+    feature = "checksum",
+    feature = "compression-zstd",
+    feature = "compression-lz4",
+    feature = "json-rpc"
+))]
+#[cfg_attr(
+    docsrs,
+    doc(cfg(any(
+        feature = "checksum",
+        feature = "compression-zstd",
+        feature = "compression-lz4",
+        feature = "json-rpc"
+    )))
+)]
+pub mod frames;
+#[cfg(feature = "protobuf")]
+#[cfg_attr(docsrs, doc(cfg(feature = "protobuf")))]
+pub mod protobuf;
+pub mod replay;
+
+use std::{error::Error, io};
+
+/// An error decoding a single message received over a transport, after which the transport can
+/// still receive the messages that follow, e.g. because the frame was read in full but its
+/// payload didn't deserialize.
+///
+/// Transports whose errors are [`io::Error`]s report such errors with a `DecodeError` as the
+/// [inner error](io::Error::get_ref), so that a [channel](crate::server::BaseChannel) configured
+/// to [skip](crate::server::DecodeErrorPolicy::Skip) them can tell them apart from errors that
+/// leave the transport unusable.
+#[derive(Debug, thiserror::Error)]
+#[error("message could not be decoded: {source}")]
+pub struct DecodeError {
+    request_id: Option<u64>,
+    #[source]
+    source: Box<dyn Error + Send + Sync>,
+}
+
+impl DecodeError {
+    /// Returns an error for a message that failed to decode because of `source`.
+    pub fn new(source: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        Self {
+            request_id: None,
+            source: source.into(),
+        }
+    }
+
+    /// Sets the ID of the request the message carried, when it could be recovered from the part
+    /// of the message that did decode.
+    pub fn with_request_id(mut self, request_id: u64) -> Self {
+        self.request_id = Some(request_id);
+        self
+    }
+
+    /// Returns the ID of the request the message carried, if it was recovered.
+    pub fn request_id(&self) -> Option<u64> {
+        self.request_id
+    }
+
+    /// Returns the decode error in the chain of `error`, if any, looking through the inner errors
+    /// of [`io::Error`]s.
+    pub fn find<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a DecodeError> {
+        find(error)
+    }
+}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> Self {
+        // Keeps the kind of the source, so that wrapping codec errors doesn't change how they're
+        // classified.
+        let kind = match e.source.downcast_ref::<io::Error>() {
+            Some(source) => source.kind(),
+            None => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// An error for a message that decoded, but violates the protocol, e.g. a request without a
+/// context. The transport can still receive the messages that follow, but the peer that sent it
+/// is not to be trusted with more.
+///
+/// As with [`DecodeError`], transports whose errors are [`io::Error`]s report such errors with a
+/// `ProtocolError` as the inner error, so that channels can tell them apart.
+#[derive(Debug, thiserror::Error)]
+#[error("message violates the protocol: {detail}")]
+pub struct ProtocolError {
+    detail: String,
+}
+
+impl ProtocolError {
+    /// Returns an error for a message that violates the protocol as described by `detail`.
+    pub fn new(detail: impl Into<String>) -> Self {
+        Self {
+            detail: detail.into(),
+        }
+    }
+
+    /// Returns the description of the violation.
+    pub fn detail(&self) -> &str {
+        &self.detail
+    }
+
+    /// Returns the protocol error in the chain of `error`, if any, looking through the inner
+    /// errors of [`io::Error`]s.
+    pub fn find<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a ProtocolError> {
+        find(error)
+    }
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(e: ProtocolError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Returns the error of type `E` in the chain of `error`, if any. The inner error of an
+/// `io::Error` isn't its source, so it's checked along the way.
+fn find<'a, E: Error + 'static>(error: &'a (dyn Error + 'static)) -> Option<&'a E> {
+    let mut next = Some(error);
+    while let Some(error) = next {
+        if let Some(error) = error.downcast_ref::<E>() {
+            return Some(error);
+        }
+        let inner = error
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::get_ref)
+            .and_then(|inner| inner.downcast_ref::<E>());
+        if inner.is_some() {
+            return inner;
+        }
+        next = error.source();
+    }
+    None
+}
 
 pub(crate) mod sealed {
     use futures::prelude::*;
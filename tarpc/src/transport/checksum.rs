@@ -0,0 +1,232 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A transport combinator that checks the integrity of frames.
+//!
+//! [`Checksummed`] wraps a [transport of frames](super::frames) and appends a checksum to each
+//! frame sent. A received frame whose checksum doesn't match is rejected with a
+//! [`ChecksumError`], rather than failing, or worse, succeeding, to deserialize.
+//!
+//! Both peers must wrap their transports with the same [`Checksum`]. Checksums detect accidental
+//! corruption, e.g. by faulty hardware or a buggy proxy; they don't authenticate frames, which
+//! requires a MAC or an encrypted transport.
+
+use super::frames::invalid_data;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{prelude::*, ready};
+use pin_project::pin_project;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A checksum algorithm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Checksum {
+    /// CRC-32 (IEEE), appended as 4 little-endian bytes.
+    Crc32,
+    /// The 64-bit [XXH3](https://github.com/Cyan4973/xxHash) hash, appended as 8 little-endian
+    /// bytes. Faster than CRC-32 on large frames.
+    Xxh3,
+}
+
+impl Checksum {
+    /// The number of bytes the checksum adds to each frame.
+    pub fn size(self) -> usize {
+        match self {
+            Checksum::Crc32 => 4,
+            Checksum::Xxh3 => 8,
+        }
+    }
+
+    fn compute(self, frame: &[u8]) -> u64 {
+        match self {
+            Checksum::Crc32 => crc32fast::hash(frame).into(),
+            Checksum::Xxh3 => xxhash_rust::xxh3::xxh3_64(frame),
+        }
+    }
+}
+
+/// A frame was rejected because it failed its integrity check.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChecksumError {
+    /// The frame is too short to hold a checksum.
+    #[error("frame of {len} bytes is too short to hold a {checksum:?} checksum")]
+    Truncated {
+        /// The checksum algorithm.
+        checksum: Checksum,
+        /// The length of the frame.
+        len: usize,
+    },
+    /// The checksum sent with the frame doesn't match its contents.
+    #[error("frame {checksum:?} checksum {actual:#x} doesn't match the expected {expected:#x}")]
+    Mismatch {
+        /// The checksum algorithm.
+        checksum: Checksum,
+        /// The checksum sent with the frame.
+        expected: u64,
+        /// The checksum of the frame as received.
+        actual: u64,
+    },
+}
+
+impl From<ChecksumError> for io::Error {
+    fn from(e: ChecksumError) -> Self {
+        invalid_data(e)
+    }
+}
+
+/// A transport of frames that carry checksums. See the [module docs](self).
+#[pin_project]
+#[derive(Debug)]
+pub struct Checksummed<T> {
+    #[pin]
+    inner: T,
+    checksum: Checksum,
+}
+
+impl<T> Checksummed<T> {
+    /// Returns a transport that appends a `checksum` to the frames sent over `inner`, and checks
+    /// the frames received.
+    pub fn new(inner: T, checksum: Checksum) -> Self {
+        Self { inner, checksum }
+    }
+
+    /// Returns the inner transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T, E> Stream for Checksummed<T>
+where
+    T: Stream<Item = Result<BytesMut, E>>,
+    E: From<ChecksumError>,
+{
+    type Item = Result<BytesMut, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let checksum = *this.checksum;
+        let mut frame = match ready!(this.inner.poll_next(cx)?) {
+            Some(frame) => frame,
+            None => return Poll::Ready(None),
+        };
+        let len = frame.len();
+        if len < checksum.size() {
+            return Poll::Ready(Some(Err(ChecksumError::Truncated { checksum, len }.into())));
+        }
+        let trailer = frame.split_off(len - checksum.size());
+        let mut expected = [0; 8];
+        expected[..trailer.len()].copy_from_slice(&trailer);
+        let expected = u64::from_le_bytes(expected);
+        let actual = checksum.compute(&frame);
+        if expected != actual {
+            return Poll::Ready(Some(Err(ChecksumError::Mismatch {
+                checksum,
+                expected,
+                actual,
+            }
+            .into())));
+        }
+        Poll::Ready(Some(Ok(frame)))
+    }
+}
+
+impl<T> Sink<Bytes> for Checksummed<T>
+where
+    T: Sink<Bytes>,
+{
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Bytes) -> Result<(), T::Error> {
+        let this = self.project();
+        let checksum = this.checksum.compute(&frame).to_le_bytes();
+        let mut out = BytesMut::with_capacity(frame.len() + this.checksum.size());
+        out.put_slice(&frame);
+        out.put_slice(&checksum[..this.checksum.size()]);
+        this.inner.start_send(out.freeze())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+/// Extends transports of frames with [`checksummed`](ChecksumExt::checksummed).
+pub trait ChecksumExt: Sized {
+    /// Returns a transport that appends a `checksum` to the frames sent over `self`, and checks
+    /// the frames received.
+    fn checksummed(self, checksum: Checksum) -> Checksummed<Self> {
+        Checksummed::new(self, checksum)
+    }
+}
+
+impl<T> ChecksumExt for T where T: Sink<Bytes> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::frames::framed;
+    use assert_matches::assert_matches;
+    use tokio::io::duplex;
+
+    fn checksum_error(e: &io::Error) -> Option<&ChecksumError> {
+        e.get_ref()?.downcast_ref()
+    }
+
+    #[tokio::test]
+    async fn round_trip() -> io::Result<()> {
+        for checksum in [Checksum::Crc32, Checksum::Xxh3] {
+            let (a, b) = duplex(1024);
+            let mut a = framed(a).checksummed(checksum);
+            let mut b = framed(b).checksummed(checksum);
+
+            a.send(Bytes::from_static(b"hello")).await?;
+            assert_eq!(b.next().await.unwrap()?[..], *b"hello");
+            b.send(Bytes::new()).await?;
+            assert_eq!(a.next().await.unwrap()?[..], *b"");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_corrupted_frames() -> io::Result<()> {
+        let (a, b) = duplex(1024);
+        let mut a = framed(a);
+        let mut b = framed(b).checksummed(Checksum::Crc32);
+
+        let mut frame = BytesMut::from(&b"hello"[..]);
+        frame.put_u32_le(crc32fast::hash(b"hello"));
+        frame[0] = b'j';
+        a.send(frame.freeze()).await?;
+        assert_matches!(
+            b.next().await,
+            Some(Err(e)) if matches!(checksum_error(&e), Some(ChecksumError::Mismatch { .. }))
+        );
+
+        a.send(Bytes::from_static(b"abc")).await?;
+        assert_matches!(
+            b.next().await,
+            Some(Err(e)) if checksum_error(&e) == Some(&ChecksumError::Truncated {
+                checksum: Checksum::Crc32,
+                len: 3,
+            })
+        );
+        Ok(())
+    }
+}
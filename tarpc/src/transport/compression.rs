@@ -0,0 +1,350 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A transport combinator that compresses frames.
+//!
+//! [`Compressed`] wraps a [transport of frames](super::frames) and compresses the frames sent
+//! that are at least [`CompressionConfig::threshold`] bytes long, tagging each frame with how
+//! it's encoded.
+//!
+//! Both peers must wrap their transports. The algorithm is negotiated per channel: each side
+//! starts by announcing the algorithms it can decompress, then compresses with the first
+//! algorithm in its [`CompressionConfig::algorithms`] that the peer announced. Until the peer's
+//! announcement arrives, frames are sent uncompressed.
+
+use super::frames::invalid_data;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{prelude::*, ready};
+use pin_project::pin_project;
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The first byte of a frame, identifying how the rest of the frame is encoded.
+mod tag {
+    pub const UNCOMPRESSED: u8 = 0;
+    #[cfg(feature = "compression-zstd")]
+    pub const ZSTD: u8 = 1;
+    #[cfg(feature = "compression-lz4")]
+    pub const LZ4: u8 = 2;
+    /// Announces the algorithms the sender can decompress, as a bitmask of their tags.
+    pub const HELLO: u8 = 0xff;
+}
+
+/// A compression algorithm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Algorithm {
+    /// [Zstandard](https://facebook.github.io/zstd/) compression, at the given level.
+    #[cfg(feature = "compression-zstd")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "compression-zstd")))]
+    Zstd {
+        /// The compression level; 0 selects zstd's default.
+        level: i32,
+    },
+    /// [LZ4](https://lz4.github.io/lz4/) block compression.
+    #[cfg(feature = "compression-lz4")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "compression-lz4")))]
+    Lz4,
+}
+
+impl Algorithm {
+    /// All algorithms enabled in this build, in order of preference.
+    const ALL: &'static [Algorithm] = &[
+        #[cfg(feature = "compression-zstd")]
+        Algorithm::Zstd { level: 0 },
+        #[cfg(feature = "compression-lz4")]
+        Algorithm::Lz4,
+    ];
+
+    fn tag(self) -> u8 {
+        match self {
+            #[cfg(feature = "compression-zstd")]
+            Algorithm::Zstd { .. } => tag::ZSTD,
+            #[cfg(feature = "compression-lz4")]
+            Algorithm::Lz4 => tag::LZ4,
+        }
+    }
+
+    fn compress(self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "compression-zstd")]
+            Algorithm::Zstd { level } => zstd::bulk::compress(frame, level),
+            #[cfg(feature = "compression-lz4")]
+            Algorithm::Lz4 => Ok(lz4_flex::compress_prepend_size(frame)),
+        }
+    }
+}
+
+/// Decompresses the body of a frame tagged with `tag`, failing if it would decompress to more
+/// than `max_len` bytes.
+fn decompress(tag: u8, body: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    match tag {
+        #[cfg(feature = "compression-zstd")]
+        tag::ZSTD => zstd::bulk::decompress(body, max_len).map_err(invalid_data),
+        #[cfg(feature = "compression-lz4")]
+        tag::LZ4 => {
+            if body.len() < 4 {
+                return Err(invalid_data("lz4 frame is missing its length"));
+            }
+            let (len, body) = body.split_at(4);
+            let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            if len > max_len {
+                return Err(invalid_data(format!(
+                    "lz4 frame decompresses to {len} bytes, more than the max of {max_len}"
+                )));
+            }
+            lz4_flex::decompress(body, len).map_err(invalid_data)
+        }
+        _ => Err(invalid_data(format!("unknown frame tag {tag}"))),
+    }
+}
+
+/// Settings that control the compression of a transport.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CompressionConfig {
+    /// The algorithms to compress with, in order of preference. The first one the peer can
+    /// decompress is used. Frames received are decompressed with any enabled algorithm,
+    /// regardless of this list.
+    pub algorithms: Vec<Algorithm>,
+    /// Frames shorter than this many bytes are sent uncompressed, because compressing them
+    /// rarely pays off.
+    pub threshold: usize,
+    /// The max number of bytes a received frame may decompress to. Larger frames fail the
+    /// transport, so that a small frame can't exhaust memory when decompressed.
+    pub max_frame_length: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            algorithms: Algorithm::ALL.to_vec(),
+            threshold: 1024,
+            max_frame_length: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// A transport of frames that are compressed on the wire. See the [module docs](self).
+#[pin_project]
+pub struct Compressed<T> {
+    #[pin]
+    inner: T,
+    config: CompressionConfig,
+    /// Whether the hello frame announcing the algorithms this side decompresses has been sent.
+    hello_sent: bool,
+    /// The algorithm negotiated for sending, once the peer's hello is received.
+    algorithm: Option<Algorithm>,
+}
+
+impl<T> Compressed<T> {
+    /// Returns a transport that compresses the frames sent over `inner`.
+    pub fn new(inner: T, config: CompressionConfig) -> Self {
+        Self {
+            inner,
+            config,
+            hello_sent: false,
+            algorithm: None,
+        }
+    }
+
+    /// Returns the inner transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the algorithm frames are compressed with, once it has been negotiated with the
+    /// peer.
+    pub fn algorithm(&self) -> Option<Algorithm> {
+        self.algorithm
+    }
+}
+
+impl<T> fmt::Debug for Compressed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Compressed")
+            .field("config", &self.config)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+fn hello() -> Bytes {
+    let algorithms = Algorithm::ALL
+        .iter()
+        .fold(0u8, |mask, algorithm| mask | 1 << algorithm.tag());
+    Bytes::from(vec![tag::HELLO, algorithms])
+}
+
+impl<T, E> Stream for Compressed<T>
+where
+    T: Stream<Item = Result<BytesMut, E>>,
+    E: From<io::Error>,
+{
+    type Item = Result<BytesMut, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let mut frame = match ready!(this.inner.as_mut().poll_next(cx)?) {
+                Some(frame) => frame,
+                None => return Poll::Ready(None),
+            };
+            if frame.is_empty() {
+                return Poll::Ready(Some(Err(invalid_data("frame is missing its tag").into())));
+            }
+            let body = frame.split_off(1);
+            match frame[0] {
+                tag::HELLO => {
+                    let peer = body.first().copied().unwrap_or_default();
+                    *this.algorithm = this
+                        .config
+                        .algorithms
+                        .iter()
+                        .copied()
+                        .find(|algorithm| peer & 1 << algorithm.tag() != 0);
+                    tracing::trace!(algorithm = ?this.algorithm, "Negotiated compression.");
+                }
+                tag::UNCOMPRESSED => return Poll::Ready(Some(Ok(body))),
+                tag => {
+                    let frame = decompress(tag, &body, this.config.max_frame_length)?;
+                    return Poll::Ready(Some(Ok(BytesMut::from(&frame[..]))));
+                }
+            }
+        }
+    }
+}
+
+impl<T, E> Sink<Bytes> for Compressed<T>
+where
+    T: Sink<Bytes, Error = E>,
+    E: From<io::Error>,
+{
+    type Error = E;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        let mut this = self.project();
+        if !*this.hello_sent {
+            ready!(this.inner.as_mut().poll_ready(cx)?);
+            this.inner.as_mut().start_send(hello())?;
+            *this.hello_sent = true;
+        }
+        this.inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Bytes) -> Result<(), E> {
+        let this = self.project();
+        let compressed = match *this.algorithm {
+            Some(algorithm) if frame.len() >= this.config.threshold => {
+                Some((algorithm.tag(), algorithm.compress(&frame)?))
+            }
+            _ => None,
+        };
+        let (tag, body) = match compressed {
+            Some((tag, ref compressed)) if compressed.len() < frame.len() => (tag, &compressed[..]),
+            _ => (tag::UNCOMPRESSED, &frame[..]),
+        };
+        let mut out = BytesMut::with_capacity(1 + body.len());
+        out.put_u8(tag);
+        out.put_slice(body);
+        this.inner.start_send(out.freeze())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+/// Extends transports of frames with [`compressed`](CompressExt::compressed).
+pub trait CompressExt: Sized {
+    /// Returns a transport that compresses the frames sent over `self`.
+    fn compressed(self, config: CompressionConfig) -> Compressed<Self> {
+        Compressed::new(self, config)
+    }
+}
+
+impl<T> CompressExt for T where T: Sink<Bytes> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::frames::framed;
+    use assert_matches::assert_matches;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn negotiates_and_compresses_large_frames() -> io::Result<()> {
+        let (a, b) = duplex(1 << 20);
+        let mut a = framed(a).compressed(CompressionConfig::default());
+        let mut b = framed(b);
+
+        // Nothing is compressed before the peer's hello arrives.
+        let large = Bytes::from(vec![7u8; 10_000]);
+        a.send(large.clone()).await?;
+        assert_eq!(b.next().await.unwrap()?[..], [tag::HELLO, hello()[1]]);
+        let frame = b.next().await.unwrap()?;
+        assert_eq!(frame[0], tag::UNCOMPRESSED);
+        assert_eq!(frame[1..], large[..]);
+
+        b.send(hello()).await?;
+        b.send(Bytes::from_static(&[tag::UNCOMPRESSED, 1, 2, 3]))
+            .await?;
+        assert_eq!(a.next().await.unwrap()?[..], [1, 2, 3]);
+        assert_eq!(a.algorithm(), Algorithm::ALL.first().copied());
+
+        a.send(large.clone()).await?;
+        let frame = b.next().await.unwrap()?;
+        assert_ne!(frame[0], tag::UNCOMPRESSED);
+        assert!(frame.len() < large.len());
+        assert_eq!(decompress(frame[0], &frame[1..], 1 << 20)?, large);
+
+        // Small frames aren't worth compressing.
+        a.send(Bytes::from_static(b"small")).await?;
+        assert_eq!(b.next().await.unwrap()?[..], *b"\0small");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn round_trip() -> io::Result<()> {
+        let (a, b) = duplex(1 << 20);
+        let mut a = framed(a).compressed(CompressionConfig::default());
+        let mut b = framed(b).compressed(CompressionConfig::default());
+
+        a.send(Bytes::from_static(b"hi")).await?;
+        assert_eq!(b.next().await.unwrap()?[..], *b"hi");
+        b.send(Bytes::from_static(b"hey")).await?;
+        assert_eq!(a.next().await.unwrap()?[..], *b"hey");
+
+        let large = Bytes::from(vec![7u8; 10_000]);
+        a.send(large.clone()).await?;
+        assert_eq!(b.next().await.unwrap()?[..], large[..]);
+        assert!(a.algorithm().is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_frames_that_decompress_too_large() -> io::Result<()> {
+        let (a, b) = duplex(1 << 20);
+        let mut a = framed(a).compressed(CompressionConfig::default());
+        let mut b = framed(b).compressed(CompressionConfig {
+            max_frame_length: 1024,
+            ..Default::default()
+        });
+
+        b.send(Bytes::from_static(b"hello")).await?;
+        assert_eq!(a.next().await.unwrap()?[..], *b"hello");
+        a.send(Bytes::from(vec![7u8; 10_000])).await?;
+        assert_matches!(b.next().await, Some(Err(e)) if e.kind() == io::ErrorKind::InvalidData);
+        Ok(())
+    }
+}
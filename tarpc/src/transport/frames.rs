@@ -0,0 +1,62 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Combinators over transports of serialized frames.
+//!
+//! A transport of frames is a [`Stream`](futures::Stream) of [`BytesMut`](bytes::BytesMut) and a
+//! [`Sink`](futures::Sink) of [`Bytes`](bytes::Bytes), such as a
+//! [`Framed`](tokio_util::codec::Framed) io. Each combinator wraps one and transforms the frames
+//! on their way to and from the wire, presenting another transport of frames, so combinators
+//! stack in any order beneath a serialization codec, e.g. [`tokio_serde::Framed`]:
+//!
+//! ```ignore
+//! use tarpc::transport::{
+//!     checksum::{Checksum, ChecksumExt},
+//!     compression::{CompressExt, CompressionConfig},
+//! };
+//!
+//! let frames = Framed::new(io, LengthDelimitedCodec::new())
+//!     .checksummed(Checksum::Crc32)
+//!     .compressed(CompressionConfig::default());
+//! let transport = tokio_serde::Framed::new(frames, Json::default());
+//! ```
+//!
+//! The combinators are:
+//!
+//! - [`checksum`](super::checksum), which checks the integrity of frames.
+//! - [`compression`](super::compression), which compresses frames.
+//! - [`json_rpc`](crate::server::json_rpc), which takes the place of the codec to serve
+//!   JSON-RPC clients:
+//!
+//! ```ignore
+//! use tarpc::server::{self, json_rpc::JsonRpc, BaseChannel, Channel};
+//!
+//! let transport = JsonRpc::new(Framed::new(io, LengthDelimitedCodec::new()));
+//! BaseChannel::with_defaults(transport).execute(HelloServer.serve())
+//! ```
+//!
+//! Both peers must stack the same combinators, in the same order.
+
+/// Returns an error for a frame that can't be decoded by a combinator.
+#[cfg(any(
+    feature = "checksum",
+    feature = "compression-zstd",
+    feature = "compression-lz4"
+))]
+pub(crate) fn invalid_data<E>(e: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+/// Returns a transport of length-delimited frames over `io`.
+#[cfg(test)]
+pub(crate) fn framed(
+    io: tokio::io::DuplexStream,
+) -> tokio_util::codec::Framed<tokio::io::DuplexStream, tokio_util::codec::LengthDelimitedCodec> {
+    tokio_util::codec::Framed::new(io, tokio_util::codec::LengthDelimitedCodec::new())
+}
@@ -0,0 +1,1028 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A transport of [prost](https://docs.rs/prost) messages, for teams with existing `.proto`
+//! definitions.
+//!
+//! Requests and responses are protobuf messages, carried in protobuf envelopes. Each envelope is
+//! framed by its length as a varint, as written by prost's `encode_length_delimited` or Java's
+//! `writeDelimitedTo`, so peers in other languages can implement the protocol with their own
+//! protobuf libraries. The envelopes are defined by the following schema, where `Req` and
+//! `Resp` are the request and response messages of the service:
+//!
+//! ```protobuf
+//! syntax = "proto3";
+//!
+//! message TraceContext {
+//!   bytes trace_id = 1; // 16 bytes, big-endian.
+//!   fixed64 span_id = 2;
+//!   bool sampled = 3;
+//! }
+//!
+//! message Context {
+//!   uint64 timeout_micros = 1; // Time until the deadline, when sent.
+//!   TraceContext trace_context = 2;
+//!   optional uint64 keep_alive_micros = 3;
+//!   optional uint64 request_size = 4;
+//!   optional uint64 response_size = 5;
+//! }
+//!
+//! message Request {
+//!   uint64 id = 1;
+//!   Context context = 2;
+//!   Req message = 3;
+//! }
+//!
+//! message Cancel {
+//!   uint64 request_id = 1;
+//!   TraceContext trace_context = 2;
+//! }
+//!
+//! message StreamCredit {
+//!   uint64 request_id = 1;
+//!   uint32 credits = 2;
+//! }
+//!
+//! message ClientStreamItem {
+//!   uint64 request_id = 1;
+//!   Req item = 2;
+//! }
+//!
+//! message StreamEnd {
+//!   uint64 request_id = 1;
+//! }
+//!
+//! message OneWay {
+//!   Context context = 1;
+//!   Req message = 2;
+//! }
+//!
+//! message Hello {
+//!   uint32 capabilities = 1; // A bitset of tarpc::capabilities::Capabilities.
+//! }
+//!
+//! message Batch {
+//!   repeated Request requests = 1;
+//! }
+//!
+//! message ClientMessage {
+//!   oneof kind {
+//!     Request request = 1;
+//!     Cancel cancel = 2;
+//!     StreamCredit stream_credit = 3;
+//!     ClientStreamItem stream_item = 4;
+//!     StreamEnd stream_end = 5;
+//!     OneWay one_way = 6;
+//!     Hello hello = 7;
+//!     Batch batch = 8;
+//!   }
+//! }
+//!
+//! message ServerError {
+//!   uint32 kind = 1; // An io::ErrorKind, numbered as in tarpc's serde encoding.
+//!   string detail = 2;
+//!   uint32 cause = 3; // A ServerErrorCause, numbered as in tarpc's serde encoding.
+//! }
+//!
+//! message Response {
+//!   uint64 request_id = 1;
+//!   oneof result {
+//!     Resp message = 2;
+//!     ServerError error = 3;
+//!   }
+//! }
+//!
+//! message ServerStreamItem {
+//!   uint64 request_id = 1;
+//!   Resp item = 2;
+//! }
+//!
+//! message KeepAlive {
+//!   uint64 request_id = 1;
+//! }
+//!
+//! message Close {
+//!   repeated uint64 aborted = 1;
+//! }
+//!
+//! message ServerMessage {
+//!   oneof kind {
+//!     Response response = 1;
+//!     ServerStreamItem stream_item = 2;
+//!     StreamEnd stream_end = 3;
+//!     KeepAlive keep_alive = 4;
+//!     Resp push = 5;
+//!     Hello hello = 6;
+//!     Close close = 7;
+//!   }
+//! }
+//! ```
+
+use crate::{
+    capabilities::Capabilities,
+    context, trace,
+    transport::{DecodeError, ProtocolError},
+    util::{self, TimeUntil},
+    ClientMessage, Request, Response, ServerError, ServerMessage,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{prelude::*, ready, task::*};
+use pin_project::pin_project;
+use std::{fmt, io, marker::PhantomData, pin::Pin, time::Duration};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// The default maximum length of a frame, excluding its length prefix.
+const DEFAULT_MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+
+/// The longest a varint encoding a `u64` can be.
+const MAX_VARINT_LENGTH: usize = 10;
+
+/// The protobuf envelopes of tarpc's messages.
+mod proto {
+    use bytes::Bytes;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TraceContext {
+        #[prost(bytes = "vec", tag = "1")]
+        pub trace_id: Vec<u8>,
+        #[prost(fixed64, tag = "2")]
+        pub span_id: u64,
+        #[prost(bool, tag = "3")]
+        pub sampled: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Context {
+        #[prost(uint64, tag = "1")]
+        pub timeout_micros: u64,
+        #[prost(message, optional, tag = "2")]
+        pub trace_context: Option<TraceContext>,
+        #[prost(uint64, optional, tag = "3")]
+        pub keep_alive_micros: Option<u64>,
+        #[prost(uint64, optional, tag = "4")]
+        pub request_size: Option<u64>,
+        #[prost(uint64, optional, tag = "5")]
+        pub response_size: Option<u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Request {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(message, optional, tag = "2")]
+        pub context: Option<Context>,
+        #[prost(bytes = "bytes", tag = "3")]
+        pub message: Bytes,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Cancel {
+        #[prost(uint64, tag = "1")]
+        pub request_id: u64,
+        #[prost(message, optional, tag = "2")]
+        pub trace_context: Option<TraceContext>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StreamCredit {
+        #[prost(uint64, tag = "1")]
+        pub request_id: u64,
+        #[prost(uint32, tag = "2")]
+        pub credits: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StreamItem {
+        #[prost(uint64, tag = "1")]
+        pub request_id: u64,
+        #[prost(bytes = "bytes", tag = "2")]
+        pub item: Bytes,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StreamEnd {
+        #[prost(uint64, tag = "1")]
+        pub request_id: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct OneWay {
+        #[prost(message, optional, tag = "1")]
+        pub context: Option<Context>,
+        #[prost(bytes = "bytes", tag = "2")]
+        pub message: Bytes,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Hello {
+        #[prost(uint32, tag = "1")]
+        pub capabilities: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Batch {
+        #[prost(message, repeated, tag = "1")]
+        pub requests: Vec<Request>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ClientMessage {
+        #[prost(oneof = "client_message::Kind", tags = "1, 2, 3, 4, 5, 6, 7, 8")]
+        pub kind: Option<client_message::Kind>,
+    }
+
+    pub mod client_message {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Kind {
+            #[prost(message, tag = "1")]
+            Request(super::Request),
+            #[prost(message, tag = "2")]
+            Cancel(super::Cancel),
+            #[prost(message, tag = "3")]
+            StreamCredit(super::StreamCredit),
+            #[prost(message, tag = "4")]
+            StreamItem(super::StreamItem),
+            #[prost(message, tag = "5")]
+            StreamEnd(super::StreamEnd),
+            #[prost(message, tag = "6")]
+            OneWay(super::OneWay),
+            #[prost(message, tag = "7")]
+            Hello(super::Hello),
+            #[prost(message, tag = "8")]
+            Batch(super::Batch),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ServerError {
+        #[prost(uint32, tag = "1")]
+        pub kind: u32,
+        #[prost(string, tag = "2")]
+        pub detail: String,
+        #[prost(uint32, tag = "3")]
+        pub cause: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Response {
+        #[prost(uint64, tag = "1")]
+        pub request_id: u64,
+        #[prost(oneof = "response::Result", tags = "2, 3")]
+        pub result: Option<response::Result>,
+    }
+
+    pub mod response {
+        use bytes::Bytes;
+
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Result {
+            #[prost(bytes = "bytes", tag = "2")]
+            Message(Bytes),
+            #[prost(message, tag = "3")]
+            Error(super::ServerError),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KeepAlive {
+        #[prost(uint64, tag = "1")]
+        pub request_id: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Close {
+        #[prost(uint64, repeated, tag = "1")]
+        pub aborted: Vec<u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ServerMessage {
+        #[prost(oneof = "server_message::Kind", tags = "1, 2, 3, 4, 5, 6, 7")]
+        pub kind: Option<server_message::Kind>,
+    }
+
+    pub mod server_message {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Kind {
+            #[prost(message, tag = "1")]
+            Response(super::Response),
+            #[prost(message, tag = "2")]
+            StreamItem(super::StreamItem),
+            #[prost(message, tag = "3")]
+            StreamEnd(super::StreamEnd),
+            #[prost(message, tag = "4")]
+            KeepAlive(super::KeepAlive),
+            #[prost(bytes = "bytes", tag = "5")]
+            Push(bytes::Bytes),
+            #[prost(message, tag = "6")]
+            Hello(super::Hello),
+            #[prost(message, tag = "7")]
+            Close(super::Close),
+        }
+    }
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Returns an error for an envelope that decoded, but violates the protocol, e.g. by lacking a
+/// required field.
+fn protocol_error(detail: &str) -> io::Error {
+    ProtocolError::new(detail).into()
+}
+
+fn encode_payload<M: prost::Message>(message: &M) -> Bytes {
+    message.encode_to_vec().into()
+}
+
+fn decode_payload<M: prost::Message + Default>(bytes: Bytes) -> io::Result<M> {
+    M::decode(bytes).map_err(invalid_data)
+}
+
+impl From<trace::Context> for proto::TraceContext {
+    fn from(context: trace::Context) -> Self {
+        Self {
+            trace_id: u128::from(context.trace_id).to_be_bytes().to_vec(),
+            span_id: context.span_id.into(),
+            sampled: context.sampling_decision == trace::SamplingDecision::Sampled,
+        }
+    }
+}
+
+impl TryFrom<proto::TraceContext> for trace::Context {
+    type Error = io::Error;
+
+    fn try_from(context: proto::TraceContext) -> io::Result<Self> {
+        let trace_id = <[u8; 16]>::try_from(&context.trace_id[..])
+            .map_err(|_| protocol_error("trace ID is not 16 bytes"))?;
+        Ok(Self {
+            trace_id: u128::from_be_bytes(trace_id).into(),
+            span_id: context.span_id.into(),
+            sampling_decision: if context.sampled {
+                trace::SamplingDecision::Sampled
+            } else {
+                trace::SamplingDecision::Unsampled
+            },
+        })
+    }
+}
+
+fn trace_context_from_proto(context: Option<proto::TraceContext>) -> io::Result<trace::Context> {
+    context
+        .map(trace::Context::try_from)
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
+impl From<context::Context> for proto::Context {
+    fn from(context: context::Context) -> Self {
+        Self {
+            timeout_micros: micros(context.deadline.time_until()),
+            trace_context: Some(context.trace_context.into()),
+            keep_alive_micros: context.keep_alive.map(micros),
+            request_size: context.size_hint.request,
+            response_size: context.size_hint.response,
+        }
+    }
+}
+
+impl TryFrom<proto::Context> for context::Context {
+    type Error = io::Error;
+
+    fn try_from(context: proto::Context) -> io::Result<Self> {
+        Ok(Self {
+            deadline: util::time::now() + Duration::from_micros(context.timeout_micros),
+            trace_context: trace_context_from_proto(context.trace_context)?,
+            keep_alive: context.keep_alive_micros.map(Duration::from_micros),
+            size_hint: context::SizeHint {
+                request: context.request_size,
+                response: context.response_size,
+            },
+            transport: None,
+            peer_addr: None,
+        })
+    }
+}
+
+fn request_to_proto<T: prost::Message>(request: Request<T>) -> proto::Request {
+    proto::Request {
+        id: request.id,
+        context: Some(request.context.into()),
+        message: encode_payload(&request.message),
+    }
+}
+
+fn request_from_proto<T: prost::Message + Default>(
+    request: proto::Request,
+) -> io::Result<Request<T>> {
+    Ok(Request {
+        id: request.id,
+        context: request
+            .context
+            .ok_or_else(|| protocol_error("request has no context"))?
+            .try_into()?,
+        message: decode_payload(request.message)?,
+    })
+}
+
+fn micros(duration: Duration) -> u64 {
+    u64::try_from(duration.as_micros()).unwrap_or(u64::MAX)
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// A tarpc message whose payloads are protobuf messages, so that it can be sent over a
+/// [`Transport`]. Implemented for [`ClientMessage`] and [`ServerMessage`].
+pub trait Envelope: sealed::Sealed + Sized {
+    /// Encodes the message in its protobuf envelope.
+    fn encode_envelope(self) -> Bytes;
+
+    /// Decodes the message from its protobuf envelope.
+    fn decode_envelope(bytes: Bytes) -> io::Result<Self>;
+}
+
+impl<T> sealed::Sealed for ClientMessage<T> {}
+
+impl<T> Envelope for ClientMessage<T>
+where
+    T: prost::Message + Default,
+{
+    fn encode_envelope(self) -> Bytes {
+        use proto::client_message::Kind;
+        let kind = match self {
+            ClientMessage::Request(request) => Kind::Request(request_to_proto(request)),
+            ClientMessage::Cancel {
+                trace_context,
+                request_id,
+            } => Kind::Cancel(proto::Cancel {
+                request_id,
+                trace_context: Some(trace_context.into()),
+            }),
+            ClientMessage::StreamCredit {
+                request_id,
+                credits,
+            } => Kind::StreamCredit(proto::StreamCredit {
+                request_id,
+                credits,
+            }),
+            ClientMessage::StreamItem { request_id, item } => Kind::StreamItem(proto::StreamItem {
+                request_id,
+                item: encode_payload(&item),
+            }),
+            ClientMessage::StreamEnd { request_id } => {
+                Kind::StreamEnd(proto::StreamEnd { request_id })
+            }
+            ClientMessage::OneWay { context, message } => Kind::OneWay(proto::OneWay {
+                context: Some(context.into()),
+                message: encode_payload(&message),
+            }),
+            ClientMessage::Hello { capabilities } => Kind::Hello(proto::Hello {
+                capabilities: capabilities.bits(),
+            }),
+            ClientMessage::Batch { requests } => Kind::Batch(proto::Batch {
+                requests: requests.into_iter().map(request_to_proto).collect(),
+            }),
+        };
+        encode_payload(&proto::ClientMessage { kind: Some(kind) })
+    }
+
+    fn decode_envelope(bytes: Bytes) -> io::Result<Self> {
+        use proto::client_message::Kind;
+        let message: proto::ClientMessage = decode_payload(bytes)?;
+        Ok(
+            match message
+                .kind
+                .ok_or_else(|| protocol_error("client message is empty"))?
+            {
+                Kind::Request(request) => {
+                    let request_id = request.id;
+                    ClientMessage::Request(request_from_proto(request).map_err(|e| {
+                        io::Error::from(DecodeError::new(e).with_request_id(request_id))
+                    })?)
+                }
+                Kind::Cancel(cancel) => ClientMessage::Cancel {
+                    trace_context: trace_context_from_proto(cancel.trace_context)?,
+                    request_id: cancel.request_id,
+                },
+                Kind::StreamCredit(credit) => ClientMessage::StreamCredit {
+                    request_id: credit.request_id,
+                    credits: credit.credits,
+                },
+                Kind::StreamItem(item) => ClientMessage::StreamItem {
+                    request_id: item.request_id,
+                    item: decode_payload(item.item)?,
+                },
+                Kind::StreamEnd(end) => ClientMessage::StreamEnd {
+                    request_id: end.request_id,
+                },
+                Kind::OneWay(one_way) => ClientMessage::OneWay {
+                    context: one_way
+                        .context
+                        .ok_or_else(|| protocol_error("one-way request has no context"))?
+                        .try_into()?,
+                    message: decode_payload(one_way.message)?,
+                },
+                Kind::Hello(hello) => ClientMessage::Hello {
+                    capabilities: Capabilities::from_bits(hello.capabilities),
+                },
+                Kind::Batch(batch) => ClientMessage::Batch {
+                    requests: batch
+                        .requests
+                        .into_iter()
+                        .map(request_from_proto)
+                        .collect::<io::Result<_>>()?,
+                },
+            },
+        )
+    }
+}
+
+impl<T> sealed::Sealed for ServerMessage<T> {}
+
+impl<T> Envelope for ServerMessage<T>
+where
+    T: prost::Message + Default,
+{
+    fn encode_envelope(self) -> Bytes {
+        use proto::{response::Result as ProtoResult, server_message::Kind};
+        let kind = match self {
+            ServerMessage::Response(response) => Kind::Response(proto::Response {
+                request_id: response.request_id,
+                result: Some(match response.message {
+                    Ok(message) => ProtoResult::Message(encode_payload(&message)),
+                    Err(error) => ProtoResult::Error(proto::ServerError {
+                        kind: util::io_error_kind_to_u32(error.kind),
+                        cause: util::server_error_cause_to_u32(error.cause()),
+                        detail: error.detail,
+                    }),
+                }),
+            }),
+            ServerMessage::StreamItem { request_id, item } => Kind::StreamItem(proto::StreamItem {
+                request_id,
+                item: encode_payload(&item),
+            }),
+            ServerMessage::StreamEnd { request_id } => {
+                Kind::StreamEnd(proto::StreamEnd { request_id })
+            }
+            ServerMessage::KeepAlive { request_id } => {
+                Kind::KeepAlive(proto::KeepAlive { request_id })
+            }
+            ServerMessage::Push(message) => Kind::Push(encode_payload(&message)),
+            ServerMessage::Hello { capabilities } => Kind::Hello(proto::Hello {
+                capabilities: capabilities.bits(),
+            }),
+            ServerMessage::Close { aborted } => Kind::Close(proto::Close { aborted }),
+        };
+        encode_payload(&proto::ServerMessage { kind: Some(kind) })
+    }
+
+    fn decode_envelope(bytes: Bytes) -> io::Result<Self> {
+        use proto::{response::Result as ProtoResult, server_message::Kind};
+        let message: proto::ServerMessage = decode_payload(bytes)?;
+        Ok(
+            match message
+                .kind
+                .ok_or_else(|| protocol_error("server message is empty"))?
+            {
+                Kind::Response(response) => ServerMessage::Response(Response {
+                    request_id: response.request_id,
+                    message: match response
+                        .result
+                        .ok_or_else(|| protocol_error("response has no result"))?
+                    {
+                        ProtoResult::Message(message) => Ok(decode_payload(message)?),
+                        ProtoResult::Error(error) => Err(ServerError::with_cause(
+                            util::io_error_kind_from_u32(error.kind),
+                            util::server_error_cause_from_u32(error.cause),
+                            error.detail,
+                        )),
+                    },
+                }),
+                Kind::StreamItem(item) => ServerMessage::StreamItem {
+                    request_id: item.request_id,
+                    item: decode_payload(item.item)?,
+                },
+                Kind::StreamEnd(end) => ServerMessage::StreamEnd {
+                    request_id: end.request_id,
+                },
+                Kind::KeepAlive(keep_alive) => ServerMessage::KeepAlive {
+                    request_id: keep_alive.request_id,
+                },
+                Kind::Push(message) => ServerMessage::Push(decode_payload(message)?),
+                Kind::Hello(hello) => ServerMessage::Hello {
+                    capabilities: Capabilities::from_bits(hello.capabilities),
+                },
+                Kind::Close(close) => ServerMessage::Close {
+                    aborted: close.aborted,
+                },
+            },
+        )
+    }
+}
+
+/// Frames messages by their length, encoded as a varint.
+#[derive(Debug)]
+struct VarintDelimited {
+    max_frame_length: usize,
+}
+
+impl VarintDelimited {
+    fn check_length(&self, length: usize) -> io::Result<()> {
+        if length > self.max_frame_length {
+            return Err(invalid_data(format!(
+                "frame of {} bytes exceeds the max frame length of {} bytes",
+                length, self.max_frame_length
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for VarintDelimited {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        // The last byte of a varint is the first without its continuation bit set.
+        let prefix_length = match src
+            .iter()
+            .take(MAX_VARINT_LENGTH)
+            .position(|byte| byte & 0x80 == 0)
+        {
+            Some(last) => last + 1,
+            None if src.len() < MAX_VARINT_LENGTH => return Ok(None),
+            None => return Err(invalid_data("frame length prefix is not a varint")),
+        };
+        let length = prost::decode_length_delimiter(&src[..prefix_length]).map_err(invalid_data)?;
+        self.check_length(length)?;
+        if src.len() < prefix_length + length {
+            src.reserve(prefix_length + length - src.len());
+            return Ok(None);
+        }
+        src.advance(prefix_length);
+        Ok(Some(src.split_to(length)))
+    }
+}
+
+impl Encoder<Bytes> for VarintDelimited {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        self.check_length(frame.len())?;
+        dst.reserve(prost::length_delimiter_len(frame.len()) + frame.len());
+        prost::encode_length_delimiter(frame.len(), dst).map_err(invalid_data)?;
+        dst.extend_from_slice(&frame);
+        Ok(())
+    }
+}
+
+/// A transport that sends `SinkItem`s and receives `Item`s over a byte stream, encoded as
+/// length-delimited protobuf envelopes.
+#[pin_project]
+pub struct Transport<S, Item, SinkItem> {
+    #[pin]
+    inner: Framed<S, VarintDelimited>,
+    ghost: PhantomData<(fn() -> Item, fn(SinkItem))>,
+}
+
+impl<S, Item, SinkItem> fmt::Debug for Transport<S, Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transport").finish()
+    }
+}
+
+impl<S, Item, SinkItem> Transport<S, Item, SinkItem> {
+    /// Returns the inner transport over which messages are sent and received.
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+
+    /// Sets the maximum length of a frame, excluding its length prefix. Larger frames are
+    /// rejected with an error, both when sent and when received. Defaults to 8 MiB.
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.inner.codec_mut().max_frame_length = max_frame_length;
+        self
+    }
+}
+
+/// Returns a transport over `io`.
+pub fn new<S, Item, SinkItem>(io: S) -> Transport<S, Item, SinkItem>
+where
+    S: AsyncRead + AsyncWrite,
+    Item: Envelope,
+    SinkItem: Envelope,
+{
+    Transport {
+        inner: Framed::new(
+            io,
+            VarintDelimited {
+                max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            },
+        ),
+        ghost: PhantomData,
+    }
+}
+
+impl<S, Item, SinkItem> Stream for Transport<S, Item, SinkItem>
+where
+    S: AsyncRead,
+    Item: Envelope,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
+        Poll::Ready(ready!(self.project().inner.poll_next(cx)?).map(|frame| {
+            // The frame was read in full, so failing to decode it leaves the transport usable.
+            Item::decode_envelope(frame.freeze()).map_err(|e| {
+                if DecodeError::find(&e).is_some() || ProtocolError::find(&e).is_some() {
+                    e
+                } else {
+                    DecodeError::new(e).into()
+                }
+            })
+        }))
+    }
+}
+
+impl<S, Item, SinkItem> Sink<SinkItem> for Transport<S, Item, SinkItem>
+where
+    S: AsyncWrite,
+    SinkItem: Envelope,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        self.project().inner.start_send(item.encode_envelope())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{self, BaseChannel};
+    #[cfg(feature = "tokio1")]
+    use crate::{client, server::Channel};
+    use assert_matches::assert_matches;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Echo {
+        #[prost(string, tag = "1")]
+        text: String,
+    }
+
+    #[test]
+    fn frames_are_varint_delimited() {
+        let response = ServerMessage::Response(Response {
+            request_id: 1,
+            message: Ok(Echo {
+                text: "x".repeat(200),
+            }),
+        })
+        .encode_envelope();
+        let mut codec = VarintDelimited {
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+        };
+        let mut frame = BytesMut::new();
+        codec.encode(response.clone(), &mut frame).unwrap();
+        assert_eq!(
+            frame.len(),
+            prost::length_delimiter_len(response.len()) + response.len()
+        );
+        assert_eq!(
+            prost::decode_length_delimiter(&frame[..]).unwrap(),
+            response.len()
+        );
+
+        // A partial frame is not yet decoded.
+        let mut partial = BytesMut::from(&frame[..1]);
+        assert_matches!(codec.decode(&mut partial), Ok(None));
+        assert_eq!(codec.decode(&mut frame).unwrap().unwrap(), &response[..]);
+
+        codec.max_frame_length = 16;
+        assert_matches!(codec.encode(response, &mut frame), Err(_));
+    }
+
+    #[test]
+    fn server_errors_round_trip() {
+        for error in [
+            ServerError::deadline_exceeded("slow"),
+            ServerError::unimplemented("no such method"),
+            ServerError::new(io::ErrorKind::TimedOut, "handler timed out"),
+        ] {
+            let response = ServerMessage::<Echo>::Response(Response {
+                request_id: 1,
+                message: Err(error.clone()),
+            });
+            assert_matches!(
+                ServerMessage::<Echo>::decode_envelope(response.encode_envelope()),
+                Ok(ServerMessage::Response(Response {
+                    request_id: 1,
+                    message: Err(decoded),
+                })) if decoded == error && decoded.cause() == error.cause()
+            );
+        }
+    }
+
+    #[test]
+    fn pushes_round_trip() {
+        let push = ServerMessage::Push(Echo {
+            text: "stale".into(),
+        });
+        assert_eq!(
+            ServerMessage::<Echo>::decode_envelope(push.clone().encode_envelope()).unwrap(),
+            push
+        );
+    }
+
+    #[test]
+    fn one_way_requests_round_trip() {
+        let one_way = ClientMessage::OneWay {
+            context: context::current(),
+            message: Echo {
+                text: "fire".into(),
+            },
+        };
+        assert_matches!(
+            ClientMessage::<Echo>::decode_envelope(one_way.encode_envelope()),
+            Ok(ClientMessage::OneWay { message: Echo { text }, .. }) if text == "fire"
+        );
+    }
+
+    #[test]
+    fn batches_round_trip() {
+        let request = |id, text: &str| Request {
+            id,
+            context: context::current(),
+            message: Echo { text: text.into() },
+        };
+        let batch = ClientMessage::Batch {
+            requests: vec![request(1, "one"), request(2, "two")],
+        };
+        match ClientMessage::<Echo>::decode_envelope(batch.encode_envelope()) {
+            Ok(ClientMessage::Batch { requests }) => assert_eq!(
+                requests
+                    .into_iter()
+                    .map(|request| (request.id, request.message.text))
+                    .collect::<Vec<_>>(),
+                vec![(1, "one".into()), (2, "two".into())]
+            ),
+            message => panic!("unexpected message: {:?}", message),
+        }
+    }
+
+    #[test]
+    fn hellos_round_trip() {
+        let capabilities = Capabilities::from_bits(Capabilities::SUPPORTED.bits() | 1 << 31);
+        assert_matches!(
+            ClientMessage::<Echo>::decode_envelope(
+                ClientMessage::<Echo>::Hello { capabilities }.encode_envelope()
+            ),
+            Ok(ClientMessage::Hello { capabilities: decoded }) if decoded == capabilities
+        );
+        assert_eq!(
+            ServerMessage::<Echo>::decode_envelope(
+                ServerMessage::<Echo>::Hello { capabilities }.encode_envelope()
+            )
+            .unwrap(),
+            ServerMessage::Hello { capabilities }
+        );
+    }
+
+    #[cfg(feature = "tokio1")]
+    #[tokio::test]
+    async fn protobuf() {
+        let (io, peer_io) = tokio::io::duplex(1024);
+        tokio::spawn(
+            BaseChannel::with_defaults(new(peer_io))
+                .execute(|_: context::Context, echo: Echo| future::ready(echo)),
+        );
+        let client = client::new::<Echo, Echo, _>(client::Config::default(), new(io)).spawn();
+        let echo = Echo {
+            text: "hello".into(),
+        };
+        let response = client.call(context::current(), "", echo.clone()).await;
+        assert_eq!(response.unwrap(), echo);
+    }
+
+    #[tokio::test]
+    async fn channel_errors_classify_undecodable_and_invalid_messages() {
+        use proto::client_message::Kind;
+
+        let (io, peer_io) = tokio::io::duplex(1024);
+        let mut channel = BaseChannel::<Echo, Echo, _>::with_defaults(new(peer_io));
+        let mut client = Framed::new(
+            io,
+            VarintDelimited {
+                max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            },
+        );
+
+        let request = |context, message| {
+            encode_payload(&proto::ClientMessage {
+                kind: Some(Kind::Request(proto::Request {
+                    id: 1,
+                    context,
+                    message,
+                })),
+            })
+        };
+        client.send(request(None, Bytes::new())).await.unwrap();
+        assert_matches!(
+            channel.next().await,
+            Some(Err(server::ChannelError::Protocol(e)))
+                if ProtocolError::find(&e).unwrap().detail() == "request has no context"
+        );
+        client
+            .send(request(
+                Some(context::current().into()),
+                Bytes::from_static(b"\x0a\x01\xff"),
+            ))
+            .await
+            .unwrap();
+        assert_matches!(
+            channel.next().await,
+            Some(Err(server::ChannelError::Decode(_)))
+        );
+    }
+
+    #[cfg(feature = "tokio1")]
+    #[tokio::test]
+    async fn undecodable_requests_fail_without_closing_channel() {
+        use proto::client_message::Kind;
+
+        let (io, peer_io) = tokio::io::duplex(1024);
+        let config = server::Config::default().with_decode_errors(server::DecodeErrorPolicy::Skip);
+        tokio::spawn(
+            BaseChannel::new(config, new(peer_io))
+                .execute(|_: context::Context, echo: Echo| future::ready(echo)),
+        );
+        let mut client = Framed::new(
+            io,
+            VarintDelimited {
+                max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            },
+        );
+
+        // The payload of the first request isn't valid UTF-8.
+        let undecodable = encode_payload(&proto::ClientMessage {
+            kind: Some(Kind::Request(proto::Request {
+                id: 1,
+                context: Some(context::current().into()),
+                message: Bytes::from_static(b"\x0a\x01\xff"),
+            })),
+        });
+        client.send(undecodable).await.unwrap();
+        let echo = Echo {
+            text: "hello".into(),
+        };
+        let request = ClientMessage::Request(Request {
+            id: 2,
+            context: context::current(),
+            message: echo.clone(),
+        });
+        client.send(request.encode_envelope()).await.unwrap();
+
+        let mut responses = vec![];
+        for _ in 0..2 {
+            let frame = client.next().await.unwrap().unwrap();
+            match ServerMessage::<Echo>::decode_envelope(frame.freeze()).unwrap() {
+                ServerMessage::Response(response) => responses.push(response),
+                message => panic!("unexpected message: {:?}", message),
+            }
+        }
+        responses.sort_by_key(|response| response.request_id);
+        assert_matches!(
+            &responses[0],
+            Response { request_id: 1, message: Err(e) } if e.kind == io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            responses[1],
+            Response {
+                request_id: 2,
+                message: Ok(echo)
+            }
+        );
+    }
+}
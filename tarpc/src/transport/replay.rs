@@ -0,0 +1,455 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Records the requests a server receives off the wire, and replays them against a live server
+//! for load testing.
+//!
+//! A [`Recorder`] wraps the transports of a server with [`Recorder::record`]. The wrapped
+//! transports note each request as it is received, with the time it arrived and the time the
+//! server took to respond. The resulting [`Recording`] — serializable with the `serde1` feature —
+//! is then replayed by a [`Player`] through a [client](crate::client::Channel), preserving the
+//! pacing of the original traffic, sped up or slowed down by a multiplier. The [`Report`] of a
+//! replay holds the latency of each request next to its recorded latency, so regressions of a
+//! server build show up under realistic load:
+//!
+//! ```ignore
+//! let report = Player::new()
+//!     .with_speed(2.0)
+//!     .with_concurrency(50)
+//!     .play(&recording, &client)
+//!     .await;
+//! println!(
+//!     "p99: recorded {:?}, replayed {:?}",
+//!     report.recorded_latency(0.99),
+//!     report.replayed_latency(0.99),
+//! );
+//! ```
+//!
+//! Only requests and batched requests are recorded; streams, one-way requests, and cancellations
+//! are not.
+
+use crate::{
+    client::{self, RpcError},
+    context, ClientMessage, Response, ServerMessage,
+};
+use fnv::FnvHashMap;
+use futures::{prelude::*, ready};
+use pin_project::pin_project;
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// A request received by a server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct RecordedRequest<Req> {
+    /// The time since the recording started at which the request was received.
+    pub offset: Duration,
+    /// The request message.
+    pub request: Req,
+    /// The time the server took to respond to the request, or None if it didn't respond while
+    /// recording.
+    pub latency: Option<Duration>,
+}
+
+/// The requests received by the transports of a [`Recorder`], in the order they were received.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Recording<Req> {
+    /// The recorded requests.
+    pub requests: Vec<RecordedRequest<Req>>,
+}
+
+impl<Req> Default for Recording<Req> {
+    fn default() -> Self {
+        Self { requests: vec![] }
+    }
+}
+
+/// Records the requests received by the transports it wraps. Cloned recorders share their
+/// recording.
+pub struct Recorder<Req> {
+    state: Arc<Mutex<RecorderState<Req>>>,
+}
+
+struct RecorderState<Req> {
+    start: Instant,
+    recording: Recording<Req>,
+}
+
+impl<Req> Clone for Recorder<Req> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<Req> fmt::Debug for Recorder<Req> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("start", &self.state().start)
+            .finish()
+    }
+}
+
+impl<Req> Default for Recorder<Req> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Req> Recorder<Req> {
+    /// Returns a recorder whose recording starts now.
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(RecorderState {
+                start: Instant::now(),
+                recording: Recording::default(),
+            })),
+        }
+    }
+
+    /// Wraps the transport of a server, recording the requests it receives.
+    pub fn record<T>(&self, transport: T) -> Record<T, Req> {
+        Record {
+            inner: transport,
+            recorder: self.clone(),
+            in_flight: FnvHashMap::default(),
+        }
+    }
+
+    /// Returns a copy of the requests recorded so far.
+    pub fn recording(&self) -> Recording<Req>
+    where
+        Req: Clone,
+    {
+        self.state().recording.clone()
+    }
+
+    /// Records a request received at `now`, returning its index in the recording.
+    fn push(&self, request: Req, now: Instant) -> usize {
+        let mut state = self.state();
+        let offset = now.saturating_duration_since(state.start);
+        let requests = &mut state.recording.requests;
+        requests.push(RecordedRequest {
+            offset,
+            request,
+            latency: None,
+        });
+        requests.len() - 1
+    }
+
+    fn set_latency(&self, index: usize, latency: Duration) {
+        self.state().recording.requests[index].latency = Some(latency);
+    }
+
+    /// Returns the state of the recording, even if a panic poisoned its lock.
+    fn state(&self) -> MutexGuard<'_, RecorderState<Req>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A transport that records the requests it receives. Created by [`Recorder::record`].
+#[pin_project]
+pub struct Record<T, Req> {
+    #[pin]
+    inner: T,
+    recorder: Recorder<Req>,
+    /// The index in the recording, and the receive time, of each request awaiting a response.
+    in_flight: FnvHashMap<u64, (usize, Instant)>,
+}
+
+impl<T, Req> Record<T, Req> {
+    /// Returns the inner transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T, Req> fmt::Debug for Record<T, Req>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Record")
+            .field("inner", &self.inner)
+            .field("in_flight", &self.in_flight.len())
+            .finish()
+    }
+}
+
+impl<T, Req, E> Stream for Record<T, Req>
+where
+    T: Stream<Item = Result<ClientMessage<Req>, E>>,
+    Req: Clone,
+{
+    type Item = T::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let message = ready!(this.inner.poll_next(cx));
+        if let Some(Ok(message)) = &message {
+            let now = Instant::now();
+            let requests = match message {
+                ClientMessage::Request(request) => std::slice::from_ref(request),
+                ClientMessage::Batch { requests } => &requests[..],
+                _ => &[],
+            };
+            for request in requests {
+                let index = this.recorder.push(request.message.clone(), now);
+                this.in_flight.insert(request.id, (index, now));
+            }
+        }
+        Poll::Ready(message)
+    }
+}
+
+impl<T, Req, Resp> Sink<ServerMessage<Resp>> for Record<T, Req>
+where
+    T: Sink<ServerMessage<Resp>>,
+{
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: ServerMessage<Resp>) -> Result<(), Self::Error> {
+        let this = self.project();
+        if let ServerMessage::Response(Response { request_id, .. }) = &item {
+            if let Some((index, received)) = this.in_flight.remove(request_id) {
+                this.recorder.set_latency(index, received.elapsed());
+            }
+        }
+        this.inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+/// Replays a [`Recording`] against a server.
+#[derive(Clone, Copy, Debug)]
+pub struct Player {
+    speed: f64,
+    concurrency: usize,
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Player {
+    /// Returns a player that replays requests at their recorded pace, at most 100 at a time.
+    pub fn new() -> Self {
+        Self {
+            speed: 1.0,
+            concurrency: 100,
+        }
+    }
+
+    /// Sets the multiplier of the pace of the replay: at a speed of 2, requests are sent twice as
+    /// fast as they were recorded.
+    ///
+    /// # Panics
+    ///
+    /// If `speed` isn't positive.
+    pub fn with_speed(mut self, speed: f64) -> Self {
+        assert!(speed > 0.0, "speed must be positive, got {}", speed);
+        self.speed = speed;
+        self
+    }
+
+    /// Sets the maximum number of requests in flight at once. Once reached, requests are sent
+    /// late, as earlier requests complete.
+    ///
+    /// # Panics
+    ///
+    /// If `concurrency` is 0.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        assert!(concurrency > 0, "concurrency must be positive");
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Sends the requests of `recording` through `client`, each with a fresh
+    /// [context](context::current), and returns the latency of each.
+    pub async fn play<Req, Resp>(
+        &self,
+        recording: &Recording<Req>,
+        client: &client::Channel<Req, Resp>,
+    ) -> Report
+    where
+        Req: Clone + fmt::Debug,
+        Resp: fmt::Debug,
+    {
+        let start = Instant::now();
+        let replays = stream::iter(recording.requests.iter())
+            .then(|recorded| async move {
+                tokio::time::sleep_until(start + recorded.offset.div_f64(self.speed)).await;
+                recorded
+            })
+            .map(|recorded| async move {
+                let sent = Instant::now();
+                let result = client
+                    .call(context::current(), "replay", recorded.request.clone())
+                    .await;
+                Replay {
+                    recorded: recorded.latency,
+                    replayed: sent.elapsed(),
+                    error: result.err(),
+                }
+            })
+            .buffered(self.concurrency);
+        Report {
+            replays: replays.collect().await,
+        }
+    }
+}
+
+/// The outcome of replaying a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Replay {
+    /// The latency of the request when recorded.
+    pub recorded: Option<Duration>,
+    /// The latency of the request when replayed.
+    pub replayed: Duration,
+    /// The error the replayed request failed with, if any.
+    pub error: Option<RpcError>,
+}
+
+/// The outcome of a replay.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Report {
+    /// The outcome of each request, in the order of the recording.
+    pub replays: Vec<Replay>,
+}
+
+impl Report {
+    /// Returns the number of replayed requests that failed.
+    pub fn errors(&self) -> usize {
+        self.replays.iter().filter(|r| r.error.is_some()).count()
+    }
+
+    /// Returns the `quantile` of the recorded latencies of the replayed requests that succeeded,
+    /// or None if there are none. `quantile` is clamped to [0, 1].
+    pub fn recorded_latency(&self, quantile: f64) -> Option<Duration> {
+        Self::quantile(
+            self.succeeded().filter_map(|replay| replay.recorded),
+            quantile,
+        )
+    }
+
+    /// Returns the `quantile` of the latencies of the replayed requests that succeeded, or None
+    /// if there are none. `quantile` is clamped to [0, 1].
+    pub fn replayed_latency(&self, quantile: f64) -> Option<Duration> {
+        Self::quantile(self.succeeded().map(|replay| replay.replayed), quantile)
+    }
+
+    fn succeeded(&self) -> impl Iterator<Item = &Replay> {
+        self.replays.iter().filter(|replay| replay.error.is_none())
+    }
+
+    fn quantile(latencies: impl Iterator<Item = Duration>, quantile: f64) -> Option<Duration> {
+        let mut latencies: Vec<_> = latencies.collect();
+        if latencies.is_empty() {
+            return None;
+        }
+        latencies.sort_unstable();
+        let index = ((latencies.len() - 1) as f64 * quantile.clamp(0.0, 1.0)).round() as usize;
+        Some(latencies[index])
+    }
+}
+
+#[cfg(all(test, feature = "tokio1"))]
+mod tests {
+    use super::*;
+    use crate::{
+        server::{self, BaseChannel, Channel},
+        transport::channel,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_recorded_pace_and_latency() {
+        let recorder = Recorder::new();
+        let (client_transport, server_transport) = channel::unbounded();
+        let server = BaseChannel::with_defaults(recorder.record(server_transport)).execute(
+            |_: context::Context, delay: u64| async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                delay
+            },
+        );
+        tokio::spawn(server);
+        let client = client::new(client::Config::default(), client_transport).spawn();
+
+        client.call(context::current(), "", 10).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.call(context::current(), "", 20).await.unwrap();
+
+        let recording = recorder.recording();
+        assert_eq!(
+            recording.requests,
+            [
+                RecordedRequest {
+                    offset: Duration::ZERO,
+                    request: 10,
+                    latency: Some(Duration::from_millis(10)),
+                },
+                RecordedRequest {
+                    offset: Duration::from_millis(110),
+                    request: 20,
+                    latency: Some(Duration::from_millis(20)),
+                },
+            ]
+        );
+
+        // Replay against a server that got 5ms slower, twice as fast as recorded.
+        let (client_transport, server_transport) = channel::unbounded();
+        let server = BaseChannel::new(server::Config::default(), server_transport).execute(
+            |_: context::Context, delay: u64| async move {
+                tokio::time::sleep(Duration::from_millis(delay + 5)).await;
+                delay
+            },
+        );
+        tokio::spawn(server);
+        let client = client::new(client::Config::default(), client_transport).spawn();
+
+        let start = Instant::now();
+        let report = Player::new()
+            .with_speed(2.0)
+            .play(&recording, &client)
+            .await;
+        assert_eq!(start.elapsed(), Duration::from_millis(55 + 25));
+        assert_eq!(report.errors(), 0);
+        assert_eq!(
+            report.recorded_latency(1.0),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(
+            report.replayed_latency(0.0),
+            Some(Duration::from_millis(15))
+        );
+        assert_eq!(
+            report.replayed_latency(1.0),
+            Some(Duration::from_millis(25))
+        );
+    }
+}
@@ -10,10 +10,117 @@ use std::{
     time::{Duration, SystemTime},
 };
 
+pub(crate) mod time;
+
 #[cfg(feature = "serde1")]
 #[cfg_attr(docsrs, doc(cfg(feature = "serde1")))]
 pub mod serde;
 
+#[cfg(feature = "rkyv")]
+#[cfg_attr(docsrs, doc(cfg(feature = "rkyv")))]
+pub mod rkyv;
+
+/// Encodes [`ErrorKind`](std::io::ErrorKind) as a `u32`, for the wire.
+#[cfg(any(feature = "serde1", feature = "protobuf", feature = "rkyv"))]
+pub(crate) fn io_error_kind_to_u32(kind: std::io::ErrorKind) -> u32 {
+    use std::io::ErrorKind::*;
+    match kind {
+        NotFound => 0,
+        PermissionDenied => 1,
+        ConnectionRefused => 2,
+        ConnectionReset => 3,
+        ConnectionAborted => 4,
+        NotConnected => 5,
+        AddrInUse => 6,
+        AddrNotAvailable => 7,
+        BrokenPipe => 8,
+        AlreadyExists => 9,
+        WouldBlock => 10,
+        InvalidInput => 11,
+        InvalidData => 12,
+        TimedOut => 13,
+        WriteZero => 14,
+        Interrupted => 15,
+        Other => 16,
+        UnexpectedEof => 17,
+        OutOfMemory => 18,
+        Unsupported => 19,
+        _ => 16,
+    }
+}
+
+/// Decodes [`ErrorKind`](std::io::ErrorKind) from a `u32`, as encoded by
+/// [`io_error_kind_to_u32`].
+#[cfg(any(feature = "serde1", feature = "protobuf", feature = "rkyv"))]
+pub(crate) fn io_error_kind_from_u32(kind: u32) -> std::io::ErrorKind {
+    use std::io::ErrorKind::*;
+    match kind {
+        0 => NotFound,
+        1 => PermissionDenied,
+        2 => ConnectionRefused,
+        3 => ConnectionReset,
+        4 => ConnectionAborted,
+        5 => NotConnected,
+        6 => AddrInUse,
+        7 => AddrNotAvailable,
+        8 => BrokenPipe,
+        9 => AlreadyExists,
+        10 => WouldBlock,
+        11 => InvalidInput,
+        12 => InvalidData,
+        13 => TimedOut,
+        14 => WriteZero,
+        15 => Interrupted,
+        16 => Other,
+        17 => UnexpectedEof,
+        18 => OutOfMemory,
+        19 => Unsupported,
+        _ => Other,
+    }
+}
+
+/// Encodes [`ServerErrorCause`](crate::ServerErrorCause) as a `u32`, for the wire. Each cause has
+/// a code of its own, rather than being derived from the error's kind, so that an error of a
+/// handler that has the kind of, e.g., an [overloaded](crate::ServerError::overloaded) error isn't
+/// taken for one.
+#[cfg(any(feature = "serde1", feature = "protobuf", feature = "rkyv"))]
+pub(crate) fn server_error_cause_to_u32(cause: crate::ServerErrorCause) -> u32 {
+    use crate::ServerErrorCause::*;
+    match cause {
+        Other => 0,
+        Overloaded => 1,
+        Unimplemented => 2,
+        PayloadTooLarge => 3,
+        Shutdown => 4,
+        DeadlineExceeded => 5,
+    }
+}
+
+/// Decodes [`ServerErrorCause`](crate::ServerErrorCause) from a `u32`, as encoded by
+/// [`server_error_cause_to_u32`].
+#[cfg(any(feature = "serde1", feature = "protobuf", feature = "rkyv"))]
+pub(crate) fn server_error_cause_from_u32(cause: u32) -> crate::ServerErrorCause {
+    use crate::ServerErrorCause::*;
+    match cause {
+        1 => Overloaded,
+        2 => Unimplemented,
+        3 => PayloadTooLarge,
+        4 => Shutdown,
+        5 => DeadlineExceeded,
+        _ => Other,
+    }
+}
+
+/// Evaluates the failpoint `name` if the `failpoints` feature is enabled, returning the result of
+/// `f` if the failpoint is configured with the `return` action. Other actions, like `panic` or
+/// `sleep`, are carried out by the `fail` crate itself.
+#[allow(unused_variables)]
+pub(crate) fn fail_point<R>(name: &str, f: impl FnOnce(Option<String>) -> R) -> Option<R> {
+    #[cfg(feature = "failpoints")]
+    fail::fail_point!(name, |arg| Some(f(arg)));
+    None
+}
+
 /// Extension trait for [SystemTimes](SystemTime) in the future, i.e. deadlines.
 pub trait TimeUntil {
     /// How much time from now until this time is reached.
@@ -22,7 +129,7 @@ pub trait TimeUntil {
 
 impl TimeUntil for SystemTime {
     fn time_until(&self) -> Duration {
-        self.duration_since(SystemTime::now()).unwrap_or_default()
+        self.duration_since(time::now()).unwrap_or_default()
     }
 }
 
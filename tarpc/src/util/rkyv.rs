@@ -0,0 +1,121 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Wrappers that archive fields of tarpc's messages whose types rkyv does not support.
+
+use crate::ServerErrorCause;
+use rkyv::{
+    time::ArchivedDuration,
+    with::{ArchiveWith, DeserializeWith, SerializeWith},
+    Archive, Archived, Deserialize, Fallible,
+};
+use std::{
+    io,
+    time::{Duration, SystemTime},
+};
+
+/// Archives a [`SystemTime`] as the [`Duration`] until it, to prevent clock skew issues.
+#[derive(Debug)]
+pub struct RelativeTime;
+
+impl ArchiveWith<SystemTime> for RelativeTime {
+    type Archived = ArchivedDuration;
+    type Resolver = ();
+
+    unsafe fn resolve_with(
+        time: &SystemTime,
+        pos: usize,
+        resolver: (),
+        out: *mut ArchivedDuration,
+    ) {
+        let duration = time
+            .duration_since(crate::util::time::now())
+            .unwrap_or(Duration::ZERO);
+        duration.resolve(pos, resolver, out);
+    }
+}
+
+impl<S: Fallible + ?Sized> SerializeWith<SystemTime, S> for RelativeTime {
+    fn serialize_with(_: &SystemTime, _: &mut S) -> Result<(), S::Error> {
+        Ok(())
+    }
+}
+
+impl<D: Fallible + ?Sized> DeserializeWith<ArchivedDuration, SystemTime, D> for RelativeTime {
+    fn deserialize_with(duration: &ArchivedDuration, _: &mut D) -> Result<SystemTime, D::Error> {
+        Ok(crate::util::time::now() + Duration::from(*duration))
+    }
+}
+
+/// Archives an [`io::ErrorKind`] as a `u32`.
+#[derive(Debug)]
+pub struct IoErrorKindAsU32;
+
+impl ArchiveWith<io::ErrorKind> for IoErrorKindAsU32 {
+    type Archived = Archived<u32>;
+    type Resolver = ();
+
+    unsafe fn resolve_with(
+        kind: &io::ErrorKind,
+        pos: usize,
+        resolver: (),
+        out: *mut Archived<u32>,
+    ) {
+        super::io_error_kind_to_u32(*kind).resolve(pos, resolver, out);
+    }
+}
+
+impl<S: Fallible + ?Sized> SerializeWith<io::ErrorKind, S> for IoErrorKindAsU32 {
+    fn serialize_with(_: &io::ErrorKind, _: &mut S) -> Result<(), S::Error> {
+        Ok(())
+    }
+}
+
+impl<D: Fallible + ?Sized> DeserializeWith<Archived<u32>, io::ErrorKind, D> for IoErrorKindAsU32 {
+    fn deserialize_with(
+        kind: &Archived<u32>,
+        deserializer: &mut D,
+    ) -> Result<io::ErrorKind, D::Error> {
+        let kind: u32 = kind.deserialize(deserializer)?;
+        Ok(super::io_error_kind_from_u32(kind))
+    }
+}
+
+/// Archives a [`ServerErrorCause`] as a `u32`.
+#[derive(Debug)]
+pub struct ServerErrorCauseAsU32;
+
+impl ArchiveWith<ServerErrorCause> for ServerErrorCauseAsU32 {
+    type Archived = Archived<u32>;
+    type Resolver = ();
+
+    unsafe fn resolve_with(
+        cause: &ServerErrorCause,
+        pos: usize,
+        resolver: (),
+        out: *mut Archived<u32>,
+    ) {
+        super::server_error_cause_to_u32(*cause).resolve(pos, resolver, out);
+    }
+}
+
+impl<S: Fallible + ?Sized> SerializeWith<ServerErrorCause, S> for ServerErrorCauseAsU32 {
+    fn serialize_with(_: &ServerErrorCause, _: &mut S) -> Result<(), S::Error> {
+        Ok(())
+    }
+}
+
+impl<D: Fallible + ?Sized> DeserializeWith<Archived<u32>, ServerErrorCause, D>
+    for ServerErrorCauseAsU32
+{
+    fn deserialize_with(
+        cause: &Archived<u32>,
+        deserializer: &mut D,
+    ) -> Result<ServerErrorCause, D::Error> {
+        let cause: u32 = cause.deserialize(deserializer)?;
+        Ok(super::server_error_cause_from_u32(cause))
+    }
+}
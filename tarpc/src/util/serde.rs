@@ -4,8 +4,11 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::io;
+use serde::{
+    de::{self, DeserializeSeed, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{fmt, io};
 
 /// Serializes [`io::ErrorKind`] as a `u32`.
 #[allow(clippy::trivially_copy_pass_by_ref)] // Exact fn signature required by serde derive
@@ -16,29 +19,7 @@ pub fn serialize_io_error_kind_as_u32<S>(
 where
     S: Serializer,
 {
-    use std::io::ErrorKind::*;
-    match *kind {
-        NotFound => 0,
-        PermissionDenied => 1,
-        ConnectionRefused => 2,
-        ConnectionReset => 3,
-        ConnectionAborted => 4,
-        NotConnected => 5,
-        AddrInUse => 6,
-        AddrNotAvailable => 7,
-        BrokenPipe => 8,
-        AlreadyExists => 9,
-        WouldBlock => 10,
-        InvalidInput => 11,
-        InvalidData => 12,
-        TimedOut => 13,
-        WriteZero => 14,
-        Interrupted => 15,
-        Other => 16,
-        UnexpectedEof => 17,
-        _ => 16,
-    }
-    .serialize(serializer)
+    super::io_error_kind_to_u32(*kind).serialize(serializer)
 }
 
 /// Deserializes [`io::ErrorKind`] from a `u32`.
@@ -48,26 +29,82 @@ pub fn deserialize_io_error_kind_from_u32<'de, D>(
 where
     D: Deserializer<'de>,
 {
-    use std::io::ErrorKind::*;
-    Ok(match u32::deserialize(deserializer)? {
-        0 => NotFound,
-        1 => PermissionDenied,
-        2 => ConnectionRefused,
-        3 => ConnectionReset,
-        4 => ConnectionAborted,
-        5 => NotConnected,
-        6 => AddrInUse,
-        7 => AddrNotAvailable,
-        8 => BrokenPipe,
-        9 => AlreadyExists,
-        10 => WouldBlock,
-        11 => InvalidInput,
-        12 => InvalidData,
-        13 => TimedOut,
-        14 => WriteZero,
-        15 => Interrupted,
-        16 => Other,
-        17 => UnexpectedEof,
-        _ => Other,
-    })
+    Ok(super::io_error_kind_from_u32(u32::deserialize(
+        deserializer,
+    )?))
+}
+
+/// Serializes [`ServerErrorCause`](crate::ServerErrorCause) as a `u32`.
+#[allow(clippy::trivially_copy_pass_by_ref)] // Exact fn signature required by serde derive
+pub fn serialize_server_error_cause_as_u32<S>(
+    cause: &crate::ServerErrorCause,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    super::server_error_cause_to_u32(*cause).serialize(serializer)
+}
+
+/// Deserializes [`ServerErrorCause`](crate::ServerErrorCause) from a `u32`.
+pub fn deserialize_server_error_cause_from_u32<'de, D>(
+    deserializer: D,
+) -> Result<crate::ServerErrorCause, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(super::server_error_cause_from_u32(u32::deserialize(
+        deserializer,
+    )?))
+}
+
+/// Identifies the variant of an enum whose variants are serialized with explicit wire tags, as
+/// generated for services whose rpcs have `#[tarpc(id = N)]` attributes. Binary formats identify
+/// variants by tag, and self-describing formats by name. Deserializes to the variant's position.
+#[derive(Clone, Copy, Debug)]
+pub struct VariantTag {
+    /// The wire tags of the variants.
+    pub tags: &'static [u32],
+    /// The names of the variants.
+    pub names: &'static [&'static str],
+}
+
+impl<'de> DeserializeSeed<'de> for VariantTag {
+    type Value = usize;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<usize, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_identifier(self)
+    }
+}
+
+impl<'de> Visitor<'de> for VariantTag {
+    type Value = usize;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("variant tag or name")
+    }
+
+    fn visit_u64<E: de::Error>(self, tag: u64) -> Result<usize, E> {
+        self.tags
+            .iter()
+            .position(|&t| u64::from(t) == tag)
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(tag), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<usize, E> {
+        self.names
+            .iter()
+            .position(|&n| n == name)
+            .ok_or_else(|| E::unknown_variant(name, self.names))
+    }
+
+    fn visit_bytes<E: de::Error>(self, name: &[u8]) -> Result<usize, E> {
+        match std::str::from_utf8(name) {
+            Ok(name) => self.visit_str(name),
+            Err(_) => Err(E::invalid_value(de::Unexpected::Bytes(name), &self)),
+        }
+    }
 }
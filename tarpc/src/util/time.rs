@@ -0,0 +1,159 @@
+// Copyright 2022 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Clocks and timers for deadlines.
+//!
+//! On `wasm32-unknown-unknown`, `std::time` has no clock and Tokio has no timer, so the clocks are
+//! read from JavaScript's `Date.now()` and `performance.now()`, and timers are set with
+//! `setTimeout`. Elsewhere, they're the clocks and timers of std and Tokio.
+
+use std::time::SystemTime;
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) use tokio::time::Instant;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) use tokio_util::time::{delay_queue::Key, DelayQueue};
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub(crate) use wasm::{DelayQueue, Instant, Key};
+
+/// Returns the current time of the system clock.
+pub(crate) fn now() -> SystemTime {
+    #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+    {
+        SystemTime::UNIX_EPOCH + std::time::Duration::from_secs_f64(js_sys::Date::now() / 1000.)
+    }
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    {
+        SystemTime::now()
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+mod wasm {
+    use fnv::FnvHashMap;
+    use std::{
+        ops::Add,
+        task::{Context, Poll, Waker},
+        time::Duration,
+    };
+    use wasm_bindgen::{closure::Closure, prelude::*};
+
+    #[wasm_bindgen]
+    extern "C" {
+        #[wasm_bindgen(js_namespace = performance, js_name = now)]
+        fn performance_now() -> f64;
+
+        #[wasm_bindgen(js_name = setTimeout)]
+        fn set_timeout(handler: JsValue, timeout: f64) -> JsValue;
+    }
+
+    /// A measurement of a monotonic clock, read from `performance.now()`.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub(crate) struct Instant(Duration);
+
+    impl Instant {
+        pub(crate) fn now() -> Self {
+            Self(Duration::from_secs_f64(performance_now() / 1000.))
+        }
+
+        pub(crate) fn elapsed(&self) -> Duration {
+            Self::now().0.saturating_sub(self.0)
+        }
+    }
+
+    impl Add<Duration> for Instant {
+        type Output = Self;
+
+        fn add(self, duration: Duration) -> Self {
+            Self(self.0 + duration)
+        }
+    }
+
+    /// Identifies an item of a [`DelayQueue`].
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub(crate) struct Key(u64);
+
+    /// An item of a [`DelayQueue`] whose delay elapsed.
+    #[derive(Debug)]
+    pub(crate) struct Expired<T>(T);
+
+    impl<T> Expired<T> {
+        pub(crate) fn into_inner(self) -> T {
+            self.0
+        }
+    }
+
+    /// A queue of items that are yielded once their delays elapse, with the subset of the API of
+    /// Tokio's `DelayQueue` used for deadlines.
+    ///
+    /// Finding the next item to expire scans the queue, which suits the few requests a browser
+    /// client has in flight.
+    #[derive(Debug)]
+    pub(crate) struct DelayQueue<T> {
+        items: FnvHashMap<Key, (Instant, T)>,
+        next_key: u64,
+        /// When the pending timer fires, and the task it wakes.
+        timer: Option<(Instant, Waker)>,
+    }
+
+    impl<T> Default for DelayQueue<T> {
+        fn default() -> Self {
+            Self {
+                items: FnvHashMap::default(),
+                next_key: 0,
+                timer: None,
+            }
+        }
+    }
+
+    impl<T> DelayQueue<T> {
+        pub(crate) fn insert(&mut self, value: T, timeout: Duration) -> Key {
+            let key = Key(self.next_key);
+            self.next_key += 1;
+            self.items.insert(key, (Instant::now() + timeout, value));
+            key
+        }
+
+        pub(crate) fn remove(&mut self, key: &Key) -> Expired<T> {
+            let (_, value) = self.items.remove(key).expect("invalid key");
+            Expired(value)
+        }
+
+        pub(crate) fn reset(&mut self, key: &Key, timeout: Duration) {
+            if let Some((deadline, _)) = self.items.get_mut(key) {
+                *deadline = Instant::now() + timeout;
+            }
+        }
+
+        /// Yields the next item whose delay elapsed, or None if the queue is empty.
+        pub(crate) fn poll_expired(&mut self, cx: &mut Context) -> Poll<Option<Expired<T>>> {
+            let (key, deadline) = match self.items.iter().min_by_key(|(_, (deadline, _))| *deadline)
+            {
+                Some((&key, &(deadline, _))) => (key, deadline),
+                None => return Poll::Ready(None),
+            };
+            let now = Instant::now();
+            if deadline <= now {
+                return Poll::Ready(Some(self.remove(&key)));
+            }
+            // A timer that fires later than the deadline, or that already fired, or that wakes
+            // another task, is superseded rather than cleared; it only causes a spurious wakeup.
+            let scheduled = matches!(
+                &self.timer,
+                Some((fires, waker)) if *fires <= deadline && *fires > now && waker.will_wake(cx.waker())
+            );
+            if !scheduled {
+                let waker = cx.waker().clone();
+                let wake = Closure::once_into_js(move || waker.wake());
+                let timeout = deadline.0.saturating_sub(now.0);
+                set_timeout(wake, timeout.as_secs_f64() * 1000.);
+                self.timer = Some((deadline, cx.waker().clone()));
+            }
+            Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,13 @@
+#[tarpc::service(derive_serde = false)]
+trait Accounts {
+    #[tarpc(app_error)]
+    async fn balance(account: String) -> u64;
+}
+
+#[tarpc::service(derive_serde = false)]
+trait Ledger {
+    #[tarpc(app_error)]
+    async fn append(entries: impl Stream<Item = String>) -> Result<(), String>;
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[tarpc::service]
+trait World {
+    async fn hello(#[inject] #[encrypted] name: String);
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[tarpc::service]
+trait World {
+    async fn closed();
+}
+
+fn main() {}
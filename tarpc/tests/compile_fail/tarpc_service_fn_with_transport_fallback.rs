@@ -0,0 +1,6 @@
+#[tarpc::service]
+trait World {
+    async fn with_transport_fallback();
+}
+
+fn main() {}
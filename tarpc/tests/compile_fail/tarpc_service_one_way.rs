@@ -0,0 +1,13 @@
+#[tarpc::service(derive_serde = false)]
+trait Telemetry {
+    #[tarpc(one_way)]
+    async fn record(metric: String) -> u64;
+}
+
+#[tarpc::service(derive_serde = false)]
+trait Events {
+    #[tarpc(one_way, one_way)]
+    async fn emit(event: String);
+}
+
+fn main() {}
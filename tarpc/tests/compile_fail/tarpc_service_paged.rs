@@ -0,0 +1,13 @@
+#[tarpc::service(derive_serde = false)]
+trait Inventory {
+    #[tarpc(paged)]
+    async fn items(category: String) -> tarpc::paging::Page<String>;
+}
+
+#[tarpc::service(derive_serde = false)]
+trait Categories {
+    #[tarpc(paged)]
+    async fn categories(page: Option<tarpc::paging::PageToken>) -> Vec<String>;
+}
+
+fn main() {}
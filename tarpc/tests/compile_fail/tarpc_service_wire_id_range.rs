@@ -0,0 +1,7 @@
+#[tarpc::service(derive_serde = false)]
+trait World {
+    #[tarpc(id = 2147483648)]
+    async fn hello(name: String) -> String;
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+#[tarpc::service(derive_serde = false)]
+trait World {
+    #[tarpc(id = 1)]
+    async fn hello(name: String) -> String;
+    async fn goodbye(name: String) -> String;
+    #[tarpc(id = 1)]
+    async fn greet(name: String) -> String;
+}
+
+fn main() {}
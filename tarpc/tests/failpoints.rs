@@ -0,0 +1,73 @@
+//! Failpoints are global, so these tests live in their own binary; each test holds a
+//! `FailScenario`, which serializes them.
+
+use assert_matches::assert_matches;
+use futures::future::{ready, Ready};
+use std::time::{Duration, SystemTime};
+use tarpc::{
+    client, context,
+    server::{self, BaseChannel, Channel},
+    transport::channel,
+};
+
+#[tarpc::service]
+trait Service {
+    async fn add(x: i32, y: i32) -> i32;
+}
+
+#[derive(Clone)]
+struct Server;
+
+impl Service for Server {
+    type AddFut = Ready<i32>;
+
+    fn add(self, _: context::Context, x: i32, y: i32) -> Self::AddFut {
+        ready(x + y)
+    }
+}
+
+#[tokio::test]
+async fn failpoint_before_handler_rejects_request() -> anyhow::Result<()> {
+    let scenario = fail::FailScenario::setup();
+    fail::cfg("tarpc::server::before_handler", "return(injected)").unwrap();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(server::Config::default(), rx)
+            .requests()
+            .execute(Server.serve()),
+    );
+    let client = ServiceClient::new(client::Config::default(), tx).spawn();
+
+    assert_matches!(
+        client.add(context::current(), 1, 2).await,
+        Err(client::RpcError::Server(e)) if e.detail == "injected"
+    );
+    scenario.teardown();
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn failpoint_after_decode_drops_request() -> anyhow::Result<()> {
+    let scenario = fail::FailScenario::setup();
+    fail::cfg("tarpc::server::after_decode", "1*return").unwrap();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(server::Config::default(), rx)
+            .requests()
+            .execute(Server.serve()),
+    );
+    let client = ServiceClient::new(client::Config::default(), tx).spawn();
+
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + Duration::from_secs(1);
+    assert_matches!(
+        client.add(ctx, 1, 2).await,
+        Err(client::RpcError::DeadlineExceeded)
+    );
+    // The failpoint only fires once.
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+    scenario.teardown();
+    Ok(())
+}
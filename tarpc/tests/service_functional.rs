@@ -2,6 +2,7 @@ use assert_matches::assert_matches;
 use futures::{
     future::{join_all, ready, Ready},
     prelude::*,
+    stream,
 };
 use std::time::{Duration, SystemTime};
 use tarpc::{
@@ -57,6 +58,57 @@ async fn sequential() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[cfg(feature = "tower")]
+#[tokio::test]
+async fn tower_service_client() -> anyhow::Result<()> {
+    use tower::{timeout::TimeoutLayer, ServiceBuilder, ServiceExt};
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (tx, rx) = channel::unbounded();
+
+    tokio::spawn(
+        BaseChannel::new(server::Config::default(), rx)
+            .requests()
+            .execute(Server.serve()),
+    );
+
+    let client = ServiceClient::new(client::Config::default(), tx).spawn();
+    let service = ServiceBuilder::new()
+        .layer(TimeoutLayer::new(Duration::from_secs(10)))
+        .service(client);
+
+    let response = service.oneshot(ServiceRequest::Add { x: 1, y: 2 }).await;
+    assert_matches!(response, Ok(ServiceResponse::Add(3)));
+
+    Ok(())
+}
+
+#[cfg(feature = "tower")]
+#[tokio::test]
+async fn standard_layers() -> anyhow::Result<()> {
+    use tarpc::{layers, server::tower::Layered};
+    use tower::{Layer, ServiceExt};
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (tx, rx) = channel::unbounded();
+
+    tokio::spawn(
+        BaseChannel::new(server::Config::default(), rx)
+            .requests()
+            .execute(Layered::new(layers::standard_server(), Server.serve())),
+    );
+
+    let client = ServiceClient::new(client::Config::default(), tx).spawn();
+    let service = layers::standard_client().layer(client);
+
+    let response = service.oneshot(ServiceRequest::Add { x: 1, y: 2 }).await;
+    assert_matches!(response, Ok(ServiceResponse::Add(3)));
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn dropped_channel_aborts_in_flight_requests() -> anyhow::Result<()> {
     #[tarpc_plugins::service]
@@ -67,9 +119,6 @@ async fn dropped_channel_aborts_in_flight_requests() -> anyhow::Result<()> {
     #[derive(Clone)]
     struct LoopServer;
 
-    #[derive(Debug)]
-    struct AllHandlersComplete;
-
     #[tarpc::server]
     impl Loop for LoopServer {
         async fn r#loop(self, _: context::Context) {
@@ -136,6 +185,80 @@ async fn serde_tcp() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn client_routes_by_transport_hint() -> anyhow::Result<()> {
+    use context::{TransportClass, TransportHint};
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (local_tx, local_rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(local_rx)
+            .requests()
+            .execute(Server.serve()),
+    );
+    let local = ServiceClient::new(client::Config::default(), local_tx).spawn();
+
+    // The remote server is unreachable.
+    let (remote_tx, remote_rx) = channel::unbounded();
+    drop(remote_rx);
+    let remote = ServiceClient::new(client::Config::default(), remote_tx).spawn();
+    remote.closed().await;
+
+    let client = local
+        .with_transport_class(TransportClass::LOCAL)
+        .with_transport_fallback(remote.with_transport_class(TransportClass::REMOTE));
+    let hinted = |hint| {
+        let mut ctx = context::current();
+        ctx.transport = Some(hint);
+        ctx
+    };
+
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+    assert_matches!(
+        client
+            .add(hinted(TransportHint::Prefer(TransportClass::REMOTE)), 1, 2)
+            .await,
+        Ok(3)
+    );
+    assert_matches!(
+        client
+            .add(hinted(TransportHint::Require(TransportClass::REMOTE)), 1, 2)
+            .await,
+        Err(client::RpcError::Disconnected(_))
+    );
+
+    Ok(())
+}
+
+#[cfg(all(feature = "serde-transport", feature = "tcp"))]
+#[tokio::test]
+async fn client_closed_when_server_disconnects() -> anyhow::Result<()> {
+    use tarpc::{serde_transport, ClientMessage, ServerMessage};
+    use tokio_serde::formats::Json;
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let mut listener = serde_transport::tcp::listen(
+        "localhost:0",
+        Json::<ClientMessage<ServiceRequest>, ServerMessage<ServiceResponse>>::default,
+    )
+    .await?;
+    let addr = listener.local_addr();
+    let transport = serde_transport::tcp::connect(addr, Json::default).await?;
+    let client = ServiceClient::new(client::Config::default(), transport).spawn();
+
+    // The server drops the connection as soon as it's accepted.
+    drop(listener.next().await);
+    assert_eq!(client.closed().await, client::CloseReason::ServerClosed);
+    assert_matches!(
+        client.add(context::current(), 1, 2).await,
+        Err(client::RpcError::Disconnected(_))
+    );
+
+    Ok(())
+}
+
 #[cfg(all(feature = "serde-transport", feature = "unix", unix))]
 #[tokio::test]
 async fn serde_uds() -> anyhow::Result<()> {
@@ -239,6 +362,29 @@ async fn concurrent_join_all() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn batched_requests() -> anyhow::Result<()> {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        stream::once(ready(rx))
+            .map(BaseChannel::with_defaults)
+            .execute(Server.serve()),
+    );
+
+    let mut config = client::Config::default();
+    config.max_batch_size = 4;
+    let client = ServiceClient::new(config, tx).spawn();
+
+    let responses = join_all((0..10).map(|i| client.add(context::current(), i, i))).await;
+    for (i, response) in (0..10).zip(responses) {
+        assert_eq!(response?, i * 2);
+    }
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn counter() -> anyhow::Result<()> {
     #[tarpc::service]
@@ -273,3 +419,1235 @@ async fn counter() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn server_streaming() -> anyhow::Result<()> {
+    #[tarpc::service]
+    trait Numbers {
+        async fn count_to(n: u32) -> impl Stream<Item = u32>;
+        async fn sum(x: u32, y: u32) -> u32;
+    }
+
+    #[derive(Clone)]
+    struct NumbersServer;
+
+    #[tarpc::server]
+    impl Numbers for NumbersServer {
+        async fn count_to(self, _: context::Context, n: u32) -> impl Stream<Item = u32> {
+            stream::iter(1..=n)
+        }
+
+        async fn sum(self, _: context::Context, x: u32, y: u32) -> u32 {
+            x + y
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    // A window smaller than the stream forces the server to wait for credits from the client.
+    let config = server::Config::default().with_stream_window(2);
+    tokio::spawn(
+        BaseChannel::new(config, rx)
+            .requests()
+            .execute(NumbersServer.serve()),
+    );
+
+    let client = NumbersClient::new(client::Config::default(), tx).spawn();
+    let counts = client.count_to(context::current(), 10).await?;
+    let counts: Vec<u32> = counts.try_collect().await?;
+    assert_eq!(counts, (1..=10).collect::<Vec<_>>());
+    assert_matches!(client.sum(context::current(), 1, 2).await, Ok(3));
+
+    let counts = client.count_to(context::current(), 0).await?;
+    assert_eq!(counts.collect::<Vec<_>>().await.len(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn dropped_response_stream_cancels_handler() -> anyhow::Result<()> {
+    #[tarpc::service]
+    trait Forever {
+        async fn forever() -> impl Stream<Item = ()>;
+    }
+
+    #[derive(Clone)]
+    struct ForeverServer;
+
+    #[tarpc::server]
+    impl Forever for ForeverServer {
+        async fn forever(self, _: context::Context) -> impl Stream<Item = ()> {
+            stream::repeat(())
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let server = tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .requests()
+            .execute(ForeverServer.serve()),
+    );
+
+    let client = ForeverClient::new(client::Config::default(), tx).spawn();
+    let mut stream = Box::pin(client.forever(context::current()).await?);
+    assert_matches!(stream.next().await, Some(Ok(())));
+    drop(stream);
+    drop(client);
+
+    // The server can only shut down once the infinite handler is canceled.
+    tokio::time::timeout(Duration::from_secs(10), server).await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_streaming() -> anyhow::Result<()> {
+    #[tarpc::service]
+    trait Upload {
+        async fn upload(name: String, chunks: impl Stream<Item = String>) -> String;
+        async fn sum(x: u32, y: u32) -> u32;
+    }
+
+    #[derive(Clone)]
+    struct UploadServer;
+
+    #[tarpc::server]
+    impl Upload for UploadServer {
+        async fn upload(
+            self,
+            _: context::Context,
+            name: String,
+            chunks: impl Stream<Item = String>,
+        ) -> String {
+            let chunks: Vec<String> = chunks.collect().await;
+            format!("{name}: {}", chunks.concat())
+        }
+
+        async fn sum(self, _: context::Context, x: u32, y: u32) -> u32 {
+            x + y
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .requests()
+            .execute(UploadServer.serve()),
+    );
+
+    let client = UploadClient::new(client::Config::default(), tx).spawn();
+    let (chunks, response) = client.upload(context::current(), "file".into()).await?;
+    futures::pin_mut!(chunks);
+    chunks
+        .send_all(&mut stream::iter(["a", "b", "c"]).map(|chunk| Ok(chunk.to_string())))
+        .await?;
+    chunks.close().await?;
+    assert_eq!(response.await?, "file: abc");
+    assert_matches!(client.sum(context::current(), 1, 2).await, Ok(3));
+
+    // Dropping the sink without sending any items ends the stream.
+    let (chunks, response) = client.upload(context::current(), "empty".into()).await?;
+    drop(chunks);
+    assert_eq!(response.await?, "empty: ");
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn keep_alive_defers_response() -> anyhow::Result<()> {
+    #[tarpc::service]
+    trait Slow {
+        async fn slow(duration: Duration);
+    }
+
+    #[derive(Clone)]
+    struct SlowServer;
+
+    #[tarpc::server]
+    impl Slow for SlowServer {
+        async fn slow(self, _: context::Context, duration: Duration) {
+            tokio::time::sleep(duration).await;
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .requests()
+            .execute(SlowServer.serve()),
+    );
+    let client = SlowClient::new(client::Config::default(), tx).spawn();
+
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + Duration::from_secs(1);
+    assert_matches!(
+        client.slow(ctx, Duration::from_secs(5)).await,
+        Err(client::RpcError::DeadlineExceeded)
+    );
+
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + Duration::from_secs(1);
+    ctx.keep_alive = Some(Duration::from_secs(1));
+    assert_matches!(client.slow(ctx, Duration::from_secs(5)).await, Ok(()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn bidi_streaming() -> anyhow::Result<()> {
+    #[tarpc::service]
+    trait Echo {
+        async fn echo(
+            prefix: String,
+            lines: impl Stream<Item = String>,
+        ) -> impl Stream<Item = String>;
+    }
+
+    #[derive(Clone)]
+    struct EchoServer;
+
+    #[tarpc::server]
+    impl Echo for EchoServer {
+        async fn echo(
+            self,
+            _: context::Context,
+            prefix: String,
+            lines: impl Stream<Item = String>,
+        ) -> impl Stream<Item = String> {
+            lines.map(move |line| format!("{prefix}{line}"))
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .requests()
+            .execute(EchoServer.serve()),
+    );
+
+    let client = EchoClient::new(client::Config::default(), tx).spawn();
+    let (lines, echoes) = client.echo(context::current(), "> ".into()).await?;
+    futures::pin_mut!(lines, echoes);
+    // Each echo is received before the next line is sent.
+    for line in ["a", "b"] {
+        lines.send(line.to_string()).await?;
+        assert_eq!(echoes.next().await.transpose()?, Some(format!("> {line}")));
+    }
+    lines.close().await?;
+    assert_matches!(echoes.next().await, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn injected_dependencies() -> anyhow::Result<()> {
+    use std::{collections::HashMap, sync::Arc};
+
+    #[derive(Clone, Debug)]
+    struct Peer(&'static str);
+
+    #[tarpc::service]
+    trait Store {
+        async fn get(#[inject] db: Arc<HashMap<String, i32>>, key: String) -> Option<i32>;
+        async fn whoami(#[inject] peer: Peer) -> String;
+    }
+
+    #[derive(Clone)]
+    struct StoreServer;
+
+    #[tarpc::server]
+    impl Store for StoreServer {
+        async fn get(
+            self,
+            _: context::Context,
+            db: Arc<HashMap<String, i32>>,
+            key: String,
+        ) -> Option<i32> {
+            db.get(&key).copied()
+        }
+
+        async fn whoami(self, _: context::Context, peer: Peer) -> String {
+            peer.0.to_string()
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let mut dependencies = server::Dependencies::new();
+    dependencies.insert(Arc::new(HashMap::from([("a".to_string(), 1)])));
+    let connect = |dependencies| {
+        let (tx, rx) = channel::unbounded();
+        tokio::spawn(
+            BaseChannel::with_defaults(rx)
+                .requests()
+                .execute(StoreServer.serve_with(dependencies)),
+        );
+        StoreClient::new(client::Config::default(), tx).spawn()
+    };
+
+    let client = connect(dependencies.clone());
+    assert_matches!(
+        client.get(context::current(), "a".into()).await,
+        Ok(Some(1))
+    );
+    assert_matches!(
+        client.whoami(context::current()).await,
+        Err(client::RpcError::Server(e)) if e.kind == std::io::ErrorKind::NotFound
+    );
+
+    // Dependencies of a single connection extend those of the server.
+    let mut connection_dependencies = dependencies.clone();
+    connection_dependencies.insert(Peer("alice"));
+    let client = connect(connection_dependencies);
+    assert_matches!(client.get(context::current(), "b".into()).await, Ok(None));
+    assert_matches!(client.whoami(context::current()).await, Ok(ref s) if s == "alice");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn encrypted_fields() -> anyhow::Result<()> {
+    use tarpc::{
+        encryption::{KeyProvider, Keys},
+        server::Serve,
+    };
+
+    /// XORs with a key derived from the field name. Not a cipher!
+    struct Xor;
+
+    impl KeyProvider for Xor {
+        fn encrypt(&self, field: &'static str, plaintext: Vec<u8>) -> Vec<u8> {
+            let key = field.len() as u8;
+            plaintext.into_iter().map(|b| b ^ key).collect()
+        }
+
+        fn decrypt(&self, field: &'static str, ciphertext: Vec<u8>) -> std::io::Result<Vec<u8>> {
+            Ok(self.encrypt(field, ciphertext))
+        }
+    }
+
+    /// Rejects every ciphertext.
+    struct Tampered;
+
+    impl KeyProvider for Tampered {
+        fn encrypt(&self, _: &'static str, plaintext: Vec<u8>) -> Vec<u8> {
+            plaintext
+        }
+
+        fn decrypt(&self, field: &'static str, _: Vec<u8>) -> std::io::Result<Vec<u8>> {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("`{field}` was tampered with"),
+            ))
+        }
+    }
+
+    #[tarpc::service]
+    trait Registry {
+        #[encrypted]
+        async fn ssn(#[encrypted] name: String, year: u32) -> String;
+    }
+
+    #[derive(Clone)]
+    struct RegistryServer;
+
+    #[tarpc::server]
+    impl Registry for RegistryServer {
+        async fn ssn(self, _: context::Context, name: String, year: u32) -> String {
+            format!("{name}-{year}")
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let connect = |dependencies| {
+        let (tx, rx) = channel::unbounded();
+        tokio::spawn(
+            BaseChannel::with_defaults(rx)
+                .requests()
+                .execute(RegistryServer.serve_with(dependencies)),
+        );
+        RegistryClient::new(client::Config::default(), tx).spawn()
+    };
+
+    let mut dependencies = server::Dependencies::new();
+    dependencies.insert(Keys::new(Xor));
+    let client = connect(dependencies);
+    assert_matches!(
+        client.clone().with_keys(Keys::new(Xor)).ssn(context::current(), "alice".into(), 1970).await,
+        Ok(ref s) if s == "alice-1970"
+    );
+    assert_matches!(
+        client.ssn(context::current(), "alice".into(), 1970).await,
+        Err(client::RpcError::Encryption(_))
+    );
+
+    let client = connect(server::Dependencies::new()).with_keys(Keys::new(Xor));
+    assert_matches!(
+        client.ssn(context::current(), "alice".into(), 1970).await,
+        Err(client::RpcError::Server(e)) if e.kind == std::io::ErrorKind::NotFound
+    );
+
+    // Serving a request whose args can't be decrypted fails rather than panicking.
+    let mut dependencies = server::Dependencies::new();
+    dependencies.insert(Keys::new(Tampered));
+    let request = RegistryRequest::Ssn {
+        name: Keys::new(Xor).seal("Registry.ssn.name", &"alice".to_string()),
+        year: 1970,
+    };
+    assert_matches!(
+        RegistryServer
+            .serve_with(dependencies)
+            .serve(context::current(), request)
+            .await,
+        Err(e) if e.kind == std::io::ErrorKind::InvalidData
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "json-rpc")]
+#[tokio::test]
+async fn json_rpc() -> anyhow::Result<()> {
+    use serde_json::{json, Value};
+    use tarpc::server::json_rpc::{JsonRpc, METHOD_NOT_FOUND};
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (client_io, server_io) = tokio::io::duplex(4096);
+    let transport = JsonRpc::new(Framed::new(server_io, LengthDelimitedCodec::new()));
+    tokio::spawn(
+        BaseChannel::with_defaults(transport)
+            .requests()
+            .execute(Server.serve()),
+    );
+
+    let mut client = Framed::new(client_io, LengthDelimitedCodec::new());
+    async fn call(
+        client: &mut Framed<tokio::io::DuplexStream, LengthDelimitedCodec>,
+        request: Value,
+    ) -> anyhow::Result<Value> {
+        client.send(bytes::Bytes::from(request.to_string())).await?;
+        let response = client.next().await.unwrap()?;
+        Ok(serde_json::from_slice(&response)?)
+    }
+
+    assert_eq!(
+        call(
+            &mut client,
+            json!({"jsonrpc": "2.0", "method": "add", "params": [1, 2], "id": 1})
+        )
+        .await?,
+        json!({"jsonrpc": "2.0", "result": 3, "id": 1})
+    );
+    assert_eq!(
+        call(
+            &mut client,
+            json!({"jsonrpc": "2.0", "method": "hey", "params": {"name": "Tim"}, "id": "2"})
+        )
+        .await?,
+        json!({"jsonrpc": "2.0", "result": "Hey, Tim.", "id": "2"})
+    );
+    assert_eq!(
+        call(
+            &mut client,
+            json!({"jsonrpc": "2.0", "method": "Add", "params": [1, 2], "id": 3})
+        )
+        .await?["error"]["code"],
+        json!(METHOD_NOT_FOUND)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn router_serves_multiple_services() -> anyhow::Result<()> {
+    use tarpc::{
+        router::{self, Router},
+        serde_transport,
+    };
+    use tokio_serde::formats::Json;
+
+    #[tarpc::service]
+    trait Greeter {
+        async fn hey(name: String) -> String;
+    }
+
+    #[derive(Clone)]
+    struct GreeterServer;
+
+    impl Greeter for GreeterServer {
+        type HeyFut = Ready<String>;
+
+        fn hey(self, _: context::Context, name: String) -> Self::HeyFut {
+            ready(format!("Greetings, {name}."))
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let connect = || {
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let server_transport = serde_transport::Transport::from((server_io, Json::default()));
+        tokio::spawn(
+            BaseChannel::with_defaults(server_transport)
+                .execute(Router::new(Server.serve()).mount(GreeterServer.serve())),
+        );
+        client_io
+    };
+
+    let service = ServiceClient::new(
+        client::Config::default(),
+        router::routed(serde_transport::Transport::from((
+            connect(),
+            Json::default(),
+        ))),
+    )
+    .spawn();
+    let greeter = GreeterClient::new(
+        client::Config::default(),
+        router::routed(serde_transport::Transport::from((
+            connect(),
+            Json::default(),
+        ))),
+    )
+    .spawn();
+
+    assert_matches!(service.add(context::current(), 1, 2).await, Ok(3));
+    assert_matches!(
+        service.hey(context::current(), "Tim".into()).await,
+        Ok(ref s) if s == "Hey, Tim."
+    );
+    assert_matches!(
+        greeter.hey(context::current(), "Tim".into()).await,
+        Ok(ref s) if s == "Greetings, Tim."
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn router_isolates_tenants() -> anyhow::Result<()> {
+    use tarpc::{
+        router::{
+            self,
+            tenants::{Namespace, Tenant},
+            Router,
+        },
+        serde_transport,
+    };
+    use tokio_serde::formats::Json;
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let tenant = Tenant::new("a", Namespace::new().method("Service.add"));
+    let (client_io, server_io) = tokio::io::duplex(4096);
+    tokio::spawn(
+        BaseChannel::with_defaults(serde_transport::Transport::from((
+            server_io,
+            Json::default(),
+        )))
+        .execute(Router::new(Server.serve()).scoped(tenant.clone())),
+    );
+    let client = ServiceClient::new(
+        client::Config::default(),
+        router::routed(serde_transport::Transport::from((
+            client_io,
+            Json::default(),
+        ))),
+    )
+    .spawn();
+
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+    assert_matches!(
+        client.hey(context::current(), "Tim".into()).await,
+        Err(client::RpcError::Server(e)) if e.kind == std::io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(tenant.stats().admitted(), 1);
+    assert_eq!(tenant.stats().denied(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn wire_ids_keep_reordered_rpcs_compatible() -> anyhow::Result<()> {
+    mod v1 {
+        #[tarpc::service]
+        pub trait Store {
+            #[tarpc(id = 1)]
+            async fn get(key: String) -> Option<i32>;
+            #[tarpc(id = 2)]
+            async fn put(key: String, value: i32);
+            #[tarpc(id = 3)]
+            async fn put_all(entries: impl Stream<Item = (String, i32)>) -> u32;
+        }
+    }
+
+    mod v2 {
+        #[tarpc::service]
+        pub trait Store {
+            #[tarpc(id = 4)]
+            async fn len() -> u64;
+            #[tarpc(id = 3)]
+            async fn put_all(entries: impl Stream<Item = (String, i32)>) -> u32;
+            #[tarpc(id = 2)]
+            async fn put(key: String, value: i32);
+            #[tarpc(id = 1)]
+            async fn get(key: String) -> Option<i32>;
+        }
+    }
+
+    let request = v1::StoreRequest::Put {
+        key: "a".into(),
+        value: 1,
+    };
+    let bytes = bincode::serialize(&request)?;
+    assert_matches!(
+        bincode::deserialize(&bytes)?,
+        v2::StoreRequest::Put { key, value: 1 } if key == "a"
+    );
+    let json = serde_json::to_string(&request)?;
+    assert_eq!(json, r#"{"Put":{"key":"a","value":1}}"#);
+    assert_matches!(
+        serde_json::from_str(&json)?,
+        v2::StoreRequest::Put { key, value: 1 } if key == "a"
+    );
+
+    let item = v1::StoreRequest::PutAllStreamItem(("b".into(), 2));
+    assert_matches!(
+        bincode::deserialize(&bincode::serialize(&item)?)?,
+        v2::StoreRequest::PutAllStreamItem((key, 2)) if key == "b"
+    );
+
+    let response = v1::StoreResponse::Get(Some(3));
+    assert_matches!(
+        bincode::deserialize(&bincode::serialize(&response)?)?,
+        v2::StoreResponse::Get(Some(3))
+    );
+
+    let request = v2::StoreRequest::Len {};
+    assert_matches!(
+        bincode::deserialize::<v1::StoreRequest>(&bincode::serialize(&request)?),
+        Err(_)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn paged_responses() -> anyhow::Result<()> {
+    use tarpc::paging::{Page, PageToken, Pager};
+
+    #[tarpc::service]
+    trait Inventory {
+        #[tarpc(paged)]
+        async fn items(below: u32, page: Option<PageToken>) -> Page<u32>;
+    }
+
+    #[derive(Clone)]
+    struct InventoryServer(Pager<u32>);
+
+    #[tarpc::server]
+    impl Inventory for InventoryServer {
+        async fn items(
+            self,
+            _: context::Context,
+            below: u32,
+            page: Option<PageToken>,
+        ) -> Page<u32> {
+            self.0.page(page, || (0..below).collect())
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .requests()
+            .execute(InventoryServer(Pager::new(3)).serve()),
+    );
+    let client = InventoryClient::new(client::Config::default(), tx).spawn();
+
+    let page = client.items(context::current(), 5, None).await?;
+    assert_eq!(page.items(), [0, 1, 2]);
+    let page = client
+        .items(context::current(), 5, page.next_token())
+        .await?;
+    assert_eq!(page.items(), [3, 4]);
+    assert_eq!(page.next_token(), None);
+
+    let items = client
+        .items_pages(context::current(), 10)
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(items, (0..10).collect::<Vec<_>>());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn graceful_shutdown() -> anyhow::Result<()> {
+    use tokio::sync::mpsc;
+
+    #[tarpc::service]
+    trait Sleep {
+        async fn sleep(millis: u64) -> u64;
+    }
+
+    #[derive(Clone)]
+    struct SleepServer(mpsc::UnboundedSender<()>);
+
+    #[tarpc::server]
+    impl Sleep for SleepServer {
+        async fn sleep(self, _: context::Context, millis: u64) -> u64 {
+            let _ = self.0.send(());
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+            millis
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let shutdown = server::Shutdown::new();
+    let config = server::Config::default().with_shutdown(shutdown.clone());
+    let (tx, rx) = channel::unbounded();
+    let (idle_tx, idle_rx) = channel::unbounded();
+    let (started_tx, mut started) = mpsc::unbounded_channel();
+    let server = tokio::spawn(
+        config
+            .channels(stream::iter([rx, idle_rx]).chain(stream::pending()))
+            .execute(SleepServer(started_tx).serve())
+            .with_shutdown(&shutdown),
+    );
+    let client = SleepClient::new(client::Config::default(), tx).spawn();
+    let idle_client = SleepClient::new(client::Config::default(), idle_tx).spawn();
+    assert_matches!(idle_client.sleep(context::current(), 0).await, Ok(0));
+    started.recv().await;
+    assert_eq!(shutdown.channels(), 2);
+
+    // Requests in flight at shutdown complete.
+    let sleep = tokio::spawn({
+        let client = client.clone();
+        async move { client.sleep(context::current(), 100).await }
+    });
+    started.recv().await;
+    shutdown.shutdown(Duration::from_secs(10)).await?;
+    server.await?;
+    assert_matches!(sleep.await?, Ok(100));
+    assert_eq!(shutdown.channels(), 0);
+
+    // The channels are closed.
+    assert_matches!(client.sleep(context::current(), 0).await, Err(_));
+    assert_matches!(idle_client.sleep(context::current(), 0).await, Err(_));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn graceful_shutdown_times_out() -> anyhow::Result<()> {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let shutdown = server::Shutdown::new();
+    let config = server::Config::default().with_shutdown(shutdown.clone());
+    let (tx, rx) = channel::unbounded();
+    let (started_tx, mut started) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(config.channel(rx).execute(move |_, ()| {
+        let _ = started_tx.send(());
+        future::pending::<()>()
+    }));
+    let client = client::new::<(), (), _>(client::Config::default(), tx).spawn();
+    let request = tokio::spawn(async move { client.call(context::current(), "", ()).await });
+    started.recv().await;
+
+    tokio::time::pause();
+    assert_matches!(
+        shutdown.shutdown(Duration::from_secs(1)).await,
+        Err(server::DrainTimeout { channels: 1, .. })
+    );
+    request.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_pushes_reach_client() -> anyhow::Result<()> {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let requests = BaseChannel::with_defaults(rx).requests();
+    let pusher = requests.pusher();
+    tokio::spawn(requests.execute(|_, name: String| async move { format!("Hey, {name}.") }));
+
+    let client = client::new(client::Config::default(), tx).spawn();
+    let mut pushes = client.pushes().expect("pushes are taken only once");
+    assert!(client.pushes().is_none());
+
+    pusher.push(String::from("invalidate")).await?;
+    assert_eq!(
+        client
+            .call(context::current(), "", String::from("Tim"))
+            .await?,
+        "Hey, Tim."
+    );
+    assert_eq!(pushes.next().await.as_deref(), Some("invalidate"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn pubsub_streams_published_events() -> anyhow::Result<()> {
+    use tarpc::pubsub::{Broker, Subscription};
+
+    #[tarpc::service]
+    trait Prices {
+        async fn subscribe(symbol: String) -> impl Stream<Item = u64>;
+    }
+
+    #[derive(Clone)]
+    struct PricesServer(Broker<u64>);
+
+    impl Prices for PricesServer {
+        type SubscribeStream = Subscription<u64>;
+
+        fn subscribe(self, _: context::Context, symbol: String) -> Subscription<u64> {
+            self.0.subscribe(symbol)
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let broker = Broker::new(16);
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .requests()
+            .execute(PricesServer(broker.clone()).serve()),
+    );
+    let client = PricesClient::new(client::Config::default(), tx).spawn();
+
+    let mut prices = client.subscribe(context::current(), "GOOG".into()).await?;
+    while broker.subscribers("GOOG") == 0 {
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(broker.publish("GOOG", 100), 1);
+    assert_eq!(broker.publish("AAPL", 200), 0);
+    assert_eq!(broker.publish("GOOG", 101), 1);
+    assert_matches!(prices.next().await, Some(Ok(100)));
+    assert_matches!(prices.next().await, Some(Ok(101)));
+
+    broker.close("GOOG");
+    assert_matches!(prices.next().await, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn one_way_requests_reach_server() -> anyhow::Result<()> {
+    #[tarpc::service]
+    trait Telemetry {
+        #[tarpc(one_way)]
+        async fn record(metric: String);
+        async fn flush() -> usize;
+    }
+
+    #[derive(Clone)]
+    struct TelemetryServer(tokio::sync::mpsc::UnboundedSender<String>);
+
+    impl Telemetry for TelemetryServer {
+        type RecordFut = Ready<()>;
+
+        fn record(self, _: context::Context, metric: String) -> Self::RecordFut {
+            let _ = self.0.send(metric);
+            ready(())
+        }
+
+        type FlushFut = Ready<usize>;
+
+        fn flush(self, _: context::Context) -> Self::FlushFut {
+            ready(0)
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (metrics_tx, mut metrics) = tokio::sync::mpsc::unbounded_channel();
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .requests()
+            .execute(TelemetryServer(metrics_tx).serve()),
+    );
+    let client = TelemetryClient::new(client::Config::default(), tx).spawn();
+
+    client.record(context::current(), "cpu".into()).await?;
+    client.record(context::current(), "mem".into()).await?;
+    assert_eq!(metrics.recv().await.as_deref(), Some("cpu"));
+    assert_eq!(metrics.recv().await.as_deref(), Some("mem"));
+    // Requests that expect a response are unaffected by the one-way requests before them.
+    assert_matches!(client.flush(context::current()).await, Ok(0));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn app_errors_are_typed() -> anyhow::Result<()> {
+    #[derive(Debug, PartialEq, Eq)]
+    enum WithdrawError {
+        Insufficient { balance: u64 },
+    }
+
+    #[tarpc::service(derive_serde = false)]
+    trait Account {
+        #[tarpc(app_error)]
+        async fn withdraw(amount: u64) -> Result<u64, WithdrawError>;
+    }
+
+    #[derive(Clone)]
+    struct AccountServer;
+
+    impl Account for AccountServer {
+        type WithdrawFut = Ready<Result<u64, WithdrawError>>;
+
+        fn withdraw(self, _: context::Context, amount: u64) -> Self::WithdrawFut {
+            let balance = 10;
+            ready(if amount <= balance {
+                Ok(balance - amount)
+            } else {
+                Err(WithdrawError::Insufficient { balance })
+            })
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .requests()
+            .execute(AccountServer.serve()),
+    );
+    let client = AccountClient::new(client::Config::default(), tx).spawn();
+
+    assert_matches!(client.withdraw(context::current(), 3).await, Ok(7));
+    assert_matches!(
+        client.withdraw(context::current(), 30).await,
+        Err(client::CallError::App(WithdrawError::Insufficient {
+            balance: 10
+        }))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn handshake_exchanges_capabilities() -> anyhow::Result<()> {
+    use tarpc::capabilities::Capabilities;
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .requests()
+            .execute(Server.serve()),
+    );
+    let mut config = client::Config::default();
+    config.capabilities = Some(Capabilities::STREAMING | Capabilities::COMPRESSION);
+    let client = ServiceClient::new(config, tx).spawn();
+
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+    // The server answers the handshake before responding to any request.
+    assert_eq!(client.peer_capabilities(), Some(Capabilities::SUPPORTED));
+
+    // Clients don't handshake by default.
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .requests()
+            .execute(Server.serve()),
+    );
+    let client = ServiceClient::new(client::Config::default(), tx).spawn();
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+    assert_eq!(client.peer_capabilities(), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn erased_client_is_send_sync_static() -> anyhow::Result<()> {
+    use std::any::Any;
+
+    #[tarpc::service(erased_client = true)]
+    trait Numbers {
+        async fn add(x: u32, y: u32) -> u32;
+        async fn count_to(n: u32) -> impl Stream<Item = u32>;
+        async fn sum(numbers: impl Stream<Item = u32>) -> u32;
+        async fn double(numbers: impl Stream<Item = u32>) -> impl Stream<Item = u32>;
+    }
+
+    #[derive(Clone)]
+    struct NumbersServer;
+
+    impl Numbers for NumbersServer {
+        type AddFut = Ready<u32>;
+
+        fn add(self, _: context::Context, x: u32, y: u32) -> Self::AddFut {
+            ready(x + y)
+        }
+
+        type CountToStream = stream::Iter<std::ops::RangeInclusive<u32>>;
+
+        fn count_to(self, _: context::Context, n: u32) -> Self::CountToStream {
+            stream::iter(1..=n)
+        }
+
+        type SumFut = future::BoxFuture<'static, u32>;
+
+        fn sum(
+            self,
+            _: context::Context,
+            numbers: tarpc::server::RequestStream<NumbersRequest, u32>,
+        ) -> Self::SumFut {
+            numbers.fold(0, |sum, n| ready(sum + n)).boxed()
+        }
+
+        type DoubleStream = stream::BoxStream<'static, u32>;
+
+        fn double(
+            self,
+            _: context::Context,
+            numbers: tarpc::server::RequestStream<NumbersRequest, u32>,
+        ) -> Self::DoubleStream {
+            numbers.map(|n| n * 2).boxed()
+        }
+    }
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .requests()
+            .execute(NumbersServer.serve()),
+    );
+    let client = ErasedNumbersClient::new(client::Config::default(), tx).spawn();
+
+    // The client can be stored without naming the transport or any future type.
+    let registry: Vec<Box<dyn Any + Send + Sync>> = vec![Box::new(client)];
+    let client = registry[0]
+        .downcast_ref::<ErasedNumbersClient>()
+        .unwrap()
+        .clone();
+
+    let add: future::BoxFuture<'static, _> = client.add(context::current(), 1, 2);
+    drop(registry);
+    assert_matches!(tokio::spawn(add).await?, Ok(3));
+
+    let counts = client.count_to(context::current(), 3).await?;
+    assert_eq!(counts.try_collect::<Vec<_>>().await?, vec![1, 2, 3]);
+
+    let (mut numbers, sum) = client.sum(context::current()).await?;
+    numbers.send(4).await?;
+    numbers.send(5).await?;
+    numbers.close().await?;
+    assert_matches!(sum.await, Ok(9));
+
+    let (mut numbers, doubled) = client.double(context::current()).await?;
+    numbers.send(7).await?;
+    numbers.close().await?;
+    assert_eq!(doubled.try_collect::<Vec<_>>().await?, vec![14]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn spread_channels_across_runtimes() -> anyhow::Result<()> {
+    #[tarpc::service]
+    trait Thread {
+        async fn name() -> String;
+    }
+
+    #[derive(Clone)]
+    struct ThreadServer;
+
+    impl Thread for ThreadServer {
+        type NameFut = Ready<String>;
+
+        fn name(self, _: context::Context) -> Self::NameFut {
+            ready(std::thread::current().name().unwrap_or_default().into())
+        }
+    }
+
+    let runtimes = ["alpha", "beta"]
+        .into_iter()
+        .map(|name| {
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .thread_name(name)
+                .enable_all()
+                .build()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let handles: Vec<_> = runtimes.iter().map(|r| r.handle().clone()).collect();
+
+    type ServerChannel = BaseChannel<
+        ThreadRequest,
+        ThreadResponse,
+        channel::UnboundedChannel<
+            tarpc::ClientMessage<ThreadRequest>,
+            tarpc::ServerMessage<ThreadResponse>,
+        >,
+    >;
+
+    async fn thread_names(
+        n: usize,
+        execute: impl FnOnce(Vec<ServerChannel>),
+    ) -> anyhow::Result<Vec<String>> {
+        let (clients, servers): (Vec<_>, Vec<_>) = (0..n).map(|_| channel::unbounded()).unzip();
+        execute(
+            servers
+                .into_iter()
+                .map(BaseChannel::with_defaults)
+                .collect(),
+        );
+        let mut names = vec![];
+        for tx in clients {
+            let client = ThreadClient::new(client::Config::default(), tx).spawn();
+            names.push(client.name(context::current()).await?);
+        }
+        Ok(names)
+    }
+
+    let names = thread_names(4, |channels| {
+        tokio::spawn(
+            stream::iter(channels)
+                .execute(ThreadServer.serve())
+                .spread(handles.clone()),
+        );
+    })
+    .await?;
+    assert_eq!(names, ["alpha", "beta", "alpha", "beta"]);
+
+    let names = thread_names(3, |channels| {
+        tokio::spawn(
+            stream::iter(channels)
+                .execute(ThreadServer.serve())
+                .spread_by_key(handles.clone(), |_: &ServerChannel| "tenant"),
+        );
+    })
+    .await?;
+    assert!(names.iter().all(|name| *name == names[0]));
+
+    for runtime in runtimes {
+        runtime.shutdown_background();
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn bound_channels_served_at_once() -> anyhow::Result<()> {
+    use tarpc::server::tokio::Overflow;
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let (clients, servers): (Vec<_>, Vec<_>) = (0..2).map(|_| channel::unbounded()).unzip();
+    let executor = stream::iter(servers.into_iter().map(BaseChannel::with_defaults))
+        .execute(Server.serve())
+        .max_channels(1, Overflow::Queue);
+    let tasks = executor.channel_tasks();
+    tokio::spawn(executor);
+    let mut clients = clients
+        .into_iter()
+        .map(|tx| ServiceClient::new(client::Config::default(), tx).spawn());
+    let first = clients.next().unwrap();
+    let second = clients.next().unwrap();
+
+    assert_matches!(first.add(context::current(), 1, 2).await, Ok(3));
+    // The second channel is queued until the first closes.
+    assert_matches!(
+        tokio::time::timeout(
+            Duration::from_millis(100),
+            second.add(context::current(), 3, 4)
+        )
+        .await,
+        Err(_)
+    );
+    drop(first);
+    assert_matches!(second.add(context::current(), 3, 4).await, Ok(7));
+    drop(second);
+    tasks.join().await;
+    assert_eq!(tasks.running(), 0);
+
+    let (clients, servers): (Vec<_>, Vec<_>) = (0..2).map(|_| channel::unbounded()).unzip();
+    tokio::spawn(
+        stream::iter(servers.into_iter().map(BaseChannel::with_defaults))
+            .execute(Server.serve())
+            .max_channels(1, Overflow::Refuse),
+    );
+    let mut clients = clients
+        .into_iter()
+        .map(|tx| ServiceClient::new(client::Config::default(), tx).spawn());
+    let first = clients.next().unwrap();
+    let second = clients.next().unwrap();
+
+    assert_matches!(first.add(context::current(), 1, 2).await, Ok(3));
+    // The second channel is dropped as soon as it's accepted.
+    assert_matches!(
+        second.add(context::current(), 3, 4).await,
+        Err(client::RpcError::Disconnected(_))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn failover_on_unavailable_server() -> anyhow::Result<()> {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tarpc::metrics::Recorder;
+
+    #[derive(Debug, Default)]
+    struct Failovers(AtomicUsize);
+
+    impl Recorder for Failovers {
+        fn failover(&self, method: &str) {
+            assert_eq!(method, "Service.add");
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let failovers = Arc::new(Failovers::default());
+    let mut config = client::Config::default();
+    config.metrics = Some(failovers.clone());
+    // The primary server is gone.
+    let (tx, rx) = channel::unbounded();
+    drop(rx);
+    let primary = ServiceClient::new(config, tx).spawn();
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .requests()
+            .execute(Server.serve()),
+    );
+    let secondary = ServiceClient::new(client::Config::default(), tx).spawn();
+
+    let client = primary.with_failover(secondary);
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+    assert_matches!(client.add(context::current(), 3, 4).await, Ok(7));
+    assert_eq!(failovers.0.load(Ordering::Relaxed), 2);
+
+    Ok(())
+}